@@ -7,8 +7,10 @@ extern crate test;
 extern crate eve;
 
 use eve::indexes::*;
+use eve::ops::Interned;
 use self::test::Bencher;
 use std::num::Wrapping;
+use std::collections::HashMap;
 
 fn rand(rseed:u32) -> u32 {
     return ((Wrapping(rseed) * Wrapping(1103515245) + Wrapping(12345)) & Wrapping(0x7fffffff)).0;
@@ -88,3 +90,125 @@ fn hash_read(b:&mut Bencher) {
     });
     // println!("results: {:?}", total);
 }
+
+// A tag-like value shared by a lot of entities, so proposing entities for it walks one
+// long run of rows.
+#[bench]
+fn hash_scan_wide_leaf(b:&mut Bencher) {
+    let mut index = HashIndex::new();
+    for e in 1..100_001 {
+        index.insert(e, 1, 7);
+    }
+    b.iter(|| {
+        let total:u64 = index.get(0, 1, 7).unwrap().map(|e| e as u64).sum();
+        test::black_box(total);
+    });
+}
+
+#[bench]
+fn hash_scan_narrow_leaves(b:&mut Bencher) {
+    let mut index = HashIndex::new();
+    for e in 1..100_001 {
+        for v in 0..4 {
+            index.insert(e, 1, e + v);
+        }
+    }
+    let mut seed = 0;
+    b.iter(|| {
+        let e = rand(seed);
+        seed = e;
+        let total:u64 = index.get(e % 100_000 + 1, 1, 0).unwrap().map(|v| v as u64).sum();
+        test::black_box(total);
+    });
+}
+
+#[bench]
+fn hash_remove_wide_leaf(b:&mut Bencher) {
+    b.iter(|| {
+        let mut index = HashIndex::new();
+        for e in 1..10_001 {
+            index.insert(e, 1, 7);
+        }
+        for e in 1..10_001 {
+            index.remove(e, 1, 7);
+        }
+    });
+}
+
+// The same facts as hash sets keyed by entity or value, the way an attribute was stored
+// before its facts were columns, so the benches above have something to be compared
+// against.
+#[bench]
+fn hash_set_scan_wide_leaf(b:&mut Bencher) {
+    let mut leaf:HashMap<Interned, (), MyHasher> = HashMap::default();
+    for e in 1..100_001 {
+        leaf.insert(e, ());
+    }
+    b.iter(|| {
+        let total:u64 = leaf.keys().map(|&e| e as u64).sum();
+        test::black_box(total);
+    });
+}
+
+#[bench]
+fn hash_set_scan_narrow_leaves(b:&mut Bencher) {
+    let mut leaves:HashMap<Interned, HashMap<Interned, (), MyHasher>, MyHasher> = HashMap::default();
+    for e in 1..100_001 {
+        for v in 0..4 {
+            leaves.entry(e).or_insert_with(HashMap::default).insert(e + v, ());
+        }
+    }
+    let mut seed = 0;
+    b.iter(|| {
+        let e = rand(seed);
+        seed = e;
+        let total:u64 = leaves[&(e % 100_000 + 1)].keys().map(|&v| v as u64).sum();
+        test::black_box(total);
+    });
+}
+
+#[bench]
+fn hash_set_remove_wide_leaf(b:&mut Bencher) {
+    b.iter(|| {
+        let mut leaf:HashMap<Interned, (), MyHasher> = HashMap::default();
+        for e in 1..10_001 {
+            leaf.insert(e, ());
+        }
+        for e in 1..10_001 {
+            leaf.remove(&e);
+        }
+        test::black_box(leaf.len());
+    });
+}
+
+// The key of a `not` that nothing matched, which is what anti-scans probe for most of the
+// time.
+#[bench]
+fn intermediate_anti_miss(b:&mut Bencher) {
+    let mut index = IntermediateIndex::new();
+    for ix in 1..10_001 {
+        index.distinct(vec![ix, ix * 2], vec![ix, ix * 2], vec![], 1, 1, false);
+    }
+    let mut seed = 0;
+    b.iter(|| {
+        let e = rand(seed);
+        seed = e;
+        test::black_box(index.distinct_iter(&vec![e % 10_000 + 1, 3], &vec![]).count());
+    });
+}
+
+// The same misses against a bare map, which is all an anti-scan had to go on before the
+// filter.
+#[bench]
+fn intermediate_anti_miss_unfiltered(b:&mut Bencher) {
+    let mut index:HashMap<Vec<Interned>, (), MyHasher> = HashMap::default();
+    for ix in 1..10_001 {
+        index.insert(vec![ix, ix * 2], ());
+    }
+    let mut seed = 0;
+    b.iter(|| {
+        let e = rand(seed);
+        seed = e;
+        test::black_box(index.contains_key(&vec![e % 10_000 + 1, 3]));
+    });
+}
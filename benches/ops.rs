@@ -7,6 +7,7 @@ use eve::ops::*;
 use eve::solver::{Solver};
 use eve::compiler::{parse_string};
 use eve::indexes::{DistinctIter};
+use eve::batch::{self, Comparison};
 use test::Bencher;
 
 #[bench]
@@ -20,15 +21,90 @@ pub fn round_holder_compute_output_rounds_bench(b:&mut Bencher) {
     });
 }
 
+fn million_numbers() -> Vec<u32> {
+    (0..1_000_000).map(|ix| (ix as f32).to_bits()).collect()
+}
+
+#[bench]
+pub fn batch_filter_million(b:&mut Bencher) {
+    let values = million_numbers();
+    let mut mask = vec![0; (values.len() + 63) / 64];
+    b.iter(|| {
+        for word in mask.iter_mut() { *word = !0; }
+        batch::retain(&values, Comparison::GreaterEqual, 500_000f32.to_bits(), &mut mask);
+        mask[0]
+    });
+}
+
+#[bench]
+pub fn batch_filter_million_scalar(b:&mut Bencher) {
+    let values = million_numbers();
+    let mut mask = vec![0; (values.len() + 63) / 64];
+    b.iter(|| {
+        for word in mask.iter_mut() { *word = !0; }
+        batch::retain_scalar(&values, Comparison::GreaterEqual, 500_000f32.to_bits(), &mut mask);
+        mask[0]
+    });
+}
+
+// A million distinct values proposed at once, so the `!=` is the batch filter over the
+// interned number column rather than a range scan.
+#[bench]
+pub fn ops_query_filter_million(b:&mut Bencher) {
+    let mut program = Program::new("filter");
+    let mut builder = program.transaction();
+    for ix in 0..1_000_000 {
+        builder = builder.insert(Internable::String(format!("reading|{}", ix)), "value", Internable::from_number(ix as f32));
+    }
+    builder.commit();
+    b.iter(|| {
+        program.query("search\n  [value]\n  value != 3\nproject (value)\nend", QueryBudget::unlimited()).unwrap()
+    });
+}
+
+// Most candidates of a `not` don't match it, which is the case the intermediate key
+// filter turns away without probing the index.
+#[bench]
+pub fn ops_negation_heavy(b:&mut Bencher) {
+    let mut program = Program::new("negation");
+    let blocks = parse_string(&mut program.state.interner, "
+search
+  [#item id]
+  not([#flagged id])
+bind
+  [#unflagged id]
+end", "negation");
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+    let node = Internable::String("bench".to_string());
+    let items = |count:Count| {
+        let mut changes = vec![];
+        for ix in 0..1000 {
+            let mut records = vec![(format!("item|{}|", ix), "item")];
+            if ix % 100 == 0 { records.push((format!("flagged|{}|", ix), "flagged")); }
+            for (e, tag) in records {
+                let e = Internable::Reference(e);
+                changes.push(RawChange::new(e.clone(), Internable::String("tag".to_string()), Internable::String(tag.to_string()), node.clone(), count));
+                changes.push(RawChange::new(e, Internable::String("id".to_string()), Internable::from_number(ix as f32), node.clone(), count));
+            }
+        }
+        changes
+    };
+    b.iter(|| {
+        program.annotated_transaction(items(1), vec![]);
+        program.annotated_transaction(items(-1), vec![]);
+    });
+}
+
 fn test_solver(b: &mut Bencher, code: &str, setup:&str) {
     let mut program = Program::new();
 
-    let to_test = parse_string(&mut program.state.interner, code, "test", false).pop().unwrap();
+    let to_test = parse_string(&mut program.state.interner, code, "test").pop().unwrap();
     let solver = Solver::new(&mut program.state.interner, 0, 0, None, &to_test.constraints);
     program.block_info.blocks.push(to_test);
 
     let mut blocks = vec![];
-    blocks.extend(parse_string(&mut program.state.interner, setup, "test", false));
+    blocks.extend(parse_string(&mut program.state.interner, setup, "test"));
 
     let mut txn = CodeTransaction::new();
     txn.exec(&mut program, blocks, vec![]);
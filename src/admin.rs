@@ -0,0 +1,45 @@
+//-------------------------------------------------------------------------
+// Admin
+//-------------------------------------------------------------------------
+
+use ops::BlockStats;
+use indexes::IndexStats;
+
+// Runtime control for a running program, sent as `RunLoopMessage::Admin` so it's
+// applied between transactions. The server exposes these over HTTP.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AdminCommand {
+    Blocks,
+    Enable(String),
+    Disable(String),
+    Load(String),
+    Snapshot,
+    Stats,
+    Compact,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminBlock {
+    pub name: String,
+    pub path: String,
+    pub enabled: bool,
+    pub stats: BlockStats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminStats {
+    pub blocks: usize,
+    pub disabled: usize,
+    pub watchers: usize,
+    pub transactions: u64,
+    pub committed: usize,
+    pub indexes: Vec<(String, IndexStats)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum AdminReply {
+    Blocks(Vec<AdminBlock>),
+    Stats(AdminStats),
+    Done,
+    Error(String),
+}
@@ -0,0 +1,195 @@
+//-------------------------------------------------------------------------
+// Arrangements
+//-------------------------------------------------------------------------
+
+use ops::{Interned, Block, Field, Constraint};
+use provenance::renumber_registers;
+use std::collections::HashMap;
+
+// A `not`, an aggregate or anything else that compiles to a sub-block maintains an
+// intermediate collection keyed by the variables it shares with its block. Blocks that
+// ask the same question, e.g. every block with `not([#archived])` on the same record,
+// each used to keep their own copy up to date. Now the first of them to be added keeps
+// an arrangement that the others read from: their sub-blocks are registered, so they
+// can be named, disabled and removed like any block, but none of their pipes run and
+// their intermediate ids are rewritten to the arrangement's. When the block keeping the
+// arrangement is removed, one of the others takes over.
+//
+// Only sub-blocks that are the sole writer of their intermediates and don't write
+// anything else are shared. `if` branches all write the same collection, so they aren't.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arrangement {
+    /// The intermediate ids every user reads, which are the first maintainer's.
+    pub ids: Vec<Interned>,
+    /// The block whose pipes keep the arrangement up to date.
+    pub maintainer: String,
+    /// Every block using the arrangement, the maintainer included.
+    pub users: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Arrangements {
+    by_shape: HashMap<String, Arrangement>,
+    // each user's shape and the ids it was compiled with
+    members: HashMap<String, (String, Vec<Interned>)>,
+    aliases: HashMap<Interned, Interned>,
+}
+
+impl Arrangements {
+    pub fn get(&self, block:&str) -> Option<&Arrangement> {
+        self.members.get(block).and_then(|&(ref shape, _)| self.by_shape.get(shape))
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_shape.len()
+    }
+
+    /// Whether the block reads an arrangement another block maintains.
+    pub fn is_passive(&self, block:&str) -> bool {
+        self.get(block).map_or(false, |arrangement| arrangement.maintainer != block)
+    }
+
+    // Points the block at the arrangements its sub-blocks were folded into.
+    pub fn rewrite(&self, block:&mut Block) -> bool {
+        if self.aliases.is_empty() { return false; }
+        let mut changed = false;
+        for constraint in block.constraints.iter_mut() {
+            for field in constraint.fields_mut() {
+                if let &mut Field::Value(id) = field {
+                    if let Some(&alias) = self.aliases.get(&id) {
+                        *field = Field::Value(alias);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    // Adds the block to the arrangement with its shape, making one if there isn't any.
+    // Returns true if another block already maintains it.
+    pub fn join(&mut self, block:&mut Block) -> bool {
+        let ids = written_intermediates(&block.constraints);
+        let shape = match arrangement_shape(&block.constraints, &ids) {
+            Some(shape) => shape,
+            None => return false,
+        };
+        let passive = match self.by_shape.get_mut(&shape) {
+            Some(arrangement) => {
+                for (&id, &canonical) in ids.iter().zip(arrangement.ids.iter()) {
+                    if id != canonical { self.aliases.insert(id, canonical); }
+                }
+                arrangement.users.push(block.name.to_string());
+                true
+            }
+            None => {
+                self.by_shape.insert(shape.to_string(), Arrangement { ids: ids.clone(), maintainer: block.name.to_string(), users: vec![block.name.to_string()] });
+                false
+            }
+        };
+        self.members.insert(block.name.to_string(), (shape, ids));
+        if passive { self.rewrite(block); }
+        passive
+    }
+
+    // Takes the block out of its arrangement. If it was the maintainer and anyone else
+    // is still using the arrangement, returns who has to take over.
+    pub fn leave(&mut self, block:&str) -> Option<String> {
+        let (shape, ids) = self.members.remove(block)?;
+        for id in ids {
+            self.aliases.remove(&id);
+        }
+        let arrangement = self.by_shape.get_mut(&shape)?;
+        arrangement.users.retain(|user| user != block);
+        if arrangement.users.is_empty() {
+            self.by_shape.remove(&shape);
+            None
+        } else if arrangement.maintainer == block {
+            arrangement.maintainer = arrangement.users[0].to_string();
+            Some(arrangement.maintainer.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+// The intermediate ids a block fills in: the key of each intermediate insert and the
+// group and result keys of an aggregate.
+pub fn written_intermediates(constraints:&[Constraint]) -> Vec<Interned> {
+    let mut ids = vec![];
+    for constraint in constraints {
+        match constraint {
+            &Constraint::InsertIntermediate { ref key, .. } => {
+                if let Some(&Field::Value(id)) = key.first() { ids.push(id); }
+            }
+            &Constraint::Aggregate { ref group, ref output_key, .. } => {
+                if let Some(&Field::Value(id)) = group.first() { ids.push(id); }
+                if let Some(&Field::Value(id)) = output_key.first() { ids.push(id); }
+            }
+            _ => {}
+        }
+    }
+    ids
+}
+
+// What a block computes regardless of what it's called or how its registers happen to
+// be numbered. None if the block does anything other than fill in intermediates.
+fn arrangement_shape(constraints:&[Constraint], written:&[Interned]) -> Option<String> {
+    if written.is_empty() { return None; }
+    let mut constraints = constraints.to_vec();
+    renumber_registers(&mut constraints);
+    let mut parts = vec![];
+    for constraint in constraints.iter_mut() {
+        match constraint {
+            &mut Constraint::Insert { .. } | &mut Constraint::Remove { .. } | &mut Constraint::RemoveAttribute { .. } |
+            &mut Constraint::RemoveEntity { .. } | &mut Constraint::DynamicCommit { .. } | &mut Constraint::Project { .. } |
+            &mut Constraint::Watch { .. } | &mut Constraint::LookupRemote { .. } => return None,
+            _ => {}
+        }
+        for field in constraint.fields_mut() {
+            if let &mut Field::Value(id) = field {
+                // counted down from the top so they can't be mistaken for a real value
+                if let Some(ix) = written.iter().position(|&cur| cur == id) { *field = Field::Value(Interned::max_value() - ix as Interned); }
+            }
+        }
+        let extra = match constraint {
+            &mut Constraint::Aggregate { ref output, .. } => format!(" -> {:?}", output),
+            _ => String::new(),
+        };
+        parts.push(format!("{:?}{}", constraint, extra));
+    }
+    parts.sort();
+    Some(parts.join("\n"))
+}
+
+fn read_intermediates(constraints:&[Constraint]) -> Vec<Interned> {
+    constraints.iter().filter_map(|constraint| match constraint {
+        &Constraint::AntiScan { ref key, .. } |
+        &Constraint::IntermediateScan { ref key, .. } => match key.first() {
+            Some(&Field::Value(id)) => Some(id),
+            _ => None,
+        },
+        _ => None,
+    }).collect()
+}
+
+// Puts blocks after the blocks filling in the intermediates they read, so a sub-block's
+// arrangement is settled before anything reading it is registered.
+pub fn order_by_intermediates(blocks:Vec<Block>) -> Vec<Block> {
+    let mut pending:Vec<(Block, Vec<Interned>, Vec<Interned>)> = blocks.into_iter().map(|block| {
+        let written = written_intermediates(&block.constraints);
+        let read = read_intermediates(&block.constraints);
+        (block, written, read)
+    }).collect();
+    let mut ordered = vec![];
+    while pending.len() > 0 {
+        let ready = (0..pending.len()).find(|&ix| {
+            pending[ix].2.iter().all(|id| pending.iter().enumerate().all(|(other, cur)| other == ix || !cur.1.contains(id)))
+        });
+        // intermediates read in a cycle are left in the order they came in
+        let (block, _, _) = pending.remove(ready.unwrap_or(0));
+        ordered.push(block);
+    }
+    ordered
+}
@@ -0,0 +1,149 @@
+//-------------------------------------------------------------------------
+// Batch filters
+//-------------------------------------------------------------------------
+
+// Compares a whole column of numbers against a constant at once, four lanes at a time
+// with SSE2 on x86_64 and one at a time everywhere else. Numbers are kept as the bits
+// they're interned with, so equality is exactly `ops::eq` on two numbers, NaNs and
+// all, and the orderings are the same float comparisons as `ops::lt` and friends.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Comparison {
+    pub fn from_op(op:&str) -> Option<Comparison> {
+        match op {
+            "=" => Some(Comparison::Equal),
+            "!=" => Some(Comparison::NotEqual),
+            "<" => Some(Comparison::Less),
+            "<=" => Some(Comparison::LessEqual),
+            ">" => Some(Comparison::Greater),
+            ">=" => Some(Comparison::GreaterEqual),
+            _ => None,
+        }
+    }
+
+    /// The same comparison with its sides swapped, so `5 < x` can be run as `x > 5`.
+    pub fn flip(self) -> Comparison {
+        match self {
+            Comparison::Less => Comparison::Greater,
+            Comparison::LessEqual => Comparison::GreaterEqual,
+            Comparison::Greater => Comparison::Less,
+            Comparison::GreaterEqual => Comparison::LessEqual,
+            other => other,
+        }
+    }
+
+    pub fn test(self, value:u32, constant:u32) -> bool {
+        let (a, b) = (f32::from_bits(value), f32::from_bits(constant));
+        match self {
+            Comparison::Equal => value == constant,
+            Comparison::NotEqual => value != constant,
+            Comparison::Less => a < b,
+            Comparison::LessEqual => a <= b,
+            Comparison::Greater => a > b,
+            Comparison::GreaterEqual => a >= b,
+        }
+    }
+}
+
+/// Clears the bit in `mask` of every value that fails `op` against `constant`, leaving
+/// the rest alone, so running several filters over the same mask keeps what passes all of
+/// them. Bit `ix` is bit `ix % 64` of word `ix / 64`.
+pub fn retain_scalar(values:&[u32], op:Comparison, constant:u32, mask:&mut [u64]) {
+    retain_scalar_from(values, 0, op, constant, mask);
+}
+
+fn retain_scalar_from(values:&[u32], start:usize, op:Comparison, constant:u32, mask:&mut [u64]) {
+    for (ix, &value) in values.iter().enumerate().skip(start) {
+        if !op.test(value, constant) { mask[ix / 64] &= !(1 << (ix % 64)); }
+    }
+}
+
+/// Same as `retain_scalar`, but vectorized where we can.
+#[cfg(target_arch = "x86_64")]
+pub fn retain(values:&[u32], op:Comparison, constant:u32, mask:&mut [u64]) {
+    use std::arch::x86_64::*;
+    // SSE2 is part of x86_64, so there's nothing to detect at runtime
+    let lanes = values.len() / 4 * 4;
+    unsafe {
+        let constant_bits = _mm_set1_epi32(constant as i32);
+        let constant_num = _mm_castsi128_ps(constant_bits);
+        let mut ix = 0;
+        while ix < lanes {
+            let bits = _mm_loadu_si128(values.as_ptr().add(ix) as *const __m128i);
+            let nums = _mm_castsi128_ps(bits);
+            let passed = match op {
+                Comparison::Equal => _mm_movemask_ps(_mm_castsi128_ps(_mm_cmpeq_epi32(bits, constant_bits))),
+                Comparison::NotEqual => !_mm_movemask_ps(_mm_castsi128_ps(_mm_cmpeq_epi32(bits, constant_bits))) & 0xf,
+                Comparison::Less => _mm_movemask_ps(_mm_cmplt_ps(nums, constant_num)),
+                Comparison::LessEqual => _mm_movemask_ps(_mm_cmple_ps(nums, constant_num)),
+                Comparison::Greater => _mm_movemask_ps(_mm_cmpgt_ps(nums, constant_num)),
+                Comparison::GreaterEqual => _mm_movemask_ps(_mm_cmpge_ps(nums, constant_num)),
+            };
+            // four lanes never straddle a word, since 64 is a multiple of four
+            mask[ix / 64] &= !(((!passed & 0xf) as u64) << (ix % 64));
+            ix += 4;
+        }
+    }
+    retain_scalar_from(values, lanes, op, constant, mask);
+}
+
+/// Same as `retain_scalar`, but vectorized where we can.
+#[cfg(not(target_arch = "x86_64"))]
+pub fn retain(values:&[u32], op:Comparison, constant:u32, mask:&mut [u64]) {
+    retain_scalar(values, op, constant, mask);
+}
+
+#[test]
+fn batch_filter_matches_scalar() {
+    use std::f32;
+    let mut values:Vec<u32> = (0..103).map(|ix| (ix as f32 - 50.0).to_bits()).collect();
+    values.extend([f32::NAN, -0.0, 0.0, f32::INFINITY, f32::NEG_INFINITY].iter().map(|num| num.to_bits()));
+    let ops = [Comparison::Equal, Comparison::NotEqual, Comparison::Less, Comparison::LessEqual, Comparison::Greater, Comparison::GreaterEqual];
+    for &op in ops.iter() {
+        for &constant in [0.0f32, -0.0, 7.0, f32::NAN].iter() {
+            // the odd bits start out cleared, and no filter sets them again
+            let (mut simd, mut scalar) = (vec![0x5555_5555_5555_5555u64; 2], vec![0x5555_5555_5555_5555u64; 2]);
+            retain(&values, op, constant.to_bits(), &mut simd);
+            retain_scalar(&values, op, constant.to_bits(), &mut scalar);
+            assert_eq!(simd, scalar, "{:?} {}", op, constant);
+            let expected:Vec<u64> = (0..2).map(|word| (0..64).filter(|bit| {
+                let ix = word * 64 + bit;
+                bit % 2 == 0 && (ix >= values.len() || op.test(values[ix], constant.to_bits()))
+            }).fold(0, |mask, bit| mask | 1 << bit)).collect();
+            assert_eq!(scalar, expected, "{:?} {}", op, constant);
+        }
+    }
+}
+
+#[test]
+fn batch_filter_proposal() {
+    use ops::{Program, CodeTransaction, RawChange, Internable};
+    use compiler::parse_string;
+    let mut program = Program::new("batch");
+    let code = "search\n  [#n value]\n  value != 3\n  100 > value\nbind\n  [#kept value]\nend\n";
+    let blocks = parse_string(&mut program.state.interner, code, "batch.eve");
+    CodeTransaction::new().exec(&mut program, blocks, vec![]);
+    let node = Internable::String("test".to_string());
+    let mut changes = vec![];
+    for ix in 0..300 {
+        let n = Internable::Reference(format!("n|{}|", ix));
+        // strings never pass a numeric comparison, batched or not
+        let value = if ix % 3 == 0 { Internable::String(ix.to_string()) } else { Internable::from_number(ix as f32) };
+        changes.push(RawChange::new(n.clone(), Internable::String("tag".to_string()), Internable::String("n".to_string()), node.clone(), 1));
+        changes.push(RawChange::new(n, Internable::String("value".to_string()), value, node.clone(), 1));
+    }
+    program.annotated_transaction(changes, vec![]);
+    let tag = program.state.interner.string_id("tag");
+    let kept = program.state.interner.string_id("kept");
+    // 1 through 99 that aren't multiples of 3
+    assert_eq!(program.state.index.get(0, tag, kept).map_or(0, |iter| iter.count()), 66);
+}
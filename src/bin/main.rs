@@ -4,73 +4,172 @@ extern crate eve;
 extern crate time;
 
 extern crate clap;
-use clap::{Arg, App};
+use clap::{Arg, ArgMatches, App, SubCommand, AppSettings};
+
+extern crate term_painter;
+use term_painter::ToStyle;
+use term_painter::Color::*;
+
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
 
 use eve::paths::EvePaths;
-use eve::ops::{DebugMode, ProgramRunner, Persister};
-use eve::watchers::system::{SystemTimerWatcher, PanicWatcher};
+use eve::ops::{DebugMode, ProgramRunner, RunLoop, Interner, Program, CodeTransaction, QueryBudget};
+use eve::persister::Persister;
+use eve::check::{check_db, read_changes};
+use eve::compiler::{check_string, compile_string, eve_files, CompileOptions};
+use eve::bytecode::save_compiled_file;
+use eve::formatter::{format_source_with, FormatOptions};
+use eve::report::{bundle_report, DEFAULT_LOG_TAIL};
+use eve::redact::{export_db, ExportFormat, Redaction};
+use eve::scaffold::{find_template, new_project, TEMPLATES};
+use eve::lint::{lint_sources, diagnostics_to_json, LintConfig, Severity, RULES};
+use eve::tutorial::{builtin_lessons, lessons_in, Submission, Tutorial};
+use eve::watchers::system::{SystemTimerWatcher, PanicWatcher, EntityMergeWatcher, InspectorWatcher};
 use eve::watchers::console::{ConsoleWatcher, PrintDiffWatcher};
 use eve::watchers::file::FileWatcher;
+use eve::watchers::filewatch::FileWatchWatcher;
+use eve::watchers::sqlite::SqliteWatcher;
+#[cfg(feature = "db-postgres")]
+use eve::watchers::postgres::PostgresWatcher;
+use eve::watchers::process::ProcessWatcher;
+use eve::watchers::tcp::{TcpConnectWatcher, TcpListenWatcher};
+use eve::watchers::plugin::{load_plugin, PluginManifest};
 
 //-------------------------------------------------------------------------
-// Main
+// Arguments
 //-------------------------------------------------------------------------
 
-fn main() {
-    let matches = App::new("Eve")
-        .version("0.4")
-        .author("Kodowa Inc.")
-        .about("Creates an instance of the Eve server")
-        .arg(Arg::with_name("persist")
-             .long("persist")
-             .value_name("FILE")
-             .help("Sets the name for the database to load from and write to")
-             .takes_value(true))
-        .arg(Arg::with_name("library-path")
-             .short("L")
-             .long("library-path")
-             .value_name("PATH")
-             .help("Override default library path")
-             .takes_value(true))
-        .arg(Arg::with_name("EVE_FILES")
-             .help("The eve files and folders to load")
-             .required(true)
-             .multiple(true))
-        .arg(Arg::with_name("clean")
-             .short("C")
-             .long("Clean")
-             .help("Starts Eve with a clean database and no watchers (false)"))
-        .arg(Arg::with_name("debug")
-             .short("D")
-             .long("debug")
-             .value_name("MODE")
-             .help("Enable the specified debug mode. Options: ('compile')"))
-        .get_matches();
+// `eve run`, `eve watch` and plain `eve FILES...` all start a program the same way.
+fn program_args<'a, 'b>(app:App<'a, 'b>) -> App<'a, 'b> {
+    app.arg(Arg::with_name("persist")
+            .long("persist")
+            .value_name("FILE")
+            .help("Sets the name for the database to load from and write to")
+            .takes_value(true))
+       .arg(Arg::with_name("library-path")
+            .short("L")
+            .long("library-path")
+            .value_name("PATH")
+            .help("Override default library path")
+            .takes_value(true))
+       .arg(Arg::with_name("plugins")
+            .long("plugins")
+            .value_name("FILE")
+            .help("A JSON manifest of watcher plugins to load, e.g. {\"plugins\": [{\"path\": \"libslack.so\"}]}")
+            .takes_value(true))
+       .arg(Arg::with_name("EVE_FILES")
+            .help("The eve files and folders to load")
+            .required(true)
+            .multiple(true))
+       .arg(Arg::with_name("clean")
+            .short("C")
+            .long("Clean")
+            .help("Starts Eve with a clean database and no watchers (false)"))
+       .arg(Arg::with_name("debug")
+            .short("D")
+            .long("debug")
+            .value_name("MODE")
+            .help("Enable the specified debug mode. Options: ('parse', 'unify', 'compile', 'runtime')"))
+}
+
+fn source_args<'a, 'b>(app:App<'a, 'b>, required:bool) -> App<'a, 'b> {
+    app.arg(Arg::with_name("EVE_FILES")
+            .help("The eve files and folders to use")
+            .required(required)
+            .multiple(true))
+}
+
+fn source_paths(matches:&ArgMatches) -> Vec<String> {
+    let mut paths = vec![];
+    for path in matches.values_of("EVE_FILES").map_or(vec![], |files| files.collect()) {
+        paths.extend(eve_files(path));
+    }
+    paths
+}
+
+fn read_source(path:&str) -> String {
+    let mut contents = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        Ok(_) => contents,
+        Err(why) => {
+            println!("{} Unable to read {}: {}", BrightRed.paint("Error:"), path, why);
+            process::exit(1);
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+// Run
+//-------------------------------------------------------------------------
 
+// With `reload`, the program's files are hot-reloaded whenever they change.
+fn run(matches:&ArgMatches, reload:bool) -> RunLoop {
     let clean = matches.is_present("clean");
 
     let eve_paths = EvePaths::new(clean,
                                   matches.values_of("EVE_FILES").map_or(vec![], |files| files.collect()),
                                   matches.value_of("server-file").map_or(vec![], |file| vec![file]),
                                   matches.value_of("persist"),
-                                  matches.value_of("libraries-path"),
+                                  matches.value_of("library-path"),
                                   matches.value_of("programs-path"));
 
     let mut runner = ProgramRunner::new("main");
     matches.value_of("debug").map(|mode_name| runner.debug(match mode_name {
+        "parse" => DebugMode::Parse,
+        "unify" => DebugMode::Unify,
         "compile" => DebugMode::Compile,
+        "runtime" => DebugMode::Runtime,
         _ => panic!("Unknown debug mode '{:?}'.", mode_name)
     }));
 
     let outgoing = runner.program.outgoing.clone();
     if !clean {
         runner.program.attach(Box::new(SystemTimerWatcher::new(outgoing.clone())));
+        runner.program.attach(Box::new(EntityMergeWatcher::new(outgoing.clone())));
+        runner.program.attach(Box::new(InspectorWatcher::new(outgoing.clone())));
         runner.program.attach(Box::new(FileWatcher::new(outgoing.clone())));
+        if !reload {
+            runner.program.attach(Box::new(FileWatchWatcher::new(outgoing.clone())));
+        }
+        runner.program.attach(Box::new(SqliteWatcher::new(outgoing.clone())));
+        #[cfg(feature = "db-postgres")]
+        runner.program.attach(Box::new(PostgresWatcher::new(outgoing.clone())));
+        runner.program.attach(Box::new(ProcessWatcher::new(outgoing.clone())));
+        runner.program.attach(Box::new(TcpConnectWatcher::new(outgoing.clone())));
+        runner.program.attach(Box::new(TcpListenWatcher::new(outgoing.clone())));
         runner.program.attach(Box::new(ConsoleWatcher::new()));
         runner.program.attach(Box::new(PrintDiffWatcher::new()));
         runner.program.attach(Box::new(PanicWatcher::new()));
     }
 
+    if reload {
+        match FileWatchWatcher::with_reload(outgoing.clone(), &eve_paths.files) {
+            Ok(watcher) => runner.program.attach(Box::new(watcher)),
+            Err(why) => {
+                println!("{} {}", BrightRed.paint("Error:"), why);
+                process::exit(1);
+            }
+        }
+        println!("{} {}", BrightCyan.paint("Watching:"), eve_paths.files.join(", "));
+    }
+
+    if let Some(path) = matches.value_of("plugins") {
+        let loaded = PluginManifest::from_file(path).and_then(|manifest| {
+            for spec in manifest.plugins.iter() {
+                runner.program.attach(load_plugin(spec, outgoing.clone())?);
+            }
+            Ok(())
+        });
+        if let Err(why) = loaded {
+            println!("{} {}", BrightRed.paint("Error:"), why);
+            process::exit(1);
+        }
+    }
+
     if let Some(persist_file) = eve_paths.persist() {
         let mut persister = Persister::new(persist_file);
         persister.load(persist_file);
@@ -84,6 +183,524 @@ fn main() {
         runner.load(file);
     }
 
-    let running = runner.run();
-    running.wait();
+    runner.run()
+}
+
+//-------------------------------------------------------------------------
+// Watch
+//-------------------------------------------------------------------------
+
+// Runs the program and hot-reloads any of its files that change, so only the blocks
+// that were edited get swapped out.
+fn watch(matches:&ArgMatches) {
+    run(matches, true).wait();
+}
+
+//-------------------------------------------------------------------------
+// Check
+//-------------------------------------------------------------------------
+
+fn check(matches:&ArgMatches) {
+    let mut ok = true;
+    if let Some(db) = matches.value_of("db") {
+        let report = check_db(db, matches.is_present("repair"));
+        println!("Checked {} changes", report.changes);
+        for repaired in report.repaired.iter() {
+            println!("  repaired: {}", repaired);
+        }
+        for problem in report.problems.iter() {
+            println!("  problem: {}", problem);
+        }
+        ok = report.is_ok();
+    }
+    let mut interner = Interner::new();
+    for path in source_paths(matches) {
+        let (blocks, errors) = check_string(&mut interner, &read_source(&path), &path);
+        if errors > 0 {
+            println!("{} {} ({} blocks, {} errors)", BrightRed.paint("Failed:"), path, blocks, errors);
+            ok = false;
+        } else {
+            println!("{} {} ({} blocks)", BrightGreen.paint("Ok:"), path, blocks);
+        }
+    }
+    if !ok {
+        process::exit(1);
+    }
+}
+
+//-------------------------------------------------------------------------
+// Compile
+//-------------------------------------------------------------------------
+
+// Everything has to compile cleanly, a compiled program that's missing blocks would be
+// a confusing thing to ship.
+fn compile(matches:&ArgMatches) {
+    let mut interner = Interner::new();
+    let mut blocks = vec![];
+    let mut ok = true;
+    for path in source_paths(matches) {
+        let (compiled, errors) = compile_string(&mut interner, &read_source(&path), &path, &CompileOptions::default());
+        if errors > 0 {
+            println!("{} {} ({} errors)", BrightRed.paint("Failed:"), path, errors);
+            ok = false;
+        }
+        blocks.extend(compiled);
+    }
+    if !ok {
+        process::exit(1);
+    }
+    let output = matches.value_of("output").unwrap();
+    if let Err(why) = save_compiled_file(&interner, &blocks, output) {
+        println!("{} Unable to write {}: {}", BrightRed.paint("Error:"), output, why);
+        process::exit(1);
+    }
+    println!("{} {} ({} blocks)", BrightGreen.paint("Compiled:"), output, blocks.len());
+}
+
+//-------------------------------------------------------------------------
+// Fmt
+//-------------------------------------------------------------------------
+
+fn fmt(matches:&ArgMatches) {
+    let options = FormatOptions { sort_attributes: matches.is_present("sort-attributes"), ..FormatOptions::default() };
+    let mut unformatted = false;
+    for path in source_paths(matches) {
+        let source = read_source(&path);
+        let formatted = format_source_with(&source, &options);
+        if matches.is_present("check") {
+            if formatted != source {
+                println!("{} {}", BrightYellow.paint("Unformatted:"), path);
+                unformatted = true;
+            }
+        } else if matches.is_present("write") {
+            if formatted != source {
+                if let Err(why) = File::create(&path).and_then(|mut file| file.write_all(formatted.as_bytes())) {
+                    println!("{} Unable to write {}: {}", BrightRed.paint("Error:"), path, why);
+                    process::exit(1);
+                }
+                println!("{} {}", BrightCyan.paint("Formatted:"), path);
+            }
+        } else {
+            print!("{}", formatted);
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+//-------------------------------------------------------------------------
+// Lint
+//-------------------------------------------------------------------------
+
+fn lint(matches:&ArgMatches) {
+    let config_path = matches.value_of("config").map(|path| path.to_string())
+        .or_else(|| if PathBuf::from("eve-lint.json").is_file() { Some("eve-lint.json".to_string()) } else { None });
+    let config = match config_path {
+        Some(path) => match LintConfig::from_file(&path) {
+            Ok(config) => config,
+            Err(why) => {
+                println!("{} Unable to read the lint config {}: {}", BrightRed.paint("Error:"), path, why);
+                process::exit(1);
+            }
+        },
+        None => LintConfig::default(),
+    };
+    if matches.is_present("rules") {
+        for rule in RULES.iter() {
+            println!("{} ({}) - {}", rule.name, config.severity(rule.name), rule.description);
+        }
+        return;
+    }
+    let sources:Vec<(String, String)> = source_paths(matches).into_iter().map(|path| {
+        let source = read_source(&path);
+        (path, source)
+    }).collect();
+    let diagnostics = lint_sources(&sources, &config);
+    if matches.is_present("json") {
+        println!("{}", diagnostics_to_json(&diagnostics));
+    } else {
+        for diagnostic in diagnostics.iter() {
+            let label = match diagnostic.severity {
+                Severity::Error => BrightRed.paint("Error:"),
+                Severity::Warning => BrightYellow.paint("Warning:"),
+                _ => BrightCyan.paint("Info:"),
+            };
+            println!("{} {}:{}:{} {} [{}]", label, diagnostic.path, diagnostic.line, diagnostic.column, diagnostic.message, diagnostic.rule);
+        }
+    }
+    if diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error) {
+        process::exit(1);
+    }
+}
+
+//-------------------------------------------------------------------------
+// Bundle report
+//-------------------------------------------------------------------------
+
+fn redaction(matches:&ArgMatches) -> Redaction {
+    match matches.value_of("redact") {
+        Some(path) => match Redaction::from_file(path) {
+            Ok(redaction) => redaction,
+            Err(why) => {
+                println!("{} Unable to read redactions from {}: {}", BrightRed.paint("Error:"), path, why);
+                process::exit(1);
+            }
+        },
+        None => Redaction::none(),
+    }
+}
+
+fn bundle(matches:&ArgMatches) {
+    let redaction = redaction(matches);
+    let tail = matches.value_of("log-tail").and_then(|tail| tail.parse().ok()).unwrap_or(DEFAULT_LOG_TAIL);
+    let output = matches.value_of("output").unwrap_or("eve-report.json");
+    let written = bundle_report(&source_paths(matches), matches.value_of("db"), &redaction, tail)
+        .and_then(|report| report.write(output));
+    match written {
+        Ok(_) => println!("{} {}", BrightCyan.paint("Wrote:"), output),
+        Err(why) => {
+            println!("{} Unable to bundle a report: {}", BrightRed.paint("Error:"), why);
+            process::exit(1);
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+// Export
+//-------------------------------------------------------------------------
+
+fn export(matches:&ArgMatches) {
+    let redaction = redaction(matches);
+    let db = matches.value_of("db").unwrap();
+    let format = if matches.is_present("json") { ExportFormat::Json } else { ExportFormat::Snapshot };
+    let output = matches.value_of("output").unwrap();
+    match export_db(db, output, format, &redaction) {
+        Ok(facts) => println!("{} {} facts to {}", BrightCyan.paint("Exported:"), facts, output),
+        Err(why) => {
+            println!("{} Unable to export {}: {}", BrightRed.paint("Error:"), db, why);
+            process::exit(1);
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+// New
+//-------------------------------------------------------------------------
+
+fn new(matches:&ArgMatches) {
+    let name = matches.value_of("TEMPLATE").unwrap();
+    let template = match find_template(name) {
+        Some(template) => template,
+        None => {
+            println!("{} There's no `{}` template. The templates are:", BrightRed.paint("Error:"), name);
+            for template in TEMPLATES.iter() {
+                println!("  {} - {}", template.name, template.description);
+            }
+            process::exit(1);
+        }
+    };
+    let dir = PathBuf::from(matches.value_of("DIR").unwrap_or(name));
+    match new_project(template, &dir) {
+        Ok(written) => {
+            for path in written {
+                println!("{} {}", BrightCyan.paint("Created:"), path.display());
+            }
+            println!("See {} for how to run it.", dir.join("readme.md").display());
+        }
+        Err(why) => {
+            println!("{} Unable to create {}: {}", BrightRed.paint("Error:"), dir.display(), why);
+            process::exit(1);
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+// Tutorial
+//-------------------------------------------------------------------------
+
+fn show_lesson(tutorial:&Tutorial) {
+    if let Some(lesson) = tutorial.lesson() {
+        let (number, total) = tutorial.progress();
+        println!("\n{} {}/{}: {}\n", BrightCyan.paint("Lesson"), number, total, lesson.title);
+        println!("{}\n", lesson.text);
+        println!("Type a block and finish it with `end`. `:lesson` shows the lesson again, `:skip` moves on and `:quit` stops.");
+    }
+}
+
+fn tutorial(matches:&ArgMatches) {
+    let lessons = match matches.value_of("DIR") {
+        Some(dir) => match lessons_in(dir) {
+            Ok(lessons) => lessons,
+            Err(why) => {
+                println!("{} Unable to read the lessons in {}: {}", BrightRed.paint("Error:"), dir, why);
+                process::exit(1);
+            }
+        },
+        None => builtin_lessons(),
+    };
+    let mut tutorial = Tutorial::new(lessons);
+    show_lesson(&tutorial);
+    let stdin = io::stdin();
+    let mut code = String::new();
+    print!("> ");
+    io::stdout().flush().unwrap();
+    for line in stdin.lock().lines() {
+        let line = line.unwrap();
+        match line.trim() {
+            ":quit" => return,
+            ":lesson" => show_lesson(&tutorial),
+            ":skip" => {
+                code.clear();
+                tutorial.next_lesson();
+                show_lesson(&tutorial);
+            }
+            trimmed => {
+                code.push_str(&line);
+                code.push('\n');
+                if trimmed == "end" {
+                    match tutorial.submit(&code) {
+                        Submission::Errors(_) => println!("{} Fix the errors above and try again.", BrightYellow.paint("Not quite:")),
+                        Submission::Added => println!("{} The block was added, but the lesson isn't done yet.", BrightYellow.paint("Added:")),
+                        Submission::Passed => {
+                            println!("{}", BrightGreen.paint("Passed!"));
+                            tutorial.next_lesson();
+                            show_lesson(&tutorial);
+                        }
+                    }
+                    code.clear();
+                }
+            }
+        }
+        if tutorial.finished() {
+            println!("\n{} That's every lesson.", BrightGreen.paint("Done!"));
+            return;
+        }
+        print!("{}", if code.is_empty() { "> " } else { "| " });
+        io::stdout().flush().unwrap();
+    }
+}
+
+//-------------------------------------------------------------------------
+// Repl
+//-------------------------------------------------------------------------
+
+// Loads the program and its db without running any watchers, then answers queries
+// typed at it, each a search block finishing with `project (...)` and `end`.
+fn repl(matches:&ArgMatches) {
+    let mut program = Program::new("repl");
+    let options = program.compile_options();
+    let mut blocks = vec![];
+    for path in source_paths(matches) {
+        let source = read_source(&path);
+        let (compiled, _) = compile_string(&mut program.state.interner, &source, &path, &options);
+        blocks.extend(compiled);
+    }
+    let mut txn = CodeTransaction::new();
+    if let Some(db) = matches.value_of("db") {
+        match read_changes(db) {
+            Ok((changes, _)) => {
+                for change in changes {
+                    txn.input_change(change.to_change(&mut program.state.interner));
+                }
+            }
+            Err(why) => {
+                println!("{} Unable to read {}: {}", BrightRed.paint("Error:"), db, why);
+                process::exit(1);
+            }
+        }
+    }
+    txn.exec(&mut program, blocks, vec![]);
+
+    let mut budget = QueryBudget::unlimited();
+    if let Some(ms) = matches.value_of("time").and_then(|ms| ms.parse().ok()) {
+        budget = budget.time(Duration::from_millis(ms));
+    }
+    if let Some(rows) = matches.value_of("rows").and_then(|rows| rows.parse().ok()) {
+        budget = budget.rows(rows);
+    }
+
+    println!("Type a query and finish it with `end`, e.g. `search [#person name] project (name) end`. `:quit` stops.");
+    let stdin = io::stdin();
+    let mut code = String::new();
+    print!("> ");
+    io::stdout().flush().unwrap();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let trimmed = line.trim().to_string();
+        if trimmed == ":quit" { return; }
+        code.push_str(&line);
+        code.push('\n');
+        if trimmed == "end" || trimmed.ends_with(" end") {
+            match program.query(&code, budget) {
+                Ok(result) => {
+                    for row in result.rows.iter() {
+                        let values:Vec<String> = row.iter().map(|value| value.print()).collect();
+                        println!("{}", values.join("\t"));
+                    }
+                    if result.complete {
+                        println!("{}", BrightCyan.paint(format!("{} rows", result.rows.len())));
+                    } else {
+                        println!("{}", BrightYellow.paint(format!("{} rows before the budget ran out", result.rows.len())));
+                    }
+                }
+                Err(why) => println!("{} {}", BrightRed.paint("Error:"), why),
+            }
+            code.clear();
+        }
+        print!("{}", if code.is_empty() { "> " } else { "| " });
+        io::stdout().flush().unwrap();
+    }
+}
+
+//-------------------------------------------------------------------------
+// Main
+//-------------------------------------------------------------------------
+
+fn main() {
+    let app = App::new("Eve")
+        .version("0.4")
+        .author("Kodowa Inc.")
+        .about("Runs, checks and formats Eve programs")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(program_args(SubCommand::with_name("run")
+                    .about("Runs a program with the standard watchers attached")))
+        .subcommand(program_args(SubCommand::with_name("watch")
+                    .about("Runs a program and hot-reloads its files when they change")))
+        .subcommand(source_args(SubCommand::with_name("check")
+                    .about("Parses and compiles programs without running them, and checks persisted databases for damage")
+                    .arg(Arg::with_name("db")
+                         .long("db")
+                         .value_name("FILE")
+                         .help("A database file to check for damage left by crashes or disk issues")
+                         .takes_value(true))
+                    .arg(Arg::with_name("repair")
+                         .long("repair")
+                         .help("Fixes what can be fixed safely in the database instead of only reporting it")), false))
+        .subcommand(source_args(SubCommand::with_name("compile")
+                    .about("Compiles programs into one file that can be run without parsing, e.g. `eve compile prog.eve -o prog.evb`")
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .value_name("FILE")
+                         .help("Where to write the compiled program")
+                         .required(true)
+                         .takes_value(true)), true))
+        .subcommand(source_args(SubCommand::with_name("lint")
+                    .about("Looks for blocks that compile but probably don't do what was meant")
+                    .arg(Arg::with_name("config")
+                         .long("config")
+                         .value_name("FILE")
+                         .help("Rule severities to use instead of ./eve-lint.json, e.g. {\"rules\": {\"unread-tag\": \"error\"}}")
+                         .takes_value(true))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Prints the problems as JSON for editors and other tools"))
+                    .arg(Arg::with_name("rules")
+                         .long("rules")
+                         .help("Lists the rules and their severities instead of linting")), false))
+        .subcommand(source_args(SubCommand::with_name("fmt")
+                    .about("Prints programs in the canonical format")
+                    .arg(Arg::with_name("write")
+                         .short("w")
+                         .long("write")
+                         .help("Rewrites the files in place instead of printing them"))
+                    .arg(Arg::with_name("check")
+                         .long("check")
+                         .help("Lists the files that aren't formatted and fails if there are any"))
+                    .arg(Arg::with_name("sort-attributes")
+                         .long("sort-attributes")
+                         .help("Puts the tag and attributes of every record in alphabetical order")), true))
+        .subcommand(source_args(SubCommand::with_name("bundle-report")
+                    .about("Packages a program's sources, data and engine stats into one file to attach to bug reports")
+                    .arg(Arg::with_name("db")
+                         .long("db")
+                         .value_name("FILE")
+                         .help("The program's database file")
+                         .takes_value(true))
+                    .arg(Arg::with_name("redact")
+                         .long("redact")
+                         .value_name("FILE")
+                         .help("A JSON file saying how to redact each attribute, e.g. {\"attributes\": {\"email\": \"hash\"}}")
+                         .takes_value(true))
+                    .arg(Arg::with_name("log-tail")
+                         .long("log-tail")
+                         .value_name("CHANGES")
+                         .help("How many of the most recent logged changes to include (1000)")
+                         .takes_value(true))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .value_name("FILE")
+                         .help("Where to write the report (eve-report.json)")
+                         .takes_value(true)), false))
+        .subcommand(SubCommand::with_name("new")
+                    .about("Creates a project from a template, e.g. `eve new todo-app`")
+                    .arg(Arg::with_name("TEMPLATE")
+                         .help("The template to start from")
+                         .required(true))
+                    .arg(Arg::with_name("DIR")
+                         .help("Where to create the project (a folder named after the template)")))
+        .subcommand(SubCommand::with_name("tutorial")
+                    .about("Walks through the basics of Eve one lesson at a time")
+                    .arg(Arg::with_name("DIR")
+                         .help("A folder of lessons to use instead of the built-in ones")))
+        .subcommand(source_args(SubCommand::with_name("repl")
+                    .about("Loads a program and its database and answers queries typed at it")
+                    .arg(Arg::with_name("db")
+                         .long("db")
+                         .value_name("FILE")
+                         .help("A database file to load the program's facts from")
+                         .takes_value(true))
+                    .arg(Arg::with_name("time")
+                         .long("time")
+                         .value_name("MS")
+                         .help("How long a query can run before it's cut off with the rows found so far")
+                         .takes_value(true))
+                    .arg(Arg::with_name("rows")
+                         .long("rows")
+                         .value_name("ROWS")
+                         .help("How many rows a query can return before it's cut off")
+                         .takes_value(true)), false))
+        .subcommand(SubCommand::with_name("export")
+                    .about("Writes out a database's facts with sensitive values hashed, masked or dropped")
+                    .arg(Arg::with_name("db")
+                         .value_name("DB")
+                         .help("The database file to export")
+                         .required(true))
+                    .arg(Arg::with_name("redact")
+                         .long("redact")
+                         .value_name("FILE")
+                         .help("A JSON file saying how to redact each attribute, e.g. {\"attributes\": {\"email\": \"hash\", \"ssn\": \"drop\"}}")
+                         .takes_value(true))
+                    .arg(Arg::with_name("json")
+                         .long("json")
+                         .help("Writes the facts as JSON instead of a snapshot that can be loaded as a db"))
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .value_name("FILE")
+                         .help("Where to write the export")
+                         .required(true)
+                         .takes_value(true)));
+    let matches = program_args(app).get_matches();
+
+    match matches.subcommand() {
+        ("run", Some(sub)) => run(sub, false).wait(),
+        ("watch", Some(sub)) => watch(sub),
+        ("check", Some(sub)) => check(sub),
+        ("compile", Some(sub)) => compile(sub),
+        ("fmt", Some(sub)) => fmt(sub),
+        ("lint", Some(sub)) => lint(sub),
+        ("bundle-report", Some(sub)) => bundle(sub),
+        ("export", Some(sub)) => export(sub),
+        ("new", Some(sub)) => new(sub),
+        ("tutorial", Some(sub)) => tutorial(sub),
+        ("repl", Some(sub)) => repl(sub),
+        // `eve FILES...` is the same as `eve run FILES...`
+        _ => run(&matches, false).wait(),
+    }
 }
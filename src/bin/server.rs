@@ -1,6 +1,6 @@
 extern {}
 
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 extern crate clap;
 use clap::{Arg, App};
@@ -17,20 +17,23 @@ use serde_json::{Error};
 
 use std::sync::mpsc::{self, Sender};
 
-extern crate notify;
-use notify::{RecommendedWatcher, Watcher, RecursiveMode, DebouncedEvent};
-use std::time::Duration;
 
 extern crate time;
 
 extern crate eve;
 use eve::paths::EvePaths;
-use eve::ops::{ProgramRunner, RunLoop, RunLoopMessage, RawChange, Internable, Persister, JSONInternable};
-use eve::watchers::system::{SystemTimerWatcher, PanicWatcher};
+use eve::ops::{ProgramRunner, RunLoop, RunLoopMessage, RawChange, Internable, JSONInternable};
+use eve::persister::Persister;
+use eve::admin::{AdminCommand, AdminReply};
+use eve::watchers::system::{SystemTimerWatcher, PanicWatcher, EntityMergeWatcher};
 use eve::watchers::compiler::{CompilerWatcher};
 use eve::watchers::textcompiler::{RawTextCompilerWatcher};
 use eve::watchers::console::{ConsoleWatcher};
 use eve::watchers::file::{FileWatcher};
+use eve::watchers::filewatch::{FileWatchWatcher};
+use eve::watchers::sqlite::{SqliteWatcher};
+#[cfg(feature = "db-postgres")]
+use eve::watchers::postgres::{PostgresWatcher};
 use eve::watchers::editor::EditorWatcher;
 use eve::watchers::remote::{Router, RouterMessage, RemoteWatcher};
 use eve::watchers::websocket::WebsocketClientWatcher;
@@ -39,13 +42,15 @@ extern crate iron;
 extern crate staticfile;
 extern crate mount;
 
-use iron::{Iron, Chain, status, Request, Response, IronResult, IronError, AfterMiddleware};
+use iron::{Iron, Chain, status, Request, Response, IronResult, IronError, AfterMiddleware, Handler as IronHandler};
+use iron::method::Method;
+use iron::headers::{Authorization, Bearer, ContentType};
+use std::io::Read;
 use staticfile::Static;
 use mount::Mount;
 use std::thread;
 use std::sync::{Arc, Mutex};
 use std::ops::Deref;
-use std::collections::HashSet;
 
 extern crate term_painter;
 use self::term_painter::ToStyle;
@@ -78,9 +83,16 @@ impl ClientHandler {
         router.lock().expect("ERROR: Failed to lock router: Cannot register new client.").register(&client_name, outgoing.clone());
         if !eve_flags.clean {
             runner.program.attach(Box::new(SystemTimerWatcher::new(outgoing.clone())));
+            runner.program.attach(Box::new(EntityMergeWatcher::new(outgoing.clone())));
             runner.program.attach(Box::new(CompilerWatcher::new(outgoing.clone(), false)));
             runner.program.attach(Box::new(RawTextCompilerWatcher::new(outgoing.clone())));
             runner.program.attach(Box::new(FileWatcher::new(outgoing.clone())));
+            if !eve_flags.watch {
+                runner.program.attach(Box::new(FileWatchWatcher::new(outgoing.clone())));
+            }
+            runner.program.attach(Box::new(SqliteWatcher::new(outgoing.clone())));
+            #[cfg(feature = "db-postgres")]
+            runner.program.attach(Box::new(PostgresWatcher::new(outgoing.clone())));
             runner.program.attach(Box::new(WebsocketClientWatcher::new(out.clone(), client_name)));
             runner.program.attach(Box::new(ConsoleWatcher::new()));
             runner.program.attach(Box::new(PanicWatcher::new()));
@@ -91,6 +103,16 @@ impl ClientHandler {
             }
         }
 
+        if eve_flags.watch {
+            println!("Starting file watcher!");
+            let mut sources:Vec<&str> = eve_paths.libraries().into_iter().collect();
+            sources.extend(eve_paths.files.iter());
+            match FileWatchWatcher::with_reload(outgoing.clone(), &sources) {
+                Ok(watcher) => runner.program.attach(Box::new(watcher)),
+                Err(why) => println!("{} Unable to monitor files for hot-reloading due to error:\n{}", BrightRed.paint("Error:"), why),
+            }
+        }
+
         if let Some(path) = eve_paths.libraries() {
             runner.load(path);
         }
@@ -100,94 +122,8 @@ impl ClientHandler {
 
         let running = runner.run();
 
-        if eve_flags.watch {
-            println!("Starting file watcher!");
-            ClientHandler::make_file_notifier(eve_paths, &running);
-        }
-
         ClientHandler {out, running, client_name: client_name.to_owned(), router, router_channel }
     }
-
-    fn make_file_notifier(eve_paths:&EvePaths, run_loop:&RunLoop) {
-        println!("WARN: @TODO: Make this die when the client DC's!");
-        let client_channel = run_loop.channel();
-        let files:Vec<String> = eve_paths.files.iter().map(|f| f.to_string()).collect();
-        let libraries = eve_paths.libraries().map(|s| s.to_owned());
-
-        thread::Builder::new().name("client file watcher".to_owned()).spawn(move || {
-            let (outgoing, incoming) = mpsc::channel();
-            let mut watcher:RecommendedWatcher = match Watcher::new(outgoing, Duration::from_secs(1)) {
-                Ok(w) => w,
-                Err(e) => {
-                    panic!(println!("{} Unable to monitor files for hot-reloading due to error:\n{}", BrightRed.paint("Fatal Error:"), e));
-                }
-            };
-
-            if let Some(path) = libraries {
-                match watcher.watch(path, RecursiveMode::Recursive) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        panic!(println!("{} Could not watch standard library source due to error:\n{}", BrightRed.paint("Error:"), e));
-                    }
-                };
-            }
-            for file in files.iter() {
-                match watcher.watch(file, RecursiveMode::Recursive) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        println!("{} Could not watch file {} due to error:\n{}", BrightRed.paint("Error:"), file, e);
-                        panic!();
-                    }
-                }
-            }
-
-            loop {
-                let mut dirty:HashSet<PathBuf> = HashSet::new();
-                match incoming.recv() {
-                    Ok(event) => {
-                        match event {
-                            DebouncedEvent::Error(err, ..) => {
-                                println!("{} {:?}! Closing client file watcher...", BrightRed.paint("Error:"), err);
-                                break;
-                            },
-                            DebouncedEvent::NoticeRemove(path) |
-                            DebouncedEvent::NoticeWrite(path) => {
-                                let should_reload = match path.extension() {
-                                    Some(ext) => ext == "eve" || ext == "eve.md",
-                                    _ => false
-                                };
-                                if should_reload {
-                                    dirty.insert(path);
-                                }
-                            },
-                            DebouncedEvent::Create(path) |
-                            DebouncedEvent::Chmod(path) |
-                            DebouncedEvent::Remove(path) |
-                            DebouncedEvent::Write(path) => {
-                                let should_reload = match path.extension() {
-                                    Some(ext) => ext == "eve" || ext == "eve.md",
-                                    _ => false
-                                };
-                                if should_reload {
-                                    dirty.insert(path);
-                                    if let Err(_) = client_channel.send(RunLoopMessage::Reload(dirty.clone())) {
-                                        println!("Closing client file watcher.");
-                                        break;
-                                    }
-                                    dirty.clear();
-                                }
-                            },
-                            DebouncedEvent::Rename(..) | // (old, new) (gotta pass in both)
-                            DebouncedEvent::Rescan => {
-                                unimplemented!();
-                            }
-                        };
-                    },
-                    Err(err) => println!("ERROR: {:?}", err)
-                }
-            }
-        });
-    }
 }
 
 impl Handler for ClientHandler {
@@ -261,7 +197,99 @@ fn http_server(address: String) -> std::thread::JoinHandle<()> {
     })
 }
 
-fn websocket_server(address: String, eve_paths:&EvePaths, eve_flags:&EveFlags) {
+//-------------------------------------------------------------------------
+// Admin API
+//-------------------------------------------------------------------------
+
+// A small control surface for the server program. Every request needs an
+// `Authorization: Bearer <token>` header matching --admin-token. Block names and
+// file paths are sent as the request body.
+//
+//   GET  /blocks          list blocks and whether they're enabled
+//   POST /blocks/enable   re-enable a disabled block
+//   POST /blocks/disable  disable a block, retracting what it derived
+//   POST /load            load (or reload) an eve file
+//   POST /snapshot        rewrite the db with just the facts currently committed
+//   GET  /stats           block, watcher and fact counts, plus the size of each index
+//   POST /compact         drop fully retracted keys from the intermediate indexes
+
+struct AdminHandler {
+    token: String,
+    channel: Mutex<Sender<RunLoopMessage>>,
+}
+
+impl AdminHandler {
+    fn command(req: &mut Request) -> Option<AdminCommand> {
+        let path = req.url.path().join("/");
+        let mut body = String::new();
+        if let Err(_) = req.body.read_to_string(&mut body) {
+            return None;
+        }
+        let body = body.trim().to_string();
+        match (req.method.clone(), path.trim_right_matches('/')) {
+            (Method::Get, "blocks") => Some(AdminCommand::Blocks),
+            (Method::Post, "blocks/enable") => Some(AdminCommand::Enable(body)),
+            (Method::Post, "blocks/disable") => Some(AdminCommand::Disable(body)),
+            (Method::Post, "load") => Some(AdminCommand::Load(body)),
+            (Method::Post, "snapshot") => Some(AdminCommand::Snapshot),
+            (Method::Get, "stats") => Some(AdminCommand::Stats),
+            (Method::Post, "compact") => Some(AdminCommand::Compact),
+            _ => None,
+        }
+    }
+}
+
+// Compares every byte no matter where the first mismatch is, so how long a
+// request takes doesn't give away how much of the token it got right.
+fn same_token(given: &str, token: &str) -> bool {
+    let (given, token) = (given.as_bytes(), token.as_bytes());
+    let mut diff = given.len() ^ token.len();
+    for (ix, byte) in token.iter().enumerate() {
+        diff |= (given.get(ix).cloned().unwrap_or(0) ^ byte) as usize;
+    }
+    diff == 0
+}
+
+impl IronHandler for AdminHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let authorized = match req.headers.get::<Authorization<Bearer>>() {
+            Some(&Authorization(Bearer { ref token })) => same_token(token, &self.token),
+            None => false,
+        };
+        if !authorized {
+            return Ok(Response::with((status::Unauthorized, "Missing or invalid admin token")));
+        }
+        let command = match AdminHandler::command(req) {
+            Some(command) => command,
+            None => return Ok(Response::with((status::NotFound, "Unknown admin command"))),
+        };
+        let (reply, result) = mpsc::channel();
+        if let Err(_) = self.channel.lock().unwrap().send(RunLoopMessage::Admin(command, reply)) {
+            return Ok(Response::with((status::ServiceUnavailable, "The server program has stopped")));
+        }
+        let (code, reply) = match result.recv() {
+            Ok(reply @ AdminReply::Error(_)) => (status::BadRequest, reply),
+            Ok(reply) => (status::Ok, reply),
+            Err(_) => return Ok(Response::with((status::ServiceUnavailable, "The server program has stopped"))),
+        };
+        let mut response = Response::with((code, serde_json::to_string(&reply).unwrap()));
+        response.headers.set(ContentType::json());
+        Ok(response)
+    }
+}
+
+fn admin_server(address: String, token: String, channel: Sender<RunLoopMessage>) -> std::thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let handler = AdminHandler { token, channel: Mutex::new(channel) };
+        println!("{} Admin Server at {}... ", BrightGreen.paint("Starting:"), address);
+        match Iron::new(handler).http(&address) {
+            Ok(_) => {},
+            Err(why) => println!("{} Failed to start Admin Server: {}", BrightRed.paint("Error:"), why),
+        };
+    })
+}
+
+fn websocket_server(address: String, eve_paths:&EvePaths, eve_flags:&EveFlags, admin: Option<(String, String)>) {
     println!("{} Websocket Server at {}... ", BrightGreen.paint("Starting:"), address);
 
     // create a server program
@@ -272,6 +300,7 @@ fn websocket_server(address: String, eve_paths:&EvePaths, eve_flags:&EveFlags) {
 
     if !eve_flags.clean {
         runner.program.attach(Box::new(SystemTimerWatcher::new(outgoing.clone())));
+        runner.program.attach(Box::new(EntityMergeWatcher::new(outgoing.clone())));
         runner.program.attach(Box::new(CompilerWatcher::new(outgoing.clone(), false)));
         runner.program.attach(Box::new(RawTextCompilerWatcher::new(outgoing)));
         runner.program.attach(Box::new(ConsoleWatcher::new()));
@@ -289,7 +318,10 @@ fn websocket_server(address: String, eve_paths:&EvePaths, eve_flags:&EveFlags) {
         runner.load(file);
     }
 
-    runner.run();
+    let running = runner.run();
+    if let Some((admin_address, token)) = admin {
+        admin_server(admin_address, token, running.channel());
+    }
     let mut ix = 0;
 
     match listen(address, |out| {
@@ -364,6 +396,16 @@ fn main() {
              .value_name("ADDRESS")
              .help("Sets the address of the server (127.0.0.1)")
              .takes_value(true))
+        .arg(Arg::with_name("admin-token")
+             .long("admin-token")
+             .value_name("TOKEN")
+             .help("Enables the admin API, requiring this bearer token on every request")
+             .takes_value(true))
+        .arg(Arg::with_name("admin-port")
+             .long("admin-port")
+             .value_name("PORT")
+             .help("Sets the port for the admin API (8082)")
+             .takes_value(true))
         .arg(Arg::with_name("clean")
              .short("C")
              .long("clean")
@@ -388,7 +430,9 @@ fn main() {
     let address = matches.value_of("address").unwrap_or("127.0.0.1");
     let http_address = format!("{}:{}",address,hport);
     let websocket_address = format!("{}:{}",address,wport);
+    let aport = matches.value_of("admin-port").unwrap_or("8082");
+    let admin = matches.value_of("admin-token").map(|token| (format!("{}:{}",address,aport), token.to_string()));
 
     http_server(http_address);
-    websocket_server(websocket_address, &eve_paths, &eve_flags);
+    websocket_server(websocket_address, &eve_paths, &eve_flags, admin);
 }
@@ -0,0 +1,340 @@
+//-------------------------------------------------------------------------
+// Compiled programs
+//-------------------------------------------------------------------------
+
+// `eve compile prog.eve -o prog.evb` writes a program's blocks out already compiled, so
+// it can be loaded where the parser isn't wanted, e.g. embedded or WASM builds. A
+// compiled file is `EVEB`, the format version as a little-endian u32, and then the
+// bincode of a `CompiledProgram`.
+//
+// Interned ids only mean something to the interner that made them, so the values the
+// blocks use are written out once in a table and fields point into it. Functions are
+// written by name and looked up again on load, which means custom functions have to be
+// registered on the program before it loads anything that uses them.
+
+extern crate bincode;
+
+use compiler::{CustomFunctions, FunctionKind};
+use ops::{Block, BlockMetadata, Constraint, Field, Internable, Interned, Interner, make_function,
+          make_multi_function, make_index_function, make_custom_function, make_aggregate, make_filter};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+pub const COMPILED_MAGIC:&'static [u8] = b"EVEB";
+/// Bumped whenever `CompiledProgram` changes shape. Files from other versions are refused
+/// rather than misread, they just need to be compiled again.
+pub const COMPILED_VERSION:u32 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompiledError {
+    Io(String),
+    /// The file doesn't start with `EVEB`.
+    NotCompiled,
+    /// Compiled by a different version of Eve.
+    Version(u32),
+    Corrupt(String),
+    /// Uses a custom function that wasn't registered on the program loading it.
+    UnknownFunction(String),
+}
+
+impl fmt::Display for CompiledError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &CompiledError::Io(ref why) => write!(f, "{}", why),
+            &CompiledError::NotCompiled => write!(f, "This isn't a compiled Eve program"),
+            &CompiledError::Version(version) => write!(f, "This program was compiled for version {} of the format, but only version {} can be loaded. Compile it again.", version, COMPILED_VERSION),
+            &CompiledError::Corrupt(ref why) => write!(f, "This compiled program is damaged: {}", why),
+            &CompiledError::UnknownFunction(ref op) => write!(f, "This program calls `{}`, but no function by that name is registered", op),
+        }
+    }
+}
+
+impl From<io::Error> for CompiledError {
+    fn from(err:io::Error) -> CompiledError {
+        CompiledError::Io(err.to_string())
+    }
+}
+
+// Fields are the same as in the blocks, except that a `Field::Value` is an index into
+// the program's values rather than an interned id. The masks are kept as the compiler
+// left them, since they aren't always what the constructors would compute.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum CompiledConstraint {
+    Scan {e: Field, a: Field, v: Field, register_mask: u64},
+    RangeScan {e: Field, a: Field, v: Field, low: Option<(Field, bool)>, high: Option<(Field, bool)>, register_mask: u64},
+    LookupCommit {e: Field, a: Field, v: Field, register_mask: u64},
+    LookupRemote {e: Field, a: Field, v: Field, _for: Field, _type: Field, from: Field, to: Field, register_mask: u64},
+    AntiScan {key: Vec<Field>, register_mask: u64},
+    IntermediateScan {full_key: Vec<Field>, key: Vec<Field>, value: Vec<Field>, register_mask: u64, output_mask: u64},
+    Function {op: String, output: Field, params: Vec<Field>, param_mask: u64, output_mask: u64},
+    MultiFunction {op: String, outputs: Vec<Field>, params: Vec<Field>, param_mask: u64, output_mask: u64},
+    IndexFunction {op: String, outputs: Vec<Field>, params: Vec<Field>, param_mask: u64, output_mask: u64},
+    CustomFunction {op: String, outputs: Vec<Field>, params: Vec<Field>, param_mask: u64, output_mask: u64},
+    Aggregate {op: String, output: Vec<Field>, group: Vec<Field>, projection: Vec<Field>, params: Vec<Field>, param_mask: u64, output_mask: u64, output_key: Vec<Field>, kind: FunctionKind},
+    Filter {op: String, left: Field, right: Field, param_mask: u64},
+    Insert {e: Field, a: Field, v: Field, commit: bool},
+    InsertIntermediate {key: Vec<Field>, value: Vec<Field>, negate: bool},
+    Remove {e: Field, a: Field, v: Field},
+    RemoveAttribute {e: Field, a: Field},
+    RemoveEntity {e: Field},
+    DynamicCommit {e: Field, a: Field, v: Field, _type: Field},
+    Project {registers: Vec<usize>},
+    Watch {name: String, registers: Vec<Field>},
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CompiledBlock {
+    name: String,
+    path: String,
+    metadata: BlockMetadata,
+    constraints: Vec<CompiledConstraint>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CompiledProgram {
+    values: Vec<Internable>,
+    blocks: Vec<CompiledBlock>,
+}
+
+//-------------------------------------------------------------------------
+// Writing
+//-------------------------------------------------------------------------
+
+struct Values<'a> {
+    interner: &'a Interner,
+    ids: HashMap<Interned, usize>,
+    values: Vec<Internable>,
+}
+
+impl<'a> Values<'a> {
+    fn field(&mut self, field:&Field) -> Field {
+        match field {
+            &Field::Register(_) => *field,
+            &Field::Value(id) => {
+                let (interner, values) = (self.interner, &mut self.values);
+                Field::Value(*self.ids.entry(id).or_insert_with(|| {
+                    values.push(interner.get_value(id).clone());
+                    values.len() - 1
+                }) as Interned)
+            }
+        }
+    }
+
+    fn fields(&mut self, fields:&[Field]) -> Vec<Field> {
+        fields.iter().map(|field| self.field(field)).collect()
+    }
+
+    fn bound(&mut self, bound:&Option<(Field, bool)>) -> Option<(Field, bool)> {
+        (*bound).map(|(field, inclusive)| (self.field(&field), inclusive))
+    }
+
+    fn constraint(&mut self, constraint:&Constraint) -> CompiledConstraint {
+        match constraint {
+            &Constraint::Scan { ref e, ref a, ref v, register_mask } => CompiledConstraint::Scan { e: self.field(e), a: self.field(a), v: self.field(v), register_mask },
+            &Constraint::RangeScan { ref e, ref a, ref v, ref low, ref high, register_mask } => {
+                CompiledConstraint::RangeScan { e: self.field(e), a: self.field(a), v: self.field(v), low: self.bound(low), high: self.bound(high), register_mask }
+            }
+            &Constraint::LookupCommit { ref e, ref a, ref v, register_mask } => CompiledConstraint::LookupCommit { e: self.field(e), a: self.field(a), v: self.field(v), register_mask },
+            &Constraint::LookupRemote { ref e, ref a, ref v, ref _for, ref _type, ref from, ref to, register_mask } => {
+                CompiledConstraint::LookupRemote { e: self.field(e), a: self.field(a), v: self.field(v), _for: self.field(_for), _type: self.field(_type), from: self.field(from), to: self.field(to), register_mask }
+            }
+            &Constraint::AntiScan { ref key, register_mask } => CompiledConstraint::AntiScan { key: self.fields(key), register_mask },
+            &Constraint::IntermediateScan { ref full_key, ref key, ref value, register_mask, output_mask } => {
+                CompiledConstraint::IntermediateScan { full_key: self.fields(full_key), key: self.fields(key), value: self.fields(value), register_mask, output_mask }
+            }
+            &Constraint::Function { ref op, ref output, ref params, param_mask, output_mask, .. } => {
+                CompiledConstraint::Function { op: op.to_string(), output: self.field(output), params: self.fields(params), param_mask, output_mask }
+            }
+            &Constraint::MultiFunction { ref op, ref outputs, ref params, param_mask, output_mask, .. } => {
+                CompiledConstraint::MultiFunction { op: op.to_string(), outputs: self.fields(outputs), params: self.fields(params), param_mask, output_mask }
+            }
+            &Constraint::IndexFunction { ref op, ref outputs, ref params, param_mask, output_mask, .. } => {
+                CompiledConstraint::IndexFunction { op: op.to_string(), outputs: self.fields(outputs), params: self.fields(params), param_mask, output_mask }
+            }
+            &Constraint::CustomFunction { ref op, ref outputs, ref params, param_mask, output_mask, .. } => {
+                CompiledConstraint::CustomFunction { op: op.to_string(), outputs: self.fields(outputs), params: self.fields(params), param_mask, output_mask }
+            }
+            &Constraint::Aggregate { ref op, ref output, ref group, ref projection, ref params, param_mask, output_mask, ref output_key, kind, .. } => {
+                CompiledConstraint::Aggregate { op: op.to_string(), output: self.fields(output), group: self.fields(group), projection: self.fields(projection),
+                                                params: self.fields(params), param_mask, output_mask, output_key: self.fields(output_key), kind }
+            }
+            &Constraint::Filter { ref op, ref left, ref right, param_mask, .. } => CompiledConstraint::Filter { op: op.to_string(), left: self.field(left), right: self.field(right), param_mask },
+            &Constraint::Insert { ref e, ref a, ref v, commit } => CompiledConstraint::Insert { e: self.field(e), a: self.field(a), v: self.field(v), commit },
+            &Constraint::InsertIntermediate { ref key, ref value, negate } => CompiledConstraint::InsertIntermediate { key: self.fields(key), value: self.fields(value), negate },
+            &Constraint::Remove { ref e, ref a, ref v } => CompiledConstraint::Remove { e: self.field(e), a: self.field(a), v: self.field(v) },
+            &Constraint::RemoveAttribute { ref e, ref a } => CompiledConstraint::RemoveAttribute { e: self.field(e), a: self.field(a) },
+            &Constraint::RemoveEntity { ref e } => CompiledConstraint::RemoveEntity { e: self.field(e) },
+            &Constraint::DynamicCommit { ref e, ref a, ref v, ref _type } => CompiledConstraint::DynamicCommit { e: self.field(e), a: self.field(a), v: self.field(v), _type: self.field(_type) },
+            &Constraint::Project { ref registers } => CompiledConstraint::Project { registers: registers.clone() },
+            &Constraint::Watch { ref name, ref registers } => CompiledConstraint::Watch { name: name.to_string(), registers: self.fields(registers) },
+        }
+    }
+}
+
+/// Writes `blocks` out in the compiled format.
+pub fn write_compiled<W:Write>(interner:&Interner, blocks:&[Block], writer:&mut W) -> Result<(), CompiledError> {
+    let mut values = Values { interner, ids: HashMap::new(), values: vec![] };
+    let blocks = blocks.iter().map(|block| {
+        CompiledBlock { name: block.name.to_string(),
+                        path: block.path.to_string(),
+                        metadata: block.metadata.clone(),
+                        constraints: block.constraints.iter().map(|constraint| values.constraint(constraint)).collect() }
+    }).collect();
+    let program = CompiledProgram { values: values.values, blocks };
+    writer.write_all(COMPILED_MAGIC)?;
+    writer.write_all(&[COMPILED_VERSION as u8, (COMPILED_VERSION >> 8) as u8, (COMPILED_VERSION >> 16) as u8, (COMPILED_VERSION >> 24) as u8])?;
+    bincode::serialize_into(writer, &program, bincode::Infinite).map_err(|err| CompiledError::Io(err.to_string()))
+}
+
+pub fn save_compiled_file(interner:&Interner, blocks:&[Block], path:&str) -> Result<(), CompiledError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_compiled(interner, blocks, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+//-------------------------------------------------------------------------
+// Reading
+//-------------------------------------------------------------------------
+
+struct Ids<'a> {
+    ids: Vec<Interned>,
+    functions: &'a CustomFunctions,
+}
+
+impl<'a> Ids<'a> {
+    fn field(&self, field:Field) -> Result<Field, CompiledError> {
+        match field {
+            Field::Register(_) => Ok(field),
+            Field::Value(ix) => match self.ids.get(ix as usize) {
+                Some(&id) => Ok(Field::Value(id)),
+                None => Err(CompiledError::Corrupt(format!("value {} is missing", ix))),
+            },
+        }
+    }
+
+    fn fields(&self, fields:Vec<Field>) -> Result<Vec<Field>, CompiledError> {
+        fields.into_iter().map(|field| self.field(field)).collect()
+    }
+
+    fn bound(&self, bound:Option<(Field, bool)>) -> Result<Option<(Field, bool)>, CompiledError> {
+        match bound {
+            Some((field, inclusive)) => Ok(Some((self.field(field)?, inclusive))),
+            None => Ok(None),
+        }
+    }
+
+    // The function pointers come from the same constructors the compiler uses, which also
+    // reject any function this build doesn't know about.
+    fn constraint(&self, constraint:CompiledConstraint) -> Result<Constraint, CompiledError> {
+        Ok(match constraint {
+            CompiledConstraint::Scan { e, a, v, register_mask } => Constraint::Scan { e: self.field(e)?, a: self.field(a)?, v: self.field(v)?, register_mask },
+            CompiledConstraint::RangeScan { e, a, v, low, high, register_mask } => {
+                Constraint::RangeScan { e: self.field(e)?, a: self.field(a)?, v: self.field(v)?, low: self.bound(low)?, high: self.bound(high)?, register_mask }
+            }
+            CompiledConstraint::LookupCommit { e, a, v, register_mask } => Constraint::LookupCommit { e: self.field(e)?, a: self.field(a)?, v: self.field(v)?, register_mask },
+            CompiledConstraint::LookupRemote { e, a, v, _for, _type, from, to, register_mask } => {
+                Constraint::LookupRemote { e: self.field(e)?, a: self.field(a)?, v: self.field(v)?, _for: self.field(_for)?, _type: self.field(_type)?, from: self.field(from)?, to: self.field(to)?, register_mask }
+            }
+            CompiledConstraint::AntiScan { key, register_mask } => Constraint::AntiScan { key: self.fields(key)?, register_mask },
+            CompiledConstraint::IntermediateScan { full_key, key, value, register_mask, output_mask } => {
+                Constraint::IntermediateScan { full_key: self.fields(full_key)?, key: self.fields(key)?, value: self.fields(value)?, register_mask, output_mask }
+            }
+            CompiledConstraint::Function { op, output, params, param_mask, output_mask } => {
+                let (output, params) = (self.field(output)?, self.fields(params)?);
+                match make_function(&op, params.clone(), output) {
+                    Constraint::Function { func, .. } => Constraint::Function { op, output, func, params, param_mask, output_mask },
+                    _ => unreachable!(),
+                }
+            }
+            CompiledConstraint::MultiFunction { op, outputs, params, param_mask, output_mask } => {
+                let (outputs, params) = (self.fields(outputs)?, self.fields(params)?);
+                match make_multi_function(&op, params.clone(), outputs.clone()) {
+                    Constraint::MultiFunction { func, .. } => Constraint::MultiFunction { op, outputs, func, params, param_mask, output_mask },
+                    _ => unreachable!(),
+                }
+            }
+            CompiledConstraint::IndexFunction { op, outputs, params, param_mask, output_mask } => {
+                let (outputs, params) = (self.fields(outputs)?, self.fields(params)?);
+                match make_index_function(&op, params.clone(), outputs.clone()) {
+                    Constraint::IndexFunction { func, .. } => Constraint::IndexFunction { op, outputs, func, params, param_mask, output_mask },
+                    _ => unreachable!(),
+                }
+            }
+            CompiledConstraint::CustomFunction { op, outputs, params, param_mask, output_mask } => {
+                let (outputs, params) = (self.fields(outputs)?, self.fields(params)?);
+                let func = match self.functions.get(&op) {
+                    Some(func) => func,
+                    None => return Err(CompiledError::UnknownFunction(op)),
+                };
+                match make_custom_function(&op, func, params.clone(), outputs.clone()) {
+                    Constraint::CustomFunction { func, .. } => Constraint::CustomFunction { op, outputs, func, params, param_mask, output_mask },
+                    _ => unreachable!(),
+                }
+            }
+            CompiledConstraint::Aggregate { op, output, group, projection, params, param_mask, output_mask, output_key, kind } => {
+                let (output, group, projection, params, output_key) = (self.fields(output)?, self.fields(group)?, self.fields(projection)?, self.fields(params)?, self.fields(output_key)?);
+                match make_aggregate(&op, group.clone(), projection.clone(), params.clone(), output.clone(), kind) {
+                    Constraint::Aggregate { add, remove, .. } => Constraint::Aggregate { op, output, add, remove, group, projection, params, param_mask, output_mask, output_key, kind },
+                    _ => unreachable!(),
+                }
+            }
+            CompiledConstraint::Filter { op, left, right, param_mask } => {
+                let (left, right) = (self.field(left)?, self.field(right)?);
+                match make_filter(&op, left, right) {
+                    Constraint::Filter { func, .. } => Constraint::Filter { op, func, left, right, param_mask },
+                    _ => unreachable!(),
+                }
+            }
+            CompiledConstraint::Insert { e, a, v, commit } => Constraint::Insert { e: self.field(e)?, a: self.field(a)?, v: self.field(v)?, commit },
+            CompiledConstraint::InsertIntermediate { key, value, negate } => Constraint::InsertIntermediate { key: self.fields(key)?, value: self.fields(value)?, negate },
+            CompiledConstraint::Remove { e, a, v } => Constraint::Remove { e: self.field(e)?, a: self.field(a)?, v: self.field(v)? },
+            CompiledConstraint::RemoveAttribute { e, a } => Constraint::RemoveAttribute { e: self.field(e)?, a: self.field(a)? },
+            CompiledConstraint::RemoveEntity { e } => Constraint::RemoveEntity { e: self.field(e)? },
+            CompiledConstraint::DynamicCommit { e, a, v, _type } => Constraint::DynamicCommit { e: self.field(e)?, a: self.field(a)?, v: self.field(v)?, _type: self.field(_type)? },
+            CompiledConstraint::Project { registers } => Constraint::Project { registers },
+            CompiledConstraint::Watch { name, registers } => Constraint::Watch { name, registers: self.fields(registers)? },
+        })
+    }
+}
+
+/// Reads blocks written by `write_compiled`, interning their values in `interner`. Calls
+/// to custom functions are bound to the ones in `functions`.
+pub fn read_compiled<R:Read>(interner:&mut Interner, functions:&CustomFunctions, reader:&mut R) -> Result<Vec<Block>, CompiledError> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header).map_err(|_| CompiledError::NotCompiled)?;
+    if &header[..4] != COMPILED_MAGIC { return Err(CompiledError::NotCompiled); }
+    let version = header[4..].iter().rev().fold(0, |version, &byte| (version << 8) | byte as u32);
+    if version != COMPILED_VERSION { return Err(CompiledError::Version(version)); }
+    let program:CompiledProgram = bincode::deserialize_from(reader, bincode::Infinite).map_err(|err| CompiledError::Corrupt(err.to_string()))?;
+    // id 0 is nothing, which the interner never hands out for a value
+    let ids = program.values.into_iter().map(|value| {
+        match value {
+            Internable::Null => 0,
+            value => interner.internable_to_id(value),
+        }
+    }).collect();
+    let ids = Ids { ids, functions };
+    let mut blocks = vec![];
+    for compiled in program.blocks {
+        let constraints = compiled.constraints.into_iter().map(|constraint| ids.constraint(constraint)).collect::<Result<Vec<Constraint>, CompiledError>>()?;
+        let block_id = interner.string_id(&compiled.name);
+        let mut block = Block::new(interner, &compiled.name, block_id, constraints);
+        block.path = compiled.path;
+        block.metadata = compiled.metadata;
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+pub fn load_compiled_file(interner:&mut Interner, functions:&CustomFunctions, path:&str) -> Result<Vec<Block>, CompiledError> {
+    read_compiled(interner, functions, &mut BufReader::new(File::open(path)?))
+}
+
+/// Whether `path` names a compiled program rather than source.
+pub fn is_compiled_file(path:&str) -> bool {
+    Path::new(path).extension().map_or(false, |ext| ext == "evb")
+}
@@ -0,0 +1,240 @@
+//-------------------------------------------------------------------------
+// Database checks
+//-------------------------------------------------------------------------
+
+// Validates a persisted db the way it'll be read at startup: the change log has to
+// decode all the way through, no snapshot or checkpoint write can have been left half
+// done, and replaying the log has to leave the indexes agreeing with each other. With
+// `repair` set, the problems that have a safe fix get fixed.
+
+extern crate bincode;
+
+use ops::{Program, RawChange, Internable, Transaction, EstimateIterPool};
+use delivery::DeliveryRecord;
+use indexes::HashIndex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, BufReader};
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub changes: usize,
+    pub problems: Vec<String>,
+    pub repaired: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.len() == 0
+    }
+}
+
+// Counts what's been read so a decode failure can be placed in the file.
+struct CountingReader<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R:Read> Read for CountingReader<R> {
+    fn read(&mut self, buf:&mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+/// Reads every change in the log at `path`, returning them along with the offset just
+/// past the last one that decoded.
+pub fn read_changes(path:&str) -> io::Result<(Vec<RawChange>, u64)> {
+    let file = File::open(path)?;
+    let mut reader = CountingReader { inner: BufReader::new(file), offset: 0 };
+    let mut changes = vec![];
+    let mut good = 0;
+    loop {
+        let result:Result<RawChange, _> = bincode::deserialize_from(&mut reader, bincode::Infinite);
+        match result {
+            Ok(change) => {
+                changes.push(change);
+                good = reader.offset;
+            }
+            Err(_) => break,
+        }
+    }
+    Ok((changes, good))
+}
+
+fn check_log(path:&str, repair:bool, report:&mut CheckReport) -> Vec<RawChange> {
+    let length = match fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(err) => {
+            report.problems.push(format!("Unable to read {}: {}", path, err));
+            return vec![];
+        }
+    };
+    let (changes, good) = match read_changes(path) {
+        Ok(result) => result,
+        Err(err) => {
+            report.problems.push(format!("Unable to read {}: {}", path, err));
+            return vec![];
+        }
+    };
+    report.changes = changes.len();
+    if good < length {
+        let problem = format!("{} has {} bytes after the last complete change (offset {})", path, length - good, good);
+        if repair {
+            match OpenOptions::new().write(true).open(path).and_then(|file| file.set_len(good)) {
+                Ok(_) => report.repaired.push(format!("Truncated {} to {} bytes", path, good)),
+                Err(err) => report.problems.push(format!("{}, and truncating it failed: {}", problem, err)),
+            }
+        } else {
+            report.problems.push(problem);
+        }
+    }
+    for (ix, change) in changes.iter().enumerate() {
+        if change.count != 1 && change.count != -1 {
+            report.problems.push(format!("Change {} has a count of {}, persisted changes are always 1 or -1", ix, change.count));
+        }
+        if change.e == Internable::Null || change.a == Internable::Null || change.v == Internable::Null {
+            report.problems.push(format!("Change {} is missing part of its fact: {:?}", ix, change));
+        }
+    }
+    changes
+}
+
+fn check_leftovers(path:&str, repair:bool, report:&mut CheckReport) {
+    // a snapshot is written beside the db and renamed over it, so one still sitting
+    // there was interrupted and the db itself is the last good state
+    let snapshot = format!("{}.snapshot", path);
+    if Path::new(&snapshot).exists() {
+        if repair {
+            match fs::remove_file(&snapshot) {
+                Ok(_) => report.repaired.push(format!("Removed interrupted snapshot {}", snapshot)),
+                Err(err) => report.problems.push(format!("Unable to remove interrupted snapshot {}: {}", snapshot, err)),
+            }
+        } else {
+            report.problems.push(format!("Found interrupted snapshot {}", snapshot));
+        }
+    }
+
+    // the watcher delivery log is append-only, so like the change log it can only be
+    // torn at the end, and load stops at the tear anyway
+    let deliveries = format!("{}.watchers", path);
+    if let Ok(metadata) = fs::metadata(&deliveries) {
+        let good = File::open(&deliveries).map(|file| {
+            let mut reader = CountingReader { inner: BufReader::new(file), offset: 0 };
+            let mut good = 0;
+            while let Ok(_) = bincode::deserialize_from::<_, DeliveryRecord>(&mut reader, bincode::Infinite) {
+                good = reader.offset;
+            }
+            good
+        });
+        match good {
+            Ok(good) if good < metadata.len() => {
+                let problem = format!("{} has {} bytes after the last complete delivery (offset {})", deliveries, metadata.len() - good, good);
+                if repair {
+                    match OpenOptions::new().write(true).open(&deliveries).and_then(|file| file.set_len(good)) {
+                        Ok(_) => report.repaired.push(format!("Truncated {} to {} bytes", deliveries, good)),
+                        Err(err) => report.problems.push(format!("{}, and truncating it failed: {}", problem, err)),
+                    }
+                } else {
+                    report.problems.push(problem);
+                }
+            }
+            Ok(_) => {}
+            Err(err) => report.problems.push(format!("Unable to read watcher deliveries {}: {}", deliveries, err)),
+        }
+    }
+}
+
+/// Problems with a program's indexes: the hash index disagreeing with one rebuilt from
+/// scratch out of the facts the distinct index considers available, committed facts
+/// missing from it, facts supported by a negative number of derivations and
+/// intermediates left behind by blocks that aren't loaded.
+pub fn check_indexes(program:&Program) -> Vec<String> {
+    let blocks:Vec<String> = program.block_info.block_names.keys().cloned().collect();
+    check_state(program, &blocks)
+}
+
+fn check_state(program:&Program, blocks:&[String]) -> Vec<String> {
+    let mut problems = vec![];
+    let interner = &program.state.interner;
+    let index = &program.state.index;
+    let distinct = &program.state.distinct_index;
+    let describe = |e, a, v| format!("({}, {}, {})", interner.get_value(e).print(), interner.get_value(a).print(), interner.get_value(v).print());
+
+    let mut rebuilt = HashIndex::new();
+    for (&(e, a, v), _) in distinct.eavs.iter() {
+        if distinct.is_available(e, a, v) {
+            rebuilt.insert(e, a, v);
+        }
+        if distinct.is_commit(e, a, v) && !index.check(e, a, v) {
+            problems.push(format!("Committed fact {} is missing from the index", describe(e, a, v)));
+        }
+        let support = distinct.support(e, a, v);
+        if support < 0 {
+            problems.push(format!("Fact {} has a support count of {}", describe(e, a, v), support));
+        }
+    }
+    for (e, a, v) in index.facts() {
+        if !rebuilt.check(e, a, v) {
+            problems.push(format!("Indexed fact {} has no live rounds", describe(e, a, v)));
+        }
+    }
+    for (e, a, v) in rebuilt.facts() {
+        if !index.check(e, a, v) {
+            problems.push(format!("Available fact {} is missing from the index", describe(e, a, v)));
+        } else if !index.get(0, a, v).map_or(false, |mut found| found.any(|found| found == e)) {
+            // looked up by value the fact has to turn up too
+            problems.push(format!("Fact {} can't be found by its value", describe(e, a, v)));
+        }
+    }
+    if index.size != rebuilt.size {
+        problems.push(format!("The index counts {} facts but rebuilding it gives {}", index.size, rebuilt.size));
+    }
+
+    for key in program.state.intermediates.keys() {
+        let name = match key.get(0).map(|&id| interner.get_value(id)) {
+            Some(&Internable::String(ref name)) => name,
+            _ => continue,
+        };
+        let owner = match name.find("|sub_block|") {
+            Some(ix) => &name[..ix],
+            None => continue,
+        };
+        // sub-block keys are named after the block, or one of its nested parts
+        let live = blocks.iter().any(|block| owner == &block[..] || (owner.starts_with(&block[..]) && owner[block.len()..].starts_with("|")));
+        if !live {
+            problems.push(format!("Intermediate `{}` belongs to a block that isn't loaded", name));
+        }
+    }
+    problems
+}
+
+pub fn check_db(path:&str, repair:bool) -> CheckReport {
+    let mut report = CheckReport::default();
+    check_leftovers(path, repair, &mut report);
+    let changes = check_log(path, repair, &mut report);
+
+    let mut program = Program::new("check");
+    let mut iter_pool = EstimateIterPool::new();
+    {
+        let mut txn = Transaction::new(&mut iter_pool);
+        for change in changes.iter() {
+            txn.input_change(change.clone().to_change(&mut program.state.interner));
+        }
+        txn.exec(&mut program, &mut None);
+    }
+    report.problems.extend(check_indexes(&program));
+
+    // the derived state checkpointed beside the db is loaded as is at startup, so it
+    // gets the same checks, against the blocks it was taken with
+    let derived = format!("{}.derived", path);
+    if Path::new(&derived).exists() {
+        let mut checkpointed = Program::new("check");
+        if let Some(point) = checkpointed.restore_checkpoint(&derived, &changes) {
+            let problems = check_state(&checkpointed, &point.block_names());
+            report.problems.extend(problems.into_iter().map(|problem| format!("{}: {}", derived, problem)));
+        }
+    }
+    report
+}
@@ -1,5 +1,6 @@
 use super::compiler::{OutputType, Node};
 use super::error::*;
+use super::parser::{Syntax, Feature, DEFAULT_SYNTAX};
 
 //--------------------------------------------------------------------
 // Combinator Macros
@@ -375,11 +376,12 @@ pub struct ParseState<'a> {
     pub pos: usize,
     ignore_space: bool,
     pub output_type: OutputType,
+    pub syntax: Syntax,
 }
 
 impl<'a> ParseState<'a> {
     pub fn new(input:&str) -> ParseState {
-        ParseState { input, stack:vec![], line:0, ch:0, pos:0, output_type: OutputType::Lookup, ignore_space: false }
+        ParseState { input, stack:vec![], line:0, ch:0, pos:0, output_type: OutputType::Lookup, ignore_space: false, syntax: DEFAULT_SYNTAX }
     }
 
     pub fn capture(&self, start:usize) -> &'a str {
@@ -424,6 +426,13 @@ impl<'a> ParseState<'a> {
                         self.eat_space();
                         return;
                     },
+                    '*' if self.syntax.allows(Feature::BlockComments) => {
+                        self.ch += 1;
+                        self.pos += 1;
+                        self.consume_block_comment();
+                        self.eat_space();
+                        return;
+                    },
                     _ => { self.ch -= 1; self.pos -= 1; break; }
                 }
             } else {
@@ -439,6 +448,20 @@ impl<'a> ParseState<'a> {
         }
     }
 
+    // Skips past the `*/` that closes a block comment, or to the end of the input if
+    // nothing does.
+    fn consume_block_comment(&mut self) {
+        let remaining = &self.input[self.pos..];
+        let length = remaining.find("*/").map_or(remaining.len(), |end| end + 2);
+        for c in remaining[..length].chars() {
+            match c {
+                '\n' => { self.line += 1; self.ch = 0; }
+                _ => { self.ch += 1; }
+            }
+            self.pos += c.len_utf8();
+        }
+    }
+
     pub fn consume_line(&mut self) {
         let remaining = &self.input[self.pos..];
         for c in remaining.chars() {
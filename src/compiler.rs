@@ -4,18 +4,24 @@ extern crate term_painter;
 
 use std::hash::Hash;
 use std::collections::{HashMap, HashSet};
-use std::collections::hash_map::RandomState;
 use std::collections::hash_map::Entry;
-use ops::{Interner, Field, Constraint, register, make_scan, make_anti_scan, Internable,
-          make_intermediate_insert, make_intermediate_scan, make_filter, make_function,
-          make_multi_function, make_commit_lookup, make_remote_lookup, make_aggregate, Block};
+use ops::{Interner, Field, Constraint, register, make_scan, make_anti_scan, Internable, query_param, query_param_name,
+          make_intermediate_insert, make_intermediate_scan, make_attribute_set, make_filter, make_function,
+          make_multi_function, make_index_function, make_custom_function, make_commit_lookup, make_remote_lookup, make_aggregate, make_range_scan, Block, BlockMetadata,
+          DebugMode, trace, levenshtein, scoped_attribute, Interned, TAG_INTERNED_ID, CustomMultiFunction};
 use std::io::prelude::*;
 use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use std::cmp::{self};
+use std::u32;
+use std::sync::{Arc, RwLock};
+use std::fmt;
 use self::walkdir::WalkDir;
-use parser::{embedded_blocks, block};
-use combinators::{ParseResult, ParseState, Span, EMPTY_SPAN};
+use parser::{embedded_blocks, block, syntax_header, prose_lines, DEFAULT_SYNTAX, LATEST_SYNTAX, SUPPORTED_SYNTAX};
+use combinators::{ParseResult, ParseState, Pos, Span, EMPTY_SPAN};
 use error::{self, CompileError, report_errors};
+use numerics::Decimal;
+use indexes::{HashIndex, MyHasher};
 use self::term_painter::ToStyle;
 use self::term_painter::Color::*;
 
@@ -34,9 +40,17 @@ macro_rules! get_provided (
     });
 );
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+/// `lookup[record, attribute, value]` is how Eve spells it, `entity` is what older
+/// programs use. Either names the record a lookup is about.
+fn is_lookup_entity(attribute:&str) -> bool {
+    attribute == "record" || attribute == "entity"
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum FunctionKind {
     Multi,
+    Index,
+    Custom,
     Scalar,
     Sum,
     Sort,
@@ -44,6 +58,7 @@ pub enum FunctionKind {
     NeedleSort,
 }
 
+#[derive(Clone)]
 pub struct FunctionInfo {
     kind: FunctionKind,
     params: Vec<String>,
@@ -68,6 +83,18 @@ impl FunctionInfo {
         FunctionInfo { kind: FunctionKind::Multi, params, outputs }
     }
 
+    pub fn index(raw_params:Vec<&str>, raw_outputs:Vec<&str>) -> FunctionInfo {
+        let params = raw_params.iter().map(|s| s.to_string()).collect();
+        let outputs = raw_outputs.iter().map(|s| s.to_string()).collect();
+        FunctionInfo { kind: FunctionKind::Index, params, outputs }
+    }
+
+    pub fn custom(raw_params:Vec<&str>, raw_outputs:Vec<&str>) -> FunctionInfo {
+        let params = raw_params.iter().map(|s| s.to_string()).collect();
+        let outputs = raw_outputs.iter().map(|s| s.to_string()).collect();
+        FunctionInfo { kind: FunctionKind::Custom, params, outputs }
+    }
+
     pub fn aggregate(raw_params:Vec<&str>, raw_outputs:Vec<&str>, kind: FunctionKind) -> FunctionInfo {
         let params = raw_params.iter().map(|s| s.to_string()).collect();
         let outputs = raw_outputs.iter().map(|s| s.to_string()).collect();
@@ -88,22 +115,41 @@ impl FunctionInfo {
     pub fn get_params(&self) -> &Vec<String> {
         &self.params
     }
-}
 
-lazy_static! {
-    static ref DETERMINISTIC_STATE: RandomState = RandomState::new();
+    pub fn get_attributes(&self) -> Vec<String> {
+        let mut attributes:Vec<String> = self.params.iter().chain(self.outputs.iter()).filter(|x| *x != "*").cloned().collect();
+        match self.kind {
+            FunctionKind::Sum | FunctionKind::SortedSum | FunctionKind::Sort => {
+                attributes.extend(vec!["per".to_string(), "for".to_string()]);
+            }
+            FunctionKind::NeedleSort => {
+                attributes.extend(vec!["per".to_string(), "for".to_string(), "from".to_string()]);
+            }
+            _ => {}
+        }
+        attributes
+    }
+
+    pub fn max_outputs(&self) -> usize {
+        // a `*` output takes as many values as the caller projects
+        if self.outputs.iter().any(|x| x == "*") { return usize::max_value(); }
+        cmp::max(self.outputs.len(), 1)
+    }
 }
 
-pub fn make_det_hash_map<K: Hash + Eq, V>() -> HashMap<K, V> {
-    HashMap::with_hasher(DETERMINISTIC_STATE.clone())
+// Compilation walks these maps and sets to decide the order of constraints and sub
+// blocks, so they use a fixed hasher rather than a random one: the same source compiles
+// to the same blocks in every process, not just within one.
+pub fn make_det_hash_map<K: Hash + Eq, V>() -> HashMap<K, V, MyHasher> {
+    HashMap::default()
 }
 
-pub fn make_det_hash_set<V: Hash + Eq>() -> HashSet<V> {
-    HashSet::with_hasher(DETERMINISTIC_STATE.clone())
+pub fn make_det_hash_set<V: Hash + Eq>() -> HashSet<V, MyHasher> {
+    HashSet::default()
 }
 
 lazy_static! {
-    static ref FUNCTION_INFO: HashMap<String, FunctionInfo> = {
+    static ref FUNCTION_INFO: RwLock<HashMap<String, FunctionInfo, MyHasher>> = RwLock::new({
         let mut m = make_det_hash_map();
         let mut info = make_det_hash_map();
         info.insert("degrees".to_string(), 0);
@@ -118,18 +164,41 @@ lazy_static! {
         m.insert("math/floor".to_string(), FunctionInfo::new(vec!["value"]));
         m.insert("math/round".to_string(), FunctionInfo::new(vec!["value"]));
         m.insert("math/range".to_string(), FunctionInfo::multi(vec!["from", "to"], vec!["value"]));
+        m.insert("range".to_string(), FunctionInfo::multi(vec!["from", "to", "increment"], vec!["value"]));
         m.insert("random/number".to_string(), FunctionInfo::new(vec!["seed"]));
+        m.insert("random/uuid".to_string(), FunctionInfo::new(vec![]));
+        m.insert("sample".to_string(), FunctionInfo::new(vec!["fraction", "per", "seed"]));
         m.insert("string/replace".to_string(), FunctionInfo::new(vec!["text", "replace", "with"]));
         m.insert("string/contains".to_string(), FunctionInfo::new(vec!["text", "substring"]));
         m.insert("string/lowercase".to_string(), FunctionInfo::new(vec!["text"]));
         m.insert("string/uppercase".to_string(), FunctionInfo::new(vec!["text"]));
         m.insert("string/length".to_string(), FunctionInfo::new(vec!["text"]));
+        m.insert("string/levenshtein".to_string(), FunctionInfo::new(vec!["a", "b"]));
+        m.insert("string/soundex".to_string(), FunctionInfo::new(vec!["text"]));
+        m.insert("string/pad-start".to_string(), FunctionInfo::new(vec!["text", "length", "pad"]));
+        m.insert("string/pad-end".to_string(), FunctionInfo::new(vec!["text", "length", "pad"]));
+        m.insert("string/title-case".to_string(), FunctionInfo::new(vec!["text"]));
+        m.insert("string/slugify".to_string(), FunctionInfo::new(vec!["text"]));
         m.insert("string/substring".to_string(), FunctionInfo::new(vec!["text", "from", "to"]));
         m.insert("string/split".to_string(), FunctionInfo::multi(vec!["text", "by"], vec!["token", "index"]));
         m.insert("eve-internal/string/split-reverse".to_string(), FunctionInfo::multi(vec!["text", "by"], vec!["token", "index"]));
         m.insert("string/index-of".to_string(), FunctionInfo::multi(vec!["text", "substring"], vec!["index"]));
+        m.insert("url/parse".to_string(), FunctionInfo::multi(vec!["text"], vec!["scheme", "host", "port", "path", "query", "fragment"]));
+        m.insert("url/query-param".to_string(), FunctionInfo::multi(vec!["text"], vec!["key", "value", "index"]));
+        m.insert("html/select".to_string(), FunctionInfo::multi(vec!["document", "selector", "attribute"], vec!["element", "tag", "text", "value"]));
+        m.insert("url/encode".to_string(), FunctionInfo::new(vec!["value"]));
+        m.insert("url/decode".to_string(), FunctionInfo::new(vec!["text"]));
+        m.insert("url/encode-query".to_string(), FunctionInfo::new(vec!["key", "value"]));
         m.insert("eve/type-of".to_string(), FunctionInfo::new(vec!["value"]));
         m.insert("eve/parse-value".to_string(), FunctionInfo::new(vec!["value"]));
+        m.insert("date/now".to_string(), FunctionInfo::new(vec![]));
+        m.insert("date/parse".to_string(), FunctionInfo::new(vec!["text", "format"]));
+        m.insert("date/format".to_string(), FunctionInfo::new(vec!["timestamp", "format"]));
+        m.insert("date/add".to_string(), FunctionInfo::new(vec!["timestamp", "amount", "unit"]));
+        m.insert("date/diff".to_string(), FunctionInfo::new(vec!["from", "to", "unit"]));
+        m.insert("graph/shortest-path".to_string(), FunctionInfo::index(vec!["from", "to", "edge"], vec!["node", "step"]));
+        m.insert("graph/components".to_string(), FunctionInfo::index(vec!["edge"], vec!["node", "component"]));
+        m.insert("search/text".to_string(), FunctionInfo::index(vec!["query", "attribute"], vec!["record", "score"]));
         m.insert("gather/sum".to_string(), FunctionInfo::aggregate(vec!["value"], vec!["sum"], FunctionKind::Sum));
         m.insert("gather/average".to_string(), FunctionInfo::aggregate(vec!["value"], vec!["average"], FunctionKind::Sum));
         m.insert("gather/string-join".to_string(), FunctionInfo::aggregate(vec!["value", "separator"], vec!["string"], FunctionKind::SortedSum));
@@ -139,11 +208,58 @@ lazy_static! {
         m.insert("gather/next".to_string(), FunctionInfo::aggregate(vec![], vec!["*"], FunctionKind::NeedleSort));
         m.insert("gather/previous".to_string(), FunctionInfo::aggregate(vec![], vec!["*"], FunctionKind::NeedleSort));
         m
-    };
+    });
+}
+
+pub fn get_function_info(op:&str) -> Option<FunctionInfo> {
+    return FUNCTION_INFO.read().unwrap().get(op).cloned();
+}
+
+/// The functions an embedder has registered on a program with `Program::register_function`.
+/// Each program has its own, and they're handed to the compiler through `CompileOptions`.
+#[derive(Clone, Default)]
+pub struct CustomFunctions {
+    functions: HashMap<String, (FunctionInfo, Arc<Box<CustomMultiFunction>>)>,
+}
+
+impl CustomFunctions {
+    pub fn new() -> CustomFunctions {
+        CustomFunctions::default()
+    }
+
+    pub fn register(&mut self, op:&str, info:FunctionInfo, func:Box<CustomMultiFunction>) {
+        self.functions.insert(op.to_string(), (info, Arc::new(func)));
+    }
+
+    pub fn info(&self, op:&str) -> Option<FunctionInfo> {
+        self.functions.get(op).map(|&(ref info, _)| info.clone())
+    }
+
+    pub fn get(&self, op:&str) -> Option<Arc<Box<CustomMultiFunction>>> {
+        self.functions.get(op).map(|&(_, ref func)| func.clone())
+    }
 }
 
-pub fn get_function_info(op:&str) -> Option<&FunctionInfo> {
-    return FUNCTION_INFO.get(op);
+impl fmt::Debug for CustomFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names:Vec<&String> = self.functions.keys().collect();
+        names.sort();
+        write!(f, "CustomFunctions({:?})", names)
+    }
+}
+
+fn closest_matches<'a, I: Iterator<Item=&'a String>>(needle:&str, candidates:I) -> Vec<String> {
+    let threshold = cmp::max(2, needle.len() / 3);
+    let mut matches:Vec<(usize, &String)> = candidates.map(|candidate| (levenshtein(needle, candidate), candidate))
+                                                      .filter(|&(distance, _)| distance <= threshold)
+                                                      .collect();
+    matches.sort();
+    matches.into_iter().take(3).map(|(_, candidate)| candidate.to_owned()).collect()
+}
+
+pub fn suggest_functions(op:&str) -> Vec<String> {
+    let info = FUNCTION_INFO.read().unwrap();
+    closest_matches(op, info.keys().filter(|name| !name.starts_with("eve-internal/")))
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -159,11 +275,17 @@ pub enum Node<'a> {
     Pos(Span, Box<Node<'a>>),
     Integer(i32),
     Float(f32),
+    Decimal(Decimal),
     RawString(&'a str),
     EmbeddedString(Option<String>, Vec<Node<'a>>),
     ExprSet(Vec<Node<'a>>),
     NoneValue,
+    // `$name` in a prepared query, see `Program::prepare`.
+    Placeholder(&'a str),
     Tag(&'a str),
+    // `#dog|#cat` in a search record. Gathering equalities fills in the union of one
+    // `if` branch per tag that provides the record.
+    TagUnion(Vec<&'a str>, Option<Box<Node<'a>>>),
     Variable(&'a str),
     Identifier(&'a str),
     GeneratedVariable(String),
@@ -183,15 +305,18 @@ pub enum Node<'a> {
     RecordFunction { op:&'a str, params:Vec<Node<'a>>, outputs:Vec<Node<'a>> },
     OutputRecord(Option<String>, Vec<Node<'a>>, OutputType),
     RecordUpdate {record:Box<Node<'a>>, value:Box<Node<'a>>, op:&'a str, output_type:OutputType},
+    BulkUpdate(Vec<Node<'a>>),
     Not(usize, Vec<Node<'a>>),
+    Objective(&'a str, Box<Node<'a>>),
     IfBranch { sub_block_id: usize, exclusive:bool, result:Box<Node<'a>>, body:Vec<Node<'a>> },
     If { sub_block_id:usize, exclusive:bool, outputs:Option<Vec<Node<'a>>>, branches:Vec<Node<'a>> },
     Search(Vec<Node<'a>>),
+    Scoped(&'a str, Box<Node<'a>>),
     Bind(Vec<Node<'a>>),
     Commit(Vec<Node<'a>>),
     Project(Vec<Node<'a>>),
     Watch(&'a str, Vec<Node<'a>>),
-    Block{code: &'a str, errors: Vec<ParseResult<'a, Node<'a>>>, search:Box<Option<Node<'a>>>, update:Box<Node<'a>>},
+    Block{code: &'a str, errors: Vec<ParseResult<'a, Node<'a>>>, search:Box<Option<Node<'a>>>, update:Box<Node<'a>>, metadata: BlockMetadata},
     DisabledBlock(&'a str),
     Doc { file:String, blocks:Vec<Node<'a>> }
 }
@@ -205,6 +330,71 @@ pub enum SubBlock {
     If(Compilation, Vec<Field>, bool),
 }
 
+//-------------------------------------------------------------------------
+// Scopes
+//-------------------------------------------------------------------------
+
+fn scope_field(interner:&mut Interner, scope:&str, attribute:Field) -> Field {
+    let scoped = match attribute {
+        Field::Value(0) => return attribute,
+        Field::Value(id) => match interner.get_value(id) {
+            &Internable::String(ref name) => scoped_attribute(scope, name),
+            _ => return attribute,
+        },
+        _ => return attribute,
+    };
+    Field::Value(interner.internable_to_id(scoped))
+}
+
+// The attribute a constraint reads or writes, and whether it reads it.
+fn attribute_field(constraint:&mut Constraint) -> Option<(&mut Field, bool)> {
+    match constraint {
+        &mut Constraint::Scan {ref mut a, ..} |
+        &mut Constraint::RangeScan {ref mut a, ..} |
+        &mut Constraint::LookupCommit {ref mut a, ..} => Some((a, true)),
+        &mut Constraint::Insert {ref mut a, ..} |
+        &mut Constraint::Remove {ref mut a, ..} |
+        &mut Constraint::RemoveAttribute {ref mut a, ..} => Some((a, false)),
+        _ => None,
+    }
+}
+
+// Constant attributes are put in the scope as the block is compiled. An attribute in a
+// register is only known once the block runs, so a scan finds the scoped attribute in
+// a register of its own and takes it out of the scope, and an insert or remove puts it
+// in the scope first.
+fn scope_constraints(interner:&mut Interner, scope:&str, comp:&mut Compilation, start:usize) {
+    let mut dynamic = vec![];
+    for ix in start..comp.constraints.len() {
+        if let Some((a, reads)) = attribute_field(&mut comp.constraints[ix]) {
+            match *a {
+                Field::Register(_) => dynamic.push((ix, *a, reads)),
+                _ => *a = scope_field(interner, scope, *a),
+            }
+        }
+    }
+    let scope = interner.string(scope);
+    for (ix, attribute, reads) in dynamic {
+        let scoped = comp.gen_var("scoped_attribute").unwrap();
+        let mut lookup:HashMap<Field, Field> = comp.constraints[ix].get_registers().into_iter().map(|field| (field, field)).collect();
+        lookup.insert(attribute, scoped);
+        comp.constraints[ix].replace_registers(&lookup);
+        let function = if reads {
+            make_function("eve-internal/unscope", vec![scope, scoped], attribute)
+        } else {
+            make_function("eve-internal/scope", vec![scope, attribute], scoped)
+        };
+        comp.constraints.push(function);
+    }
+}
+
+fn scope_compilation(interner:&mut Interner, scope:&str, comp:&mut Compilation) {
+    scope_constraints(interner, scope, comp, 0);
+    for sub_block in comp.sub_blocks.iter_mut() {
+        scope_compilation(interner, scope, sub_block.get_mut_compilation());
+    }
+}
+
 impl SubBlock {
     pub fn get_mut_compilation(&mut self) -> &mut Compilation {
         match self {
@@ -245,22 +435,131 @@ impl<'a> Node<'a> {
 
     pub fn unwrap_ref_pos(&self) -> &Node<'a> {
         match self {
-            &Node::Pos(_, box ref node) => node,
+            &Node::Pos(_, ref node) => node,
             _ => &self
         }
     }
 
+    pub fn block_metadata(&self) -> BlockMetadata {
+        match self.unwrap_ref_pos() {
+            &Node::Block { ref metadata, .. } => metadata.clone(),
+            _ => BlockMetadata::default(),
+        }
+    }
+
     pub fn to_pos_ref<'t>(&'t self, cur_span:&'t Span) -> (&'t Span, &Node<'a>) {
         match self {
-            &Node::Pos(ref span, box ref node) => (span, node),
+            &Node::Pos(ref span, ref node) => (span, node),
             _ => (cur_span, &self)
         }
     }
 
+    // An objective is kept as the gather it compiles to, which ranks its `for`.
+    pub fn objective_value(&self) -> Option<&Node<'a>> {
+        if let &Node::Objective(_, ref function) = self.unwrap_ref_pos() {
+            if let &Node::RecordFunction { ref params, .. } = function.unwrap_ref_pos() {
+                if let Some(&Node::AttributeEquality("for", ref value)) = params.first().map(|param| param.unwrap_ref_pos()) {
+                    return Some(value);
+                }
+            }
+        }
+        None
+    }
+
+    // Collects the attributes this node searches for and the ones its actions write.
+    // Function and lookup arguments aren't attributes, so only their values are walked.
+    pub fn attribute_uses(&self, span:&Span, uses:&mut AttributeUses<'a>) {
+        match self {
+            &Node::Pos(ref span, ref sub) => sub.attribute_uses(span, uses),
+            &Node::Record(_, ref attrs) => {
+                for attr in attrs {
+                    let (local_span, unwrapped) = attr.to_pos_ref(span);
+                    match unwrapped {
+                        &Node::Attribute(a) => { uses.searched.insert(a); }
+                        &Node::AttributeEquality(a, ref v) => { uses.searched.insert(a); v.attribute_uses(local_span, uses); }
+                        &Node::AttributeInequality { attribute, ref right, .. } => { uses.searched.insert(attribute); right.attribute_uses(local_span, uses); }
+                        _ => {}
+                    }
+                }
+            }
+            &Node::OutputRecord(_, ref attrs, _) => {
+                for attr in attrs {
+                    let (local_span, unwrapped) = attr.to_pos_ref(span);
+                    match unwrapped {
+                        &Node::Attribute(a) => { uses.written.push((a, local_span.clone())); }
+                        &Node::AttributeEquality(a, ref v) => { uses.written.push((a, local_span.clone())); v.attribute_uses(local_span, uses); }
+                        _ => {}
+                    }
+                }
+            }
+            &Node::AttributeAccess(ref items) => { uses.searched.extend(items[1..].iter().cloned()); }
+            &Node::MutatingAttributeAccess(ref items) => {
+                if items.len() > 1 {
+                    uses.searched.extend(items[1..items.len() - 1].iter().cloned());
+                    uses.written.push((items[items.len() - 1], span.clone()));
+                }
+            }
+            &Node::RecordUpdate { ref record, ref value, .. } => {
+                record.attribute_uses(span, uses);
+                value.attribute_uses(span, uses);
+            }
+            &Node::Lookup(ref args, _) | &Node::LookupCommit(ref args) | &Node::LookupRemote(ref args, _) => {
+                for arg in args {
+                    let (local_span, unwrapped) = arg.to_pos_ref(span);
+                    if let &Node::AttributeEquality(_, ref v) = unwrapped { v.attribute_uses(local_span, uses); }
+                }
+            }
+            &Node::RecordFunction { ref params, ref outputs, .. } => {
+                for node in params.iter().chain(outputs.iter()) {
+                    let (local_span, unwrapped) = node.to_pos_ref(span);
+                    match unwrapped {
+                        &Node::AttributeEquality(_, ref v) => v.attribute_uses(local_span, uses),
+                        other => other.attribute_uses(local_span, uses),
+                    }
+                }
+            }
+            &Node::AttributeInequality { ref right, .. } => right.attribute_uses(span, uses),
+            &Node::AttributeEquality(_, ref v) => v.attribute_uses(span, uses),
+            &Node::Inequality { ref left, ref right, .. } |
+            &Node::Equality { ref left, ref right } |
+            &Node::Infix { ref left, ref right, .. } => {
+                left.attribute_uses(span, uses);
+                right.attribute_uses(span, uses);
+            }
+            &Node::IfBranch { ref result, ref body, .. } => {
+                result.attribute_uses(span, uses);
+                for node in body { node.attribute_uses(span, uses); }
+            }
+            &Node::If { ref outputs, ref branches, .. } => {
+                for node in outputs.iter().flat_map(|outputs| outputs.iter()).chain(branches.iter()) {
+                    node.attribute_uses(span, uses);
+                }
+            }
+            &Node::EmbeddedString(_, ref nodes) |
+            &Node::ExprSet(ref nodes) |
+            &Node::RecordSet(ref nodes) |
+            &Node::BulkUpdate(ref nodes) |
+            &Node::Not(_, ref nodes) |
+            &Node::Search(ref nodes) |
+            &Node::Bind(ref nodes) |
+            &Node::Commit(ref nodes) |
+            &Node::Project(ref nodes) |
+            &Node::Watch(_, ref nodes) => {
+                for node in nodes { node.attribute_uses(span, uses); }
+            }
+            &Node::Scoped(_, ref sub) => sub.attribute_uses(span, uses),
+            &Node::Block { ref search, ref update, .. } => {
+                if let Some(ref search) = **search { search.attribute_uses(span, uses); }
+                update.attribute_uses(span, uses);
+            }
+            _ => {}
+        }
+    }
+
     pub fn unify(&self, comp:&mut Compilation) {
         {
-            let ref mut values:HashMap<Field, Field> = comp.var_values;
-            let ref mut unified_registers:HashMap<Field, Field> = comp.unified_registers;
+            let ref mut values:HashMap<Field, Field, MyHasher> = comp.var_values;
+            let ref mut unified_registers:HashMap<Field, Field, MyHasher> = comp.unified_registers;
             let ref mut provided = comp.provided_registers;
             for v in comp.vars.values() {
                 let field = Field::Register(*v);
@@ -315,7 +614,9 @@ impl<'a> Node<'a> {
                             provided.insert(r, true);
                             changed = true;
                         },
-                        (a, b) => { if a != b { panic!("Invalid equality {:?} != {:?}", a, b); } },
+                        // two different constants can't be equal; compile reports it once
+                        // it can point at where
+                        (_, _) => {},
                     }
                 }
             }
@@ -348,9 +649,12 @@ impl<'a> Node<'a> {
             &mut Node::Pipe => { None },
             &mut Node::DisabledBlock(_) => { None },
             &mut Node::Tag(_) => { None },
+            &mut Node::TagUnion(..) => { None },
             &mut Node::Integer(v) => { Some(interner.number(v as f32)) }
             &mut Node::Float(v) => { Some(interner.number(v)) },
+            &mut Node::Decimal(v) => { Some(interner.decimal(v)) },
             &mut Node::RawString(v) => { Some(interner.string(v)) },
+            &mut Node::Placeholder(name) => { Some(Field::Value(interner.internable_to_id(query_param(name)))) },
             &mut Node::Variable(v) => { Some(cur_block.get_register(v)) },
             &mut Node::GeneratedVariable(ref v) => { Some(cur_block.get_register(v)) },
             &mut Node::NoneValue => { None },
@@ -468,13 +772,27 @@ impl<'a> Node<'a> {
                 None
             },
             &mut Node::Record(ref mut var, ref mut attrs) => {
-                for attr in attrs {
+                for attr in attrs.iter_mut() {
                     attr.gather_equalities(interner, cur_block);
                 }
                 let var_name = format!("__eve_record{}", cur_block.id);
                 cur_block.id += 1;
                 let reg = cur_block.get_register(&var_name);
                 cur_block.provide(reg, true);
+                for attr in attrs.iter_mut() {
+                    let attr = match attr { &mut Node::Pos(_, ref mut inner) => &mut **inner, other => other };
+                    if let &mut Node::TagUnion(ref tags, ref mut union) = attr {
+                        // i.e. `record = if [#dog] then [#dog] if [#cat] then [#cat]`
+                        let branches = tags.iter().map(|&tag| {
+                            let result = Node::Record(None, vec![Node::Tag(tag)]);
+                            Node::IfBranch { sub_block_id:0, exclusive:false, body:vec![], result:Box::new(result) }
+                        }).collect();
+                        let outputs = Some(vec![Node::GeneratedVariable(var_name.to_string())]);
+                        let mut node = Node::If { sub_block_id:0, exclusive:false, outputs, branches };
+                        node.gather_equalities(interner, cur_block);
+                        *union = Some(Box::new(node));
+                    }
+                }
                 *var = Some(var_name);
                 Some(reg)
             },
@@ -524,6 +842,10 @@ impl<'a> Node<'a> {
                 cur_block.sub_blocks.push(SubBlock::Not(sub_block));
                 None
             },
+            &mut Node::Objective(_, ref mut function) => {
+                function.gather_equalities(interner, cur_block);
+                None
+            },
             &mut Node::IfBranch {ref mut sub_block_id, ref mut body, ref mut result, ..} => {
                 let mut sub_block = Compilation::new_child(cur_block);
                 for item in body {
@@ -556,6 +878,9 @@ impl<'a> Node<'a> {
                 };
                 None
             },
+            &mut Node::Scoped(_, ref mut section) => {
+                section.gather_equalities(interner, cur_block)
+            },
             &mut Node::Bind(ref mut statements) => {
                 cur_block.mode = CompilationMode::Output;
                 for s in statements {
@@ -570,6 +895,12 @@ impl<'a> Node<'a> {
                 };
                 None
             },
+            &mut Node::BulkUpdate(ref mut updates) => {
+                for update in updates {
+                    update.gather_equalities(interner, cur_block);
+                };
+                None
+            },
             &mut Node::Project(ref mut values) => {
                 cur_block.mode = CompilationMode::Output;
                 for v in values {
@@ -601,13 +932,18 @@ impl<'a> Node<'a> {
             &Node::DisabledBlock(_) => { None },
             &Node::Integer(v) => { Some(interner.number(v as f32)) }
             &Node::Float(v) => { Some(interner.number(v)) },
+            &Node::Decimal(v) => { Some(interner.decimal(v)) },
             &Node::RawString(v) => { Some(interner.string(v)) },
+            &Node::Placeholder(name) => { Some(Field::Value(interner.internable_to_id(query_param(name)))) },
             &Node::Variable(v) => { Some(get_provided!(cur_block, span, v)) },
             &Node::GeneratedVariable(ref v) => { Some(get_provided!(cur_block, span, v)) },
             // &Node::AttributeEquality(a, ref v) => { v.compile(interner, comp, cur_block) },
             &Node::Equality {ref left, ref right} => {
-                left.compile(interner, cur_block, span);
-                right.compile(interner, cur_block, span);
+                let left_value = left.compile(interner, cur_block, span);
+                let right_value = right.compile(interner, cur_block, span);
+                if let (Some(l), Some(r)) = (left_value, right_value) {
+                    cur_block.check_equality(interner, l, r, span);
+                }
                 None
             },
             &Node::AttributeAccess(ref items) => {
@@ -647,6 +983,7 @@ impl<'a> Node<'a> {
                 let right_value = right.compile(interner, cur_block, span);
                 match (left_value, right_value) {
                     (Some(l), Some(r)) => {
+                        cur_block.check_filter(interner, op, l, r, span);
                         cur_block.constraints.push(make_filter(op, l, r));
                     },
                     _ => panic!("inequality without both a left and right: {:?} {} {:?}", left, op, right)
@@ -688,13 +1025,17 @@ impl<'a> Node<'a> {
                 }
             },
             &Node::RecordFunction { ref op, ref params, ref outputs} => {
-                let info = match FUNCTION_INFO.get(*op) {
+                let info = match cur_block.functions.info(op).or_else(|| get_function_info(op)) {
                     Some(v) => v,
                     None => {
-                        cur_block.error(span, error::Error::UnknownFunction(op.to_string()));
+                        cur_block.error(span, error::Error::UnknownFunction(op.to_string(), suggest_functions(op)));
                         return Some(Field::Value(0));
                     }
                 };
+                if outputs.len() > info.max_outputs() {
+                    cur_block.error(span, error::Error::TooManyFunctionOutputs(op.to_string(), outputs.len(), info.max_outputs()));
+                    return Some(Field::Value(0));
+                }
                 let mut cur_outputs = vec![Field::Value(0); cmp::max(outputs.len(), info.outputs.len())];
                 let mut cur_params = vec![Field::Value(0); info.params.len()];
                 let mut group = vec![];
@@ -709,7 +1050,8 @@ impl<'a> Node<'a> {
                         }
                         &Node::AttributeEquality(a, ref v) => {
                             let (local_pos, unwrapped) = v.to_pos_ref(span);
-                            if let &Node::ExprSet(ref items) = unwrapped {
+                            // i.e. `gather/count[for: [#todo]]`, each record's id is the param
+                            if let &Node::ExprSet(ref items) | &Node::RecordSet(ref items) = unwrapped {
                                 for item in items {
                                     compiled_params.push((a, item.compile(interner, cur_block, local_pos).unwrap()))
                                 }
@@ -729,7 +1071,9 @@ impl<'a> Node<'a> {
                                     (FunctionKind::Sum, "for") | (FunctionKind::SortedSum, "for") | (FunctionKind::Sort, "for") | (FunctionKind::NeedleSort, "for") => { projection.push(v) }
                                     (FunctionKind::NeedleSort, "from") => { needle.push(v) }
                                     _ => {
-                                        cur_block.error(span, error::Error::UnknownFunctionParam(op.to_string(), a.to_string()));
+                                        let attributes = info.get_attributes();
+                                        let suggestions = closest_matches(a, attributes.iter());
+                                        cur_block.error(span, error::Error::UnknownFunctionParam(op.to_string(), a.to_string(), attributes, suggestions));
                                     }
                                 }
                             }
@@ -786,6 +1130,15 @@ impl<'a> Node<'a> {
                     FunctionKind::Multi => {
                         cur_block.constraints.push(make_multi_function(op, cur_params, cur_outputs));
                     },
+                    FunctionKind::Index => {
+                        cur_block.constraints.push(make_index_function(op, cur_params, cur_outputs));
+                    },
+                    FunctionKind::Custom => {
+                        match cur_block.functions.get(op) {
+                            Some(func) => cur_block.constraints.push(make_custom_function(op, func, cur_params, cur_outputs)),
+                            None => cur_block.error(span, error::Error::UnknownFunction(op.to_string(), suggest_functions(op))),
+                        }
+                    },
                     FunctionKind::Sort | FunctionKind::Sum | FunctionKind::SortedSum => {
                         let mut sub_block = Compilation::new_child(cur_block);
                         let unified_output:Vec<Field> = cur_outputs.iter().map(|x| cur_block.get_unified(x)).collect();
@@ -822,8 +1175,8 @@ impl<'a> Node<'a> {
                 for attr in attrs {
                     let (local_span, unwrapped) = attr.to_pos_ref(span);
                     match unwrapped {
-                        &Node::Attribute("entity") => { entity = Some(get_provided!(cur_block, local_span, "entity")); },
-                        &Node::AttributeEquality("entity", ref v) => { entity = v.compile(interner, cur_block, local_span); }
+                        &Node::Attribute(a) if is_lookup_entity(a) => { entity = Some(get_provided!(cur_block, local_span, a)); },
+                        &Node::AttributeEquality(a, ref v) if is_lookup_entity(a) => { entity = v.compile(interner, cur_block, local_span); }
                         _ => {}
                     }
                 }
@@ -851,11 +1204,11 @@ impl<'a> Node<'a> {
                     // @FIXME: What do we do if there are multiple fields for a given a?
                     // Seems like that should be handled in gather_equalities, is it?
                     match a {
-                        "entity" => {}
+                        "entity" | "record" => {}
                         "attribute" => attribute = v,
                         "value" => value = v,
                         "type" => _type = v,
-                        _ => panic!("Invalid lookup attribute '{}'. Lookup supports only entity, attribute, and value lookups.", a)
+                        _ => panic!("Invalid lookup attribute '{}'. Lookup supports only record (or entity), attribute, and value lookups.", a)
                     }
                 }
 
@@ -889,8 +1242,8 @@ impl<'a> Node<'a> {
                 for attr in attrs {
                     let (local_span, unwrapped) = attr.to_pos_ref(span);
                     match unwrapped {
-                        &Node::Attribute("entity") => { entity = Some(get_provided!(cur_block, local_span, "entity")); },
-                        &Node::AttributeEquality("entity", ref v) => { entity = v.compile(interner, cur_block, local_span); }
+                        &Node::Attribute(a) if is_lookup_entity(a) => { entity = Some(get_provided!(cur_block, local_span, a)); },
+                        &Node::AttributeEquality(a, ref v) if is_lookup_entity(a) => { entity = v.compile(interner, cur_block, local_span); }
                         _ => {}
                     }
                 }
@@ -918,10 +1271,10 @@ impl<'a> Node<'a> {
                     // @FIXME: What do we do if there are multiple fields for a given a?
                     // Seems like that should be handled in gather_equalities, is it?
                     match a {
-                        "entity" => {}
+                        "entity" | "record" => {}
                         "attribute" => attribute = v,
                         "value" => value = v,
-                        _ => panic!("Invalid lookup attribute '{}'. Lookup supports only entity, attribute, and value lookups.", a)
+                        _ => panic!("Invalid lookup attribute '{}'. Lookup supports only record (or entity), attribute, and value lookups.", a)
                     }
                 }
 
@@ -943,8 +1296,8 @@ impl<'a> Node<'a> {
                 for attr in attrs {
                     let (local_span, unwrapped) = attr.to_pos_ref(span);
                     match unwrapped {
-                        &Node::Attribute("entity") => { entity = Some(get_provided!(cur_block, local_span, "entity")); },
-                        &Node::AttributeEquality("entity", ref v) => { entity = v.compile(interner, cur_block, local_span); }
+                        &Node::Attribute(a) if is_lookup_entity(a) => { entity = Some(get_provided!(cur_block, local_span, a)); },
+                        &Node::AttributeEquality(a, ref v) if is_lookup_entity(a) => { entity = v.compile(interner, cur_block, local_span); }
                         _ => {}
                     }
                 }
@@ -972,14 +1325,14 @@ impl<'a> Node<'a> {
                     // @FIXME: What do we do if there are multiple fields for a given a?
                     // Seems like that should be handled in gather_equalities, is it?
                     match a {
-                        "entity" => {}
+                        "entity" | "record" => {}
                         "attribute" => attribute = v,
                         "value" => value = v,
                         "to" => to = v,
                         "from" => from = v,
                         "for" => _for = v,
                         "type" => _type = v,
-                        _ => panic!("Invalid lookup attribute '{}'. Lookup supports only entity, attribute, and value lookups.", a)
+                        _ => panic!("Invalid lookup attribute '{}'. Lookup supports only record (or entity), attribute, and value lookups.", a)
                     }
                 }
 
@@ -1008,6 +1361,12 @@ impl<'a> Node<'a> {
                     let (local_span, unwrapped) = attr.to_pos_ref(span);
                     let (a, v) = match unwrapped {
                         &Node::Tag(t) => { (interner.string("tag"), interner.string(t)) },
+                        &Node::TagUnion(_, ref union) => {
+                            if let &Some(ref union) = union {
+                                union.compile(interner, cur_block, local_span);
+                            }
+                            continue;
+                        },
                         &Node::Attribute(a) => { (interner.string(a), get_provided!(cur_block, local_span, a)) },
                         &Node::AttributeEquality(a, ref v) => {
                             let result_a = interner.string(a);
@@ -1176,15 +1535,8 @@ impl<'a> Node<'a> {
                 };
                 for (a, v) in avs {
                     match (*op, a, v) {
-                        (":=", Field::Value(0), Field::Value(0)) => {
-                            cur_block.constraints.push(Constraint::RemoveEntity {e:reg });
-                        },
-                        (":=", _, Field::Value(0)) => {
-                            cur_block.constraints.push(Constraint::RemoveAttribute {e:reg, a });
-                        },
                         (":=", _, _) => {
-                            cur_block.constraints.push(Constraint::RemoveAttribute {e:reg, a });
-                            cur_block.constraints.push(Constraint::Insert {e:reg, a, v, commit});
+                            cur_block.constraints.extend(make_attribute_set(reg, a, v, commit));
                         },
                         (_, Field::Value(0), Field::Value(0)) => {  }
                         ("+=", _, _) => { cur_block.constraints.push(Constraint::Insert {e:reg, a, v, commit}); }
@@ -1203,6 +1555,11 @@ impl<'a> Node<'a> {
                 for item in items {
                     item.compile(interner, sub_block, span);
                 };
+                cur_block.negations.push((sub_block_id, span.clone()));
+                None
+            },
+            &Node::Objective(_, ref function) => {
+                function.compile(interner, cur_block, span);
                 None
             },
             &Node::IfBranch { sub_block_id, ref body, ref result, ..} => {
@@ -1259,12 +1616,27 @@ impl<'a> Node<'a> {
                 } else {
                     vec![]
                 };
+                let expected = compiled_outputs.len();
+                let mut mismatched = vec![];
                 if let SubBlock::If(ref mut sub_block, ref mut out_registers, ..) = cur_block.sub_blocks[sub_block_id] {
                     out_registers.extend(compiled_outputs);
-                    for branch in branches {
+                    for (ix, branch) in branches.iter().enumerate() {
                         branch.compile(interner, sub_block, span);
+                        // every branch has to give a value for each output, or its results
+                        // won't line up with them
+                        let (branch_span, unwrapped) = branch.to_pos_ref(span);
+                        if let (&Some(_), &Node::IfBranch { sub_block_id, .. }) = (outputs, unwrapped) {
+                            if let SubBlock::IfBranch(_, ref results) = sub_block.sub_blocks[sub_block_id] {
+                                if results.len() != expected {
+                                    mismatched.push((branch_span.clone(), ix + 1, results.len()));
+                                }
+                            }
+                        }
                     }
                 }
+                for (branch_span, branch, found) in mismatched {
+                    cur_block.error(&branch_span, error::Error::IfArity(branch, found, expected));
+                }
                 None
             },
             &Node::Search(ref statements) => {
@@ -1273,6 +1645,18 @@ impl<'a> Node<'a> {
                 };
                 None
             },
+            &Node::Scoped(scope, ref section) => {
+                let start = cur_block.constraints.len();
+                section.compile(interner, cur_block, span);
+                scope_constraints(interner, scope, cur_block, start);
+                // not, if and aggregates only ever come from the search section
+                if let &Node::Search(..) = section.unwrap_ref_pos() {
+                    for sub_block in cur_block.sub_blocks.iter_mut() {
+                        scope_compilation(interner, scope, sub_block.get_mut_compilation());
+                    }
+                }
+                None
+            },
             &Node::Bind(ref statements) => {
                 for s in statements {
                     s.compile(interner, cur_block, span);
@@ -1285,6 +1669,12 @@ impl<'a> Node<'a> {
                 };
                 None
             },
+            &Node::BulkUpdate(ref updates) => {
+                for update in updates {
+                    update.compile(interner, cur_block, span);
+                };
+                None
+            },
             &Node::Project(ref values) => {
                 let registers = values.iter()
                                       .map(|v| v.compile(interner, cur_block, span))
@@ -1317,6 +1707,7 @@ impl<'a> Node<'a> {
                 if let Some(ref s) = **search {
                     s.compile(interner, cur_block, span);
                 };
+                cur_block.check_negations(interner);
                 update.compile(interner, cur_block, span);
 
                 self.sub_blocks(interner, cur_block);
@@ -1328,7 +1719,7 @@ impl<'a> Node<'a> {
 
     pub fn sub_blocks(&self, interner:&mut Interner, parent:&mut Compilation) {
         // gather all the registers that we know about at the root
-        let mut parent_registers:HashSet<Field> = make_det_hash_set();
+        let mut parent_registers:HashSet<Field, MyHasher> = make_det_hash_set();
         for constraint in parent.constraints.iter() {
             parent_registers.extend(constraint.get_registers().iter());
         }
@@ -1354,7 +1745,7 @@ impl<'a> Node<'a> {
 
     }
 
-    pub fn sub_block_output(&self, interner:&mut Interner, block:&mut SubBlock, ix:usize, inputs:&HashSet<Field>) -> Constraint {
+    pub fn sub_block_output(&self, interner:&mut Interner, block:&mut SubBlock, ix:usize, inputs:&HashSet<Field, MyHasher>) -> Constraint {
         match block {
             &mut SubBlock::Not(ref mut cur_block) => {
                 let block_name = cur_block.block_name.to_string();
@@ -1388,7 +1779,7 @@ impl<'a> Node<'a> {
 
     }
 
-    pub fn compile_sub_block(&self, interner:&mut Interner, block:&mut SubBlock, ix:usize, inputs:&HashSet<Field>, ancestor_constraints: &Vec<Constraint>) {
+    pub fn compile_sub_block(&self, interner:&mut Interner, block:&mut SubBlock, ix:usize, inputs:&HashSet<Field, MyHasher>, ancestor_constraints: &Vec<Constraint>) {
         let output_constraint = self.sub_block_output(interner, block, ix, inputs);
         match block {
             &mut SubBlock::Not(ref mut cur_block) => {
@@ -1477,7 +1868,7 @@ impl<'a> Node<'a> {
     }
 }
 
-pub fn get_input_constraints(needles:&HashSet<Field>, haystack:&Vec<Constraint>) -> Vec<Constraint> {
+pub fn get_input_constraints(needles:&HashSet<Field, MyHasher>, haystack:&Vec<Constraint>) -> Vec<Constraint> {
     let mut related = make_det_hash_set();
     for hay in haystack {
         let mut found = false;
@@ -1500,17 +1891,19 @@ pub fn get_input_constraints(needles:&HashSet<Field>, haystack:&Vec<Constraint>)
     while changed {
         changed = false;
         let start_size = related.len();
+        // a constraint that's already related can still turn up new registers to follow
+        let start_needles = transitive_needles.len();
         for hay in haystack {
             if let &Constraint::IntermediateScan {..} = hay { continue; }
             let mut found = false;
-            let outs = hay.get_filtering_registers();
+            let outs = filtering_registers(hay, haystack);
             for out in outs.iter() {
                 if transitive_needles.contains(out) {
                     found = true;
                 }
             }
             if found {
-                for filtering in hay.get_filtering_registers() {
+                for filtering in outs {
                     transitive_needles.insert(filtering);
                 }
                 if !related.contains(hay) {
@@ -1518,7 +1911,7 @@ pub fn get_input_constraints(needles:&HashSet<Field>, haystack:&Vec<Constraint>)
                 }
             }
         }
-        if related.len() > start_size {
+        if related.len() > start_size || transitive_needles.len() > start_needles {
             changed = true;
         }
     }
@@ -1526,7 +1919,22 @@ pub fn get_input_constraints(needles:&HashSet<Field>, haystack:&Vec<Constraint>)
     results
 }
 
-pub fn get_input_constraints_transitive(needles:&HashSet<Field>, haystack:&Vec<Constraint>) -> Vec<Constraint> {
+// A function whose output nothing else reads, e.g. `sample[fraction: 0.5 per: user]`,
+// is only there to filter its params. The output still counts, since the sub-block
+// asking, like an aggregate ranking it, may be the one that reads it.
+fn filtering_registers(hay:&Constraint, haystack:&Vec<Constraint>) -> Vec<Field> {
+    if let &Constraint::Function { ref output, ref params, .. } = hay {
+        let read = haystack.iter().any(|other| other != hay && other.get_registers().contains(output));
+        if output.is_register() && !read {
+            let mut registers:Vec<Field> = params.iter().filter(|param| param.is_register()).cloned().collect();
+            registers.push(*output);
+            return registers;
+        }
+    }
+    hay.get_filtering_registers()
+}
+
+pub fn get_input_constraints_transitive(needles:&HashSet<Field, MyHasher>, haystack:&Vec<Constraint>) -> Vec<Constraint> {
     let mut transitive_needles = needles.clone();
     let mut related = make_det_hash_set();
     let mut changed = true;
@@ -1535,16 +1943,21 @@ pub fn get_input_constraints_transitive(needles:&HashSet<Field>, haystack:&Vec<C
         let start_size = related.len();
         for hay in haystack {
             let mut found = false;
-            let outs = hay.get_filtering_registers();
+            let outs = filtering_registers(hay, haystack);
             for out in outs.iter() {
                 if transitive_needles.contains(out) {
                     found = true;
                 }
             }
             if found {
-                for filtering in hay.get_filtering_registers() {
+                for filtering in outs {
                     transitive_needles.insert(filtering);
                 }
+                // a function is found through its output, but it needs its params bound
+                // to compute it
+                if let &Constraint::Function { ref params, .. } = hay {
+                    transitive_needles.extend(params.iter().filter(|param| param.is_register()));
+                }
                 related.insert(hay.clone());
             }
         }
@@ -1562,6 +1975,27 @@ pub enum Provided {
     No(Field),
 }
 
+// A register compared against a numeric constant, kept so that a block that asks for
+// `x > 5` and `x < 3` can be reported as never matching.
+#[derive(Debug, Clone)]
+struct RangeBound {
+    field: Field,
+    op: String,
+    value: f32,
+    span: Span,
+}
+
+impl RangeBound {
+    fn is_lower(&self) -> bool {
+        self.op == ">" || self.op == ">="
+    }
+
+    // Whether this lower bound leaves nothing under the upper bound `upper`.
+    fn excludes(&self, upper:&RangeBound) -> bool {
+        self.value > upper.value || (self.value == upper.value && (self.op == ">" || upper.op == "<"))
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum CompilationMode {
     Search,
@@ -1572,28 +2006,33 @@ pub enum CompilationMode {
 pub struct Compilation {
     mode: CompilationMode,
     block_name: String,
-    vars: HashMap<String, usize>,
-    var_values: HashMap<Field, Field>,
-    unified_registers: HashMap<Field, Field>,
-    provided_registers: HashMap<Field, bool>,
+    vars: HashMap<String, usize, MyHasher>,
+    var_values: HashMap<Field, Field, MyHasher>,
+    unified_registers: HashMap<Field, Field, MyHasher>,
+    provided_registers: HashMap<Field, bool, MyHasher>,
     equalities: Vec<(Field, Field)>,
     pub constraints: Vec<Constraint>,
     sub_blocks: Vec<SubBlock>,
     required_fields: Vec<Field>,
+    bounds: Vec<RangeBound>,
+    // the `not`s in this block, by sub-block, along with where they are
+    negations: Vec<(usize, Span)>,
     is_child: bool,
     id: usize,
+    functions: CustomFunctions,
     errors: Vec<CompileError>
 }
 
 impl Compilation {
     pub fn new(block_name:String) -> Compilation {
-        Compilation { mode: CompilationMode::Search, vars:make_det_hash_map(), var_values:make_det_hash_map(), unified_registers:make_det_hash_map(), provided_registers:make_det_hash_map(), equalities:vec![], id:0, block_name, constraints:vec![], sub_blocks:vec![], required_fields:vec![], is_child: false, errors: vec![] }
+        Compilation { mode: CompilationMode::Search, vars:make_det_hash_map(), var_values:make_det_hash_map(), unified_registers:make_det_hash_map(), provided_registers:make_det_hash_map(), equalities:vec![], id:0, block_name, constraints:vec![], sub_blocks:vec![], required_fields:vec![], bounds:vec![], negations:vec![], is_child: false, functions: CustomFunctions::new(), errors: vec![] }
     }
 
     pub fn new_child(parent:&Compilation) -> Compilation {
         let mut child = Compilation::new(format!("{}|{}", parent.block_name, parent.sub_blocks.len()));
         child.id = parent.id + 10000 + (1000 * parent.sub_blocks.len());
         child.is_child = true;
+        child.functions = parent.functions.clone();
         child
     }
 
@@ -1608,6 +2047,138 @@ impl Compilation {
         self.errors.push(CompileError { span:span.clone(), error });
     }
 
+    // The constant a field is known to be once everything's unified, if it is one.
+    fn constant(&self, field:Field) -> Option<Interned> {
+        match field {
+            // 0 stands in for a value that failed to compile, which was already reported
+            Field::Value(0) => None,
+            Field::Value(value) => Some(value),
+            Field::Register(_) => match self.var_values.get(&field) {
+                Some(&Field::Value(value)) if value > 0 => Some(value),
+                _ => None,
+            },
+        }
+    }
+
+    fn describe(&self, interner:&Interner, field:Field) -> String {
+        if let Some(value) = self.constant(field) {
+            return match interner.get_value(value) {
+                &Internable::String(ref text) => format!("\"{}\"", text),
+                other => Internable::to_string(other),
+            };
+        }
+        let unified = self.unified_registers.get(&field).cloned().unwrap_or(field);
+        let mut names:Vec<&String> = self.vars.iter()
+            .filter(|&(name, reg)| !name.starts_with("__") && self.unified_registers.get(&register(*reg)).cloned().unwrap_or(register(*reg)) == unified)
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        names.first().map_or("a value".to_string(), |name| name.to_string())
+    }
+
+    // Only the top level of a block is checked: a sub-block that can never match just
+    // means its `not` always holds or its branch is never taken.
+    fn check_equality(&mut self, interner:&Interner, left:Field, right:Field, span:&Span) {
+        if self.is_child { return; }
+        if let (Some(l), Some(r)) = (self.constant(left), self.constant(right)) {
+            if l != r {
+                let reason = format!("`{}` can never equal `{}`", self.describe(interner, Field::Value(l)), self.describe(interner, Field::Value(r)));
+                self.error(span, error::Error::NeverMatches(reason));
+            }
+        }
+    }
+
+    fn check_filter(&mut self, interner:&Interner, op:&str, left:Field, right:Field, span:&Span) {
+        if self.is_child { return; }
+        match (self.constant(left), self.constant(right)) {
+            (Some(l), Some(r)) => {
+                if let Constraint::Filter { func, .. } = make_filter(op, Field::Value(l), Field::Value(r)) {
+                    if !func(interner.get_value(l), interner.get_value(r)) {
+                        let reason = format!("`{} {} {}` is never true", self.describe(interner, left), op, self.describe(interner, right));
+                        self.error(span, error::Error::NeverMatches(reason));
+                    }
+                }
+            }
+            (None, Some(r)) => self.check_bound(interner, op, left, r, span),
+            (Some(l), None) => {
+                let flipped = match op { ">" => "<", ">=" => "<=", "<" => ">", "<=" => ">=", other => other };
+                self.check_bound(interner, flipped, right, l, span)
+            }
+            (None, None) => {}
+        }
+    }
+
+    fn check_bound(&mut self, interner:&Interner, op:&str, field:Field, value:Interned, span:&Span) {
+        let number = match interner.get_value(value) {
+            &Internable::Number(_) => Internable::to_number(interner.get_value(value)),
+            _ => return,
+        };
+        let is_lower = match op { ">" | ">=" => true, "<" | "<=" => false, _ => return };
+        let field = self.unified_registers.get(&field).cloned().unwrap_or(field);
+        let bound = RangeBound { field, op: op.to_string(), value: number, span: span.clone() };
+        let conflict = self.bounds.iter().find(|other| {
+            other.field == field && match (is_lower, other.is_lower()) {
+                (true, false) => bound.excludes(other),
+                (false, true) => other.excludes(&bound),
+                _ => false,
+            }
+        }).cloned();
+        if let Some(other) = conflict {
+            let name = self.describe(interner, field);
+            let reason = format!("`{} {} {}` and `{} {} {}` (line {}) can't both be true",
+                                 name, bound.op, bound.value, name, other.op, other.value, other.span.start.line + 1);
+            self.error(span, error::Error::NeverMatches(reason));
+        }
+        self.bounds.push(bound);
+    }
+
+    // A `not` that only looks for records the block already requires can never hold, e.g.
+    // `p = [#person]` with `not(p = [#person])`. The `not` shares the block's variables, so
+    // its scans are compared once they're unified the way the block's are.
+    fn check_negations(&mut self, interner:&Interner) {
+        if self.is_child { return; }
+        let unified = |field:Field| self.unified_registers.get(&field).cloned().unwrap_or(field);
+        let mut found = vec![];
+        for &(ix, ref span) in self.negations.iter() {
+            let sub = match self.sub_blocks[ix] {
+                SubBlock::Not(ref sub) => sub,
+                _ => continue,
+            };
+            // the `not`'s own equalities, like `p = [#person]`, aren't unified until it's compiled
+            let aliases:HashMap<Field, Field> = sub.equalities.iter().filter_map(|&(l, r)| match (l, r) {
+                (Field::Register(l_reg), Field::Register(r_reg)) if l_reg > r_reg => Some((l, r)),
+                (Field::Register(l_reg), Field::Register(r_reg)) if r_reg > l_reg => Some((r, l)),
+                _ => None,
+            }).collect();
+            let resolve = |field:Field| unified(aliases.get(&field).cloned().unwrap_or(field));
+            let mut required = vec![];
+            for constraint in sub.constraints.iter() {
+                let (e, a, v) = match constraint {
+                    &Constraint::Scan { e, a, v, .. } => (resolve(e), resolve(a), resolve(v)),
+                    _ => { required.clear(); break; }
+                };
+                let known = self.constraints.iter().any(|constraint| match constraint {
+                    &Constraint::Scan { e:e2, a:a2, v:v2, .. } => (e, a, v) == (e2, a2, v2),
+                    _ => false,
+                });
+                if !known { required.clear(); break; }
+                required.push((e, a, v));
+            }
+            if required.len() > 0 { found.push((required, span.clone())); }
+        }
+        for (required, span) in found {
+            let described:Vec<String> = required.iter().map(|&(e, a, v)| {
+                match (self.constant(a), self.constant(v)) {
+                    (Some(TAG_INTERNED_ID), Some(tag)) => format!("`{}` is tagged `#{}`", self.describe(interner, e), Internable::to_string(interner.get_value(tag))),
+                    (Some(a), _) => format!("`{}` has a `{}`", self.describe(interner, e), Internable::to_string(interner.get_value(a))),
+                    _ => format!("`{}` has an attribute", self.describe(interner, e)),
+                }
+            }).collect();
+            let reason = format!("the `not` only asks for what's already required, {}", described.join(" and "));
+            self.error(&span, error::Error::NeverMatches(reason));
+        }
+    }
+
     pub fn get_register(&mut self, name: &str) -> Field {
         let ref mut id = self.id;
         let ix = *self.vars.entry(name.to_string()).or_insert_with(|| { *id += 1; *id });
@@ -1645,7 +2216,7 @@ impl Compilation {
         results
     }
 
-    pub fn get_inputs(&self, haystack: &Vec<Constraint>) -> HashSet<Field> {
+    pub fn get_inputs(&self, haystack: &Vec<Constraint>) -> HashSet<Field, MyHasher> {
         let mut regs = make_det_hash_set();
         let mut input_regs = make_det_hash_set();
         for needle in self.constraints.iter() {
@@ -1664,14 +2235,22 @@ impl Compilation {
         input_regs
     }
 
-    pub fn finalize(&mut self) {
-        self.reassign_registers();
+    pub fn finalize(&mut self, interner:&mut Interner) {
+        let reassigned = self.reassign_registers();
+        let access = self.access_registers(&reassigned);
+        let folded = fold_constants(interner, &mut self.constraints);
+        let scans = merge_access_scans(&mut self.constraints, &access);
+        let functions = merge_common_functions(&mut self.constraints);
+        if folded || scans || functions {
+            renumber_registers(&mut self.constraints);
+        }
+        fuse_range_scans(&mut self.constraints);
         let mut collapsed = make_det_hash_set();
         collapsed.extend(self.constraints.drain(..));
         self.constraints.extend(collapsed);
     }
 
-    pub fn reassign_registers(&mut self) {
+    pub fn reassign_registers(&mut self) -> HashMap<Field, Field, MyHasher> {
         let mut regs = make_det_hash_map();
         let ref var_values = self.var_values;
         let mut ix = 0;
@@ -1700,6 +2279,21 @@ impl Compilation {
         for c in self.constraints.iter_mut() {
             c.replace_registers(&regs);
         }
+        regs
+    }
+
+    // The registers attribute access paths like `p.name` ended up in once registers
+    // were reassigned.
+    fn access_registers(&self, reassigned:&HashMap<Field, Field, MyHasher>) -> HashSet<Field> {
+        self.vars.iter()
+            .filter(|&(name, _)| name.starts_with("attr_access|"))
+            .filter_map(|(_, reg)| {
+                let reg = register(*reg);
+                let unified = self.unified_registers.get(&reg).cloned().unwrap_or(reg);
+                reassigned.get(&unified).cloned()
+            })
+            .filter(|field| field.is_register())
+            .collect()
     }
 
     pub fn get_value(&mut self, name: &str) -> Field {
@@ -1723,28 +2317,314 @@ impl Compilation {
     }
 }
 
+fn flip_inequality(op:&str) -> &str {
+    match op {
+        ">" => "<",
+        ">=" => "<=",
+        "<" => ">",
+        "<=" => ">=",
+        _ => op,
+    }
+}
+
+//-------------------------------------------------------------------------
+// Constant folding
+//-------------------------------------------------------------------------
+
+// Functions that give something different every time, so they run per row even when all
+// of their arguments are constants. gen_id is among them since a program can swap in its
+// own id generator after the block is compiled.
+const UNFOLDABLE:&'static [&'static str] = &["random/number", "random/uuid", "sample", "date/now", "gen_id"];
+
+enum Fold {
+    Keep,
+    Drop,
+    Replace(Field, Interned),
+}
+
+// Functions whose arguments are all constants are run once here instead of for every
+// row, and their result replaces their output throughout the block, so `x = 2 + 3 * 4`
+// is just 14 by the time the block runs. Filters between constants that hold are
+// dropped. A function that fails or a filter that doesn't hold is left alone, the block
+// fails on it at runtime same as before.
+fn fold_constants(interner:&mut Interner, constraints:&mut Vec<Constraint>) -> bool {
+    let pinned = match pinned_registers(constraints) {
+        Some(pinned) => pinned,
+        None => return false,
+    };
+    let mut folded = false;
+    let mut ix = 0;
+    while ix < constraints.len() {
+        // a placeholder only gets its value when the prepared query runs
+        let placeholder = constraints[ix].fields_mut().iter().any(|field| match **field {
+            Field::Value(value) => query_param_name(interner.get_value(value)).is_some(),
+            _ => false,
+        });
+        if placeholder { ix += 1; continue; }
+        let fold = match constraints[ix] {
+            Constraint::Function { ref op, func, ref params, output, .. } if !UNFOLDABLE.contains(&&op[..]) && params.iter().all(|param| !param.is_register()) => {
+                let result = func(params.iter().map(|param| interner.get_value(param.to_value())).collect());
+                match (result, output) {
+                    (Some(result), Field::Register(_)) if !pinned.contains(&output) => Fold::Replace(output, interner.internable_to_id(result)),
+                    (Some(result), Field::Value(value)) if interner.id(&result) == Some(value) => Fold::Drop,
+                    _ => Fold::Keep,
+                }
+            }
+            Constraint::Filter { func, left: Field::Value(left), right: Field::Value(right), .. } => {
+                if func(interner.get_value(left), interner.get_value(right)) { Fold::Drop } else { Fold::Keep }
+            }
+            _ => Fold::Keep,
+        };
+        match fold {
+            Fold::Keep => { ix += 1; continue; }
+            Fold::Drop => { constraints.remove(ix); }
+            Fold::Replace(output, value) => {
+                constraints.remove(ix);
+                replace_fields(constraints, |field| if field == output { Field::Value(value) } else { field });
+            }
+        }
+        folded = true;
+        // what's been replaced may have made earlier constraints foldable too
+        ix = 0;
+    }
+    folded
+}
+
+// The registers a pass can't replace, since projects refer to registers by number and
+// the solver binds the outputs of multi-output functions and intermediate scans by register.
+// Blocks with aggregates aren't touched at all, as an aggregate's outputs aren't
+// renumbered along with everything else.
+fn pinned_registers(constraints:&Vec<Constraint>) -> Option<HashSet<Field>> {
+    let mut pinned = HashSet::new();
+    for constraint in constraints.iter() {
+        match constraint {
+            &Constraint::Aggregate { .. } => return None,
+            &Constraint::Project { .. } => pinned.extend(constraint.get_registers()),
+            &Constraint::MultiFunction { ref outputs, .. } |
+            &Constraint::IndexFunction { ref outputs, .. } |
+            &Constraint::CustomFunction { ref outputs, .. } => pinned.extend(outputs.iter().cloned()),
+            &Constraint::IntermediateScan { ref value, .. } => pinned.extend(value.iter().cloned()),
+            _ => {}
+        }
+    }
+    Some(pinned)
+}
+
+// The solver expects registers numbered from 0 without gaps, which replacing some of
+// them leaves.
+fn renumber_registers(constraints:&mut Vec<Constraint>) {
+    let mut renumbered:HashMap<Field, Field> = HashMap::new();
+    for constraint in constraints.iter() {
+        for field in constraint.get_registers() {
+            let next = Field::Register(renumbered.len());
+            renumbered.entry(field).or_insert(next);
+        }
+    }
+    replace_fields(constraints, |field| renumbered[&field]);
+}
+
+//-------------------------------------------------------------------------
+// Common scans and functions
+//-------------------------------------------------------------------------
+
+// Attribute access gives each path its own register, so once `p = q` is unified,
+// `p.address.city` and `q.address.city` are two chains of scans of the same thing. A
+// path means the same value wherever it's written, so the later scans' registers are
+// pointed at the first's, which leaves them exact duplicates that get collapsed once
+// the block is finalized. Other scans of the same entity and attribute are left alone:
+// an attribute can have many values and each of those scans ranges over all of them.
+fn merge_access_scans(constraints:&mut Vec<Constraint>, access:&HashSet<Field>) -> bool {
+    let pinned = match pinned_registers(constraints) {
+        Some(pinned) => pinned,
+        None => return false,
+    };
+    let mut merged = false;
+    loop {
+        let mut firsts = HashMap::new();
+        let mut found = None;
+        for constraint in constraints.iter() {
+            if let &Constraint::Scan { e, a, v, .. } = constraint {
+                if !access.contains(&v) { continue; }
+                let first = *firsts.entry((e, a)).or_insert(v);
+                if first != v && !pinned.contains(&v) {
+                    found = Some((v, first));
+                    break;
+                }
+            }
+        }
+        let (later, first) = match found {
+            Some(found) => found,
+            None => return merged,
+        };
+        // merging one step of a path lines up the scans for the next
+        replace_fields(constraints, |field| if field == later { first } else { field });
+        merged = true;
+    }
+}
+
+// The same goes for functions, e.g. `x = a + 1` and `y = a + 1`. Given the same
+// arguments a function gives the same result, so only the first is kept and the
+// others' outputs are pointed at its output.
+fn merge_common_functions(constraints:&mut Vec<Constraint>) -> bool {
+    let pinned = match pinned_registers(constraints) {
+        Some(pinned) => pinned,
+        None => return false,
+    };
+    let mut merged = false;
+    loop {
+        let mut found = None;
+        for (ix, constraint) in constraints.iter().enumerate() {
+            if let &Constraint::Function { ref op, ref params, output: output @ Field::Register(_), .. } = constraint {
+                if UNFOLDABLE.contains(&&op[..]) || pinned.contains(&output) { continue; }
+                let first = constraints[..ix].iter().filter_map(|earlier| match earlier {
+                    &Constraint::Function { op: ref earlier_op, params: ref earlier_params, output: earlier_output, .. } if earlier_op == op && earlier_params == params => Some(earlier_output),
+                    _ => None,
+                }).next();
+                if let Some(first) = first {
+                    found = Some((ix, output, first));
+                    break;
+                }
+            }
+        }
+        let (ix, output, first) = match found {
+            Some(found) => found,
+            None => return merged,
+        };
+        constraints.remove(ix);
+        replace_fields(constraints, |field| if field == output { first } else { field });
+        merged = true;
+    }
+}
+
+fn replace_fields<F:Fn(Field) -> Field>(constraints:&mut Vec<Constraint>, replace:F) {
+    let mut lookup = HashMap::new();
+    for constraint in constraints.iter() {
+        for field in constraint.get_registers() {
+            lookup.insert(field, replace(field));
+        }
+        // a function's output is looked up even when it's already a constant
+        if let &Constraint::Function { output: output @ Field::Value(_), .. } = constraint {
+            lookup.insert(output, output);
+        }
+    }
+    for constraint in constraints.iter_mut() {
+        constraint.replace_registers(&lookup);
+    }
+}
+
+// Filters comparing a scanned value against a constant (`age > 30`) become bounds on
+// the scan so it only proposes the records in range. The filter is left in place since
+// it's still what decides the comparison for anything the ordered index can't order.
+fn fuse_range_scans(constraints:&mut Vec<Constraint>) {
+    let mut bounds:HashMap<Field, (Option<(Field, bool)>, Option<(Field, bool)>), MyHasher> = make_det_hash_map();
+    for constraint in constraints.iter() {
+        if let &Constraint::Filter { ref op, left, right, .. } = constraint {
+            let (register, op, value) = match (left, right) {
+                (Field::Register(_), Field::Value(_)) => (left, &op[..], right),
+                (Field::Value(_), Field::Register(_)) => (right, flip_inequality(op), left),
+                _ => continue,
+            };
+            let entry = bounds.entry(register).or_insert((None, None));
+            match op {
+                ">" => { entry.0 = Some((value, false)); }
+                ">=" => { entry.0 = Some((value, true)); }
+                "<" => { entry.1 = Some((value, false)); }
+                "<=" => { entry.1 = Some((value, true)); }
+                _ => {}
+            }
+        }
+    }
+    if bounds.len() == 0 { return; }
+    for constraint in constraints.iter_mut() {
+        let fused = match *constraint {
+            Constraint::Scan { e, a: a @ Field::Value(_), v, .. } => {
+                match bounds.get(&v) {
+                    Some(&(low, high)) if low.is_some() || high.is_some() => make_range_scan(e, a, v, low, high),
+                    _ => continue,
+                }
+            }
+            _ => continue,
+        };
+        *constraint = fused;
+    }
+}
+
+// Roughly how many rows a scan will propose when only its constant fields are bound. Scans
+// with a variable attribute could be touching anything, so they sort last.
+fn estimate_scan(index:&HashIndex, e:Field, a:Field, v:Field) -> u32 {
+    let stats = match a {
+        Field::Value(a) => index.attribute_stats(a),
+        Field::Register(_) => return u32::MAX,
+    };
+    match (e, v) {
+        (Field::Value(_), Field::Value(_)) => cmp::min(stats.facts, 1),
+        (Field::Value(_), _) => stats.facts / cmp::max(stats.entities, 1),
+        (_, Field::Value(_)) => stats.facts / cmp::max(stats.values, 1),
+        _ => stats.facts,
+    }
+}
+
+/// Reorders a block's scans so the ones expected to produce the fewest rows come first,
+/// using the current shape of `index`. The solver still picks the cheapest proposal at
+/// each step, but ties go to the earlier scan and accepts are checked in order, so putting
+/// the most selective scans first means rows get rejected as early as possible. Returns
+/// whether anything moved.
+pub fn order_scans(constraints:&mut Vec<Constraint>, index:&HashIndex) -> bool {
+    let mut positions = vec![];
+    let mut scans = vec![];
+    for (ix, constraint) in constraints.iter().enumerate() {
+        let estimate = match constraint {
+            &Constraint::Scan { e, a, v, .. } |
+            &Constraint::RangeScan { e, a, v, .. } => estimate_scan(index, e, a, v),
+            _ => continue,
+        };
+        let original = positions.len();
+        positions.push(ix);
+        scans.push((estimate, original, constraint.clone()));
+    }
+    // sorting on the original position too keeps equally selective scans in source order
+    scans.sort_by_key(|&(estimate, original, _)| (estimate, original));
+    let mut moved = false;
+    for (&ix, (_, original, scan)) in positions.iter().zip(scans.into_iter()) {
+        if positions[original] != ix { moved = true; }
+        constraints[ix] = scan;
+    }
+    moved
+}
+
 pub fn make_block(interner:&mut Interner, name:&str, content:&str) -> Vec<Block> {
     let mut state = ParseState::new(content);
     let parsed = block(&mut state);
     let mut comp = Compilation::new(name.to_string());
-    // println!("Parsed {:?}", parsed);
+    trace(DebugMode::Parse, || format!("Parsed {:?}", parsed));
     match parsed {
         ParseResult::Ok(mut block) => {
             block.gather_equalities(interner, &mut comp);
             block.unify(&mut comp);
+            trace_unified(&comp);
             block.compile(interner, &mut comp, &EMPTY_SPAN);
         }
-        _ => { println!("Failed: {:?}", parsed); }
+        _ => { trace(DebugMode::Parse, || format!("Failed: {:?}", parsed)); }
     }
 
-    comp.finalize();
-    // for c in comp.constraints.iter() {
-    //     println!("{:?}", c);
-    // }
-    compilation_to_blocks(comp, interner, name, content, false)
+    comp.finalize(interner);
+    compilation_to_blocks(comp, interner, name, content)
+}
+
+fn trace_unified(comp:&Compilation) {
+    trace(DebugMode::Unify, || {
+        let mut result = format!("---------------------- Unified {} ---------------------------\n", comp.block_name);
+        let mut vars:Vec<_> = comp.vars.iter().collect();
+        vars.sort_by_key(|&(_, reg)| *reg);
+        for (name, reg) in vars {
+            let field = Field::Register(*reg);
+            result.push_str(&format!("   {} ({:?}) => {:?}\n", name, field, comp.unified_registers.get(&field).unwrap_or(&field)));
+        }
+        result
+    });
 }
 
-pub fn compilation_to_blocks(mut comp:Compilation, interner: &mut Interner, path:&str, source: &str, debug: bool) -> Vec<Block> {
+pub fn compilation_to_blocks(mut comp:Compilation, interner: &mut Interner, path:&str, source: &str) -> Vec<Block> {
     let mut compilation_blocks = vec![];
     if comp.errors.len() > 0 {
         report_errors(&comp.errors, path, source);
@@ -1760,13 +2640,14 @@ pub fn compilation_to_blocks(mut comp:Compilation, interner: &mut Interner, path
         let mut cur = subs.pop().unwrap();
         let mut sub_comp = cur.get_mut_compilation();
         if sub_comp.constraints.len() > 0 {
-            sub_comp.finalize();
-            if debug {
-                println!("       SubBlock: {}", sub_name);
+            sub_comp.finalize(interner);
+            trace(DebugMode::Compile, || {
+                let mut result = format!("       SubBlock: {}", sub_name);
                 for c in sub_comp.constraints.iter() {
-                    println!("            {:?}", c);
+                    result.push_str(&format!("\n            {:?}", c));
                 }
-            }
+                result
+            });
             let interned_name = interner.string_id(&sub_name);
             let mut block = Block::new(interner, &sub_name, interned_name, sub_comp.constraints.clone());
             block.path = path.to_owned();
@@ -1782,43 +2663,404 @@ pub fn compilation_to_blocks(mut comp:Compilation, interner: &mut Interner, path
     compilation_blocks
 }
 
-pub fn parse_string(interner:&mut Interner, content:&str, path:&str, debug: bool) -> Vec<Block> {
+//-------------------------------------------------------------------------
+// Strict mode
+//-------------------------------------------------------------------------
+
+/// How a file is compiled. A file can also turn strict mode on for itself with an
+/// `eve:strict` line outside of its blocks and code fences, and declare attributes that
+/// only watchers read with `eve:attributes name age ...`.
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// Fail blocks whose actions write an attribute nothing in the project searches for
+    /// and that isn't declared, which is what a typo like `[#person nmae]` looks like.
+    pub strict: bool,
+    pub attributes: Vec<String>,
+    pub functions: CustomFunctions,
+}
+
+impl CompileOptions {
+    fn for_file(&self, content:&str) -> CompileOptions {
+        let mut options = self.clone();
+        for line in prose_lines(content) {
+            let line = line.trim();
+            if line == "eve:strict" {
+                options.strict = true;
+            } else if line.starts_with("eve:attributes ") {
+                options.attributes.extend(line["eve:attributes ".len()..].split_whitespace().map(|a| a.to_string()));
+            }
+        }
+        options
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AttributeUses<'a> {
+    pub searched: HashSet<&'a str>,
+    pub written: Vec<(&'a str, Span)>,
+}
+
+// What the blocks in `content` search for, so strict mode can check a file's writes
+// against the whole project rather than just the file itself.
+fn searched_attributes(content:&str) -> Vec<String> {
     let mut state = ParseState::new(content);
-    let res = embedded_blocks(&mut state, path);
-    if let ParseResult::Ok(mut cur) = res {
-        if let Node::Doc { ref mut blocks, .. } = cur {
-            let mut program_blocks = vec![];
-            let mut ix = 0;
-            for block in blocks {
-                ix += 1;
-                let block_name = format!("{}|block|{}", path, ix);
-                let mut comp = Compilation::new(block_name.to_string());
-                block.gather_equalities(interner, &mut comp);
-                block.unify(&mut comp);
-                block.compile(interner, &mut comp, &EMPTY_SPAN);
-
-                comp.finalize();
-                if debug {
-                    println!("---------------------- Block {} ---------------------------", block_name);
-                    if let &mut Node::Block { code, ..} = block {
-                        println!("{}\n\n => \n", code);
-                    }
-                    for c in comp.constraints.iter() {
-                        println!("   {:?}", c);
-                    }
+    let blocks = match embedded_blocks(&mut state, "") {
+        ParseResult::Ok(Node::Doc { blocks, .. }) => blocks,
+        _ => return vec![],
+    };
+    let mut uses = AttributeUses::default();
+    for block in blocks.iter() {
+        block.attribute_uses(&EMPTY_SPAN, &mut uses);
+    }
+    uses.searched.iter().map(|attribute| attribute.to_string()).collect()
+}
+
+fn check_strict(comp:&mut Compilation, uses:&AttributeUses, known:&HashSet<&str>) {
+    for &(attribute, ref span) in uses.written.iter() {
+        if !known.contains(attribute) {
+            let suggestions = closest_matches(attribute, known.iter().map(|a| a.to_string()).collect::<Vec<String>>().iter());
+            comp.error(span, error::Error::UndeclaredAttribute(attribute.to_string(), suggestions));
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+// Stratification
+//-------------------------------------------------------------------------
+
+// A block whose `not` depends on what the block itself binds, directly or through other
+// blocks, never settles: the bind makes the `not` fail, which retracts the bind, which
+// makes the `not` hold again. Blocks are linked from what they bind to whatever reads it
+// and a cycle through an anti-scan is a negation cycle. Records are told apart by the tags
+// a block knows they have, so binding `[#greeting name]` doesn't feed `not([#banned name])`.
+// Commits change state rather than derive it, so they aren't followed.
+//
+// A record a block creates shows up with everything bound on it at once, so it can't feed
+// a sub-block's reads of the records its owning block hands it: by the time the new record
+// is handed over, the sub-block already sees all of it.
+
+struct Dependencies {
+    // (tags, attribute, value, whether the entity is handed in by the owning block)
+    reads: Vec<(Vec<Interned>, Interned, Interned, bool)>,
+    // (tags, attribute, value, whether the entity is a record the block creates)
+    binds: Vec<(Vec<Interned>, Interned, Interned, bool)>,
+    // the intermediates read, and whether through a `not`
+    scans: Vec<(Interned, bool)>,
+    writes: Vec<Interned>,
+}
+
+fn constant(field:&Field) -> Interned {
+    if let &Field::Value(value) = field { value } else { 0 }
+}
+
+fn scanned(constraint:&Constraint) -> Option<(Field, Field, Field)> {
+    match constraint {
+        &Constraint::Scan { e, a, v, .. } |
+        &Constraint::RangeScan { e, a, v, .. } |
+        &Constraint::LookupCommit { e, a, v, .. } => Some((e, a, v)),
+        _ => None,
+    }
+}
+
+// A sub-block repeats the scans that bind its inputs, which are the owning block's reads
+// rather than anything the sub-block depends on.
+fn block_dependencies(interner:&Interner, block:&Block, owner:Option<&Block>) -> Dependencies {
+    // a scoped tag tells records apart as well as a plain one
+    let is_tag = |a:Interned| a == TAG_INTERNED_ID || match interner.get_value(a) {
+        &Internable::Scoped(_, ref attribute) => attribute == "tag",
+        _ => false,
+    };
+    let mut tags:HashMap<Field, Vec<Interned>> = HashMap::new();
+    for constraint in block.constraints.iter() {
+        match constraint {
+            &Constraint::Scan { e, a: Field::Value(a), v: Field::Value(tag), .. } |
+            &Constraint::Insert { e, a: Field::Value(a), v: Field::Value(tag), .. } if is_tag(a) => {
+                tags.entry(e).or_insert_with(|| vec![]).push(tag);
+            }
+            _ => {}
+        }
+    }
+    let inherited:HashSet<(Field, Field)> = owner.iter().flat_map(|owner| owner.constraints.iter().filter_map(scanned)).map(|(_, a, v)| (a, v)).collect();
+    let mut inputs = HashSet::new();
+    let mut created = HashSet::new();
+    for constraint in block.constraints.iter() {
+        match constraint {
+            &Constraint::InsertIntermediate { ref key, .. } if owner.is_some() => inputs.extend(key[1..].iter().cloned()),
+            &Constraint::Function { ref op, output, .. } if op == "gen_id" => { created.insert(output); }
+            _ => {}
+        }
+    }
+    let tags_of = |e:&Field| tags.get(e).cloned().unwrap_or_default();
+    let mut deps = Dependencies { reads: vec![], binds: vec![], scans: vec![], writes: vec![] };
+    for constraint in block.constraints.iter() {
+        match constraint {
+            &Constraint::Scan { ref e, ref a, ref v, .. } |
+            &Constraint::RangeScan { ref e, ref a, ref v, .. } |
+            &Constraint::LookupCommit { ref e, ref a, ref v, .. } => {
+                // sub-blocks are renumbered, so an untagged scan is matched to the owning
+                // block's by attribute, and reading every value of it covers a branch that
+                // only looks for one
+                let covered = tags_of(e).is_empty() && inherited.iter().any(|&(a2, v2)| a2 == *a && (v2 == *v || v2.is_register()));
+                if !covered { deps.reads.push((tags_of(e), constant(a), constant(v), inputs.contains(e))); }
+            }
+            &Constraint::Insert { ref e, ref a, ref v, commit: false } => {
+                // a record made only of constants gets its id at compile time
+                deps.binds.push((tags_of(e), constant(a), constant(v), !e.is_register() || created.contains(e)))
+            }
+            &Constraint::AntiScan { ref key, .. } => deps.scans.push((constant(&key[0]), true)),
+            &Constraint::IntermediateScan { ref key, .. } => deps.scans.push((constant(&key[0]), false)),
+            &Constraint::InsertIntermediate { ref key, .. } => deps.writes.push(constant(&key[0])),
+            &Constraint::Aggregate { ref group, ref output_key, .. } => {
+                deps.writes.push(constant(&group[0]));
+                deps.writes.push(constant(&output_key[0]));
+            }
+            _ => {}
+        }
+    }
+    deps
+}
+
+fn feeds(bind:&(Vec<Interned>, Interned, Interned, bool), read:&(Vec<Interned>, Interned, Interned, bool)) -> bool {
+    let (ref bound_tags, a, v, created) = *bind;
+    let (ref read_tags, a2, v2, input) = *read;
+    if created && input { return false; }
+    let tags = bound_tags.is_empty() || read_tags.is_empty() || bound_tags.iter().any(|tag| read_tags.contains(tag));
+    // a bound record's tags include the ones it's given, so tagging an existing record
+    // still feeds whatever reads the new tag
+    (a == 0 || a2 == 0 || a == a2) && (v == 0 || v2 == 0 || v == v2) && tags
+}
+
+// The block a sub-block was compiled out of.
+fn owning_block(name:&str) -> &str {
+    match name.find("|sub_block|") {
+        Some(end) => &name[..end],
+        None => name,
+    }
+}
+
+/// Every cycle through a `not` among `blocks`, as the names of the blocks around it
+/// starting and ending with the block the `not` is in.
+pub fn negation_cycles(interner:&Interner, blocks:&[Block]) -> Vec<Vec<String>> {
+    let deps:Vec<Dependencies> = blocks.iter().map(|block| {
+        let owner = blocks.iter().find(|owner| owner.name != block.name && owner.name == owning_block(&block.name));
+        block_dependencies(interner, block, owner)
+    }).collect();
+    // edges[writer] holds (reader, through a `not`)
+    let mut edges:Vec<Vec<(usize, bool)>> = vec![vec![]; blocks.len()];
+    for (writer, written) in deps.iter().enumerate() {
+        for (reader, read) in deps.iter().enumerate() {
+            if let Some(&(_, negated)) = read.scans.iter().find(|&&(id, _)| written.writes.contains(&id)) {
+                edges[writer].push((reader, negated));
+            } else if written.binds.iter().any(|bind| read.reads.iter().any(|read| feeds(bind, read))) {
+                edges[writer].push((reader, false));
+            }
+        }
+    }
+    let mut cycles = vec![];
+    let mut seen = HashSet::new();
+    for writer in 0..blocks.len() {
+        for &(reader, negated) in edges[writer].iter() {
+            if !negated { continue; }
+            // a path back from the block with the `not` to the sub-block it reads closes the cycle
+            let mut previous:HashMap<usize, usize> = HashMap::new();
+            let mut queue = vec![reader];
+            let mut found = reader == writer;
+            while !found && queue.len() > 0 {
+                let cur = queue.remove(0);
+                for &(next, _) in edges[cur].iter() {
+                    if next == reader || previous.contains_key(&next) { continue; }
+                    previous.insert(next, cur);
+                    if next == writer { found = true; break; }
+                    queue.push(next);
                 }
-                program_blocks.extend(compilation_to_blocks(comp, interner, path, content, debug));
             }
-            program_blocks
-        } else {
-            panic!("Got a non-doc parse??");
+            if !found { continue; }
+            let mut path = vec![writer];
+            while let Some(&prev) = previous.get(path.last().unwrap()) {
+                path.push(prev);
+            }
+            path.reverse();
+            let mut names:Vec<String> = vec![owning_block(&blocks[reader].name).to_string()];
+            for ix in path.into_iter().chain(Some(reader)) {
+                let name = owning_block(&blocks[ix].name);
+                if names.last().map_or(true, |last| last != name) { names.push(name.to_string()); }
+            }
+            if names.len() == 1 { names.push(names[0].to_string()); }
+            if seen.insert(names.to_vec()) { cycles.push(names); }
         }
+    }
+    cycles
+}
+
+pub fn parse_string(interner:&mut Interner, content:&str, path:&str) -> Vec<Block> {
+    compile_string(interner, content, path, &CompileOptions::default()).0
+}
+
+pub fn parse_string_with(interner:&mut Interner, content:&str, path:&str, options:&CompileOptions) -> Vec<Block> {
+    compile_string(interner, content, path, options).0
+}
+
+/// Parses and compiles `content` without running anything, reporting errors the same
+/// way loading it would. Returns how many blocks compiled and how many errors there were.
+pub fn check_string(interner:&mut Interner, content:&str, path:&str) -> (usize, usize) {
+    let (blocks, errors) = compile_string(interner, content, path, &CompileOptions::default());
+    (blocks.len(), errors)
+}
+
+/// The `minimize` or `maximize` in the search of the first block in `content`, when
+/// what it ranks is a variable. `column` is where that variable is among the values the
+/// block projects, counting only the ones that end up in its rows, since constants are
+/// left out of them.
+#[derive(Debug, Clone)]
+pub struct QueryObjective {
+    pub maximize: bool,
+    pub variable: String,
+    pub column: Option<usize>,
+    pub span: Span,
+}
+
+pub fn query_objective(content:&str) -> Option<QueryObjective> {
+    let mut state = ParseState::new(content);
+    let blocks = match embedded_blocks(&mut state, "query") {
+        ParseResult::Ok(Node::Doc { blocks, .. }) => blocks,
+        _ => return None,
+    };
+    let (search, update) = match blocks.first().map(|block| block.unwrap_ref_pos()) {
+        Some(&Node::Block { ref search, ref update, .. }) => (search, update),
+        _ => return None,
+    };
+    let section = match **search {
+        Some(ref section) => match section.unwrap_ref_pos() {
+            &Node::Scoped(_, ref section) => section.unwrap_ref_pos(),
+            section => section,
+        },
+        None => return None,
+    };
+    let statements = match section {
+        &Node::Search(ref statements) => statements,
+        _ => return None,
+    };
+    let (span, sense, value) = match statements.iter().filter_map(|statement| match statement {
+        &Node::Pos(ref span, ref node) => match **node {
+            Node::Objective(sense, _) => Some((span, sense, node.objective_value())),
+            _ => None,
+        },
+        _ => None,
+    }).next() {
+        Some((span, sense, Some(value))) => (span, sense, value),
+        _ => return None,
+    };
+    let variable = match value.unwrap_ref_pos() {
+        &Node::Variable(name) => name,
+        _ => return None,
+    };
+    let column = match update.unwrap_ref_pos() {
+        &Node::Project(ref items) => items.iter().filter(|item| match item.unwrap_ref_pos() {
+            &Node::Integer(_) | &Node::Float(_) | &Node::Decimal(_) | &Node::RawString(_) | &Node::Placeholder(_) | &Node::NoneValue => false,
+            _ => true,
+        }).position(|item| match item.unwrap_ref_pos() {
+            &Node::Variable(name) => name == variable,
+            _ => false,
+        }),
+        _ => None,
+    };
+    Some(QueryObjective { maximize: sense == "maximize", variable: variable.to_string(), column, span: span.clone() })
+}
+
+/// The blocks that compiled along with how many errors were reported.
+pub fn compile_string(interner:&mut Interner, content:&str, path:&str, options:&CompileOptions) -> (Vec<Block>, usize) {
+    if let Some((Err(version), span)) = syntax_header(content) {
+        let supported = SUPPORTED_SYNTAX.iter().map(|syntax| syntax.to_string()).collect();
+        report_errors(&vec![CompileError { span, error: error::Error::UnsupportedSyntax(version, supported) }], path, content);
+        return (vec![], 1);
+    }
+    let options = options.for_file(content);
+    let mut state = ParseState::new(content);
+    let res = embedded_blocks(&mut state, path);
+    trace(DebugMode::Parse, || format!("Parsed {}: {:?}", path, res));
+    let mut cur = match res {
+        ParseResult::Ok(cur) => cur,
+        failed => {
+            // a file that doesn't parse is reported like any other error, not a crash
+            let error = match failed {
+                ParseResult::Error(..) => error::from_parse_error(&failed),
+                _ => invalid_document(),
+            };
+            report_errors(&vec![error], path, content);
+            return (vec![], 1);
+        }
+    };
+    if let Node::Doc { ref mut blocks, .. } = cur {
+        let mut program_blocks = vec![];
+        let mut errors = 0;
+        let mut ix = 0;
+        // strict mode checks writes against what every block in the file searches for
+        let uses:Vec<AttributeUses> = blocks.iter().map(|block| {
+            let mut uses = AttributeUses::default();
+            if options.strict { block.attribute_uses(&EMPTY_SPAN, &mut uses); }
+            uses
+        }).collect();
+        let mut known:HashSet<&str> = uses.iter().flat_map(|uses| uses.searched.iter().cloned()).collect();
+        known.extend(options.attributes.iter().map(|a| &a[..]));
+        known.insert("tag");
+        let mut spans:HashMap<String, Span> = HashMap::new();
+        for (block, uses) in blocks.iter_mut().zip(uses.iter()) {
+            ix += 1;
+            let metadata = block.block_metadata();
+            let block_name = match metadata.name {
+                Some(ref name) => name.to_string(),
+                None => format!("{}|block|{}", path, ix),
+            };
+            if let Node::Pos(ref span, _) = *block {
+                spans.insert(block_name.to_string(), span.clone());
+            }
+            let mut comp = Compilation::new(block_name.to_string());
+            comp.functions = options.functions.clone();
+            block.gather_equalities(interner, &mut comp);
+            block.unify(&mut comp);
+            trace_unified(&comp);
+            block.compile(interner, &mut comp, &EMPTY_SPAN);
+            check_strict(&mut comp, uses, &known);
+
+            comp.finalize(interner);
+            trace(DebugMode::Compile, || {
+                let mut result = format!("---------------------- Block {} ---------------------------\n", block_name);
+                if let &mut Node::Block { code, ..} = block {
+                    result.push_str(&format!("{}\n\n => \n\n", code));
+                }
+                for c in comp.constraints.iter() {
+                    result.push_str(&format!("   {:?}\n", c));
+                }
+                result
+            });
+            errors += comp.errors.len();
+            let mut compiled = compilation_to_blocks(comp, interner, path, content);
+            // sub blocks only feed the block itself, so only it carries the metadata
+            if let Some(block) = compiled.last_mut() { block.metadata = metadata; }
+            program_blocks.extend(compiled);
+        }
+        // a block in a negation cycle is reported and left out, sub-blocks and all
+        let mut cyclic = vec![];
+        for cycle in negation_cycles(interner, &program_blocks) {
+            let span = spans.get(&cycle[0]).cloned().unwrap_or(EMPTY_SPAN.clone());
+            cyclic.push(cycle[0].to_string());
+            report_errors(&vec![CompileError { span, error: error::Error::NegationCycle(cycle) }], path, content);
+            errors += 1;
+        }
+        program_blocks.retain(|block| !cyclic.iter().any(|name| name == owning_block(&block.name)));
+        (program_blocks, errors)
     } else {
-        panic!("Failed to parse");
+        report_errors(&vec![invalid_document()], path, content);
+        (vec![], 1)
     }
 }
 
-pub fn parse_file(interner:&mut Interner, path:&str, report: bool, debug: bool) -> Vec<Block> {
+fn invalid_document() -> CompileError {
+    CompileError { span: EMPTY_SPAN.clone(), error: error::Error::ParseError(error::ParseError::InvalidBlock) }
+}
+
+/// The .eve and .md files at `path`, which is either one file or a folder to search.
+pub fn eve_files(path:&str) -> Vec<String> {
     let metadata = fs::metadata(path).expect(&format!("Invalid path: {:?}", path));
     let mut paths = vec![];
     if metadata.is_file() {
@@ -1836,19 +3078,105 @@ pub fn parse_file(interner:&mut Interner, path:&str, report: bool, debug: bool)
            }
        }
     }
-    let mut blocks = vec![];
-    for cur_path in paths {
-        if report {
-            println!("{} {}", BrightCyan.paint("Compiling:"), cur_path.replace("\\","/"));
+    paths
+}
+
+/// What to tell the user about a file without a `syntax` header, if it doesn't have one.
+pub fn missing_syntax_warning(path:&str, content:&str) -> Option<String> {
+    if syntax_header(content).is_some() { return None; }
+    Some(format!("{} has no `syntax` header, so it's read as syntax {}. Start it with `syntax {}` to use the latest syntax.", path, DEFAULT_SYNTAX, LATEST_SYNTAX))
+}
+
+pub fn parse_file(interner:&mut Interner, path:&str, report: bool) -> Vec<Block> {
+    parse_file_with(interner, path, report, &CompileOptions::default())
+}
+
+pub fn parse_file_with(interner:&mut Interner, path:&str, report: bool, options:&CompileOptions) -> Vec<Block> {
+    let files = eve_files(path);
+    let mut options = options.clone();
+    let sources:Vec<String> = files.iter().filter_map(|file| fs::read_to_string(file).ok()).collect();
+    if options.strict || sources.iter().any(|source| options.for_file(source).strict) {
+        for source in sources.iter() {
+            options.attributes.extend(searched_attributes(source));
         }
-        let mut file = File::open(&cur_path).expect("Unable to open the file");
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).expect("Unable to read the file");
-        blocks.extend(parse_string(interner, &contents, &cur_path, debug).into_iter());
+    }
+    let mut blocks = vec![];
+    let mut loaded = HashSet::new();
+    for cur_path in files {
+        load_file(interner, &cur_path, report, &options, &mut loaded, &mut vec![], &mut blocks);
     }
     blocks
 }
 
+//-------------------------------------------------------------------------
+// Imports
+//-------------------------------------------------------------------------
+
+// A line like `import "util.eve"` outside of any block loads that file, relative to
+// the one importing it, before the importer's own blocks. Every file is loaded once
+// however many files import it, and its blocks are named after its own path, so two
+// files' blocks never collide. Imports that form a cycle are reported as errors.
+
+/// The paths imported by `content`, with where each import is in it.
+pub fn file_imports(content:&str) -> Vec<(String, Span)> {
+    let mut imports = vec![];
+    let mut pos = 0;
+    for (line_ix, line) in content.split("\n").enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("import \"") && trimmed.ends_with("\"") && trimmed.len() > "import \"\"".len() {
+            let ch = line.len() - line.trim_left().len();
+            let start = Pos { line: line_ix, ch, pos: pos + ch };
+            let stop = Pos { line: line_ix, ch: ch + trimmed.len(), pos: pos + ch + trimmed.len() };
+            imports.push((trimmed["import \"".len()..trimmed.len() - 1].to_string(), Span { start, stop }));
+        }
+        pos += line.len() + 1;
+    }
+    imports
+}
+
+fn canonical_path(path:&Path) -> PathBuf {
+    path.canonicalize().unwrap_or(path.to_path_buf())
+}
+
+fn load_file(interner:&mut Interner, path:&str, report:bool, options:&CompileOptions, loaded:&mut HashSet<PathBuf>, importing:&mut Vec<PathBuf>, blocks:&mut Vec<Block>) {
+    let canonical = canonical_path(Path::new(path));
+    if !loaded.insert(canonical.clone()) { return; }
+    if report {
+        println!("{} {}", BrightCyan.paint("Compiling:"), path.replace("\\","/"));
+    }
+    let mut file = File::open(path).expect("Unable to open the file");
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).expect("Unable to read the file");
+    if report {
+        if let Some(warning) = missing_syntax_warning(&path.replace("\\","/"), &contents) {
+            println!("{} {}", BrightYellow.paint("Warning:"), warning);
+        }
+    }
+
+    importing.push(canonical.clone());
+    let mut errors = vec![];
+    for (import, span) in file_imports(&contents) {
+        let import_path = canonical.parent().unwrap_or(Path::new("")).join(&import);
+        if !import_path.is_file() {
+            errors.push(CompileError { span, error: error::Error::MissingImport(import) });
+            continue;
+        }
+        let import_path = canonical_path(&import_path);
+        if let Some(start) = importing.iter().position(|cur| *cur == import_path) {
+            let mut cycle:Vec<String> = importing[start..].iter().map(|cur| cur.to_string_lossy().replace("\\", "/")).collect();
+            cycle.push(import_path.to_string_lossy().replace("\\", "/"));
+            errors.push(CompileError { span, error: error::Error::ImportCycle(cycle) });
+            continue;
+        }
+        load_file(interner, &import_path.to_string_lossy(), report, options, loaded, importing, blocks);
+    }
+    importing.pop();
+    if errors.len() > 0 {
+        report_errors(&errors, path, &contents);
+    }
+    blocks.extend(parse_string_with(interner, &contents, path, options).into_iter());
+}
+
 #[test]
 pub fn parser_test() {
     let mut file = File::open("examples/test2.eve").expect("Unable to open the file");
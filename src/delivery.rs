@@ -0,0 +1,199 @@
+//-------------------------------------------------------------------------
+// Watcher delivery
+//-------------------------------------------------------------------------
+
+extern crate bincode;
+
+use ops::{Internable, Interner, DebugMode, trace};
+use indexes::{WatchIndex, WatchDiff};
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions, File};
+use std::io::{Write, BufReader, BufWriter};
+use std::mem;
+
+#[derive(Debug, Default)]
+pub struct WatcherCheckpoint {
+    /// The high-water mark: the log position of the last delivery to the watcher.
+    pub position: u64,
+    delivered: HashSet<Vec<Internable>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeliveryRecord {
+    position: u64,
+    watcher: String,
+    adds: Vec<Vec<Internable>>,
+    removes: Vec<Vec<Internable>>,
+}
+
+// A log of what every watcher has been handed. Once the watchers have taken a
+// transaction's diffs, they're appended as records keyed by the transaction's position in
+// the log and synced to disk, which moves each watcher's high-water mark on. Nothing is
+// recorded for a watcher before it has the diff, so a crash can't leave the log claiming a
+// delivery that never happened. If the records can't be written, the whole log is
+// rewritten with the next transaction instead.
+//
+// A program that restarts against the log replays it up to each watcher's high-water
+// mark, skipping any record at or below it, and then only delivers what each watcher
+// missed: rows it never saw get added and rows that went away while it was down get
+// removed. The only thing that can be delivered twice is the diff a crash cut off before
+// it was recorded. The replayed log is compacted down to one record per watcher, so it
+// only grows with the deliveries made since the last start.
+pub struct DeliveryLog {
+    path: Option<String>,
+    writer: Option<BufWriter<File>>,
+    position: u64,
+    checkpoints: HashMap<String, WatcherCheckpoint>,
+    resuming: HashSet<String>,
+    pending: Vec<DeliveryRecord>,
+    // the last records couldn't be written, so the log on disk is behind the checkpoints
+    behind: bool,
+}
+
+impl DeliveryLog {
+    pub fn new() -> DeliveryLog {
+        DeliveryLog { path: None, writer: None, position: 0, checkpoints: HashMap::new(), resuming: HashSet::new(), pending: vec![], behind: false }
+    }
+
+    pub fn load(path:&str) -> DeliveryLog {
+        let mut log = DeliveryLog::new();
+        log.path = Some(path.to_string());
+        if let Ok(file) = File::open(path) {
+            let mut reader = BufReader::new(file);
+            // a torn record at the end is one that was never flushed, so it was never delivered
+            while let Ok(record) = bincode::deserialize_from::<_, DeliveryRecord>(&mut reader, bincode::Infinite) {
+                let high_water = log.checkpoints.get(&record.watcher).map_or(0, |checkpoint| checkpoint.position);
+                if record.position > high_water {
+                    log.position = cmp::max(log.position, record.position);
+                    log.apply(record);
+                }
+            }
+            log.resuming.extend(log.checkpoints.keys().cloned());
+            if let Err(why) = log.compact() {
+                trace(DebugMode::Runtime, || format!("Unable to compact the watcher deliveries in {}: {}", path, why));
+            }
+        }
+        log
+    }
+
+    pub fn checkpoint(&self, watcher:&str) -> Option<&WatcherCheckpoint> {
+        self.checkpoints.get(watcher)
+    }
+
+    pub fn is_resuming(&self, watcher:&str) -> bool {
+        self.resuming.contains(watcher)
+    }
+
+    pub fn begin(&mut self) {
+        self.position += 1;
+    }
+
+    pub fn deliver(&mut self, watcher:&str, interner:&mut Interner, diff:WatchDiff, index:&WatchIndex) -> WatchDiff {
+        if self.path.is_none() { return diff; }
+        let resuming = self.resuming.remove(watcher);
+        let checkpoint = self.checkpoints.entry(watcher.to_string()).or_insert_with(|| WatcherCheckpoint::default());
+        let mut record = DeliveryRecord { position: self.position, watcher: watcher.to_string(), adds: vec![], removes: vec![] };
+        let mut adds = vec![];
+        let mut removes = vec![];
+        if resuming {
+            // The watch index was rebuilt from scratch, so what it holds now is the
+            // truth and the checkpoint is what the watcher already believes.
+            let current:HashSet<Vec<Internable>> = index.rows().iter().map(|row| {
+                row.iter().map(|v| interner.get_value(*v).clone()).collect()
+            }).collect();
+            for row in checkpoint.delivered.difference(&current) {
+                removes.push(row.iter().map(|v| interner.internable_to_id(v.clone())).collect());
+                record.removes.push(row.clone());
+            }
+            for row in current.difference(&checkpoint.delivered) {
+                adds.push(row.iter().map(|v| interner.internable_to_id(v.clone())).collect());
+                record.adds.push(row.clone());
+            }
+        } else {
+            for add in diff.adds {
+                let row:Vec<Internable> = add.iter().map(|v| interner.get_value(*v).clone()).collect();
+                if !checkpoint.delivered.contains(&row) && !record.adds.contains(&row) {
+                    adds.push(add);
+                    record.adds.push(row);
+                }
+            }
+            for remove in diff.removes {
+                let row:Vec<Internable> = remove.iter().map(|v| interner.get_value(*v).clone()).collect();
+                if checkpoint.delivered.contains(&row) && !record.removes.contains(&row) {
+                    removes.push(remove);
+                    record.removes.push(row);
+                }
+            }
+        }
+        if adds.len() > 0 || removes.len() > 0 {
+            self.pending.push(record);
+        }
+        WatchDiff { adds, removes }
+    }
+
+    /// Records this transaction's deliveries once the watchers have been sent them.
+    pub fn commit(&mut self) -> Result<(), String> {
+        if self.pending.is_empty() && !self.behind { return Ok(()); }
+        let pending = mem::replace(&mut self.pending, vec![]);
+        let appended = if self.behind { Ok(()) } else { self.append(&pending) };
+        // the watchers have these whether or not they could be written down
+        for record in pending { self.apply(record); }
+        let written = match appended {
+            Ok(_) if self.behind => self.compact(),
+            result => result,
+        };
+        self.behind = written.is_err();
+        if self.behind { self.writer = None; }
+        written
+    }
+
+    fn append(&mut self, records:&[DeliveryRecord]) -> Result<(), String> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if self.writer.is_none() {
+            let file = OpenOptions::new().append(true).create(true).open(path).map_err(|why| format!("Unable to open {}: {}", path, why))?;
+            self.writer = Some(BufWriter::new(file));
+        }
+        if let Some(ref mut writer) = self.writer {
+            for record in records {
+                bincode::serialize_into(&mut *writer, record, bincode::Infinite).map_err(|why| format!("Unable to write to {}: {}", path, why))?;
+            }
+            writer.flush().map_err(|why| format!("Unable to write to {}: {}", path, why))?;
+            writer.get_ref().sync_all().map_err(|why| format!("Unable to sync {}: {}", path, why))?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, record:DeliveryRecord) {
+        let checkpoint = self.checkpoints.entry(record.watcher).or_insert_with(|| WatcherCheckpoint::default());
+        for row in record.removes { checkpoint.delivered.remove(&row); }
+        for row in record.adds { checkpoint.delivered.insert(row); }
+        checkpoint.position = record.position;
+    }
+
+    // Rewrites the log as one record per watcher holding everything it's been sent.
+    fn compact(&mut self) -> Result<(), String> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        // write and then rename so a crash mid-write never leaves a torn log
+        let temp_path = format!("{}.tmp", path);
+        {
+            let file = File::create(&temp_path).map_err(|why| format!("Unable to create {}: {}", temp_path, why))?;
+            let mut writer = BufWriter::new(file);
+            for (watcher, checkpoint) in self.checkpoints.iter() {
+                let record = DeliveryRecord { position: checkpoint.position, watcher: watcher.to_string(), adds: checkpoint.delivered.iter().cloned().collect(), removes: vec![] };
+                bincode::serialize_into(&mut writer, &record, bincode::Infinite).map_err(|why| format!("Unable to write to {}: {}", temp_path, why))?;
+            }
+            writer.flush().map_err(|why| format!("Unable to write to {}: {}", temp_path, why))?;
+            writer.get_ref().sync_all().map_err(|why| format!("Unable to sync {}: {}", temp_path, why))?;
+        }
+        fs::rename(&temp_path, &path).map_err(|why| format!("Unable to replace {}: {}", path, why))?;
+        self.writer = None;
+        Ok(())
+    }
+}
@@ -40,8 +40,16 @@ pub enum Error {
     InvalidNeedle,
     InvalidLookupType,
     Unprovided(String),
-    UnknownFunction(String),
-    UnknownFunctionParam(String, String),
+    UnknownFunction(String, Vec<String>),
+    UnknownFunctionParam(String, String, Vec<String>, Vec<String>),
+    TooManyFunctionOutputs(String, usize, usize),
+    NeverMatches(String),
+    IfArity(usize, usize, usize),
+    UndeclaredAttribute(String, Vec<String>),
+    MissingImport(String),
+    ImportCycle(Vec<String>),
+    NegationCycle(Vec<String>),
+    UnsupportedSyntax(String, Vec<String>),
     ParseError(ParseError),
 }
 
@@ -51,13 +59,54 @@ impl fmt::Display for Error {
             &Error::InvalidNeedle => { write!(f, "The `from` in a sorted aggregate has to be the same size as the `for` in order to match the values.") }
             &Error::InvalidLookupType => { write!(f, "Lookup can only have \"add\" or \"remove\" for its type field.") }
             &Error::Unprovided(ref var) => { write!(f, "Nothing in the block is providing `{}`. You can search for\n something that provides `{}`, or bind a constant.\n e.g. `{}: \"Hello\"`", var, var, var) }
-            &Error::UnknownFunction(ref func) => { write!(f, "I don't know the `{}` function, so I'm not sure what to execute.", func) }
-            &Error::UnknownFunctionParam(ref func, ref param) => { write!(f, "The `{}` function doesn't have a `{}` attribute.", func, param) }
+            &Error::UnknownFunction(ref func, ref suggestions) => {
+                write!(f, "I don't know the `{}` function, so I'm not sure what to execute.", func)?;
+                if suggestions.len() > 0 {
+                    write!(f, "\n Did you mean {}?", format_choices(suggestions, "or"))?;
+                }
+                Ok(())
+            }
+            &Error::UnknownFunctionParam(ref func, ref param, ref valid, ref suggestions) => {
+                write!(f, "The `{}` function doesn't have a `{}` attribute.", func, param)?;
+                if suggestions.len() > 0 {
+                    write!(f, "\n Did you mean {}?", format_choices(suggestions, "or"))?;
+                } else if valid.len() > 0 {
+                    write!(f, "\n It takes {}.", format_choices(valid, "and"))?;
+                }
+                Ok(())
+            }
+            &Error::TooManyFunctionOutputs(ref func, given, expected) => { write!(f, "The `{}` function returns {} value(s), but {} were asked for here.", func, expected, given) }
+            &Error::NeverMatches(ref reason) => { write!(f, "This block can never match: {}.", reason) }
+            &Error::IfArity(branch, found, expected) => {
+                write!(f, "Branch {} of this `if` gives {} value{}, but it's assigned to {}.\n Every branch needs to give one value per output, e.g. `(a, b) = if x then (1, 2) else (3, 4)`",
+                       branch, found, if found == 1 { "" } else { "s" }, expected)
+            }
+            &Error::UndeclaredAttribute(ref attribute, ref suggestions) => {
+                write!(f, "Nothing searches for `{}` and it isn't declared, so strict mode won't let it be written.", attribute)?;
+                if suggestions.len() > 0 {
+                    write!(f, "\n Did you mean {}?", format_choices(suggestions, "or"))?;
+                } else {
+                    write!(f, "\n If something outside this file reads it, declare it with `eve:attributes {}`.", attribute)?;
+                }
+                Ok(())
+            }
+            &Error::MissingImport(ref path) => { write!(f, "Unable to import `{}`, there's no file there.", path) }
+            &Error::ImportCycle(ref cycle) => { write!(f, "These files import each other in a cycle: {}.", cycle.join(" -> ")) }
+            &Error::NegationCycle(ref cycle) => { write!(f, "This block's `not` depends on what it binds, so it would never settle: {}.\n Commit the facts instead, or break the cycle.", cycle.join(" -> ")) }
+            &Error::UnsupportedSyntax(ref version, ref supported) => { write!(f, "This file is written in syntax `{}`, which I don't know how to read.\n I can read {}.", version, format_choices(supported, "and")) }
             &Error::ParseError(ref err) => { write!(f, "{}", err) }
         }
     }
 }
 
+fn format_choices(choices:&Vec<String>, joiner:&str) -> String {
+    let quoted:Vec<String> = choices.iter().map(|choice| format!("`{}`", choice)).collect();
+    match quoted.split_last() {
+        Some((last, rest)) if rest.len() > 0 => format!("{} {} {}", rest.join(", "), joiner, last),
+        Some((last, _)) => last.to_string(),
+        None => String::new(),
+    }
+}
 
 fn format_error_source(span:&Span, lines:&Vec<&str>) {
     let start = &span.start;
@@ -0,0 +1,197 @@
+//-------------------------------------------------------------------------
+// State export
+//-------------------------------------------------------------------------
+
+// Dumps what a running program currently knows for tools that would rather not talk to
+// the engine. There are two shapes:
+//
+// - documents: a JSON array with one object per entity, `{"id": ..., "name": "ann"}`,
+//   where an attribute with several values gets an array of them and references to other
+//   entities are replaced by their own documents, down to a depth limit. Committed and
+//   bound facts alike end up in them.
+// - EAV text: one fact per line, the entity, attribute and value tab separated and each
+//   written as JSON. `import_eav` reads it back in, so only the facts the persister would
+//   keep are written; importing bound facts would commit them for good.
+//
+// Either can be narrowed down to the entities with a tag, the facts in a scope or both.
+// Decimals come out as the closest float, like everywhere else values meet JSON.
+
+extern crate serde_json;
+
+use ops::{Program, Internable, JSONInternable, RawChange, attribute_scope, scoped_attribute};
+use std::collections::{BTreeMap, HashSet};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportFilter {
+    /// Only entities with this tag, in `scope` if there is one.
+    pub tag: Option<String>,
+    /// Only facts whose attribute is in this scope, e.g. `session` for what
+    /// `commit @session` made.
+    pub scope: Option<String>,
+}
+
+impl ExportFilter {
+    fn attribute_matches(&self, attribute:&Internable) -> bool {
+        match self.scope {
+            Some(ref scope) => attribute_scope(attribute) == Some(scope.as_str()),
+            None => true,
+        }
+    }
+
+    fn entity_matches(&self, record:&BTreeMap<Internable, Vec<Internable>>) -> bool {
+        let tag = match self.tag {
+            Some(ref tag) => Internable::String(tag.to_string()),
+            None => return true,
+        };
+        let attribute = match self.scope {
+            Some(ref scope) => scoped_attribute(scope, "tag"),
+            None => Internable::String("tag".to_string()),
+        };
+        record.get(&attribute).map_or(false, |tags| tags.contains(&tag))
+    }
+}
+
+fn json_value(value:&Internable) -> serde_json::Value {
+    serde_json::to_value(JSONInternable::from(value)).unwrap()
+}
+
+// Documents are read by people and other tools, so a reference that isn't followed is
+// written as its plain id.
+fn document_value(value:&Internable) -> serde_json::Value {
+    match value {
+        &Internable::Reference(ref id) => json!(id),
+        _ => json_value(value),
+    }
+}
+
+// The entity's facts that pass the filter's scope, grouped by attribute.
+fn record(program:&Program, id:&Internable, filter:&ExportFilter) -> Option<BTreeMap<Internable, Vec<Internable>>> {
+    let interner = &program.state.interner;
+    let e = interner.id(id)?;
+    let mut record = BTreeMap::new();
+    for (a, v) in program.state.index.entity_facts(e) {
+        let attribute = interner.get_value(a);
+        if filter.attribute_matches(attribute) {
+            record.entry(attribute.clone()).or_insert_with(|| vec![]).push(interner.get_value(v).clone());
+        }
+    }
+    if record.is_empty() { return None; }
+    for values in record.values_mut() {
+        values.sort();
+    }
+    Some(record)
+}
+
+fn document(program:&Program, id:&Internable, record:&BTreeMap<Internable, Vec<Internable>>, filter:&ExportFilter, depth:usize, path:&mut Vec<Internable>) -> serde_json::Value {
+    let mut doc = serde_json::Map::new();
+    doc.insert("id".to_string(), document_value(id));
+    path.push(id.clone());
+    for (attribute, values) in record.iter() {
+        let mut resolved:Vec<serde_json::Value> = values.iter().map(|value| {
+            // references are followed until the depth runs out or they'd loop back around
+            if let &Internable::Reference(_) = value {
+                if depth > 0 && !path.contains(value) {
+                    if let Some(nested) = self::record(program, value, filter) {
+                        return document(program, value, &nested, filter, depth - 1, path);
+                    }
+                }
+            }
+            document_value(value)
+        }).collect();
+        let value = if resolved.len() == 1 { resolved.remove(0) } else { serde_json::Value::Array(resolved) };
+        doc.insert(Internable::to_string(attribute), value);
+    }
+    path.pop();
+    serde_json::Value::Object(doc)
+}
+
+// Every entity with at least one fact in the filter's scope, in a stable order.
+fn entities(program:&Program, filter:&ExportFilter) -> Vec<Internable> {
+    let mut seen = HashSet::new();
+    let mut entities = vec![];
+    for (e, a, _) in program.all_facts() {
+        if filter.attribute_matches(a) && seen.insert(e.clone()) {
+            entities.push(e.clone());
+        }
+    }
+    entities.sort();
+    entities
+}
+
+/// The program's entities as JSON documents, with references resolved `depth` levels
+/// deep. A depth of 0 leaves every reference as its id.
+pub fn export_json(program:&Program, filter:&ExportFilter, depth:usize) -> String {
+    let mut documents = vec![];
+    for id in entities(program, filter) {
+        if let Some(record) = record(program, &id, filter) {
+            if filter.entity_matches(&record) {
+                documents.push(document(program, &id, &record, filter, depth, &mut vec![]));
+            }
+        }
+    }
+    serde_json::to_string_pretty(&documents).unwrap()
+}
+
+/// The program's committed facts as EAV text, one tab separated fact per line.
+pub fn export_eav(program:&Program, filter:&ExportFilter) -> String {
+    let mut records:BTreeMap<Internable, BTreeMap<Internable, Vec<Internable>>> = BTreeMap::new();
+    for change in program.committed_facts() {
+        if filter.attribute_matches(&change.a) {
+            records.entry(change.e).or_insert_with(BTreeMap::new).entry(change.a).or_insert_with(|| vec![]).push(change.v);
+        }
+    }
+    let mut text = String::new();
+    for (id, mut record) in records {
+        for values in record.values_mut() {
+            values.sort();
+        }
+        if !filter.entity_matches(&record) { continue; }
+        for (attribute, values) in record.iter() {
+            for value in values {
+                text.push_str(&format!("{}\t{}\t{}\n", json_value(&id), json_value(attribute), json_value(value)));
+            }
+        }
+    }
+    text
+}
+
+/// Reads EAV text back into facts. Blank lines are skipped.
+pub fn parse_eav(text:&str) -> Result<Vec<RawChange>, String> {
+    let mut changes = vec![];
+    for (ix, line) in text.lines().enumerate() {
+        if line.trim().is_empty() { continue; }
+        let fields:Vec<&str> = line.split('\t').collect();
+        if fields.len() != 3 {
+            return Err(format!("Line {} has {} fields instead of 3", ix + 1, fields.len()));
+        }
+        let mut values = vec![];
+        for field in fields {
+            match serde_json::from_str::<JSONInternable>(field) {
+                Ok(value) => values.push(Internable::from(value)),
+                Err(why) => return Err(format!("Line {} has an unreadable value `{}`: {}", ix + 1, field, why)),
+            }
+        }
+        let v = values.pop().unwrap();
+        let a = values.pop().unwrap();
+        let e = values.pop().unwrap();
+        changes.push(RawChange::new(e, a, v, Internable::String("import".to_string()), 1));
+    }
+    Ok(changes)
+}
+
+/// Commits the facts in EAV text to `program` as one transaction. Returns how many
+/// facts there were.
+pub fn import_eav(program:&mut Program, text:&str) -> Result<usize, String> {
+    let changes = parse_eav(text)?;
+    let count = changes.len();
+    let mut transaction = program.transaction();
+    for change in changes {
+        let attribute = match change.a {
+            Internable::String(attribute) => attribute,
+            other => return Err(format!("`{}` isn't an attribute", other.print())),
+        };
+        transaction = transaction.insert(change.e, &attribute, change.v);
+    }
+    transaction.commit();
+    Ok(count)
+}
@@ -0,0 +1,588 @@
+use compiler::{Node};
+use combinators::{ParseState, ParseResult};
+use parser::{block};
+
+//-------------------------------------------------------------------------
+// Formatter
+//-------------------------------------------------------------------------
+
+// Blocks are parsed and printed back out from their AST: one statement per line,
+// section keywords at the start of the line, records wrapped once they run past the
+// width, and optionally the attributes in each record put in order. A block is only
+// replaced if the printed version parses back to the same AST, so anything the printer
+// can't reproduce (comments, parse errors, disabled blocks) is instead laid out line by
+// line, indented by how many brackets are open. Prose between blocks, and fences for
+// other languages, are only trimmed.
+
+const INDENT:usize = 2;
+const SECTIONS:[&'static str; 7] = ["search", "bind", "commit", "watch", "project", "disabled", "end"];
+
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    pub width: usize,
+    pub sort_attributes: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions { width: 80, sort_attributes: false }
+    }
+}
+
+/// Formats an .eve document. Formatting an already formatted document gives back the
+/// same text.
+pub fn format_source(source:&str) -> String {
+    format_source_with(source, &FormatOptions::default())
+}
+
+pub fn format_source_with(source:&str, options:&FormatOptions) -> String {
+    let fenced = source.lines().any(|line| fence_info(line).is_some());
+    let mut in_fence = false;
+    let mut eve_fence = false;
+    let mut block_lines:Vec<&str> = vec![];
+    let mut out = String::new();
+    for line in source.lines() {
+        if let Some(info) = fence_info(line) {
+            // a fence always closes whatever block was open in it
+            flush_block(&mut block_lines, options, &mut out);
+            if in_fence {
+                in_fence = false;
+            } else {
+                in_fence = true;
+                eve_fence = info == "" || info == "eve";
+            }
+            push_line(&mut out, line.trim_right());
+            continue;
+        }
+        if fenced && !(in_fence && eve_fence) {
+            push_line(&mut out, line.trim_right());
+            continue;
+        }
+        let trimmed = line.trim();
+        match section_keyword(trimmed) {
+            Some("end") if block_lines.len() > 0 => {
+                block_lines.push(line);
+                flush_block(&mut block_lines, options, &mut out);
+            }
+            Some(keyword) if keyword != "end" => block_lines.push(line),
+            _ if block_lines.len() > 0 => block_lines.push(line),
+            _ => push_line(&mut out, line.trim_right()),
+        }
+    }
+    flush_block(&mut block_lines, options, &mut out);
+    let trimmed_len = out.trim_right().len();
+    out.truncate(trimmed_len);
+    out.push('\n');
+    out
+}
+
+fn push_line(out:&mut String, line:&str) {
+    out.push_str(line);
+    out.push('\n');
+}
+
+fn flush_block(lines:&mut Vec<&str>, options:&FormatOptions, out:&mut String) {
+    if lines.len() == 0 { return; }
+    let source = lines.join("\n");
+    match format_block(&source, options) {
+        Some(formatted) => push_line(out, &formatted),
+        None => layout_lines(lines, out),
+    }
+    lines.clear();
+}
+
+//-------------------------------------------------------------------------
+// Line layout
+//-------------------------------------------------------------------------
+
+fn section_keyword(line:&str) -> Option<&'static str> {
+    for keyword in SECTIONS.iter() {
+        if line.starts_with(keyword) {
+            match line[keyword.len()..].chars().next() {
+                None | Some(' ') | Some('\t') | Some('@') | Some('(') => return Some(keyword),
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+fn fence_info(line:&str) -> Option<&str> {
+    let trimmed = line.trim();
+    if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+        Some(trimmed.trim_left_matches(|c| c == '`' || c == '~').trim())
+    } else {
+        None
+    }
+}
+
+// How many brackets a line leaves open, ignoring any inside strings or comments.
+fn bracket_delta(line:&str) -> i32 {
+    let mut delta = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut prev = ' ';
+    for c in line.chars() {
+        if in_string {
+            if escaped { escaped = false; }
+            else if c == '\\' { escaped = true; }
+            else if c == '"' { in_string = false; }
+        } else {
+            match c {
+                '"' => in_string = true,
+                '/' if prev == '/' => break,
+                '[' | '(' => delta += 1,
+                ']' | ')' => delta -= 1,
+                _ => {}
+            }
+        }
+        prev = c;
+    }
+    delta
+}
+
+fn has_comment(source:&str) -> bool {
+    source.lines().any(|line| {
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut prev = ' ';
+        for c in line.chars() {
+            if in_string {
+                if escaped { escaped = false; }
+                else if c == '\\' { escaped = true; }
+                else if c == '"' { in_string = false; }
+            } else if c == '"' {
+                in_string = true;
+            } else if (c == '/' || c == '*') && prev == '/' {
+                return true;
+            }
+            prev = c;
+        }
+        false
+    })
+}
+
+fn layout_lines(lines:&[&str], out:&mut String) {
+    let mut depth = 0;
+    for line in lines {
+        let trimmed = line.trim();
+        if section_keyword(trimmed).is_some() {
+            push_line(out, trimmed);
+            depth = 0;
+        } else if trimmed.len() == 0 {
+            out.push('\n');
+        } else {
+            let extra = if trimmed.starts_with("else") { 1 } else { 0 };
+            out.push_str(&spaces(INDENT * (1 + depth + extra) as usize));
+            push_line(out, trimmed);
+            depth = (depth + bracket_delta(trimmed)).max(0);
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+// Printing blocks
+//-------------------------------------------------------------------------
+
+fn format_block(source:&str, options:&FormatOptions) -> Option<String> {
+    if has_comment(source) { return None; }
+    let shape = block_shape(source)?;
+    let printed = print_block(source, &FormatOptions { sort_attributes: false, ..options.clone() })?;
+    // only trust the printer if it reproduced the block exactly
+    if block_shape(&printed)? != shape { return None; }
+    if !options.sort_attributes { return Some(printed); }
+    let sorted = print_block(source, options)?;
+    if print_block(&sorted, options)? != sorted { return None; }
+    Some(sorted)
+}
+
+fn parse_block<'a>(source:&'a str) -> Option<Node<'a>> {
+    let mut state = ParseState::new(source);
+    match block(&mut state) {
+        ParseResult::Ok(node) => match node.unwrap_pos() {
+            node @ Node::Block { .. } => Some(node),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// The block's AST without any source positions, for telling whether two versions of a
+// block mean the same thing.
+fn block_shape(source:&str) -> Option<String> {
+    match parse_block(source) {
+        Some(Node::Block { errors, search, update, .. }) => {
+            if errors.len() > 0 { return None; }
+            Some(strip_spans(&format!("{:?} {:?}", search, update)))
+        }
+        _ => None,
+    }
+}
+
+// Removes every `Pos(Span { .. }, node)` wrapper from a debug printed AST.
+fn strip_spans(debug:&str) -> String {
+    let mut out = String::new();
+    let mut parens:Vec<bool> = vec![];
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut ix = 0;
+    while ix < debug.len() {
+        let rest = &debug[ix..];
+        let c = rest.chars().next().unwrap();
+        if in_string {
+            if escaped { escaped = false; }
+            else if c == '\\' { escaped = true; }
+            else if c == '"' { in_string = false; }
+            out.push(c);
+        } else if rest.starts_with("Pos(Span {") {
+            let end = rest.find("} }, ").map(|end| end + 5).unwrap_or(rest.len());
+            parens.push(false);
+            ix += end;
+            continue;
+        } else {
+            match c {
+                '"' => { in_string = true; out.push(c); }
+                '(' => { parens.push(true); out.push(c); }
+                ')' => if parens.pop().unwrap_or(true) { out.push(c); },
+                _ => out.push(c),
+            }
+        }
+        ix += c.len_utf8();
+    }
+    out
+}
+
+fn print_block(source:&str, options:&FormatOptions) -> Option<String> {
+    match parse_block(source) {
+        Some(Node::Block { errors, search, update, .. }) => {
+            if errors.len() > 0 { return None; }
+            let printer = Printer { options };
+            let mut out = String::new();
+            if let Some(ref search) = *search {
+                printer.section(search, &mut out);
+            }
+            printer.section(&update, &mut out);
+            out.push_str("end");
+            Some(out)
+        }
+        _ => None,
+    }
+}
+
+fn spaces(count:usize) -> String {
+    " ".repeat(count)
+}
+
+fn last_line_len(text:&str) -> usize {
+    text.rsplit('\n').next().map_or(0, |line| line.len())
+}
+
+fn escape_string(text:&str) -> String {
+    text.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n").replace("\t", "\\t")
+}
+
+// Binding strength of an infix operator, used to decide where parens are needed.
+fn infix_level(node:&Node) -> Option<u8> {
+    match node.unwrap_ref_pos() {
+        &Node::Infix { op, .. } => match op {
+            "+" | "-" => Some(1),
+            "*" | "/" | "%" => Some(2),
+            _ => Some(3),
+        },
+        _ => None,
+    }
+}
+
+// Tags come first, then attributes by name. Everything else, and anything on the other
+// side of a `|`, keeps its place.
+fn sort_attributes(items:&[Node]) -> Vec<usize> {
+    let mut order:Vec<usize> = (0..items.len()).collect();
+    let mut start = 0;
+    for ix in 0..=items.len() {
+        let boundary = ix == items.len() || match items[ix].unwrap_ref_pos() {
+            &Node::Pipe => true,
+            _ => false,
+        };
+        if boundary {
+            order[start..ix].sort_by_key(|&item| match items[item].unwrap_ref_pos() {
+                &Node::Tag(tag) => (0, tag),
+                &Node::TagUnion(ref tags, _) => (0, tags[0]),
+                &Node::Attribute(name) |
+                &Node::AttributeEquality(name, _) |
+                &Node::AttributeInequality { attribute: name, .. } => (1, name),
+                _ => (2, ""),
+            });
+            start = ix + 1;
+        }
+    }
+    order
+}
+
+struct Printer<'o> {
+    options: &'o FormatOptions,
+}
+
+impl<'o> Printer<'o> {
+    fn section(&self, node:&Node, out:&mut String) {
+        let (scope, section) = match node.unwrap_ref_pos() {
+            &Node::Scoped(scope, ref section) => (Some(scope), section.unwrap_ref_pos()),
+            other => (None, other),
+        };
+        let (keyword, items) = match section {
+            &Node::Search(ref items) => ("search", items),
+            &Node::Bind(ref items) => ("bind", items),
+            &Node::Commit(ref items) => ("commit", items),
+            &Node::Project(ref items) => {
+                out.push_str(&format!("project ({})\n", self.exprs(items, " ")));
+                return;
+            }
+            &Node::Watch(watcher, ref items) => {
+                out.push_str(&format!("watch {}", watcher));
+                for item in items {
+                    out.push_str(&format!("\n{}{}", spaces(INDENT), self.expr(item)));
+                }
+                out.push('\n');
+                return;
+            }
+            _ => return,
+        };
+        out.push_str(keyword);
+        if let Some(scope) = scope {
+            out.push_str(&format!(" @{}", scope));
+        }
+        for item in items {
+            out.push('\n');
+            out.push_str(&spaces(INDENT));
+            out.push_str(&self.statement(item, INDENT));
+        }
+        out.push('\n');
+    }
+
+    fn exprs(&self, items:&[Node], separator:&str) -> String {
+        items.iter().map(|item| self.expr(item)).collect::<Vec<String>>().join(separator)
+    }
+
+    fn record_items(&self, items:&[Node]) -> Vec<String> {
+        if self.options.sort_attributes {
+            sort_attributes(items).into_iter().map(|ix| self.expr(&items[ix])).collect()
+        } else {
+            items.iter().map(|item| self.expr(item)).collect()
+        }
+    }
+
+    fn operand(&self, node:&Node, parens_up_to:u8) -> String {
+        match infix_level(node) {
+            Some(inner) if inner <= parens_up_to => format!("({})", self.expr(node)),
+            _ => self.expr(node),
+        }
+    }
+
+    /// The node on a single line.
+    fn expr(&self, node:&Node) -> String {
+        match node.unwrap_ref_pos() {
+            &Node::Pipe => "|".to_string(),
+            &Node::Integer(value) => value.to_string(),
+            &Node::Float(value) => format!("{:?}", value),
+            &Node::Decimal(ref value) => format!("{}d", value.to_string()),
+            &Node::RawString(text) => format!("\"{}\"", escape_string(text)),
+            &Node::EmbeddedString(_, ref parts) => {
+                let mut out = "\"".to_string();
+                for part in parts {
+                    match part.unwrap_ref_pos() {
+                        &Node::RawString(text) => out.push_str(&escape_string(text)),
+                        other => out.push_str(&format!("{{{{{}}}}}", self.expr(other))),
+                    }
+                }
+                out.push('"');
+                out
+            }
+            &Node::ExprSet(ref items) => format!("({})", self.exprs(items, ", ")),
+            &Node::NoneValue => "none".to_string(),
+            &Node::Placeholder(name) => format!("${}", name),
+            &Node::Tag(tag) => format!("#{}", tag),
+            &Node::TagUnion(ref tags, _) => tags.iter().map(|tag| format!("#{}", tag)).collect::<Vec<String>>().join("|"),
+            &Node::Variable(name) |
+            &Node::Identifier(name) |
+            &Node::Attribute(name) => name.to_string(),
+            &Node::GeneratedVariable(ref name) => name.to_string(),
+            &Node::AttributeEquality(name, ref value) => format!("{}: {}", name, self.expr(value)),
+            &Node::AttributeInequality { attribute, ref right, op } => format!("{} {} {}", attribute, op, self.expr(right)),
+            &Node::AttributeAccess(ref items) |
+            &Node::MutatingAttributeAccess(ref items) => items.join("."),
+            &Node::Inequality { ref left, ref right, op } => format!("{} {} {}", self.expr(left), op, self.expr(right)),
+            &Node::Equality { ref left, ref right } => format!("{} = {}", self.expr(left), self.expr(right)),
+            &Node::Infix { ref left, ref right, op, .. } => {
+                // operators nest to the right, so a left operand needs parens whenever it
+                // binds as loosely as the operator itself
+                let (left_parens, right_parens) = match infix_level(node).unwrap() {
+                    1 => (1, 0),
+                    2 => (2, 1),
+                    _ => (3, 2),
+                };
+                format!("{} {} {}", self.operand(left, left_parens), op, self.operand(right, right_parens))
+            }
+            &Node::Record(_, ref items) |
+            &Node::OutputRecord(_, ref items, _) => format!("[{}]", self.record_items(items).join(" ")),
+            &Node::RecordSet(ref records) => self.exprs(records, " "),
+            &Node::Lookup(ref items, _) => format!("lookup[{}]", self.exprs(items, " ")),
+            &Node::LookupCommit(ref items) => format!("lookup-commit[{}]", self.exprs(items, " ")),
+            &Node::LookupRemote(ref items, _) => format!("lookup-remote[{}]", self.exprs(items, " ")),
+            &Node::RecordFunction { op, ref params, ref outputs } => {
+                let call = format!("{}[{}]", op, self.exprs(params, " "));
+                if outputs.len() > 0 { format!("({}) = {}", self.exprs(outputs, ", "), call) } else { call }
+            }
+            &Node::RecordUpdate { ref record, ref value, op, .. } => format!("{} {} {}", self.expr(record), op, self.expr(value)),
+            &Node::BulkUpdate(ref updates) => {
+                let mut record = "";
+                let mut sets = vec![];
+                for update in updates {
+                    if let &Node::RecordUpdate { record: ref access, ref value, .. } = update.unwrap_ref_pos() {
+                        if let &Node::MutatingAttributeAccess(ref items) = access.unwrap_ref_pos() {
+                            record = items[0];
+                            sets.push(format!("{} := {}", items[1], self.expr(value)));
+                        }
+                    }
+                }
+                format!("update all {} set {}", record, sets.join(", "))
+            }
+            &Node::Not(_, ref items) => format!("not({})", self.exprs(items, " ")),
+            &Node::Objective(sense, _) => match node.objective_value() {
+                Some(value) => format!("{} {}", sense, self.expr(value)),
+                None => format!("{:?}", node),
+            },
+            &Node::If { .. } => self.if_expression(node, None),
+            &Node::IfBranch { .. } => self.if_branch(node, true),
+            other => format!("{:?}", other),
+        }
+    }
+
+    fn if_branch(&self, node:&Node, first:bool) -> String {
+        match node.unwrap_ref_pos() {
+            &Node::IfBranch { exclusive, ref body, ref result, .. } => {
+                let result = self.expr(result);
+                match (first, exclusive, body.len()) {
+                    (false, true, 0) => format!("else {}", result),
+                    (false, true, _) => format!("else if {} then {}", self.exprs(body, " "), result),
+                    _ => format!("if {} then {}", self.exprs(body, " "), result),
+                }
+            }
+            _ => self.expr(node),
+        }
+    }
+
+    // Every branch after the first goes on its own line, indented under the `if`.
+    fn if_expression(&self, node:&Node, indent:Option<usize>) -> String {
+        match node.unwrap_ref_pos() {
+            &Node::If { ref outputs, ref branches, .. } => {
+                let mut out = match *outputs {
+                    Some(ref outputs) if outputs.len() == 1 => format!("{} = ", self.expr(&outputs[0])),
+                    Some(ref outputs) => format!("({}) = ", self.exprs(outputs, ", ")),
+                    None => "".to_string(),
+                };
+                for (ix, branch) in branches.iter().enumerate() {
+                    if ix > 0 {
+                        match indent {
+                            Some(indent) => { out.push('\n'); out.push_str(&spaces(indent + INDENT)); }
+                            None => out.push(' '),
+                        }
+                    }
+                    out.push_str(&self.if_branch(branch, ix == 0));
+                }
+                out
+            }
+            _ => self.expr(node),
+        }
+    }
+
+    /// A statement starting at column `indent`, wrapped onto more lines if it's too wide.
+    fn statement(&self, node:&Node, indent:usize) -> String {
+        match node.unwrap_ref_pos() {
+            &Node::If { .. } => return self.if_expression(node, Some(indent)),
+            _ => {}
+        }
+        let line = self.expr(node);
+        if indent + line.len() <= self.options.width { return line; }
+        match node.unwrap_ref_pos() {
+            &Node::Record(..) | &Node::OutputRecord(..) => self.record(node, indent, indent),
+            &Node::Equality { ref left, ref right } if self.is_record(right) => {
+                let prefix = format!("{} = ", self.expr(left));
+                let record = self.record(right, indent, indent + prefix.len());
+                format!("{}{}", prefix, record)
+            }
+            &Node::Not(_, ref items) => {
+                let mut out = "not(".to_string();
+                for item in items {
+                    out.push('\n');
+                    out.push_str(&spaces(indent + INDENT));
+                    out.push_str(&self.statement(item, indent + INDENT));
+                }
+                out.push_str(&format!("\n{})", spaces(indent)));
+                out
+            }
+            _ => line,
+        }
+    }
+
+    fn is_record(&self, node:&Node) -> bool {
+        match node.unwrap_ref_pos() {
+            &Node::Record(..) | &Node::OutputRecord(..) => true,
+            _ => false,
+        }
+    }
+
+    // Fills lines with as many attributes as fit. An attribute whose value is a set of
+    // records too wide for the line puts each record on its own line, one indent in.
+    fn record(&self, node:&Node, indent:usize, column:usize) -> String {
+        let items = match node.unwrap_ref_pos() {
+            &Node::Record(_, ref items) |
+            &Node::OutputRecord(_, ref items, _) => items,
+            _ => return self.expr(node),
+        };
+        let one_line = self.expr(node);
+        if column + one_line.len() <= self.options.width { return one_line; }
+        let order:Vec<usize> = if self.options.sort_attributes { sort_attributes(items) } else { (0..items.len()).collect() };
+        let inner = indent + INDENT;
+        let mut out = "[".to_string();
+        let mut column = column + 1;
+        let mut first = true;
+        for ix in order {
+            let item = &items[ix];
+            let text = self.expr(item);
+            let separator = if first { 0 } else { 1 };
+            if column + separator + text.len() + 1 <= self.options.width {
+                if !first { out.push(' '); }
+                out.push_str(&text);
+                column += separator + text.len();
+            } else {
+                let records = match item.unwrap_ref_pos() {
+                    &Node::AttributeEquality(name, ref value) => match value.unwrap_ref_pos() {
+                        &Node::RecordSet(ref records) => Some((name, records.iter().collect::<Vec<&Node>>())),
+                        value @ &Node::Record(..) | value @ &Node::OutputRecord(..) => Some((name, vec![value])),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                match records {
+                    Some((name, ref records)) if column + separator + name.len() + 1 <= self.options.width => {
+                        if !first { out.push(' '); }
+                        out.push_str(&format!("{}:", name));
+                        for record in records.iter() {
+                            out.push_str(&format!("\n{}{}", spaces(inner), self.record(record, inner, inner)));
+                        }
+                        // whatever follows a set of records starts on a fresh line
+                        column = self.options.width;
+                    }
+                    _ => {
+                        out.push_str(&format!("\n{}", spaces(inner)));
+                        let wrapped = if self.is_record(item) { self.record(item, inner, inner) } else { text };
+                        out.push_str(&wrapped);
+                        column = last_line_len(&out);
+                    }
+                }
+            }
+            first = false;
+        }
+        out.push(']');
+        out
+    }
+}
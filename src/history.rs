@@ -0,0 +1,72 @@
+//-------------------------------------------------------------------------
+// History
+//-------------------------------------------------------------------------
+
+use ops::{RawChange, MetaMessage};
+use std::cmp;
+
+/// One transaction as history sees it: the changes that went in and the commits they
+/// led to, collapsed so each fact shows up once. Binds aren't kept since they follow
+/// from the commits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub inputs: Vec<RawChange>,
+    pub commits: Vec<RawChange>,
+}
+
+/// Every transaction a program has run while recording, see `Program::record_history`.
+/// Transactions are numbered from 1 and `position` is the last one currently applied,
+/// it only trails the end of the history after a rewind. Running a new transaction
+/// from there drops the ones that had been rewound, like typing after an undo.
+pub struct History {
+    entries: Vec<HistoryEntry>,
+    position: usize,
+}
+
+impl History {
+    pub fn new() -> History {
+        History { entries: vec![], position: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    pub fn entry(&self, transaction:usize) -> Option<&HistoryEntry> {
+        if transaction == 0 { return None; }
+        self.entries.get(transaction - 1)
+    }
+
+    // Moves the position once the program has been rewound or replayed to it.
+    pub fn seek(&mut self, position:usize) {
+        self.position = position;
+    }
+
+    pub fn record(&mut self, entry:HistoryEntry) {
+        self.entries.truncate(self.position);
+        self.entries.push(entry);
+        self.position = self.entries.len();
+    }
+
+    /// What was committed between the end of transaction `from` and the end of
+    /// transaction `to`. Going backwards gives the changes that undo it.
+    pub fn diff(&self, from:usize, to:usize) -> Vec<RawChange> {
+        let (start, end, direction) = if from <= to { (from, to, 1) } else { (to, from, -1) };
+        let end = cmp::min(end, self.entries.len());
+        let mut changes = vec![];
+        if start < end {
+            for entry in self.entries[start..end].iter() {
+                changes.extend(entry.commits.iter().map(|change| RawChange { count: change.count * direction, ..change.clone() }));
+            }
+        }
+        MetaMessage::collapse_changes(changes)
+    }
+}
@@ -3,16 +3,24 @@
 //-------------------------------------------------------------------------
 
 // use std::collections::HashMap;
-use ops::{EstimateIter, OutputingIter, Change, RoundHolder, Interned, Round, Count, IntermediateChange, Internable, Interner, AggregateFunction};
+use ops::{EstimateIter, OutputingIter, Change, RoundHolder, Interned, Round, Count, IntermediateChange, Internable, Interner, AggregateFunction, tokenize, FULLTEXT_INTERNED_ID};
 use std::cmp;
 
 extern crate fnv;
 use indexes::fnv::FnvHasher;
-use std::hash::{BuildHasherDefault};
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
 use std::collections::hash_map::{Entry};
-use std::iter::{self, Iterator, repeat};
-use std::collections::{BTreeMap, HashMap, BTreeSet, btree_map};
+use std::iter::{Iterator, repeat};
+use std::slice;
+use std::collections::{BTreeMap, HashMap, HashSet, BTreeSet, btree_map, Bound};
+use std::mem::{size_of, transmute};
+use std::u32;
+use std::borrow::Borrow;
+use std::sync::Arc;
+use serde::ser::{Serialize, Serializer, SerializeMap};
+use serde::de::{Deserialize, Deserializer};
 use compiler::{FunctionKind};
+use numerics::Decimal;
 
 extern crate term_painter;
 use self::term_painter::Color::*;
@@ -39,159 +47,389 @@ pub fn get_delta(last:i32, next:i32) -> i32 {
 }
 
 //-------------------------------------------------------------------------
-// HashIndexLeaf
+// Columns
 //-------------------------------------------------------------------------
 
-#[derive(Clone)]
-pub enum HashIndexLeaf {
-    Single(Interned),
-    Many(HashMap<Interned, (), MyHasher>),
+// One side of an attribute's facts as a pair of u32 columns, `keys` and `values`, sorted
+// by (key, value) so every key's values sit side by side. The rows are cut into blocks
+// of at most COLUMN_BLOCK, which keeps an insert or a removal to shifting the rest of one
+// block while a scan still walks contiguous ids. Finding a row is a binary search over
+// `firsts`, the first row of every block, and then one within the block.
+const COLUMN_BLOCK:usize = 512;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ColumnBlock {
+    keys: Vec<Interned>,
+    values: Vec<Interned>,
 }
 
-impl HashIndexLeaf {
-    pub fn insert(&mut self, neue_value:Interned) -> bool {
-        match self {
-            &mut HashIndexLeaf::Single(prev) => {
-                if prev != neue_value {
-                    let mut neue = HashMap::default();
-                    neue.insert(prev, ());
-                    neue.insert(neue_value, ());
-                    *self = HashIndexLeaf::Many(neue);
-                    true
-                } else {
-                    false
-                }
-            },
-            &mut HashIndexLeaf::Many(ref mut prev) => {
-                prev.insert(neue_value, ()).is_none()
-            },
+impl ColumnBlock {
+    fn row(&self, ix:usize) -> (Interned, Interned) {
+        (self.keys[ix], self.values[ix])
+    }
+}
+
+// The first ix in 0..len that `before` is false for, given it's true for every ix below
+// that and false for the rest.
+fn partition_point<F:Fn(usize) -> bool>(len:usize, before:F) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = (low + high) / 2;
+        if before(mid) { low = mid + 1; } else { high = mid; }
+    }
+    low
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Columns {
+    blocks: Vec<ColumnBlock>,
+    firsts: Vec<(Interned, Interned)>,
+    // how many distinct keys the rows hold
+    distinct: u32,
+}
+
+impl Columns {
+    // The (block, row) of the first row that `before` is false for. Past the last row
+    // that's (blocks.len(), 0). Only rows with `key` are compared in full, the rest are
+    // placed by the keys column alone.
+    fn seek<F:Fn(Interned) -> bool>(&self, key:Interned, before:F) -> (usize, usize) {
+        let firsts = &self.firsts;
+        let block = partition_point(firsts.len(), |ix| {
+            let (k, v) = firsts[ix];
+            k < key || (k == key && before(v))
+        });
+        if block == 0 { return (0, 0); }
+        let found = &self.blocks[block - 1];
+        let len = found.keys.len();
+        let start = partition_point(len, |ix| found.keys[ix] < key);
+        let row = start + partition_point(len - start, |offset| found.keys[start + offset] == key && before(found.values[start + offset]));
+        if row == len { (block, 0) } else { (block - 1, row) }
+    }
+
+    fn get(&self, (block, row):(usize, usize)) -> Option<(Interned, Interned)> {
+        self.blocks.get(block).map(|found| found.row(row))
+    }
+
+    fn previous(&self, (block, row):(usize, usize)) -> Option<(Interned, Interned)> {
+        if row > 0 {
+            Some(self.blocks[block].row(row - 1))
+        } else if block > 0 {
+            let found = &self.blocks[block - 1];
+            Some(found.row(found.keys.len() - 1))
+        } else {
+            None
         }
     }
 
-    pub fn remove(&mut self, neue_value:Interned) -> bool {
-        match self {
-            &mut HashIndexLeaf::Single(prev) => {
-                prev == neue_value
-            },
-            &mut HashIndexLeaf::Many(ref mut prev) => {
-                prev.remove(&neue_value);
-                prev.len() == 0
-            },
+    fn has_key_around(&self, at:(usize, usize), key:Interned) -> bool {
+        self.previous(at).map_or(false, |(k, _)| k == key) || self.get(at).map_or(false, |(k, _)| k == key)
+    }
+
+    fn contains(&self, key:Interned, value:Interned) -> bool {
+        self.get(self.seek(key, |v| v < value)) == Some((key, value))
+    }
+
+    fn contains_key(&self, key:Interned) -> bool {
+        self.get(self.seek(key, |_| false)).map_or(false, |(k, _)| k == key)
+    }
+
+    fn insert(&mut self, key:Interned, value:Interned) -> bool {
+        if self.blocks.is_empty() {
+            self.blocks.push(ColumnBlock { keys: vec![key], values: vec![value] });
+            self.firsts.push((key, value));
+            self.distinct += 1;
+            return true;
+        }
+        let at = self.seek(key, |v| v < value);
+        if self.get(at) == Some((key, value)) { return false; }
+        if !self.has_key_around(at, key) { self.distinct += 1; }
+        // past the end of a block goes on the end of it rather than the start of the next
+        let (block, row) = match at {
+            (block, 0) if block > 0 => (block - 1, self.blocks[block - 1].keys.len()),
+            at => at,
+        };
+        if row == 0 { self.firsts[block] = (key, value); }
+        let last = block + 1 == self.blocks.len();
+        let split = {
+            let found = &mut self.blocks[block];
+            found.keys.insert(row, key);
+            found.values.insert(row, value);
+            let len = found.keys.len();
+            if len <= COLUMN_BLOCK {
+                None
+            } else {
+                // appending to the last block is the common case for new ids, so that
+                // leaves the full block full
+                let ix = if last && row == len - 1 { row } else { len / 2 };
+                Some(ColumnBlock { keys: found.keys.split_off(ix), values: found.values.split_off(ix) })
+            }
+        };
+        if let Some(split) = split {
+            self.firsts.insert(block + 1, split.row(0));
+            self.blocks.insert(block + 1, split);
         }
+        true
     }
 
-    pub fn check(&self, v:Interned) -> bool {
-        match self {
-            &HashIndexLeaf::Single(cur) => cur == v,
-            &HashIndexLeaf::Many(ref cur) => cur.contains_key(&v),
+    fn remove(&mut self, key:Interned, value:Interned) -> bool {
+        let (block, row) = self.seek(key, |v| v < value);
+        if self.get((block, row)) != Some((key, value)) { return false; }
+        let len = {
+            let found = &mut self.blocks[block];
+            found.keys.remove(row);
+            found.values.remove(row);
+            found.keys.len()
+        };
+        let at = if len == 0 {
+            self.blocks.remove(block);
+            self.firsts.remove(block);
+            (block, 0)
+        } else if row == len {
+            (block + 1, 0)
+        } else {
+            if row == 0 { self.firsts[block] = self.blocks[block].row(0); }
+            (block, row)
+        };
+        if !self.has_key_around(at, key) { self.distinct -= 1; }
+        // fold a mostly emptied block into the one after it
+        if len > 0 && len < COLUMN_BLOCK / 4 && block + 1 < self.blocks.len() && len + self.blocks[block + 1].keys.len() <= COLUMN_BLOCK {
+            let next = self.blocks.remove(block + 1);
+            self.firsts.remove(block + 1);
+            let found = &mut self.blocks[block];
+            found.keys.extend(next.keys);
+            found.values.extend(next.values);
         }
+        true
+    }
+
+    fn values<'a>(&'a self, key:Interned) -> Option<ColumnValues<'a>> {
+        let (block, row) = self.seek(key, |_| false);
+        // a key's rows run on into the next block only when they fill the rest of this one
+        let mut left = 0;
+        let mut ix = block;
+        let mut from = row;
+        while ix < self.blocks.len() {
+            let found = &self.blocks[ix];
+            let len = found.keys.len();
+            if found.keys[len - 1] == key {
+                left += len - from;
+                ix += 1;
+                from = 0;
+            } else {
+                left += partition_point(len - from, |offset| found.keys[from + offset] == key);
+                break;
+            }
+        }
+        if left == 0 { return None; }
+        Some(ColumnValues { current: self.blocks[block].values[row..].iter(), rest: self.blocks[block + 1..].iter(), left })
     }
 
-    pub fn iter<'a>(&'a self) -> Box<ExactSizeIterator<Item=Interned> + 'a> {
-        match self {
-            &HashIndexLeaf::Single(value) => Box::new(iter::once(value)),
-            &HashIndexLeaf::Many(ref index) => Box::new(index.keys().cloned()),
+    fn keys<'a>(&'a self) -> ColumnKeys<'a> {
+        ColumnKeys { current: [].iter(), rest: self.blocks.iter(), last: None, left: self.distinct as usize }
+    }
+
+    fn rows<'a>(&'a self) -> Box<Iterator<Item=(Interned, Interned)> + 'a> {
+        Box::new(self.blocks.iter().flat_map(|block| block.keys.iter().cloned().zip(block.values.iter().cloned())))
+    }
+}
+
+// The values of one key, in order.
+struct ColumnValues<'a> {
+    current: slice::Iter<'a, Interned>,
+    rest: slice::Iter<'a, ColumnBlock>,
+    left: usize,
+}
+
+impl<'a> Iterator for ColumnValues<'a> {
+    type Item = Interned;
+
+    fn next(&mut self) -> Option<Interned> {
+        if self.left == 0 { return None; }
+        self.left -= 1;
+        loop {
+            if let Some(&value) = self.current.next() { return Some(value); }
+            self.current = self.rest.next().unwrap().values.iter();
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+impl<'a> ExactSizeIterator for ColumnValues<'a> {}
+
+// Every distinct key, in order.
+struct ColumnKeys<'a> {
+    current: slice::Iter<'a, Interned>,
+    rest: slice::Iter<'a, ColumnBlock>,
+    last: Option<Interned>,
+    left: usize,
+}
+
+impl<'a> Iterator for ColumnKeys<'a> {
+    type Item = Interned;
+
+    fn next(&mut self) -> Option<Interned> {
+        if self.left == 0 { return None; }
+        loop {
+            match self.current.next() {
+                Some(&key) => {
+                    if self.last != Some(key) {
+                        self.last = Some(key);
+                        self.left -= 1;
+                        return Some(key);
+                    }
+                }
+                None => self.current = self.rest.next().unwrap().keys.iter(),
+            }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+impl<'a> ExactSizeIterator for ColumnKeys<'a> {}
+
+//-------------------------------------------------------------------------
+// AttributeStats
+//-------------------------------------------------------------------------
+
+/// How many facts an attribute has and how many distinct entities and values they're
+/// spread over, which is enough to guess how many rows a scan on it will propose. Levels
+/// don't count removals, so `facts` is a high-water mark rather than an exact count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AttributeStats {
+    pub facts: u32,
+    pub entities: u32,
+    pub values: u32,
+}
+
+//-------------------------------------------------------------------------
+// IndexStats
+//-------------------------------------------------------------------------
+
+/// How much an index holds and what it costs, for capacity planning. `entries` is what
+/// the index holds at its top level, `slots` and `filled` add up every hash table in
+/// it, nested ones included, and `bytes` estimates the memory behind the tables and
+/// vecs, not counting allocator overhead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct IndexStats {
+    pub entries: usize,
+    pub tables: usize,
+    pub slots: usize,
+    pub filled: usize,
+    pub bytes: usize,
+}
+
+impl IndexStats {
+    /// How full the index's tables are, from 0 to 1. A low load factor after a burst
+    /// of retractions is memory that compacting would give back.
+    pub fn load_factor(&self) -> f32 {
+        if self.slots == 0 { 0.0 } else { self.filled as f32 / self.slots as f32 }
+    }
+
+    fn table<K:Eq + Hash, V, S:BuildHasher>(&mut self, map:&HashMap<K, V, S>) {
+        self.tables += 1;
+        self.slots += map.capacity();
+        self.filled += map.len();
+        self.bytes += map.capacity() * (size_of::<K>() + size_of::<V>());
+    }
+
+    fn shared_table<K:Eq + Hash + Clone, V:Clone, S:BuildHasher + Clone + Default>(&mut self, map:&SharedMap<K, V, S>) {
+        for shard in map.shards() {
+            self.table(shard);
+        }
+    }
+
+    fn buffer<T>(&mut self, vec:&Vec<T>) {
+        self.bytes += vec.capacity() * size_of::<T>();
+    }
+
+    fn columns(&mut self, columns:&Columns) {
+        self.buffer(&columns.blocks);
+        for block in columns.blocks.iter() {
+            self.buffer(&block.keys);
+            self.buffer(&block.values);
+        }
+    }
+
+    fn round_entry(&mut self, entry:&RoundEntry) {
+        self.buffer(&entry.rounds);
+        self.buffer(&entry.active_rounds);
+    }
 }
 
 //-------------------------------------------------------------------------
 // HashIndexLevel
 //-------------------------------------------------------------------------
 
-#[derive(Clone)]
+// One attribute's facts, kept both ways round: `e` holds (e, v) rows so an entity's values
+// are side by side, and `v` is the reverse (A,V) -> E index with (v, e) rows. Proposals
+// use whichever side is bound, so `[#person name: "ann"]` only ever touches the people
+// named ann rather than walking every entity with a name.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct HashIndexLevel {
-    e: HashMap<Interned, HashIndexLeaf, MyHasher>,
-    v: HashMap<Interned, HashIndexLeaf, MyHasher>,
+    e: Columns,
+    v: Columns,
     size: u32,
 }
 
 impl HashIndexLevel {
     pub fn new() -> HashIndexLevel {
-        HashIndexLevel { e: HashMap::default(), v: HashMap::default(), size: 0 }
+        HashIndexLevel { e: Columns::default(), v: Columns::default(), size: 0 }
     }
 
     pub fn insert(&mut self, e: Interned, v:Interned) -> bool {
-        let added = match self.e.entry(e) {
-            Entry::Occupied(mut o) => {
-                o.get_mut().insert(v)
-            }
-            Entry::Vacant(o) => {
-                o.insert(HashIndexLeaf::Single(v));
-                true
-            },
-        };
+        let added = self.e.insert(e, v);
         if added {
             self.size += 1;
-            match self.v.entry(v) {
-                Entry::Occupied(mut o) => {
-                    o.get_mut().insert(e);
-                }
-                Entry::Vacant(o) => {
-                    o.insert(HashIndexLeaf::Single(e));
-                },
-            };
+            self.v.insert(v, e);
         }
         added
     }
 
     pub fn remove(&mut self, e:Interned, v:Interned) -> bool {
-        let added = match self.e.entry(e) {
-            Entry::Occupied(mut o) => {
-                let is_empty = o.get_mut().remove(v);
-                if is_empty {
-                    o.remove_entry();
-                }
-                true
-            }
-            Entry::Vacant(_) => {
-                false
-            },
-        };
-        if added {
+        let removed = self.e.remove(e, v);
+        if removed {
             // self.size -= 1;
-            match self.v.entry(v) {
-                Entry::Occupied(mut o) => {
-                    let is_empty = o.get_mut().remove(e);
-                    if is_empty {
-                        o.remove_entry();
-                    }
-                }
-                Entry::Vacant(_) => { },
-            };
+            self.v.remove(v, e);
         }
-        added
+        removed
     }
 
     pub fn check(&self, e: Interned, v:Interned) -> bool {
         if e > 0 && v > 0 {
-            match self.e.get(&e) {
-                Some(leaf) => leaf.check(v),
-                None => false,
-            }
+            self.e.contains(e, v)
         } else if e > 0 {
-            self.e.contains_key(&e)
+            self.e.contains_key(e)
         } else if v > 0 {
-            self.v.contains_key(&v)
+            self.v.contains_key(v)
         } else {
             self.size > 0
         }
     }
 
     pub fn find_values<'a>(&'a self, e:Interned) -> Option<Box<ExactSizeIterator<Item=Interned> + 'a>> {
-        match self.e.get(&e) {
-            Some(leaf) => Some(leaf.iter()),
+        match self.e.values(e) {
+            Some(values) => Some(Box::new(values)),
             None => None,
         }
     }
 
     pub fn find_entities<'a>(&'a self, v:Interned) -> Option<Box<ExactSizeIterator<Item=Interned> + 'a>> {
-        match self.v.get(&v) {
-            Some(leaf) => Some(leaf.iter()),
+        match self.v.values(v) {
+            Some(entities) => Some(Box::new(entities)),
             None => None,
         }
     }
 
+    pub fn pairs(&self) -> Vec<(Interned, Interned)> {
+        self.e.rows().collect()
+    }
+
     pub fn get<'a>(&'a self, e:Interned, v:Interned) -> Option<Box<ExactSizeIterator<Item=Interned> + 'a>> {
         if e > 0 {
             // println!("here looking for v {:?}", e);
@@ -199,17 +437,17 @@ impl HashIndexLevel {
         } else if v > 0 {
             self.find_entities(v)
         } else {
-            let es_len = self.e.len();
-            let vs_len = self.v.len();
+            let es_len = self.e.distinct;
+            let vs_len = self.v.distinct;
             if es_len < vs_len {
                 if es_len > 0 {
-                    Some(Box::new(self.e.keys().cloned()))
+                    Some(Box::new(self.e.keys()))
                 } else {
                     None
                 }
             } else {
                 if vs_len > 0 {
-                    Some(Box::new(self.v.keys().cloned()))
+                    Some(Box::new(self.v.keys()))
                 } else {
                     None
                 }
@@ -249,10 +487,10 @@ impl HashIndexLevel {
                 true
             }
         } else {
-            let es_len = self.e.len();
-            let vs_len = self.v.len();
+            let es_len = self.e.distinct as usize;
+            let vs_len = self.v.distinct as usize;
             if es_len < vs_len {
-                let hash_iter = Box::new(self.e.keys().cloned());
+                let hash_iter = Box::new(self.e.keys());
                 if iter.is_better(es_len) {
                     iter.estimate = es_len;
                     iter.iter = OutputingIter::Single(0, OutputingIter::make_ptr(hash_iter));
@@ -261,7 +499,7 @@ impl HashIndexLevel {
                     false
                 }
             } else {
-                let hash_iter = Box::new(self.v.keys().cloned());
+                let hash_iter = Box::new(self.v.keys());
                 if iter.is_better(vs_len) {
                     iter.estimate = vs_len;
                     iter.iter = OutputingIter::Single(2, OutputingIter::make_ptr(hash_iter));
@@ -340,11 +578,190 @@ pub fn generic_distinct<F>(counts:&mut Vec<Count>, mut input_count:Count, input_
     // println!("Post counts {:?}", counts);
 }
 
+//-------------------------------------------------------------------------
+// Undo
+//-------------------------------------------------------------------------
+
+// What each key held before it was first changed after the last save point, so a
+// transaction stopped part way through a frame can put the indexes back the way the
+// frame found them.
+struct Undo<K:Hash + Eq, V> {
+    saved: HashMap<K, Option<V>, MyHasher>,
+}
+
+impl<K:Hash + Eq + Clone, V:Clone> Undo<K, V> {
+    fn new() -> Undo<K, V> {
+        Undo { saved: HashMap::default() }
+    }
+
+    // Called before every change to `key`, with what it holds right now.
+    fn save(&mut self, key:&K, current:Option<&V>) {
+        if !self.saved.contains_key(key) {
+            self.saved.insert(key.clone(), current.cloned());
+        }
+    }
+
+    fn clear(&mut self) {
+        self.saved.clear();
+    }
+
+    fn drain(&mut self) -> Vec<(K, Option<V>)> {
+        self.saved.drain().collect()
+    }
+}
+
+impl<K:Hash + Eq + Clone, V:Clone> Default for Undo<K, V> {
+    fn default() -> Undo<K, V> {
+        Undo::new()
+    }
+}
+
+//-------------------------------------------------------------------------
+// SharedMap
+//-------------------------------------------------------------------------
+
+// A hash map split into shards that copies share until one of them writes to a shard,
+// at which point the writer gets its own copy of just that shard. Taking a copy costs
+// a few hundred Arcs, so the program can hand a consistent view of its indexes to
+// another thread to be written out while transactions keep changing the originals.
+const SHARED_MAP_SHARDS:usize = 256;
+
+pub struct SharedMap<K, V, S=MyHasher> {
+    // empty until the first insert, so maps that never hold anything cost nothing
+    shards: Vec<Arc<HashMap<K, V, S>>>,
+    hasher: S,
+}
+
+impl<K:Hash + Eq + Clone, V:Clone, S:BuildHasher + Clone + Default> SharedMap<K, V, S> {
+    pub fn new() -> SharedMap<K, V, S> {
+        SharedMap { shards: vec![], hasher: S::default() }
+    }
+
+    // The key's own hash picks the shard too, mixed so the bits the shard's table goes
+    // by aren't the ones that picked it.
+    fn shard<Q:?Sized + Hash>(&self, key:&Q) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        key.hash(&mut hasher);
+        (hasher.finish().wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 56) as usize
+    }
+
+    /// The shard `key` goes in, copied first if anything else still shares it.
+    pub fn shard_mut(&mut self, key:&K) -> &mut HashMap<K, V, S> {
+        if self.shards.len() == 0 {
+            let hasher = self.hasher.clone();
+            self.shards = (0..SHARED_MAP_SHARDS).map(|_| Arc::new(HashMap::with_hasher(hasher.clone()))).collect();
+        }
+        let ix = self.shard(key);
+        Arc::make_mut(&mut self.shards[ix])
+    }
+
+    pub fn get<Q:?Sized + Hash + Eq>(&self, key:&Q) -> Option<&V> where K:Borrow<Q> {
+        if self.shards.len() == 0 { return None; }
+        self.shards[self.shard(key)].get(key)
+    }
+
+    pub fn get_mut(&mut self, key:&K) -> Option<&mut V> {
+        if self.shards.len() == 0 { return None; }
+        self.shard_mut(key).get_mut(key)
+    }
+
+    pub fn contains_key<Q:?Sized + Hash + Eq>(&self, key:&Q) -> bool where K:Borrow<Q> {
+        self.get(key).is_some()
+    }
+
+    pub fn entry(&mut self, key:K) -> Entry<K, V> {
+        self.shard_mut(&key).entry(key)
+    }
+
+    pub fn insert(&mut self, key:K, value:V) -> Option<V> {
+        self.shard_mut(&key).insert(key, value)
+    }
+
+    pub fn remove(&mut self, key:&K) -> Option<V> {
+        if !self.contains_key(key) { return None; }
+        self.shard_mut(key).remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn shards(&self) -> &[Arc<HashMap<K, V, S>>] {
+        &self.shards
+    }
+
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item=(&'a K, &'a V)> + 'a> {
+        Box::new(self.shards.iter().flat_map(|shard| shard.iter()))
+    }
+
+    pub fn keys<'a>(&'a self) -> Box<Iterator<Item=&'a K> + 'a> {
+        Box::new(self.shards.iter().flat_map(|shard| shard.keys()))
+    }
+
+    pub fn values<'a>(&'a self) -> Box<Iterator<Item=&'a V> + 'a> {
+        Box::new(self.shards.iter().flat_map(|shard| shard.values()))
+    }
+
+    pub fn retain<F:FnMut(&K, &mut V) -> bool>(&mut self, mut keep:F) {
+        for shard in self.shards.iter_mut() {
+            if shard.len() > 0 {
+                Arc::make_mut(shard).retain(|key, value| keep(key, value));
+            }
+        }
+    }
+
+    pub fn shrink_to_fit(&mut self) {
+        for shard in self.shards.iter_mut() {
+            if shard.capacity() > shard.len() {
+                Arc::make_mut(shard).shrink_to_fit();
+            }
+        }
+    }
+
+    /// Another map holding the same entries. Nothing is copied until one of them
+    /// changes a shard.
+    pub fn share(&self) -> SharedMap<K, V, S> {
+        SharedMap { shards: self.shards.clone(), hasher: self.hasher.clone() }
+    }
+}
+
+impl<K:Hash + Eq + Clone, V:Clone, S:BuildHasher + Clone + Default> Default for SharedMap<K, V, S> {
+    fn default() -> SharedMap<K, V, S> {
+        SharedMap::new()
+    }
+}
+
+// written as one plain map, so the sharding doesn't show up on disk
+impl<K:Hash + Eq + Clone + Serialize, V:Clone + Serialize, S:BuildHasher + Clone + Default> Serialize for SharedMap<K, V, S> {
+    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
+        where Ser: Serializer
+    {
+        let mut map = serializer.serialize_map(Some(self.len()))?;
+        for (key, value) in self.iter() {
+            map.serialize_entry(key, value)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de, K:Hash + Eq + Clone + Deserialize<'de>, V:Clone + Deserialize<'de>, S:BuildHasher + Clone + Default> Deserialize<'de> for SharedMap<K, V, S> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        let entries:HashMap<K, V, S> = Deserialize::deserialize(deserializer)?;
+        let mut map = SharedMap::new();
+        for (key, value) in entries {
+            map.insert(key, value);
+        }
+        Ok(map)
+    }
+}
+
 //-------------------------------------------------------------------------
 // HashIndex
 //-------------------------------------------------------------------------
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RoundEntry {
     inserted: bool,
     pub rounds: Vec<i32>,
@@ -387,22 +804,452 @@ fn update_active_rounds_vec(active_rounds: &mut Vec<i32>, round:Round, count:Cou
                 active_rounds.insert(pos, (round as i32) * -1);
             }
         }
-}
+}
+
+impl RoundEntry {
+    pub fn update_active(&mut self, round:Round, count:Count) {
+        update_active_rounds_vec(&mut self.active_rounds, round, count);
+    }
+
+    /// Nothing derives this anymore in any round.
+    pub fn is_retracted(&self) -> bool {
+        self.active_rounds.len() == 0 && self.rounds.iter().all(|count| *count == 0)
+    }
+}
+
+//-------------------------------------------------------------------------
+// OrderedLevel
+//-------------------------------------------------------------------------
+
+// Maps a float onto a u32 that sorts the same way, so numbers can live in a BTreeSet.
+// -0 and 0 compare equal as floats, so they share a key.
+pub fn ordered_key(num:f32) -> u32 {
+    let num = if num == 0.0 { 0.0 } else { num };
+    let bits = unsafe { transmute::<f32, u32>(num) };
+    if bits & 0x8000_0000 != 0 { !bits } else { bits | 0x8000_0000 }
+}
+
+// The numeric values of an attribute in sorted order, so `age > 30` can look at just
+// the values past 30 rather than every age. Decimals are only counted: the f32 they'd
+// sort by isn't exact, so an attribute holding any of them falls back to a full scan.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OrderedLevel {
+    numbers: BTreeSet<(u32, Interned)>,
+    decimals: usize,
+}
+
+impl OrderedLevel {
+    pub fn new() -> OrderedLevel {
+        OrderedLevel { numbers: BTreeSet::new(), decimals: 0 }
+    }
+
+    pub fn insert(&mut self, v:Interned, value:&Internable) {
+        match value {
+            &Internable::Number(_) => {
+                let num = Internable::to_number(value);
+                if !num.is_nan() { self.numbers.insert((ordered_key(num), v)); }
+            }
+            &Internable::Decimal(_) => { self.decimals += 1; }
+            _ => {}
+        }
+    }
+
+    pub fn remove(&mut self, v:Interned, value:&Internable) {
+        match value {
+            &Internable::Number(_) => {
+                let num = Internable::to_number(value);
+                if !num.is_nan() { self.numbers.remove(&(ordered_key(num), v)); }
+            }
+            &Internable::Decimal(_) => { self.decimals = self.decimals.saturating_sub(1); }
+            _ => {}
+        }
+    }
+
+    /// The values between `low` and `high`, or None if the level can't answer exactly.
+    pub fn range(&self, low:Bound<f32>, high:Bound<f32>) -> Option<Vec<Interned>> {
+        if self.decimals > 0 { return None; }
+        let start = match low {
+            Bound::Included(num) => Bound::Included((ordered_key(num), 0)),
+            Bound::Excluded(num) => Bound::Excluded((ordered_key(num), u32::MAX)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match high {
+            Bound::Included(num) => Bound::Included((ordered_key(num), u32::MAX)),
+            Bound::Excluded(num) => Bound::Excluded((ordered_key(num), 0)),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        // BTreeSet::range panics on a backwards range, so an empty one has to be caught here
+        match (start, end) {
+            (Bound::Included(from), Bound::Included(to)) |
+            (Bound::Included(from), Bound::Excluded(to)) |
+            (Bound::Excluded(from), Bound::Included(to)) |
+            (Bound::Excluded(from), Bound::Excluded(to)) => {
+                if from >= to { return Some(vec![]); }
+            }
+            _ => {}
+        }
+        Some(self.numbers.range((start, end)).map(|&(_, v)| v).collect())
+    }
+}
+
+//-------------------------------------------------------------------------
+// Graph level
+//-------------------------------------------------------------------------
+
+// The edges stored under a single attribute, kept current as facts are added and
+// removed so the graph functions never walk the whole attribute. Components are
+// merged when an edge joins them, and only the component an edge leaves gets
+// walked again. Paths are remembered until the next change to the edges.
+#[derive(Clone)]
+pub struct GraphLevel {
+    edges: HashMap<Interned, HashSet<Interned, MyHasher>, MyHasher>,
+    // edges in both directions, counted since `a -> b` and `b -> a` are one link
+    neighbors: HashMap<Interned, HashMap<Interned, u32, MyHasher>, MyHasher>,
+    component: HashMap<Interned, Interned, MyHasher>,
+    members: HashMap<Interned, Vec<Interned>, MyHasher>,
+    paths: HashMap<(Interned, Interned), Option<Vec<Interned>>, MyHasher>,
+}
+
+impl GraphLevel {
+    pub fn new() -> GraphLevel {
+        GraphLevel { edges: HashMap::default(), neighbors: HashMap::default(), component: HashMap::default(), members: HashMap::default(), paths: HashMap::default() }
+    }
+
+    fn link(&mut self, from:Interned, to:Interned) {
+        *self.neighbors.entry(from).or_insert_with(HashMap::default).entry(to).or_insert(0) += 1;
+    }
+
+    fn unlink(&mut self, from:Interned, to:Interned) {
+        let empty = match self.neighbors.get_mut(&from) {
+            Some(links) => {
+                let gone = match links.get_mut(&to) {
+                    Some(count) => { *count -= 1; *count == 0 }
+                    None => false,
+                };
+                if gone { links.remove(&to); }
+                links.len() == 0
+            }
+            None => false,
+        };
+        if empty { self.neighbors.remove(&from); }
+    }
+
+    fn root(&mut self, node:Interned) -> Interned {
+        if let Some(&root) = self.component.get(&node) { return root; }
+        self.component.insert(node, node);
+        self.members.insert(node, vec![node]);
+        node
+    }
+
+    pub fn insert(&mut self, e:Interned, v:Interned) {
+        if !self.edges.entry(e).or_insert_with(HashSet::default).insert(v) { return; }
+        self.paths.clear();
+        self.link(e, v);
+        if e != v { self.link(v, e); }
+        let left = self.root(e);
+        let right = self.root(v);
+        if left == right { return; }
+        // the smallest id in a component names it, so results are stable
+        let (keep, gone) = if left < right { (left, right) } else { (right, left) };
+        let moved = self.members.remove(&gone).unwrap_or_else(|| vec![]);
+        for node in moved.iter() {
+            self.component.insert(*node, keep);
+        }
+        self.members.entry(keep).or_insert_with(|| vec![]).extend(moved);
+    }
+
+    pub fn remove(&mut self, e:Interned, v:Interned) {
+        let removed = match self.edges.get_mut(&e) {
+            Some(out) => out.remove(&v),
+            None => false,
+        };
+        if !removed { return; }
+        if self.edges.get(&e).map_or(false, |out| out.len() == 0) { self.edges.remove(&e); }
+        self.paths.clear();
+        self.unlink(e, v);
+        if e != v { self.unlink(v, e); }
+        // the component may have split, so walk what's left of it again
+        let root = match self.component.get(&e) {
+            Some(&root) => root,
+            None => return,
+        };
+        let old = self.members.remove(&root).unwrap_or_else(|| vec![]);
+        for node in old.iter() {
+            self.component.remove(node);
+        }
+        for &node in old.iter() {
+            if self.component.contains_key(&node) || !self.neighbors.contains_key(&node) { continue; }
+            let mut found = vec![node];
+            let mut seen:HashSet<Interned, MyHasher> = HashSet::default();
+            seen.insert(node);
+            let mut ix = 0;
+            while ix < found.len() {
+                if let Some(links) = self.neighbors.get(&found[ix]) {
+                    for next in links.keys() {
+                        if seen.insert(*next) { found.push(*next); }
+                    }
+                }
+                ix += 1;
+            }
+            let min = *found.iter().min().unwrap();
+            for member in found.iter() {
+                self.component.insert(*member, min);
+            }
+            self.members.insert(min, found);
+        }
+    }
+
+    /// Every node with an edge, along with the smallest node it's connected to.
+    pub fn components(&self) -> Vec<(Interned, Interned)> {
+        self.component.iter().map(|(&node, &root)| (node, root)).collect()
+    }
+
+    /// The nodes along one of the shortest paths from `from` to `to`, both included.
+    pub fn shortest_path(&mut self, from:Interned, to:Interned) -> Option<Vec<Interned>> {
+        if let Some(path) = self.paths.get(&(from, to)) { return path.clone(); }
+        let path = self.find_path(from, to);
+        self.paths.insert((from, to), path.clone());
+        path
+    }
+
+    fn find_path(&self, from:Interned, to:Interned) -> Option<Vec<Interned>> {
+        // breadth first, remembering where we came from so we can walk back
+        let mut previous:HashMap<Interned, Interned, MyHasher> = HashMap::default();
+        let mut frontier = vec![from];
+        previous.insert(from, 0);
+        'search: while frontier.len() > 0 && !previous.contains_key(&to) {
+            let mut next = vec![];
+            for node in frontier {
+                if let Some(out) = self.edges.get(&node) {
+                    for neighbor in out.iter() {
+                        if !previous.contains_key(neighbor) {
+                            previous.insert(*neighbor, node);
+                            if *neighbor == to { break 'search; }
+                            next.push(*neighbor);
+                        }
+                    }
+                }
+            }
+            frontier = next;
+        }
+        if !previous.contains_key(&to) { return None; }
+        let mut path = vec![to];
+        let mut cur = to;
+        while cur != from {
+            cur = previous[&cur];
+            path.push(cur);
+        }
+        path.reverse();
+        Some(path)
+    }
+}
+
+//-------------------------------------------------------------------------
+// Text level
+//-------------------------------------------------------------------------
+
+const BM25_K1:f32 = 1.2;
+const BM25_B:f32 = 0.75;
+
+// An inverted index over the string values of a single attribute, where all of a
+// record's values make up one document. It's updated a value at a time, so scoring a
+// query only looks at the postings for its terms.
+#[derive(Clone)]
+pub struct TextLevel {
+    postings: HashMap<String, HashMap<Interned, u32, MyHasher>, MyHasher>,
+    // for each document, how many string values it has and how many terms they hold
+    documents: HashMap<Interned, (u32, u32), MyHasher>,
+    total_length: u64,
+}
+
+impl TextLevel {
+    pub fn new() -> TextLevel {
+        TextLevel { postings: HashMap::default(), documents: HashMap::default(), total_length: 0 }
+    }
+
+    pub fn insert(&mut self, e:Interned, value:&Internable) {
+        let terms = match value {
+            &Internable::String(ref text) => tokenize(text),
+            _ => return,
+        };
+        {
+            let document = self.documents.entry(e).or_insert((0, 0));
+            document.0 += 1;
+            document.1 += terms.len() as u32;
+        }
+        self.total_length += terms.len() as u64;
+        for term in terms {
+            *self.postings.entry(term).or_insert_with(HashMap::default).entry(e).or_insert(0) += 1;
+        }
+    }
+
+    pub fn remove(&mut self, e:Interned, value:&Internable) {
+        let terms = match value {
+            &Internable::String(ref text) => tokenize(text),
+            _ => return,
+        };
+        let gone = match self.documents.get_mut(&e) {
+            Some(document) => {
+                document.0 -= 1;
+                document.1 -= terms.len() as u32;
+                document.0 == 0
+            }
+            None => return,
+        };
+        if gone { self.documents.remove(&e); }
+        self.total_length -= terms.len() as u64;
+        for term in terms {
+            let empty = match self.postings.get_mut(&term) {
+                Some(posting) => {
+                    let zero = match posting.get_mut(&e) {
+                        Some(frequency) => { *frequency -= 1; *frequency == 0 }
+                        None => false,
+                    };
+                    if zero { posting.remove(&e); }
+                    posting.len() == 0
+                }
+                None => false,
+            };
+            if empty { self.postings.remove(&term); }
+        }
+    }
 
-impl RoundEntry {
-    pub fn update_active(&mut self, round:Round, count:Count) {
-        update_active_rounds_vec(&mut self.active_rounds, round, count);
+    /// The BM25 score of every document containing at least one of the terms.
+    pub fn score(&self, terms:&[String]) -> Vec<(Interned, f32)> {
+        if self.documents.len() == 0 { return vec![]; }
+        let document_count = self.documents.len() as f32;
+        let average_length = self.total_length as f32 / document_count;
+        let mut scores:HashMap<Interned, f32, MyHasher> = HashMap::default();
+        for term in terms.iter() {
+            let posting = match self.postings.get(term) {
+                Some(posting) => posting,
+                None => continue,
+            };
+            let containing = posting.len() as f32;
+            let idf = (1.0 + (document_count - containing + 0.5) / (containing + 0.5)).ln();
+            for (e, &frequency) in posting.iter() {
+                let length = self.documents.get(e).map_or(0, |document| document.1) as f32;
+                let length_norm = 1.0 - BM25_B + BM25_B * (length / average_length);
+                let frequency = frequency as f32;
+                *scores.entry(*e).or_insert(0.0) += idf * (frequency * (BM25_K1 + 1.0)) / (frequency + BM25_K1 * length_norm);
+            }
+        }
+        scores.into_iter().filter(|&(_, score)| score > 0.0).collect()
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct HashIndex {
     a: HashMap<Interned, HashIndexLevel, MyHasher>,
+    ordered: HashMap<Interned, OrderedLevel, MyHasher>,
+    // Only the attributes a graph function has asked about. These are rebuilt from `a`
+    // the first time they're needed, so there's no point in saving them.
+    #[serde(skip)]
+    graphs: HashMap<Interned, GraphLevel, MyHasher>,
+    // Text indexes for the attributes declared `@fulltext` or searched by `search/text`,
+    // rebuilt the same way.
+    #[serde(skip)]
+    texts: HashMap<Interned, TextLevel, MyHasher>,
     pub size: u32,
 }
 
 impl HashIndex {
     pub fn new() -> HashIndex{
-        HashIndex { a: HashMap::default(), size: 0 }
+        HashIndex { a: HashMap::default(), ordered: HashMap::default(), graphs: HashMap::default(), texts: HashMap::default(), size: 0 }
+    }
+
+    pub fn stats(&self) -> IndexStats {
+        let mut stats = IndexStats { entries: self.size as usize, ..IndexStats::default() };
+        stats.table(&self.a);
+        stats.table(&self.ordered);
+        for level in self.a.values() {
+            stats.columns(&level.e);
+            stats.columns(&level.v);
+        }
+        for level in self.ordered.values() {
+            stats.bytes += level.numbers.len() * size_of::<(u32, Interned)>();
+        }
+        stats
+    }
+
+    pub fn attribute_stats(&self, a:Interned) -> AttributeStats {
+        match self.a.get(&a) {
+            Some(level) => AttributeStats { facts: level.size, entities: level.e.distinct, values: level.v.distinct },
+            None => AttributeStats { facts: 0, entities: 0, values: 0 },
+        }
+    }
+
+    /// Every (e, a, v) in the index, in no particular order.
+    pub fn facts(&self) -> Vec<(Interned, Interned, Interned)> {
+        let mut facts = vec![];
+        for (&a, level) in self.a.iter() {
+            facts.extend(level.e.rows().map(|(e, v)| (e, a, v)));
+        }
+        facts
+    }
+
+    fn has_value(&self, a:Interned, v:Interned) -> bool {
+        self.a.get(&a).map_or(false, |level| level.v.contains_key(v))
+    }
+
+    /// Inserts like `insert`, also keeping the ordered, graph and text indexes for `a`
+    /// up to date with the value `v` stands for.
+    pub fn insert_value(&mut self, e:Interned, a:Interned, v:Interned, interner:&Interner) -> bool {
+        let value = interner.get_value(v);
+        let is_new_value = !self.has_value(a, v);
+        let added = self.insert(e, a, v);
+        if added && is_new_value {
+            self.ordered.entry(a).or_insert_with(OrderedLevel::new).insert(v, value);
+        }
+        if added {
+            if let Some(graph) = self.graphs.get_mut(&a) { graph.insert(e, v); }
+            if let Some(text) = self.texts.get_mut(&a) { text.insert(e, value); }
+            // `commit @fulltext [attribute: "body"]` starts indexing `body` right away
+            if a == FULLTEXT_INTERNED_ID { self.text(v, interner); }
+        }
+        added
+    }
+
+    pub fn remove_value(&mut self, e:Interned, a:Interned, v:Interned, interner:&Interner) -> bool {
+        let value = interner.get_value(v);
+        let had_value = self.has_value(a, v);
+        let removed = self.remove(e, a, v);
+        if removed && had_value && !self.has_value(a, v) {
+            if let Some(level) = self.ordered.get_mut(&a) {
+                level.remove(v, value);
+            }
+        }
+        if removed {
+            if let Some(graph) = self.graphs.get_mut(&a) { graph.remove(e, v); }
+            if let Some(text) = self.texts.get_mut(&a) { text.remove(e, value); }
+        }
+        removed
+    }
+
+    /// The graph formed by the facts for `a`. It's built the first time it's asked for
+    /// and kept up to date by `insert_value` and `remove_value` from then on.
+    pub fn graph(&mut self, a:Interned) -> &mut GraphLevel {
+        if !self.graphs.contains_key(&a) {
+            let mut level = GraphLevel::new();
+            for (e, v) in self.edges(a) {
+                level.insert(e, v);
+            }
+            self.graphs.insert(a, level);
+        }
+        self.graphs.get_mut(&a).unwrap()
+    }
+
+    /// The text index over the string values of `a`, built the first time it's asked
+    /// for or when `a` is declared `@fulltext`, and kept up to date from then on.
+    pub fn text(&mut self, a:Interned, interner:&Interner) -> &TextLevel {
+        if !self.texts.contains_key(&a) {
+            let mut level = TextLevel::new();
+            for (e, v) in self.edges(a) {
+                level.insert(e, interner.get_value(v));
+            }
+            self.texts.insert(a, level);
+        }
+        &self.texts[&a]
     }
 
     pub fn insert(&mut self, e: Interned, a:Interned, v:Interned) -> bool {
@@ -475,6 +1322,68 @@ impl HashIndex {
         }
     }
 
+    pub fn entity_facts(&self, e:Interned) -> Vec<(Interned, Interned)> {
+        let mut facts = vec![];
+        for (a, level) in self.a.iter() {
+            if let Some(values) = level.find_values(e) {
+                facts.extend(values.map(|v| (*a, v)));
+            }
+        }
+        facts
+    }
+
+    pub fn value_facts(&self, v:Interned) -> Vec<(Interned, Interned)> {
+        let mut facts = vec![];
+        for (a, level) in self.a.iter() {
+            if let Some(entities) = level.find_entities(v) {
+                facts.extend(entities.map(|e| (e, *a)));
+            }
+        }
+        facts
+    }
+
+    pub fn attributes(&self) -> Vec<Interned> {
+        self.a.keys().cloned().collect()
+    }
+
+    pub fn edges(&self, a:Interned) -> Vec<(Interned, Interned)> {
+        match self.a.get(&a) {
+            Some(level) => level.pairs(),
+            None => vec![],
+        }
+    }
+
+    /// Proposes the entities whose value for `a` falls between the bounds. Returns None
+    /// if the ordered index can't answer for `a`, in which case the caller should fall
+    /// back to a normal `propose`.
+    pub fn propose_range(&self, iter: &mut EstimateIter, a:Interned, low:Bound<f32>, high:Bound<f32>) -> Option<bool> {
+        let (level, ordered) = match (self.a.get(&a), self.ordered.get(&a)) {
+            (Some(level), Some(ordered)) => (level, ordered),
+            _ => return None,
+        };
+        let values = match ordered.range(low, high) {
+            Some(values) => values,
+            None => return None,
+        };
+        let mut entities = vec![];
+        for v in values {
+            if let Some(found) = level.find_entities(v) {
+                entities.extend(found);
+            }
+        }
+        // an entity with several values in range would otherwise be proposed once per value
+        entities.sort();
+        entities.dedup();
+        let estimate = entities.len();
+        if iter.is_better(estimate) {
+            iter.estimate = estimate;
+            iter.iter = OutputingIter::Single(0, OutputingIter::make_ptr(Box::new(entities.into_iter())));
+            Some(true)
+        } else {
+            Some(false)
+        }
+    }
+
     pub fn propose(&self, iter: &mut EstimateIter, e:Interned, a:Interned, v:Interned) -> bool {
         if a == 0 {
             // @NOTE: In the case where we have an arbitrary lookup we may propose values that may not be correct, but
@@ -506,17 +1415,65 @@ impl HashIndex {
 // Distinct Index
 //-------------------------------------------------------------------------
 
+// Bound facts are counted per round, one for every derivation that produced them, so
+// a fact with several derivations sticks around until the last of them is retracted.
+// Commits at round 0 are set-like instead: committing a fact twice and removing it once
+// removes it.
+#[derive(Serialize, Deserialize)]
 pub struct DistinctIndex {
-    pub eavs: HashMap<(Interned, Interned, Interned), RoundEntry, MyHasher>,
+    pub eavs: SharedMap<(Interned, Interned, Interned), RoundEntry>,
     empty: Vec<i32>,
+    #[serde(skip)]
+    undo: Undo<(Interned, Interned, Interned), RoundEntry>,
 }
 
 impl DistinctIndex {
     pub fn new() -> DistinctIndex {
-        DistinctIndex { eavs: HashMap::default(), empty: vec![] }
+        DistinctIndex { eavs: SharedMap::new(), empty: vec![], undo: Undo::new() }
+    }
+
+    /// A copy to write out while this one keeps changing.
+    pub fn share(&self) -> DistinctIndex {
+        DistinctIndex { eavs: self.eavs.share(), empty: vec![], undo: Undo::new() }
+    }
+
+    fn save(&mut self, key:(Interned, Interned, Interned)) {
+        self.undo.save(&key, self.eavs.get(&key));
+    }
+
+    /// Forgets how to undo what's been changed so far.
+    pub fn save_point(&mut self) {
+        self.undo.clear();
+    }
+
+    /// Puts every EAV changed since the last save point back the way it was, returning
+    /// the ones that were in the index then and aren't now or the other way round, with
+    /// whether they were.
+    pub fn roll_back(&mut self) -> Vec<((Interned, Interned, Interned), bool)> {
+        let mut flipped = vec![];
+        for (key, saved) in self.undo.drain() {
+            let was = saved.as_ref().map_or(false, |entry| entry.inserted);
+            let is = self.eavs.get(&key).map_or(false, |entry| entry.inserted);
+            match saved {
+                Some(entry) => { self.eavs.insert(key, entry); }
+                None => { self.eavs.remove(&key); }
+            }
+            if was != is { flipped.push((key, was)); }
+        }
+        flipped
+    }
+
+    pub fn stats(&self) -> IndexStats {
+        let mut stats = IndexStats { entries: self.eavs.len(), ..IndexStats::default() };
+        stats.shared_table(&self.eavs);
+        for entry in self.eavs.values() {
+            stats.round_entry(entry);
+        }
+        stats
     }
 
     pub fn insert_active(&mut self, e: Interned, a:Interned, v:Interned, round:Round) -> bool {
+        self.save((e,a,v));
         match self.eavs.entry((e,a,v)) {
             Entry::Occupied(mut entry) => {
                 let info = entry.get_mut();
@@ -533,6 +1490,7 @@ impl DistinctIndex {
     }
 
     pub fn remove_active(&mut self, e: Interned, a:Interned, v:Interned, round:Round) -> bool {
+        self.save((e,a,v));
         match self.eavs.entry((e,a,v)) {
             Entry::Occupied(mut entry) => {
                 // There are two possibilities we have to worry about here. One is that we have
@@ -572,10 +1530,25 @@ impl DistinctIndex {
         }
     }
 
+    pub fn commits(&self) -> Vec<(Interned, Interned, Interned)> {
+        self.eavs.iter()
+            .filter(|&(_, rounds)| rounds.active_rounds.get(0) == Some(&0))
+            .map(|(&eav, _)| eav)
+            .collect()
+    }
+
+    /// Every EAV that's currently in the index, committed or bound.
+    pub fn inserted(&self) -> Vec<(Interned, Interned, Interned)> {
+        self.eavs.iter()
+            .filter(|&(_, entry)| entry.inserted)
+            .map(|(&eav, _)| eav)
+            .collect()
+    }
+
     pub fn is_available(&self, e:Interned, a:Interned, v:Interned) -> bool {
+        // 0 is never handed out as an id, so a fact with one can't be in here
         if e == 0 || a == 0 || v == 0 {
-            panic!(println!("{} Can't check availability of an unformed EAV ({}, {}, {})",
-                            BrightRed.paint("Fatal Internal Error:"), e, a, v));
+            return false;
         }
         match self.eavs.get(&(e,a,v)) {
             Some(rounds) => {
@@ -589,8 +1562,17 @@ impl DistinctIndex {
         self.eavs.get(&(e,a,v))
     }
 
+    /// The number of derivations currently holding up a bound fact.
+    pub fn support(&self, e:Interned, a:Interned, v:Interned) -> Count {
+        match self.eavs.get(&(e,a,v)) {
+            Some(entry) => entry.rounds.iter().skip(1).fold(0, |prev, x| prev + x),
+            None => 0,
+        }
+    }
+
     pub fn raw_insert(&mut self, e:Interned, a:Interned, v:Interned, round:Round, count:Count) -> bool {
         let key = (e, a, v);
+        self.save(key);
         let info = self.eavs.entry(key).or_insert_with(|| RoundEntry { inserted:false, rounds: vec![], active_rounds:vec![] });
         let ref mut counts = info.rounds;
         ensure_len(counts, (round + 1) as usize);
@@ -618,6 +1600,7 @@ impl DistinctIndex {
 
     pub fn distinct(&mut self, input:&Change, rounds:&mut RoundHolder) {
         let key = (input.e, input.a, input.v);
+        self.save(key);
         let insert = |round, delta| {
             rounds.insert(input.with_round_count(round, delta));
         };
@@ -632,6 +1615,69 @@ impl DistinctIndex {
     }
 }
 
+//-------------------------------------------------------------------------
+// Block Distinct
+//-------------------------------------------------------------------------
+
+// The binds of a block in distinct mode, counted per round by how many of the block's own
+// rows derive them. Only the block's first derivation of an EAV is passed on to the
+// DistinctIndex and only its last retraction takes it back, so however many ways the join
+// finds the same output, the block supports it exactly once.
+#[derive(Serialize, Deserialize)]
+pub struct BlockDistinct {
+    eavs: SharedMap<(Interned, Interned, Interned), Vec<Count>>,
+    #[serde(skip)]
+    undo: Undo<(Interned, Interned, Interned), Vec<Count>>,
+}
+
+impl BlockDistinct {
+    pub fn new() -> BlockDistinct {
+        BlockDistinct { eavs: SharedMap::new(), undo: Undo::new() }
+    }
+
+    /// A copy to write out while this one keeps changing.
+    pub fn share(&self) -> BlockDistinct {
+        BlockDistinct { eavs: self.eavs.share(), undo: Undo::new() }
+    }
+
+    pub fn save_point(&mut self) {
+        self.undo.clear();
+    }
+
+    pub fn roll_back(&mut self) {
+        for (key, saved) in self.undo.drain() {
+            match saved {
+                Some(counts) => { self.eavs.insert(key, counts); }
+                None => { self.eavs.remove(&key); }
+            }
+        }
+    }
+
+    /// Counts a derivation of the EAV, calling `insert` with the round and count of every
+    /// change to whether the block derives it at all.
+    pub fn derive<F>(&mut self, e:Interned, a:Interned, v:Interned, round:Round, count:Count, insert:F) where F: FnMut(Round, Count) {
+        let key = (e, a, v);
+        self.undo.save(&key, self.eavs.get(&key));
+        let retracted = {
+            let counts = self.eavs.entry(key).or_insert_with(|| vec![]);
+            generic_distinct(counts, count, round, insert, false);
+            counts.iter().all(|count| *count == 0)
+        };
+        if retracted {
+            self.eavs.remove(&key);
+        }
+    }
+
+    /// How many of the block's rows derive the EAV, over every round.
+    pub fn derivations(&self, e:Interned, a:Interned, v:Interned) -> Count {
+        self.eavs.get(&(e, a, v)).map_or(0, |counts| counts.iter().sum())
+    }
+
+    pub fn len(&self) -> usize {
+        self.eavs.len()
+    }
+}
+
 //-------------------------------------------------------------------------
 // Distinct Iter
 //-------------------------------------------------------------------------
@@ -760,10 +1806,11 @@ impl RemoteIndex {
 // Intermediate Index
 //-------------------------------------------------------------------------
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum AggregateEntry {
     Empty,
     Result(f32),
+    Decimal(Decimal),
     Counted { sum: f32, count: f32, result: f32 },
     SortedSum { items: BTreeMap<Vec<Internable>, Vec<Internable>>, result: Internable },
     Sorted { items: BTreeMap<Vec<Internable>, Vec<Count>>, input_round: Round, current_round: Round, current_params:Option<Vec<Internable>>, changes: Vec<(Vec<Internable>, Round, Count)>, limit: usize },
@@ -773,6 +1820,7 @@ impl AggregateEntry {
     pub fn get_result(&self, interner:&mut Interner) -> Vec<Interned> {
         match self {
             &AggregateEntry::Result(res) => vec![interner.number_id(res)],
+            &AggregateEntry::Decimal(res) => vec![interner.decimal_id(res)],
             &AggregateEntry::Counted { result, .. } => vec![interner.number_id(result)],
             &AggregateEntry::SortedSum { ref result, .. } => { vec![interner.internable_to_id(result.clone())] },
             &AggregateEntry::Sorted {..} => { unimplemented!() },
@@ -781,6 +1829,88 @@ impl AggregateEntry {
     }
 }
 
+//-------------------------------------------------------------------------
+// KeyFilter
+//-------------------------------------------------------------------------
+
+// A Bloom filter over the keys an intermediate index has held. Anti-scans probe the
+// index once per candidate row and most of the time nothing matched the `not`, so the
+// key isn't there; the filter answers that from a few bits of the key's hash, without
+// walking the index's table and comparing vecs. The index finds keys by that same hash,
+// so a key that does get past the filter isn't hashed again. Keys are never taken back out, so as the index
+// churns the filter collects stale keys and it's rebuilt from the live ones, with room
+// to spare, once it has taken in more keys than it was sized for.
+const KEY_FILTER_HASHES:u64 = 3;
+const KEY_FILTER_BITS_PER_KEY:usize = 10;
+const KEY_FILTER_MIN_BITS:usize = 1024;
+
+/// The hash both the key filter and the intermediate index find a key by.
+pub fn key_hash(key:&[Interned]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct KeyFilter {
+    bits: Vec<u64>,
+    keys: usize,
+}
+
+impl Default for KeyFilter {
+    fn default() -> KeyFilter {
+        KeyFilter::new(0)
+    }
+}
+
+impl KeyFilter {
+    pub fn new(expected_keys:usize) -> KeyFilter {
+        let size = cmp::max(KEY_FILTER_MIN_BITS, (expected_keys * KEY_FILTER_BITS_PER_KEY).next_power_of_two());
+        KeyFilter { bits: vec![0; size / 64], keys: 0 }
+    }
+
+    // Double hashing: the i-th probe is h1 + i * h2, which behaves as well as k
+    // independent hashes for a filter this size.
+    fn probes(&self, hash:u64) -> (u64, u64, u64) {
+        let h2 = hash.rotate_left(32) | 1;
+        (hash, h2, (self.bits.len() * 64) as u64 - 1)
+    }
+
+    pub fn insert(&mut self, key:&[Interned]) {
+        self.insert_hash(key_hash(key));
+    }
+
+    /// Inserts a key by its `key_hash`.
+    pub fn insert_hash(&mut self, hash:u64) {
+        let (h1, h2, mask) = self.probes(hash);
+        let mut added = false;
+        for ix in 0..KEY_FILTER_HASHES {
+            let bit = (h1.wrapping_add(ix.wrapping_mul(h2)) & mask) as usize;
+            added |= self.bits[bit / 64] & (1 << (bit % 64)) == 0;
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        if added { self.keys += 1; }
+    }
+
+    /// False means the key has definitely never been inserted.
+    pub fn may_contain(&self, key:&[Interned]) -> bool {
+        self.may_contain_hash(key_hash(key))
+    }
+
+    pub fn may_contain_hash(&self, hash:u64) -> bool {
+        let (h1, h2, mask) = self.probes(hash);
+        (0..KEY_FILTER_HASHES).all(|ix| {
+            let bit = (h1.wrapping_add(ix.wrapping_mul(h2)) & mask) as usize;
+            self.bits[bit / 64] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.keys * KEY_FILTER_BITS_PER_KEY > self.bits.len() * 64
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 enum IntermediateLevel {
     Value(HashMap<Vec<Interned>, RoundEntry, MyHasher>),
     KeyOnly(RoundEntry),
@@ -788,23 +1918,173 @@ enum IntermediateLevel {
     SortAggregate(Vec<Round>, AggregateEntry),
 }
 
+// Key hashes are already spread out, so the table keyed by them uses them as they are.
+#[derive(Default)]
+pub struct KeyHashHasher(u64);
+
+impl Hasher for KeyHashHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes:&[u8]) {
+        for &byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ byte as u64;
+        }
+    }
+    fn write_u64(&mut self, hash:u64) {
+        self.0 = hash;
+    }
+}
+
+// An intermediate index's levels, found by the `key_hash` of their key. The key filter
+// is probed with the same hash, so a lookup hashes its key once. A key whose hash is
+// already held by another key goes in `collided`, which is nearly always empty; every
+// key in it shares its hash with a key in `hashed`.
+#[derive(Serialize, Deserialize, Default)]
+struct KeyedLevels {
+    hashed: SharedMap<u64, (Vec<Interned>, IntermediateLevel), BuildHasherDefault<KeyHashHasher>>,
+    collided: HashMap<Vec<Interned>, IntermediateLevel, MyHasher>,
+}
+
+impl KeyedLevels {
+    fn share(&self) -> KeyedLevels {
+        KeyedLevels { hashed: self.hashed.share(), collided: self.collided.clone() }
+    }
+
+    fn len(&self) -> usize {
+        self.hashed.len() + self.collided.len()
+    }
+
+    // Whether the key is the one held under its hash, if any key is.
+    fn holds(&self, hash:u64, key:&[Interned]) -> Option<bool> {
+        self.hashed.get(&hash).map(|&(ref found, _)| &found[..] == key)
+    }
+
+    fn contains_key(&self, hash:u64, key:&[Interned]) -> bool {
+        match self.holds(hash, key) {
+            Some(true) => true,
+            Some(false) => self.collided.contains_key(key),
+            None => false,
+        }
+    }
+
+    fn get(&self, hash:u64, key:&[Interned]) -> Option<&IntermediateLevel> {
+        match self.holds(hash, key) {
+            Some(true) => self.hashed.get(&hash).map(|&(_, ref level)| level),
+            Some(false) => self.collided.get(key),
+            None => None,
+        }
+    }
+
+    fn get_mut(&mut self, hash:u64, key:&[Interned]) -> Option<&mut IntermediateLevel> {
+        match self.holds(hash, key) {
+            Some(true) => self.hashed.get_mut(&hash).map(|&mut (_, ref mut level)| level),
+            Some(false) => self.collided.get_mut(key),
+            None => None,
+        }
+    }
+
+    fn get_or_insert_with<F:FnOnce() -> IntermediateLevel>(&mut self, hash:u64, key:Vec<Interned>, make:F) -> &mut IntermediateLevel {
+        match self.holds(hash, &key) {
+            Some(false) => self.collided.entry(key).or_insert_with(make),
+            _ => &mut self.hashed.entry(hash).or_insert_with(|| (key, make())).1,
+        }
+    }
+
+    fn insert(&mut self, hash:u64, key:Vec<Interned>, level:IntermediateLevel) {
+        match self.holds(hash, &key) {
+            Some(false) => { self.collided.insert(key, level); }
+            _ => { self.hashed.insert(hash, (key, level)); }
+        }
+    }
+
+    fn remove(&mut self, hash:u64, key:&[Interned]) {
+        match self.holds(hash, key) {
+            Some(true) => {
+                self.hashed.remove(&hash);
+                self.promote_collided();
+            }
+            Some(false) => { self.collided.remove(key); }
+            None => {}
+        }
+    }
+
+    // Moves collided keys whose hash nothing holds any more into `hashed`.
+    fn promote_collided(&mut self) {
+        if self.collided.len() == 0 { return; }
+        let keys:Vec<Vec<Interned>> = self.collided.keys().cloned().collect();
+        for key in keys {
+            let hash = key_hash(&key);
+            if !self.hashed.contains_key(&hash) {
+                let level = self.collided.remove(&key).unwrap();
+                self.hashed.insert(hash, (key, level));
+            }
+        }
+    }
+
+    // Every key's hash, once. Collided keys share theirs with a key in `hashed`.
+    fn hashes<'a>(&'a self) -> Box<Iterator<Item=u64> + 'a> {
+        Box::new(self.hashed.keys().cloned())
+    }
+
+    fn keys<'a>(&'a self) -> Box<Iterator<Item=&'a Vec<Interned>> + 'a> {
+        Box::new(self.hashed.values().map(|&(ref key, _)| key).chain(self.collided.keys()))
+    }
+
+    fn iter<'a>(&'a self) -> Box<Iterator<Item=(&'a Vec<Interned>, &'a IntermediateLevel)> + 'a> {
+        Box::new(self.hashed.values().map(|&(ref key, ref level)| (key, level)).chain(self.collided.iter()))
+    }
+
+    fn retain<F:FnMut(&Vec<Interned>, &mut IntermediateLevel) -> bool>(&mut self, mut keep:F) {
+        self.hashed.retain(|_, &mut (ref key, ref mut level)| keep(key, level));
+        self.collided.retain(|key, level| keep(key, level));
+        self.promote_collided();
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.hashed.shrink_to_fit();
+        self.collided.shrink_to_fit();
+    }
+
+    fn stats(&self, stats:&mut IndexStats) {
+        stats.shared_table(&self.hashed);
+        stats.table(&self.collided);
+    }
+}
+
 pub struct DebugEntry {
     input: Internable,
     count: Count,
     pairs: Vec<(Internable, Internable, Count)>
 }
 
+// Intermediate keys are composite: the id of the sub-block that produced them followed
+// by its inputs. Besides the keys themselves, the index counts how many live keys start
+// with each prefix of those inputs, whatever their id, so a `not` and an `if` keyed on
+// the same inputs can find out with one probe whether either of them could match. Any
+// change to which keys exist bumps `generation`, so answers cached by the solver can
+// tell when they've gone stale.
+#[derive(Serialize, Deserialize)]
 pub struct IntermediateIndex {
-    index: HashMap<Vec<Interned>, IntermediateLevel, MyHasher>,
+    index: KeyedLevels,
     pub rounds: HashMap<Round, HashMap<Vec<Interned>, IntermediateChange, MyHasher>, MyHasher>,
     max_round: Round,
     empty: Vec<i32>,
-
+    // the filter and prefixes are rebuilt from the keys rather than saved
+    #[serde(skip)]
+    filter: KeyFilter,
+    #[serde(skip)]
+    prefixes: HashMap<Vec<Interned>, u32, MyHasher>,
+    generation: u64,
+    #[serde(skip)]
+    undo: Undo<Vec<Interned>, IntermediateLevel>,
+
+    #[serde(skip)]
     debug_vec: Vec<DebugEntry>
 }
 
 // FIXME: attack of the clones.
-fn intermediate_distinct(index:&mut HashMap<Vec<Interned>, IntermediateLevel, MyHasher>,
+fn intermediate_distinct(index:&mut KeyedLevels, hash:u64,
                          rounds:&mut HashMap<Round, HashMap<Vec<Interned>, IntermediateChange, MyHasher>, MyHasher>,
                          full_key:Vec<Interned>, key:Vec<Interned>, value:Vec<Interned>,
                          round:Round, count:Count, negate:bool) {
@@ -826,7 +2106,7 @@ fn intermediate_distinct(index:&mut HashMap<Vec<Interned>, IntermediateLevel, My
             }
         }
     };
-    let entry = index.entry(key.clone()).or_insert_with(|| {
+    let entry = index.get_or_insert_with(hash, key.clone(), || {
         let entry = RoundEntry { inserted:false, rounds: vec![], active_rounds: vec![] };
         if value.len() == 0 {
             IntermediateLevel::KeyOnly(entry)
@@ -896,11 +2176,126 @@ pub fn update_aggregate(interner: &mut Interner, changes: &mut Vec<AggregateChan
 impl IntermediateIndex {
 
     pub fn new() -> IntermediateIndex {
-        IntermediateIndex { index: HashMap::default(), rounds: HashMap::default(), empty: vec![], max_round:0, debug_vec: vec![] }
+        IntermediateIndex { index: KeyedLevels::default(), rounds: HashMap::default(), empty: vec![], max_round:0, filter: KeyFilter::new(0), prefixes: HashMap::default(), generation: 0, undo: Undo::new(), debug_vec: vec![] }
+    }
+
+    /// A copy to write out while this one keeps changing. Like a saved index, it has to
+    /// be `rebuild`-ed before it can be used.
+    pub fn share(&self) -> IntermediateIndex {
+        IntermediateIndex { index: self.index.share(), rounds: self.rounds.clone(), empty: vec![], max_round: self.max_round, filter: KeyFilter::new(0), prefixes: HashMap::default(), generation: self.generation, undo: Undo::new(), debug_vec: vec![] }
+    }
+
+    /// Rebuilds the key filter and prefix counts from the keys, for an index that was
+    /// saved without them.
+    pub fn rebuild(&mut self) {
+        self.rebuild_filter();
+        let derived:Vec<Vec<Interned>> = self.index.iter().filter_map(|(key, level)| {
+            match level {
+                &IntermediateLevel::KeyOnly(_) | &IntermediateLevel::Value(_) => Some(key.clone()),
+                _ => None,
+            }
+        }).collect();
+        self.prefixes = HashMap::default();
+        for key in derived {
+            self.add_prefixes(&key);
+        }
+    }
+
+    /// The keys that have anything stored under them.
+    pub fn keys(&self) -> Vec<&Vec<Interned>> {
+        self.index.keys().collect()
+    }
+
+    /// Whether any live key, whatever sub-block it's from, has inputs that start with
+    /// `inputs`.
+    pub fn has_prefix(&self, inputs:&[Interned]) -> bool {
+        self.prefixes.contains_key(inputs)
+    }
+
+    /// Changes whenever a key is added or removed.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    fn add_prefixes(&mut self, key:&[Interned]) {
+        for end in 2..key.len() + 1 {
+            *self.prefixes.entry(key[1..end].to_vec()).or_insert(0) += 1;
+        }
+        self.generation += 1;
+    }
+
+    fn remove_prefixes(&mut self, key:&[Interned]) {
+        for end in 2..key.len() + 1 {
+            let gone = match self.prefixes.get_mut(&key[1..end]) {
+                Some(count) => { *count -= 1; *count == 0 }
+                None => false,
+            };
+            if gone { self.prefixes.remove(&key[1..end]); }
+        }
+        self.generation += 1;
+    }
+
+    fn save(&mut self, hash:u64, key:&[Interned]) {
+        if self.undo.saved.contains_key(key) { return; }
+        let current = self.index.get(hash, key).cloned();
+        self.undo.saved.insert(key.to_vec(), current);
+    }
+
+    /// Forgets how to undo what's been changed so far.
+    pub fn save_point(&mut self) {
+        self.undo.clear();
+    }
+
+    /// Puts every key changed since the last save point back the way it was and drops
+    /// the changes still waiting to be run.
+    pub fn roll_back(&mut self) {
+        for (key, saved) in self.undo.drain() {
+            let hash = key_hash(&key);
+            // only the keys of intermediates, not aggregates, count towards the prefixes
+            let derived = |level:&IntermediateLevel| match level {
+                &IntermediateLevel::KeyOnly(_) | &IntermediateLevel::Value(_) => true,
+                _ => false,
+            };
+            let was = saved.as_ref().map_or(false, &derived);
+            let is = self.index.get(hash, &key).map_or(false, &derived);
+            if was && !is { self.add_prefixes(&key); }
+            if is && !was { self.remove_prefixes(&key); }
+            self.index.remove(hash, &key);
+            if let Some(level) = saved {
+                self.track_key(hash);
+                self.index.insert(hash, key, level);
+            }
+        }
+        self.rounds.clear();
+        self.max_round = 0;
+    }
+
+    /// Whether `key` could be in the index. Keys that were never produced, the usual case
+    /// for the key of a `not`, are turned away here without a lookup.
+    pub fn may_contain(&self, key:&Vec<Interned>) -> bool {
+        self.filter.may_contain(key)
+    }
+
+    fn track_key(&mut self, hash:u64) {
+        self.filter.insert_hash(hash);
+        if self.filter.is_saturated() {
+            self.rebuild_filter();
+            self.filter.insert_hash(hash);
+        }
+    }
+
+    fn rebuild_filter(&mut self) {
+        let mut filter = KeyFilter::new(self.index.len() * 2);
+        for hash in self.index.hashes() {
+            filter.insert_hash(hash);
+        }
+        self.filter = filter;
     }
 
     pub fn check(&self, key:&Vec<Interned>, value:&Vec<Interned>) -> bool {
-        match self.index.get(key) {
+        let hash = key_hash(key);
+        if !self.filter.may_contain_hash(hash) { return false; }
+        match self.index.get(hash, key) {
             Some(level) => {
                 match level {
                     &IntermediateLevel::KeyOnly(ref entry) => entry.active_rounds.len() > 0,
@@ -919,7 +2314,9 @@ impl IntermediateIndex {
     }
 
     pub fn distinct_iter(&self, key:&Vec<Interned>, value:&Vec<Interned>) -> DistinctIter {
-        match self.index.get(key) {
+        let hash = key_hash(key);
+        if !self.filter.may_contain_hash(hash) { return DistinctIter::new(&self.empty); }
+        match self.index.get(hash, key) {
             Some(level) => {
                 match level {
                     &IntermediateLevel::KeyOnly(ref entry) => DistinctIter::new(&entry.active_rounds),
@@ -940,8 +2337,11 @@ impl IntermediateIndex {
     pub fn aggregate(&mut self, interner:&mut Interner, group:Vec<Interned>, mut projection:Vec<Internable>, value:Vec<Internable>, round:Round, count:Count, action:AggregateFunction, out:Vec<Interned>, kind:FunctionKind) {
         let projection_len = projection.len();
         let mut changes = vec![];
+        let hash = key_hash(&group);
+        self.track_key(hash);
+        self.save(hash, &group);
         {
-            let cur = self.index.entry(group).or_insert_with(|| {
+            let cur = self.index.get_or_insert_with(hash, group, || {
                 if kind == FunctionKind::Sum || kind == FunctionKind::SortedSum {
                     IntermediateLevel::SumAggregate(BTreeMap::new())
                 } else {
@@ -1043,7 +2443,7 @@ impl IntermediateIndex {
     }
 
     pub fn propose(&self, iter: &mut EstimateIter, key:Vec<Interned>, outputs: Vec<usize>) -> bool {
-        match self.index.get(&key) {
+        match self.index.get(key_hash(&key), &key) {
             Some(&IntermediateLevel::Value(ref lookup)) => {
                 let estimate = lookup.len();
                 if iter.is_better(estimate) {
@@ -1075,16 +2475,18 @@ impl IntermediateIndex {
     pub fn update_active_rounds(&mut self, change: &IntermediateChange) {
         let (key, value) = change.key.split_at(change.value_pos);
         let count = change.count;
-        let should_remove = match self.index.get_mut(key) {
+        let hash = key_hash(key);
+        self.save(hash, key);
+        let should_remove = match self.index.get_mut(hash, key) {
             Some(&mut IntermediateLevel::KeyOnly(ref mut info)) => {
                 info.update_active(change.round, count);
-                !info.rounds.iter().any(|x| *x != 0) && info.active_rounds.len() == 0
+                info.is_retracted()
             }
             Some(&mut IntermediateLevel::Value(ref mut lookup)) => {
                 let remove = match lookup.get_mut(value) {
                     Some(ref mut info) => {
                         info.update_active(change.round, count);
-                        !info.rounds.iter().any(|x| *x != 0) && info.active_rounds.len() == 0
+                        info.is_retracted()
                     },
                     None => panic!(println!("{} Updating active rounds for an intermediate that doesn't exist: {:?}",
                                             BrightRed.paint("Fatal Internal Error:"), change))
@@ -1100,8 +2502,82 @@ impl IntermediateIndex {
                                       BrightRed.paint("Fatal Internal Error:"), change)) }
         };
         if should_remove {
-            self.index.remove(key);
+            self.index.remove(hash, key);
+            self.remove_prefixes(key);
+        }
+    }
+
+    pub fn stats(&self) -> IndexStats {
+        let mut stats = IndexStats { entries: self.index.len(), ..IndexStats::default() };
+        self.index.stats(&mut stats);
+        for (key, level) in self.index.iter() {
+            stats.buffer(key);
+            match level {
+                &IntermediateLevel::KeyOnly(ref entry) => stats.round_entry(entry),
+                &IntermediateLevel::Value(ref lookup) => {
+                    stats.table(lookup);
+                    for (value, entry) in lookup.iter() {
+                        stats.buffer(value);
+                        stats.round_entry(entry);
+                    }
+                }
+                &IntermediateLevel::SumAggregate(ref rounds) => {
+                    stats.bytes += rounds.len() * size_of::<(Round, AggregateEntry)>();
+                }
+                &IntermediateLevel::SortAggregate(ref rounds, ref entry) => {
+                    stats.buffer(rounds);
+                    if let &AggregateEntry::Sorted { ref items, .. } = entry {
+                        stats.bytes += items.len() * size_of::<(Vec<Internable>, Vec<Count>)>();
+                    }
+                }
+            }
+        }
+        stats.table(&self.rounds);
+        for changes in self.rounds.values() {
+            stats.table(changes);
+        }
+        stats.buffer(&self.filter.bits);
+        stats.table(&self.prefixes);
+        for prefix in self.prefixes.keys() {
+            stats.buffer(prefix);
+        }
+        stats
+    }
+
+    /// Drops what fully retracted keys leave behind. Entries whose counts went back to
+    /// zero in every round without ever going through `update_active_rounds` are removed,
+    /// along with the change tables of rounds that have already been processed, tables
+    /// are shrunk to what's left in them and the key filter is rebuilt so stale keys stop
+    /// getting past it. Aggregates are left alone, since later rounds are computed from
+    /// their history. Keys are only dropped while no changes are pending, so this is meant
+    /// to be run between transactions. Returns how many keys were dropped.
+    pub fn compact(&mut self) -> usize {
+        self.rounds.retain(|_, changes| changes.len() > 0);
+        for changes in self.rounds.values_mut() {
+            changes.shrink_to_fit();
+        }
+        self.rounds.shrink_to_fit();
+        if self.rounds.values().any(|changes| changes.values().any(|change| change.count != 0)) {
+            return 0;
         }
+        // compacting doesn't change what anything holds, but would throw off an undo
+        self.undo.clear();
+        let before = self.index.len();
+        self.index.retain(|_, level| {
+            match level {
+                &mut IntermediateLevel::KeyOnly(ref entry) => !entry.is_retracted(),
+                &mut IntermediateLevel::Value(ref mut lookup) => {
+                    lookup.retain(|_, entry| !entry.is_retracted());
+                    lookup.shrink_to_fit();
+                    lookup.len() > 0
+                }
+                &mut IntermediateLevel::SumAggregate(_) |
+                &mut IntermediateLevel::SortAggregate(..) => true,
+            }
+        });
+        self.index.shrink_to_fit();
+        self.rebuild();
+        before - self.index.len()
     }
 
     pub fn consume_round(&mut self) -> Round {
@@ -1113,7 +2589,13 @@ impl IntermediateIndex {
     pub fn distinct(&mut self, full_key:Vec<Interned>, key:Vec<Interned>, value:Vec<Interned>, round:Round, count:Count, negate:bool) {
         // println!("    -> Intermediate! {:?} {:?} {:?}", full_key, round, count);
         self.max_round = cmp::max(self.max_round, round);
-        intermediate_distinct(&mut self.index, &mut self.rounds, full_key, key, value, round, count, negate);
+        let hash = key_hash(&key);
+        self.track_key(hash);
+        self.save(hash, &key);
+        if !self.index.contains_key(hash, &key) {
+            self.add_prefixes(&key);
+        }
+        intermediate_distinct(&mut self.index, hash, &mut self.rounds, full_key, key, value, round, count, negate);
     }
 
 }
@@ -1227,9 +2709,12 @@ impl CollapsedChanges {
 // Watch Index
 //-------------------------------------------------------------------------
 
+#[derive(Serialize, Deserialize)]
 pub struct WatchIndex {
-    cur: HashMap<Vec<Interned>, Count, MyHasher>,
+    cur: SharedMap<Vec<Interned>, Count>,
     next: HashMap<Vec<Interned>, Count, MyHasher>,
+    #[serde(skip)]
+    undo: Undo<Vec<Interned>, Count>,
 }
 
 #[derive(Debug)]
@@ -1259,23 +2744,56 @@ fn update_watch_count(index:&mut HashMap<Vec<Interned>, Count, MyHasher>, key:Ve
 
 impl WatchIndex {
     pub fn new() -> WatchIndex {
-        WatchIndex { cur: HashMap::default(), next: HashMap::default() }
+        WatchIndex { cur: SharedMap::new(), next: HashMap::default(), undo: Undo::new() }
+    }
+
+    /// A copy to write out while this one keeps changing.
+    pub fn share(&self) -> WatchIndex {
+        WatchIndex { cur: self.cur.share(), next: self.next.clone(), undo: Undo::new() }
+    }
+
+    pub fn save_point(&mut self) {
+        self.undo.clear();
+    }
+
+    pub fn roll_back(&mut self) {
+        for (key, saved) in self.undo.drain() {
+            match saved {
+                Some(count) => { self.next.insert(key, count); }
+                None => { self.next.remove(&key); }
+            }
+        }
     }
 
     pub fn dirty(&self) -> bool {
        self.next.len() > 0
     }
 
+    pub fn stats(&self) -> IndexStats {
+        let mut stats = IndexStats { entries: self.cur.len(), ..IndexStats::default() };
+        stats.shared_table(&self.cur);
+        stats.table(&self.next);
+        for key in self.cur.keys().chain(self.next.keys()) {
+            stats.buffer(key);
+        }
+        stats
+    }
+
     pub fn insert(&mut self, key: Vec<Interned>, count: Count) {
+        self.undo.save(&key, self.next.get(&key));
         update_watch_count(&mut self.next, key, count);
     }
 
+    pub fn rows(&self) -> Vec<Vec<Interned>> {
+        self.cur.keys().cloned().collect()
+    }
+
     pub fn reconcile(&mut self) -> WatchDiff {
         let mut adds = vec![];
         let mut removes = vec![];
         for (k, v) in self.next.drain() {
             let cloned = k.clone();
-            let (prev, neue) = update_watch_count(&mut self.cur, k, v);
+            let (prev, neue) = update_watch_count(self.cur.shard_mut(&k), k, v);
             if prev == 0 && neue > 0 {
                 adds.push(cloned);
             } else if prev > 0 && neue == 0 {
@@ -1,9 +1,3 @@
-#![feature(slice_patterns)]
-#![feature(box_patterns)]
-#![feature(vec_remove_item)]
-#![feature(conservative_impl_trait)]
-#![feature(slice_concat_ext)]
-
 // #[link_args = "-s EXPORTED_FUNCTIONS=['_coolrand','_makeIter','_next']"]
 extern {}
 
@@ -32,12 +26,37 @@ pub mod paths;
 pub mod indexes;
 pub mod compiler;
 pub mod parser;
+pub mod formatter;
 pub mod error;
 pub mod solver;
+pub mod spill;
+pub mod provenance;
+pub mod history;
+pub mod arrangements;
+pub mod admin;
+pub mod delivery;
+pub mod persister;
+pub mod batch;
 
 pub mod numerics;
 
 pub mod watchers;
 
+pub mod check;
+
+pub mod report;
+
+pub mod redact;
+
+pub mod scaffold;
+
+pub mod tutorial;
+
+pub mod lint;
+
+pub mod export;
+
+pub mod bytecode;
+
 #[macro_use]
 pub mod test_util;
@@ -0,0 +1,364 @@
+//-------------------------------------------------------------------------
+// Lint
+//-------------------------------------------------------------------------
+
+// `eve lint` looks for programs that compile fine but probably don't do what was meant.
+// It works on the parsed blocks of every file at once, since whether a tag is read
+// depends on the whole project. Each rule has a default severity that a project can
+// change, or turn off, in an `eve-lint.json` next to it:
+//
+//     {"rules": {"commit-without-search": "off", "unread-tag": "error"}, "ignore_tags": ["debug"]}
+//
+// Tags with a `/` in them, like `#html/div`, belong to watchers and are never reported
+// as unread.
+
+extern crate serde_json;
+
+use compiler::Node;
+use combinators::{ParseResult, ParseState, Span, EMPTY_SPAN};
+use parser::embedded_blocks;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Off,
+    Info,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Severity::Off => write!(f, "off"),
+            &Severity::Info => write!(f, "info"),
+            &Severity::Warning => write!(f, "warning"),
+            &Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+pub struct Rule {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub severity: Severity,
+}
+
+pub const COMMIT_WITHOUT_SEARCH:&'static str = "commit-without-search";
+pub const UNREAD_TAG:&'static str = "unread-tag";
+pub const NON_EXHAUSTIVE_IF:&'static str = "non-exhaustive-if";
+pub const UNUSED_VARIABLE:&'static str = "unused-variable";
+
+pub const RULES:&'static [Rule] = &[
+    Rule {
+        name: COMMIT_WITHOUT_SEARCH,
+        description: "A block commits without searching for anything, so it runs once and its records are never taken back out",
+        severity: Severity::Info,
+    },
+    Rule {
+        name: UNREAD_TAG,
+        description: "A block binds records with a tag that no block searches for",
+        severity: Severity::Warning,
+    },
+    Rule {
+        name: NON_EXHAUSTIVE_IF,
+        description: "An attribute is written from an `if` with no `else`, so the block writes nothing when no branch matches",
+        severity: Severity::Warning,
+    },
+    Rule {
+        name: UNUSED_VARIABLE,
+        description: "A variable is searched for but never used anywhere else in its block",
+        severity: Severity::Warning,
+    },
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LintConfig {
+    /// Severities that replace the rules' defaults.
+    #[serde(default)]
+    pub rules: HashMap<String, Severity>,
+    /// Tags that are read by something other than a block, e.g. a host application.
+    #[serde(default)]
+    pub ignore_tags: Vec<String>,
+}
+
+impl LintConfig {
+    pub fn from_file(path:&str) -> io::Result<LintConfig> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        serde_json::from_str(&contents).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+    }
+
+    pub fn severity(&self, rule:&str) -> Severity {
+        match self.rules.get(rule) {
+            Some(&severity) => severity,
+            None => RULES.iter().find(|cur| cur.name == rule).map_or(Severity::Off, |cur| cur.severity),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub rule: String,
+    pub severity: Severity,
+    pub path: String,
+    /// Where the problem starts, counting lines and columns from 1.
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}: {} [{}] {}", self.path, self.line, self.column, self.severity, self.rule, self.message)
+    }
+}
+
+// Calls `f` with every node under `node`, along with the span of the closest node that
+// has one.
+fn visit<'a, F:FnMut(&Node<'a>, &Span)>(node:&Node<'a>, span:&Span, f:&mut F) {
+    f(node, span);
+    match node {
+        &Node::Pos(ref span, ref sub) => visit(sub, span, f),
+        &Node::Record(_, ref nodes) |
+        &Node::OutputRecord(_, ref nodes, _) |
+        &Node::EmbeddedString(_, ref nodes) |
+        &Node::ExprSet(ref nodes) |
+        &Node::RecordSet(ref nodes) |
+        &Node::BulkUpdate(ref nodes) |
+        &Node::Not(_, ref nodes) |
+        &Node::Lookup(ref nodes, _) |
+        &Node::LookupCommit(ref nodes) |
+        &Node::LookupRemote(ref nodes, _) |
+        &Node::Search(ref nodes) |
+        &Node::Bind(ref nodes) |
+        &Node::Commit(ref nodes) |
+        &Node::Project(ref nodes) |
+        &Node::Watch(_, ref nodes) => {
+            for node in nodes { visit(node, span, f); }
+        }
+        &Node::AttributeEquality(_, ref sub) |
+        &Node::AttributeInequality { right: ref sub, .. } |
+        &Node::Scoped(_, ref sub) => visit(sub, span, f),
+        &Node::Inequality { ref left, ref right, .. } |
+        &Node::Equality { ref left, ref right } |
+        &Node::Infix { ref left, ref right, .. } => {
+            visit(left, span, f);
+            visit(right, span, f);
+        }
+        &Node::RecordUpdate { ref record, ref value, .. } => {
+            visit(record, span, f);
+            visit(value, span, f);
+        }
+        &Node::RecordFunction { ref params, ref outputs, .. } => {
+            for node in params.iter().chain(outputs.iter()) { visit(node, span, f); }
+        }
+        &Node::IfBranch { ref result, ref body, .. } => {
+            for node in body { visit(node, span, f); }
+            visit(result, span, f);
+        }
+        &Node::If { ref outputs, ref branches, .. } => {
+            for node in outputs.iter().flat_map(|outputs| outputs.iter()).chain(branches.iter()) {
+                visit(node, span, f);
+            }
+        }
+        &Node::Block { ref search, ref update, .. } => {
+            if let Some(ref search) = **search { visit(search, span, f); }
+            visit(update, span, f);
+        }
+        _ => {}
+    }
+}
+
+// The tags a search record requires, whether written `#tag` or `tag: "tag"`.
+fn record_tags<'a>(attrs:&[Node<'a>], span:&Span) -> Vec<(&'a str, Span)> {
+    let mut tags = vec![];
+    for attr in attrs {
+        let (local_span, unwrapped) = attr.to_pos_ref(span);
+        match unwrapped {
+            &Node::Tag(tag) => tags.push((tag, local_span.clone())),
+            &Node::TagUnion(ref union, _) => tags.extend(union.iter().map(|tag| (*tag, local_span.clone()))),
+            &Node::AttributeEquality("tag", ref value) => {
+                if let &Node::RawString(tag) = value.unwrap_ref_pos() { tags.push((tag, local_span.clone())); }
+            }
+            _ => {}
+        }
+    }
+    tags
+}
+
+// Sections can be scoped, e.g. `commit @browser`.
+fn unscoped<'b, 'a>(node:&'b Node<'a>) -> &'b Node<'a> {
+    match node.unwrap_ref_pos() {
+        &Node::Scoped(_, ref sub) => unscoped(sub),
+        other => other,
+    }
+}
+
+struct Linter<'c> {
+    config: &'c LintConfig,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'c> Linter<'c> {
+    fn report(&mut self, rule:&str, path:&str, span:&Span, message:String) {
+        let severity = self.config.severity(rule);
+        if severity == Severity::Off { return; }
+        self.diagnostics.push(Diagnostic { rule: rule.to_string(), severity, path: path.to_string(), line: span.start.line + 1, column: span.start.ch + 1, message });
+    }
+
+    fn commit_without_search(&mut self, path:&str, span:&Span, search:&Option<Node>, update:&Node) {
+        if search.is_some() { return; }
+        if let &Node::Commit(_) = unscoped(update) {
+            self.report(COMMIT_WITHOUT_SEARCH, path, span, "This block commits without searching for anything. It runs once and its records stay until something removes them.".to_string());
+        }
+    }
+
+    fn unread_tags(&mut self, path:&str, span:&Span, update:&Node, read:&HashSet<&str>) {
+        match unscoped(update) {
+            &Node::Bind(_) => {}
+            _ => return,
+        }
+        let mut written = vec![];
+        visit(update, span, &mut |node, span| {
+            if let &Node::OutputRecord(_, ref attrs, _) = node {
+                written.extend(record_tags(attrs, span));
+            }
+        });
+        for (tag, span) in written {
+            if tag.contains('/') || read.contains(tag) || self.config.ignore_tags.iter().any(|ignored| ignored == tag) { continue; }
+            self.report(UNREAD_TAG, path, &span, format!("Nothing searches for `#{}`, so the records bound with it aren't used.", tag));
+        }
+    }
+
+    fn non_exhaustive_ifs(&mut self, path:&str, span:&Span, search:&Option<Node>, update:&Node) {
+        let search = match search { &Some(ref search) => search, &None => return };
+        // the variables each `x = if ...` without an `else` provides
+        let mut partial:Vec<&str> = vec![];
+        visit(search, span, &mut |node, _| {
+            if let &Node::If { outputs: Some(ref outputs), ref branches, .. } = node {
+                let exhaustive = branches.iter().any(|branch| match branch.unwrap_ref_pos() {
+                    &Node::IfBranch { exclusive: true, ref body, .. } => body.is_empty(),
+                    _ => false,
+                });
+                if exhaustive { return; }
+                for output in outputs {
+                    if let &Node::Variable(name) = output.unwrap_ref_pos() { partial.push(name); }
+                }
+            }
+        });
+        if partial.is_empty() { return; }
+        let mut written:Vec<(&str, &str, Span)> = vec![];
+        visit(update, span, &mut |node, span| {
+            match node {
+                &Node::OutputRecord(_, ref attrs, _) => {
+                    for attr in attrs {
+                        let (local_span, unwrapped) = attr.to_pos_ref(span);
+                        match unwrapped {
+                            &Node::Attribute(attribute) => written.push((attribute, attribute, local_span.clone())),
+                            &Node::AttributeEquality(attribute, ref value) => {
+                                if let &Node::Variable(variable) = value.unwrap_ref_pos() { written.push((attribute, variable, local_span.clone())); }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                &Node::RecordUpdate { ref record, ref value, .. } => {
+                    if let (&Node::MutatingAttributeAccess(ref items), &Node::Variable(variable)) = (record.unwrap_ref_pos(), value.unwrap_ref_pos()) {
+                        if let Some(&attribute) = items.last() { written.push((attribute, variable, span.clone())); }
+                    }
+                }
+                _ => {}
+            }
+        });
+        for (attribute, variable, span) in written {
+            if partial.contains(&variable) {
+                self.report(NON_EXHAUSTIVE_IF, path, &span, format!("`{}` comes from an `if` with no `else`, so when none of its branches match nothing is written to `{}`. Add an `else` if it should always be set.", variable, attribute));
+            }
+        }
+    }
+}
+
+// The variables a node mentions by name, whether as `x`, `x.name`, or as the shorthand
+// `[name]` that is both an attribute and a variable.
+fn mentioned<'a>(node:&Node<'a>) -> Option<&'a str> {
+    match node {
+        &Node::Variable(name) |
+        &Node::Attribute(name) |
+        &Node::AttributeInequality { attribute: name, .. } => Some(name),
+        &Node::AttributeAccess(ref items) |
+        &Node::MutatingAttributeAccess(ref items) => items.first().cloned(),
+        _ => None,
+    }
+}
+
+impl<'c> Linter<'c> {
+    // Shorthand attributes like `[#person name]` aren't reported, they're a common way to
+    // say a record has an attribute. Variables starting with `_` are left alone too.
+    fn unused_variables(&mut self, path:&str, span:&Span, search:&Option<Node>, update:&Node) {
+        let search = match search { &Some(ref search) => search, &None => return };
+        let mut uses:HashMap<&str, usize> = HashMap::new();
+        let mut searched:Vec<(&str, Span)> = vec![];
+        visit(search, span, &mut |node, span| {
+            if let Some(name) = mentioned(node) { *uses.entry(name).or_insert(0) += 1; }
+            if let &Node::Variable(name) = node { searched.push((name, span.clone())); }
+        });
+        visit(update, span, &mut |node, _| {
+            if let Some(name) = mentioned(node) { *uses.entry(name).or_insert(0) += 1; }
+        });
+        for (name, span) in searched {
+            if name.starts_with("_") || uses.get(name).cloned().unwrap_or(0) > 1 { continue; }
+            self.report(UNUSED_VARIABLE, path, &span, format!("`{}` is searched for but never used. Leave it out, or name it `_{}` if it's there on purpose.", name, name));
+        }
+    }
+}
+
+/// Lints `(path, source)` pairs together, as one project. Blocks that don't parse are
+/// skipped, `eve check` is what reports those.
+pub fn lint_sources(sources:&[(String, String)], config:&LintConfig) -> Vec<Diagnostic> {
+    let mut docs = vec![];
+    for &(ref path, ref source) in sources {
+        let mut state = ParseState::new(source);
+        if let ParseResult::Ok(Node::Doc { blocks, .. }) = embedded_blocks(&mut state, path) {
+            docs.push((path, blocks));
+        }
+    }
+    let mut read = HashSet::new();
+    for &(_, ref blocks) in docs.iter() {
+        for block in blocks {
+            if let &Node::Block { ref search, .. } = block.unwrap_ref_pos() {
+                if let Some(ref search) = **search {
+                    visit(search, &EMPTY_SPAN, &mut |node, span| {
+                        if let &Node::Record(_, ref attrs) = node {
+                            read.extend(record_tags(attrs, span).into_iter().map(|(tag, _)| tag));
+                        }
+                    });
+                }
+            }
+        }
+    }
+    let mut linter = Linter { config, diagnostics: vec![] };
+    for &(path, ref blocks) in docs.iter() {
+        for block in blocks {
+            let (span, unwrapped) = block.to_pos_ref(&EMPTY_SPAN);
+            if let &Node::Block { ref errors, ref search, ref update, .. } = unwrapped {
+                if !errors.is_empty() { continue; }
+                linter.commit_without_search(path, span, search, update);
+                linter.unread_tags(path, span, update, &read);
+                linter.non_exhaustive_ifs(path, span, search, update);
+                linter.unused_variables(path, span, search, update);
+            }
+        }
+    }
+    let mut diagnostics = linter.diagnostics;
+    diagnostics.sort_by(|a, b| (&a.path, a.line, a.column).cmp(&(&b.path, b.line, b.column)));
+    diagnostics
+}
+
+pub fn diagnostics_to_json(diagnostics:&[Diagnostic]) -> String {
+    serde_json::to_string_pretty(diagnostics).unwrap()
+}
@@ -7,6 +7,7 @@
 
 extern crate num;
 use self::num::Float;
+use std::cmp;
 
 const EXTENSION_MASK:u64 = 1 << 63;
 const MANTISSA_MASK:u64 = (((1 as u64) << 49) as u64 - 1); // 49 bits at the end
@@ -257,6 +258,182 @@ impl TaggedMath for Tagged {
     }
 }
 
+//-------------------------------------------------------------------------
+// Decimal
+//-------------------------------------------------------------------------
+
+// A fixed-point decimal, value = mantissa * 10^-scale. Decimals are always
+// kept normalized (no trailing zeros in the mantissa) so that 12.50 and 12.5
+// hash and intern to the same value.
+//
+// A decimal is never the same value as a float, even an integral one: `3d` and `3`
+// intern to different ids, so whatever matches by id, a join, a record lookup or `=`
+// between two variables (which unifies them), only ever matches the same kind of
+// number. Comparing a variable against a constant, e.g. `x = 3` or `x > 2`, goes by
+// numeric value. Keeping them apart is what keeps decimal arithmetic exact, so where
+// a search has to match one against the other, convert it first.
+
+const DECIMAL_MAX_SCALE:u32 = 18;
+pub const DECIMAL_DIVIDE_SCALE:u32 = 12;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Decimal {
+    mantissa: i64,
+    scale: u32,
+}
+
+impl Decimal {
+    pub fn new(mantissa:i64, scale:u32) -> Decimal {
+        Decimal { mantissa, scale }.normalize()
+    }
+
+    pub fn from_str(text:&str) -> Option<Decimal> {
+        let (negative, digits) = if text.starts_with("-") { (true, &text[1..]) } else { (false, text) };
+        let (whole, fraction) = match digits.find('.') {
+            Some(ix) => (&digits[..ix], &digits[ix + 1..]),
+            None => (digits, ""),
+        };
+        if whole.len() == 0 || fraction.len() as u32 > DECIMAL_MAX_SCALE { return None; }
+        let mut mantissa:i64 = 0;
+        for c in whole.chars().chain(fraction.chars()) {
+            let digit = match c.to_digit(10) { Some(d) => d as i64, None => return None };
+            mantissa = match mantissa.checked_mul(10).and_then(|m| m.checked_add(digit)) {
+                Some(m) => m,
+                None => return None,
+            };
+        }
+        if negative { mantissa = -mantissa; }
+        Some(Decimal::new(mantissa, fraction.len() as u32))
+    }
+
+    pub fn from_f32(num:f32) -> Option<Decimal> {
+        // go through the shortest decimal representation of the float so that
+        // 0.1 becomes 0.1 and not 0.100000001490116...
+        Decimal::from_str(&num.to_string())
+    }
+
+    pub fn mantissa(&self) -> i64 {
+        self.mantissa
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mantissa == 0
+    }
+
+    fn normalize(mut self) -> Decimal {
+        if self.mantissa == 0 {
+            self.scale = 0;
+        }
+        while self.scale > 0 && self.mantissa % 10 == 0 {
+            self.mantissa /= 10;
+            self.scale -= 1;
+        }
+        self
+    }
+
+    fn rescale(&self, scale:u32) -> Option<i64> {
+        debug_assert!(scale >= self.scale);
+        pow10(scale - self.scale).and_then(|factor| self.mantissa.checked_mul(factor))
+    }
+
+    fn align(self, other:Decimal) -> Option<(i64, i64, u32)> {
+        let scale = cmp::max(self.scale, other.scale);
+        match (self.rescale(scale), other.rescale(scale)) {
+            (Some(a), Some(b)) => Some((a, b, scale)),
+            _ => None,
+        }
+    }
+
+    pub fn negate(self) -> Decimal {
+        Decimal { mantissa: -self.mantissa, scale: self.scale }
+    }
+
+    pub fn add(self, other:Decimal) -> Option<Decimal> {
+        let (a, b, scale) = match self.align(other) { Some(aligned) => aligned, None => return None };
+        a.checked_add(b).map(|mantissa| Decimal::new(mantissa, scale))
+    }
+
+    pub fn sub(self, other:Decimal) -> Option<Decimal> {
+        self.add(other.negate())
+    }
+
+    pub fn multiply(self, other:Decimal) -> Option<Decimal> {
+        let mantissa = match self.mantissa.checked_mul(other.mantissa) { Some(m) => m, None => return None };
+        let scale = self.scale + other.scale;
+        if scale > DECIMAL_MAX_SCALE {
+            let factor = 10i64.pow(scale - DECIMAL_MAX_SCALE);
+            Some(Decimal::new(round_div(mantissa, factor), DECIMAL_MAX_SCALE))
+        } else {
+            Some(Decimal::new(mantissa, scale))
+        }
+    }
+
+    pub fn divide(self, other:Decimal) -> Option<Decimal> {
+        if other.is_zero() { return None; }
+        // a / b = (ma * 10^(sb + S - sa)) / mb at scale S
+        let scale = cmp::max(DECIMAL_DIVIDE_SCALE, self.scale);
+        let numerator = pow10(other.scale + scale - self.scale)
+                             .and_then(|factor| self.mantissa.checked_mul(factor));
+        numerator.map(|numerator| Decimal::new(round_div(numerator, other.mantissa), scale))
+    }
+
+    pub fn to_float(&self) -> f64 {
+        (self.mantissa as f64) / 10f64.powi(self.scale as i32)
+    }
+
+    pub fn to_string(&self) -> String {
+        let digits = self.mantissa.abs().to_string();
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return format!("{}{}", sign, digits);
+        }
+        let padded = format!("{:0>width$}", digits, width = scale + 1);
+        let split = padded.len() - scale;
+        format!("{}{}.{}", sign, &padded[..split], &padded[split..])
+    }
+}
+
+fn pow10(exp:u32) -> Option<i64> {
+    let mut result:i64 = 1;
+    for _ in 0..exp {
+        result = match result.checked_mul(10) { Some(r) => r, None => return None };
+    }
+    Some(result)
+}
+
+// Integer division rounding half away from zero
+fn round_div(numerator:i64, denominator:i64) -> i64 {
+    let quotient = numerator / denominator;
+    let remainder = numerator % denominator;
+    if remainder.abs() * 2 >= denominator.abs() {
+        if (numerator < 0) == (denominator < 0) { quotient + 1 } else { quotient - 1 }
+    } else {
+        quotient
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, rhs:&Decimal) -> Option<cmp::Ordering> {
+        Some(self.cmp(rhs))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, rhs:&Decimal) -> cmp::Ordering {
+        match self.align(*rhs) {
+            Some((a, b, _)) => a.cmp(&b),
+            // the values are too far apart in scale to line up exactly, in which
+            // case the float comparison can't be wrong about their order
+            None => self.to_float().partial_cmp(&rhs.to_float()).unwrap(),
+        }
+    }
+}
+
 #[test]
 fn numerics_base() {
     let x = make_tagged(1, 3, 1);
@@ -314,6 +491,37 @@ fn numerics_base_float() {
 }
 
 
+#[test]
+fn numerics_decimal_parse() {
+    let x = Decimal::from_str("12.50").unwrap();
+    assert_eq!(x, Decimal::new(125, 1));
+    assert_eq!(x.to_string(), "12.5");
+    assert_eq!(Decimal::from_str("-0.05").unwrap().to_string(), "-0.05");
+    assert_eq!(Decimal::from_str("3").unwrap().to_string(), "3");
+    assert_eq!(Decimal::from_str("1.x"), None);
+}
+
+#[test]
+fn numerics_decimal_exact() {
+    let tenth = Decimal::from_str("0.1").unwrap();
+    let two_tenths = Decimal::from_str("0.2").unwrap();
+    assert_eq!(tenth.add(two_tenths).unwrap(), Decimal::from_str("0.3").unwrap());
+    assert_eq!(tenth.sub(two_tenths).unwrap().to_string(), "-0.1");
+    assert_eq!(Decimal::from_str("19.99").unwrap().multiply(Decimal::new(3, 0)).unwrap().to_string(), "59.97");
+    assert_eq!(Decimal::new(10, 0).divide(Decimal::new(4, 0)).unwrap().to_string(), "2.5");
+    assert_eq!(Decimal::new(2, 0).divide(Decimal::new(3, 0)).unwrap().to_string(), "0.666666666667");
+    assert_eq!(Decimal::new(1, 0).divide(Decimal::new(0, 0)), None);
+}
+
+#[test]
+fn numerics_decimal_compare() {
+    let a = Decimal::from_str("1.05").unwrap();
+    let b = Decimal::from_str("1.5").unwrap();
+    assert!(a < b);
+    assert!(b.negate() < a.negate());
+    assert_eq!(Decimal::from_str("2.000").unwrap().cmp(&Decimal::new(2, 0)), cmp::Ordering::Equal);
+}
+
 
 // extern crate test;
 // use self::test::{Bencher};
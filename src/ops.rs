@@ -7,38 +7,57 @@ extern crate serde_json;
 extern crate bincode;
 extern crate term_painter;
 extern crate natord;
+extern crate fnv;
+extern crate scraper;
 
 use unicode_segmentation::UnicodeSegmentation;
 
-use indexes::{HashIndex, DistinctIter, DistinctIndex, WatchIndex, IntermediateIndex, MyHasher, AggregateEntry,
-              CollapsedChanges, RemoteIndex, RemoteChange, RawRemoteChange};
-use solver::Solver;
-use compiler::{make_block, parse_file, FunctionKind, Node};
-use std::collections::{HashMap, HashSet, Bound, BTreeMap};
+use self::fnv::FnvHasher;
+use indexes::{HashIndex, DistinctIter, DistinctIndex, BlockDistinct, WatchIndex, WatchDiff, IntermediateIndex, MyHasher, AggregateEntry,
+              CollapsedChanges, RemoteIndex, RemoteChange, RawRemoteChange, IndexStats};
+use solver::{Solver, PartitionOutputs};
+use spill::Spill;
+use provenance::{Provenance, Explanation, ExplainedDerivation, WhyNot, order_by_inputs, renumber_registers, describe_constraint};
+use history::{History, HistoryEntry};
+use arrangements::{Arrangements, Arrangement, order_by_intermediates, written_intermediates};
+use admin::{AdminCommand, AdminBlock, AdminStats, AdminReply};
+use delivery::DeliveryLog;
+use persister::{Persister, PersisterMessage, ProgramView, Checkpoint, ResumePoint, write_snapshot, block_fingerprint, same_path};
+use bytecode::{is_compiled_file, load_compiled_file};
+use redact::Redaction;
+use compiler::{make_block, parse_file_with, parse_string_with, query_objective, CompileOptions, CustomFunctions, order_scans, FunctionKind, FunctionInfo, Node};
+use std::collections::{HashMap, HashSet, Bound, BTreeMap, VecDeque};
 use std::mem::transmute;
+use std::cell::RefCell;
 use std::cmp::{self, Eq, PartialOrd};
 use std::collections::hash_map::{DefaultHasher, Entry};
-use std::hash::{Hash, Hasher};
+use std::hash::{Hash, Hasher, BuildHasher};
 use std::iter::{Iterator, FromIterator};
 use std::fmt;
-use watchers::{Watcher};
-use std::sync::mpsc::{Sender, Receiver, SendError};
+use watchers::{Watcher, WatcherErrors};
+use watchers::input::{Input, InputConfig};
+use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
-use serde::ser::{Serialize, Serializer};
-use serde::de::{Deserialize, Deserializer, Visitor};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use serde::ser::{Serialize, Serializer, SerializeMap, SerializeSeq};
+use serde::de::{Deserialize, Deserializer, Visitor, MapAccess, Error as DeError};
 use std::error::Error;
 use std::thread::{self, JoinHandle};
-use std::io::{Write, BufReader, BufWriter};
-use std::fs::{OpenOptions, File, canonicalize};
+use std::io;
 use std::path::{Path, PathBuf};
 use std::f32::consts::{PI};
 use std::mem;
+use std::panic;
 use std::usize;
+use std::time::{Duration, Instant};
 use rand::{Rng, SeedableRng, XorShiftRng};
 use self::term_painter::ToStyle;
 use self::term_painter::Color::*;
+use self::scraper::{Html, Selector};
 use parser;
 use combinators::{ParseState, ParseResult};
+use numerics::Decimal;
 
 
 //-------------------------------------------------------------------------
@@ -54,26 +73,94 @@ pub type Count = i32;
 // as that is used specifically throughout the code to do filtering and the
 // like.
 pub const TAG_INTERNED_ID:Interned = 1;
+// Likewise for `attribute` in the @fulltext scope, so the index can spot an attribute
+// being declared `@fulltext` as the fact goes in.
+pub const FULLTEXT_INTERNED_ID:Interned = 2;
 
 //-------------------------------------------------------------------------
 // Utils
 //-------------------------------------------------------------------------
 
 pub fn format_interned(interner:&Interner, v:Interned) -> String {
-    let v_str = interner.get_value(v).print();
-    if v_str.contains("|") {
-        format!("<{}>", v)
-    } else {
-        v_str
+    match interner.get_value(v) {
+        &Internable::Reference(_) => format!("<{}>", v),
+        value => value.print(),
     }
 }
 
-pub fn print_block_constraints(block:&Block) {
-    println!("\n----------- Constraints ------------[{}] \n", block.name);
+pub fn block_constraints_string(block:&Block) -> String {
+    let mut result = format!("\n----------- Constraints ------------[{}] \n\n", block.name);
     for constraint in block.constraints.iter() {
-        println!("  {:?}", constraint);
+        result.push_str(&format!("  {:?}\n", constraint));
+    }
+    result
+}
+
+//-------------------------------------------------------------------------
+// Tracing
+//-------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DebugMode {
+    Parse,
+    Unify,
+    Compile,
+    Runtime,
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceEntry {
+    pub mode: DebugMode,
+    pub message: String,
+}
+
+/// Collects the debugging output of the parser, compiler and run loop. Nothing
+/// is traced until a mode is enabled; traced messages are printed unless the
+/// tracer has been asked to capture them instead.
+pub struct Tracer {
+    captured: Option<Vec<TraceEntry>>,
+}
+
+// One bit per DebugMode, so that tracing that's turned off never takes the lock.
+static TRACE_ENABLED:AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref TRACER: Mutex<Tracer> = Mutex::new(Tracer { captured: None });
+}
+
+impl Tracer {
+    pub fn enable(mode:DebugMode) {
+        TRACE_ENABLED.fetch_or(1 << mode as usize, Ordering::SeqCst);
+    }
+
+    pub fn disable(mode:DebugMode) {
+        TRACE_ENABLED.fetch_and(!(1 << mode as usize), Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(mode:DebugMode) -> bool {
+        TRACE_ENABLED.load(Ordering::Relaxed) & (1 << mode as usize) != 0
+    }
+
+    pub fn capture() {
+        let mut tracer = TRACER.lock().unwrap();
+        if tracer.captured.is_none() {
+            tracer.captured = Some(vec![]);
+        }
+    }
+
+    /// Returns everything captured so far and goes back to printing.
+    pub fn take() -> Vec<TraceEntry> {
+        TRACER.lock().unwrap().captured.take().unwrap_or(vec![])
+    }
+}
+
+pub fn trace<F: FnOnce() -> String>(mode:DebugMode, message:F) {
+    if !Tracer::is_enabled(mode) { return; }
+    let message = message();
+    match TRACER.lock().unwrap().captured {
+        Some(ref mut captured) => captured.push(TraceEntry { mode, message }),
+        None => println!("{}", message),
     }
-    println!("");
 }
 
 pub fn s(string: &str) -> Internable {
@@ -94,7 +181,7 @@ pub enum ChangeType {
     Remove,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Change {
     pub e: Interned,
     pub a: Interned,
@@ -111,8 +198,7 @@ impl Change {
     }
     pub fn print(&self, interner:&Interner) -> String {
         let a = interner.get_value(self.a).print();
-        let mut v = interner.get_value(self.v).print();
-        v = if v.contains("|") { format!("<{}>", self.v) } else { v };
+        let v = format_interned(interner, self.v);
         format!("Change (<{}>, {:?}, {})  {}:{}:{}", self.e, a, v, self.transaction, self.round, self.count)
     }
 
@@ -163,7 +249,7 @@ impl Ord for RawChange {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IntermediateChange {
     pub key: Vec<Interned>,
     pub count: Count,
@@ -183,6 +269,18 @@ pub enum PipeShape {
     Remote(Interned),
 }
 
+/// What the `eve:block` annotation above a block says about it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockMetadata {
+    /// The block is registered under this name instead of `path|block|N`.
+    pub name: Option<String>,
+    /// The block is loaded but doesn't run until it's enabled by name.
+    pub disabled: bool,
+    /// Rows that bind the same EAV are collapsed, so the block supports each of its
+    /// outputs once however many ways it derives them.
+    pub distinct: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct Block {
     pub name: String,
@@ -190,13 +288,14 @@ pub struct Block {
     pub path: String,
     pub constraints: Vec<Constraint>,
     pub solver: Option<Solver>,
-    pub shapes: Vec<Vec<PipeShape>>
+    pub shapes: Vec<Vec<PipeShape>>,
+    pub metadata: BlockMetadata,
 }
 
 impl Block {
 
     pub fn new(interner:&mut Interner, name:&str, block_id:Interned, constraints:Vec<Constraint>) -> Block {
-        let mut me = Block { name:name.to_string(), block_id, path: "".to_owned(), constraints, solver:None, shapes: vec![] };
+        let mut me = Block { name:name.to_string(), block_id, path: "".to_owned(), constraints, solver:None, shapes: vec![], metadata: BlockMetadata::default() };
         let shapes = me.to_shapes();
         me.shapes.extend(shapes);
         me.solver = Some(Solver::new(interner, block_id, 0, None, &me.constraints));
@@ -207,6 +306,7 @@ impl Block {
         self.constraints.iter().filter(|constraint| {
             match constraint {
                 &&Constraint::Scan {..} => true,
+                &&Constraint::RangeScan {..} => true,
                 &&Constraint::LookupCommit {..} => true,
                 &&Constraint::LookupRemote {..} => true,
                 &&Constraint::AntiScan {..} => true,
@@ -229,6 +329,41 @@ impl Block {
         solvers
     }
 
+    pub fn reads(&self) -> Vec<BlockKey> {
+        let mut keys = vec![];
+        for constraint in self.constraints.iter() {
+            match constraint {
+                &Constraint::Scan {ref a, ref v, ..} |
+                &Constraint::RangeScan {ref a, ref v, ..} |
+                &Constraint::LookupCommit {ref a, ref v, ..} => { keys.push(BlockKey::Fact(field_key(a), field_key(v))); }
+                &Constraint::AntiScan {ref key, ..} |
+                &Constraint::IntermediateScan {ref key, ..} => { keys.push(BlockKey::Intermediate(field_key(&key[0]))); }
+                _ => {}
+            }
+        }
+        keys
+    }
+
+    pub fn writes(&self) -> Vec<BlockKey> {
+        let mut keys = vec![];
+        for constraint in self.constraints.iter() {
+            match constraint {
+                &Constraint::Insert {ref a, ref v, ..} |
+                &Constraint::Remove {ref a, ref v, ..} |
+                &Constraint::DynamicCommit {ref a, ref v, ..} => { keys.push(BlockKey::Fact(field_key(a), field_key(v))); }
+                &Constraint::RemoveAttribute {ref a, ..} => { keys.push(BlockKey::Fact(field_key(a), 0)); }
+                &Constraint::RemoveEntity {..} => { keys.push(BlockKey::Fact(0, 0)); }
+                &Constraint::InsertIntermediate {ref key, ..} => { keys.push(BlockKey::Intermediate(field_key(&key[0]))); }
+                &Constraint::Aggregate {ref group, ref output_key, ..} => {
+                    keys.push(BlockKey::Intermediate(field_key(&group[0])));
+                    keys.push(BlockKey::Intermediate(field_key(&output_key[0])));
+                }
+                _ => {}
+            }
+        }
+        keys
+    }
+
     pub fn to_shapes(&self) -> Vec<Vec<PipeShape>> {
         let scans = self.get_block_scans();
         let mut shapes = vec![];
@@ -238,6 +373,7 @@ impl Block {
         for scan in scans.iter() {
             match scan {
                 &&Constraint::Scan {ref e, ref a, ref v, ..} |
+                &&Constraint::RangeScan {ref e, ref a, ref v, ..} |
                 &&Constraint::LookupCommit { ref e, ref a, ref v, ..} => {
                     let actual_a = if let &Field::Value(val) = a { val } else { 0 };
                     let actual_v = if let &Field::Value(val) = v { val } else { 0 };
@@ -255,6 +391,7 @@ impl Block {
             let mut scan_shapes = vec![];
             match scan {
                 &&Constraint::Scan {ref e, ref a, ref v, ..} |
+                &&Constraint::RangeScan {ref e, ref a, ref v, ..} |
                 &&Constraint::LookupCommit { ref e, ref a, ref v, ..} => {
                     let actual_e = if let &Field::Value(val) = e { val } else { 0 };
                     let actual_a = if let &Field::Value(val) = a { val } else { 0 };
@@ -309,6 +446,7 @@ impl Block {
 impl PartialEq for Block {
     fn eq(&self, other:&Self) -> bool {
         if self.constraints.len() != other.constraints.len() { return false; }
+        if self.metadata != other.metadata { return false; }
         let my_constraints:HashSet<Constraint> = HashSet::from_iter(self.constraints.iter().cloned());
         let other_constraints:HashSet<Constraint> = HashSet::from_iter(other.constraints.iter().cloned());
         my_constraints == other_constraints
@@ -529,6 +667,339 @@ impl fmt::Debug for OutputingIter {
 }
 
 
+//-------------------------------------------------------------------------
+// Query budgets
+//-------------------------------------------------------------------------
+
+/// Limits on how long an ad-hoc query may run and how many rows it may produce. A query
+/// that hits either stops where it is and hands back what it found so far.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueryBudget {
+    pub time: Option<Duration>,
+    pub rows: Option<usize>,
+}
+
+impl QueryBudget {
+    pub fn unlimited() -> QueryBudget {
+        QueryBudget { time: None, rows: None }
+    }
+
+    pub fn time(mut self, time:Duration) -> QueryBudget {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn rows(mut self, rows:usize) -> QueryBudget {
+        self.rows = Some(rows);
+        self
+    }
+}
+
+// Reading the clock costs more than a step of most joins, so the deadline is only
+// checked every this many steps.
+const BUDGET_CLOCK_INTERVAL:u32 = 256;
+
+struct BudgetState {
+    deadline: Option<Instant>,
+    max_rows: Option<usize>,
+    rows: usize,
+    steps: u32,
+}
+
+#[derive(Debug)]
+pub struct QueryResult {
+    pub rows: Vec<Vec<Internable>>,
+    /// False if the budget ran out before the query finished.
+    pub complete: bool,
+}
+
+//-------------------------------------------------------------------------
+// Objectives
+//-------------------------------------------------------------------------
+
+// Rostering and packing problems are a search over every candidate assignment, one row
+// each, plus a cost to rank them by. Rather than picking the winner with a chain of ifs,
+// the search states `minimize total-cost` and only the best rows come out of it.
+// `Program::solve` runs such a search as a query and then proves what it found: it
+// searches again for anything strictly better, with the optimum as a bound that cuts off
+// any candidate as soon as its cost is known, and that coming back empty is the proof.
+
+#[derive(Debug)]
+pub struct Solution {
+    /// The best row, or `None` if the search found nothing.
+    pub row: Option<Vec<Internable>>,
+    /// The objective's value in `row`.
+    pub optimum: Option<Internable>,
+    /// True once the search for anything better than `optimum` has finished empty. When
+    /// the budget runs out first, `row` is only the best of the candidates that were seen.
+    pub optimal: bool,
+}
+
+//-------------------------------------------------------------------------
+// Prepared queries
+//-------------------------------------------------------------------------
+
+// A `$name` placeholder compiles to this reference, which no string the query could
+// contain ever equals. Each run swaps the bound values in for them.
+pub fn query_param(name:&str) -> Internable {
+    Internable::Reference(format!("query/param|{}|", name))
+}
+
+pub fn query_param_name(value:&Internable) -> Option<&str> {
+    match value {
+        &Internable::Reference(ref id) if id.starts_with("query/param|") && id.len() > "query/param|".len() => Some(&id["query/param|".len()..id.len() - 1]),
+        _ => None,
+    }
+}
+
+// The placeholders in a compiled block, in the order its constraints first use them.
+fn query_params(interner:&Interner, constraints:&Vec<Constraint>) -> Vec<(String, Interned)> {
+    let mut params:Vec<(String, Interned)> = vec![];
+    for constraint in constraints.clone().iter_mut() {
+        for field in constraint.fields_mut() {
+            if let Field::Value(value) = *field {
+                if let Some(name) = query_param_name(interner.get_value(value)) {
+                    if !params.iter().any(|&(_, param)| param == value) { params.push((name.to_string(), value)); }
+                }
+            }
+        }
+    }
+    params
+}
+
+// How many differently bound copies of a prepared query keep their solver around.
+const PREPARED_INSTANCES:usize = 64;
+
+/// A query compiled by `Program::prepare`. Running it only swaps the bound values in for
+/// the placeholders; nothing is parsed or compiled again, and running it with values it
+/// has seen before reuses the solver built for them.
+pub struct PreparedQuery {
+    block: Block,
+    params: Vec<(String, Interned)>,
+    instances: RefCell<HashMap<Vec<Interned>, Block>>,
+}
+
+impl PreparedQuery {
+    fn new(block:Block, params:Vec<(String, Interned)>) -> PreparedQuery {
+        PreparedQuery { block, params, instances: RefCell::new(HashMap::new()) }
+    }
+
+    pub fn params(&self) -> Vec<&str> {
+        self.params.iter().map(|&(ref name, _)| &name[..]).collect()
+    }
+
+    // The value bound to each placeholder, in the order of `params`.
+    fn bind(&self, program:&mut Program, bindings:&HashMap<String, Internable>) -> Result<Vec<Interned>, String> {
+        self.params.iter().map(|&(ref name, _)| {
+            match bindings.get(name) {
+                Some(value) => Ok(program.state.interner.internable_to_id(value.clone())),
+                None => Err(format!("No value bound for `${}`", name)),
+            }
+        }).collect()
+    }
+
+    fn instantiate(&self, program:&mut Program, bound:&Vec<Interned>) -> Block {
+        let lookup:HashMap<Interned, Interned> = self.params.iter().map(|&(_, placeholder)| placeholder).zip(bound.iter().cloned()).collect();
+        let mut constraints = self.block.constraints.clone();
+        for constraint in constraints.iter_mut() {
+            for field in constraint.fields_mut() {
+                if let Field::Value(value) = *field {
+                    if let Some(&bound) = lookup.get(&value) {
+                        *field = Field::Value(bound);
+                    }
+                }
+            }
+        }
+        Block::new(&mut program.state.interner, &self.block.name, self.block.block_id, constraints)
+    }
+
+    /// Runs the query against `program` with each placeholder bound to the value of the
+    /// same name in `bindings`.
+    pub fn exec(&self, program:&mut Program, bindings:&HashMap<String, Internable>, budget:QueryBudget) -> Result<QueryResult, String> {
+        let bound = self.bind(program, bindings)?;
+        let mut instances = self.instances.borrow_mut();
+        if !instances.contains_key(&bound) {
+            if instances.len() >= PREPARED_INSTANCES { instances.clear(); }
+            let block = self.instantiate(program, &bound);
+            instances.insert(bound.clone(), block);
+        }
+        Ok(program.run_query(&instances[&bound], vec![], budget))
+    }
+
+    /// Keeps the query running inside `program`. The subscription starts out with the
+    /// current results, and from then on every transaction that changes them sends the
+    /// rows it added and removed. The query is maintained incrementally like any other
+    /// block, so a transaction that doesn't touch what it reads costs it nothing.
+    pub fn subscribe(&self, program:&mut Program, bindings:&HashMap<String, Internable>) -> Result<Subscription, String> {
+        let bound = self.bind(program, bindings)?;
+        let instantiated = self.instantiate(program, &bound);
+        let name = (0..).map(|ix| format!("query/subscription|{}", ix))
+                        .find(|name| !program.watchers.contains_key(name)).unwrap();
+        let constraints = instantiated.constraints.into_iter().map(|constraint| {
+            match constraint {
+                Constraint::Project { registers } => {
+                    Constraint::Watch { name: name.to_string(), registers: registers.into_iter().map(Field::Register).collect() }
+                }
+                other => other,
+            }
+        }).collect();
+        let block_id = program.state.interner.string_id(&name);
+        let block = Block::new(&mut program.state.interner, &name, block_id, constraints);
+
+        let (sender, receiver) = mpsc::channel();
+        program.attach(Box::new(SubscriptionWatcher { name: name.to_string(), sender }));
+        let mut txn = CodeTransaction::new();
+        txn.exec(program, vec![block], vec![]);
+        let rows = receiver.try_iter().flat_map(|diff:QueryDiff| diff.adds.into_iter()).collect();
+        Ok(Subscription { name, rows, receiver, dropped: Some(program.dropped_subscriptions.clone()) })
+    }
+}
+
+/// The rows a transaction added to and removed from a subscribed query's results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryDiff {
+    pub adds: Vec<Vec<Internable>>,
+    pub removes: Vec<Vec<Internable>>,
+}
+
+pub struct Subscription {
+    name: String,
+    /// The results at the time of subscribing.
+    pub rows: Vec<Vec<Internable>>,
+    receiver: Receiver<QueryDiff>,
+    dropped: Option<Arc<Mutex<Vec<String>>>>,
+}
+
+// A subscription that's dropped rather than passed to `Program::unsubscribe` leaves its
+// name behind, and the program removes it before its next transaction.
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(dropped) = self.dropped.take() {
+            if let Ok(mut names) = dropped.lock() { names.push(self.name.to_string()); }
+        }
+    }
+}
+
+impl Subscription {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Every change to the results since the last call, one diff per transaction.
+    pub fn changes(&self) -> Vec<QueryDiff> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+struct SubscriptionWatcher {
+    name: String,
+    sender: Sender<QueryDiff>,
+}
+
+impl Watcher for SubscriptionWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff) {
+        let to_row = |row:Vec<Interned>| -> Vec<Internable> { row.into_iter().map(|value| interner.get_value(value).clone()).collect() };
+        let diff = QueryDiff { adds: diff.adds.into_iter().map(&to_row).collect(), removes: diff.removes.into_iter().map(&to_row).collect() };
+        // the subscription may have been dropped without unsubscribing
+        self.sender.send(diff).ok();
+    }
+}
+
+//-------------------------------------------------------------------------
+// Views
+//-------------------------------------------------------------------------
+
+/// Converts one value of a view's rows, see `View::typed`.
+pub trait FromValue: Sized {
+    fn from_value(value:&Value) -> Option<Self>;
+}
+
+impl FromValue for Value {
+    fn from_value(value:&Value) -> Option<Value> { Some(value.clone()) }
+}
+
+impl FromValue for String {
+    fn from_value(value:&Value) -> Option<String> {
+        match value {
+            &Value::String(ref string) => Some(string.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value:&Value) -> Option<f64> {
+        match value {
+            &Value::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+}
+
+/// Converts a whole row of a view, see `View::typed`.
+pub trait FromRow: Sized {
+    fn from_row(row:&[Value]) -> Option<Self>;
+}
+
+macro_rules! tuple_from_row {
+    ($len:expr, $($name:ident $ix:expr),*) => {
+        impl<$($name:FromValue),*> FromRow for ($($name,)*) {
+            fn from_row(row:&[Value]) -> Option<Self> {
+                if row.len() != $len { return None; }
+                Some(($($name::from_value(&row[$ix])?,)*))
+            }
+        }
+    }
+}
+
+tuple_from_row!(1, A 0);
+tuple_from_row!(2, A 0, B 1);
+tuple_from_row!(3, A 0, B 1, C 2);
+tuple_from_row!(4, A 0, B 1, C 2, D 3);
+
+/// The standing results of a named block's `project` section, from `Program::view`. The
+/// engine keeps the query up to date incrementally as transactions come in, and the view
+/// only folds those changes into its rows when they're read.
+pub struct View {
+    subscription: Subscription,
+    rows: Vec<Vec<Internable>>,
+}
+
+impl View {
+    pub fn name(&self) -> &str {
+        self.subscription.name()
+    }
+
+    fn refresh(&mut self) {
+        for diff in self.subscription.changes() {
+            for removed in diff.removes {
+                if let Some(ix) = self.rows.iter().position(|row| *row == removed) {
+                    self.rows.swap_remove(ix);
+                }
+            }
+            self.rows.extend(diff.adds);
+        }
+    }
+
+    /// The current results, in no particular order.
+    pub fn rows(&mut self) -> Vec<Vec<Value>> {
+        self.refresh();
+        self.rows.iter().map(|row| row.iter().map(Value::from_internable).collect()).collect()
+    }
+
+    /// The current results as `T`s, usually tuples like `(String, f64)`. Rows that don't
+    /// fit `T` are skipped.
+    pub fn typed<T:FromRow>(&mut self) -> Vec<T> {
+        self.rows().iter().filter_map(|row| T::from_row(row)).collect()
+    }
+}
+
 //-------------------------------------------------------------------------
 // Frame
 //-------------------------------------------------------------------------
@@ -571,11 +1042,67 @@ pub struct Frame {
     pub results: Vec<Interned>,
     #[allow(dead_code)]
     pub counters: Counters,
+    budget: Option<BudgetState>,
+    pub cancelled: bool,
+    /// Join steps taken per constraint of the block being run.
+    pub steps: Vec<u64>,
+    /// The last intermediate prefix probed, shared by the sub-block scans that start with
+    /// the same inputs.
+    pub prefix_probe: Option<PrefixProbe>,
+}
+
+pub struct PrefixProbe {
+    pub generation: u64,
+    pub values: Vec<Interned>,
+    pub present: bool,
 }
 
 impl Frame {
     pub fn new() -> Frame {
-        Frame {row: Row::new(64), block_ix:0, input: None, intermediate: None, remote: None, results: vec![], counters: Counters {iter_next: 0, accept: 0, accept_bail: 0, inserts: 0, instructions: 0, accept_ns: 0, total_ns: 0, considered: 0}}
+        Frame {row: Row::new(64), block_ix:0, input: None, intermediate: None, remote: None, results: vec![], counters: Counters {iter_next: 0, accept: 0, accept_bail: 0, inserts: 0, instructions: 0, accept_ns: 0, total_ns: 0, considered: 0}, budget: None, cancelled: false, steps: vec![], prefix_probe: None}
+    }
+
+    pub fn with_budget(budget:QueryBudget) -> Frame {
+        let mut frame = Frame::new();
+        frame.budget = Some(BudgetState { deadline: budget.time.map(|time| Instant::now() + time), max_rows: budget.rows, rows: 0, steps: 0 });
+        frame
+    }
+
+    /// Called by the solver once per step; true once the frame's budget has run out and
+    /// the join should unwind.
+    #[inline]
+    pub fn over_budget(&mut self) -> bool {
+        if self.cancelled { return true; }
+        if let Some(ref mut budget) = self.budget {
+            // a query that produces exactly its row limit and then finishes is complete,
+            // so the limit only cancels once there's more work to do
+            if let Some(max_rows) = budget.max_rows {
+                if budget.rows >= max_rows { self.cancelled = true; }
+            }
+            budget.steps = budget.steps.wrapping_add(1);
+            if budget.steps % BUDGET_CLOCK_INTERVAL == 0 {
+                if let Some(deadline) = budget.deadline {
+                    if Instant::now() >= deadline { self.cancelled = true; }
+                }
+            }
+        }
+        self.cancelled
+    }
+
+    #[inline]
+    pub fn count_step(&mut self, constraint:usize) {
+        if self.steps.len() <= constraint { self.steps.resize(constraint + 1, 0); }
+        self.steps[constraint] += 1;
+    }
+
+    pub fn produced_rows(&self) -> usize {
+        self.budget.as_ref().map_or(0, |budget| budget.rows)
+    }
+
+    pub fn count_row(&mut self) {
+        if let Some(ref mut budget) = self.budget {
+            budget.rows += 1;
+        }
     }
 
     pub fn get_register(&self, register:usize) -> Interned {
@@ -601,7 +1128,7 @@ impl Frame {
 // Field
 //-------------------------------------------------------------------------
 
-#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone)]
+#[derive(Debug, Eq, PartialEq, Hash, Copy, Clone, Serialize, Deserialize)]
 pub enum Field {
     Register(usize),
     Value(Interned),
@@ -653,6 +1180,13 @@ pub enum Internable {
     Null,
     String(String),
     Number(u32),
+    Decimal(Decimal),
+    // Record ids made by gen_id. These are kept apart from strings so that a
+    // string that happens to spell out an id is never the same value as the record.
+    Reference(String),
+    // An attribute in a scope, e.g. `name` as committed by `commit @session`. It's
+    // never the same value as the plain attribute or any string.
+    Scoped(String, String),
 }
 
 impl PartialOrd for Internable {
@@ -666,11 +1200,20 @@ impl PartialOrd for Internable {
         match (self, rhs) {
             (&Internable::Null, &Internable::Null) => { Some(cmp::Ordering::Equal) },
             (&Internable::String(ref s), &Internable::String(ref s2)) => { Some(natord::compare(s, s2)) },
+            (&Internable::Reference(ref s), &Internable::Reference(ref s2)) => { Some(s.cmp(s2)) },
+            (&Internable::Scoped(ref scope, ref a), &Internable::Scoped(ref scope2, ref a2)) => { Some((scope, a).cmp(&(scope2, a2))) },
             (&Internable::Number(n), &Internable::Number(n2)) => {
                 let value = unsafe {transmute::<u32, f32>(n) };
                 let value2 = unsafe {transmute::<u32, f32>(n2) };
                 value.partial_cmp(&value2)
             },
+            (&Internable::Decimal(ref d), &Internable::Decimal(ref d2)) => { Some(d.cmp(d2)) },
+            (&Internable::Decimal(ref d), &Internable::Number(_)) => {
+                d.to_float().partial_cmp(&(Internable::to_number(rhs) as f64))
+            },
+            (&Internable::Number(_), &Internable::Decimal(ref d2)) => {
+                (Internable::to_number(self) as f64).partial_cmp(&d2.to_float())
+            },
             _ => { unreachable!() }
         }
     }
@@ -686,6 +1229,7 @@ impl Internable {
     pub fn to_number(intern: &Internable) -> f32 {
         match intern {
             &Internable::Number(num) => unsafe { transmute::<u32, f32>(num) },
+            &Internable::Decimal(ref decimal) => decimal.to_float() as f32,
             _ => { panic!("to_number on non-number") }
         }
     }
@@ -693,7 +1237,10 @@ impl Internable {
     pub fn to_string(intern: &Internable) -> String {
         match intern {
             &Internable::String(ref string) => string.to_string(),
+            &Internable::Reference(ref id) => id.to_string(),
             &Internable::Number(_) => Internable::to_number(intern).to_string(),
+            &Internable::Decimal(ref decimal) => decimal.to_string(),
+            &Internable::Scoped(ref scope, ref attribute) => format!("@{}|{}", scope, attribute),
             _ => { panic!("to_string on non-string/number") }
         }
     }
@@ -711,6 +1258,15 @@ impl Internable {
             &Internable::Number(_) => {
                 Internable::to_number(self).to_string()
             }
+            &Internable::Decimal(ref decimal) => {
+                decimal.to_string()
+            }
+            &Internable::Reference(ref id) => {
+                format!("<{}>", id)
+            }
+            &Internable::Scoped(ref scope, ref attribute) => {
+                format!("@{} {}", scope, attribute)
+            }
             &Internable::Null => {
                 "Null!".to_string()
             }
@@ -718,18 +1274,17 @@ impl Internable {
     }
 
     pub fn to_json(&self) -> JSONInternable {
-        match self {
-            &Internable::String(ref s) => { JSONInternable::String(s.to_owned()) }
-            &Internable::Number(n) => { JSONInternable::Number(n) }
-            &Internable::Null => { JSONInternable::Null }
-        }
+        JSONInternable::from(self)
     }
 
     pub fn to_sort_priority(&self) -> usize {
         match self {
             &Internable::Null => { 0 }
             &Internable::Number(_) => { 1 }
+            &Internable::Decimal(_) => { 1 }
             &Internable::String(_) => { 2 }
+            &Internable::Reference(_) => { 3 }
+            &Internable::Scoped(..) => { 4 }
         }
     }
 }
@@ -738,15 +1293,23 @@ impl From<JSONInternable> for Internable {
     fn from(json: JSONInternable) -> Self {
         match json {
             JSONInternable::String(s) => { Internable::String(s) }
+            JSONInternable::Reference(id) => { Internable::Reference(id) }
+            JSONInternable::Scoped(scope, attribute) => { Internable::Scoped(scope, attribute) }
             JSONInternable::Number(n) => { Internable::Number(n) }
             JSONInternable::Null => { Internable::Null }
         }
     }
 }
 
+// Record ids go out as `{"ref": "person|1|"}` so that whoever sends them back, be it a
+// client, another program or an EAV file, hands over a reference rather than a string
+// that happens to spell one. Scoped attributes likewise go out as
+// `{"scope": "session", "attribute": "name"}`.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum JSONInternable {
     String(String),
+    Reference(String),
+    Scoped(String, String),
     Number(u32),
     Null,
 }
@@ -766,9 +1329,12 @@ impl JSONInternable {
 
     pub fn print(&self) -> String {
         match self {
-            &JSONInternable::String(ref s) => {
+            &JSONInternable::String(ref s) | &JSONInternable::Reference(ref s) => {
                 s.to_string()
             }
+            &JSONInternable::Scoped(ref scope, ref attribute) => {
+                format!("@{}|{}", scope, attribute)
+            }
             &JSONInternable::Number(_) => {
                 JSONInternable::to_number(self).to_string()
             }
@@ -783,7 +1349,11 @@ impl From<Internable> for JSONInternable {
     fn from(internable: Internable) -> Self {
         match internable {
             Internable::String(s) => { JSONInternable::String(s) }
+            Internable::Reference(id) => { JSONInternable::Reference(id) }
+            Internable::Scoped(scope, attribute) => { JSONInternable::Scoped(scope, attribute) }
             Internable::Number(n) => { JSONInternable::Number(n) }
+            // JSON has no exact decimal, so the client gets the closest float
+            Internable::Decimal(d) => { JSONInternable::from_number(d.to_float() as f32) }
             Internable::Null => { JSONInternable::Null }
         }
     }
@@ -793,7 +1363,10 @@ impl<'a> From<&'a Internable> for JSONInternable {
     fn from(internable: &'a Internable) -> Self {
         match internable {
             &Internable::String(ref s) => { JSONInternable::String(s.to_owned()) }
+            &Internable::Reference(ref id) => { JSONInternable::Reference(id.to_owned()) }
+            &Internable::Scoped(ref scope, ref attribute) => { JSONInternable::Scoped(scope.to_owned(), attribute.to_owned()) }
             &Internable::Number(n) => { JSONInternable::Number(n) }
+            &Internable::Decimal(ref d) => { JSONInternable::from_number(d.to_float() as f32) }
             &Internable::Null => { JSONInternable::Null }
         }
     }
@@ -805,6 +1378,17 @@ impl Serialize for JSONInternable {
     {
         match self {
             &JSONInternable::String(ref s) => serializer.serialize_str(s),
+            &JSONInternable::Reference(ref id) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("ref", id)?;
+                map.end()
+            }
+            &JSONInternable::Scoped(ref scope, ref attribute) => {
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry("scope", scope)?;
+                map.serialize_entry("attribute", attribute)?;
+                map.end()
+            }
             &JSONInternable::Number(_) => serializer.serialize_f32(JSONInternable::to_number(self)),
             _ => serializer.serialize_unit(),
         }
@@ -853,22 +1437,133 @@ impl<'de> Deserialize<'de> for JSONInternable {
             {
                 Ok(JSONInternable::Null)
             }
+
+            fn visit_map<M>(self, mut map: M) -> Result<Self::Value, M::Error>
+                where M: MapAccess<'de>
+            {
+                let mut entries = HashMap::new();
+                while let Some((key, value)) = map.next_entry::<String, String>()? {
+                    entries.insert(key, value);
+                }
+                match (entries.remove("ref"), entries.remove("scope"), entries.remove("attribute")) {
+                    (Some(id), None, None) if entries.is_empty() => Ok(JSONInternable::Reference(id)),
+                    (None, Some(scope), Some(attribute)) if entries.is_empty() => Ok(JSONInternable::Scoped(scope, attribute)),
+                    _ => Err(DeError::custom("expected a reference like {\"ref\": \"id\"} or a scoped attribute like {\"scope\": \"session\", \"attribute\": \"name\"}")),
+                }
+            }
         }
 
         deserializer.deserialize_any(InternableVisitor)
     }
 }
 
+// The interned values, kept in chunks that copies share rather than duplicate. Only the
+// last chunk ever grows, so taking a copy costs a handful of Arcs, and interning after
+// it copies at most that one chunk.
+const INTERNED_CHUNK:usize = 4096;
+
+// Alongside each chunk is a column with a word per value, so the batch filters can read a
+// number's bits without matching on its Internable. A number's word is its bits with
+// NUMBER_WORD set above them, and anything else's is 0.
+const NUMBER_WORD:u64 = 1 << 32;
+
+#[derive(Clone, Default)]
+pub struct InternedValues {
+    chunks: Vec<Arc<Vec<Internable>>>,
+    numbers: Vec<Arc<Vec<u64>>>,
+    len: usize,
+}
+
+impl InternedValues {
+    fn push(&mut self, value:Internable) {
+        if self.len % INTERNED_CHUNK == 0 {
+            self.chunks.push(Arc::new(Vec::with_capacity(INTERNED_CHUNK)));
+            self.numbers.push(Arc::new(Vec::with_capacity(INTERNED_CHUNK)));
+        }
+        let word = match value {
+            Internable::Number(bits) => NUMBER_WORD | bits as u64,
+            _ => 0,
+        };
+        Arc::make_mut(self.numbers.last_mut().unwrap()).push(word);
+        Arc::make_mut(self.chunks.last_mut().unwrap()).push(value);
+        self.len += 1;
+    }
+
+    /// Puts the bits of each of `ids` in `column`, and sets its bit in `numeric` if it's a
+    /// number. Anything else gets a 0 in `column`. Bit `ix` is bit `ix % 64` of word
+    /// `ix / 64`.
+    pub fn number_column(&self, ids:&[Interned], column:&mut Vec<u32>, numeric:&mut Vec<u64>) {
+        numeric.clear();
+        numeric.resize((ids.len() + 63) / 64, 0);
+        column.clear();
+        column.reserve(ids.len());
+        for (ix, &id) in ids.iter().enumerate() {
+            let id = id as usize;
+            let word = self.numbers[id / INTERNED_CHUNK][id % INTERNED_CHUNK];
+            column.push(word as u32);
+            numeric[ix / 64] |= (word >> 32) << (ix % 64);
+        }
+    }
+
+    pub fn get(&self, id:usize) -> Option<&Internable> {
+        if id >= self.len { return None; }
+        Some(&self.chunks[id / INTERNED_CHUNK][id % INTERNED_CHUNK])
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl ::std::ops::Index<usize> for InternedValues {
+    type Output = Internable;
+    fn index(&self, id:usize) -> &Internable {
+        &self.chunks[id / INTERNED_CHUNK][id % INTERNED_CHUNK]
+    }
+}
+
+// written as a plain list of values, the way the Vec it replaced was
+impl Serialize for InternedValues {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let mut seq = serializer.serialize_seq(Some(self.len))?;
+        for chunk in self.chunks.iter() {
+            for value in chunk.iter() {
+                seq.serialize_element(value)?;
+            }
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for InternedValues {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where D: Deserializer<'de>
+    {
+        let values:Vec<Internable> = Deserialize::deserialize(deserializer)?;
+        let mut interned = InternedValues::default();
+        for value in values {
+            interned.push(value);
+        }
+        Ok(interned)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Interner {
     id_to_value: HashMap<Internable, Interned, MyHasher>,
-    value_to_id: Vec<Internable>,
+    value_to_id: InternedValues,
     next_id: Interned,
 }
 
 impl Interner {
     pub fn new() -> Interner {
-        let mut me = Interner {id_to_value: HashMap::default(), value_to_id:vec![Internable::Null], next_id:1};
+        let mut value_to_id = InternedValues::default();
+        value_to_id.push(Internable::Null);
+        let mut me = Interner {id_to_value: HashMap::default(), value_to_id, next_id:1};
         me.string("tag");
+        me.internable_to_id(scoped_attribute("fulltext", "attribute"));
         me
     }
 
@@ -895,6 +1590,10 @@ impl Interner {
         self.internable_to_id(thing)
     }
 
+    pub fn scoped_id(&mut self, scope:&str, attribute:&str) -> Interned {
+        self.internable_to_id(scoped_attribute(scope, attribute))
+    }
+
     #[allow(dead_code)]
     pub fn number(&mut self, num:f32) -> Field {
         let bitpattern = unsafe {
@@ -912,11 +1611,53 @@ impl Interner {
         self.internable_to_id(thing)
     }
 
+    pub fn decimal(&mut self, decimal:Decimal) -> Field {
+        Field::Value(self.internable_to_id(Internable::Decimal(decimal)))
+    }
+
+    pub fn decimal_id(&mut self, decimal:Decimal) -> Interned {
+        self.internable_to_id(Internable::Decimal(decimal))
+    }
+
     #[allow(dead_code)]
     pub fn get_value(&self, id:u32) -> &Internable {
         &self.value_to_id[id as usize]
     }
 
+    /// How many values have been interned, which is also the id the next one gets.
+    pub fn len(&self) -> usize {
+        self.value_to_id.len()
+    }
+
+    /// The id `thing` was interned as, without interning it if it wasn't.
+    pub fn id(&self, thing:&Internable) -> Option<Interned> {
+        self.id_to_value.get(thing).cloned()
+    }
+
+    /// An interner that hands out the ids in `values` for what they hold.
+    pub fn from_values(values:InternedValues) -> Interner {
+        let id_to_value = (1..values.len()).map(|id| (values[id].clone(), id as Interned)).collect();
+        let next_id = values.len() as Interned;
+        Interner { id_to_value, value_to_id: values, next_id }
+    }
+
+    /// Whether every id `other` has handed out means the same thing here.
+    pub fn extends(&self, other:&Interner) -> bool {
+        self.value_to_id.len() >= other.value_to_id.len() &&
+            (0..other.value_to_id.len()).all(|id| self.value_to_id[id] == other.value_to_id[id])
+    }
+
+    /// A copy of every value interned so far, for decoding ids off the program's
+    /// thread. It shares its storage with the interner, so it's cheap to take.
+    pub fn values(&self) -> InternedValues {
+        self.value_to_id.clone()
+    }
+
+    /// See `InternedValues::number_column`.
+    pub fn number_column(&self, ids:&[Interned], column:&mut Vec<u32>, numeric:&mut Vec<u64>) {
+        self.value_to_id.number_column(ids, column, numeric);
+    }
+
     #[allow(dead_code)]
     pub fn get_string(&self, id:u32) -> Option<String> {
         match self.get_value(id) {
@@ -924,25 +1665,83 @@ impl Interner {
             _ => None
         }
     }
-}
 
-//-------------------------------------------------------------------------
-// Constraint
-//-------------------------------------------------------------------------
+    /// Decodes an interned field, e.g. one from a watcher's diff. Ids this interner never
+    /// handed out decode to `Value::None` rather than panicking.
+    pub fn value(&self, id:Interned) -> Value {
+        self.value_to_id.get(id as usize).map_or(Value::None, Value::from_internable)
+    }
 
-type FilterFunction = fn(&Internable, &Internable) -> bool;
+    /// The value as text: strings and record ids as they are, numbers the way Eve
+    /// prints them.
+    pub fn value_to_string(&self, id:Interned) -> Option<String> {
+        match self.value(id) {
+            Value::String(string) => Some(string),
+            Value::Number(_) => Some(Internable::to_string(&self.value_to_id[id as usize])),
+            Value::None => None,
+        }
+    }
+
+    pub fn value_to_number(&self, id:Interned) -> Option<f64> {
+        match self.value(id) {
+            Value::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+}
+
+/// An owned, decoded value for code outside of the runtime, which has no use for the
+/// distinctions Internable makes between number representations or strings and ids.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f64),
+    None,
+}
+
+impl Value {
+    pub fn from_internable(internable:&Internable) -> Value {
+        match internable {
+            &Internable::String(ref string) | &Internable::Reference(ref string) => Value::String(string.to_string()),
+            &Internable::Scoped(..) => Value::String(Internable::to_string(internable)),
+            &Internable::Number(_) => Value::Number(Internable::to_number(internable) as f64),
+            &Internable::Decimal(ref decimal) => Value::Number(decimal.to_float()),
+            &Internable::Null => Value::None,
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+// Constraint
+//-------------------------------------------------------------------------
+
+type FilterFunction = fn(&Internable, &Internable) -> bool;
 type Function = fn(Vec<&Internable>) -> Option<Internable>;
 type MultiFunction = fn(Vec<&Internable>) -> Option<Vec<Vec<Internable>>>;
+// Index functions are multi-functions that need to look at the EAV index itself
+// (e.g. graph traversals) rather than only at their params. They get it mutably so
+// they can ask it to start maintaining the structure they read.
+type IndexFunction = fn(&mut HashIndex, &mut Interner, Vec<Interned>) -> Option<Vec<Vec<Interned>>>;
+// Custom functions are registered by embedders at runtime and return a single
+// row of outputs. Multi custom functions return any number of rows, which is what
+// every custom function is turned into once registered.
+pub type CustomFunction = Fn(&[Internable]) -> Option<Vec<Internable>> + Send + Sync;
+pub type CustomMultiFunction = Fn(&[Internable]) -> Vec<Vec<Internable>> + Send + Sync;
 pub type AggregateFunction = fn(&mut AggregateEntry, &Vec<Internable>, &Vec<Internable>);
 
 pub enum Constraint {
     Scan {e: Field, a: Field, v: Field, register_mask: u64},
+    // A scan whose value is also compared against constant bounds (`age > 30`). The
+    // bounds only narrow what gets proposed; the filter stays as the actual check.
+    RangeScan {e: Field, a: Field, v: Field, low: Option<(Field, bool)>, high: Option<(Field, bool)>, register_mask: u64},
     LookupCommit {e: Field, a: Field, v: Field, register_mask: u64},
     LookupRemote {e: Field, a: Field, v: Field, _for: Field, _type: Field, from: Field, to: Field, register_mask: u64},
     AntiScan {key: Vec<Field>, register_mask: u64},
     IntermediateScan {full_key:Vec<Field>, key: Vec<Field>, value: Vec<Field>, register_mask: u64, output_mask: u64},
     Function {op: String, output: Field, func: Function, params: Vec<Field>, param_mask: u64, output_mask: u64},
     MultiFunction {op: String, outputs: Vec<Field>, func: MultiFunction, params: Vec<Field>, param_mask: u64, output_mask: u64},
+    IndexFunction {op: String, outputs: Vec<Field>, func: IndexFunction, params: Vec<Field>, param_mask: u64, output_mask: u64},
+    CustomFunction {op: String, outputs: Vec<Field>, func: Arc<Box<CustomMultiFunction>>, params: Vec<Field>, param_mask: u64, output_mask: u64},
     Aggregate {op: String, output: Vec<Field>, add: AggregateFunction, remove:AggregateFunction, group:Vec<Field>, projection:Vec<Field>, params: Vec<Field>, param_mask: u64, output_mask: u64, output_key:Vec<Field>, kind: FunctionKind},
     Filter {op: String, func: FilterFunction, left: Field, right: Field, param_mask: u64},
     Insert {e: Field, a: Field, v:Field, commit:bool},
@@ -959,7 +1758,7 @@ fn filter_registers(fields:&Vec<&Field>) -> Vec<Field> {
     fields.iter().filter(|v| is_register(**v)).map(|v| (**v).clone()).collect()
 }
 
-fn replace_registers(fields:&mut Vec<&mut Field>, lookup:&HashMap<Field,Field>) {
+fn replace_registers<S:BuildHasher>(fields:&mut Vec<&mut Field>, lookup:&HashMap<Field,Field,S>) {
     for field in fields {
         if is_register(*field) {
             **field = *lookup.get(field).unwrap();
@@ -968,9 +1767,62 @@ fn replace_registers(fields:&mut Vec<&mut Field>, lookup:&HashMap<Field,Field>)
 }
 
 impl Constraint {
+    pub fn fields_mut(&mut self) -> Vec<&mut Field> {
+        let mut fields = vec![];
+        match self {
+            &mut Constraint::RangeScan { ref mut e, ref mut a, ref mut v, ref mut low, ref mut high, ..} => {
+                fields.push(e); fields.push(a); fields.push(v);
+                if let &mut Some((ref mut bound, _)) = low { fields.push(bound); }
+                if let &mut Some((ref mut bound, _)) = high { fields.push(bound); }
+            }
+            &mut Constraint::Scan { ref mut e, ref mut a, ref mut v, ..} |
+            &mut Constraint::LookupCommit { ref mut e, ref mut a, ref mut v, ..} |
+            &mut Constraint::Insert { ref mut e, ref mut a, ref mut v, ..} |
+            &mut Constraint::Remove { ref mut e, ref mut a, ref mut v } => { fields.push(e); fields.push(a); fields.push(v); }
+            &mut Constraint::LookupRemote { ref mut e, ref mut a, ref mut v, ref mut _for, ref mut _type, ref mut from, ref mut to, ..} => {
+                fields.extend(vec![e, a, v, _for, _type, from, to]);
+            }
+            &mut Constraint::DynamicCommit { ref mut e, ref mut a, ref mut v, ref mut _type } => { fields.extend(vec![e, a, v, _type]); }
+            &mut Constraint::RemoveAttribute { ref mut e, ref mut a } => { fields.push(e); fields.push(a); }
+            &mut Constraint::RemoveEntity { ref mut e } => { fields.push(e); }
+            &mut Constraint::AntiScan { ref mut key, ..} => { fields.extend(key.iter_mut()); }
+            &mut Constraint::IntermediateScan { ref mut full_key, ref mut key, ref mut value, ..} => {
+                fields.extend(full_key.iter_mut());
+                fields.extend(key.iter_mut());
+                fields.extend(value.iter_mut());
+            }
+            &mut Constraint::InsertIntermediate { ref mut key, ref mut value, ..} => {
+                fields.extend(key.iter_mut());
+                fields.extend(value.iter_mut());
+            }
+            &mut Constraint::Function { ref mut output, ref mut params, ..} => {
+                fields.push(output);
+                fields.extend(params.iter_mut());
+            }
+            &mut Constraint::MultiFunction { ref mut outputs, ref mut params, ..} |
+            &mut Constraint::IndexFunction { ref mut outputs, ref mut params, ..} |
+            &mut Constraint::CustomFunction { ref mut outputs, ref mut params, ..} => {
+                fields.extend(outputs.iter_mut());
+                fields.extend(params.iter_mut());
+            }
+            &mut Constraint::Aggregate { ref mut output, ref mut group, ref mut projection, ref mut params, ref mut output_key, ..} => {
+                fields.extend(output.iter_mut());
+                fields.extend(group.iter_mut());
+                fields.extend(projection.iter_mut());
+                fields.extend(params.iter_mut());
+                fields.extend(output_key.iter_mut());
+            }
+            &mut Constraint::Filter { ref mut left, ref mut right, ..} => { fields.push(left); fields.push(right); }
+            &mut Constraint::Watch { ref mut registers, ..} => { fields.extend(registers.iter_mut()); }
+            &mut Constraint::Project { .. } => {}
+        }
+        fields
+    }
+
     pub fn get_registers(&self) -> Vec<Field> {
         match self {
             &Constraint::Scan { ref e, ref a, ref v, ..} => { filter_registers(&vec![e,a,v]) }
+            &Constraint::RangeScan { ref e, ref a, ref v, ..} => { filter_registers(&vec![e,a,v]) }
             &Constraint::LookupCommit { ref e, ref a, ref v, ..} => { filter_registers(&vec![e,a,v]) }
             &Constraint::LookupRemote { ref e, ref a, ref v, ref _for, ref _type, ref from, ref to, ..} => { filter_registers(&vec![e,a,v, _for, _type, from, to]) }
             &Constraint::AntiScan { ref key, ..} => { filter_registers(&key.iter().collect()) }
@@ -982,7 +1834,9 @@ impl Constraint {
                 vs.extend(params);
                 filter_registers(&vs)
             }
-            &Constraint::MultiFunction {ref outputs, ref params, ..} => {
+            &Constraint::MultiFunction {ref outputs, ref params, ..} |
+            &Constraint::IndexFunction {ref outputs, ref params, ..} |
+            &Constraint::CustomFunction {ref outputs, ref params, ..} => {
                 let mut vs = vec![];
                 vs.extend(outputs);
                 vs.extend(params);
@@ -1016,10 +1870,13 @@ impl Constraint {
     pub fn get_output_registers(&self) -> Vec<Field> {
         match self {
             &Constraint::Scan { ref e, ref a, ref v, ..} => { filter_registers(&vec![e,a,v]) }
+            &Constraint::RangeScan { ref e, ref a, ref v, ..} => { filter_registers(&vec![e,a,v]) }
             &Constraint::LookupCommit { ref e, ref a, ref v, ..} => { filter_registers(&vec![e,a,v]) }
             &Constraint::LookupRemote { ref e, ref a, ref v, ref _for, ref _type, ref from, ref to, ..} => { filter_registers(&vec![e,a,v, _for, _type, from, to]) }
             &Constraint::Function {ref output, ..} => { filter_registers(&vec![output]) }
             &Constraint::MultiFunction {ref outputs, ..} => { filter_registers(&outputs.iter().collect()) }
+            &Constraint::IndexFunction {ref outputs, ..} => { filter_registers(&outputs.iter().collect()) }
+            &Constraint::CustomFunction {ref outputs, ..} => { filter_registers(&outputs.iter().collect()) }
             &Constraint::Aggregate {ref output, ..} => { filter_registers(&output.iter().collect()) }
             &Constraint::IntermediateScan {ref value, ..} => { filter_registers(&value.iter().collect()) }
             _ => { vec![] }
@@ -1029,10 +1886,13 @@ impl Constraint {
     pub fn get_filtering_registers(&self) -> Vec<Field> {
         match self {
             &Constraint::Scan { ref e, ref a, ref v, ..} => { filter_registers(&vec![e,a,v]) }
+            &Constraint::RangeScan { ref e, ref a, ref v, ..} => { filter_registers(&vec![e,a,v]) }
             &Constraint::LookupCommit { ref e, ref a, ref v, ..} => { filter_registers(&vec![e,a,v]) }
             &Constraint::LookupRemote { ref e, ref a, ref v, ref _for, ref _type, ref from, ref to, ..} => { filter_registers(&vec![e,a,v, _for, _type, from, to]) }
             &Constraint::Function {ref output, ..} => { filter_registers(&vec![output]) }
             &Constraint::MultiFunction {ref outputs, ..} => { filter_registers(&outputs.iter().collect()) }
+            &Constraint::IndexFunction {ref outputs, ..} => { filter_registers(&outputs.iter().collect()) }
+            &Constraint::CustomFunction {ref outputs, ..} => { filter_registers(&outputs.iter().collect()) }
             &Constraint::Filter {ref left, ref right, ..} => { filter_registers(&vec![left, right]) }
             &Constraint::AntiScan {ref key, ..} => { filter_registers(&key.iter().collect()) }
             &Constraint::IntermediateScan {ref full_key, ..} => { filter_registers(&full_key.iter().collect()) }
@@ -1040,12 +1900,16 @@ impl Constraint {
         }
     }
 
-    pub fn replace_registers(&mut self, lookup:&HashMap<Field, Field>) {
+    pub fn replace_registers<S:BuildHasher>(&mut self, lookup:&HashMap<Field, Field, S>) {
         match self {
             &mut Constraint::Scan { ref mut e, ref mut a, ref mut v, ref mut register_mask} => {
                 replace_registers(&mut vec![e,a,v], lookup);
                 *register_mask = make_register_mask(vec![e,a,v]);
             }
+            &mut Constraint::RangeScan { ref mut e, ref mut a, ref mut v, ref mut register_mask, ..} => {
+                replace_registers(&mut vec![e,a,v], lookup);
+                *register_mask = make_register_mask(vec![e,a,v]);
+            }
             &mut Constraint::LookupCommit { ref mut e, ref mut a, ref mut v, ref mut register_mask} => {
                 replace_registers(&mut vec![e,a,v], lookup);
                 *register_mask = make_register_mask(vec![e,a,v]);
@@ -1075,7 +1939,9 @@ impl Constraint {
                 *output = *lookup.get(output).unwrap();
                 *output_mask = make_register_mask(vec![output]);
             }
-            &mut Constraint::MultiFunction {ref mut outputs, ref mut params, ref mut param_mask, ref mut output_mask, ..} => {
+            &mut Constraint::MultiFunction {ref mut outputs, ref mut params, ref mut param_mask, ref mut output_mask, ..} |
+            &mut Constraint::IndexFunction {ref mut outputs, ref mut params, ref mut param_mask, ref mut output_mask, ..} |
+            &mut Constraint::CustomFunction {ref mut outputs, ref mut params, ref mut param_mask, ref mut output_mask, ..} => {
                 {
                     let mut vs = vec![];
                     vs.extend(outputs.iter_mut());
@@ -1129,6 +1995,7 @@ impl Clone for Constraint {
     fn clone(&self) -> Self {
         match self {
             &Constraint::Scan { e, a, v, register_mask } => { Constraint::Scan {e,a,v,register_mask} }
+            &Constraint::RangeScan { e, a, v, low, high, register_mask } => { Constraint::RangeScan {e,a,v,low,high,register_mask} }
             &Constraint::LookupCommit { e, a, v, register_mask } => { Constraint::LookupCommit {e,a,v,register_mask} }
             &Constraint::LookupRemote { e, a, v, _for, _type, from, to, register_mask } => { Constraint::LookupRemote { e,a,v,_for,_type,from,to,register_mask } }
             &Constraint::AntiScan { ref key, register_mask } => { Constraint::AntiScan {key:key.clone(),register_mask} }
@@ -1141,6 +2008,12 @@ impl Clone for Constraint {
             &Constraint::MultiFunction {ref op, ref outputs, ref func, ref params, ref param_mask, ref output_mask} => {
                 Constraint::MultiFunction{ op:op.clone(), outputs:outputs.clone(), func:*func, params:params.clone(), param_mask:*param_mask, output_mask:*output_mask }
             }
+            &Constraint::IndexFunction {ref op, ref outputs, ref func, ref params, ref param_mask, ref output_mask} => {
+                Constraint::IndexFunction{ op:op.clone(), outputs:outputs.clone(), func:*func, params:params.clone(), param_mask:*param_mask, output_mask:*output_mask }
+            }
+            &Constraint::CustomFunction {ref op, ref outputs, ref func, ref params, ref param_mask, ref output_mask} => {
+                Constraint::CustomFunction{ op:op.clone(), outputs:outputs.clone(), func:func.clone(), params:params.clone(), param_mask:*param_mask, output_mask:*output_mask }
+            }
             &Constraint::Aggregate {ref op, ref output, ref add, ref remove, ref group, ref projection, ref params, ref param_mask, ref output_mask, ref output_key, kind} => {
                 Constraint::Aggregate { op:op.clone(), output:output.clone(), add:*add, remove:*remove, group:group.clone(), projection:projection.clone(), params:params.clone(), param_mask:*param_mask, output_mask:*output_mask, output_key:output_key.clone(), kind }
             }
@@ -1166,12 +2039,15 @@ impl PartialEq for Constraint {
     fn eq(&self, other:&Constraint) -> bool {
         match (self, other) {
             (&Constraint::Scan { e, a, v, ..}, &Constraint::Scan {e:e2, a:a2, v:v2, ..} ) => { e == e2 && a == a2 && v == v2 },
+            (&Constraint::RangeScan { e, a, v, low, high, ..}, &Constraint::RangeScan {e:e2, a:a2, v:v2, low:low2, high:high2, ..} ) => { e == e2 && a == a2 && v == v2 && low == low2 && high == high2 },
             (&Constraint::LookupCommit { e, a, v, ..}, &Constraint::LookupCommit {e:e2, a:a2, v:v2, ..} ) => { e == e2 && a == a2 && v == v2 },
             (&Constraint::LookupRemote { e, a, v, _for, _type, from, to, ..}, &Constraint::LookupRemote {e:e2, a:a2, v:v2, _for:for2, _type:type2, from: from2, to:to2, ..} ) => { e == e2 && a == a2 && v == v2 && _for == for2 && _type == type2 && from == from2 && to == to2 },
             (&Constraint::AntiScan { ref key, ..}, &Constraint::AntiScan { key:ref key2, ..})  => { key == key2 }
             (&Constraint::IntermediateScan { ref full_key, ..}, &Constraint::IntermediateScan { full_key:ref full_key2, ..}) => { full_key == full_key2 }
             (&Constraint::Function {ref op, ref output, ref params, ..}, &Constraint::Function {op:ref op2, output:ref output2, params:ref params2, ..}) => { op == op2 && output == output2 && params == params2 }
             (&Constraint::MultiFunction {ref op, ref outputs, ref params, ..}, &Constraint::MultiFunction {op:ref op2, outputs:ref outputs2, params:ref params2, ..}) => { op == op2 && outputs == outputs2 && params == params2 }
+            (&Constraint::IndexFunction {ref op, ref outputs, ref params, ..}, &Constraint::IndexFunction {op:ref op2, outputs:ref outputs2, params:ref params2, ..}) => { op == op2 && outputs == outputs2 && params == params2 }
+            (&Constraint::CustomFunction {ref op, ref outputs, ref params, ..}, &Constraint::CustomFunction {op:ref op2, outputs:ref outputs2, params:ref params2, ..}) => { op == op2 && outputs == outputs2 && params == params2 }
             (&Constraint::Aggregate {ref op, ref output, ref group, ref projection, ref params, ..}, &Constraint::Aggregate {op:ref op2, output:ref output2, group:ref group2, projection:ref projection2, params:ref params2, ..}) => { op == op2 && output == output2 && params == params2 && group == group2 && projection == projection2 }
             (&Constraint::Filter {ref op, ref left, ref right, ..}, &Constraint::Filter {op:ref op2, left:ref left2, right:ref right2, ..}) => { op == op2 && left == left2 && right == right2 }
             (&Constraint::Insert { e,a,v,commit }, &Constraint::Insert { e:e2, a:a2, v:v2, commit:commit2 }) => {  e == e2 && a == a2 && v == v2 && commit == commit2 },
@@ -1193,12 +2069,15 @@ impl Hash for Constraint {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
             &Constraint::Scan { e, a, v, ..} => { e.hash(state); a.hash(state); v.hash(state); },
+            &Constraint::RangeScan { e, a, v, low, high, ..} => { e.hash(state); a.hash(state); v.hash(state); low.hash(state); high.hash(state); },
             &Constraint::LookupCommit { e, a, v, ..} => { e.hash(state); a.hash(state); v.hash(state); },
             &Constraint::LookupRemote { e, a, v, _for, _type, from, to, ..} => { e.hash(state); a.hash(state); v.hash(state); _for.hash(state); _type.hash(state); from.hash(state); to.hash(state); },
             &Constraint::AntiScan { ref key, ..}  => { key.hash(state); }
             &Constraint::IntermediateScan { ref full_key, ..} => { full_key.hash(state) }
             &Constraint::Function {ref op, ref output, ref params, ..} => { op.hash(state); output.hash(state); params.hash(state); }
             &Constraint::MultiFunction {ref op, ref outputs, ref params, ..} => { op.hash(state); outputs.hash(state); params.hash(state); }
+            &Constraint::IndexFunction {ref op, ref outputs, ref params, ..} => { op.hash(state); outputs.hash(state); params.hash(state); }
+            &Constraint::CustomFunction {ref op, ref outputs, ref params, ..} => { op.hash(state); outputs.hash(state); params.hash(state); }
             &Constraint::Aggregate {ref op, ref output, ref group, ref projection, ref params, ..} => { op.hash(state); output.hash(state); group.hash(state); projection.hash(state); params.hash(state); }
             &Constraint::Filter {ref op, ref left, ref right, ..} => { op.hash(state); left.hash(state); right.hash(state); }
             &Constraint::Insert { e,a,v,commit } => { e.hash(state); a.hash(state); v.hash(state); commit.hash(state); },
@@ -1219,6 +2098,7 @@ impl fmt::Debug for Constraint {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             &Constraint::Scan { e, a, v, .. } => { write!(f, "Scan ( {:?}, {:?}, {:?} )", e, a, v) }
+            &Constraint::RangeScan { e, a, v, low, high, .. } => { write!(f, "RangeScan ( {:?}, {:?}, {:?}, {:?}..{:?} )", e, a, v, low, high) }
             &Constraint::LookupCommit { e, a, v, .. } => { write!(f, "LookupCommit ( {:?}, {:?}, {:?} )", e, a, v) }
             &Constraint::LookupRemote { e, a, v, _for, _type, from, to, .. } => { write!(f, "LookupRemote ( {:?}, {:?}, {:?}, {:?}, {:?}, {:?}, {:?} )", e, a, v, _for, _type, from, to) }
             &Constraint::AntiScan { ref key, .. } => { write!(f, "AntiScan ({:?})", key) }
@@ -1231,6 +2111,8 @@ impl fmt::Debug for Constraint {
             &Constraint::DynamicCommit { e, a, v, _type, .. } => { write!(f, "Remove ( {:?}, {:?}, {:?}, {:?} )", e, a, v, _type) }
             &Constraint::Function { ref op, ref params, ref output, .. } => { write!(f, "{:?} = {}({:?})", output, op, params) }
             &Constraint::MultiFunction { ref op, ref params, ref outputs, .. } => { write!(f, "{:?} = {}({:?})", outputs, op, params) }
+            &Constraint::IndexFunction { ref op, ref params, ref outputs, .. } => { write!(f, "{:?} = {}({:?})", outputs, op, params) }
+            &Constraint::CustomFunction { ref op, ref params, ref outputs, .. } => { write!(f, "{:?} = {}({:?})", outputs, op, params) }
             &Constraint::Aggregate { ref op, ref group, ref projection, ref params, ref output_key, .. } => { write!(f, "{:?} = {}(per: {:?}, for: {:?}, {:?})", output_key, op, group, projection, params) }
             &Constraint::Filter { ref op, ref left, ref right, .. } => { write!(f, "Filter ( {:?} {} {:?} )", left, op, right) }
             &Constraint::Project { ref registers } => { write!(f, "Project {:?}", registers) }
@@ -1256,6 +2138,11 @@ pub fn make_scan(e:Field, a:Field, v:Field) -> Constraint {
     Constraint::Scan{e, a, v, register_mask }
 }
 
+pub fn make_range_scan(e:Field, a:Field, v:Field, low:Option<(Field, bool)>, high:Option<(Field, bool)>) -> Constraint {
+    let register_mask = make_register_mask(vec![&e,&a,&v]);
+    Constraint::RangeScan{e, a, v, low, high, register_mask }
+}
+
 pub fn make_commit_lookup(e:Field, a:Field, v:Field) -> Constraint {
     let register_mask = make_register_mask(vec![&e,&a,&v]);
     Constraint::LookupCommit{e, a, v, register_mask }
@@ -1283,6 +2170,16 @@ pub fn make_intermediate_insert(key: Vec<Field>, value:Vec<Field>, negate:bool)
     Constraint::InsertIntermediate {key, value, negate}
 }
 
+// Setting an attribute always removes and re-inserts against the same e, so a `:=`
+// never mints a new id. A none attribute and value removes the whole entity.
+pub fn make_attribute_set(e:Field, a:Field, v:Field, commit:bool) -> Vec<Constraint> {
+    match (a, v) {
+        (Field::Value(0), Field::Value(0)) => vec![Constraint::RemoveEntity {e}],
+        (_, Field::Value(0)) => vec![Constraint::RemoveAttribute {e, a}],
+        _ => vec![Constraint::RemoveAttribute {e, a}, Constraint::Insert {e, a, v, commit}],
+    }
+}
+
 pub fn make_function(op: &str, params: Vec<Field>, output: Field) -> Constraint {
     let param_mask = make_register_mask(params.iter().collect::<Vec<&Field>>());
     let output_mask = make_register_mask(vec![&output]);
@@ -1291,6 +2188,8 @@ pub fn make_function(op: &str, params: Vec<Field>, output: Field) -> Constraint
         "-" => subtract,
         "*" => multiply,
         "/" => divide,
+        "%" => math_mod,
+        "^" => math_pow,
         "math/sin" => math_sin,
         "math/cos" => math_cos,
         "math/absolute" => math_absolute,
@@ -1302,16 +2201,34 @@ pub fn make_function(op: &str, params: Vec<Field>, output: Field) -> Constraint
         "math/floor" => math_floor,
         "math/round" => math_round,
         "random/number" => random_number,
+        "random/uuid" => random_uuid,
+        "sample" => sample,
         "string/replace" => string_replace,
         "string/contains" => string_contains,
         "string/lowercase" => string_lowercase,
         "string/uppercase" => string_uppercase,
         "string/substring" => string_substring,
         "string/length" => string_length,
+        "string/levenshtein" => string_levenshtein,
+        "string/soundex" => string_soundex,
+        "string/pad-start" => string_pad_start,
+        "string/pad-end" => string_pad_end,
+        "string/title-case" => string_title_case,
+        "string/slugify" => string_slugify,
+        "url/encode" => url_encode,
+        "url/decode" => url_decode,
+        "url/encode-query" => url_encode_query,
         "eve/type-of" => eve_type_of,
         "eve/parse-value" => eve_parse_value,
+        "date/now" => date_now,
+        "date/parse" => date_parse,
+        "date/format" => date_format,
+        "date/add" => date_add,
+        "date/diff" => date_diff,
         "concat" => concat,
         "gen_id" => gen_id,
+        "eve-internal/scope" => scope_attribute,
+        "eve-internal/unscope" => unscope_attribute,
         _ => panic!("Unknown function: {:?}", op)
     };
     Constraint::Function {op: op.to_string(), func, params, output, param_mask, output_mask }
@@ -1325,11 +2242,33 @@ pub fn make_multi_function(op: &str, params: Vec<Field>, outputs: Vec<Field>) ->
         "string/split" => string_split,
         "string/index-of" => string_index_of,
         "math/range" => math_range,
+        "range" => range,
+        "url/parse" => url_parse,
+        "url/query-param" => url_query_param,
+        "html/select" => html_select,
         _ => panic!("Unknown multi function: {:?}", op)
     };
     Constraint::MultiFunction {op: op.to_string(), func, params, outputs, param_mask, output_mask }
 }
 
+pub fn make_index_function(op: &str, params: Vec<Field>, outputs: Vec<Field>) -> Constraint {
+    let param_mask = make_register_mask(params.iter().collect::<Vec<&Field>>());
+    let output_mask = make_register_mask(outputs.iter().collect::<Vec<&Field>>());
+    let func = match op {
+        "graph/shortest-path" => graph_shortest_path,
+        "graph/components" => graph_components,
+        "search/text" => search_text,
+        _ => panic!("Unknown index function: {:?}", op)
+    };
+    Constraint::IndexFunction {op: op.to_string(), func, params, outputs, param_mask, output_mask }
+}
+
+pub fn make_custom_function(op: &str, func: Arc<Box<CustomMultiFunction>>, params: Vec<Field>, outputs: Vec<Field>) -> Constraint {
+    let param_mask = make_register_mask(params.iter().collect::<Vec<&Field>>());
+    let output_mask = make_register_mask(outputs.iter().collect::<Vec<&Field>>());
+    Constraint::CustomFunction {op: op.to_string(), func, params, outputs, param_mask, output_mask }
+}
+
 pub fn make_aggregate(op: &str, group: Vec<Field>, projection:Vec<Field>, params: Vec<Field>, output: Vec<Field>, kind:FunctionKind) -> Constraint {
     let param_mask = make_register_mask(params.iter().collect::<Vec<&Field>>());
     let output_mask = make_register_mask(output.iter().collect::<Vec<&Field>>());
@@ -1366,11 +2305,15 @@ pub fn make_filter(op: &str, left: Field, right:Field) -> Constraint {
 //-------------------------------------------------------------------------
 
 pub fn eq(left:&Internable, right:&Internable) -> bool {
-    left == right
+    match (left, right) {
+        (&Internable::Decimal(_), &Internable::Number(_)) |
+        (&Internable::Number(_), &Internable::Decimal(_)) => { left.cmp(right) == cmp::Ordering::Equal },
+        _ => { left == right }
+    }
 }
 
 pub fn not_eq(left:&Internable, right:&Internable) -> bool {
-    left != right
+    !eq(left, right)
 }
 
 macro_rules! numeric_filter {
@@ -1382,6 +2325,11 @@ macro_rules! numeric_filter {
                     let b = Internable::to_number(right);
                     a $op b
                 },
+                (&Internable::Decimal(_), &Internable::Decimal(_)) |
+                (&Internable::Decimal(_), &Internable::Number(_)) |
+                (&Internable::Number(_), &Internable::Decimal(_)) => {
+                    left $op right
+                },
                 (&Internable::String(ref a), &Internable::String(ref b)) => {
                     a $op b
                 },
@@ -1400,8 +2348,127 @@ numeric_filter!(lte, <=);
 // Functions
 //-------------------------------------------------------------------------
 
+// Mixing a decimal with a float promotes the float to a decimal, so money math
+// stays exact as long as one side of it is.
+fn to_decimal(value:&Internable) -> Option<Decimal> {
+    match value {
+        &Internable::Decimal(decimal) => Some(decimal),
+        &Internable::Number(_) => Decimal::from_f32(Internable::to_number(value)),
+        _ => None
+    }
+}
+
+// Functions that get an argument of the wrong kind, like math on a string, just don't
+// produce an output. So that a program can tell that from a search that didn't match,
+// every one of these is also reported as `@system [#eve/error kind: "type-error" block
+// function value message]` with the next transaction. There's one record per block and
+// constraint, holding what it was last given that it couldn't take, and it's taken back
+// out once the block runs again without that constraint failing or the block goes away.
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FunctionError {
+    pub block: Interned,
+    /// The function's constraint in the block.
+    pub constraint: usize,
+    pub function: String,
+    pub values: Vec<Internable>,
+    /// What every argument of the function has to be.
+    pub expected: &'static str,
+    /// For an embedder's function that returned a row of the wrong length, how many
+    /// values it returned and how many outputs it has.
+    pub returned: Option<(usize, usize)>,
+}
+
+impl fmt::Display for FunctionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let values:Vec<String> = self.values.iter().map(|value| match value {
+            &Internable::String(ref string) => format!("{:?}", string),
+            _ => value.print(),
+        }).collect();
+        match self.returned {
+            Some((count, outputs)) => write!(f, "`{}` has {} outputs, but returned {} values when given {}", self.function, outputs, count, values.join(", ")),
+            None => write!(f, "`{}` expects {}, but was given {}", self.function, self.expected, values.join(", ")),
+        }
+    }
+}
+
+// Attributes declared to hold records. An input that gives one of these a string means
+// the record with that id, and anything else that ends up there is reported as
+// `@system [#eve/error kind: "schema-error" entity attribute value message]`.
+
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    references: HashSet<Interned>,
+    errors: Vec<(Interned, Interned, Interned)>,
+}
+
+impl Schema {
+    pub fn declare_reference(&mut self, a:Interned) {
+        self.references.insert(a);
+    }
+
+    pub fn is_reference(&self, a:Interned) -> bool {
+        self.references.contains(&a)
+    }
+
+    pub fn references(&self) -> &HashSet<Interned> {
+        &self.references
+    }
+
+    // Turns a string given for a reference attribute into the record it names.
+    fn coerce(&self, change:&mut Change, interner:&mut Interner) {
+        if !self.is_reference(change.a) { return; }
+        let id = match interner.get_value(change.v) {
+            &Internable::String(ref id) => id.clone(),
+            _ => return,
+        };
+        change.v = interner.internable_to_id(Internable::Reference(id));
+    }
+
+    fn check(&mut self, e:Interned, a:Interned, v:Interned, interner:&Interner) {
+        if !self.is_reference(a) { return; }
+        match interner.get_value(v) {
+            &Internable::Reference(_) => {}
+            _ => self.errors.push((e, a, v)),
+        }
+    }
+}
+
+// The kind of argument a function can't do without, for the functions whose every
+// argument has to be of the same kind.
+fn expected_arguments(op:&str) -> Option<&'static str> {
+    match op {
+        "+" | "-" | "*" | "/" | "%" | "^" | "math/sin" | "math/cos" | "math/absolute" | "math/mod" | "math/pow" |
+        "math/to-fixed" | "math/to-hex" | "math/ceiling" | "math/floor" | "math/round" => Some("numbers"),
+        "string/lowercase" | "string/uppercase" | "string/length" | "string/levenshtein" | "string/soundex" |
+        "string/title-case" | "string/slugify" => Some("strings"),
+        "eve/type-of" | "eve/parse-value" => Some("a value"),
+        _ => None,
+    }
+}
+
+/// Why a function that didn't produce an output couldn't, if it was given something it
+/// can't take.
+pub fn function_error(block:Interned, constraint:usize, op:&str, values:Vec<Internable>) -> Option<FunctionError> {
+    let expected = expected_arguments(op)?;
+    let fits = |value:&Internable| match (expected, value) {
+        ("numbers", &Internable::Number(_)) | ("numbers", &Internable::Decimal(_)) => true,
+        ("strings", &Internable::String(_)) => true,
+        ("a value", &Internable::Null) => false,
+        ("a value", _) => true,
+        _ => false,
+    };
+    if values.iter().all(|value| fits(value)) { return None; }
+    Some(FunctionError { block, constraint, function: op.to_string(), values, expected, returned: None })
+}
+
+/// The error for an embedder's function that returned `count` values for `outputs` outputs.
+pub fn output_count_error(block:Interned, constraint:usize, op:&str, values:Vec<Internable>, count:usize, outputs:usize) -> FunctionError {
+    FunctionError { block, constraint, function: op.to_string(), values, expected: "a value per output", returned: Some((count, outputs)) }
+}
+
 macro_rules! binary_math {
-    ($name:ident, $op:tt) => {
+    ($name:ident, $op:tt, $decimal_op:ident) => {
         pub fn $name(params: Vec<&Internable>) -> Option<Internable> {
             match params.as_slice() {
                 &[&Internable::Number(_), &Internable::Number(_)] => {
@@ -1409,16 +2476,24 @@ macro_rules! binary_math {
                     let b = Internable::to_number(params[1]);
                     Some(Internable::from_number(a $op b))
                 },
+                &[&Internable::Decimal(_), &Internable::Decimal(_)] |
+                &[&Internable::Decimal(_), &Internable::Number(_)] |
+                &[&Internable::Number(_), &Internable::Decimal(_)] => {
+                    match (to_decimal(params[0]), to_decimal(params[1])) {
+                        (Some(a), Some(b)) => a.$decimal_op(b).map(Internable::Decimal),
+                        _ => None
+                    }
+                },
                 _ => { None }
             }
         }
     };
 }
 
-binary_math!(add, +);
-binary_math!(subtract, -);
-binary_math!(multiply, *);
-binary_math!(divide, /);
+binary_math!(add, +, add);
+binary_math!(subtract, -, sub);
+binary_math!(multiply, *, multiply);
+binary_math!(divide, /, divide);
 
 
 pub fn math_sin(params: Vec<&Internable>) -> Option<Internable> {
@@ -1524,13 +2599,44 @@ pub fn math_round(params: Vec<&Internable>) -> Option<Internable> {
     }
 }
 
+// Ranges are generated as rows, so one this long is almost certainly a mistake (a bound
+// off by a few orders of magnitude or a tiny increment) rather than something wanted.
+pub const MAX_RANGE_ITEMS:usize = 1_000_000;
+
+fn number_range(from:f32, to:f32, increment:f32) -> Option<Vec<Vec<Internable>>> {
+    if !from.is_finite() || !to.is_finite() || !increment.is_finite() || increment == 0.0 {
+        return None;
+    }
+    // stepping away from `to` never gets there, so there's nothing in the range
+    if (to - from) * increment < 0.0 {
+        return Some(vec![]);
+    }
+    // the fudge keeps e.g. 0 to 1 by 0.1 from losing its last step to rounding
+    let steps = ((to - from) / increment + 1e-4).floor();
+    if steps >= MAX_RANGE_ITEMS as f32 {
+        return None;
+    }
+    Some((0..(steps as usize + 1)).map(|ix| vec![Internable::from_number(from + ix as f32 * increment)]).collect())
+}
+
 pub fn math_range(params: Vec<&Internable>) -> Option<Vec<Vec<Internable>>> {
     match params.as_slice() {
         &[&Internable::Number(_), &Internable::Number(_)] => {
             let from = Internable::to_number(params[0]) as i64;
             let to = Internable::to_number(params[1]) as i64;
+            number_range(from as f32, to as f32, 1.0)
+        },
+        _ => { None }
+    }
+}
 
-            Some((from..to+1).map(|x| vec![Internable::from_number(x as f32)]).collect())
+pub fn range(params: Vec<&Internable>) -> Option<Vec<Vec<Internable>>> {
+    match params.as_slice() {
+        &[&Internable::Number(_), &Internable::Number(_), &Internable::Null] => {
+            number_range(Internable::to_number(params[0]), Internable::to_number(params[1]), 1.0)
+        },
+        &[&Internable::Number(_), &Internable::Number(_), &Internable::Number(_)] => {
+            number_range(Internable::to_number(params[0]), Internable::to_number(params[1]), Internable::to_number(params[2]))
         },
         _ => { None }
     }
@@ -1555,6 +2661,31 @@ pub fn random_number(params: Vec<&Internable>) -> Option<Internable> {
     }
 }
 
+// Deterministically keeps `fraction` of the keys it's given. We use FNV rather
+// than the std hasher so that the same seed selects the same subset across
+// runs and builds.
+pub fn sample(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[fraction @ &Internable::Number(_), key, seed] => {
+            let fraction = Internable::to_number(fraction) as f64;
+            if fraction <= 0.0 || key == &Internable::Null { return None; }
+            let mut hash = FnvHasher::default();
+            match seed {
+                &Internable::Null => {},
+                _ => Internable::to_string(seed).hash(&mut hash),
+            }
+            Internable::to_string(key).hash(&mut hash);
+            let bucket = (hash.finish() as f64) / (u64::max_value() as f64);
+            if bucket < fraction {
+                Some(key.clone())
+            } else {
+                None
+            }
+        },
+        _ => { None }
+    }
+}
+
 pub fn string_replace(params: Vec<&Internable>) -> Option<Internable> {
     match params.as_slice() {
         &[&Internable::String(ref text), &Internable::String(ref replace), &Internable::String(ref with)] => {
@@ -1600,6 +2731,148 @@ pub fn string_length(params: Vec<&Internable>) -> Option<Internable> {
     }
 }
 
+pub fn levenshtein(a:&str, b:&str) -> usize {
+    let a:Vec<&str> = UnicodeSegmentation::graphemes(a, true).collect();
+    let b:Vec<&str> = UnicodeSegmentation::graphemes(b, true).collect();
+    let mut previous:Vec<usize> = (0..b.len() + 1).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (a_ix, a_char) in a.iter().enumerate() {
+        current[0] = a_ix + 1;
+        for (b_ix, b_char) in b.iter().enumerate() {
+            let substitution = previous[b_ix] + if a_char == b_char { 0 } else { 1 };
+            current[b_ix + 1] = cmp::min(substitution, cmp::min(previous[b_ix + 1], current[b_ix]) + 1);
+        }
+        mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+pub fn string_levenshtein(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[&Internable::String(ref a), &Internable::String(ref b)] => {
+            Some(Internable::from_number(levenshtein(a, b) as f32))
+        },
+        _ => None
+    }
+}
+
+fn soundex_code(c:char) -> Option<char> {
+    match c {
+        'b' | 'f' | 'p' | 'v' => Some('1'),
+        'c' | 'g' | 'j' | 'k' | 'q' | 's' | 'x' | 'z' => Some('2'),
+        'd' | 't' => Some('3'),
+        'l' => Some('4'),
+        'm' | 'n' => Some('5'),
+        'r' => Some('6'),
+        _ => None
+    }
+}
+
+pub fn string_soundex(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[&Internable::String(ref text)] => {
+            let letters:Vec<char> = text.to_lowercase().chars().filter(|c| *c >= 'a' && *c <= 'z').collect();
+            let first = match letters.first() {
+                Some(first) => *first,
+                None => return None,
+            };
+            let mut result:String = first.to_uppercase().collect();
+            let mut last = soundex_code(first);
+            for c in letters[1..].iter() {
+                let code = soundex_code(*c);
+                match code {
+                    Some(digit) if code != last => { result.push(digit); }
+                    _ => {}
+                }
+                // h and w don't separate letters with the same code, vowels do
+                if *c != 'h' && *c != 'w' { last = code; }
+                if result.len() == 4 { break; }
+            }
+            while result.len() < 4 { result.push('0'); }
+            Some(Internable::String(result))
+        },
+        _ => None
+    }
+}
+
+fn pad_text(params: Vec<&Internable>, at_start:bool) -> Option<Internable> {
+    let text = match params.as_slice() {
+        &[&Internable::String(ref text), ..] => text.to_string(),
+        // numbers are the usual thing to pad, e.g. to zero-fill an invoice number
+        &[value @ &Internable::Number(_), ..] => value.print(),
+        _ => return None,
+    };
+    let length = match params.as_slice() {
+        &[_, value @ &Internable::Number(_), ..] if Internable::to_number(value) >= 0.0 => Internable::to_number(value) as usize,
+        _ => return None,
+    };
+    let pad = match params.as_slice() {
+        &[_, _, &Internable::String(ref pad)] => pad.as_str(),
+        &[_, _, &Internable::Null] => " ",
+        _ => return None,
+    };
+    let current = UnicodeSegmentation::graphemes(text.as_str(), true).count();
+    let pad:Vec<&str> = UnicodeSegmentation::graphemes(pad, true).collect();
+    if current >= length || pad.len() == 0 {
+        return Some(Internable::String(text));
+    }
+    let padding:String = pad.iter().cycle().take(length - current).cloned().collect();
+    if at_start {
+        Some(Internable::String(padding + &text))
+    } else {
+        Some(Internable::String(text + &padding))
+    }
+}
+
+pub fn string_pad_start(params: Vec<&Internable>) -> Option<Internable> {
+    pad_text(params, true)
+}
+
+pub fn string_pad_end(params: Vec<&Internable>) -> Option<Internable> {
+    pad_text(params, false)
+}
+
+pub fn string_title_case(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[&Internable::String(ref text)] => {
+            let mut result = String::with_capacity(text.len());
+            let mut word_start = true;
+            for c in text.chars() {
+                if word_start {
+                    result.extend(c.to_uppercase());
+                } else {
+                    result.extend(c.to_lowercase());
+                }
+                word_start = c.is_whitespace();
+            }
+            Some(Internable::String(result))
+        },
+        _ => None
+    }
+}
+
+pub fn string_slugify(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[&Internable::String(ref text)] => {
+            let mut slug = String::with_capacity(text.len());
+            // runs of anything that isn't a letter or digit collapse into a single dash,
+            // and there's never one at either end
+            let mut pending_dash = false;
+            for c in text.chars() {
+                if c.is_alphanumeric() {
+                    if pending_dash && slug.len() > 0 { slug.push('-'); }
+                    pending_dash = false;
+                    slug.extend(c.to_lowercase());
+                } else {
+                    pending_dash = true;
+                }
+            }
+            Some(Internable::String(slug))
+        },
+        _ => None
+    }
+}
+
 pub fn string_substring(params: Vec<&Internable>) -> Option<Internable> {
     let params_slice = params.as_slice();
     match params_slice {
@@ -1662,92 +2935,706 @@ pub fn string_split_reverse(params: Vec<&Internable>) -> Option<Vec<Vec<Internab
     }
 }
 
-pub fn concat(params: Vec<&Internable>) -> Option<Internable> {
-    let mut result = String::new();
-    for param in params {
-        match param {
-            &Internable::String(ref string) => {
-                result.push_str(string);
-            },
-            &Internable::Number(_) => {
-                result.push_str(&Internable::to_number(param).to_string());
-            },
-            _ => {}
-        }
+// Urls are split up the way RFC 3986 describes, without checking that each part only
+// holds characters it's allowed to. Parts that aren't there come back as "", and the
+// port falls back to the scheme's default when it's one we know.
+fn default_port(scheme:&str) -> Option<u16> {
+    match scheme {
+        "http" | "ws" => Some(80),
+        "https" | "wss" => Some(443),
+        "ftp" => Some(21),
+        _ => None,
     }
-    Some(Internable::String(result))
 }
 
-pub fn gen_id(params: Vec<&Internable>) -> Option<Internable> {
-    let mut result = String::new();
-    for param in params {
-        match param {
-            &Internable::String(ref string) => {
-                result.push_str(string);
-                result.push_str("|");
-            },
-            &Internable::Number(_) => {
-                result.push_str(&Internable::to_number(param).to_string());
-                result.push_str("|");
-            },
-            _ => {}
-        }
+fn split_scheme(url:&str) -> (&str, &str) {
+    if let Some(ix) = url.find(':') {
+        let scheme = &url[..ix];
+        let mut chars = scheme.chars();
+        let valid = match chars.next() {
+            Some(first) => first.is_alphabetic() && chars.all(|c| c.is_alphanumeric() || c == '+' || c == '-' || c == '.'),
+            None => false,
+        };
+        if valid { return (scheme, &url[ix + 1..]); }
     }
-    Some(Internable::String(result))
+    ("", url)
 }
 
-pub fn eve_type_of(params: Vec<&Internable>) -> Option<Internable> {
-    match params.get(0) {
-        Some(&&Internable::String(_)) => Some(Internable::String("string".to_owned())),
-        Some(&&Internable::Number(_)) => Some(Internable::String("number".to_owned())),
-        _ => { panic!("Type of called without a valid parameter") }
+pub fn percent_encode(text:&str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
     }
-}
-
-pub fn eve_parse_value(params: Vec<&Internable>) -> Option<Internable> {
-    match params.get(0) {
-        Some(&&Internable::String(ref s)) => {
-            let mut state = ParseState::new(s.as_ref());
-            let result = parser::number(&mut state);
-            match result {
-                ParseResult::Ok(Node::Pos(_, box Node::Float(f))) => { Some(Internable::from_number(f)) }
-                ParseResult::Ok(Node::Pos(_, box Node::Integer(i))) => { Some(Internable::from_number(i as f32)) }
-                _ => {
-                    Some(Internable::String(s.to_owned()))
+    encoded
+}
+
+// Query strings use `+` for spaces, everything else only has escapes.
+pub fn percent_decode(text:&str, plus_as_space:bool) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut ix = 0;
+    while ix < bytes.len() {
+        match bytes[ix] {
+            b'%' => {
+                if ix + 2 >= bytes.len() { return None; }
+                match ((bytes[ix + 1] as char).to_digit(16), (bytes[ix + 2] as char).to_digit(16)) {
+                    (Some(high), Some(low)) => decoded.push((high * 16 + low) as u8),
+                    _ => return None,
                 }
+                ix += 3;
             }
+            b'+' if plus_as_space => { decoded.push(b' '); ix += 1; }
+            byte => { decoded.push(byte); ix += 1; }
         }
-        Some(me @ &&Internable::Number(_)) => Some((*me).clone()),
-        _ => { panic!("Type of called without a valid parameter") }
     }
+    String::from_utf8(decoded).ok()
 }
 
-//-------------------------------------------------------------------------
-// Aggregates
-//-------------------------------------------------------------------------
+pub fn url_parse(params: Vec<&Internable>) -> Option<Vec<Vec<Internable>>> {
+    match params.as_slice() {
+        &[&Internable::String(ref text)] => {
+            let text = text.trim();
+            let (rest, fragment) = match text.find('#') {
+                Some(ix) => (&text[..ix], &text[ix + 1..]),
+                None => (text, ""),
+            };
+            let (rest, query) = match rest.find('?') {
+                Some(ix) => (&rest[..ix], &rest[ix + 1..]),
+                None => (rest, ""),
+            };
+            let (scheme, rest) = split_scheme(rest);
+            let (authority, path) = if rest.starts_with("//") {
+                let rest = &rest[2..];
+                match rest.find('/') {
+                    Some(ix) => (&rest[..ix], &rest[ix..]),
+                    None => (rest, "/"),
+                }
+            } else {
+                ("", rest)
+            };
+            // user info isn't worth exposing, and shouldn't leak into the host
+            let host_port = match authority.rfind('@') {
+                Some(ix) => &authority[ix + 1..],
+                None => authority,
+            };
+            // ipv6 hosts are bracketed since they're full of colons themselves
+            let port_start = if host_port.starts_with('[') {
+                host_port.find(']').and_then(|end| host_port[end..].find(':').map(|ix| ix + end))
+            } else {
+                host_port.rfind(':')
+            };
+            let (host, port) = match port_start {
+                Some(ix) => {
+                    let port = match host_port[ix + 1..].parse::<u16>() {
+                        Ok(port) => port,
+                        Err(_) => return None,
+                    };
+                    (&host_port[..ix], Some(port))
+                }
+                None => (host_port, default_port(&scheme.to_lowercase())),
+            };
+            let port = port.map_or(Internable::String("".to_string()), |port| Internable::from_number(port as f32));
+            Some(vec![vec![
+                Internable::String(scheme.to_lowercase()),
+                Internable::String(host.to_lowercase()),
+                port,
+                Internable::String(path.to_string()),
+                Internable::String(query.to_string()),
+                Internable::String(fragment.to_string()),
+            ]])
+        },
+        _ => { None }
+    }
+}
 
-pub fn aggregate_sum_add(current: &mut AggregateEntry, params: &Vec<Internable>, _: &Vec<Internable>) {
+// Takes either a whole url or just its query and produces a row per parameter, so
+// repeated keys show up once per value.
+pub fn url_query_param(params: Vec<&Internable>) -> Option<Vec<Vec<Internable>>> {
     match params.as_slice() {
-        &[ref param @ Internable::Number(_)] => {
-            let value = Internable::to_number(param);
-            match current {
-                &mut AggregateEntry::Result(ref mut res) => { *res = *res + value; }
-                _ => { *current = AggregateEntry::Result(value); }
+        &[&Internable::String(ref text)] => {
+            let text = match text.find('#') {
+                Some(ix) => &text[..ix],
+                None => &text[..],
+            };
+            let query = match text.find('?') {
+                Some(ix) => &text[ix + 1..],
+                None if !text.contains("://") => text,
+                None => "",
+            };
+            let mut results = vec![];
+            for (ix, pair) in query.split('&').filter(|pair| pair.len() > 0).enumerate() {
+                let (key, value) = match pair.find('=') {
+                    Some(split) => (&pair[..split], &pair[split + 1..]),
+                    None => (pair, ""),
+                };
+                match (percent_decode(key, true), percent_decode(value, true)) {
+                    (Some(key), Some(value)) => {
+                        results.push(vec![Internable::String(key), Internable::String(value), Internable::from_number((ix + 1) as f32)]);
+                    }
+                    _ => return None,
+                }
             }
-        }
-        _ => {}
-    };
+            Some(results)
+        },
+        _ => { None }
+    }
 }
 
-pub fn aggregate_sum_remove(current: &mut AggregateEntry, params: &Vec<Internable>, _: &Vec<Internable>) {
+fn url_component(value:&Internable) -> Option<String> {
+    match value {
+        &Internable::String(ref text) => Some(text.to_string()),
+        &Internable::Number(_) | &Internable::Decimal(_) => Some(value.print()),
+        _ => None,
+    }
+}
+
+pub fn url_encode(params: Vec<&Internable>) -> Option<Internable> {
     match params.as_slice() {
-        &[ref param @ Internable::Number(_)] => {
+        &[value] => url_component(value).map(|text| Internable::String(percent_encode(&text))),
+        _ => None
+    }
+}
+
+pub fn url_decode(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[&Internable::String(ref text)] => percent_decode(text, false).map(Internable::String),
+        _ => None
+    }
+}
+
+// Encodes a single `key=value` pair, a whole query string is built by joining them,
+// e.g. `query = gather/string-join[value: pair separator: "&"]`.
+pub fn url_encode_query(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[key, value] => {
+            match (url_component(key), url_component(value)) {
+                (Some(key), Some(value)) => Some(Internable::String(format!("{}={}", percent_encode(&key), percent_encode(&value)))),
+                _ => None,
+            }
+        },
+        _ => None
+    }
+}
+
+// Css selectors over an html document, with a row per matching element in document
+// order. Without an attribute the value is the element's inner html, with one it's
+// that attribute and elements that don't have it are left out.
+pub fn html_select(params: Vec<&Internable>) -> Option<Vec<Vec<Internable>>> {
+    let (document, selector, attribute) = match params.as_slice() {
+        &[&Internable::String(ref document), &Internable::String(ref selector), &Internable::Null] => (document, selector, None),
+        &[&Internable::String(ref document), &Internable::String(ref selector), &Internable::String(ref attribute)] => (document, selector, Some(attribute)),
+        _ => return None,
+    };
+    let selector = match Selector::parse(selector) {
+        Ok(selector) => selector,
+        Err(_) => return None,
+    };
+    let html = Html::parse_document(document);
+    let mut results = vec![];
+    for element in html.select(&selector) {
+        let value = match attribute {
+            Some(attribute) => match element.value().attr(attribute) {
+                Some(value) => value.to_string(),
+                None => continue,
+            },
+            None => element.inner_html(),
+        };
+        let text:String = element.text().collect();
+        // numbered among the rows we return, skipping elements without the attribute
+        let ix = results.len() + 1;
+        results.push(vec![
+            Internable::from_number(ix as f32),
+            Internable::String(element.value().name().to_string()),
+            Internable::String(text.trim().to_string()),
+            Internable::String(value),
+        ]);
+    }
+    Some(results)
+}
+
+// Timestamps are milliseconds since the unix epoch. They don't fit in an f32
+// without losing whole seconds, so they're carried as integral decimals which
+// gives us the full 64 bits.
+const DATE_DEFAULT_FORMAT:&'static str = "%Y-%m-%dT%H:%M:%SZ";
+
+fn to_timestamp(value:&Internable) -> Option<i64> {
+    match value {
+        &Internable::Decimal(ref decimal) if decimal.scale() == 0 => Some(decimal.mantissa()),
+        &Internable::Decimal(ref decimal) => Some(decimal.to_float().round() as i64),
+        &Internable::Number(_) => Some(Internable::to_number(value) as i64),
+        _ => None
+    }
+}
+
+fn from_timestamp(millis:i64) -> Internable {
+    Internable::Decimal(Decimal::new(millis, 0))
+}
+
+fn date_unit_millis(unit:&Internable) -> Option<i64> {
+    match unit {
+        &Internable::String(ref unit) => match &unit[..] {
+            "millisecond" | "milliseconds" => Some(1),
+            "second" | "seconds" => Some(1000),
+            "minute" | "minutes" => Some(60 * 1000),
+            "hour" | "hours" => Some(60 * 60 * 1000),
+            "day" | "days" => Some(24 * 60 * 60 * 1000),
+            "week" | "weeks" => Some(7 * 24 * 60 * 60 * 1000),
+            _ => None
+        },
+        // no unit means milliseconds
+        &Internable::Null => Some(1),
+        _ => None
+    }
+}
+
+fn date_format_string(format:&Internable) -> Option<&str> {
+    match format {
+        &Internable::String(ref format) => Some(&format[..]),
+        &Internable::Null => Some(DATE_DEFAULT_FORMAT),
+        _ => None
+    }
+}
+
+pub fn date_now(_: Vec<&Internable>) -> Option<Internable> {
+    let now = time::get_time();
+    Some(from_timestamp(now.sec * 1000 + (now.nsec / 1_000_000) as i64))
+}
+
+pub fn date_parse(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[&Internable::String(ref text), format] => {
+            date_format_string(format)
+                .and_then(|format| time::strptime(text, format).ok())
+                .map(|tm| {
+                    let spec = tm.to_timespec();
+                    from_timestamp(spec.sec * 1000 + (spec.nsec / 1_000_000) as i64)
+                })
+        },
+        _ => { None }
+    }
+}
+
+pub fn date_format(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[timestamp, format] => {
+            match (to_timestamp(timestamp), date_format_string(format)) {
+                (Some(millis), Some(format)) => {
+                    let mut sec = millis / 1000;
+                    let mut rem = millis % 1000;
+                    if rem < 0 { sec -= 1; rem += 1000; }
+                    let tm = time::at_utc(time::Timespec::new(sec, (rem * 1_000_000) as i32));
+                    time::strftime(format, &tm).ok().map(Internable::String)
+                },
+                _ => None
+            }
+        },
+        _ => { None }
+    }
+}
+
+pub fn date_add(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[timestamp, amount @ &Internable::Number(_), unit] => {
+            match (to_timestamp(timestamp), date_unit_millis(unit)) {
+                (Some(millis), Some(unit)) => {
+                    let delta = (Internable::to_number(amount) as f64 * unit as f64).round() as i64;
+                    millis.checked_add(delta).map(from_timestamp)
+                },
+                _ => None
+            }
+        },
+        _ => { None }
+    }
+}
+
+pub fn date_diff(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[from, to, unit] => {
+            match (to_timestamp(from), to_timestamp(to), date_unit_millis(unit)) {
+                (Some(from), Some(to), Some(unit)) => {
+                    Some(Internable::from_number(((to - from) as f64 / unit as f64) as f32))
+                },
+                _ => None
+            }
+        },
+        _ => { None }
+    }
+}
+
+pub fn concat(params: Vec<&Internable>) -> Option<Internable> {
+    let mut result = String::new();
+    for param in params {
+        match param {
+            &Internable::String(ref string) | &Internable::Reference(ref string) => {
+                result.push_str(string);
+            },
+            &Internable::Number(_) | &Internable::Decimal(_) | &Internable::Scoped(..) => {
+                result.push_str(&Internable::to_string(param));
+            },
+            _ => {}
+        }
+    }
+    Some(Internable::String(result))
+}
+
+pub fn gen_id(params: Vec<&Internable>) -> Option<Internable> {
+    let mut result = String::new();
+    for param in params {
+        match param {
+            &Internable::String(ref string) | &Internable::Reference(ref string) => {
+                result.push_str(string);
+                result.push_str("|");
+            },
+            &Internable::Number(_) | &Internable::Decimal(_) | &Internable::Scoped(..) => {
+                result.push_str(&Internable::to_string(param));
+                result.push_str("|");
+            },
+            _ => {}
+        }
+    }
+    Some(Internable::Reference(result))
+}
+
+// A version 4 UUID: random apart from the version and variant bits.
+fn format_uuid(high:u64, low:u64) -> String {
+    let high = high & 0xffff_ffff_ffff_0fff | 0x4000;
+    let low = low & 0x3fff_ffff_ffff_ffff | 0x8000_0000_0000_0000;
+    format!("{:08x}-{:04x}-{:04x}-{:04x}-{:012x}", high >> 32, (high >> 16) & 0xffff, high & 0xffff, low >> 48, low & 0xffff_ffff_ffff)
+}
+
+fn uuid_v4() -> String {
+    format_uuid(rand::random::<u64>(), rand::random::<u64>())
+}
+
+pub fn random_uuid(_: Vec<&Internable>) -> Option<Internable> {
+    Some(Internable::String(uuid_v4()))
+}
+
+pub fn gen_uuid(_: Vec<&Internable>) -> Option<Internable> {
+    Some(Internable::Reference(format!("{}|", uuid_v4())))
+}
+
+//-------------------------------------------------------------------------
+// Record ids
+//-------------------------------------------------------------------------
+
+pub type IdFunction = Fn(&[Internable]) -> String + Send + Sync;
+
+/// How a program makes the ids of the records its blocks create. Content hashes, the
+/// default, are built from a record's identifying attributes, so the same record always
+/// gets the same id: that's what lets a bound record be retracted once its search stops
+/// matching, and it keeps ids stable from one run to the next. UUIDs are unique across
+/// programs but fresh every time a block produces the record, so they're only used for
+/// committed records; bound ones keep their content hash. A custom generator is handed the identifying values
+/// and returns the id, which gets a trailing `|` if it's missing so it's written like
+/// every other id.
+#[derive(Clone)]
+pub enum IdGenerator {
+    ContentHash,
+    Uuid,
+    Custom(Arc<Box<IdFunction>>),
+}
+
+impl IdGenerator {
+    pub fn custom<F>(func:F) -> IdGenerator where F: Fn(&[Internable]) -> String + Send + Sync + 'static {
+        IdGenerator::Custom(Arc::new(Box::new(func)))
+    }
+
+    // Points the block's gen_id constraints at this generator, returning whether any
+    // changed. A deterministic program draws its UUIDs from the seeded RNG.
+    fn apply(&self, constraints:&mut Vec<Constraint>, determinism:Option<&Determinism>) -> bool {
+        // a bound record that got a new id every time it was derived could never be
+        // retracted, so only the ids of committed records can be fresh
+        let bound:Vec<Field> = constraints.iter().filter_map(|constraint| match constraint {
+            &Constraint::Insert { e, commit: false, .. } => Some(e),
+            _ => None,
+        }).collect();
+        let mut changed = false;
+        for constraint in constraints.iter_mut() {
+            let replacement = match constraint {
+                &mut Constraint::Function { ref op, ref params, output, .. } if op == "gen_id" => match self {
+                    &IdGenerator::ContentHash => continue,
+                    &IdGenerator::Uuid if bound.contains(&output) => continue,
+                    &IdGenerator::Uuid => match determinism {
+                        Some(determinism) => {
+                            let determinism = determinism.clone();
+                            replace_function("gen_id", params, output, Box::new(move |_:&[Internable]| {
+                                Some(vec![Internable::Reference(format!("{}|", determinism.uuid()))])
+                            }))
+                        }
+                        None => {
+                            let mut function = make_function("gen_id", params.clone(), output);
+                            if let Constraint::Function { ref mut func, .. } = function {
+                                *func = gen_uuid;
+                            }
+                            function
+                        }
+                    },
+                    &IdGenerator::Custom(ref generate) => {
+                        let generate = generate.clone();
+                        replace_function("gen_id", params, output, Box::new(move |params:&[Internable]| {
+                            let mut id = (**generate)(params);
+                            if !id.ends_with("|") { id.push('|'); }
+                            Some(vec![Internable::Reference(id)])
+                        }))
+                    }
+                },
+                _ => continue,
+            };
+            *constraint = replacement;
+            changed = true;
+        }
+        changed
+    }
+}
+
+// A scalar function swapped for a closure, which is how a program hands its own state
+// (an id generator, a seeded RNG) to functions that are otherwise plain fn pointers.
+fn replace_function(op:&str, params:&Vec<Field>, output:Field, func:Box<CustomFunction>) -> Constraint {
+    let param_mask = make_register_mask(params.iter().collect::<Vec<&Field>>());
+    let output_mask = make_register_mask(vec![&output]);
+    Constraint::CustomFunction { op: op.to_string(), func: Arc::new(single_row(func)), params: params.clone(), outputs: vec![output], param_mask, output_mask }
+}
+
+// A function returning at most one row as one returning any number of them.
+fn single_row(func:Box<CustomFunction>) -> Box<CustomMultiFunction> {
+    Box::new(move |params:&[Internable]| func(params).into_iter().collect())
+}
+
+//-------------------------------------------------------------------------
+// Tag aliases
+//-------------------------------------------------------------------------
+
+// Renaming a tag in a long-lived program shouldn't mean editing every block and
+// migrating every record in one go. Blocks registered after a tag is aliased or
+// deprecated have the old name swapped for the new one wherever they search for, add
+// or remove it, so they only ever deal in the new name. What happens to records still
+// carrying the old tag depends on which it was:
+//
+// - an alias is bridged: a bind gives every record tagged `#old` the new tag as well,
+//   so old data shows up under the new name without being touched.
+// - a deprecated tag is migrated: a commit moves every record tagged `#old` over to the
+//   new tag for good, and any block that still mentions `#old` is reported as an
+//   `@system [#system/deprecated-tag block deprecated replacement]`.
+#[derive(Debug, Clone, Copy)]
+struct TagAlias {
+    tag: Interned,
+    deprecated: bool,
+}
+
+// Swaps aliased tag values in the block's constraints, returning the aliases it used.
+fn alias_tags(constraints:&mut Vec<Constraint>, tag_attribute:Interned, aliases:&HashMap<Interned, TagAlias>) -> Vec<(Interned, TagAlias)> {
+    let mut used = vec![];
+    for constraint in constraints.iter_mut() {
+        let (a, value) = match constraint {
+            &mut Constraint::Scan { a, ref mut v, .. } |
+            &mut Constraint::LookupCommit { a, ref mut v, .. } |
+            &mut Constraint::Insert { a, ref mut v, .. } |
+            &mut Constraint::Remove { a, ref mut v, .. } => (a, v),
+            _ => continue,
+        };
+        if a != Field::Value(tag_attribute) { continue; }
+        if let Field::Value(old) = *value {
+            if let Some(alias) = aliases.get(&old) {
+                *value = Field::Value(alias.tag);
+                used.push((old, *alias));
+            }
+        }
+    }
+    used
+}
+
+fn deprecated_tag_id(block:&str, tag:&str) -> Internable {
+    Internable::Reference(format!("system/deprecated-tag|{}|{}|", block, tag))
+}
+
+//-------------------------------------------------------------------------
+// Determinism
+//-------------------------------------------------------------------------
+
+// Everything a deterministic program would otherwise get from the outside world. The
+// RNG is shared by all of the program's blocks, so the values it hands out depend only
+// on the order blocks run in, which is itself fixed for a given program and input.
+#[derive(Clone)]
+pub struct Determinism {
+    rng: Arc<Mutex<XorShiftRng>>,
+}
+
+impl Determinism {
+    pub fn new(seed:u64) -> Determinism {
+        let rng = XorShiftRng::from_seed([0x193a_6754, seed as u32, (seed >> 32) as u32, 0xa8a7_d469]);
+        Determinism { rng: Arc::new(Mutex::new(rng)) }
+    }
+
+    fn uuid(&self) -> String {
+        let mut rng = self.rng.lock().unwrap();
+        let high = rng.next_u64();
+        format_uuid(high, rng.next_u64())
+    }
+
+    // Swaps random/uuid for one drawing from the seeded RNG and freezes date/now at the
+    // epoch, returning whether anything changed.
+    fn apply(&self, constraints:&mut Vec<Constraint>) -> bool {
+        let mut changed = false;
+        for constraint in constraints.iter_mut() {
+            let replacement = match constraint {
+                &mut Constraint::Function { ref op, ref params, output, .. } if op == "random/uuid" => {
+                    let determinism = self.clone();
+                    replace_function(op, params, output, Box::new(move |_:&[Internable]| Some(vec![Internable::String(determinism.uuid())])))
+                }
+                &mut Constraint::Function { ref op, ref params, output, .. } if op == "date/now" => {
+                    replace_function(op, params, output, Box::new(|_:&[Internable]| Some(vec![from_timestamp(0)])))
+                }
+                _ => continue,
+            };
+            *constraint = replacement;
+            changed = true;
+        }
+        changed
+    }
+}
+
+pub fn eve_type_of(params: Vec<&Internable>) -> Option<Internable> {
+    match params.get(0) {
+        Some(&&Internable::String(_)) => Some(Internable::String("string".to_owned())),
+        Some(&&Internable::Number(_)) => Some(Internable::String("number".to_owned())),
+        Some(&&Internable::Decimal(_)) => Some(Internable::String("number".to_owned())),
+        Some(&&Internable::Reference(_)) => Some(Internable::String("record".to_owned())),
+        _ => None
+    }
+}
+
+pub fn eve_parse_value(params: Vec<&Internable>) -> Option<Internable> {
+    match params.get(0) {
+        Some(&&Internable::String(ref s)) => {
+            let mut state = ParseState::new(s.as_ref());
+            let result = parser::number(&mut state);
+            match result {
+                ParseResult::Ok(node) => match node.unwrap_pos() {
+                    Node::Float(f) => { Some(Internable::from_number(f)) }
+                    Node::Integer(i) => { Some(Internable::from_number(i as f32)) }
+                    Node::Decimal(d) => { Some(Internable::Decimal(d)) }
+                    _ => { Some(Internable::String(s.to_owned())) }
+                },
+                _ => {
+                    Some(Internable::String(s.to_owned()))
+                }
+            }
+        }
+        Some(me @ &&Internable::Number(_)) => Some((*me).clone()),
+        Some(me @ &&Internable::Decimal(_)) => Some((*me).clone()),
+        _ => None
+    }
+}
+
+//-------------------------------------------------------------------------
+// Graph
+//-------------------------------------------------------------------------
+
+// Both of these walk the edges stored under a single attribute, i.e. a record
+// `[#node edge: other]` is an edge from the record to `other`. The index keeps a
+// graph for each attribute they've been asked about and updates it with every
+// change, so a call only pays for the answer, not for rebuilding the graph. A block
+// that also searches for the edge attribute gets recomputed whenever an edge is
+// added or removed.
+
+pub fn graph_shortest_path(index: &mut HashIndex, interner: &mut Interner, params: Vec<Interned>) -> Option<Vec<Vec<Interned>>> {
+    let (from, to, edge) = match params.as_slice() {
+        &[from, to, edge] if from > 0 && to > 0 && edge > 0 => (from, to, edge),
+        _ => return None,
+    };
+    let path = match index.graph(edge).shortest_path(from, to) {
+        Some(path) => path,
+        None => return None,
+    };
+    Some(path.iter().enumerate().map(|(ix, node)| {
+        vec![*node, interner.number_id((ix + 1) as f32)]
+    }).collect())
+}
+
+pub fn graph_components(index: &mut HashIndex, _: &mut Interner, params: Vec<Interned>) -> Option<Vec<Vec<Interned>>> {
+    let edge = match params.as_slice() {
+        &[edge] if edge > 0 => edge,
+        _ => return None,
+    };
+    Some(index.graph(edge).components().into_iter().map(|(node, component)| vec![node, component]).collect())
+}
+
+//-------------------------------------------------------------------------
+// Text search
+//-------------------------------------------------------------------------
+
+pub fn tokenize(text:&str) -> Vec<String> {
+    UnicodeSegmentation::unicode_words(text).map(|word| word.to_lowercase()).collect()
+}
+
+// Scores every record with a string value for the given attribute against the
+// query using BM25, treating all of a record's values as a single document. The
+// terms come from the attribute's text index, which follows the attribute as it
+// changes, so a block that also searches for the attribute keeps its scores current.
+pub fn search_text(index: &mut HashIndex, interner: &mut Interner, params: Vec<Interned>) -> Option<Vec<Vec<Interned>>> {
+    let (query, attribute) = match params.as_slice() {
+        &[query, attribute] if query > 0 && attribute > 0 => (query, attribute),
+        _ => return None,
+    };
+    let query_terms = match interner.get_value(query) {
+        &Internable::String(ref text) => tokenize(text),
+        _ => return None,
+    };
+    let results = index.text(attribute, interner).score(&query_terms);
+    if results.len() == 0 { return None; }
+    Some(results.into_iter().map(|(e, score)| vec![e, interner.number_id(score)]).collect())
+}
+
+//-------------------------------------------------------------------------
+// Aggregates
+//-------------------------------------------------------------------------
+
+// Once a decimal shows up in a sum, the whole sum is carried as a decimal.
+fn aggregate_decimal_sum(current: &mut AggregateEntry, param: &Internable, negate: bool) {
+    let value = match to_decimal(param) {
+        Some(value) => if negate { value.negate() } else { value },
+        None => return,
+    };
+    let prev = match current {
+        &mut AggregateEntry::Decimal(prev) => Some(prev),
+        &mut AggregateEntry::Result(res) => Decimal::from_f32(res),
+        _ => Some(Decimal::new(0, 0)),
+    };
+    if let Some(sum) = prev.and_then(|prev| prev.add(value)) {
+        *current = AggregateEntry::Decimal(sum);
+    }
+}
+
+pub fn aggregate_sum_add(current: &mut AggregateEntry, params: &Vec<Internable>, _: &Vec<Internable>) {
+    match params.as_slice() {
+        &[ref param @ Internable::Number(_)] => {
+            let value = Internable::to_number(param);
+            match current {
+                &mut AggregateEntry::Result(ref mut res) => { *res = *res + value; }
+                &mut AggregateEntry::Decimal(_) => { aggregate_decimal_sum(current, param, false); }
+                _ => { *current = AggregateEntry::Result(value); }
+            }
+        }
+        &[ref param @ Internable::Decimal(_)] => { aggregate_decimal_sum(current, param, false); }
+        _ => {}
+    };
+}
+
+pub fn aggregate_sum_remove(current: &mut AggregateEntry, params: &Vec<Internable>, _: &Vec<Internable>) {
+    match params.as_slice() {
+        &[ref param @ Internable::Number(_)] => {
             let value = Internable::to_number(param);
             match current {
                 &mut AggregateEntry::Result(ref mut res) => { *res = *res - value; }
+                &mut AggregateEntry::Decimal(_) => { aggregate_decimal_sum(current, param, true); }
                 _ => { *current = AggregateEntry::Result(-1.0 * value); }
             }
         }
+        &[ref param @ Internable::Decimal(_)] => { aggregate_decimal_sum(current, param, true); }
         _ => {}
     };
 }
@@ -1808,7 +3695,7 @@ pub fn aggregate_avg_remove(current: &mut AggregateEntry, params: &Vec<Internabl
 pub fn aggregate_string_join_add(current: &mut AggregateEntry, params: &Vec<Internable>, projection: &Vec<Internable>) {
     let value = params.iter().map(|x| {
         match x {
-            &Internable::Number(_) => { Internable::String(Internable::to_string(x)) },
+            &Internable::Number(_) | &Internable::Decimal(_) | &Internable::Reference(_) | &Internable::Scoped(..) => { Internable::String(Internable::to_string(x)) },
             &Internable::String(_) => { x.clone() },
             _ => unreachable!(),
         }
@@ -1848,7 +3735,7 @@ pub fn aggregate_string_join_add(current: &mut AggregateEntry, params: &Vec<Inte
 pub fn aggregate_string_join_remove(current: &mut AggregateEntry, params: &Vec<Internable>, projection: &Vec<Internable>) {
     let value = params.iter().map(|x| {
         match x {
-            &Internable::Number(_) => { Internable::String(Internable::to_string(x)) },
+            &Internable::Number(_) | &Internable::Decimal(_) | &Internable::Reference(_) | &Internable::Scoped(..) => { Internable::String(Internable::to_string(x)) },
             &Internable::String(_) => { x.clone() },
             _ => unreachable!(),
         }
@@ -2326,12 +4213,21 @@ impl OutputRounds {
     }
 }
 
+// The round and commit maps hash with FNV rather than a random seed: the order changes
+// come out of them is the order blocks run in, and a deterministic program needs that to
+// be the same from one run to the next.
 pub struct RoundHolder {
-    rounds: Vec<HashMap<(Interned,Interned,Interned), Change>>,
-    commits: HashMap<(Interned, Interned, Interned, Interned), (ChangeType, Change)>,
+    rounds: Vec<HashMap<(Interned,Interned,Interned), Change, MyHasher>>,
+    commits: HashMap<(Interned, Interned, Interned, Interned), (ChangeType, Change), MyHasher>,
     staged_commit_keys: Vec<(Interned, Interned, Interned, Interned)>,
     collapsed_commits: CollapsedChanges,
+    spill: Option<Spill>,
+    pub spilled: usize,
     pub max_round: usize,
+    /// Commits that just repeated a change already committed in the same frame.
+    pub duplicates: usize,
+    /// Inserts dropped because the fact was already committed.
+    pub redundant: usize,
 }
 
 
@@ -2339,15 +4235,57 @@ impl RoundHolder {
     pub fn new() -> RoundHolder {
         let mut rounds = vec![];
         for _ in 0..100 {
-            rounds.push(HashMap::new());
+            rounds.push(HashMap::default());
+        }
+        RoundHolder { rounds, commits:HashMap::default(), staged_commit_keys:vec![], collapsed_commits:CollapsedChanges::new(), spill: None, spilled: 0, max_round: 0, duplicates: 0, redundant: 0 }
+    }
+
+    pub fn spill_to(&mut self, threshold:usize, dir:PathBuf) {
+        self.spill = Some(Spill::new(threshold, dir));
+    }
+
+    fn spill_round(&mut self, round:usize) {
+        let spill = self.spill.as_mut().unwrap();
+        if spill.has_failed() { return; }
+        let changes:Vec<Change> = self.rounds[round].values().filter(|change| change.count != 0).cloned().collect();
+        let len = changes.len();
+        if spill.write_run(round, changes) {
+            self.rounds[round].clear();
+            self.spilled += len;
         }
-        RoundHolder { rounds, commits:HashMap::new(), staged_commit_keys:vec![], collapsed_commits:CollapsedChanges::new(), max_round: 0 }
+    }
+
+    pub fn has_spilled(&self, round:usize) -> bool {
+        self.spill.as_ref().map_or(false, |spill| spill.has_runs(round))
+    }
+
+    /// Why spilling failed, if it did, which also lets rounds spill again.
+    pub fn take_spill_error(&mut self) -> Option<String> {
+        self.spill.as_mut().and_then(|spill| spill.take_error())
+    }
+
+    pub fn is_merging(&self) -> bool {
+        self.spill.as_ref().map_or(false, |spill| spill.is_merging())
+    }
+
+    fn start_merge(&mut self, round:usize) {
+        // whatever's left in memory becomes one more run, so everything comes back sorted
+        if self.rounds[round].len() > 0 { self.spill_round(round); }
+        self.spill.as_mut().unwrap().start_merge(round);
+    }
+
+    fn merge_batch(&mut self, batch:&mut Vec<Change>) {
+        self.spill.as_mut().unwrap().merge_batch(batch);
     }
 
     pub fn insert(&mut self, change:Change) {
         let key = (change.e, change.a, change.v);
         let round = change.round as usize;
         self.max_round = cmp::max(round, self.max_round);
+        // long frames can go past the rounds we started with, iterating looks one round ahead
+        while self.rounds.len() < round + 2 {
+            self.rounds.push(HashMap::default());
+        }
         match self.rounds[round].entry(key) {
             Entry::Occupied(mut o) => {
                 o.get_mut().count += change.count;
@@ -2356,6 +4294,9 @@ impl RoundHolder {
                 o.insert(change);
             }
         };
+        if let Some(threshold) = self.spill.as_ref().map(|spill| spill.threshold) {
+            if self.rounds[round].len() >= threshold { self.spill_round(round); }
+        }
     }
 
     pub fn commit(&mut self, change:Change, change_type:ChangeType) {
@@ -2365,7 +4306,11 @@ impl RoundHolder {
         }
         match self.commits.entry(key) {
             Entry::Occupied(mut o) => {
-                o.get_mut().1.count += change.count;
+                // the counts have to add up whatever order they came in, a change that just
+                // repeats the one already there is only noted as a duplicate
+                let existing = &mut o.get_mut().1;
+                if existing.count.signum() == change.count.signum() { self.duplicates += 1; }
+                existing.count += change.count;
             }
             Entry::Vacant(o) => {
                 o.insert((change_type, change));
@@ -2373,7 +4318,9 @@ impl RoundHolder {
         };
     }
 
-    pub fn prepare_commits(&mut self, index:&mut HashIndex, distinct_index:&mut DistinctIndex) -> bool {
+    // Commits whose attribute `is_readonly` are held back from the index and handed back
+    // in `rejected` so the program can report them.
+    pub fn prepare_commits<F>(&mut self, index:&mut HashIndex, distinct_index:&mut DistinctIndex, rejected:&mut Vec<Change>, is_readonly:F) -> bool where F: Fn(Interned) -> bool {
         for key in self.staged_commit_keys.iter() {
             match self.commits.get(key) {
                 Some(&(ChangeType::Remove, Change {count, e, a, v, n, transaction, round})) => {
@@ -2440,6 +4387,16 @@ impl RoundHolder {
         // @FIXME: There should be some way for us to not have to allocate a vec here
         let drained = { self.collapsed_commits.drain().collect::<Vec<Change>>() };
         for change in drained {
+            if is_readonly(change.a) {
+                rejected.push(change);
+                continue;
+            }
+            // inserting a fact that's already committed wouldn't change anything, and
+            // leaving it out can save a whole frame
+            if change.count > 0 && change.e > 0 && change.a > 0 && change.v > 0 && distinct_index.is_commit(change.e, change.a, change.v) {
+                self.redundant += 1;
+                continue;
+            }
             has_changes = true;
             // apply it
             distinct_index.distinct(&change, self);
@@ -2447,9 +4404,18 @@ impl RoundHolder {
         has_changes
     }
 
+    /// Drops whatever was committed in the current frame instead of feeding it back in.
+    pub fn discard_commits(&mut self) {
+        self.commits.clear();
+        self.staged_commit_keys.clear();
+    }
+
     pub fn clear(&mut self) {
-        for ix in 0..self.max_round {
-            self.rounds[ix].clear();
+        for round in self.rounds.iter_mut().take(self.max_round + 1) {
+            round.clear();
+        }
+        if let Some(ref mut spill) = self.spill {
+            spill.discard();
         }
         self.max_round = 0;
     }
@@ -2505,14 +4471,30 @@ impl RoundHolderIter {
             let ref mut cur_changes = self.cur_changes;
             cur_changes.clear();
             self.change_ix = 0;
-            for (_, change) in holder.rounds[round as usize].drain().filter(|v| v.1.count != 0) {
-                cur_changes.push(change);
+            if holder.has_spilled(round as usize) {
+                holder.start_merge(round as usize);
+                holder.merge_batch(cur_changes);
+            } else {
+                for (_, change) in holder.rounds[round as usize].drain().filter(|v| v.1.count != 0) {
+                    cur_changes.push(change);
+                }
             }
         }
         self.round_ix = (round as usize) + 1;
         &self.cur_changes
     }
 
+    /// The next batch of a spilled round that's being merged back in, empty once the
+    /// whole round has been handed out.
+    pub fn next_batch(&mut self, holder: &mut RoundHolder) -> &Vec<Change> {
+        self.cur_changes.clear();
+        self.change_ix = 0;
+        if holder.is_merging() {
+            holder.merge_batch(&mut self.cur_changes);
+        }
+        &self.cur_changes
+    }
+
 }
 
 //-------------------------------------------------------------------------
@@ -2529,7 +4511,48 @@ pub struct RuntimeState {
     pub interner: Interner,
     pub watch_indexes: HashMap<String, WatchIndex>,
     pub intermediates: IntermediateIndex,
-}
+    /// The outputs of the blocks running in distinct mode, by block id.
+    pub block_distinct: HashMap<Interned, BlockDistinct>,
+    pub provenance: Option<Provenance>,
+    /// Functions given arguments they can't take since the last transaction.
+    pub function_errors: Vec<FunctionError>,
+    pub schema: Schema,
+}
+
+impl RuntimeState {
+    /// Marks the indexes as they are now as what `roll_back` returns them to.
+    pub fn save_point(&mut self) {
+        self.distinct_index.save_point();
+        self.intermediates.save_point();
+        for outputs in self.block_distinct.values_mut() {
+            outputs.save_point();
+        }
+        for index in self.watch_indexes.values_mut() {
+            index.save_point();
+        }
+    }
+
+    /// Undoes everything done to the indexes since the last save point and drops the
+    /// changes still waiting to be run.
+    pub fn roll_back(&mut self) {
+        for ((e, a, v), was) in self.distinct_index.roll_back() {
+            if was {
+                self.index.insert_value(e, a, v, &self.interner);
+            } else {
+                self.index.remove_value(e, a, v, &self.interner);
+            }
+        }
+        self.intermediates.roll_back();
+        for outputs in self.block_distinct.values_mut() {
+            outputs.roll_back();
+        }
+        for index in self.watch_indexes.values_mut() {
+            index.roll_back();
+        }
+        self.rounds.discard_commits();
+        self.rounds.clear();
+    }
+}
 
 pub struct BlockInfo {
     pub pipe_lookup: HashMap<(Interned,Interned,Interned), Vec<Solver>>,
@@ -2545,6 +4568,69 @@ impl BlockInfo {
         &self.blocks[*ix]
     }
 
+    // Groups blocks that can affect each other, i.e. one writes something another
+    // one reads, directly or through a chain of other blocks. Blocks in different
+    // partitions never see each other's output.
+    pub fn partitions(&self) -> Vec<Vec<String>> {
+        let ids = self.partition_ids();
+        let mut groups = vec![vec![]; ids.iter().max().map_or(0, |&max| max + 1)];
+        for (block, &id) in self.blocks.iter().zip(ids.iter()) {
+            groups[id].push(block.name.to_string());
+        }
+        groups
+    }
+
+    // The partition of each block, numbered in the order of their first blocks.
+    pub fn partition_ids(&self) -> Vec<usize> {
+        let reads:Vec<Vec<BlockKey>> = self.blocks.iter().map(|block| block.reads()).collect();
+        let writes:Vec<Vec<BlockKey>> = self.blocks.iter().map(|block| block.writes()).collect();
+        let mut parents:Vec<usize> = (0..self.blocks.len()).collect();
+        fn root(parents:&mut Vec<usize>, ix:usize) -> usize {
+            let mut cur = ix;
+            while parents[cur] != cur { cur = parents[cur]; }
+            parents[ix] = cur;
+            cur
+        }
+        for writer in 0..self.blocks.len() {
+            for reader in 0..self.blocks.len() {
+                let connected = writes[writer].iter().any(|write| reads[reader].iter().any(|read| read.overlaps(write)));
+                if connected {
+                    let (a, b) = (root(&mut parents, writer), root(&mut parents, reader));
+                    if a != b { parents[cmp::max(a, b)] = cmp::min(a, b); }
+                }
+            }
+        }
+        // a group's root is its first block, so the ids come out in order
+        let mut ids:HashMap<usize, usize> = HashMap::new();
+        (0..self.blocks.len()).map(|ix| {
+            let group = root(&mut parents, ix);
+            let next = ids.len();
+            *ids.entry(group).or_insert(next)
+        }).collect()
+    }
+}
+
+// What a block reads or writes, with 0 standing in for anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlockKey {
+    Fact(Interned, Interned),
+    Intermediate(Interned),
+}
+
+impl BlockKey {
+    pub fn overlaps(&self, other:&BlockKey) -> bool {
+        match (*self, *other) {
+            (BlockKey::Fact(a, v), BlockKey::Fact(a2, v2)) => {
+                (a == 0 || a2 == 0 || a == a2) && (v == 0 || v2 == 0 || v == v2)
+            }
+            (BlockKey::Intermediate(id), BlockKey::Intermediate(id2)) => id == id2,
+            _ => false,
+        }
+    }
+}
+
+fn field_key(field:&Field) -> Interned {
+    if let &Field::Value(val) = field { val } else { 0 }
 }
 
 pub enum RunLoopMessage {
@@ -2555,7 +4641,17 @@ pub enum RunLoopMessage {
     Transaction(Vec<RawChange>),
     RemoteTransaction(Vec<RawRemoteChange>),
     CodeTransaction(Vec<Block>, Vec<String>),
-    RemoteCodeTransaction(Vec<PortableBlock>, Vec<String>)
+    RemoteCodeTransaction(Vec<PortableBlock>, Vec<String>),
+    Merge(Internable, Internable),
+    AnnotatedTransaction(Vec<RawChange>, Vec<(String, Internable)>),
+    ReplyTransaction(Vec<RawChange>, Sender<Vec<RawChange>>),
+    Admin(AdminCommand, Sender<AdminReply>),
+    Inspect,
+    Attach(String, Box<Watcher + Send>),
+    Detach(String),
+    WatcherError(String, String),
+    // a scope's complete new contents, see `Program::replace_scope`
+    ReplaceScope(String, Vec<(Internable, String, Internable)>),
 }
 
 impl RunLoopMessage {
@@ -2613,7 +4709,413 @@ impl RunLoopMessage {
                         removed_blocks.len(),
                         removed_blocks.join(", "))
             }
+            &RunLoopMessage::Merge(ref keep, ref merge) => {
+                format!("`Merge` of {} into {}", Internable::to_string(merge), Internable::to_string(keep))
+            }
+            &RunLoopMessage::ReplyTransaction(ref changes, _) => {
+                format!("`Reply transaction` with {} changes", changes.len())
+            }
+            &RunLoopMessage::AnnotatedTransaction(ref changes, ref annotations) => {
+                let stringified_annotations = annotations.iter().map(|&(ref a, ref v)| format!("{}:{}", a, Internable::to_string(v)))
+                    .collect::<Vec<_>>().join(" ");
+                format!("`Annotated transaction` [{}] with {} changes", stringified_annotations, changes.len())
+            }
+            &RunLoopMessage::Admin(ref command, _) => {
+                format!("`Admin` command {:?}", command)
+            }
+            &RunLoopMessage::Inspect => "`Inspect message`".to_string(),
+            &RunLoopMessage::Attach(ref name, _) => format!("`Attach` watcher {}", name),
+            &RunLoopMessage::Detach(ref name) => format!("`Detach` watcher {}", name),
+            &RunLoopMessage::WatcherError(ref name, ref message) => format!("`Watcher error` from {}: {}", name, message),
+            &RunLoopMessage::ReplaceScope(ref scope, ref facts) => format!("`Replace scope` @{} with {} facts", scope, facts.len()),
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+// Scopes
+//-------------------------------------------------------------------------
+
+// How long facts committed to a scope stick around. Transaction scopes (@event) are
+// cleared as soon as the transaction that committed them reaches a fixpoint, session
+// scopes (@session, @browser) last as long as the program but are never persisted,
+// and everything else is persisted like normal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScopeRetention {
+    Transaction,
+    Session,
+    Persistent,
+}
+
+// A scope gets its own slice of the index: every fact committed to it is keyed by its
+// attribute in that scope, which is a value of its own kind rather than a name, so
+// `commit @event [#click]` and `search [#click]` never meet and no string, whatever it
+// spells, ever lands in a scope.
+pub fn scoped_attribute(scope:&str, attribute:&str) -> Internable {
+    Internable::Scoped(scope.to_string(), attribute.to_string())
+}
+
+// Blocks and watchers are described inside the @system scope, e.g.
+// `search @system [#system/block name constraints]`, so programs can inspect the
+// engine running them.
+fn system_block_id(name:&str) -> Internable {
+    Internable::Reference(format!("system/block|{}|", name))
+}
+
+fn perf_warning_id(name:&str) -> Internable {
+    Internable::Reference(format!("system/perf-warning|{}|", name))
+}
+
+pub fn attribute_scope(attribute:&Internable) -> Option<&str> {
+    match attribute {
+        &Internable::Scoped(ref scope, _) => Some(scope),
+        _ => None,
+    }
+}
+
+pub fn retention(scopes:&HashMap<String, ScopeRetention>, attribute:&Internable) -> ScopeRetention {
+    match attribute_scope(attribute) {
+        Some(scope) => scopes.get(scope).cloned().unwrap_or(ScopeRetention::Persistent),
+        None => ScopeRetention::Persistent,
+    }
+}
+
+// The attribute in a scope, for scoped sections whose attribute is only known once the
+// block runs, e.g. `commit @session lookup[record attribute value]`.
+pub fn scope_attribute(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[&Internable::String(ref scope), &Internable::String(ref attribute)] => Some(scoped_attribute(scope, attribute)),
+        _ => None,
+    }
+}
+
+// And back out of it, for the attributes a scoped search finds. Attributes in any other
+// scope, or in none, don't match.
+pub fn unscope_attribute(params: Vec<&Internable>) -> Option<Internable> {
+    match params.as_slice() {
+        &[&Internable::String(ref scope), &Internable::Scoped(ref attribute_scope, ref attribute)] if scope == attribute_scope => {
+            Some(Internable::String(attribute.to_string()))
         }
+        _ => None,
+    }
+}
+
+fn readonly_attribute(readonly_scopes:&HashSet<String>, interner:&Interner, attribute:Interned) -> bool {
+    if attribute == 0 { return false; }
+    attribute_scope(interner.get_value(attribute)).map(|scope| readonly_scopes.contains(scope)).unwrap_or(false)
+}
+
+fn mounted_attribute(prefix:&str, attribute:&Internable) -> Internable {
+    match attribute {
+        &Internable::Scoped(ref scope, ref name) => scoped_attribute(&format!("{}/{}", prefix, scope), name),
+        _ => attribute.clone(),
+    }
+}
+
+fn mount_constraint(interner:&mut Interner, constraint:&mut Constraint, prefix:&str, shared_reads:&[&str], shared_writes:&[&str]) {
+    let (a, v, shared) = match constraint {
+        &mut Constraint::Scan {ref mut a, ref mut v, ..} |
+        &mut Constraint::RangeScan {ref mut a, ref mut v, ..} |
+        &mut Constraint::LookupCommit {ref mut a, ref mut v, ..} => (a, Some(v), shared_reads),
+        &mut Constraint::Insert {ref mut a, ref mut v, ..} |
+        &mut Constraint::Remove {ref mut a, ref mut v, ..} => (a, Some(v), shared_writes),
+        &mut Constraint::RemoveAttribute {ref mut a, ..} => (a, None, shared_writes),
+        _ => return,
+    };
+    let attribute = match *a {
+        Field::Value(id) if id != 0 => interner.get_value(id).clone(),
+        _ => return,
+    };
+    let mounted = mounted_attribute(prefix, &attribute);
+    if mounted != attribute {
+        *a = Field::Value(interner.internable_to_id(mounted));
+    }
+    let is_tag = match attribute {
+        Internable::String(ref name) | Internable::Scoped(_, ref name) => name == "tag",
+        _ => false,
+    };
+    if let (true, Some(v)) = (is_tag, v) {
+        let tag = match *v {
+            Field::Value(id) if id != 0 => match interner.get_value(id) {
+                &Internable::String(ref tag) => tag.to_string(),
+                _ => return,
+            },
+            _ => return,
+        };
+        if !shared.contains(&&tag[..]) {
+            *v = interner.string(&format!("{}/{}", prefix, tag));
+        }
+    }
+}
+
+// Intermediate keys are named after the block that made them ("{block}|sub_block|not|0"),
+// so they get the mount prefix along with the block names, or a mounted block would
+// share intermediates with a host block of the same name.
+fn mounted_intermediate(prefix:&str, roots:&[&str], value:Internable) -> Internable {
+    if let Internable::String(ref key) = value {
+        let intermediate = key.contains("|sub_block|") && roots.iter().any(|root| {
+            key.starts_with(root) && key[root.len()..].starts_with("|")
+        });
+        if intermediate {
+            return Internable::String(format!("{}/{}", prefix, key));
+        }
+    }
+    value
+}
+
+//-------------------------------------------------------------------------
+// Inspection
+//-------------------------------------------------------------------------
+
+// What the last transaction cost. `changes` counts every change that flowed through,
+// including derived ones, `frames` is how many times commits had to be fed back in
+// before hitting a fixpoint and `rounds` is the deepest round any frame reached.
+// `added` and `removed` count the facts that actually went into or out of the index.
+// `deduplicated` counts commits that were dropped before reaching the index, because
+// they were emitted more than once in a frame or the fact was already committed.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TransactionStats {
+    pub changes: usize,
+    pub commits: usize,
+    pub frames: usize,
+    pub rounds: usize,
+    #[serde(default)]
+    pub added: usize,
+    #[serde(default)]
+    pub removed: usize,
+    /// Commits that were left out because they wouldn't have changed anything.
+    #[serde(default)]
+    pub deduplicated: usize,
+    pub ns: u64,
+}
+
+/// Sent to every `Program::on_fixpoint` receiver once a transaction has run to fixpoint
+/// and its watchers have been handed their diffs. Until the next transaction starts,
+/// the index is a consistent snapshot.
+#[derive(Debug, Clone)]
+pub struct Fixpoint {
+    /// How many transactions the program had run, counting this one.
+    pub transaction: u64,
+    pub stats: TransactionStats,
+    /// Why the transaction was stopped short of a real fixpoint, if it was.
+    pub error: Option<RuntimeError>,
+}
+
+//-------------------------------------------------------------------------
+// Runaway protection
+//-------------------------------------------------------------------------
+
+// Two blocks that commit in response to each other never reach a fixpoint, every frame
+// of commits sets up the next one, and a block that binds in response to itself never
+// finishes a frame. A transaction that goes past its limits is stopped at the round it's
+// on and that frame is undone, along with the changes that set it off: the commits of the
+// frame before or, for the transaction's first frame, its inputs. The program keeps
+// whatever the frames before it did. The error is kept for the host in
+// `Program::last_error`, handed to `on_fixpoint` listeners and shows up as
+// `@system [#eve/error kind message block]` with the next transaction, where `block` is
+// each block that ran in the last frame. It's taken back out once a later transaction
+// gets through cleanly or fails in its own way.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EvalLimits {
+    /// Rounds evaluated per transaction, counted across every frame.
+    pub max_rounds: usize,
+    /// Changes, input or derived, flowing through a transaction.
+    pub max_facts: usize,
+}
+
+impl Default for EvalLimits {
+    fn default() -> EvalLimits {
+        EvalLimits { max_rounds: 10_000, max_facts: 10_000_000 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    RoundLimit { limit: usize, blocks: Vec<String> },
+    FactLimit { limit: usize, blocks: Vec<String> },
+    /// The delivery log couldn't record what the watchers were sent. It's written out
+    /// again with the next transaction.
+    Delivery { message: String },
+    /// A round couldn't be spilled to disk or read back from it.
+    Spill { message: String },
+}
+
+impl RuntimeError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            &RuntimeError::RoundLimit { .. } => "round-limit",
+            &RuntimeError::FactLimit { .. } => "fact-limit",
+            &RuntimeError::Delivery { .. } => "delivery",
+            &RuntimeError::Spill { .. } => "spill",
+        }
+    }
+
+    /// The blocks that were still running when the transaction was stopped.
+    pub fn blocks(&self) -> &[String] {
+        match self {
+            &RuntimeError::RoundLimit { ref blocks, .. } |
+            &RuntimeError::FactLimit { ref blocks, .. } => blocks,
+            &RuntimeError::Delivery { .. } |
+            &RuntimeError::Spill { .. } => &[],
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &RuntimeError::RoundLimit { limit, .. } => write!(f, "The transaction was stopped after {} rounds without reaching a fixpoint", limit)?,
+            &RuntimeError::FactLimit { limit, .. } => write!(f, "The transaction was stopped after {} changes without reaching a fixpoint", limit)?,
+            &RuntimeError::Delivery { ref message } => write!(f, "What the watchers were sent couldn't be recorded: {}", message)?,
+            &RuntimeError::Spill { ref message } => write!(f, "The transaction was stopped because a round couldn't be spilled to disk: {}", message)?,
+        }
+        if !self.blocks().is_empty() {
+            write!(f, ", these blocks were still running: {}", self.blocks().join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn internal_record_id(kind:&str, name:&str) -> Internable {
+    Internable::Reference(format!("eve/internal|{}|{}|", kind, name))
+}
+
+//-------------------------------------------------------------------------
+// Performance warnings
+//-------------------------------------------------------------------------
+
+// A block should cost roughly in proportion to the changes fed to it. Every transaction
+// a block runs in is a sample of (changes in, join steps taken), and fitting
+// steps = k * changes^growth over its recent samples tells a block whose cost is
+// heading towards quadratic apart from one that's merely big. Those are reported as
+// `@system [#system/perf-warning block growth join]`, where `join` is the constraint
+// that took the most steps, which is usually the scan missing a shared variable.
+const PERF_SAMPLES:usize = 32;
+const PERF_MIN_SAMPLES:usize = 8;
+// the inputs have to vary by at least this factor for the curve to say anything
+const PERF_MIN_SPREAD:f64 = 4.0;
+// cheap blocks aren't worth warning about however they grow
+const PERF_MIN_STEPS:u64 = 5_000;
+pub const PERF_WARN_GROWTH:f64 = 1.7;
+
+#[derive(Default)]
+struct BlockCost {
+    samples: VecDeque<(u64, u64)>,
+    steps: Vec<u64>,
+    warned: bool,
+    totals: BlockStats,
+}
+
+/// What a block has done since it was registered: the transactions it ran in, the
+/// changes fed to it and the join steps it took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockStats {
+    pub transactions: u64,
+    pub changes: u64,
+    pub steps: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerfWarning {
+    pub block: Interned,
+    pub growth: f64,
+    pub join: usize,
+    pub samples: usize,
+}
+
+#[derive(Default)]
+pub struct PerfTracker {
+    costs: HashMap<Interned, BlockCost>,
+}
+
+impl PerfTracker {
+    /// Records that `block` took `steps` (per constraint) to handle `changes` changes in
+    /// one transaction. Returns a warning the first time its cost looks superlinear.
+    pub fn observe(&mut self, block:Interned, changes:u64, steps:&[u64]) -> Option<PerfWarning> {
+        let cost = self.costs.entry(block).or_insert_with(BlockCost::default);
+        let total:u64 = steps.iter().sum();
+        cost.totals.transactions += 1;
+        cost.totals.changes += changes;
+        cost.totals.steps += total;
+        if cost.samples.len() == PERF_SAMPLES { cost.samples.pop_front(); }
+        cost.samples.push_back((changes, total));
+        if cost.steps.len() < steps.len() { cost.steps.resize(steps.len(), 0); }
+        for (total, step) in cost.steps.iter_mut().zip(steps.iter()) {
+            *total += *step;
+        }
+        if cost.warned || cost.samples.iter().all(|&(_, steps)| steps < PERF_MIN_STEPS) { return None; }
+        let growth = match growth_exponent(cost.samples.iter().cloned()) {
+            Some(growth) if growth >= PERF_WARN_GROWTH => growth,
+            _ => return None,
+        };
+        cost.warned = true;
+        let join = (0..cost.steps.len()).max_by_key(|ix| cost.steps[*ix]).unwrap_or(0);
+        Some(PerfWarning { block, growth, join, samples: cost.samples.len() })
+    }
+
+    pub fn stats(&self, block:Interned) -> BlockStats {
+        self.costs.get(&block).map_or(BlockStats::default(), |cost| cost.totals)
+    }
+
+    pub fn forget(&mut self, block:Interned) {
+        self.costs.remove(&block);
+    }
+}
+
+/// The slope of log(steps) over log(changes), i.e. the exponent of the power law that
+/// best fits the samples. None if there are too few samples or the inputs are too alike.
+pub fn growth_exponent<I:Iterator<Item=(u64, u64)>>(samples:I) -> Option<f64> {
+    let points:Vec<(f64, f64)> = samples
+        .filter(|&(changes, steps)| changes > 0 && steps > 0)
+        .map(|(changes, steps)| ((changes as f64).ln(), (steps as f64).ln()))
+        .collect();
+    if points.len() < PERF_MIN_SAMPLES { return None; }
+    let (min, max) = points.iter().fold((std::f64::MAX, std::f64::MIN), |(min, max), &(x, _)| (min.min(x), max.max(x)));
+    if max - min < PERF_MIN_SPREAD.ln() { return None; }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|&(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|&(_, y)| y).sum::<f64>() / n;
+    let covariance:f64 = points.iter().map(|&(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let variance:f64 = points.iter().map(|&(x, _)| (x - mean_x) * (x - mean_x)).sum();
+    Some(covariance / variance)
+}
+
+// Names a join the way it appears in the source where possible, e.g. `[... name]`.
+fn describe_join(constraint:&Constraint, interner:&Interner) -> String {
+    match constraint {
+        &Constraint::Scan { a: Field::Value(a), v, .. } |
+        &Constraint::LookupCommit { a: Field::Value(a), v, .. } => {
+            match v {
+                Field::Value(v) => format!("[... {}: {}]", Internable::to_string(interner.get_value(a)), Internable::to_string(interner.get_value(v))),
+                _ => format!("[... {}]", Internable::to_string(interner.get_value(a))),
+            }
+        }
+        _ => format!("{:?}", constraint),
+    }
+}
+
+//-------------------------------------------------------------------------
+// Transaction annotations
+//-------------------------------------------------------------------------
+
+// Annotating a transaction adds an `#eve/transaction` record to it holding the
+// metadata (source, user, reason, ...) and an `entity` for every record the
+// transaction touched. Since it's just more facts it's persisted in the same write
+// as the changes and is searchable and visible to watchers like anything else.
+pub fn annotate_changes(changes:&mut Vec<RawChange>, annotations:Vec<(String, Internable)>) {
+    let now = time::get_time();
+    let id = Internable::Reference(format!("eve/transaction|{}|{}|", now.sec, now.nsec));
+    let node = Internable::String("eve/transaction".to_string());
+    let mut entities:Vec<Internable> = changes.iter().map(|change| change.e.clone()).collect();
+    entities.sort();
+    entities.dedup();
+    changes.push(RawChange::new(id.clone(), Internable::String("tag".to_string()), Internable::String("eve/transaction".to_string()), node.clone(), 1));
+    for (attribute, value) in annotations {
+        changes.push(RawChange::new(id.clone(), Internable::String(attribute), value, node.clone(), 1));
+    }
+    for entity in entities {
+        changes.push(RawChange::new(id.clone(), Internable::String("entity".to_string()), entity, node.clone(), 1));
     }
 }
 
@@ -2634,7 +5136,7 @@ impl MetaMessage {
         }
     }
 
-    fn collapse_changes(mut vec: Vec<RawChange>) -> Vec<RawChange> {
+    pub fn collapse_changes(mut vec: Vec<RawChange>) -> Vec<RawChange> {
         let mut neue = vec![];
         if vec.len() == 0 { return neue; }
         vec.sort();
@@ -2654,12 +5156,67 @@ impl MetaMessage {
     }
 }
 
+/// The facts in a program's index, decoded, from `Program::all_facts`.
+pub struct Facts<'a> {
+    interner: &'a Interner,
+    facts: ::std::vec::IntoIter<(Interned, Interned, Interned)>,
+}
+
+impl<'a> Iterator for Facts<'a> {
+    type Item = (&'a Internable, &'a Internable, &'a Internable);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let interner = self.interner;
+        self.facts.next().map(|(e, a, v)| (interner.get_value(e), interner.get_value(a), interner.get_value(v)))
+    }
+}
+
+// Below this many facts the order scans run in hardly matters, so blocks aren't re-planned.
+const REPLAN_MIN_FACTS:u32 = 512;
 
 pub struct Program {
     pub name: String,
     pub state: RuntimeState,
     pub block_info: BlockInfo,
     watchers: HashMap<String, Box<Watcher + Send>>,
+    watcher_registration: Vec<String>,
+    dropped_subscriptions: Arc<Mutex<Vec<String>>>,
+    watcher_dependencies: HashMap<String, Vec<String>>,
+    watcher_order: Vec<String>,
+    pub delivery: DeliveryLog,
+    scopes: HashMap<String, ScopeRetention>,
+    readonly_scopes: HashSet<String>,
+    // the entity of every read-only error on record, with its scope and block
+    readonly_rejections: HashMap<Interned, (String, Option<Interned>)>,
+    // the entity of every type error on record, by block and constraint
+    type_errors: HashMap<(Interned, usize), Interned>,
+    ids: IdGenerator,
+    determinism: Option<Determinism>,
+    perf: PerfTracker,
+    strict: bool,
+    functions: CustomFunctions,
+    tag_aliases: HashMap<Interned, TagAlias>,
+    threads: usize,
+    eval_pool: Option<EvalPool>,
+    // worked out again the first transaction after the blocks change
+    partitions: Option<Partitions>,
+    system_changes: Vec<Change>,
+    disabled_blocks: HashMap<String, Block>,
+    pub last_transaction: TransactionStats,
+    inspected: Vec<Internable>,
+    history: Option<History>,
+    transactions: u64,
+    fixpoint_listeners: Vec<Sender<Fixpoint>>,
+    limits: EvalLimits,
+    last_error: Option<RuntimeError>,
+    // the entity of the runtime error on record, if there is one
+    error_record: Option<Interned>,
+    arrangements: Arrangements,
+    fingerprints: HashMap<String, u64>,
+    checkpoint_path: Option<String>,
+    db_path: Option<String>,
+    planned_size: u32,
+    watcher_errors: usize,
     pub incoming: Receiver<RunLoopMessage>,
     pub outgoing: Sender<RunLoopMessage>,
 }
@@ -2681,9 +5238,56 @@ impl Program {
         let remote_pipe_lookup = HashMap::new();
         let blocks = vec![];
         let (outgoing, incoming) = mpsc::channel();
-        let state = RuntimeState { debug:false, rounds, remote_index, output_rounds, index, distinct_index, interner, watch_indexes, intermediates };
+        let state = RuntimeState { debug:false, rounds, remote_index, output_rounds, index, distinct_index, interner, watch_indexes, intermediates, block_distinct: HashMap::new(), provenance: None, function_errors: vec![], schema: Schema::default() };
         let block_info = BlockInfo { pipe_lookup, remote_pipe_lookup, intermediate_pipe_lookup, block_names, blocks };
-        Program { name: name.to_owned(), state, block_info, watchers, incoming, outgoing }
+        let delivery = DeliveryLog::new();
+        let mut scopes = HashMap::new();
+        scopes.insert("event".to_string(), ScopeRetention::Transaction);
+        scopes.insert("session".to_string(), ScopeRetention::Session);
+        scopes.insert("browser".to_string(), ScopeRetention::Session);
+        scopes.insert("system".to_string(), ScopeRetention::Session);
+        Program {
+            name: name.to_owned(),
+            state,
+            block_info,
+            watchers,
+            watcher_registration: vec![],
+            dropped_subscriptions: Arc::new(Mutex::new(vec![])),
+            watcher_dependencies: HashMap::new(),
+            watcher_order: vec![],
+            delivery,
+            scopes,
+            readonly_scopes: HashSet::new(),
+            readonly_rejections: HashMap::new(),
+            type_errors: HashMap::new(),
+            ids: IdGenerator::ContentHash,
+            determinism: None,
+            perf: PerfTracker::default(),
+            strict: false,
+            functions: CustomFunctions::new(),
+            tag_aliases: HashMap::new(),
+            threads: 1,
+            eval_pool: None,
+            partitions: None,
+            system_changes: vec![],
+            disabled_blocks: HashMap::new(),
+            last_transaction: TransactionStats::default(),
+            inspected: vec![],
+            history: None,
+            transactions: 0,
+            fixpoint_listeners: vec![],
+            limits: EvalLimits::default(),
+            last_error: None,
+            error_record: None,
+            arrangements: Arrangements::default(),
+            fingerprints: HashMap::new(),
+            checkpoint_path: None,
+            db_path: None,
+            planned_size: 0,
+            watcher_errors: 0,
+            incoming,
+            outgoing,
+        }
     }
 
     pub fn clear(&mut self) {
@@ -2701,21 +5305,427 @@ impl Program {
         return frame.results;
     }
 
+    /// Starts or stops recording where derived facts come from. Only facts derived while
+    /// tracking is on can be explained.
+    pub fn track_provenance(&mut self, enabled:bool) {
+        self.state.provenance = if enabled { Some(Provenance::new()) } else { None };
+    }
+
+    /// Starts or stops recording each transaction's inputs and commits. Turning it off
+    /// throws away what's been recorded.
+    pub fn record_history(&mut self, enabled:bool) {
+        self.history = if enabled { Some(History::new()) } else { None };
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Takes the program back to how it was right after transaction `to` by retracting
+    /// everything committed since, newest first. Watchers see the retractions like any
+    /// other transaction. The rewound transactions are kept until something new is run,
+    /// so they can still be replayed.
+    pub fn rewind(&mut self, to:usize) -> Result<(), String> {
+        let changes = match self.history {
+            Some(ref history) if to <= history.position() => history.diff(history.position(), to),
+            Some(ref history) => return Err(format!("Can't rewind forward to transaction {}, the program is at {}", to, history.position())),
+            None => return Err("History isn't being recorded".to_string()),
+        };
+        self.exec_unrecorded(changes);
+        if let Some(ref mut history) = self.history {
+            history.seek(to);
+        }
+        Ok(())
+    }
+
+    /// Runs the recorded inputs of every transaction after the current position up to
+    /// and including `to` again. Blocks run as they did originally, so anything they
+    /// derive from the clock or randomness may come out differently.
+    pub fn replay(&mut self, to:usize) -> Result<(), String> {
+        let (position, inputs) = match self.history {
+            Some(ref history) if to >= history.position() && to <= history.len() => {
+                (history.position(), history.entries()[history.position()..to].iter().map(|entry| entry.inputs.clone()).collect::<Vec<_>>())
+            }
+            Some(ref history) => return Err(format!("Can't replay to transaction {}, the program is at {} of {}", to, history.position(), history.len())),
+            None => return Err("History isn't being recorded".to_string()),
+        };
+        for (ix, changes) in inputs.into_iter().enumerate() {
+            self.exec_unrecorded(changes);
+            if let Some(ref mut history) = self.history {
+                history.seek(position + ix + 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn exec_unrecorded(&mut self, changes:Vec<RawChange>) {
+        let history = self.history.take();
+        let mut iter_pool = EstimateIterPool::new();
+        {
+            let mut txn = Transaction::new(&mut iter_pool);
+            for change in changes {
+                txn.input_change(change.to_change(&mut self.state.interner));
+            }
+            txn.exec(self, &mut None);
+        }
+        self.history = history;
+    }
+
+    /// Explains why (e, a, v) exists as a tree of the block matches that produced it and,
+    /// recursively, the facts those matches were built on.
+    pub fn explain_fact(&self, e:Interned, a:Interned, v:Interned) -> Explanation {
+        let mut path = HashSet::new();
+        self.explain_fact_path(e, a, v, &mut path)
+    }
+
+    fn explain_fact_path(&self, e:Interned, a:Interned, v:Interned, path:&mut HashSet<(Interned, Interned, Interned)>) -> Explanation {
+        let mut explanation = Explanation { e, a, v, derivations: vec![] };
+        let derivations = match self.state.provenance {
+            Some(ref provenance) => provenance.derivations(e, a, v),
+            None => None,
+        };
+        // a fact that (transitively) supports itself is only expanded the first time
+        if derivations.is_none() || !path.insert((e, a, v)) { return explanation; }
+        for derivation in derivations.unwrap().iter() {
+            let block_name = match self.state.interner.get_value(derivation.block) {
+                &Internable::String(ref name) => name.to_string(),
+                other => other.print(),
+            };
+            let mut inputs = vec![];
+            if let Some(block) = self.block_info.block_names.get(&block_name).map(|&ix| &self.block_info.blocks[ix]) {
+                let resolve = |field:&Field| match field {
+                    &Field::Register(ix) => derivation.row.get(ix).cloned().unwrap_or(0),
+                    &Field::Value(value) => value,
+                };
+                for constraint in block.constraints.iter() {
+                    match constraint {
+                        &Constraint::Scan { ref e, ref a, ref v, .. } |
+                        &Constraint::RangeScan { ref e, ref a, ref v, .. } |
+                        &Constraint::LookupCommit { ref e, ref a, ref v, .. } => {
+                            inputs.push(self.explain_fact_path(resolve(e), resolve(a), resolve(v), path));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            explanation.derivations.push(ExplainedDerivation { block: block_name, bindings: derivation.row.clone(), inputs });
+        }
+        path.remove(&(e, a, v));
+        explanation
+    }
+
+    /// A live view of what the block named `name` projects, e.g. a block annotated with
+    /// `eve:block name: "open-orders"` whose last section is `project (order, total)`.
+    /// Blocks it depends on for `not`, `if` and aggregates are already running, so any
+    /// block with a project section can be viewed.
+    pub fn view(&mut self, name:&str) -> Result<View, String> {
+        let block = match self.block_info.block_names.get(name) {
+            Some(&ix) => self.block_info.blocks[ix].clone(),
+            None => return Err(format!("No block named `{}`", name)),
+        };
+        let projects = block.constraints.iter().any(|constraint| match constraint {
+            &Constraint::Project { .. } => true,
+            _ => false,
+        });
+        if !projects {
+            return Err(format!("Block `{}` has no project section to view", name));
+        }
+        let subscription = PreparedQuery::new(block, vec![]).subscribe(self, &HashMap::new())?;
+        let rows = subscription.rows.clone();
+        Ok(View { subscription, rows })
+    }
+
+    pub fn close_view(&mut self, view:View) {
+        self.unsubscribe(view.subscription);
+    }
+
+    /// Stops maintaining a subscribed query and removes its block.
+    pub fn unsubscribe(&mut self, mut subscription:Subscription) {
+        subscription.dropped = None;
+        self.remove_subscription(subscription.name.to_string());
+    }
+
+    fn remove_dropped_subscriptions(&mut self) {
+        let names:Vec<String> = match self.dropped_subscriptions.lock() {
+            Ok(mut names) => names.drain(..).collect(),
+            Err(_) => return,
+        };
+        for name in names {
+            self.remove_subscription(name);
+        }
+    }
+
+    fn remove_subscription(&mut self, name:String) {
+        if self.block_info.block_names.contains_key(&name) {
+            let mut txn = CodeTransaction::new();
+            txn.exec(self, vec![], vec![name.to_string()]);
+        }
+        self.watchers.remove(&name);
+        self.watcher_registration.retain(|cur| *cur != name);
+        self.state.watch_indexes.remove(&name);
+        self.retract_system_facts(Internable::Reference(format!("system/watcher|{}|", name)));
+        self.order_watchers();
+    }
+
+    fn has_solution(&mut self, constraints:&[Constraint]) -> bool {
+        let mut constraints = constraints.to_vec();
+        renumber_registers(&mut constraints);
+        constraints.push(Constraint::Project { registers: vec![] });
+        let block_id = self.state.interner.string_id("eve/why-not");
+        let block = Block::new(&mut self.state.interner, "eve/why-not", block_id, constraints);
+        let mut frame = Frame::with_budget(QueryBudget::unlimited().rows(1));
+        // like a query's, the block needs an input at round 0 to count its rounds from
+        frame.input = Some(Change { e:0,a:0,v:0,n: 0, transaction:0, round:0, count:1 });
+        let mut iter_pool = EstimateIterPool::new();
+        block.run(&mut self.state, &mut iter_pool, &mut frame);
+        frame.produced_rows() > 0
+    }
+
+    /// Explains why there's no record with all of the attribute/value pairs in `pattern`.
+    /// Every block that writes records like it is run one constraint at a time until its
+    /// search stops matching, which is reported along with the part that did. Where a
+    /// block writes one of the attributes from a variable, that variable is held to the
+    /// pattern's value. No blocks at all means nothing in the program produces such a
+    /// record.
+    pub fn why_not(&mut self, pattern:&Vec<(String, Internable)>) -> Vec<WhyNot> {
+        let pattern:Vec<(Interned, Interned)> = pattern.iter().map(|&(ref a, ref v)| {
+            (self.state.interner.string_id(a), self.state.interner.internable_to_id(v.clone()))
+        }).collect();
+        let mut candidates = vec![];
+        for block in self.block_info.blocks.iter() {
+            let mut entities:Vec<Field> = vec![];
+            for constraint in block.constraints.iter() {
+                if let &Constraint::Insert { e, .. } = constraint {
+                    if !entities.contains(&e) { entities.push(e); }
+                }
+            }
+            'entity: for entity in entities {
+                let mut checks = vec![];
+                for &(a, v) in pattern.iter() {
+                    let written = block.constraints.iter().filter_map(|constraint| match constraint {
+                        &Constraint::Insert { e, a: Field::Value(cur_a), v: cur_v, .. } if e == entity && cur_a == a => Some(cur_v),
+                        _ => None,
+                    }).find(|cur_v| *cur_v == Field::Value(v) || cur_v.is_register());
+                    match written {
+                        Some(reg @ Field::Register(_)) => checks.push(make_filter("=", reg, Field::Value(v))),
+                        Some(_) => {}
+                        None => continue 'entity,
+                    }
+                }
+                // the pattern's values go first so they're checked as soon as their
+                // registers are bound, otherwise any other record the block matches
+                // would get the search past the constraint that's really failing
+                let mut search = checks;
+                search.extend(block.constraints.iter().filter(|constraint| match constraint {
+                    &&Constraint::Scan {..} | &&Constraint::RangeScan {..} | &&Constraint::LookupCommit {..} |
+                    &&Constraint::IntermediateScan {..} | &&Constraint::AntiScan {..} | &&Constraint::Filter {..} |
+                    &&Constraint::Function {..} | &&Constraint::MultiFunction {..} | &&Constraint::IndexFunction {..} |
+                    &&Constraint::CustomFunction {..} => true,
+                    _ => false,
+                }).cloned());
+                candidates.push((block.name.to_string(), order_by_inputs(search)));
+                break;
+            }
+        }
+
+        candidates.into_iter().map(|(block, constraints)| {
+            let mut matched = vec![];
+            let mut failed = None;
+            for ix in 0..constraints.len() {
+                let description = describe_constraint(&self.state.interner, &constraints[ix]);
+                if self.has_solution(&constraints[..ix + 1]) {
+                    matched.push(description);
+                } else {
+                    failed = Some(description);
+                    break;
+                }
+            }
+            WhyNot { block, matched, failed }
+        }).collect()
+    }
+
+    // The query's block along with the sub-blocks its nots, chooses and aggregates
+    // compiled to.
+    fn compile_query_blocks(&mut self, source:&str) -> Result<(Block, Vec<Block>), String> {
+        let options = self.compile_options();
+        let blocks = parse_string_with(&mut self.state.interner, source, "query", &options);
+        let (mut main, sub_blocks):(Vec<Block>, Vec<Block>) = blocks.into_iter().partition(|block| !block.name.contains("|sub_block|"));
+        match main.len() {
+            0 => Err("No query block found".to_string()),
+            1 => Ok((main.pop().unwrap(), sub_blocks)),
+            _ => Err("A query is a single block".to_string()),
+        }
+    }
+
+    fn compile_query(&mut self, source:&str) -> Result<Block, String> {
+        let (block, sub_blocks) = self.compile_query_blocks(source)?;
+        if sub_blocks.len() > 0 {
+            // sub-blocks only keep producing anything once they're registered, which
+            // prepared queries aren't
+            return Err("Prepared queries can't use not, choose, or aggregates yet".to_string());
+        }
+        Ok(block)
+    }
+
+    fn run_query(&mut self, block:&Block, sub_blocks:Vec<Block>, budget:QueryBudget) -> QueryResult {
+        let width = block.constraints.iter().filter_map(|constraint| {
+            match constraint {
+                &Constraint::Project { ref registers } => Some(registers.len()),
+                _ => None,
+            }
+        }).sum::<usize>();
+        let mut frame = Frame::with_budget(budget);
+        let mut iter_pool = EstimateIterPool::new();
+        // the sub-blocks fill in the intermediates the block reads, so each runs over the
+        // whole state first, writers before readers. They get an intermediate index of
+        // their own that's dropped afterwards, and no registered block can see it.
+        let intermediates = mem::replace(&mut self.state.intermediates, IntermediateIndex::new());
+        let mut readers = BlockInfo { pipe_lookup: HashMap::new(), intermediate_pipe_lookup: HashMap::new(), remote_pipe_lookup: HashMap::new(), block_names: HashMap::new(), blocks: vec![] };
+        let mut writers = vec![];
+        for mut sub_block in order_by_intermediates(sub_blocks) {
+            let reads = sub_block.constraints.iter().any(|constraint| match constraint {
+                &Constraint::IntermediateScan { .. } => true,
+                _ => false,
+            });
+            if !reads { writers.push(sub_block); continue; }
+            // a sub-block reading an intermediate, like an aggregate, only has its inputs
+            // once a change to that intermediate hands them over, so it runs as a pipe
+            let pipes = sub_block.gen_pipes(&mut self.state.interner);
+            for (pipe, shapes) in pipes.into_iter().zip(sub_block.shapes.iter()) {
+                for shape in shapes {
+                    if let &PipeShape::Intermediate(id) = shape {
+                        readers.intermediate_pipe_lookup.entry(id).or_insert_with(|| vec![]).push(pipe.clone());
+                    }
+                }
+            }
+        }
+        for sub_block in writers {
+            frame.reset();
+            frame.input = Some(Change { e:0,a:0,v:0,n: 0, transaction:0, round:0, count:1 });
+            sub_block.run(&mut self.state, &mut iter_pool, &mut frame);
+            let mut max_round = 0;
+            let mut round = 0;
+            while round <= max_round {
+                intermediate_flow(&mut frame, &mut self.state, &readers, &mut iter_pool, None, round, &mut max_round);
+                round += 1;
+            }
+        }
+        // the block's rounds are counted from its input, so it gets one at round 0 that
+        // every fact it reads joins with
+        frame.reset();
+        frame.input = Some(Change { e:0,a:0,v:0,n: 0, transaction:0, round:0, count:1 });
+        block.run(&mut self.state, &mut iter_pool, &mut frame);
+        self.state.intermediates = intermediates;
+        let rows = if width == 0 { vec![] } else {
+            frame.results.chunks(width).map(|row| {
+                row.iter().map(|&value| self.state.interner.get_value(value).clone()).collect()
+            }).collect()
+        };
+        QueryResult { rows, complete: !frame.cancelled }
+    }
+
+    /// Runs the block in `source` against the current state and returns the rows it
+    /// projects, stopping early if `budget` runs out.
+    pub fn query(&mut self, source:&str, budget:QueryBudget) -> Result<QueryResult, String> {
+        let (block, sub_blocks) = self.compile_query_blocks(source)?;
+        for query_block in Some(&block).into_iter().chain(sub_blocks.iter()) {
+            if let Some(&(ref name, _)) = query_params(&self.state.interner, &query_block.constraints).first() {
+                return Err(format!("`${}` can only be bound in a prepared query", name));
+            }
+        }
+        Ok(self.run_query(&block, sub_blocks, budget))
+    }
+
+    /// Runs the query in `source`, whose search has to `minimize` or `maximize` one of the
+    /// values it projects, and returns the row that's best for it. Ties go to the row that
+    /// sorts first so the same state always gives the same solution. The search and the
+    /// proof that nothing beats it each get all of `budget`.
+    pub fn solve(&mut self, source:&str, budget:QueryBudget) -> Result<Solution, String> {
+        let objective = match query_objective(source) {
+            Some(objective) => objective,
+            None => return Err("The query has to minimize or maximize a variable".to_string()),
+        };
+        let column = match objective.column {
+            Some(column) => column,
+            None => return Err(format!("`{}` isn't one of the values the query projects", objective.variable)),
+        };
+        let row = match self.query(source, budget)?.rows.into_iter().min() {
+            Some(row) => row,
+            None => return Ok(Solution { row: None, optimum: None, optimal: false }),
+        };
+        let bound = match row[column] {
+            Internable::Number(_) => Internable::to_string(&row[column]),
+            Internable::Decimal(ref decimal) => format!("{}d", decimal.to_string()),
+            _ => return Err(format!("`{}` has to be a number to be optimized", objective.variable)),
+        };
+        // the same search with the objective swapped for a bound that only lets better
+        // rows through
+        let op = if objective.maximize { ">" } else { "<" };
+        let span = &objective.span;
+        let better = format!("{}{} {} {}{}", &source[..span.start.pos], objective.variable, op, bound, &source[span.stop.pos..]);
+        let proof = self.query(&better, budget)?;
+        Ok(Solution { optimal: proof.complete && proof.rows.is_empty(), optimum: Some(row[column].clone()), row: Some(row) })
+    }
+
+    /// Compiles a query once so it can be run over and over with different values for
+    /// its `$name` placeholders. See `PreparedQuery::exec`.
+    pub fn prepare(&mut self, source:&str) -> Result<PreparedQuery, String> {
+        let block = self.compile_query(source)?;
+        let params = query_params(&self.state.interner, &block.constraints);
+        Ok(PreparedQuery::new(block, params))
+    }
+
     #[allow(dead_code)]
     pub fn raw_insert(&mut self, e:Interned, a:Interned, v:Interned, round:Round, count:Count) {
         self.state.distinct_index.raw_insert(e,a,v,round,count);
         if count > 0 {
             self.state.distinct_index.insert_active(e,a,v,round);
-            self.state.index.insert(e,a,v);
+            self.state.index.insert_value(e,a,v, &self.state.interner);
         } else {
             self.state.distinct_index.remove_active(e,a,v,round);
-            self.state.index.remove(e,a,v);
+            self.state.index.remove_value(e,a,v, &self.state.interner);
+        }
+    }
+
+    fn replace_functions(&self, constraints:&mut Vec<Constraint>) -> bool {
+        let ids_changed = self.ids.apply(constraints, self.determinism.as_ref());
+        match self.determinism {
+            Some(ref determinism) => determinism.apply(constraints) || ids_changed,
+            None => ids_changed,
         }
     }
 
-    pub fn register_block(&mut self, mut block:Block) {
+    pub fn register_block(&mut self, block:Block) {
+        self.register_block_with(block, true);
+    }
+
+    // A block reading an arrangement someone else maintains is registered without pipes.
+    fn register_block_with(&mut self, mut block:Block, run:bool) {
+        let functions_changed = self.replace_functions(&mut block.constraints);
+        let tags_changed = !self.tag_aliases.is_empty() && self.rewrite_tags(&mut block);
+        let arrangements_changed = self.arrangements.rewrite(&mut block);
+        if order_scans(&mut block.constraints, &self.state.index) || functions_changed || tags_changed || arrangements_changed {
+            // the scans' positions and the functions are baked into the shapes and
+            // solver, so they have to be rebuilt around the new constraints
+            block.shapes = block.to_shapes();
+            block.solver = Some(Solver::new(&mut self.state.interner, block.block_id, 0, None, &block.constraints));
+        }
+        if block.metadata.distinct {
+            self.state.block_distinct.entry(block.block_id).or_insert_with(BlockDistinct::new);
+        }
         let ix = self.block_info.blocks.len();
+        let pipes = if run { self.register_pipes(&mut block) } else { 0 };
+        let mut block_facts = self.block_facts(&block);
+        block_facts.push(("pipes", Internable::from_number(pipes as f32)));
+        self.queue_system_facts(system_block_id(&block.name), block_facts);
+        self.block_info.block_names.insert(block.name.to_string(), ix);
+        self.block_info.blocks.push(block);
+        self.partitions = None;
+    }
+
+    fn register_pipes(&mut self, block:&mut Block) -> usize {
         let mut pipes = block.gen_pipes(&mut self.state.interner);
+        let count = pipes.len();
         for (pipe, shapes) in pipes.drain(..).zip(block.shapes.iter()) {
             for shape in shapes {
                 match shape {
@@ -2734,60 +5744,1048 @@ impl Program {
                 }
             }
         }
-        self.block_info.block_names.insert(block.name.to_string(), ix);
-        self.block_info.blocks.push(block);
+        count
+    }
+
+    // Starts running the pipes of a block that was reading someone else's arrangement.
+    fn maintain_arrangement(&mut self, name:&str) {
+        let mut block = match self.block_info.block_names.get(name) {
+            Some(&ix) => self.block_info.blocks[ix].clone(),
+            None => return,
+        };
+        self.register_pipes(&mut block);
+    }
+
+    /// The arrangement a block's intermediates are kept in, if it shares one.
+    pub fn arrangement(&self, block:&str) -> Option<&Arrangement> {
+        self.arrangements.get(block)
+    }
+
+    /// How many arrangements are being maintained.
+    pub fn arrangement_count(&self) -> usize {
+        self.arrangements.len()
+    }
+
+    pub fn unregister_block(&mut self, name:String) {
+        self.retract_system_facts(system_block_id(&name));
+        self.retract_system_facts(perf_warning_id(&name));
+        if let Some(block_ix) = self.block_info.block_names.remove(&name) {
+            let block = self.block_info.blocks.swap_remove(block_ix);
+            self.partitions = None;
+            self.perf.forget(block.block_id);
+            self.retract_readonly_errors(|_, error_block| error_block == Some(block.block_id));
+            self.retract_type_errors(|error_block, _| error_block == block.block_id);
+            self.state.block_distinct.remove(&block.block_id);
+            if let Some(neue) = self.block_info.blocks.get(block_ix) {
+                self.block_info.block_names.insert(neue.name.to_owned(), block_ix);
+            }
+            self.remove_pipes(&block);
+        }
+    }
+
+    // Takes a block's pipes out of the lookups, returning whether it had any. Blocks
+    // reading a shared arrangement never had pipes to remove.
+    fn remove_pipes(&mut self, block:&Block) -> bool {
+        let mut removed = false;
+        for shape_set in block.shapes.iter() {
+            for shape in shape_set.iter() {
+                let pipes = match shape {
+                    &PipeShape::Scan(e, a, v) => self.block_info.pipe_lookup.get_mut(&(e, a, v)),
+                    &PipeShape::Intermediate(id) => self.block_info.intermediate_pipe_lookup.get_mut(&id),
+                    &PipeShape::Remote(id) => self.block_info.remote_pipe_lookup.get_mut(&id),
+                };
+                if let Some(pipes) = pipes {
+                    let before = pipes.len();
+                    pipes.retain(|x| x.block != block.block_id);
+                    removed = removed || pipes.len() < before;
+                }
+            }
+        }
+        removed
+    }
+
+    // Blocks are usually registered before any data is loaded, when every scan looks
+    // as cheap as the next, so their scans are ordered again each time the index has
+    // doubled in size since they last were.
+    fn replan_scans(&mut self) {
+        let size = self.state.index.size;
+        if size < REPLAN_MIN_FACTS || size < self.planned_size.saturating_mul(2) { return; }
+        self.planned_size = size;
+        for ix in 0..self.block_info.blocks.len() {
+            let mut constraints = self.block_info.blocks[ix].constraints.clone();
+            if !order_scans(&mut constraints, &self.state.index) { continue; }
+            let mut block = self.block_info.blocks[ix].clone();
+            let running = self.remove_pipes(&block);
+            block.constraints = constraints;
+            block.shapes = block.to_shapes();
+            block.solver = Some(Solver::new(&mut self.state.interner, block.block_id, 0, None, &block.constraints));
+            if running { self.register_pipes(&mut block); }
+            self.block_info.blocks[ix] = block;
+        }
+    }
+
+    pub fn insert_block(&mut self, name:&str, code:&str) {
+        let bs = make_block(&mut self.state.interner, name, code);
+        for b in bs {
+            self.register_block(b);
+        }
+    }
+
+    pub fn block(&mut self, name:&str, code:&str) -> CodeTransaction {
+        let bs = make_block(&mut self.state.interner, name, code);
+        let mut txn = CodeTransaction::new();
+        txn.exec(self, bs, vec![]);
+        txn
+    }
+
+    pub fn raw_block(&mut self, block:Block) {
+        self.register_block(block);
+    }
+
+    pub fn blocks_by_path(&self, path:&str) -> Vec<&Block> {
+        self.block_info.blocks.iter().filter(|block| block.path == path).collect()
+    }
+
+    // Swaps aliased tags in a block about to be registered, reporting any deprecated ones
+    // it used. Returns whether anything was swapped.
+    fn rewrite_tags(&mut self, block:&mut Block) -> bool {
+        let tag_attribute = self.state.interner.string_id("tag");
+        let used = alias_tags(&mut block.constraints, tag_attribute, &self.tag_aliases);
+        for &(old, alias) in used.iter().filter(|&&(_, alias)| alias.deprecated) {
+            let old = Internable::to_string(self.state.interner.get_value(old));
+            let replacement = Internable::to_string(self.state.interner.get_value(alias.tag));
+            self.queue_system_facts(deprecated_tag_id(&block.name, &old), vec![
+                ("tag", Internable::String("system/deprecated-tag".to_string())),
+                ("block", Internable::String(block.name.to_string())),
+                ("deprecated", Internable::String(old.to_string())),
+                ("replacement", Internable::String(replacement)),
+            ]);
+        }
+        used.len() > 0
+    }
+
+    /// Makes `#alias` another name for `#tag`: blocks registered from now on search for
+    /// and write `#tag` instead, and records tagged `#alias` are given `#tag` as well.
+    pub fn alias_tag(&mut self, tag:&str, alias:&str) {
+        self.rename_tag(tag, alias, false);
+    }
+
+    /// Replaces `#old` with `#replacement`: blocks registered from now on use the
+    /// replacement, every record tagged `#old` is moved over to it, and blocks that still
+    /// mention `#old` are reported in the @system scope so their source can be updated.
+    pub fn deprecate_tag(&mut self, old:&str, replacement:&str) {
+        self.rename_tag(replacement, old, true);
+    }
+
+    fn rename_tag(&mut self, tag:&str, old:&str, deprecated:bool) {
+        let tag_attribute = Field::Value(self.state.interner.string_id("tag"));
+        let tag_value = self.state.interner.string_id(tag);
+        let old_value = self.state.interner.string_id(old);
+        let record = Field::Register(0);
+        let mut constraints = vec![make_scan(record, tag_attribute, Field::Value(old_value))];
+        if deprecated {
+            constraints.push(Constraint::Remove { e: record, a: tag_attribute, v: Field::Value(old_value) });
+        }
+        constraints.push(Constraint::Insert { e: record, a: tag_attribute, v: Field::Value(tag_value), commit: deprecated });
+        // the bridge is registered before the alias exists, or it would rewrite itself
+        let name = format!("eve/tag-alias|{}|{}", old, tag);
+        let block_id = self.state.interner.string_id(&name);
+        let bridge = Block::new(&mut self.state.interner, &name, block_id, constraints);
+        let mut txn = CodeTransaction::new();
+        txn.exec(self, vec![bridge], vec![]);
+        self.tag_aliases.insert(old_value, TagAlias { tag: tag_value, deprecated });
+    }
+
+    /// Pulls a block out of the program, retracting everything it derived, but holds
+    /// on to it so `enable_block` can put it back. Returns false if there's no running
+    /// block by that name.
+    pub fn disable_block(&mut self, name:&str) -> bool {
+        let block = match self.block_info.block_names.get(name) {
+            Some(&ix) => self.block_info.blocks[ix].clone(),
+            None => return false,
+        };
+        let mut txn = CodeTransaction::new();
+        txn.exec(self, vec![], vec![name.to_string()]);
+        self.hold_disabled(block);
+        true
+    }
+
+    // Keeps a block that isn't running so it can be enabled again by name.
+    fn hold_disabled(&mut self, mut block:Block) {
+        block.metadata.disabled = true;
+        let block_facts = self.block_facts(&block);
+        self.queue_system_facts(system_block_id(&block.name), block_facts);
+        self.disabled_blocks.insert(block.name.to_string(), block);
+    }
+
+    // The @system description of a block, running or not. Its counters start over
+    // whenever it's registered again.
+    fn block_facts(&self, block:&Block) -> Vec<(&'static str, Internable)> {
+        let enabled = if block.metadata.disabled { "false" } else { "true" };
+        let stats = self.perf.stats(block.block_id);
+        vec![
+            ("tag", Internable::String("system/block".to_string())),
+            ("name", Internable::String(block.name.to_string())),
+            ("path", Internable::String(block.path.to_string())),
+            ("enabled", Internable::String(enabled.to_string())),
+            ("constraints", Internable::from_number(block.constraints.len() as f32)),
+            ("transactions", Internable::from_number(stats.transactions as f32)),
+            ("changes", Internable::from_number(stats.changes as f32)),
+            ("steps", Internable::from_number(stats.steps as f32)),
+        ]
+    }
+
+    // Refreshes the counters in a running block's @system description after a
+    // transaction it ran in.
+    fn refresh_block_stats(&mut self, block_id:Interned) {
+        let name = match self.block_info.blocks.iter().find(|block| block.block_id == block_id) {
+            Some(block) => block.name.to_string(),
+            None => return,
+        };
+        let stats = self.perf.stats(block_id);
+        let id = system_block_id(&name);
+        self.update_system_fact(id.clone(), "transactions", Internable::from_number(stats.transactions as f32));
+        self.update_system_fact(id.clone(), "changes", Internable::from_number(stats.changes as f32));
+        self.update_system_fact(id, "steps", Internable::from_number(stats.steps as f32));
+    }
+
+    pub fn enable_block(&mut self, name:&str) -> bool {
+        let mut block = match self.disabled_blocks.remove(name) {
+            Some(block) => block,
+            None => return false,
+        };
+        self.retract_system_facts(system_block_id(name));
+        block.metadata.disabled = false;
+        let mut txn = CodeTransaction::new();
+        txn.exec(self, vec![block], vec![]);
+        true
+    }
+
+    pub fn admin_blocks(&self) -> Vec<AdminBlock> {
+        let mut blocks:Vec<AdminBlock> = self.block_info.blocks.iter().chain(self.disabled_blocks.values())
+            .map(|block| AdminBlock { name: block.name.to_string(), path: block.path.to_string(), enabled: !block.metadata.disabled, stats: self.perf.stats(block.block_id) })
+            .collect();
+        blocks.sort_by(|a, b| a.name.cmp(&b.name));
+        blocks
+    }
+
+    pub fn stats(&self) -> AdminStats {
+        AdminStats {
+            blocks: self.block_info.blocks.len(),
+            disabled: self.disabled_blocks.len(),
+            watchers: self.watchers.len(),
+            transactions: self.transactions,
+            committed: self.state.distinct_index.commits().len(),
+            indexes: self.index_stats(),
+        }
+    }
+
+    /// Stats for each of the program's indexes: "facts", "distinct", "intermediates",
+    /// then one "watch/<name>" per watcher that has been sent rows.
+    pub fn index_stats(&self) -> Vec<(String, IndexStats)> {
+        let mut stats = vec![
+            ("facts".to_string(), self.state.index.stats()),
+            ("distinct".to_string(), self.state.distinct_index.stats()),
+            ("intermediates".to_string(), self.state.intermediates.stats()),
+        ];
+        let mut watched:Vec<(String, IndexStats)> = self.state.watch_indexes.iter()
+            .map(|(name, index)| (format!("watch/{}", name), index.stats()))
+            .collect();
+        watched.sort_by(|a, b| a.0.cmp(&b.0));
+        stats.extend(watched);
+        stats
+    }
+
+    /// Gives back the memory fully retracted keys are holding in the intermediate indexes
+    /// `not`, `if` and aggregates are kept in. Returns how many keys were dropped.
+    pub fn compact(&mut self) -> usize {
+        self.state.intermediates.compact()
+    }
+
+    /// Describes the program's internals as `#eve/internal` records in the @system scope,
+    /// one per block plus one each for the program, its indexes and the last transaction,
+    /// so dashboards and debuggers can be written in Eve. This is a snapshot: records
+    /// from the previous inspection are replaced and nothing updates until the next one.
+    /// Like other @system facts they ride along with the next transaction.
+    pub fn inspect(&mut self) {
+        let previous:Vec<Internable> = self.inspected.drain(..).collect();
+        for id in previous {
+            self.retract_system_facts(id);
+        }
+        let tag = || ("tag", Internable::String("eve/internal".to_string()));
+        let kind = |kind:&str| ("kind", Internable::String(kind.to_string()));
+        let number = |value:usize| Internable::from_number(value as f32);
+        let mut records = vec![];
+
+        let stats = self.stats();
+        records.push((internal_record_id("program", &self.name), vec![
+            tag(), kind("program"),
+            ("name", Internable::String(self.name.to_string())),
+            ("blocks", number(stats.blocks)),
+            ("disabled", number(stats.disabled)),
+            ("watchers", number(stats.watchers)),
+        ]));
+        for block in self.block_info.blocks.iter() {
+            let registers = block.solver.as_ref().map_or(0, |solver| solver.register_count());
+            records.push((internal_record_id("block", &block.name), vec![
+                tag(), kind("block"),
+                ("name", Internable::String(block.name.to_string())),
+                ("constraints", number(block.constraints.len())),
+                ("registers", number(registers)),
+            ]));
+        }
+        records.push((internal_record_id("index", &self.name), vec![
+            tag(), kind("index"),
+            ("facts", number(self.state.index.size as usize)),
+            ("distinct", number(self.state.distinct_index.eavs.len())),
+            ("intermediates", number(self.state.intermediates.stats().entries)),
+            ("committed", number(stats.committed)),
+        ]));
+        let last = self.last_transaction;
+        records.push((internal_record_id("transaction", &self.name), vec![
+            tag(), kind("transaction"),
+            ("changes", number(last.changes)),
+            ("commits", number(last.commits)),
+            ("frames", number(last.frames)),
+            ("rounds", number(last.rounds)),
+            ("added", number(last.added)),
+            ("removed", number(last.removed)),
+            ("deduplicated", number(last.deduplicated)),
+            ("time", Internable::from_number(last.ns as f32 / 1_000_000.0)),
+        ]));
+
+        for (id, facts) in records {
+            self.inspected.push(id.clone());
+            self.queue_system_facts(id, facts);
+        }
+    }
+
+    /// Every (entity, attribute, value) in the index, committed or bound, in no
+    /// particular order.
+    pub fn all_facts(&self) -> Facts {
+        Facts { interner: &self.state.interner, facts: self.state.index.facts().into_iter() }
+    }
+
+    /// Each attribute of the entity `id` with its values in sorted order, or None if the
+    /// index has nothing about it.
+    pub fn entity(&self, id:&Internable) -> Option<BTreeMap<String, Vec<Internable>>> {
+        let interner = &self.state.interner;
+        let e = interner.id(id)?;
+        let mut record = BTreeMap::new();
+        for (a, v) in self.state.index.entity_facts(e) {
+            record.entry(Internable::to_string(interner.get_value(a))).or_insert_with(|| vec![]).push(interner.get_value(v).clone());
+        }
+        if record.is_empty() { return None; }
+        for values in record.values_mut() {
+            values.sort();
+        }
+        Some(record)
+    }
+
+    /// Every persistent fact currently committed, as it would be written by the
+    /// persister. Snapshotting this lets the db file drop facts that have since been
+    /// removed.
+    pub fn committed_facts(&self) -> Vec<RawChange> {
+        self.snapshot_view().committed_facts()
+    }
+
+    /// What the program has committed and derived so far, to be written out on another
+    /// thread. The view shares its storage with the program's indexes, which copy the
+    /// parts they change from then on, so taking one doesn't hold up transactions.
+    pub fn snapshot_view(&self) -> ProgramView {
+        // blocks registered behind the code's back, like views, never match on the way back
+        let blocks = self.block_info.block_names.keys()
+            .map(|name| (name.to_string(), self.fingerprints.get(name).cloned().unwrap_or(0)))
+            .collect();
+        ProgramView {
+            values: self.state.interner.values(),
+            scopes: self.scopes.clone(),
+            blocks,
+            distinct_index: self.state.distinct_index.share(),
+            intermediates: self.state.intermediates.share(),
+            watch_indexes: self.state.watch_indexes.iter().map(|(name, index)| (name.clone(), index.share())).collect(),
+            block_distinct: self.state.block_distinct.iter().map(|(&block, outputs)| (block, outputs.share())).collect(),
+        }
+    }
+
+    /// Snapshots the committed facts to `path` without holding up the program. All that
+    /// happens here is taking a `snapshot_view`; finding the committed facts, decoding
+    /// and writing them happens on the returned thread, so transactions keep flowing
+    /// while the file is written. The program's own db is refused, since the persister
+    /// is appending to it; snapshot that through `AdminCommand::Snapshot` instead.
+    pub fn save(&self, path:&str) -> io::Result<JoinHandle<io::Result<()>>> {
+        self.save_with(path, None)
+    }
+
+    /// Like `save`, but the facts go through `redaction` first so the snapshot can be
+    /// handed to someone who shouldn't see the real data.
+    pub fn save_redacted(&self, path:&str, redaction:&Redaction) -> io::Result<JoinHandle<io::Result<()>>> {
+        self.save_with(path, Some(redaction.clone()))
+    }
+
+    fn save_with(&self, path:&str, redaction:Option<Redaction>) -> io::Result<JoinHandle<io::Result<()>>> {
+        if let Some(ref db) = self.db_path {
+            if same_path(db, path) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("`{}` is the db the persister is writing to", path)));
+            }
+        }
+        let view = self.snapshot_view();
+        let path = path.to_string();
+        thread::Builder::new().name(format!("{} snapshot", self.name)).spawn(move || {
+            let facts = view.committed_facts();
+            let facts = match redaction {
+                Some(redaction) => redaction.redact_all(facts),
+                None => facts,
+            };
+            write_snapshot(&path, &facts)
+        })
+    }
+
+    /// Writes everything derived from `commits` to `path`, to be picked up again by
+    /// `restore_checkpoint`. `commits` has to be what the db is being snapshotted to.
+    pub fn write_checkpoint(&self, path:&str, commits:&[RawChange]) -> io::Result<()> {
+        self.snapshot_view().write_checkpoint(path, commits)
+    }
+
+    /// Loads the derived state checkpointed at `path` if it was taken over the start of
+    /// `commits`. This has to happen before any blocks are compiled, and the blocks then
+    /// have to be checked against the returned point: if they don't match, the derived
+    /// state has to be thrown away with `discard_derived`.
+    pub fn restore_checkpoint(&mut self, path:&str, commits:&[RawChange]) -> Option<ResumePoint> {
+        let checkpoint = Checkpoint::load(path, commits)?;
+        let interner = Interner::from_values(checkpoint.values);
+        if !interner.extends(&self.state.interner) {
+            return None;
+        }
+        self.state.interner = interner;
+        self.state.distinct_index = checkpoint.distinct_index;
+        self.state.intermediates = checkpoint.intermediates;
+        self.state.intermediates.rebuild();
+        self.state.watch_indexes = checkpoint.watch_indexes;
+        self.state.block_distinct = checkpoint.block_distinct;
+        // the index holds exactly the facts the distinct index has inserted
+        let mut index = HashIndex::new();
+        for (e, a, v) in self.state.distinct_index.inserted() {
+            index.insert_value(e, a, v, &self.state.interner);
+        }
+        self.state.index = index;
+        Some(ResumePoint::new(checkpoint.blocks, checkpoint.commits))
+    }
+
+    /// Forgets everything derived so far, leaving ids interned as they are.
+    pub fn discard_derived(&mut self) {
+        self.state.index = HashIndex::new();
+        self.state.distinct_index = DistinctIndex::new();
+        self.state.intermediates = IntermediateIndex::new();
+        for outputs in self.state.block_distinct.values_mut() {
+            *outputs = BlockDistinct::new();
+        }
+        self.state.watch_indexes.clear();
+    }
+
+    /// Carries out `command` and sends what came of it to `reply`. Everything but a
+    /// snapshot is answered right away; a snapshot is answered by the persister once
+    /// it's on disk, so the program doesn't wait on the write.
+    pub fn admin(&mut self, command:AdminCommand, persistence_channel:&Option<Sender<PersisterMessage>>, reply:Sender<AdminReply>) {
+        let result = match command {
+            AdminCommand::Blocks => AdminReply::Blocks(self.admin_blocks()),
+            AdminCommand::Stats => AdminReply::Stats(self.stats()),
+            AdminCommand::Compact => {
+                self.compact();
+                AdminReply::Stats(self.stats())
+            }
+            AdminCommand::Enable(name) => {
+                if self.enable_block(&name) { AdminReply::Done }
+                else { AdminReply::Error(format!("No disabled block named `{}`", name)) }
+            }
+            AdminCommand::Disable(name) => {
+                if self.disable_block(&name) { AdminReply::Done }
+                else { AdminReply::Error(format!("No running block named `{}`", name)) }
+            }
+            AdminCommand::Load(path) => {
+                // loading goes through the same path as hot-reloading so a file that's
+                // already loaded only has its changed blocks swapped out
+                let mut paths = HashSet::new();
+                paths.insert(PathBuf::from(path));
+                match self.outgoing.send(RunLoopMessage::Reload(paths)) {
+                    Ok(_) => AdminReply::Done,
+                    Err(_) => AdminReply::Error("The run loop has stopped".to_string()),
+                }
+            }
+            AdminCommand::Snapshot => {
+                match persistence_channel {
+                    &Some(ref channel) => {
+                        let message = PersisterMessage::SnapshotView(Box::new(self.snapshot_view()), self.checkpoint_path.clone(), reply.clone());
+                        if channel.send(message).is_ok() { return; }
+                        AdminReply::Error("The persister has stopped".to_string())
+                    }
+                    &None => AdminReply::Error(format!("Program `{}` isn't persisted", self.name)),
+                }
+            }
+        };
+        reply.send(result).ok();
+    }
+
+    /// Makes `op` callable from this program's Eve as `op[param: ...]`. The function gets
+    /// its params in the order given (missing ones are `Internable::Null`) and returns one
+    /// value per output; with no outputs listed it returns a single value. Only blocks
+    /// compiled with this program's `compile_options` after this can call it.
+    pub fn register_function(&mut self, op:&str, params:Vec<&str>, outputs:Vec<&str>, func:Box<CustomFunction>) {
+        self.functions.register(op, FunctionInfo::custom(params, outputs), single_row(func));
+    }
+
+    /// Like `register_function`, but the function returns any number of rows, each with
+    /// one value per output, and the search matches once per row.
+    pub fn register_multi_function(&mut self, op:&str, params:Vec<&str>, outputs:Vec<&str>, func:Box<CustomMultiFunction>) {
+        self.functions.register(op, FunctionInfo::custom(params, outputs), func);
+    }
+
+    /// Declares that `attribute` only ever holds records. Strings given for it as input are
+    /// taken as record ids, and any other value that gets there is a schema error.
+    pub fn declare_reference(&mut self, attribute:&str) {
+        let a = self.state.interner.string_id(attribute);
+        self.state.schema.declare_reference(a);
+    }
+
+    /// The commits needed to fold `merge` into `keep`: every committed fact about
+    /// `merge` moves over to `keep`, and every committed fact that points at `merge`
+    /// is rewritten to point at `keep` instead.
+    pub fn merge_changes(&self, keep:Interned, merge:Interned) -> Vec<Change> {
+        let mut changes = vec![];
+        if keep == merge { return changes; }
+        let mut facts = HashSet::new();
+        for (a, v) in self.state.index.entity_facts(merge) {
+            facts.insert((merge, a, v));
+        }
+        for (e, a) in self.state.index.value_facts(merge) {
+            facts.insert((e, a, merge));
+        }
+        let rewrite = |id| if id == merge { keep } else { id };
+        for (e, a, v) in facts {
+            if !self.state.distinct_index.is_commit(e, a, v) { continue; }
+            changes.push(Change { e, a, v, n: 0, round: 0, transaction: 0, count: -1 });
+            changes.push(Change { e: rewrite(e), a, v: rewrite(v), n: 0, round: 0, transaction: 0, count: 1 });
+        }
+        changes
+    }
+
+    pub fn merge_entities(&mut self, keep:Internable, merge:Internable) {
+        let keep = self.state.interner.internable_to_id(keep);
+        let merge = self.state.interner.internable_to_id(merge);
+        let changes = self.merge_changes(keep, merge);
+        let mut iter_pool = EstimateIterPool::new();
+        let mut txn = Transaction::new(&mut iter_pool);
+        for change in changes {
+            txn.input_change(change);
+        }
+        txn.exec(self, &mut None);
+    }
+
+    /// Pulls every block of `sub` into this program with its tags and scopes moved
+    /// under `prefix/`, so whole programs can be reused as components. Tags in
+    /// `imports` are searched for in this program as-is and tags in `exports` are
+    /// written to it as-is, everything else stays private to the mounted program.
+    pub fn mount(&mut self, sub:Program, prefix:&str, imports:Vec<&str>, exports:Vec<&str>) {
+        let shared_reads:Vec<&str> = imports.iter().chain(exports.iter()).cloned().collect();
+        let roots:Vec<&str> = sub.block_info.blocks.iter().map(|block| &block.name[..]).filter(|name| !name.contains("|sub_block|")).collect();
+        let mut blocks = vec![];
+        for block in sub.block_info.blocks.iter() {
+            let mut constraints:Vec<Constraint> = block.constraints.iter().cloned().collect();
+            for constraint in constraints.iter_mut() {
+                for field in constraint.fields_mut() {
+                    if let Field::Value(id) = *field {
+                        if id != 0 {
+                            let value = mounted_intermediate(prefix, &roots, sub.state.interner.get_value(id).clone());
+                            *field = Field::Value(self.state.interner.internable_to_id(value));
+                        }
+                    }
+                }
+                mount_constraint(&mut self.state.interner, constraint, prefix, &shared_reads, &exports);
+            }
+            let name = format!("{}/{}", prefix, block.name);
+            let block_id = self.state.interner.string_id(&name);
+            let mut mounted = Block::new(&mut self.state.interner, &name, block_id, constraints);
+            mounted.path = block.path.to_string();
+            blocks.push(mounted);
+        }
+        let mut txn = CodeTransaction::new();
+        txn.exec(self, blocks, vec![]);
+    }
+
+    /// Compiles the program's files in strict mode, as though each had an `eve:strict`
+    /// line: a block that writes an attribute nothing in the project searches for or
+    /// declares fails to compile.
+    pub fn with_strict(mut self, strict:bool) -> Program {
+        self.strict = strict;
+        self
+    }
+
+    /// Strict mode knows about the attributes the schema declares and the ones the
+    /// program's running blocks already search for, on top of whatever's being compiled.
+    pub fn compile_options(&self) -> CompileOptions {
+        let mut attributes:HashSet<Interned> = self.state.schema.references().clone();
+        for block in self.block_info.blocks.iter() {
+            for constraint in block.constraints.iter() {
+                match constraint {
+                    &Constraint::Scan { a: Field::Value(a), .. } |
+                    &Constraint::LookupCommit { a: Field::Value(a), .. } |
+                    &Constraint::RangeScan { a: Field::Value(a), .. } => { attributes.insert(a); }
+                    _ => {}
+                }
+            }
+        }
+        let attributes = attributes.into_iter().filter_map(|a| self.state.interner.get_string(a)).collect();
+        CompileOptions { strict: self.strict, attributes, functions: self.functions.clone() }
+    }
+
+    /// Makes runs reproducible: given the same blocks and the same transactions, the
+    /// program produces the same facts, ids and output every time. Record ids are content
+    /// hashes unless UUIDs were asked for, in which case they come, like random/uuid,
+    /// from an RNG seeded with `seed`; date/now is frozen at the epoch. Compilation
+    /// never depends on hash order, deterministic or not. Like the id generator, this
+    /// only affects blocks registered afterwards.
+    pub fn deterministic(mut self, seed:u64) -> Program {
+        self.determinism = Some(Determinism::new(seed));
+        self
+    }
+
+    /// Sets how the ids of records made by blocks are generated. Only blocks registered
+    /// afterwards are affected, so this belongs right after `Program::new`.
+    pub fn with_id_generator(mut self, ids:IdGenerator) -> Program {
+        self.ids = ids;
+        self
+    }
+
+    /// Runs the partitions a change feeds on `threads` threads, counting the program's
+    /// own, merging what they wrote in partition order.
+    pub fn with_threads(mut self, threads:usize) -> Program {
+        self.threads = cmp::max(threads, 1);
+        self.eval_pool = if self.threads > 1 { Some(EvalPool::new(self.threads - 1)) } else { None };
+        self
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn with_limits(mut self, limits:EvalLimits) -> Program {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> EvalLimits {
+        self.limits
+    }
+
+    /// Why the last transaction was stopped before reaching a fixpoint, if it was.
+    pub fn last_error(&self) -> Option<&RuntimeError> {
+        self.last_error.as_ref()
+    }
+
+    fn runtime_error(&mut self, error:RuntimeError) {
+        // the error is reported as an #eve/error, printing it is up to the program
+        trace(DebugMode::Runtime, || format!("[{}] {} {}", &self.name, BrightRed.paint("Runtime error:"), error));
+        let mut facts = vec![
+            ("tag", Internable::String("eve/error".to_string())),
+            ("kind", Internable::String(error.kind().to_string())),
+            ("message", Internable::String(error.to_string())),
+        ];
+        for block in error.blocks() {
+            facts.push(("block", Internable::String(block.to_string())));
+        }
+        self.retract_runtime_error();
+        let id = Internable::Reference(format!("eve/error|{}|{}|", error.kind(), self.transactions));
+        self.error_record = Some(self.state.interner.internable_to_id(id.clone()));
+        self.queue_system_facts(id, facts);
+        self.last_error = Some(error);
+    }
+
+    fn retract_runtime_error(&mut self) {
+        if let Some(e) = self.error_record.take() {
+            let id = self.state.interner.get_value(e).clone();
+            self.retract_system_facts(id);
+        }
+    }
+
+    // Type errors are reported once per block, function and arguments per transaction,
+    // and the records of constraints in blocks that `ran` cleanly are taken back out.
+    fn report_function_errors(&mut self, ran:&HashSet<Interned>) {
+        let mut errors:Vec<((Interned, usize), Vec<FunctionError>)> = vec![];
+        for error in self.state.function_errors.drain(..) {
+            let key = (error.block, error.constraint);
+            match errors.iter().position(|&(found, _)| found == key) {
+                Some(ix) => if !errors[ix].1.contains(&error) { errors[ix].1.push(error); },
+                None => errors.push((key, vec![error])),
+            }
+        }
+        self.retract_type_errors(|block, constraint| ran.contains(&block) && !errors.iter().any(|&(key, _)| key == (block, constraint)));
+        for ((block_id, constraint), found) in errors {
+            let block = Internable::to_string(self.state.interner.get_value(block_id));
+            let mut facts = vec![
+                ("tag", Internable::String("eve/error".to_string())),
+                ("kind", Internable::String("type-error".to_string())),
+                ("block", Internable::String(block.to_string())),
+                ("function", Internable::String(found[0].function.to_string())),
+            ];
+            for error in found {
+                trace(DebugMode::Runtime, || format!("[{}] {} {} in {}", &self.name, BrightRed.paint("Type error:"), error, block));
+                facts.push(("message", Internable::String(error.to_string())));
+                for value in error.values {
+                    // a none argument has no value to point at
+                    if value != Internable::Null { facts.push(("value", value)); }
+                }
+            }
+            let id = Internable::Reference(format!("eve/error|type-error|{}|{}|", block, constraint));
+            let e = self.state.interner.internable_to_id(id.clone());
+            self.type_errors.insert((block_id, constraint), e);
+            self.replace_system_facts(id, facts);
+        }
+    }
+
+    // Takes back the type errors `resolved` says no longer hold, given the block and
+    // constraint each one was about.
+    fn retract_type_errors<F>(&mut self, resolved:F) where F: Fn(Interned, usize) -> bool {
+        let errors:Vec<(Interned, usize)> = self.type_errors.keys().filter(|&&(block, constraint)| resolved(block, constraint)).cloned().collect();
+        for key in errors {
+            if let Some(e) = self.type_errors.remove(&key) {
+                let id = self.state.interner.get_value(e).clone();
+                self.retract_system_facts(id);
+            }
+        }
+    }
+
+    fn report_schema_errors(&mut self) {
+        let mut errors = vec![];
+        let mut seen = HashSet::new();
+        for error in self.state.schema.errors.drain(..) {
+            if seen.insert(error) { errors.push(error); }
+        }
+        for (ix, (e, a, v)) in errors.into_iter().enumerate() {
+            let attribute = Internable::to_string(self.state.interner.get_value(a));
+            let value = self.state.interner.get_value(v).clone();
+            let message = format!("`{}` holds records, but was given {}", attribute, value.print());
+            trace(DebugMode::Runtime, || format!("[{}] {} {}", &self.name, BrightRed.paint("Schema error:"), message));
+            let facts = vec![
+                ("tag", Internable::String("eve/error".to_string())),
+                ("kind", Internable::String("schema-error".to_string())),
+                ("message", Internable::String(message)),
+                ("entity", self.state.interner.get_value(e).clone()),
+                ("attribute", Internable::String(attribute)),
+                ("value", value),
+            ];
+            self.queue_system_facts(Internable::Reference(format!("eve/error|schema-error|{}|{}|", self.transactions, ix)), facts);
+        }
+    }
+
+    /// Lets a round that grows past `threshold` pending changes spill to sorted runs in
+    /// `dir` rather than holding it all in memory. Big joins get slower, but they finish.
+    pub fn with_spill(mut self, threshold:usize, dir:&Path) -> Program {
+        self.state.rounds.spill_to(threshold, dir.to_path_buf());
+        self
+    }
+
+    pub fn transaction(&mut self) -> TransactionBuilder {
+        TransactionBuilder { program: self, changes: vec![] }
+    }
+
+    pub fn replace_scope(&mut self, scope:&str) -> ScopeReplacement {
+        ScopeReplacement { program: self, scope: scope.to_string(), facts: vec![] }
+    }
+
+    pub fn set_scope_retention(&mut self, scope:&str, retention:ScopeRetention) {
+        self.scopes.insert(scope.to_string(), retention);
+    }
+
+    pub fn scope_retention(&self, attribute:Interned) -> ScopeRetention {
+        if attribute == 0 { return ScopeRetention::Persistent; }
+        retention(&self.scopes, self.state.interner.get_value(attribute))
+    }
+
+    pub fn is_persistent(&self, attribute:Interned) -> bool {
+        self.scope_retention(attribute) == ScopeRetention::Persistent
+    }
+
+    /// Freezes or thaws a scope. Blocks can still search a read-only scope, but anything
+    /// they commit to it is dropped and reported as an `@system [#system/error]` record
+    /// instead. Changes that come in from outside, e.g. loading the reference data in the
+    /// first place, aren't affected.
+    pub fn set_scope_readonly(&mut self, scope:&str, readonly:bool) {
+        if readonly {
+            self.readonly_scopes.insert(scope.to_string());
+        } else {
+            self.readonly_scopes.remove(scope);
+            self.retract_readonly_errors(|error_scope, _| error_scope == scope);
+        }
+    }
+
+    pub fn is_scope_readonly(&self, scope:&str) -> bool {
+        self.readonly_scopes.contains(scope)
+    }
+
+    fn readonly_errors(&mut self, rejected:&[Change]) -> Vec<Change> {
+        let mut errors = vec![];
+        let n = self.state.interner.string_id("system");
+        for change in rejected {
+            let (scope, attribute) = match self.state.interner.get_value(change.a) {
+                &Internable::Scoped(ref scope, ref attribute) => (scope.to_string(), attribute.to_string()),
+                _ => continue,
+            };
+            // block commits carry the committing block's id, anything else came from outside
+            let block = self.block_info.blocks.iter().find(|block| block.block_id == change.n).map(|block| (block.block_id, block.name.to_string()));
+            let message = match block {
+                Some((_, ref block)) => format!("Block '{}' tried to change @{}, which is read-only", block, scope),
+                None => format!("Tried to change @{}, which is read-only", scope),
+            };
+            let id = Internable::Reference(format!("system/error|readonly|{}|{}|{}|{}|", change.e, change.a, change.v, change.count > 0));
+            let e = self.state.interner.internable_to_id(id);
+            // the same rejection again is already on record
+            if self.readonly_rejections.contains_key(&e) { continue; }
+            self.readonly_rejections.insert(e, (scope.to_string(), block.as_ref().map(|&(block_id, _)| block_id)));
+            let mut facts = vec![
+                ("tag", Internable::String("system/error".to_string())),
+                ("kind", Internable::String("readonly".to_string())),
+                ("scope", Internable::String(scope)),
+                ("attribute", Internable::String(attribute)),
+                ("change", Internable::String(if change.count > 0 { "add" } else { "remove" }.to_string())),
+                ("message", Internable::String(message)),
+            ];
+            if let Some((_, block)) = block { facts.push(("block", Internable::String(block))); }
+            for (a, v) in facts {
+                let a = self.state.interner.scoped_id("system", a);
+                let v = self.state.interner.internable_to_id(v);
+                errors.push(Change { e, a, v, n, round: 0, transaction: 0, count: 1 });
+            }
+            let a = self.state.interner.scoped_id("system", "entity");
+            errors.push(Change { e, a, v: change.e, n, round: 0, transaction: 0, count: 1 });
+            let a = self.state.interner.scoped_id("system", "value");
+            errors.push(Change { e, a, v: change.v, n, round: 0, transaction: 0, count: 1 });
+        }
+        errors
+    }
+
+    // Takes back the read-only errors `resolved` says no longer hold, given the scope and
+    // the block each one was about.
+    fn retract_readonly_errors<F>(&mut self, resolved:F) where F: Fn(&str, Option<Interned>) -> bool {
+        let errors:Vec<Interned> = self.readonly_rejections.iter()
+            .filter(|&(_, &(ref scope, block))| resolved(scope, block))
+            .map(|(&e, _)| e)
+            .collect();
+        for e in errors {
+            self.readonly_rejections.remove(&e);
+            let id = self.state.interner.get_value(e).clone();
+            self.retract_system_facts(id);
+        }
+    }
+
+    fn expired_scope_changes(&self, changes:&[Change]) -> Vec<Change> {
+        let mut seen = HashSet::new();
+        changes.iter()
+            .filter(|change| change.count > 0 && self.scope_retention(change.a) == ScopeRetention::Transaction)
+            .filter(|change| seen.insert((change.e, change.a, change.v)))
+            .filter(|change| self.state.distinct_index.is_commit(change.e, change.a, change.v))
+            .map(|change| Change { count: -1, round: 0, ..*change })
+            .collect()
+    }
+
+    pub fn annotated_transaction(&mut self, mut changes:Vec<RawChange>, annotations:Vec<(String, Internable)>) {
+        annotate_changes(&mut changes, annotations);
+        let mut iter_pool = EstimateIterPool::new();
+        let mut txn = Transaction::new(&mut iter_pool);
+        for change in changes {
+            txn.input_change(change.to_change(&mut self.state.interner));
+        }
+        txn.exec(self, &mut None);
+    }
+
+    /// Attaches the watcher under its own name. A watcher already attached under that
+    /// name is torn down and replaced.
+    pub fn attach(&mut self, mut watcher:Box<Watcher + Send>) {
+        let name = watcher.get_name();
+        println!("[{}] {} {}", &self.name, BrightCyan.paint("Loaded Watcher:"), name);
+        if let Some(mut replaced) = self.watchers.remove(&name) {
+            replaced.teardown();
+        }
+        watcher.setup(WatcherErrors::new(&name, self.outgoing.clone()));
+        for dependency in watcher.dependencies() {
+            self.watcher_dependencies.entry(name.to_string()).or_insert_with(|| vec![]).push(dependency);
+        }
+        if !self.watcher_registration.contains(&name) {
+            self.watcher_registration.push(name.to_string());
+        }
+        let watcher_id = Internable::Reference(format!("system/watcher|{}|", name));
+        self.retract_system_facts(watcher_id.clone());
+        self.queue_system_facts(watcher_id, vec![
+            ("tag", Internable::String("system/watcher".to_string())),
+            ("name", Internable::String(name.to_string())),
+        ]);
+        self.watchers.insert(name, watcher);
+        self.order_watchers();
+    }
+
+    /// Attaches the watcher as `name`, which is what its program's `watch` blocks refer to.
+    pub fn attach_watcher(&mut self, name:&str, mut watcher:Box<Watcher + Send>) {
+        watcher.set_name(name);
+        self.attach(watcher);
+    }
+
+    /// Tears the watcher down and hands it back. Its watch blocks stay, but their diffs
+    /// go nowhere until a watcher is attached under the same name again.
+    pub fn detach_watcher(&mut self, name:&str) -> Option<Box<Watcher + Send>> {
+        let mut watcher = self.watchers.remove(name)?;
+        println!("[{}] {} {}", &self.name, BrightCyan.paint("Detached Watcher:"), name);
+        watcher.teardown();
+        self.watcher_registration.retain(|cur| cur != name);
+        self.watcher_dependencies.remove(name);
+        self.retract_system_facts(Internable::Reference(format!("system/watcher|{}|", name)));
+        self.order_watchers();
+        Some(watcher)
+    }
+
+    /// A handle asynchronous watchers can push changes into from any thread. See Input.
+    pub fn input(&self, config:InputConfig) -> Input {
+        Input::new(self.outgoing.clone(), config)
+    }
+
+    /// Tears down every watcher, e.g. when the program stops.
+    pub fn teardown_watchers(&mut self) {
+        for name in self.watcher_order.iter() {
+            if let Some(watcher) = self.watchers.get_mut(name) {
+                watcher.teardown();
+            }
+        }
+    }
+
+    pub fn watcher_error(&mut self, watcher:&str, message:&str) {
+        println!("[{}] {} {}: {}", &self.name, BrightRed.paint("Watcher error:"), watcher, message);
+        self.watcher_errors += 1;
+        self.queue_system_facts(Internable::Reference(format!("eve/error|watcher|{}|", self.watcher_errors)), vec![
+            ("tag", Internable::String("eve/error".to_string())),
+            ("kind", Internable::String("watcher".to_string())),
+            ("watcher", Internable::String(watcher.to_string())),
+            ("message", Internable::String(message.to_string())),
+        ]);
+    }
+
+    fn perf_warning(&mut self, warning:PerfWarning) {
+        let (name, join) = match self.block_info.blocks.iter().find(|block| block.block_id == warning.block) {
+            Some(block) => (block.name.to_string(), block.constraints.get(warning.join).map_or("".to_string(), |join| describe_join(join, &self.state.interner))),
+            None => return,
+        };
+        self.queue_system_facts(perf_warning_id(&name), vec![
+            ("tag", Internable::String("system/perf-warning".to_string())),
+            ("block", Internable::String(name.to_string())),
+            ("growth", Internable::from_number(warning.growth as f32)),
+            ("join", Internable::String(join)),
+            ("samples", Internable::from_number(warning.samples as f32)),
+        ]);
+    }
+
+    fn queue_system_facts(&mut self, id:Internable, facts:Vec<(&str, Internable)>) {
+        let e = self.state.interner.internable_to_id(id);
+        let n = self.state.interner.string_id("system");
+        for (a, v) in facts {
+            let a = self.state.interner.scoped_id("system", a);
+            let v = self.state.interner.internable_to_id(v);
+            self.system_changes.push(Change { e, a, v, n, round: 0, transaction: 0, count: 1 });
+        }
+    }
+
+    // Swaps whatever value the reflective fact had, queued or already in the index,
+    // for `value`.
+    fn update_system_fact(&mut self, id:Internable, attribute:&str, value:Internable) {
+        let e = self.state.interner.internable_to_id(id);
+        let a = self.state.interner.scoped_id("system", attribute);
+        let v = self.state.interner.internable_to_id(value);
+        let n = self.state.interner.string_id("system");
+        self.system_changes.retain(|change| change.e != e || change.a != a);
+        let mut current = false;
+        for (fact_a, fact_v) in self.state.index.entity_facts(e) {
+            if fact_a != a { continue; }
+            if fact_v == v {
+                current = true;
+            } else {
+                self.system_changes.push(Change { e, a, v: fact_v, n, round: 0, transaction: 0, count: -1 });
+            }
+        }
+        if !current {
+            self.system_changes.push(Change { e, a, v, n, round: 0, transaction: 0, count: 1 });
+        }
     }
 
-    pub fn unregister_block(&mut self, name:String) {
-        if let Some(block_ix) = self.block_info.block_names.remove(&name) {
-            let block = self.block_info.blocks.swap_remove(block_ix);
-            if let Some(neue) = self.block_info.blocks.get(block_ix) {
-                self.block_info.block_names.insert(neue.name.to_owned(), block_ix);
-            }
-            for shape_set in block.shapes.iter() {
-                for shape in shape_set.iter() {
-                    match shape {
-                        &PipeShape::Scan(e, a, v) => {
-                            self.block_info.pipe_lookup.get_mut(&(e, a, v)).unwrap().retain(|x| x.block != block.block_id);
-                        },
-                        &PipeShape::Intermediate(id) => {
-                            self.block_info.intermediate_pipe_lookup.get_mut(&id).unwrap().retain(|x| x.block != block.block_id);
-                        }
-                        &PipeShape::Remote(id) => {
-                            self.block_info.remote_pipe_lookup.get_mut(&id).unwrap().retain(|x| x.block != block.block_id);
-                        }
-                    }
-                }
+    // Leaves the reflective record holding exactly `facts`, queueing only what changes.
+    fn replace_system_facts(&mut self, id:Internable, facts:Vec<(&str, Internable)>) {
+        let e = self.state.interner.internable_to_id(id);
+        let n = self.state.interner.string_id("system");
+        let mut wanted = vec![];
+        for (a, v) in facts {
+            let fact = (self.state.interner.scoped_id("system", a), self.state.interner.internable_to_id(v));
+            if !wanted.contains(&fact) { wanted.push(fact); }
+        }
+        self.system_changes.retain(|change| change.e != e);
+        for (a, v) in self.state.index.entity_facts(e) {
+            match wanted.iter().position(|&fact| fact == (a, v)) {
+                Some(ix) => { wanted.remove(ix); }
+                None => self.system_changes.push(Change { e, a, v, n, round: 0, transaction: 0, count: -1 }),
             }
         }
+        for (a, v) in wanted {
+            self.system_changes.push(Change { e, a, v, n, round: 0, transaction: 0, count: 1 });
+        }
     }
 
-    pub fn insert_block(&mut self, name:&str, code:&str) {
-        let bs = make_block(&mut self.state.interner, name, code);
-        for b in bs {
-            self.register_block(b);
+    fn retract_system_facts(&mut self, id:Internable) {
+        let e = self.state.interner.internable_to_id(id);
+        let n = self.state.interner.string_id("system");
+        self.system_changes.retain(|change| change.e != e);
+        for (a, v) in self.state.index.entity_facts(e) {
+            self.system_changes.push(Change { e, a, v, n, round: 0, transaction: 0, count: -1 });
         }
     }
 
-    pub fn block(&mut self, name:&str, code:&str) -> CodeTransaction {
-        let bs = make_block(&mut self.state.interner, name, code);
-        let mut txn = CodeTransaction::new();
-        txn.exec(self, bs, vec![]);
-        txn
+    /// A channel that's sent a Fixpoint after every transaction from now on, for hosts
+    /// that need to know when it's safe to read from the program. Dropping the receiver
+    /// is enough to stop the notifications.
+    pub fn on_fixpoint(&mut self) -> Receiver<Fixpoint> {
+        let (sender, receiver) = mpsc::channel();
+        self.fixpoint_listeners.push(sender);
+        receiver
     }
 
-    pub fn raw_block(&mut self, block:Block) {
-        self.register_block(block);
+    fn notify_fixpoint(&mut self, stats:TransactionStats, error:Option<RuntimeError>) {
+        let fixpoint = Fixpoint { transaction: self.transactions, stats, error };
+        self.fixpoint_listeners.retain(|listener| listener.send(fixpoint.clone()).is_ok());
     }
 
-    pub fn blocks_by_path(&self, path:&str) -> Vec<&Block> {
-        self.block_info.blocks.iter().filter(|block| block.path == path).collect()
+    pub fn add_watcher_dependency(&mut self, watcher:&str, dependency:&str) {
+        self.watcher_dependencies.entry(watcher.to_string()).or_insert_with(|| vec![]).push(dependency.to_string());
+        self.order_watchers();
     }
 
-    pub fn attach(&mut self, watcher:Box<Watcher + Send>) {
-        let name = watcher.get_name();
-        println!("[{}] {} {}", &self.name, BrightCyan.paint("Loaded Watcher:"), name);
-        self.watchers.insert(name, watcher);
+    pub fn watcher_order(&self) -> &Vec<String> {
+        &self.watcher_order
+    }
+
+    // Within a transaction watchers get their diffs in the order they were attached,
+    // except that a watcher always comes after the watchers it depends on.
+    fn order_watchers(&mut self) {
+        let mut ordered = vec![];
+        let mut remaining = self.watcher_registration.clone();
+        while remaining.len() > 0 {
+            let ready = remaining.iter().position(|name| {
+                match self.watcher_dependencies.get(name) {
+                    Some(dependencies) => dependencies.iter().all(|dependency| !remaining.contains(dependency)),
+                    None => true,
+                }
+            });
+            match ready {
+                Some(ix) => { ordered.push(remaining.remove(ix)); }
+                None => {
+                    println!("[{}] {} {}", &self.name, BrightYellow.paint("Cyclic watcher dependencies:"), remaining.join(", "));
+                    ordered.extend(remaining.drain(..));
+                }
+            }
+        }
+        self.watcher_order = ordered;
     }
 
     pub fn get_pipes<'a>(&self, block_info:&'a BlockInfo, input: &Change, pipes: &mut HashSet<&'a Solver>) {
@@ -2868,11 +6866,171 @@ impl Program {
     }
 }
 
+//-------------------------------------------------------------------------
+// Eval Pool
+//-------------------------------------------------------------------------
+
+// The partition of each block, see `BlockInfo::partitions`, and which partitions have to
+// run on the program's thread since their blocks write to the state as they go: blocks
+// in distinct mode, and ones calling index functions.
+pub struct Partitions {
+    of: HashMap<Interned, usize>,
+    owned: Vec<bool>,
+}
+
+impl Partitions {
+    pub fn new(block_info:&BlockInfo) -> Partitions {
+        let ids = block_info.partition_ids();
+        let mut of = HashMap::new();
+        let mut owned = vec![false; ids.iter().max().map_or(0, |&max| max + 1)];
+        for (block, &id) in block_info.blocks.iter().zip(ids.iter()) {
+            of.insert(block.block_id, id);
+            let index_function = block.constraints.iter().any(|constraint| if let &Constraint::IndexFunction {..} = constraint { true } else { false });
+            if block.metadata.distinct || index_function { owned[id] = true; }
+        }
+        Partitions { of, owned }
+    }
+
+    // Blocks it doesn't know about run on the program's thread after everything else.
+    fn get(&self, block:Interned) -> usize {
+        self.of.get(&block).cloned().unwrap_or(usize::MAX)
+    }
+
+    fn is_owned(&self, partition:usize) -> bool {
+        self.owned.get(partition).cloned().unwrap_or(true)
+    }
+}
+
+type EvalJob<'a> = Box<FnOnce(&mut EstimateIterPool, &mut Frame) + Send + 'a>;
+
+/// Threads for running a change's partitions side by side, see `Program::with_threads`.
+/// Each one has an iterator pool and frame of its own.
+pub struct EvalPool {
+    jobs: Option<Sender<(EvalJob<'static>, Sender<bool>)>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl EvalPool {
+    pub fn new(threads:usize) -> EvalPool {
+        let (jobs, incoming) = mpsc::channel::<(EvalJob<'static>, Sender<bool>)>();
+        let incoming = Arc::new(Mutex::new(incoming));
+        let workers = (0..threads).map(|ix| {
+            let incoming = incoming.clone();
+            thread::Builder::new().name(format!("eval {}", ix)).spawn(move || {
+                let mut iter_pool = EstimateIterPool::new();
+                let mut frame = Frame::new();
+                loop {
+                    let next = incoming.lock().unwrap().recv();
+                    let (job, done) = match next { Ok(job) => job, Err(_) => break };
+                    let ran = panic::catch_unwind(panic::AssertUnwindSafe(|| job(&mut iter_pool, &mut frame))).is_ok();
+                    let _ = done.send(ran);
+                }
+            }).unwrap()
+        }).collect();
+        EvalPool { jobs: Some(jobs), workers }
+    }
+
+    // Runs every job, the first on the calling thread with its pool and frame, and only
+    // returns once they've all finished, which is what lets them borrow from the caller.
+    fn scoped<'a>(&self, mut jobs:Vec<EvalJob<'a>>, iter_pool:&mut EstimateIterPool, frame:&mut Frame) {
+        if jobs.is_empty() { return; }
+        let first = jobs.remove(0);
+        let (done, finished) = mpsc::channel();
+        let sent = jobs.len();
+        for job in jobs {
+            let job:EvalJob<'static> = unsafe { transmute(job) };
+            self.jobs.as_ref().unwrap().send((job, done.clone())).unwrap();
+        }
+        let mut ran = panic::catch_unwind(panic::AssertUnwindSafe(|| first(iter_pool, frame))).is_ok();
+        for _ in 0..sent {
+            // a job that was dropped without running drops its sender too
+            ran &= finished.recv().unwrap_or(false);
+        }
+        if !ran { panic!("A partition panicked while running"); }
+    }
+}
+
+impl Drop for EvalPool {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn run_pipe(pipe:&Solver, intermediate:bool, state:&mut RuntimeState, iter_pool:&mut EstimateIterPool, frame:&mut Frame, ran:&mut FnMut(Interned, &[u64])) {
+    frame.row.reset();
+    frame.steps.clear();
+    if intermediate { pipe.run_intermediate(state, iter_pool, frame); } else { pipe.run(state, iter_pool, frame); }
+    ran(pipe.block, &frame.steps);
+}
+
+// Runs the pipes an input feeds. With an eval pool, partitions that only read the state
+// run side by side against it as it is and what each of them wrote is merged back in
+// partition order, so the result doesn't depend on which one finished first. Partitions
+// that write as they go run on this thread when their turn in that order comes.
+fn run_pipes<'a, I:Iterator<Item=&'a Solver>>(pipes:I, intermediate:bool, state:&mut RuntimeState, iter_pool:&mut EstimateIterPool, frame:&mut Frame, eval:Option<(&EvalPool, &Partitions)>, ran:&mut FnMut(Interned, &[u64])) {
+    let (pool, partitions) = match eval {
+        Some(eval) => eval,
+        None => {
+            for pipe in pipes { run_pipe(pipe, intermediate, state, iter_pool, frame, ran); }
+            return;
+        }
+    };
+    let mut groups:BTreeMap<usize, Vec<&Solver>> = BTreeMap::new();
+    for pipe in pipes {
+        groups.entry(partitions.get(pipe.block)).or_insert_with(|| vec![]).push(pipe);
+    }
+    for group in groups.values_mut() {
+        group.sort_by_key(|pipe| (pipe.block, pipe.id));
+    }
+    let shared_count = groups.keys().filter(|&&id| !partitions.is_owned(id)).count();
+    let mut outputs:Vec<PartitionOutputs> = if shared_count > 1 {
+        (0..shared_count).map(|_| PartitionOutputs::new(state)).collect()
+    } else {
+        vec![]
+    };
+    if !outputs.is_empty() {
+        let shared:&RuntimeState = state;
+        let (input, intermediate_input) = (frame.input, frame.intermediate.clone());
+        let jobs = groups.iter().filter(|&(&id, _)| !partitions.is_owned(id)).zip(outputs.iter_mut()).map(|((_, group), outputs)| {
+            let intermediate_input = intermediate_input.clone();
+            Box::new(move |iter_pool:&mut EstimateIterPool, frame:&mut Frame| {
+                frame.reset();
+                frame.input = input;
+                frame.intermediate = intermediate_input;
+                for pipe in group.iter() {
+                    frame.row.reset();
+                    frame.steps.clear();
+                    if intermediate { pipe.run_intermediate_shared(shared, outputs, iter_pool, frame); } else { pipe.run_shared(shared, outputs, iter_pool, frame); }
+                    outputs.steps.push((pipe.block, frame.steps.clone()));
+                }
+            }) as EvalJob
+        }).collect();
+        pool.scoped(jobs, iter_pool, frame);
+    }
+    // with only one partition to run there's nothing to run it beside, so it's run here
+    // like the rest
+    let mut outputs = outputs.into_iter();
+    for (id, group) in groups {
+        if shared_count > 1 && !partitions.is_owned(id) {
+            let outputs = outputs.next().unwrap();
+            for &(block, ref steps) in outputs.steps.iter() {
+                ran(block, steps);
+            }
+            outputs.merge(state);
+        } else {
+            for pipe in group { run_pipe(pipe, intermediate, state, iter_pool, frame, ran); }
+        }
+    }
+}
+
 //-------------------------------------------------------------------------
 // Transaction
 //-------------------------------------------------------------------------
 
-fn intermediate_flow(frame: &mut Frame, state: &mut RuntimeState, block_info: &BlockInfo, iter_pool:&mut EstimateIterPool, current_round:Round, max_round:&mut Round) {
+fn intermediate_flow(frame: &mut Frame, state: &mut RuntimeState, block_info: &BlockInfo, iter_pool:&mut EstimateIterPool, eval:Option<(&EvalPool, &Partitions)>, current_round:Round, max_round:&mut Round) {
     let mut intermediate_max = state.intermediates.consume_round();
     *max_round = cmp::max(*max_round, intermediate_max);
     if let Some(_) = state.intermediates.rounds.get(&current_round) {
@@ -2885,15 +7043,7 @@ fn intermediate_flow(frame: &mut Frame, state: &mut RuntimeState, block_info: &B
                 if let Some(ref actives) = block_info.intermediate_pipe_lookup.get(&cur.key[0]) {
                     frame.reset();
                     frame.intermediate = Some(cur);
-                    for pipe in actives.iter() {
-                        // print_pipe(pipe, block_info, state);
-                        frame.row.reset();
-                        pipe.run_intermediate(state, iter_pool, frame);
-                        // if state.debug {
-                        //     state.debug = false;
-                        //     println!("\n---------------------------------\n");
-                        // }
-                    }
+                    run_pipes(actives.iter(), true, state, iter_pool, frame, eval, &mut |_, _| {});
                 }
             }
             intermediate_max = state.intermediates.consume_round();
@@ -2904,79 +7054,323 @@ fn intermediate_flow(frame: &mut Frame, state: &mut RuntimeState, block_info: &B
 }
 
 fn transaction_flow(commits: &mut Vec<Change>, frame: &mut Frame, iter_pool:&mut EstimateIterPool, program: &mut Program) {
-    transaction_flow_meta(commits, frame, iter_pool, program, None)
-}
-
-fn transaction_flow_meta(commits: &mut Vec<Change>, frame: &mut Frame, iter_pool:&mut EstimateIterPool, program: &mut Program, maybe_meta: Option<&mut MetaMessage>) {
+    transaction_flow_meta(commits, frame, iter_pool, program, None);
+}
+
+fn transaction_flow_meta(commits: &mut Vec<Change>, frame: &mut Frame, iter_pool:&mut EstimateIterPool, program: &mut Program, maybe_meta: Option<&mut MetaMessage>) -> TransactionStats {
+    let start_ns = time::precise_time_ns();
+    let mut stats = TransactionStats::default();
+    // reflective @system facts about blocks and watchers ride along with whatever
+    // transaction comes next
+    let system_changes:Vec<Change> = program.system_changes.drain(..).collect();
+    for change in system_changes.iter() {
+        program.state.distinct_index.distinct(change, &mut program.state.rounds);
+    }
+    // changes fed to and join steps taken by each block this transaction
+    let mut costs:HashMap<Interned, (u64, Vec<u64>)> = HashMap::new();
+    let limits = program.limits;
+    let mut total_rounds = 0;
+    // the blocks that ran in the current frame, to point at if the limits are hit
+    let mut frame_blocks:HashSet<Interned> = HashSet::new();
+    let mut error = None;
+    program.last_error = None;
+    let deduplicated_before = program.state.rounds.duplicates + program.state.rounds.redundant;
+    if program.eval_pool.is_some() && program.partitions.is_none() {
+        program.partitions = Some(Partitions::new(&program.block_info));
+    }
     {
-        let mut pipes = HashSet::new();
         let mut next_frame = true;
+        let mut expired_until = 0;
 
         while next_frame {
+            // pipes borrow the block info, so they can't outlive a frame's rounds
+            let mut pipes = HashSet::new();
+            let eval = match (program.eval_pool.as_ref(), program.partitions.as_ref()) {
+                (Some(pool), Some(partitions)) => Some((pool, partitions)),
+                _ => None,
+            };
+            frame_blocks.clear();
+            let frame_commits = commits.len();
+            let frame_outputs = match maybe_meta {
+                Some(&mut MetaMessage::Transaction{ref outputs, ..}) => outputs.len(),
+                _ => 0,
+            };
             let mut current_round = 0;
             let mut max_round:Round = program.state.rounds.max_round as Round;
             let mut items = program.state.rounds.iter();
             while current_round <= max_round {
-                let round = items.get_round(&mut program.state.rounds, current_round);
-                for change in round.iter() {
-                    // println!("-> {}", change.print(&program.state.interner));
-                    // If this is an add, we want to do it *before* we start running pipes.
-                    // This ensures that if there are two constraints in a single block that
-                    // would both match the given input, they both have a chance to see this
-                    // new triple at the same time. Doing so, means we don't have to go through
-                    // every possible combination of the inputs, e.g. A, B, and AB. Instead we
-                    // do AB and BA. To make sure that removes correctly cancel out, we don't
-                    // want to do a real remove until *after* the pipes have run. Hence, the
-                    // separation of insert and remove.
-                    if change.count > 0 {
-                        if program.state.distinct_index.insert_active(change.e, change.a, change.v, change.round) {
-                            let added = program.state.index.insert(change.e, change.a, change.v);
-                            if let Some(&mut MetaMessage::Transaction{ref mut outputs, ..}) = maybe_meta {
-                                if added { outputs.push(change.to_raw(&program.state.interner)); }
+                let mut round = items.get_round(&mut program.state.rounds, current_round);
+                loop {
+                    stats.changes += round.len();
+                    for change in round.iter() {
+                        // println!("-> {}", change.print(&program.state.interner));
+                        // If this is an add, we want to do it *before* we start running pipes.
+                        // This ensures that if there are two constraints in a single block that
+                        // would both match the given input, they both have a chance to see this
+                        // new triple at the same time. Doing so, means we don't have to go through
+                        // every possible combination of the inputs, e.g. A, B, and AB. Instead we
+                        // do AB and BA. To make sure that removes correctly cancel out, we don't
+                        // want to do a real remove until *after* the pipes have run. Hence, the
+                        // separation of insert and remove.
+                        if change.count > 0 {
+                            if program.state.distinct_index.insert_active(change.e, change.a, change.v, change.round) {
+                                let added = program.state.index.insert_value(change.e, change.a, change.v, &program.state.interner);
+                                if added {
+                                    stats.added += 1;
+                                    program.state.schema.check(change.e, change.a, change.v, &program.state.interner);
+                                }
+                                if let Some(&mut MetaMessage::Transaction{ref mut outputs, ..}) = maybe_meta {
+                                    if added { outputs.push(change.to_raw(&program.state.interner)); }
+                                }
                             }
                         }
-                    }
-                    pipes.clear();
-                    program.get_pipes(&program.block_info, change, &mut pipes);
-                    frame.reset();
-                    frame.input = Some(*change);
-                    for pipe in pipes.iter() {
-                        // println!("  PIPE: {:?} - {:?}", pipe.block, pipe.id);
-                        frame.row.reset();
-                        pipe.run(&mut program.state, iter_pool, frame);
-                    }
-                    // as stated above, we want to do removes after so that when we look
-                    // for AB and BA, they find the same values as when they were added.
-                    if change.count < 0 {
-                        if program.state.distinct_index.remove_active(change.e, change.a, change.v, change.round) {
-                            let removed = program.state.index.remove(change.e, change.a, change.v);
-                            if let Some(&mut MetaMessage::Transaction{ref mut outputs, ..}) = maybe_meta {
-                                if removed { outputs.push(change.to_raw(&program.state.interner)); }
+                        pipes.clear();
+                        program.get_pipes(&program.block_info, change, &mut pipes);
+                        frame.reset();
+                        frame.input = Some(*change);
+                        run_pipes(pipes.iter().cloned(), false, &mut program.state, iter_pool, frame, eval, &mut |block, steps| {
+                            frame_blocks.insert(block);
+                            let cost = costs.entry(block).or_insert_with(|| (0, vec![]));
+                            cost.0 += 1;
+                            if cost.1.len() < steps.len() { cost.1.resize(steps.len(), 0); }
+                            for (total, steps) in cost.1.iter_mut().zip(steps.iter()) {
+                                *total += *steps;
                             }
+                        });
+                        // as stated above, we want to do removes after so that when we look
+                        // for AB and BA, they find the same values as when they were added.
+                        if change.count < 0 {
+                            if program.state.distinct_index.remove_active(change.e, change.a, change.v, change.round) {
+                                let removed = program.state.index.remove_value(change.e, change.a, change.v, &program.state.interner);
+                                if removed {
+                                    stats.removed += 1;
+                                    if let Some(ref mut provenance) = program.state.provenance { provenance.forget(change.e, change.a, change.v); }
+                                }
+                                if let Some(&mut MetaMessage::Transaction{ref mut outputs, ..}) = maybe_meta {
+                                    if removed { outputs.push(change.to_raw(&program.state.interner)); }
+                                }
 
+                            }
+                        }
+                        if current_round == 0 { commits.push(change.clone()); }
+                        if let Some(&mut MetaMessage::Transaction{ref mut outputs, ..}) = maybe_meta {
+                            outputs.push(change.to_raw(&program.state.interner));
                         }
                     }
-                    if current_round == 0 { commits.push(change.clone()); }
-                    if let Some(&mut MetaMessage::Transaction{ref mut outputs, ..}) = maybe_meta {
-                        outputs.push(change.to_raw(&program.state.interner));
-                    }
+                    // a round that spilled to disk comes back a batch at a time
+                    if !program.state.rounds.is_merging() { break; }
+                    round = items.next_batch(&mut program.state.rounds);
                 }
-                intermediate_flow(frame, &mut program.state, &program.block_info, iter_pool, current_round, &mut max_round);
+                intermediate_flow(frame, &mut program.state, &program.block_info, iter_pool, eval, current_round, &mut max_round);
                 max_round = cmp::max(max_round, program.state.rounds.max_round as Round);
                 current_round += 1;
+                // a frame that never settles is stopped here, the checks below report it
+                if total_rounds + current_round as usize > limits.max_rounds || stats.changes > limits.max_facts { break; }
+            }
+            stats.frames += 1;
+            stats.rounds = cmp::max(stats.rounds, current_round as usize);
+            total_rounds += current_round as usize;
+            if let Some(message) = program.state.rounds.take_spill_error() {
+                error = Some(RuntimeError::Spill { message });
+            } else if total_rounds > limits.max_rounds || stats.changes > limits.max_facts {
+                let mut blocks:Vec<String> = frame_blocks.iter().filter_map(|&block| program.state.interner.get_string(block)).collect();
+                blocks.sort();
+                error = Some(if total_rounds > limits.max_rounds {
+                    RuntimeError::RoundLimit { limit: limits.max_rounds, blocks }
+                } else {
+                    RuntimeError::FactLimit { limit: limits.max_facts, blocks }
+                });
             }
-            next_frame = program.state.rounds.prepare_commits(&mut program.state.index, &mut program.state.distinct_index);
+            if error.is_some() {
+                // the frame is taken back along with whatever rounds it didn't get to
+                program.state.roll_back();
+                commits.truncate(frame_commits);
+                // the @system facts the first frame took back wait for the next transaction
+                if stats.frames == 1 { program.system_changes.extend(system_changes.iter().cloned()); }
+                if let Some(&mut MetaMessage::Transaction{ref mut outputs, ..}) = maybe_meta {
+                    outputs.truncate(frame_outputs);
+                }
+                break;
+            }
+            program.state.save_point();
+            let mut rejected = vec![];
+            next_frame = {
+                let readonly_scopes = &program.readonly_scopes;
+                let interner = &program.state.interner;
+                program.state.rounds.prepare_commits(&mut program.state.index, &mut program.state.distinct_index, &mut rejected, |a| {
+                    !readonly_scopes.is_empty() && readonly_attribute(readonly_scopes, interner, a)
+                })
+            };
+            if rejected.len() > 0 {
+                let errors = program.readonly_errors(&rejected);
+                for change in errors.iter() {
+                    program.state.distinct_index.distinct(change, &mut program.state.rounds);
+                }
+                next_frame = true;
+            }
+            if !next_frame {
+                // once we've hit a fixpoint, anything committed to a per-transaction
+                // scope is taken back out, which runs everything derived from it out too
+                let expired = program.expired_scope_changes(&commits[expired_until..]);
+                expired_until = commits.len();
+                for change in expired.iter() {
+                    program.state.distinct_index.distinct(change, &mut program.state.rounds);
+                }
+                next_frame = expired.len() > 0;
+            }
+        }
+    }
+
+    let ran:HashSet<Interned> = costs.keys().cloned().collect();
+    for (block, (changes, steps)) in costs {
+        if let Some(warning) = program.perf.observe(block, changes, &steps) {
+            program.perf_warning(warning);
         }
+        program.refresh_block_stats(block);
     }
+    program.replan_scans();
 
+    program.delivery.begin();
+    let mut diffs = HashMap::new();
     for (name, index) in program.state.watch_indexes.iter_mut() {
-        if index.dirty() {
-            let diff = index.reconcile();
+        if index.dirty() || program.delivery.is_resuming(name) {
+            let reconciled = index.reconcile();
+            let diff = program.delivery.deliver(name, &mut program.state.interner, reconciled, index);
+            diffs.insert(name.to_string(), diff);
+        }
+    }
+    for name in program.watcher_order.iter() {
+        if let Some(diff) = diffs.remove(name) {
             if let Some(watcher) = program.watchers.get_mut(name) {
                 watcher.on_diff(&mut program.state.interner, diff);
             }
         }
     }
+    if let Err(message) = program.delivery.commit() {
+        if error.is_none() { error = Some(RuntimeError::Delivery { message }); }
+    }
+    stats.commits = commits.len();
+    stats.deduplicated = program.state.rounds.duplicates + program.state.rounds.redundant - deduplicated_before;
+    stats.ns = time::precise_time_ns() - start_ns;
+    program.report_function_errors(&ran);
+    program.report_schema_errors();
+    program.transactions += 1;
+    match error {
+        Some(ref error) => program.runtime_error(error.clone()),
+        None => program.retract_runtime_error(),
+    }
+    program.notify_fixpoint(stats, error);
+    stats
+}
+
+//-------------------------------------------------------------------------
+// Transaction builder
+//-------------------------------------------------------------------------
+
+fn exec_with_outputs(program:&mut Program, iter_pool:&mut EstimateIterPool, persistence_channel:&mut Option<Sender<PersisterMessage>>, changes:Vec<RawChange>) -> Vec<RawChange> {
+    let mut txn = Transaction::new(iter_pool);
+    for change in changes {
+        txn.input_change(change.to_change(&mut program.state.interner));
+    }
+    let mut meta_message = MetaMessage::Transaction{inputs: vec![], outputs: vec![]};
+    txn.exec_meta(program, persistence_channel, Some(&mut meta_message));
+    let MetaMessage::Transaction{outputs, ..} = meta_message.collapse();
+    outputs
+}
+
+// Stages inserts and removes so a host can hand them to the program as a single
+// transaction. Once the program reaches a fixpoint, it gets back every change the
+// transaction caused, both its own and everything derived from them.
+pub struct TransactionBuilder<'a> {
+    program: &'a mut Program,
+    changes: Vec<RawChange>,
+}
+
+impl<'a> TransactionBuilder<'a> {
+    pub fn insert(mut self, e:Internable, a:&str, v:Internable) -> TransactionBuilder<'a> {
+        self.changes.push(RawChange::new(e, Internable::String(a.to_string()), v, Internable::String("host".to_string()), 1));
+        self
+    }
+
+    pub fn remove(mut self, e:Internable, a:&str, v:Internable) -> TransactionBuilder<'a> {
+        self.changes.push(RawChange::new(e, Internable::String(a.to_string()), v, Internable::String("host".to_string()), -1));
+        self
+    }
+
+    pub fn changes(&self) -> &Vec<RawChange> {
+        &self.changes
+    }
+
+    pub fn commit(self) -> Vec<RawChange> {
+        let mut iter_pool = EstimateIterPool::new();
+        exec_with_outputs(self.program, &mut iter_pool, &mut None, self.changes)
+    }
+
+    pub fn commit_then<F>(self, callback:F) where F: FnOnce(Vec<RawChange>) {
+        callback(self.commit());
+    }
+}
+
+/// Stages the complete new contents of a scope, e.g. a re-imported CSV, and swaps it in
+/// as one transaction. Only the difference between what the scope holds now and what
+/// was staged goes in, so blocks that depend on the scope re-derive just what changed.
+/// Attributes are given without the scope, which is added on.
+pub struct ScopeReplacement<'a> {
+    program: &'a mut Program,
+    scope: String,
+    facts: Vec<(Internable, String, Internable)>,
+}
+
+impl<'a> ScopeReplacement<'a> {
+    pub fn insert(mut self, e:Internable, a:&str, v:Internable) -> ScopeReplacement<'a> {
+        self.facts.push((e, a.to_string(), v));
+        self
+    }
+
+    /// Swaps the staged facts in and returns the changes that were actually made.
+    pub fn commit(self) -> Vec<RawChange> {
+        self.commit_with(&mut None)
+    }
+
+    /// Like `commit`, but the changes are also written by the persister behind
+    /// `persistence_channel`, so the new contents are still there after a restart.
+    pub fn commit_with(self, persistence_channel:&mut Option<Sender<PersisterMessage>>) -> Vec<RawChange> {
+        let ScopeReplacement { program, scope, facts } = self;
+        let mut staged = HashSet::new();
+        for (e, a, v) in facts {
+            let e = program.state.interner.internable_to_id(e);
+            let a = program.state.interner.scoped_id(&scope, &a);
+            let v = program.state.interner.internable_to_id(v);
+            staged.insert((e, a, v));
+        }
+        // only the scope's own attributes are looked at, not every commit in the program
+        let current:HashSet<(Interned, Interned, Interned)> = {
+            let state = &program.state;
+            state.index.attributes().into_iter()
+                .filter(|&a| attribute_scope(state.interner.get_value(a)) == Some(&scope[..]))
+                .flat_map(|a| state.index.edges(a).into_iter().map(move |(e, v)| (e, a, v)))
+                .filter(|&(e, a, v)| state.distinct_index.is_commit(e, a, v))
+                .collect()
+        };
+        let n = program.state.interner.string_id("host");
+        let mut changes = vec![];
+        for &(e, a, v) in current.difference(&staged) {
+            changes.push(Change { e, a, v, n, round: 0, transaction: 0, count: -1 });
+        }
+        for &(e, a, v) in staged.difference(&current) {
+            changes.push(Change { e, a, v, n, round: 0, transaction: 0, count: 1 });
+        }
+        let diff = changes.iter().map(|change| change.to_raw(&program.state.interner)).collect();
+        if changes.len() > 0 {
+            let mut iter_pool = EstimateIterPool::new();
+            let mut txn = Transaction::new(&mut iter_pool);
+            for change in changes {
+                txn.input_change(change);
+            }
+            txn.exec(program, persistence_channel);
+        }
+        diff
+    }
 }
 
 pub struct Transaction<'a> {
@@ -3006,13 +7400,31 @@ impl<'a> Transaction<'a> {
         self.exec_meta(program, persistence_channel, None);
     }
     pub fn exec_meta(&mut self, program: &mut Program, persistence_channel: &mut Option<Sender<PersisterMessage>>, maybe_meta: Option<&mut MetaMessage>) {
+        program.remove_dropped_subscriptions();
+        for change in self.changes.iter_mut() {
+            program.state.schema.coerce(change, &mut program.state.interner);
+        }
         if let Some(&mut MetaMessage::Transaction{ref mut inputs, ..}) = maybe_meta {
             inputs.extend(self.changes.iter().map(|c| c.to_raw(&program.state.interner)));
         }
+        let history_inputs:Option<Vec<RawChange>> = match program.history {
+            Some(_) if self.changes.len() > 0 => Some(self.changes.iter().map(|c| c.to_raw(&program.state.interner)).collect()),
+            _ => None,
+        };
         for change in self.changes.iter() {
             program.state.distinct_index.distinct(&change, &mut program.state.rounds);
         }
-        transaction_flow_meta(&mut self.commits, &mut self.frame, self.iter_pool, program, maybe_meta);
+        let stats = transaction_flow_meta(&mut self.commits, &mut self.frame, self.iter_pool, program, maybe_meta);
+        // a transaction with no inputs of its own is only flushing @system facts, e.g. the
+        // ones an inspection just queued, so it shouldn't replace the stats being shown
+        if self.changes.len() > 0 {
+            program.last_transaction = stats;
+        }
+        if let (Some(inputs), Some(history)) = (history_inputs, program.history.as_mut()) {
+            let ref interner = program.state.interner;
+            let commits = self.commits.iter().map(|c| c.to_raw(interner)).collect();
+            history.record(HistoryEntry { inputs, commits: MetaMessage::collapse_changes(commits) });
+        }
         if let &mut Some(ref channel) = persistence_channel {
             self.collapsed_commits.clear();
             let mut to_persist = vec![];
@@ -3020,7 +7432,9 @@ impl<'a> Transaction<'a> {
                 self.collapsed_commits.insert(commit);
             }
             for commit in self.collapsed_commits.drain() {
-                to_persist.push(commit.to_raw(&program.state.interner));
+                if program.is_persistent(commit.a) {
+                    to_persist.push(commit.to_raw(&program.state.interner));
+                }
             }
             channel.send(PersisterMessage::Write(to_persist)).unwrap();
         } else {
@@ -3090,7 +7504,9 @@ impl<'a> RemoteTransaction<'a> {
                 self.collapsed_commits.insert(commit);
             }
             for commit in self.collapsed_commits.drain() {
-                to_persist.push(commit.to_raw(&program.state.interner));
+                if program.is_persistent(commit.a) {
+                    to_persist.push(commit.to_raw(&program.state.interner));
+                }
             }
             channel.send(PersisterMessage::Write(to_persist)).unwrap();
         } else {
@@ -3113,20 +7529,30 @@ pub struct CodeTransaction {
     commits: Vec<Change>,
     iter_pool: EstimateIterPool,
     frame: Frame,
+    resuming: bool,
 }
 
 impl CodeTransaction {
     pub fn new() -> CodeTransaction {
         let frame = Frame::new();
         let iter_pool = EstimateIterPool::new();
-        CodeTransaction { changes: vec![], commits:vec![], frame, iter_pool}
+        CodeTransaction { changes: vec![], commits:vec![], frame, iter_pool, resuming: false }
     }
 
     pub fn input_change(&mut self, change: Change) {
         self.changes.push(change);
     }
 
+    /// Adds blocks whose results are already in a restored checkpoint, so they're
+    /// registered without being run and only see the changes from here on.
+    pub fn resume(&mut self, program: &mut Program, to_add:Vec<Block>) {
+        self.resuming = true;
+        self.exec(program, to_add, vec![]);
+        self.resuming = false;
+    }
+
     pub fn exec(&mut self, program: &mut Program, to_add:Vec<Block>, to_remove:Vec<String>) {
+        let resuming = self.resuming;
         let ref mut frame = self.frame;
         let ref mut iter_pool = self.iter_pool;
 
@@ -3137,23 +7563,61 @@ impl CodeTransaction {
                     _ => panic!("Unable to find block to remove: '{}'", name)
                 };
 
-                let remove = &program.block_info.blocks[block_ix];
-                frame.reset();
-                frame.input = Some(Change { e:0,a:0,v:0,n: 0, transaction:0, round:0, count:-1 });
-                remove.run(&mut program.state, iter_pool, frame);
+                // what a block reading a shared arrangement would retract is the
+                // maintainer's, and stays until the maintainer goes
+                if !program.arrangements.is_passive(&name) {
+                    let remove = &program.block_info.blocks[block_ix];
+                    frame.reset();
+                    frame.input = Some(Change { e:0,a:0,v:0,n: 0, transaction:0, round:0, count:-1 });
+                    remove.run(&mut program.state, iter_pool, frame);
+                }
             }
+            let successor = program.arrangements.leave(&name);
+            program.fingerprints.remove(&name);
             program.unregister_block(name);
+            if let Some(successor) = successor {
+                program.maintain_arrangement(&successor);
+                let block_ix = program.block_info.block_names[&successor];
+                frame.reset();
+                frame.input = Some(Change { e:0,a:0,v:0,n: 0, transaction:0, round:0, count:1 });
+                program.block_info.blocks[block_ix].run(&mut program.state, iter_pool, frame);
+            }
         }
 
-        for add in to_add {
+        // an intermediate written by more than one block, like an `if`'s, can't be shared
+        let mut writers:HashMap<Interned, usize> = HashMap::new();
+        for add in to_add.iter() {
+            for id in written_intermediates(&add.constraints) {
+                *writers.entry(id).or_insert(0) += 1;
+            }
+        }
+        for mut add in order_by_intermediates(to_add) {
+            program.fingerprints.insert(add.name.to_string(), block_fingerprint(&add));
+            // blocks annotated as disabled are loaded, just not run
+            if add.metadata.disabled {
+                program.hold_disabled(add);
+                continue;
+            }
+            if program.disabled_blocks.remove(&add.name).is_some() {
+                program.retract_system_facts(system_block_id(&add.name));
+            }
+            let rewritten = program.arrangements.rewrite(&mut add);
+            let shareable = written_intermediates(&add.constraints).iter().all(|id| writers.get(id).map_or(true, |&count| count == 1));
+            let passive = shareable && program.arrangements.join(&mut add);
+            if rewritten || passive {
+                // the block's solver was built around the ids it was compiled with
+                add.shapes = add.to_shapes();
+                add.solver = Some(Solver::new(&mut program.state.interner, add.block_id, 0, None, &add.constraints));
+            }
+            program.register_block_with(add, !passive);
+            if passive || resuming { continue; }
             frame.reset();
             frame.input = Some(Change { e:0,a:0,v:0,n: 0, transaction:0, round:0, count:1 });
-            program.register_block(add);
             program.block_info.blocks.last().unwrap().run(&mut program.state, iter_pool, frame);
         }
 
         let mut max_round = 0;
-        intermediate_flow(frame, &mut program.state, &program.block_info, iter_pool, 0, &mut max_round);
+        intermediate_flow(frame, &mut program.state, &program.block_info, iter_pool, None, 0, &mut max_round);
 
         for change in self.changes.iter() {
             program.state.distinct_index.distinct(&change, &mut program.state.rounds);
@@ -3342,6 +7806,8 @@ impl Constraint {
     pub fn to_portable(&self, i:&Interner) -> PortableConstraint {
         match self {
             &Constraint::Scan{ref e, ref a, ref v, ..} => PortableConstraint::Scan(e.to_portable(i), a.to_portable(i), v.to_portable(i)),
+            // the filter a range scan was fused with travels alongside it, so a plain scan is enough
+            &Constraint::RangeScan{ref e, ref a, ref v, ..} => PortableConstraint::Scan(e.to_portable(i), a.to_portable(i), v.to_portable(i)),
             &Constraint::Filter{ref op, ref left, ref right, ..} => PortableConstraint::Filter(op.to_owned(), left.to_portable(i), right.to_portable(i)),
             &Constraint::Insert{ref e, ref a, ref v, commit} => PortableConstraint::Output(e.to_portable(i), a.to_portable(i), v.to_portable(i), commit),
             &Constraint::Remove{ref e, ref a, ref v} => PortableConstraint::Remove(e.to_portable(i), a.to_portable(i), v.to_portable(i)),
@@ -3393,93 +7859,6 @@ impl Block {
     }
 }
 
-//-------------------------------------------------------------------------
-// Persister
-//-------------------------------------------------------------------------
-
-pub enum PersisterMessage {
-    Stop,
-    Write(Vec<RawChange>),
-}
-
-pub struct Persister {
-    thread: JoinHandle<()>,
-    outgoing: Sender<PersisterMessage>,
-    loaded: Vec<RawChange>,
-}
-
-impl Persister {
-    pub fn new(path_ref:&str) -> Persister {
-        let (outgoing, incoming) = mpsc::channel();
-        let path = path_ref.to_string();
-        let thread = thread::spawn(move || {
-            let file = OpenOptions::new().append(true).create(true).open(&path).unwrap();
-            let mut writer = BufWriter::new(file);
-            loop {
-                match incoming.recv().unwrap() {
-                    PersisterMessage::Stop => { break; }
-                    PersisterMessage::Write(items) => {
-                        println!("Let's persist some stuff!");
-                        for item in items {
-                            let result = bincode::serialize(&item, bincode::Infinite).unwrap();
-                            match writer.write_all(&result) {
-                                Err(e) => {panic!("Can't persist! {:?}", e); }
-                                Ok(_) => { }
-                            }
-                        }
-                        writer.flush().unwrap();
-                    }
-                }
-            }
-        });
-        Persister { outgoing, thread, loaded: vec![] }
-    }
-
-    pub fn load(&mut self, path:&str) {
-        let file = match File::open(path) {
-            Ok(f) => f,
-            Err(_) => {
-                println!("Unable to load db: {}", path);
-                return;
-            }
-        };
-        let mut reader = BufReader::new(file);
-        loop {
-            let result:Result<RawChange, _> = bincode::deserialize_from(&mut reader, bincode::Infinite);
-            match result {
-                Ok(c) => {
-                    println!("{:?}", c);
-                    self.loaded.push(c);
-                },
-                Err(info) => {
-                    println!("ran out {:?}", info);
-                    break;
-                }
-            }
-        }
-    }
-
-    pub fn send(&self, changes:Vec<RawChange>) {
-        self.outgoing.send(PersisterMessage::Write(changes)).unwrap();
-    }
-
-    pub fn wait(self) {
-        self.thread.join().unwrap();
-    }
-
-    pub fn get_channel(&self) -> Sender<PersisterMessage> {
-        self.outgoing.clone()
-    }
-
-    pub fn get_commits(&mut self) -> Vec<RawChange> {
-        mem::replace(&mut self.loaded, vec![])
-    }
-
-    pub fn close(&self) {
-        self.outgoing.send(PersisterMessage::Stop).unwrap();
-    }
-}
-
 //-------------------------------------------------------------------------
 // Program Runner
 //-------------------------------------------------------------------------
@@ -3490,6 +7869,18 @@ pub struct RunLoop {
 }
 
 impl RunLoop {
+    pub fn transaction(&self, changes:Vec<RawChange>) -> Receiver<Vec<RawChange>> {
+        let (reply, result) = mpsc::channel();
+        self.send(RunLoopMessage::ReplyTransaction(changes, reply));
+        result
+    }
+
+    pub fn admin(&self, command:AdminCommand) -> Receiver<AdminReply> {
+        let (reply, result) = mpsc::channel();
+        self.send(RunLoopMessage::Admin(command, reply));
+        result
+    }
+
     pub fn wait(self) {
         self.thread.join().unwrap();
     }
@@ -3508,11 +7899,20 @@ impl RunLoop {
     pub fn channel(&self) -> Sender<RunLoopMessage> {
         self.outgoing.clone()
     }
-}
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum DebugMode {
-    Compile
+    /// Attaches a watcher to the running program, as `Program::attach_watcher` would.
+    pub fn attach_watcher(&self, name:&str, watcher:Box<Watcher + Send>) {
+        self.send(RunLoopMessage::Attach(name.to_string(), watcher));
+    }
+
+    pub fn detach_watcher(&self, name:&str) {
+        self.send(RunLoopMessage::Detach(name.to_string()));
+    }
+
+    /// A handle for pushing changes into the running program, as `Program::input` gives.
+    pub fn input(&self, config:InputConfig) -> Input {
+        Input::new(self.outgoing.clone(), config)
+    }
 }
 
 pub struct ProgramRunner {
@@ -3521,13 +7921,12 @@ pub struct ProgramRunner {
     paths: Vec<String>,
     initial_commits: Vec<RawChange>,
     persistence_channel: Option<Sender<PersisterMessage>>,
-    debug_modes: HashSet<DebugMode>,
     pub meta_channel: Option<Sender<MetaMessage>>
 }
 
 impl ProgramRunner {
     pub fn new(name:&str) -> ProgramRunner {
-        ProgramRunner {name: name.to_owned(), paths: vec![], program: Program::new(name), persistence_channel:None, initial_commits: vec![], debug_modes: HashSet::new(), meta_channel: None }
+        ProgramRunner {name: name.to_owned(), paths: vec![], program: Program::new(name), persistence_channel:None, initial_commits: vec![], meta_channel: None }
     }
 
     pub fn load(&mut self, path:&str) {
@@ -3537,10 +7936,13 @@ impl ProgramRunner {
     pub fn persist(&mut self, persister:&mut Persister) {
         self.persistence_channel = Some(persister.get_channel());
         self.initial_commits = persister.get_commits();
+        self.program.delivery = DeliveryLog::load(&persister.watcher_checkpoint_path());
+        self.program.checkpoint_path = Some(persister.derivation_checkpoint_path());
+        self.program.db_path = Some(persister.path().to_string());
     }
 
     pub fn debug(&mut self, mode:DebugMode) {
-        self.debug_modes.insert(mode);
+        Tracer::enable(mode);
     }
 
     pub fn run(self) -> RunLoop {
@@ -3550,24 +7952,51 @@ impl ProgramRunner {
         let paths = self.paths;
         let mut persistence_channel = self.persistence_channel;
         let initial_commits = self.initial_commits;
-        let debug_compile = self.debug_modes.contains(&DebugMode::Compile);
         let meta_channel = self.meta_channel.map(|c| c.clone());
 
         let thread = thread::Builder::new().name(program.name.to_owned()).spawn(move || {
+            // the checkpoint's ids have to be in place before anything is compiled
+            let resume_point = match program.checkpoint_path.clone() {
+                Some(path) => program.restore_checkpoint(&path, &initial_commits),
+                None => None,
+            };
             let mut blocks = vec![];
             let mut start_ns = time::precise_time_ns();
             for path in paths {
-                blocks.extend(parse_file(&mut program.state.interner, &path, true, debug_compile));
+                if is_compiled_file(&path) {
+                    match load_compiled_file(&mut program.state.interner, &program.functions, &path) {
+                        Ok(compiled) => blocks.extend(compiled),
+                        Err(why) => println!("[{}] Unable to load {}: {}", &program.name, path, why),
+                    }
+                    continue;
+                }
+                let options = program.compile_options();
+                blocks.extend(parse_file_with(&mut program.state.interner, &path, true, &options));
             }
             let mut end_ns = time::precise_time_ns();
             println!("[{}] Compile took {:?}", &program.name, (end_ns - start_ns) as f64 / 1_000_000.0);
 
             start_ns = time::precise_time_ns();
+            let resumed = match resume_point {
+                Some(ref point) if point.matches(&blocks) => Some(point.commits),
+                Some(_) => {
+                    println!("[{}] Blocks changed since the last checkpoint, deriving everything again", &program.name);
+                    program.discard_derived();
+                    None
+                }
+                None => None,
+            };
             let mut txn = CodeTransaction::new();
-            for initial in initial_commits {
+            for initial in initial_commits.into_iter().skip(resumed.unwrap_or(0)) {
                 txn.input_change(initial.to_change(&mut program.state.interner));
             }
-            txn.exec(&mut program, blocks, vec![]);
+            match resumed {
+                Some(commits) => {
+                    println!("[{}] Resuming from the last checkpoint, {} commits were already derived", &program.name, commits);
+                    txn.resume(&mut program, blocks);
+                }
+                None => txn.exec(&mut program, blocks, vec![]),
+            }
             end_ns = time::precise_time_ns();
             println!("[{}] Load took {:?}", &program.name, (end_ns - start_ns) as f64 / 1_000_000.0);
 
@@ -3577,7 +8006,14 @@ impl ProgramRunner {
             let mut paused = false;
 
             'outer: loop {
-                match (program.incoming.recv(), paused) {
+                let message = match program.incoming.recv() {
+                    Ok(RunLoopMessage::AnnotatedTransaction(mut changes, annotations)) => {
+                        annotate_changes(&mut changes, annotations);
+                        Ok(RunLoopMessage::Transaction(changes))
+                    }
+                    message => message,
+                };
+                match (message, paused) {
                     (Ok(RunLoopMessage::Stop), _) => {
                         break 'outer;
                     },
@@ -3599,28 +8035,37 @@ impl ProgramRunner {
                             let resolved_path = resolved.to_str().unwrap();
                             println!("Hot-reloading {} ...", resolved_path);
 
-                            let mut parsed_blocks:Vec<Block> = if resolved.exists() {
-                                parse_file(&mut program.state.interner, resolved_path, true, debug_compile)
+                            let parsed_blocks:Vec<Block> = if resolved.exists() {
+                                let options = program.compile_options();
+                                parse_file_with(&mut program.state.interner, resolved_path, true, &options)
                             } else {
                                 vec![]
                             };
-                            let new_blocks:HashSet<&Block> = parsed_blocks.iter().collect();
+                            // the file's imports come back with it and are diffed on their own
+                            let mut by_path:HashMap<String, Vec<Block>> = HashMap::new();
+                            by_path.insert(resolved_path.to_string(), vec![]);
+                            for block in parsed_blocks {
+                                by_path.entry(block.path.to_string()).or_insert_with(|| vec![]).push(block);
+                            }
+                            for (block_path, parsed) in by_path.iter() {
+                                let new_blocks:HashSet<&Block> = parsed.iter().collect();
 
-                            let mut old_blocks:HashSet<&Block> = HashSet::new();
-                            old_blocks.extend(program.blocks_by_path(resolved_path).iter());
+                                let mut old_blocks:HashSet<&Block> = HashSet::new();
+                                old_blocks.extend(program.blocks_by_path(block_path).iter());
 
-                            let mut added = &new_blocks - &old_blocks;
-                            let mut removed = &old_blocks - &new_blocks;
+                                let mut added = &new_blocks - &old_blocks;
+                                let mut removed = &old_blocks - &new_blocks;
 
-                            added_blocks.extend(added.drain().map(|block| block.clone()));
-                            removed_blocks.extend(removed.drain().map(|block| block.name.to_owned()));
+                                added_blocks.extend(added.drain().map(|block| block.clone()));
+                                removed_blocks.extend(removed.drain().map(|block| block.name.to_owned()));
+                            }
                         }
 
                         echo_channel.send(RunLoopMessage::CodeTransaction(added_blocks, removed_blocks));
                     }
                     (Ok(RunLoopMessage::Transaction(v)), true) => {},
                     (Ok(RunLoopMessage::Transaction(v)), false) => {
-                        println!("[{}] Txn started", &program.name);
+                        trace(DebugMode::Runtime, || format!("[{}] Txn started", &program.name));
                         let start_ns = time::precise_time_ns();
                         let mut txn = Transaction::new(&mut iter_pool);
                         for cur in v {
@@ -3639,12 +8084,39 @@ impl ProgramRunner {
 
                         let end_ns = time::precise_time_ns();
                         let time = (end_ns - start_ns) as f64;
-                        println!("[{}] Txn took {:?} - {:?} insts ({:?} ns) - {:?} inserts ({:?} ns)", &program.name, time / 1_000_000.0, txn.frame.counters.instructions, (time / (txn.frame.counters.instructions as f64)).floor(), txn.frame.counters.inserts, (time / (txn.frame.counters.inserts as f64)).floor());
+                        trace(DebugMode::Runtime, || format!("[{}] Txn took {:?} - {:?} insts ({:?} ns) - {:?} inserts ({:?} ns)", &program.name, time / 1_000_000.0, txn.frame.counters.instructions, (time / (txn.frame.counters.instructions as f64)).floor(), txn.frame.counters.inserts, (time / (txn.frame.counters.inserts as f64)).floor()));
+                    }
+                    (Ok(RunLoopMessage::ReplyTransaction(..)), true) => {},
+                    (Ok(RunLoopMessage::ReplyTransaction(v, reply)), false) => {
+                        trace(DebugMode::Runtime, || format!("[{}] Reply txn started", &program.name));
+                        let outputs = exec_with_outputs(&mut program, &mut iter_pool, &mut persistence_channel, v);
+                        // the host may have stopped waiting, that's fine
+                        reply.send(outputs).ok();
+                    }
+                    (Ok(RunLoopMessage::ReplaceScope(..)), true) => {},
+                    (Ok(RunLoopMessage::ReplaceScope(scope, facts)), false) => {
+                        trace(DebugMode::Runtime, || format!("[{}] Scope replacement started", &program.name));
+                        let mut replacement = program.replace_scope(&scope);
+                        for (e, a, v) in facts {
+                            replacement = replacement.insert(e, &a, v);
+                        }
+                        replacement.commit_with(&mut persistence_channel);
+                    }
+                    (Ok(RunLoopMessage::Merge(..)), true) => {},
+                    (Ok(RunLoopMessage::Merge(keep, merge)), false) => {
+                        trace(DebugMode::Runtime, || format!("[{}] Merge started", &program.name));
+                        let keep = program.state.interner.internable_to_id(keep);
+                        let merge = program.state.interner.internable_to_id(merge);
+                        let mut txn = Transaction::new(&mut iter_pool);
+                        for change in program.merge_changes(keep, merge) {
+                            txn.input_change(change);
+                        }
+                        txn.exec(&mut program, &mut persistence_channel);
                     }
                     (Ok(RunLoopMessage::RemoteTransaction(v)), true) => {},
                     (Ok(RunLoopMessage::RemoteTransaction(v)), false) => {
                         let start_ns = time::precise_time_ns();
-                        println!("[{}] Remote txn started", &program.name);
+                        trace(DebugMode::Runtime, || format!("[{}] Remote txn started", &program.name));
                         let mut txn = RemoteTransaction::new(&mut iter_pool);
                         for cur in v {
                             txn.input_change(cur.to_change(&mut program.state.interner));
@@ -3652,57 +8124,88 @@ impl ProgramRunner {
                         txn.exec(&mut program, &mut persistence_channel);
                         let end_ns = time::precise_time_ns();
                         let time = (end_ns - start_ns) as f64;
-                        println!("[{}] Txn took {:?} - {:?} insts ({:?} ns) - {:?} inserts ({:?} ns)", &program.name, time / 1_000_000.0, txn.frame.counters.instructions, (time / (txn.frame.counters.instructions as f64)).floor(), txn.frame.counters.inserts, (time / (txn.frame.counters.inserts as f64)).floor());
+                        trace(DebugMode::Runtime, || format!("[{}] Txn took {:?} - {:?} insts ({:?} ns) - {:?} inserts ({:?} ns)", &program.name, time / 1_000_000.0, txn.frame.counters.instructions, (time / (txn.frame.counters.instructions as f64)).floor(), txn.frame.counters.inserts, (time / (txn.frame.counters.inserts as f64)).floor()));
                     }
                     (Ok(RunLoopMessage::CodeTransaction(adds, removes)), _) => {
                         let start_ns = time::precise_time_ns();
                         let mut tx = CodeTransaction::new();
-                        println!("[{}] Code Txn started", &program.name);
+                        trace(DebugMode::Runtime, || format!("[{}] Code Txn started", &program.name));
                         if adds.len() > 0 {
-                            println!("  ADDS:");
-                            for block in adds.iter() {
-                                print_block_constraints(&block);
-                            }
+                            trace(DebugMode::Runtime, || {
+                                let mut result = "  ADDS:".to_string();
+                                for block in adds.iter() {
+                                    result.push_str(&block_constraints_string(&block));
+                                }
+                                result
+                            });
                         }
                         if removes.len() > 0 {
-                            println!("  REMOVES:");
-                            for block in removes.iter() {
-                                println!("    - {:?}", block);
-                            }
+                            trace(DebugMode::Runtime, || format!("  REMOVES:\n    - {}", removes.join("\n    - ")));
                         }
                         tx.exec(&mut program, adds, removes);
                         let end_ns = time::precise_time_ns();
                         let time = (end_ns - start_ns) as f64;
-                        println!("[{}] Txn took {:?}", &program.name, time / 1_000_000.0);
+                        trace(DebugMode::Runtime, || format!("[{}] Txn took {:?}", &program.name, time / 1_000_000.0));
                     }
                     (Ok(RunLoopMessage::RemoteCodeTransaction(adds, removes)), _) => {
                         let start_ns = time::precise_time_ns();
                         let mut tx = CodeTransaction::new();
-                        println!("[{}] Remote Code Txn started", &program.name);
+                        trace(DebugMode::Runtime, || format!("[{}] Remote Code Txn started", &program.name));
                         let added_blocks:Vec<Block> = adds.iter().map(|b| b.intern(&mut program.state.interner)).collect();
 
                         if adds.len() > 0 {
-                            println!("  ADDS:");
-                            for block in added_blocks.iter() {
-                                print_block_constraints(&block);
-                            }
+                            trace(DebugMode::Runtime, || {
+                                let mut result = "  ADDS:".to_string();
+                                for block in added_blocks.iter() {
+                                    result.push_str(&block_constraints_string(&block));
+                                }
+                                result
+                            });
                         }
                         if removes.len() > 0 {
-                            println!("  REMOVES:");
-                            for block in removes.iter() {
-                                println!("    - {:?}", block);
-                            }
+                            trace(DebugMode::Runtime, || format!("  REMOVES:\n    - {}", removes.join("\n    - ")));
                         }
 
                         tx.exec(&mut program, added_blocks, removes);
                         let end_ns = time::precise_time_ns();
                         let time = (end_ns - start_ns) as f64;
-                        println!("[{}] Txn took {:?}", &program.name, time / 1_000_000.0);
+                        trace(DebugMode::Runtime, || format!("[{}] Txn took {:?}", &program.name, time / 1_000_000.0));
 
                     }
+                    (Ok(RunLoopMessage::Admin(command, reply)), _) => {
+                        trace(DebugMode::Runtime, || format!("[{}] Admin {:?}", &program.name, command));
+                        program.admin(command, &persistence_channel, reply);
+                    }
+                    (Ok(RunLoopMessage::Inspect), true) => {},
+                    (Ok(RunLoopMessage::Inspect), false) => {
+                        trace(DebugMode::Runtime, || format!("[{}] Inspect", &program.name));
+                        program.inspect();
+                        // nothing but the queued @system facts goes in, so this doesn't
+                        // count as the last transaction
+                        let mut txn = Transaction::new(&mut iter_pool);
+                        txn.exec(&mut program, &mut persistence_channel);
+                    }
+                    (Ok(RunLoopMessage::Attach(name, watcher)), _) => {
+                        program.attach_watcher(&name, watcher);
+                    }
+                    (Ok(RunLoopMessage::Detach(name)), _) => {
+                        program.detach_watcher(&name);
+                    }
+                    (Ok(RunLoopMessage::WatcherError(name, message)), paused) => {
+                        program.watcher_error(&name, &message);
+                        if !paused {
+                            // like an inspect, only the @system facts go in
+                            let mut txn = Transaction::new(&mut iter_pool);
+                            txn.exec(&mut program, &mut persistence_channel);
+                        }
+                    }
+                    (Ok(RunLoopMessage::AnnotatedTransaction(..)), _) => {
+                        unreachable!("Annotated transactions are turned into plain ones as they're received");
+                    }
                     (Err(_), _) => { break; }
                 }
             }
+            program.teardown_watchers();
             if let Some(channel) = persistence_channel {
                 channel.send(PersisterMessage::Stop).unwrap();
             }
@@ -1,7 +1,11 @@
 use compiler::{Node, OutputType};
+use ops::BlockMetadata;
 use std::str::FromStr;
+use std::cmp;
+use std::fmt;
 use combinators::*;
 use error::{ParseError};
+use numerics::Decimal;
 
 //--------------------------------------------------------------------
 // Constants
@@ -60,8 +64,29 @@ whitespace_parser!(integer(state) -> Node<'a> {
     }
 });
 
+whitespace_parser!(decimal_fraction(state) -> &'a str {
+    let start = state.pos;
+    tag!(state, "."); take_while_1!(state, is_digit);
+    let fraction = state.capture(start);
+    result!(state, fraction)
+});
+
+whitespace_parser!(decimal(state) -> Node<'a> {
+    state.eat_space();
+    let start = state.pos;
+    // -? [0-9]+ (\. [0-9]+)? d
+    any!(state, "-"); take_while_1!(state, is_digit); opt!(state, decimal_fraction);
+    let digits = state.capture(start);
+    tag!(state, "d");
+    if let Some(number) = Decimal::from_str(digits) {
+        pos_result!(state, Node::Decimal(number))
+    } else {
+        state.error(ParseError::NumberOverflow())
+    }
+});
+
 parser!(number(state) -> Node<'a> {
-    let num = alt!(state, [float integer]);
+    let num = alt!(state, [decimal float integer]);
     result!(state, num)
 });
 
@@ -124,11 +149,43 @@ parser!(none_value(state) -> Node<'a> {
     pos_result!(state, Node::NoneValue)
 });
 
+// `$name` stands in for a value bound when a prepared query runs.
+parser!(placeholder(state) -> Node<'a> {
+    tag!(state, "$");
+    let name = match call!(state, identifier).unwrap_pos() {
+        Node::Identifier(v) => v,
+        _ => unreachable!(),
+    };
+    pos_result!(state, Node::Placeholder(name))
+});
+
+// negation goes before anything named, since a name can start with `-`
 parser!(value(state) -> Node<'a> {
-    let part = alt!(state, [ number string record_function record_reference wrapped_expression ]);
+    let part = alt!(state, [ number string placeholder negation record_function record_reference wrapped_expression ]);
     result!(state, part)
 });
 
+// Negating a constant happens right here, anything else turns into `0 - value`.
+fn negate<'a>(node:Node<'a>) -> Node<'a> {
+    match node {
+        Node::Integer(v) => Node::Integer(-v),
+        Node::Float(v) => Node::Float(-v),
+        Node::Decimal(v) => Node::Decimal(v.negate()),
+        Node::Infix { result:None, left, right, op:"-" } => match *left {
+            Node::Integer(0) => *right,
+            left => Node::Infix { result:None, left:Box::new(Node::Integer(0)), right:Box::new(Node::Infix { result:None, left:Box::new(left), right, op:"-" }), op:"-" },
+        },
+        other => Node::Infix { result:None, left:Box::new(Node::Integer(0)), right:Box::new(other), op:"-" },
+    }
+}
+
+whitespace_parser!(negation(state) -> Node<'a> {
+    tag!(state, "-");
+    state.eat_space();
+    let value = call!(state, value);
+    pos_result!(state, negate(value.unwrap_pos()))
+});
+
 parser!(wrapped_expression(state) -> Node<'a> {
     tag!(state, "(");
     let value = call!(state, expression);
@@ -137,7 +194,7 @@ parser!(wrapped_expression(state) -> Node<'a> {
 });
 
 parser!(expression(state) -> Node<'a> {
-    let part = alt!(state, [ infix_addition infix_multiplication value ]);
+    let part = alt!(state, [ infix_addition infix_multiplication infix_power value ]);
     result!(state, part)
 });
 
@@ -153,7 +210,7 @@ parser!(expression_set(state) -> Node<'a> {
 //--------------------------------------------------------------------
 
 whitespace_parser!(infix_addition(state) -> Node<'a> {
-    let left = alt!(state, [ infix_multiplication value ]);
+    let left = alt!(state, [ infix_multiplication infix_power value ]);
     tag!(state, " ");
     let op = alt_tag!(state, [ "+" "-" ]);
     tag!(state, " ");
@@ -162,11 +219,20 @@ whitespace_parser!(infix_addition(state) -> Node<'a> {
 });
 
 whitespace_parser!(infix_multiplication(state) -> Node<'a> {
+    let left = alt!(state, [ infix_power value ]);
+    tag!(state, " ");
+    let op = alt_tag!(state, [ "*" "/" "%" ]);
+    tag!(state, " ");
+    let right = alt!(state, [ infix_multiplication infix_power value ]);
+    pos_result!(state, Node::Infix { result:None, left:Box::new(left), right:Box::new(right), op })
+});
+
+whitespace_parser!(infix_power(state) -> Node<'a> {
     let left = call!(state, value);
     tag!(state, " ");
-    let op = alt_tag!(state, [ "*" "/" ]);
+    let op = alt_tag!(state, [ "^" ]);
     tag!(state, " ");
-    let right = alt!(state, [ infix_multiplication value ]);
+    let right = alt!(state, [ infix_power value ]);
     pos_result!(state, Node::Infix { result:None, left:Box::new(left), right:Box::new(right), op })
 });
 
@@ -225,8 +291,26 @@ parser!(attribute_inequality(state) -> Node<'a> {
     pos_result!(state, Node::AttributeInequality { attribute, right:Box::new(right), op })
 });
 
+parser!(tag_alternative(state) -> Node<'a> {
+    tag!(state, "|");
+    let tag = call!(state, hashtag);
+    result!(state, tag)
+});
+
+// `#dog|#cat` matches records with either tag.
+parser!(tag_union(state) -> Node<'a> {
+    let first = call!(state, hashtag);
+    let mut tags = vec![first];
+    tags.extend(many_1!(state, tag_alternative));
+    let names = tags.into_iter().map(|tag| match tag.unwrap_pos() {
+        Node::Tag(name) => name,
+        _ => unreachable!(),
+    }).collect();
+    pos_result!(state, Node::TagUnion(names, None))
+});
+
 parser!(attribute(state) -> Node<'a> {
-    let part = alt!(state, [ hashtag attribute_equality attribute_inequality attribute_variable ]);
+    let part = alt!(state, [ tag_union hashtag attribute_equality attribute_inequality attribute_variable ]);
     result!(state, part)
 });
 
@@ -325,9 +409,10 @@ parser!(multi_function_equality(state) -> Node<'a> {
     tag!(state, "=");
     let mut func = call!(state, record_function);
     match func {
-        Node::Pos(_, box Node::RecordFunction { ref mut outputs, .. }) => {
-           *outputs = neue_outputs;
-        }
+        Node::Pos(_, ref mut node) => match **node {
+            Node::RecordFunction { ref mut outputs, .. } => { *outputs = neue_outputs; }
+            _ => unreachable!()
+        },
         _ => unreachable!()
     };
     result!(state, func)
@@ -411,6 +496,40 @@ parser!(update_remove(state) -> Node<'a> {
     pos_result!(state, Node::RecordUpdate { op: "-=", record:Box::new(left), value:Box::new(value), output_type: state.output_type })
 });
 
+parser!(bulk_update_set(state) -> Node<'a> {
+    let attribute = match call!(state, identifier).unwrap_pos() {
+        Node::Identifier(v) => v,
+        _ => unreachable!(),
+    };
+    tag!(state, ":=");
+    let value = alt!(state, [ none_value record record_set wrapped_record_set expression expression_set ]);
+    pos_result!(state, Node::AttributeEquality(attribute, Box::new(value)))
+});
+
+// `update all p set a := v, b := w` sets every attribute on every p the search
+// matched, each one going through the same := implementation as `p.a := v`.
+parser!(bulk_update(state) -> Node<'a> {
+    tag!(state, "update");
+    tag!(state, "all");
+    let record = match call!(state, variable).unwrap_pos() {
+        Node::Variable(v) => v,
+        _ => unreachable!(),
+    };
+    tag!(state, "set");
+    let mut sets = many_1!(state, bulk_update_set);
+    let output_type = state.output_type;
+    let updates = sets.drain(..).map(|set| {
+        match set.unwrap_pos() {
+            Node::AttributeEquality(attribute, value) => {
+                let left = Node::MutatingAttributeAccess(vec![record, attribute]);
+                Node::RecordUpdate { op: ":=", record:Box::new(left), value, output_type }
+            }
+            _ => unreachable!(),
+        }
+    }).collect();
+    pos_result!(state, Node::BulkUpdate(updates))
+});
+
 parser!(bind_update(state) -> Node<'a> {
     let result = alt!(state, [ update_add update_merge ]);
     result!(state, result)
@@ -468,8 +587,11 @@ parser!(else_only_branch(state) -> Node<'a> {
 parser!(else_branch(state) -> Node<'a> {
     tag!(state, "else");
     let mut branch = call!(state, if_branch);
-    if let Node::Pos(_, box Node::IfBranch { ref mut exclusive, .. }) = branch {
-        *exclusive = true;
+    if let Node::Pos(_, ref mut node) = branch {
+        match **node {
+            Node::IfBranch { ref mut exclusive, .. } => { *exclusive = true; }
+            _ => panic!("Invalid if branch")
+        }
     } else {
         panic!("Invalid if branch");
     };
@@ -510,21 +632,51 @@ parser!(if_expression(state) -> Node<'a> {
     pos_result!(state, Node::If { sub_block_id:0, exclusive, outputs, branches })
 });
 
+//--------------------------------------------------------------------
+// Objectives
+//--------------------------------------------------------------------
+
+// `minimize cost` keeps only the solutions where cost is lowest, `maximize` where it's
+// highest. It's a gather/bottom or gather/top with a limit of one, so every solution is
+// ranked and the ones left are the true optimum, ties included.
+parser!(objective(state) -> Node<'a> {
+    let sense = alt_tag!(state, [ "minimize" "maximize" ]);
+    let value = call!(state, expression);
+    let op = if sense == "minimize" { "gather/bottom" } else { "gather/top" };
+    let params = vec![Node::AttributeEquality("for", Box::new(value)),
+                      Node::AttributeEquality("limit", Box::new(Node::Integer(1)))];
+    pos_result!(state, Node::Objective(sense, Box::new(Node::RecordFunction { op, params, outputs:vec![] })))
+});
+
 //--------------------------------------------------------------------
 // Sections
 //--------------------------------------------------------------------
 
 parser!(search_section_statement(state) -> Node<'a> {
     let item = alt!(state, [ not_form lookup_remote lookup_commit lookup multi_function_equality if_expression inequality
-                             record_function record equality attribute_access ]);
+                             record_function record equality attribute_access objective ]);
     result!(state, item)
 });
 
+parser!(scope(state) -> Node<'a> {
+    tag!(state, "@");
+    let name = call!(state, identifier);
+    result!(state, name)
+});
+
+fn scoped<'a>(scope:Option<Node<'a>>, section:Node<'a>) -> Node<'a> {
+    match scope.map(|name| name.unwrap_pos()) {
+        Some(Node::Identifier(name)) => Node::Scoped(name, Box::new(section)),
+        _ => section,
+    }
+}
+
 parser!(search_section(state) -> Node<'a> {
     tag!(state, "search");
+    let scope = opt!(state, scope);
     state.output_type = OutputType::Lookup;
     let items = many_1!(state, search_section_statement => EmptySearch);
-    pos_result!(state, Node::Search(items))
+    pos_result!(state, scoped(scope, Node::Search(items)))
 });
 
 parser!(bind_section_statement(state) -> Node<'a> {
@@ -534,21 +686,23 @@ parser!(bind_section_statement(state) -> Node<'a> {
 
 parser!(bind_section(state) -> Node<'a> {
     tag!(state, "bind");
+    let scope = opt!(state, scope);
     state.output_type = OutputType::Bind;
     let items = many_1!(state, bind_section_statement => EmptyUpdate);
-    pos_result!(state, Node::Bind(items))
+    pos_result!(state, scoped(scope, Node::Bind(items)))
 });
 
 parser!(commit_section_statement(state) -> Node<'a> {
-    let item = alt!(state, [ lookup_remote lookup output_equality record commit_update ]);
+    let item = alt!(state, [ bulk_update lookup_remote lookup output_equality record commit_update ]);
     result!(state, item)
 });
 
 parser!(commit_section(state) -> Node<'a> {
     tag!(state, "commit");
+    let scope = opt!(state, scope);
     state.output_type = OutputType::Commit;
     let items = many_1!(state, commit_section_statement => EmptyUpdate);
-    pos_result!(state, Node::Commit(items))
+    pos_result!(state, scoped(scope, Node::Commit(items)))
 });
 
 parser!(project_section(state) -> Node<'a> {
@@ -622,7 +776,7 @@ parser!(block(state) -> Node<'a> {
     if errors.len() > 0 {
        state.consume_until(block_end);
     }
-    pos_result!(state, Node::Block {code: state.input, errors, search:Box::new(search), update:Box::new(update.unwrap_or(Node::NoneValue))})
+    pos_result!(state, Node::Block {code: state.input, errors, search:Box::new(search), update:Box::new(update.unwrap_or(Node::NoneValue)), metadata: BlockMetadata::default()})
 });
 
 parser!(block_start(state) -> &'a str {
@@ -630,38 +784,295 @@ parser!(block_start(state) -> &'a str {
     result!(state, open)
 });
 
-parser!(embedded_blocks(state, file:&str) -> Node<'a> {
-    let end = state.input.len();
-    let mut blocks = vec![];
+// `eve:block name: "my block" disabled` on the line above a block gives it a name to
+// be enabled and disabled by, and/or loads it without running it. `distinct` makes the
+// block bind each of its outputs once, however many rows derive it.
+pub fn block_annotation(line:&str) -> Option<BlockMetadata> {
+    let line = line.trim();
+    if !line.starts_with("eve:block") { return None; }
+    let mut metadata = BlockMetadata::default();
+    let mut rest = line["eve:block".len()..].trim_left();
+    while rest.len() > 0 {
+        if rest.starts_with("disabled") {
+            metadata.disabled = true;
+            rest = &rest["disabled".len()..];
+        } else if rest.starts_with("distinct") {
+            metadata.distinct = true;
+            rest = &rest["distinct".len()..];
+        } else if rest.starts_with("name:") {
+            let value = rest["name:".len()..].trim_left();
+            let (name, remaining) = if value.starts_with("\"") {
+                match value[1..].find('"') {
+                    Some(end) => (&value[1..end + 1], &value[end + 2..]),
+                    None => (&value[1..], ""),
+                }
+            } else {
+                let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                (&value[..end], &value[end..])
+            };
+            metadata.name = Some(name.to_string());
+            rest = remaining;
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            rest = &rest[end..];
+        }
+        rest = rest.trim_left();
+    }
+    Some(metadata)
+}
+
+fn annotate_block(block:&mut Node, annotation:BlockMetadata) {
+    match block {
+        &mut Node::Pos(_, ref mut node) => annotate_block(node, annotation),
+        &mut Node::Block { ref mut metadata, .. } => *metadata = annotation,
+        _ => {}
+    }
+}
+
+//--------------------------------------------------------------------
+// Syntax versions
+//--------------------------------------------------------------------
+
+// A file can say which version of the grammar it's written in with a `syntax 0.4` line
+// before anything else. New syntax is only turned on for files that ask for a version
+// that has it, so existing files keep parsing exactly the way they always have. A file
+// without the header is read as DEFAULT_SYNTAX, the grammar from before there were
+// versions, and loading it prints a warning.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Syntax {
+    pub major: u32,
+    pub minor: u32,
+}
+
+pub const DEFAULT_SYNTAX:Syntax = Syntax { major: 0, minor: 3 };
+pub const LATEST_SYNTAX:Syntax = Syntax { major: 0, minor: 4 };
+pub const SUPPORTED_SYNTAX:&'static [Syntax] = &[DEFAULT_SYNTAX, LATEST_SYNTAX];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    /// `/* ... */` comments, which can span lines.
+    BlockComments,
+}
+
+impl Feature {
+    /// The first version of the grammar with this feature.
+    pub fn since(&self) -> Syntax {
+        match self {
+            &Feature::BlockComments => Syntax { major: 0, minor: 4 },
+        }
+    }
+}
+
+impl Syntax {
+    pub fn parse(version:&str) -> Option<Syntax> {
+        let mut parts = version.splitn(2, '.');
+        let major = parts.next().and_then(|major| u32::from_str(major).ok())?;
+        let minor = parts.next().and_then(|minor| u32::from_str(minor).ok())?;
+        Some(Syntax { major, minor })
+    }
+
+    pub fn is_supported(&self) -> bool {
+        SUPPORTED_SYNTAX.contains(self)
+    }
+
+    pub fn allows(&self, feature:Feature) -> bool {
+        *self >= feature.since()
+    }
+}
+
+impl fmt::Display for Syntax {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// The version `content`'s `syntax` header asks for, or the text of the version if it
+/// isn't one, along with where the header is. None if the first line with anything on
+/// it isn't a header.
+pub fn syntax_header(content:&str) -> Option<(Result<Syntax, String>, Span)> {
+    let mut pos = 0;
+    for (line_ix, line) in content.split("\n").enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            pos += line.len() + 1;
+            continue;
+        }
+        let mut words = trimmed.split_whitespace();
+        if words.next() != Some("syntax") { return None; }
+        let version = match (words.next(), words.next()) {
+            (Some(version), None) if version.starts_with(|c:char| c.is_digit(10)) => version,
+            _ => return None,
+        };
+        let ch = line.len() - line.trim_left().len();
+        let start = Pos { line: line_ix, ch, pos: pos + ch };
+        let stop = Pos { line: line_ix, ch: ch + trimmed.len(), pos: pos + ch + trimmed.len() };
+        let syntax = match Syntax::parse(version) {
+            Some(syntax) if syntax.is_supported() => Ok(syntax),
+            _ => Err(version.to_string()),
+        };
+        return Some((syntax, Span { start, stop }));
+    }
+    None
+}
+
+// Whether a block comment is still open at the end of `line`, given whether one was
+// open at its start.
+fn in_block_comment(line:&str, mut open:bool) -> bool {
+    let mut rest = line;
+    loop {
+        let marker = if open { "*/" } else { "/*" };
+        match rest.find(marker) {
+            Some(ix) => {
+                rest = &rest[ix + marker.len()..];
+                open = !open;
+            }
+            None => return open,
+        }
+    }
+}
+
+//--------------------------------------------------------------------
+// Markdown
+//--------------------------------------------------------------------
+
+// Returns the marker and info string if the line opens or closes a code fence.
+fn code_fence(line:&str) -> Option<(&'static str, &str)> {
+    let trimmed = line.trim_left();
+    for marker in ["```", "~~~"].iter() {
+        if trimmed.starts_with(marker) {
+            let fence_char = marker.chars().next().unwrap();
+            return Some((*marker, trimmed.trim_left_matches(fence_char).trim()));
+        }
+    }
+    None
+}
+
+fn is_eve_fence(info:&str) -> bool {
+    info == "" || info == "eve"
+}
+
+/// The lines of `content` outside of its code fences, so a file's annotations aren't
+/// mixed up with ones quoted in a code sample.
+pub fn prose_lines(content:&str) -> Vec<&str> {
+    let mut lines = vec![];
+    let mut open = None;
+    for line in content.lines() {
+        match (open, code_fence(line)) {
+            (None, Some((marker, _))) => open = Some(marker),
+            (Some(marker), Some((close, ""))) if close == marker => open = None,
+            (None, None) => lines.push(line),
+            _ => {}
+        }
+    }
+    lines
+}
+
+fn scan_blocks<'a>(state:&mut ParseState<'a>, end:usize, blocks:&mut Vec<Node<'a>>) {
+    let input = state.input;
+    let mut annotation = None;
     while state.pos < end {
         state.mark("line");
         let has_start = opt!(state, block_start);
         match has_start {
-            None => { state.pop(); state.consume_line(); }
+            None => {
+                state.pop();
+                // an annotation holds through blank lines until the next block
+                let line = input[state.pos..end].lines().next().unwrap_or("");
+                if let Some(metadata) = block_annotation(line) {
+                    annotation = Some(metadata);
+                } else if line.trim().len() > 0 {
+                    annotation = None;
+                }
+                state.consume_line();
+            }
             Some(v) => {
                 state.backtrack();
                 let block_pos = state.pos;
                 let block_line = state.line;
                 let block_ch = state.ch;
+                // an `end` inside a block comment doesn't end the block
+                let mut in_comment = false;
                 while state.pos < end {
-                    if let Some(_) = opt!(state, block_end) { break; }
+                    if !in_comment {
+                        if let Some(_) = opt!(state, block_end) { break; }
+                    }
+                    if state.syntax.allows(Feature::BlockComments) {
+                        let line = input[state.pos..end].lines().next().unwrap_or("");
+                        in_comment = in_block_comment(line, in_comment);
+                    }
                     state.consume_line();
                 }
-                let block_content = &state.input[block_pos..state.pos];
+                let block_content = &input[block_pos..cmp::min(state.pos, end)];
                 let mut block_state = ParseState::new(block_content);
                 block_state.line = block_line;
                 block_state.ch = block_ch;
+                block_state.syntax = state.syntax;
                 if v == "disabled" {
                     blocks.push(Node::DisabledBlock(block_content));
                 } else {
                     let result = block(&mut block_state);
                     match result {
-                        ParseResult::Ok(block) => blocks.push(block),
+                        ParseResult::Ok(mut block) => {
+                            if let Some(metadata) = annotation.take() { annotate_block(&mut block, metadata); }
+                            blocks.push(block)
+                        }
                         _ => {}
                     }
                 }
+                annotation = None;
             },
         }
     }
+}
+
+// Once a document uses code fences, only ``` and ```eve fences are executable. Prose,
+// indented examples and fences for other languages are left alone. Blocks in a fence
+// are parsed starting from the fence's line so errors still point into the file.
+fn fenced_blocks<'a>(state:&mut ParseState<'a>, blocks:&mut Vec<Node<'a>>) {
+    let input = state.input;
+    let end = input.len();
+    while state.pos < end {
+        let line = input[state.pos..].lines().next().unwrap_or("");
+        state.consume_line();
+        if let Some((marker, info)) = code_fence(line) {
+            let (content_pos, content_line) = (state.pos, state.line);
+            let mut content_end = end;
+            while state.pos < end {
+                let line_pos = state.pos;
+                let line = input[state.pos..].lines().next().unwrap_or("");
+                state.consume_line();
+                if let Some((close, "")) = code_fence(line) {
+                    if close == marker {
+                        content_end = line_pos;
+                        break;
+                    }
+                }
+            }
+            if is_eve_fence(info) {
+                let (resume_pos, resume_line) = (state.pos, state.line);
+                state.pos = content_pos;
+                state.line = content_line;
+                state.ch = 0;
+                scan_blocks(state, content_end, blocks);
+                state.pos = resume_pos;
+                state.line = resume_line;
+                state.ch = 0;
+            }
+        }
+    }
+}
+
+parser!(embedded_blocks(state, file:&str) -> Node<'a> {
+    if let Some((Ok(syntax), _)) = syntax_header(state.input) {
+        state.syntax = syntax;
+    }
+    let mut blocks = vec![];
+    if state.input.lines().any(|line| code_fence(line).is_some()) {
+        fenced_blocks(state, &mut blocks);
+    } else {
+        let end = state.input.len();
+        scan_blocks(state, end, &mut blocks);
+    }
     result!(state, Node::Doc { file:file.to_string(), blocks})
 });
@@ -0,0 +1,342 @@
+//-------------------------------------------------------------------------
+// Persister
+//-------------------------------------------------------------------------
+
+extern crate bincode;
+
+use ops::{Block, Interned, Internable, InternedValues, RawChange, ScopeRetention, DebugMode, trace, retention};
+use indexes::{DistinctIndex, IntermediateIndex, WatchIndex, BlockDistinct};
+use admin::AdminReply;
+use std::collections::{HashMap, BTreeMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::fs::{self, OpenOptions, File, canonicalize};
+use std::io::{self, Write, BufReader, BufWriter};
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+use std::mem;
+
+pub enum PersisterMessage {
+    Stop,
+    Write(Vec<RawChange>),
+    // the reply says whether the snapshot made it to disk
+    Snapshot(Vec<RawChange>, Sender<Result<(), String>>),
+    // snapshots the view's committed facts, first checkpointing what was derived from
+    // them at the path if there is one, and answers the admin command that asked for it
+    SnapshotView(Box<ProgramView>, Option<String>, Sender<AdminReply>),
+}
+
+/// The committed facts and derived state of a program at one point in time. It shares
+/// its storage with the program, so it's cheap to take, and everything done with it
+/// can happen off the program's thread.
+pub struct ProgramView {
+    pub values: InternedValues,
+    pub scopes: HashMap<String, ScopeRetention>,
+    pub blocks: BTreeMap<String, u64>,
+    pub distinct_index: DistinctIndex,
+    pub intermediates: IntermediateIndex,
+    pub watch_indexes: HashMap<String, WatchIndex>,
+    pub block_distinct: HashMap<Interned, BlockDistinct>,
+}
+
+impl ProgramView {
+    /// Every persistent fact committed when the view was taken, as it would be written
+    /// by the persister.
+    pub fn committed_facts(&self) -> Vec<RawChange> {
+        let ids:Vec<(Interned, Interned, Interned)> = self.distinct_index.commits().into_iter()
+            .filter(|&(_, a, _)| retention(&self.scopes, &self.values[a as usize]) == ScopeRetention::Persistent)
+            .collect();
+        decode_facts(&self.values, &ids)
+    }
+
+    /// Writes everything derived from `commits` to `path`, to be picked up again by
+    /// `Program::restore_checkpoint`. `commits` has to be what the db is being
+    /// snapshotted to.
+    pub fn write_checkpoint(&self, path:&str, commits:&[RawChange]) -> io::Result<()> {
+        let checkpoint = CheckpointRef {
+            version: CHECKPOINT_VERSION,
+            blocks: &self.blocks,
+            commits: commits.len(),
+            commits_hash: commits_hash(commits),
+            values: &self.values,
+            distinct_index: &self.distinct_index,
+            intermediates: &self.intermediates,
+            watch_indexes: &self.watch_indexes,
+            block_distinct: &self.block_distinct,
+        };
+        // write and then rename so a crash mid-write never leaves a torn checkpoint
+        let temp_path = format!("{}.tmp", path);
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            bincode::serialize_into(&mut writer, &checkpoint, bincode::Infinite)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+            writer.flush()?;
+        }
+        fs::rename(&temp_path, path)
+    }
+}
+
+fn decode_facts(values:&InternedValues, ids:&[(Interned, Interned, Interned)]) -> Vec<RawChange> {
+    let n = Internable::String("snapshot".to_string());
+    ids.iter()
+        .map(|&(e, a, v)| RawChange::new(values[e as usize].clone(), values[a as usize].clone(), values[v as usize].clone(), n.clone(), 1))
+        .collect()
+}
+
+// Whether two paths name the same file, whether or not it exists yet.
+pub fn same_path(a:&str, b:&str) -> bool {
+    match (canonicalize(a), canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => Path::new(a) == Path::new(b),
+    }
+}
+
+/// Writes `items` as a complete db at `path`. The snapshot is written next to it and
+/// swapped in, so a crash part way through leaves whatever was there intact.
+pub fn write_snapshot(path:&str, items:&Vec<RawChange>) -> io::Result<()> {
+    let snapshot_path = format!("{}.snapshot", path);
+    {
+        let mut snapshot = BufWriter::new(File::create(&snapshot_path)?);
+        for item in items {
+            let result = bincode::serialize(item, bincode::Infinite)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+            snapshot.write_all(&result)?;
+        }
+        snapshot.flush()?;
+    }
+    fs::rename(&snapshot_path, path)
+}
+
+// Swaps a snapshot of `items` in for the db the persister is appending to. The snapshot
+// is written next to the db and renamed over it, so a crash part way through leaves the
+// old log intact. If any of it fails we keep appending to the log we had.
+fn swap_snapshot(path:&str, writer:&mut BufWriter<File>, items:&Vec<RawChange>) -> Result<(), String> {
+    let reopened = writer.flush()
+        .and_then(|_| write_snapshot(path, items))
+        .and_then(|_| OpenOptions::new().append(true).create(true).open(path));
+    match reopened {
+        Ok(file) => { *writer = BufWriter::new(file); Ok(()) }
+        Err(err) => Err(format!("Unable to snapshot {}: {}", path, err)),
+    }
+}
+
+pub struct Persister {
+    path: String,
+    thread: JoinHandle<()>,
+    outgoing: Sender<PersisterMessage>,
+    loaded: Vec<RawChange>,
+}
+
+
+impl Persister {
+    pub fn new(path_ref:&str) -> Persister {
+        let (outgoing, incoming) = mpsc::channel();
+        let path = path_ref.to_string();
+        let thread = thread::spawn(move || {
+            let file = OpenOptions::new().append(true).create(true).open(&path).unwrap();
+            let mut writer = BufWriter::new(file);
+            loop {
+                match incoming.recv().unwrap() {
+                    PersisterMessage::Stop => { break; }
+                    PersisterMessage::Write(items) => {
+                        println!("Let's persist some stuff!");
+                        for item in items {
+                            let result = bincode::serialize(&item, bincode::Infinite).unwrap();
+                            match writer.write_all(&result) {
+                                Err(e) => {panic!("Can't persist! {:?}", e); }
+                                Ok(_) => { }
+                            }
+                        }
+                        writer.flush().unwrap();
+                    }
+                    PersisterMessage::Snapshot(items, reply) => {
+                        reply.send(swap_snapshot(&path, &mut writer, &items)).ok();
+                    }
+                    PersisterMessage::SnapshotView(view, checkpoint_path, reply) => {
+                        let items = view.committed_facts();
+                        if let Some(checkpoint_path) = checkpoint_path {
+                            // without a checkpoint the next start just derives everything again
+                            if let Err(err) = view.write_checkpoint(&checkpoint_path, &items) {
+                                trace(DebugMode::Runtime, || format!("Unable to write derivation checkpoint {}: {}", checkpoint_path, err));
+                            }
+                        }
+                        let result = match swap_snapshot(&path, &mut writer, &items) {
+                            Ok(_) => AdminReply::Done,
+                            Err(message) => AdminReply::Error(message),
+                        };
+                        reply.send(result).ok();
+                    }
+                }
+            }
+        });
+        Persister { path: path_ref.to_string(), outgoing, thread, loaded: vec![] }
+    }
+
+    pub fn load(&mut self, path:&str) {
+        let file = match File::open(path) {
+            Ok(f) => f,
+            Err(_) => {
+                println!("Unable to load db: {}", path);
+                return;
+            }
+        };
+        let mut reader = BufReader::new(file);
+        loop {
+            let result:Result<RawChange, _> = bincode::deserialize_from(&mut reader, bincode::Infinite);
+            match result {
+                Ok(c) => {
+                    println!("{:?}", c);
+                    self.loaded.push(c);
+                },
+                Err(info) => {
+                    println!("ran out {:?}", info);
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn send(&self, changes:Vec<RawChange>) {
+        self.outgoing.send(PersisterMessage::Write(changes)).unwrap();
+    }
+
+    pub fn wait(self) {
+        self.thread.join().unwrap();
+    }
+
+    pub fn get_channel(&self) -> Sender<PersisterMessage> {
+        self.outgoing.clone()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn watcher_checkpoint_path(&self) -> String {
+        format!("{}.watchers", self.path)
+    }
+
+    pub fn derivation_checkpoint_path(&self) -> String {
+        format!("{}.derived", self.path)
+    }
+
+    pub fn get_commits(&mut self) -> Vec<RawChange> {
+        mem::replace(&mut self.loaded, vec![])
+    }
+
+    pub fn close(&self) {
+        self.outgoing.send(PersisterMessage::Stop).unwrap();
+    }
+}
+
+//-------------------------------------------------------------------------
+// Derivation checkpoints
+//-------------------------------------------------------------------------
+
+// Starting a persisted program replays every commit through every block, which for
+// expensive derivations like graph closures or text indexes can take far longer than
+// making the commits did. So when the db is snapshotted, everything derived from it, the
+// indexes, intermediates and aggregates, is written next to it as `<db>.derived`. On the
+// next start, if the blocks are the ones the checkpoint was taken with, the derived state
+// is loaded as is, the blocks are registered without being run over it and only the
+// commits made since the snapshot flow through them.
+//
+// A checkpoint is only good for the exact blocks and commits it was taken with. If any
+// block was added, removed or changed, or the db isn't the snapshot the checkpoint was
+// written alongside, it's ignored and everything is derived from scratch.
+
+const CHECKPOINT_VERSION:u32 = 5;
+
+// The index is left out, since it's just the facts the distinct index has inserted, and
+// so is the interner's lookup table, which is rebuilt from the values.
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    version: u32,
+    blocks: &'a BTreeMap<String, u64>,
+    commits: usize,
+    commits_hash: u64,
+    values: &'a InternedValues,
+    distinct_index: &'a DistinctIndex,
+    intermediates: &'a IntermediateIndex,
+    watch_indexes: &'a HashMap<String, WatchIndex>,
+    block_distinct: &'a HashMap<Interned, BlockDistinct>,
+}
+
+#[derive(Deserialize)]
+pub struct Checkpoint {
+    version: u32,
+    pub blocks: BTreeMap<String, u64>,
+    pub commits: usize,
+    commits_hash: u64,
+    pub values: InternedValues,
+    pub distinct_index: DistinctIndex,
+    pub intermediates: IntermediateIndex,
+    pub watch_indexes: HashMap<String, WatchIndex>,
+    pub block_distinct: HashMap<Interned, BlockDistinct>,
+}
+
+impl Checkpoint {
+    /// The checkpoint at `path`, if there is one and it was taken over the start of
+    /// `commits`.
+    pub fn load(path:&str, commits:&[RawChange]) -> Option<Checkpoint> {
+        let file = File::open(path).ok()?;
+        let checkpoint:Checkpoint = match bincode::deserialize_from(&mut BufReader::new(file), bincode::Infinite) {
+            Ok(checkpoint) => checkpoint,
+            Err(info) => {
+                trace(DebugMode::Runtime, || format!("Unable to load derivation checkpoint from {}: {:?}", path, info));
+                return None;
+            }
+        };
+        if checkpoint.version != CHECKPOINT_VERSION ||
+           checkpoint.commits > commits.len() ||
+           checkpoint.commits_hash != commits_hash(&commits[..checkpoint.commits]) {
+            return None;
+        }
+        Some(checkpoint)
+    }
+}
+
+/// Where a restored checkpoint left off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumePoint {
+    blocks: BTreeMap<String, u64>,
+    /// How many of the db's commits the checkpoint already derived from.
+    pub commits: usize,
+}
+
+impl ResumePoint {
+    pub fn new(blocks:BTreeMap<String, u64>, commits:usize) -> ResumePoint {
+        ResumePoint { blocks, commits }
+    }
+
+    /// Whether `blocks` are the ones that were running when the checkpoint was taken.
+    pub fn matches(&self, blocks:&[Block]) -> bool {
+        let current:BTreeMap<String, u64> = blocks.iter()
+            .filter(|block| !block.metadata.disabled)
+            .map(|block| (block.name.to_string(), block_fingerprint(block)))
+            .collect();
+        current == self.blocks
+    }
+
+    /// The blocks, sub-blocks included, the checkpoint was taken with.
+    pub fn block_names(&self) -> Vec<String> {
+        self.blocks.keys().cloned().collect()
+    }
+}
+
+// What a block was compiled to, before registering rewrites anything about it.
+pub fn block_fingerprint(block:&Block) -> u64 {
+    let mut hash = DefaultHasher::new();
+    format!("{:?}", block.constraints).hash(&mut hash);
+    if block.metadata.distinct { "distinct".hash(&mut hash); }
+    hash.finish()
+}
+
+fn commits_hash(commits:&[RawChange]) -> u64 {
+    let mut hash = DefaultHasher::new();
+    for commit in commits {
+        format!("{:?}", commit).hash(&mut hash);
+    }
+    hash.finish()
+}
@@ -0,0 +1,175 @@
+//-------------------------------------------------------------------------
+// Provenance
+//-------------------------------------------------------------------------
+
+use ops::{Interned, Interner, Count, Field, Constraint, format_interned};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Derivation {
+    pub block: Interned,
+    pub row: Vec<Interned>,
+}
+
+/// Which block produced each derived fact and the row it was matching at the time. Only
+/// kept while provenance is turned on, see `Program::track_provenance`.
+pub struct Provenance {
+    derivations: HashMap<(Interned, Interned, Interned), Vec<Derivation>>,
+}
+
+impl Provenance {
+    pub fn new() -> Provenance {
+        Provenance { derivations: HashMap::new() }
+    }
+
+    pub fn record(&mut self, e:Interned, a:Interned, v:Interned, block:Interned, row:&[Interned], count:Count) {
+        let derivation = Derivation { block, row: row.to_vec() };
+        if count > 0 {
+            let entry = self.derivations.entry((e, a, v)).or_insert_with(|| vec![]);
+            if !entry.contains(&derivation) { entry.push(derivation); }
+        } else if count < 0 {
+            let empty = match self.derivations.get_mut(&(e, a, v)) {
+                Some(entry) => {
+                    entry.retain(|cur| *cur != derivation);
+                    entry.len() == 0
+                }
+                None => false,
+            };
+            if empty { self.derivations.remove(&(e, a, v)); }
+        }
+    }
+
+    pub fn derivations(&self, e:Interned, a:Interned, v:Interned) -> Option<&Vec<Derivation>> {
+        self.derivations.get(&(e, a, v))
+    }
+
+    // Once a fact is gone so is every reason it had to exist. Commits and facts removed
+    // from outside the program never retract the derivation that put them there.
+    pub fn forget(&mut self, e:Interned, a:Interned, v:Interned) {
+        self.derivations.remove(&(e, a, v));
+    }
+
+    /// How many facts have derivations recorded.
+    pub fn len(&self) -> usize {
+        self.derivations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.derivations.is_empty()
+    }
+}
+
+/// Why a fact exists. A fact with no derivations was put there from outside the program
+/// (a commit from a watcher or the host) rather than by a block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    pub e: Interned,
+    pub a: Interned,
+    pub v: Interned,
+    pub derivations: Vec<ExplainedDerivation>,
+}
+
+/// One block match that produced a fact: the block, the values its registers held, and
+/// the explanations of the facts its scans matched. Nots, chooses and aggregates match
+/// against intermediates rather than facts, so what they contributed doesn't show up in
+/// `inputs`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExplainedDerivation {
+    pub block: String,
+    pub bindings: Vec<Interned>,
+    pub inputs: Vec<Explanation>,
+}
+
+impl Explanation {
+    pub fn print(&self, interner:&Interner) -> String {
+        let mut result = String::new();
+        self.print_depth(interner, 0, &mut result);
+        result
+    }
+
+    fn print_depth(&self, interner:&Interner, depth:usize, result:&mut String) {
+        let indent = "  ".repeat(depth);
+        result.push_str(&format!("{}(<{}>, {:?}, {})", indent, self.e, interner.get_value(self.a).print(), format_interned(interner, self.v)));
+        if self.derivations.len() == 0 {
+            result.push_str(" input");
+        }
+        result.push_str("\n");
+        for derivation in self.derivations.iter() {
+            result.push_str(&format!("{}  from {}\n", indent, derivation.block));
+            for input in derivation.inputs.iter() {
+                input.print_depth(interner, depth + 2, result);
+            }
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+// Why not
+//-------------------------------------------------------------------------
+
+/// How far one block that could have produced a missing record got. `matched` is the part
+/// of its search that still has results, in the order it was checked, and `failed` is the
+/// constraint that left nothing. `failed` is None when the block's search does match, in
+/// which case the record is (or is about to be) produced after all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WhyNot {
+    pub block: String,
+    pub matched: Vec<String>,
+    pub failed: Option<String>,
+}
+
+fn describe_field(interner:&Interner, field:&Field) -> String {
+    match field {
+        &Field::Register(_) => "?".to_string(),
+        &Field::Value(value) => format_interned(interner, value),
+    }
+}
+
+pub fn describe_constraint(interner:&Interner, constraint:&Constraint) -> String {
+    match constraint {
+        &Constraint::Scan { ref e, ref a, ref v, .. } |
+        &Constraint::RangeScan { ref e, ref a, ref v, .. } |
+        &Constraint::LookupCommit { ref e, ref a, ref v, .. } => {
+            format!("scan for attribute `{}` (entity {}, value {})", describe_field(interner, a), describe_field(interner, e), describe_field(interner, v))
+        }
+        &Constraint::Filter { ref op, ref left, ref right, .. } => {
+            format!("filter {} {} {}", describe_field(interner, left), op, describe_field(interner, right))
+        }
+        &Constraint::Function { ref op, .. } |
+        &Constraint::MultiFunction { ref op, .. } |
+        &Constraint::IndexFunction { ref op, .. } |
+        &Constraint::CustomFunction { ref op, .. } => format!("function `{}`", op),
+        &Constraint::AntiScan { .. } => "not(...)".to_string(),
+        &Constraint::IntermediateScan { .. } => "choose or aggregate lookup".to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+// Orders a block's search so each constraint comes after whatever provides its inputs,
+// which lets any prefix of it be run on its own.
+pub fn order_by_inputs(mut remaining:Vec<Constraint>) -> Vec<Constraint> {
+    let mut ordered = vec![];
+    let mut provided:HashSet<Field> = HashSet::new();
+    while remaining.len() > 0 {
+        let next = remaining.iter().position(|constraint| {
+            let outputs = constraint.get_output_registers();
+            constraint.get_registers().iter().all(|reg| outputs.contains(reg) || provided.contains(reg))
+        }).unwrap_or(0);
+        let constraint = remaining.remove(next);
+        provided.extend(constraint.get_output_registers());
+        ordered.push(constraint);
+    }
+    ordered
+}
+
+// Registers have to be numbered from zero for the solver to know when a row is done, so
+// a subset of a block's constraints gets renumbered before it's run.
+pub fn renumber_registers(constraints:&mut Vec<Constraint>) {
+    let mut registers:Vec<Field> = constraints.iter().flat_map(|constraint| constraint.get_registers()).collect();
+    registers.sort_by_key(|reg| match reg { &Field::Register(ix) => ix, &Field::Value(_) => 0 });
+    registers.dedup();
+    let lookup:HashMap<Field, Field> = registers.iter().enumerate().map(|(ix, reg)| (*reg, Field::Register(ix))).collect();
+    for constraint in constraints.iter_mut() {
+        constraint.replace_registers(&lookup);
+    }
+}
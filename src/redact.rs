@@ -0,0 +1,193 @@
+//-------------------------------------------------------------------------
+// Redaction
+//-------------------------------------------------------------------------
+
+// Production data has to be scrubbed before it's handed to developers. A redaction says
+// what happens to the value of each sensitive attribute on the way out of a snapshot
+// export, a JSON dump or a bug report:
+//
+// - hash: swapped for a keyed hash of itself, so equal values stay equal and joins still
+//   work, but a value can't be recovered by hashing guesses without the key
+// - mask: kept the same shape with the content blanked out, e.g. `****@*******.***`
+// - drop: the fact isn't exported at all
+//
+// Record ids are never touched, so whatever's left of a record still hangs together.
+// Configs are JSON, e.g. `{"attributes": {"email": "hash", "ssn": "drop"}}`, and
+// `"strings"` sets what happens to strings in every attribute that isn't listed. The key
+// is derived from `"salt"`, which has to be kept secret and stay the same for hashes to
+// line up across exports; without one a random salt is used, so hashes only line up
+// within an export.
+
+extern crate serde_json;
+extern crate siphasher;
+
+use ops::{Program, CodeTransaction, Internable, RawChange};
+use persister::write_snapshot;
+use check::read_changes;
+use self::siphasher::sip::SipHasher24;
+use rand;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RedactAction {
+    Keep,
+    Hash,
+    Mask,
+    Drop,
+}
+
+impl Default for RedactAction {
+    fn default() -> RedactAction {
+        RedactAction::Keep
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Redaction {
+    #[serde(default)]
+    pub attributes: HashMap<String, RedactAction>,
+    /// Applied to strings in attributes that aren't listed. Tags are always kept.
+    #[serde(default)]
+    pub strings: RedactAction,
+    #[serde(default = "random_salt")]
+    pub salt: String,
+}
+
+impl Default for Redaction {
+    fn default() -> Redaction {
+        Redaction { attributes: HashMap::new(), strings: RedactAction::Keep, salt: random_salt() }
+    }
+}
+
+fn random_salt() -> String {
+    format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>())
+}
+
+impl Redaction {
+    pub fn none() -> Redaction {
+        Redaction::default()
+    }
+
+    pub fn from_file(path:&str) -> io::Result<Redaction> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+        serde_json::from_str(&contents).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))
+    }
+
+    pub fn attribute(mut self, attribute:&str, action:RedactAction) -> Redaction {
+        self.attributes.insert(attribute.to_string(), action);
+        self
+    }
+
+    pub fn strings(mut self, action:RedactAction) -> Redaction {
+        self.strings = action;
+        self
+    }
+
+    pub fn salt(mut self, salt:&str) -> Redaction {
+        self.salt = salt.to_string();
+        self
+    }
+
+    // Both halves of the SipHash key come from the salt, each under its own label.
+    fn key(&self) -> (u64, u64) {
+        let half = |label:&str| {
+            let mut hasher = SipHasher24::new();
+            label.hash(&mut hasher);
+            self.salt.hash(&mut hasher);
+            hasher.finish()
+        };
+        (half("k0"), half("k1"))
+    }
+
+    fn action(&self, attribute:&Internable, value:&Internable) -> RedactAction {
+        // a scoped attribute is redacted like the attribute it scopes
+        let unscoped = match attribute {
+            &Internable::String(ref attribute) | &Internable::Scoped(_, ref attribute) => attribute,
+            _ => return RedactAction::Keep,
+        };
+        match value {
+            &Internable::Reference(_) | &Internable::Scoped(..) | &Internable::Null => RedactAction::Keep,
+            _ if unscoped == "tag" => RedactAction::Keep,
+            &Internable::String(_) => *self.attributes.get(unscoped).unwrap_or(&self.strings),
+            _ => *self.attributes.get(unscoped).unwrap_or(&RedactAction::Keep),
+        }
+    }
+
+    /// The change as it should be exported, or None if it shouldn't be.
+    pub fn redact(&self, mut change:RawChange) -> Option<RawChange> {
+        match self.action(&change.a, &change.v) {
+            RedactAction::Keep => {}
+            RedactAction::Hash => change.v = hash_value(self.key(), &change.v),
+            RedactAction::Mask => change.v = mask_value(&change.v),
+            RedactAction::Drop => return None,
+        }
+        Some(change)
+    }
+
+    pub fn redact_all(&self, changes:Vec<RawChange>) -> Vec<RawChange> {
+        changes.into_iter().filter_map(|change| self.redact(change)).collect()
+    }
+}
+
+// SipHash rather than the std hasher, whose output can change between Rust releases, so
+// the same value and salt hash the same in every export.
+fn hash_value((k0, k1):(u64, u64), value:&Internable) -> Internable {
+    let mut hasher = SipHasher24::new_with_keys(k0, k1);
+    value.hash(&mut hasher);
+    Internable::String(format!("redacted-{:016x}", hasher.finish()))
+}
+
+fn mask_value(value:&Internable) -> Internable {
+    match value {
+        &Internable::String(ref string) => Internable::String(string.chars().map(|c| if c.is_alphanumeric() { '*' } else { c }).collect()),
+        _ => Internable::from_number(0.0),
+    }
+}
+
+fn json_value(value:&Internable) -> serde_json::Value {
+    match value {
+        &Internable::String(ref string) | &Internable::Reference(ref string) => json!(string),
+        &Internable::Scoped(..) => json!(Internable::to_string(value)),
+        &Internable::Number(_) => json!(Internable::to_number(value)),
+        &Internable::Decimal(ref decimal) => json!(decimal.to_float()),
+        &Internable::Null => serde_json::Value::Null,
+    }
+}
+
+/// Facts as a JSON array of `{"e", "a", "v"}` objects, for handing data to other tools.
+/// Record ids come out as the strings they're made of, which always end in `|`.
+pub fn facts_to_json(changes:&[RawChange]) -> String {
+    let facts:Vec<serde_json::Value> = changes.iter().map(|change| {
+        json!({ "e": json_value(&change.e), "a": json_value(&change.a), "v": json_value(&change.v) })
+    }).collect();
+    serde_json::to_string_pretty(&facts).unwrap()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// The same format `Program::save` writes, so the export can be loaded like any db.
+    Snapshot,
+    Json,
+}
+
+/// Replays the log in `db`, redacts the facts it ends up with and writes them to
+/// `output`. Returns how many facts were written.
+pub fn export_db(db:&str, output:&str, format:ExportFormat, redaction:&Redaction) -> io::Result<usize> {
+    let mut program = Program::new("export");
+    let mut txn = CodeTransaction::new();
+    for change in read_changes(db)?.0 {
+        txn.input_change(change.to_change(&mut program.state.interner));
+    }
+    txn.exec(&mut program, vec![], vec![]);
+    let facts = redaction.redact_all(program.committed_facts());
+    match format {
+        ExportFormat::Snapshot => write_snapshot(output, &facts)?,
+        ExportFormat::Json => File::create(output)?.write_all(facts_to_json(&facts).as_bytes())?,
+    }
+    Ok(facts.len())
+}
@@ -0,0 +1,90 @@
+//-------------------------------------------------------------------------
+// Bug reports
+//-------------------------------------------------------------------------
+
+// A bug report is one JSON file holding everything needed to reproduce a problem with a
+// program: the source of every file it was loaded from, a snapshot of what it has
+// committed, the tail of its db's change log (the writes leading up to the problem) and
+// what the engine has to say about the program once all of that is loaded. Before
+// anything is written values can be redacted (see redact.rs), so reports can be attached
+// to public issues without leaking whatever the program stores.
+
+extern crate serde_json;
+
+use ops::{Program, CodeTransaction, RawChange};
+use admin::AdminStats;
+use compiler::{compile_string, CompileOptions};
+use check::{read_changes, check_indexes};
+use redact::Redaction;
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+/// How many of the log's most recent changes a report keeps by default.
+pub const DEFAULT_LOG_TAIL:usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportSource {
+    pub path: String,
+    pub source: String,
+    pub blocks: usize,
+    pub errors: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportStats {
+    pub program: AdminStats,
+    pub facts: usize,
+    pub log_changes: usize,
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BugReport {
+    pub version: String,
+    pub sources: Vec<ReportSource>,
+    pub snapshot: Vec<RawChange>,
+    pub log: Vec<RawChange>,
+    pub stats: ReportStats,
+}
+
+impl BugReport {
+    pub fn write(&self, path:&str) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        File::create(path)?.write_all(json.as_bytes())
+    }
+}
+
+/// Loads the files at `paths` and the changes logged in `db` into a fresh program, the
+/// way `eve run` would minus the watchers, and bundles up the result.
+pub fn bundle_report(paths:&[String], db:Option<&str>, redaction:&Redaction, log_tail:usize) -> io::Result<BugReport> {
+    let mut program = Program::new("report");
+    let mut sources = vec![];
+    let mut blocks = vec![];
+    for path in paths {
+        let mut source = String::new();
+        File::open(path)?.read_to_string(&mut source)?;
+        let (compiled, errors) = compile_string(&mut program.state.interner, &source, path, &CompileOptions::default());
+        sources.push(ReportSource { path: path.to_string(), source, blocks: compiled.len(), errors });
+        blocks.extend(compiled);
+    }
+    let log = match db {
+        Some(db) => read_changes(db)?.0,
+        None => vec![],
+    };
+    let mut txn = CodeTransaction::new();
+    for change in log.iter() {
+        txn.input_change(change.clone().to_change(&mut program.state.interner));
+    }
+    txn.exec(&mut program, blocks, vec![]);
+
+    let problems = check_indexes(&program);
+    let stats = ReportStats { program: program.stats(), facts: program.state.index.size as usize, log_changes: log.len(), problems };
+    let tail = log.len().saturating_sub(log_tail);
+    Ok(BugReport {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        sources,
+        snapshot: redaction.redact_all(program.committed_facts()),
+        log: redaction.redact_all(log.into_iter().skip(tail).collect()),
+        stats,
+    })
+}
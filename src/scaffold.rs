@@ -0,0 +1,49 @@
+//-------------------------------------------------------------------------
+// Project templates
+//-------------------------------------------------------------------------
+
+// `eve new TEMPLATE` writes out a small, runnable project to start from. Templates live
+// in templates/ and are compiled into the binary, so the command works from anywhere.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub struct Template {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub files: &'static [(&'static str, &'static str)],
+}
+
+pub const TEMPLATES:&'static [Template] = &[
+    Template {
+        name: "todo-app",
+        description: "A todo list with an HTML UI, seed data and tests",
+        files: &[
+            ("readme.md", include_str!("../templates/todo-app/readme.md")),
+            ("todos.eve", include_str!("../templates/todo-app/todos.eve")),
+            ("seed.eve", include_str!("../templates/todo-app/seed.eve")),
+            ("tests.eve", include_str!("../templates/todo-app/tests.eve")),
+        ],
+    },
+];
+
+pub fn find_template(name:&str) -> Option<&'static Template> {
+    TEMPLATES.iter().find(|template| template.name == name)
+}
+
+/// Writes the template's files into `dir`, which is created if needed but mustn't have
+/// anything in it already. Returns the paths written.
+pub fn new_project(template:&Template, dir:&Path) -> io::Result<Vec<PathBuf>> {
+    if dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} isn't empty", dir.display())));
+    }
+    fs::create_dir_all(dir)?;
+    let mut written = vec![];
+    for &(name, contents) in template.files.iter() {
+        let path = dir.join(name);
+        File::create(&path)?.write_all(contents.as_bytes())?;
+        written.push(path);
+    }
+    Ok(written)
+}
@@ -1,17 +1,181 @@
 use ops::*;
 use compiler::{FunctionKind};
-use indexes::{WatchIndex, RemoteChangeField};
-use std::collections::{HashSet};
+use indexes::{WatchIndex, RemoteChangeField, DistinctIter, DistinctIndex, IntermediateIndex};
+use provenance::Provenance;
+use batch::{self, Comparison};
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::usize;
 use std::iter;
 use std::sync::Arc;
+use std::collections::Bound;
 use std::fmt;
 
-pub type OutputFunc = fn(&Solver, &mut RuntimeState, &mut Frame);
-pub type AcceptFunc = Fn(&mut RuntimeState, &mut Frame, usize) -> bool;
-pub type GetIteratorFunc = Fn(&mut EstimateIter, &mut RuntimeState, &mut Frame) -> bool;
-pub type GetRoundsFunc = Fn(&mut RuntimeState, &mut Frame);
+pub type OutputFunc = fn(&Solver, &mut EvalState, &mut Frame);
+pub type AcceptFunc = Fn(&mut EvalState, &mut Frame, usize) -> bool;
+pub type GetIteratorFunc = Fn(&mut EstimateIter, &mut EvalState, &mut Frame) -> bool;
+pub type GetRoundsFunc = Fn(&mut EvalState, &mut Frame);
+
+// Below this many proposed values it's cheaper to leave the filters to the accepts.
+const BATCH_FILTER_MIN:usize = 64;
+
+//-------------------------------------------------------------------------
+// Eval State
+//-------------------------------------------------------------------------
+
+/// What a solver runs against: either the program's state, written to as it goes, or
+/// the state shared with the other partitions running on the eval pool, in which case
+/// what would be written is kept in the partition's outputs until they're merged.
+pub enum EvalState<'a> {
+    Owned(&'a mut RuntimeState),
+    Shared(&'a RuntimeState, &'a mut PartitionOutputs),
+}
+
+impl<'a> EvalState<'a> {
+    #[inline]
+    pub fn runtime(&self) -> &RuntimeState {
+        match *self {
+            EvalState::Owned(ref state) => state,
+            EvalState::Shared(state, _) => state,
+        }
+    }
+
+    #[inline]
+    pub fn output_rounds(&mut self) -> &mut OutputRounds {
+        match *self {
+            EvalState::Owned(ref mut state) => &mut state.output_rounds,
+            EvalState::Shared(_, ref mut outputs) => &mut outputs.output_rounds,
+        }
+    }
+
+    // The output rounds along with the indexes they're computed from.
+    #[inline]
+    fn rounds_and_indexes(&mut self) -> (&mut OutputRounds, &DistinctIndex, &IntermediateIndex) {
+        match *self {
+            EvalState::Owned(ref mut state) => {
+                let RuntimeState { ref mut output_rounds, ref distinct_index, ref intermediates, .. } = **state;
+                (output_rounds, distinct_index, intermediates)
+            }
+            EvalState::Shared(state, ref mut outputs) => (&mut outputs.output_rounds, &state.distinct_index, &state.intermediates),
+        }
+    }
+
+    #[inline]
+    pub fn get_value(&self, id:Interned) -> &Internable {
+        match *self {
+            EvalState::Owned(ref state) => state.interner.get_value(id),
+            EvalState::Shared(state, ref outputs) => {
+                if id < outputs.base { state.interner.get_value(id) } else { &outputs.interned[(id - outputs.base) as usize] }
+            }
+        }
+    }
+
+    pub fn intern(&mut self, thing:Internable) -> Interned {
+        match *self {
+            EvalState::Owned(ref mut state) => state.interner.internable_to_id(thing),
+            EvalState::Shared(state, ref mut outputs) => {
+                match state.interner.id(&thing) {
+                    Some(id) => id,
+                    None => outputs.intern(thing),
+                }
+            }
+        }
+    }
+
+    // See `Interner::number_column`. What a partition interned itself isn't in the
+    // interner's columns, so a proposal holding any of it is left to the accepts.
+    fn number_column(&self, ids:&[Interned], column:&mut Vec<u32>, numeric:&mut Vec<u64>) {
+        match *self {
+            EvalState::Shared(_, ref outputs) if ids.iter().any(|&id| id >= outputs.base) => {
+                column.clear();
+                column.resize(ids.len(), 0);
+                numeric.clear();
+                numeric.resize((ids.len() + 63) / 64, 0);
+            }
+            _ => self.runtime().interner.number_column(ids, column, numeric),
+        }
+    }
+
+    fn function_error(&mut self, error:FunctionError) {
+        match *self {
+            EvalState::Owned(ref mut state) => state.function_errors.push(error),
+            EvalState::Shared(_, ref mut outputs) => outputs.writes.push(PartitionWrite::FunctionError(error)),
+        }
+    }
+}
+
+enum PartitionWrite {
+    Bind(Change, Interned, Option<Vec<Interned>>),
+    Commit(Change, ChangeType, Interned, Option<Vec<Interned>>),
+    Intermediate(Vec<Interned>, Vec<Interned>, Vec<Interned>, Round, Count, bool),
+    Aggregate(Vec<Interned>, Vec<Internable>, Vec<Internable>, Round, Count, AggregateFunction, Vec<Interned>, FunctionKind),
+    Watch(String, Vec<Interned>, Count),
+    FunctionError(FunctionError),
+}
+
+/// Everything a partition run on the eval pool wrote, in the order it wrote it. Values
+/// it had to intern get ids past the ones the interner had handed out when it started,
+/// which are swapped for the interner's own when the outputs are merged.
+pub struct PartitionOutputs {
+    base: Interned,
+    interned: Vec<Internable>,
+    ids: HashMap<Internable, Interned>,
+    output_rounds: OutputRounds,
+    writes: Vec<PartitionWrite>,
+    /// The join steps taken by each pipe that ran, by block.
+    pub steps: Vec<(Interned, Vec<u64>)>,
+}
+
+impl PartitionOutputs {
+    pub fn new(state:&RuntimeState) -> PartitionOutputs {
+        PartitionOutputs { base: state.interner.len() as Interned, interned: vec![], ids: HashMap::new(), output_rounds: OutputRounds::new(), writes: vec![], steps: vec![] }
+    }
+
+    fn intern(&mut self, thing:Internable) -> Interned {
+        if let Some(&id) = self.ids.get(&thing) { return id; }
+        let id = self.base + self.interned.len() as Interned;
+        self.interned.push(thing.clone());
+        self.ids.insert(thing, id);
+        id
+    }
+
+    /// Writes everything the partition wrote into the program's state.
+    pub fn merge(self, state:&mut RuntimeState) {
+        let PartitionOutputs { base, interned, writes, .. } = self;
+        let ids:Vec<Interned> = interned.into_iter().map(|thing| state.interner.internable_to_id(thing)).collect();
+        let map = |id:Interned| if id < base { id } else { ids[(id - base) as usize] };
+        let map_all = |values:Vec<Interned>| -> Vec<Interned> { values.into_iter().map(&map).collect() };
+        for write in writes {
+            match write {
+                PartitionWrite::Bind(output, block, row) => {
+                    let output = Change { e: map(output.e), a: map(output.a), v: map(output.v), ..output };
+                    if let (Some(row), &mut Some(ref mut provenance)) = (row, &mut state.provenance) {
+                        provenance.record(output.e, output.a, output.v, block, &map_all(row), output.count);
+                    }
+                    state.distinct_index.distinct(&output, &mut state.rounds);
+                }
+                PartitionWrite::Commit(output, change_type, block, row) => {
+                    let output = Change { e: map(output.e), a: map(output.a), v: map(output.v), ..output };
+                    if let (Some(row), &mut Some(ref mut provenance)) = (row, &mut state.provenance) {
+                        provenance.record(output.e, output.a, output.v, block, &map_all(row), output.count);
+                    }
+                    state.rounds.commit(output, change_type);
+                }
+                PartitionWrite::Intermediate(full_key, key, value, round, count, negate) => {
+                    state.intermediates.distinct(map_all(full_key), map_all(key), map_all(value), round, count, negate);
+                }
+                PartitionWrite::Aggregate(group, projection, params, round, count, action, output, kind) => {
+                    state.intermediates.aggregate(&mut state.interner, map_all(group), projection, params, round, count, action, map_all(output), kind);
+                }
+                PartitionWrite::Watch(name, values, count) => {
+                    let index = state.watch_indexes.entry(name).or_insert_with(|| WatchIndex::new());
+                    index.insert(map_all(values), count);
+                }
+                PartitionWrite::FunctionError(error) => { state.function_errors.push(error); }
+            }
+        }
+    }
+}
 
 //-------------------------------------------------------------------------
 // Input Fields
@@ -61,6 +225,7 @@ pub struct Solver {
     project_fields: Vec<usize>,
     intermediates: Vec<(Vec<Field>, Vec<Field>, bool)>,
     intermediate_accepts: Vec<(usize, Interned)>,
+    batch_filters: Vec<(usize, Comparison, u32)>,
     aggregates: Vec<(Vec<Field>, Vec<Field>, Vec<Field>, Vec<Field>, AggregateFunction, AggregateFunction, FunctionKind)>,
     interned_remove: Interned,
 }
@@ -81,6 +246,9 @@ impl PartialEq for Solver {
 impl Eq for Solver {}
 
 unsafe impl Send for Solver {}
+// the closures only hold what they were built from, so solvers can be shared by the
+// threads running partitions
+unsafe impl Sync for Solver {}
 impl Clone for Solver {
     fn clone(&self) -> Self {
         Solver {
@@ -96,6 +264,7 @@ impl Clone for Solver {
             binds: self.binds.clone(),
             intermediates: self.intermediates.clone(),
             intermediate_accepts: self.intermediate_accepts.clone(),
+            batch_filters: self.batch_filters.clone(),
             outputs: self.outputs.iter().map(|x| *x).collect(),
             watch_registers: self.watch_registers.clone(),
             project_fields: self.project_fields.clone(),
@@ -112,6 +281,24 @@ impl fmt::Debug for Solver {
     }
 }
 
+// A filter between a register and a number can be run over a whole proposal for that
+// register before any of its values get to the accepts.
+fn batch_filter(interner:&Interner, op:&str, left:Field, right:Field) -> Option<(usize, Comparison, u32)> {
+    let comparison = match Comparison::from_op(op) {
+        Some(comparison) => comparison,
+        None => return None,
+    };
+    let (reg, value, comparison) = match (left, right) {
+        (Field::Register(reg), Field::Value(value)) => (reg, value, comparison),
+        (Field::Value(value), Field::Register(reg)) => (reg, value, comparison.flip()),
+        _ => return None,
+    };
+    match interner.get_value(value) {
+        &Internable::Number(bits) => Some((reg, comparison, bits)),
+        _ => None,
+    }
+}
+
 fn move_or_accept(field:&Field, ix:usize, moves:&mut Vec<(usize, usize)>, accepts:&mut Vec<(usize, Interned)>) {
     if let &Field::Register(reg) = field {
         moves.push((ix, reg));
@@ -136,12 +323,14 @@ impl Solver {
         let mut intermediates = vec![];
         let mut aggregates = vec![];
         let mut intermediate_accepts = vec![];
+        let mut batch_filters = vec![];
 
         let mut output_funcs = HashSet::new();
         let mut to_solve = HashSet::new();
 
         match active_scan {
-            Some(&Constraint::Scan { e, a, v, .. }) => {
+            Some(&Constraint::Scan { e, a, v, .. }) |
+            Some(&Constraint::RangeScan { e, a, v, .. }) => {
                 to_solve.extend(active_scan.unwrap().get_registers());
                 if let Field::Register(ix) = e {
                     moves.push((0, ix));
@@ -188,6 +377,9 @@ impl Solver {
             _ => {}
         }
 
+        let prefixes = shared_prefixes(active_scan, constraints);
+        let prefix = |ix:usize| prefixes.get(&ix).cloned().unwrap_or_default();
+
         for (ix, constraint) in constraints.iter().enumerate() {
             to_solve.extend(constraint.get_registers());
             if active_scan.map_or(false, |x| x == constraint) { continue; }
@@ -198,6 +390,11 @@ impl Solver {
                     accepts.push(make_scan_accept(constraint, ix));
                     get_rounds.push(make_scan_get_rounds(constraint));
                 },
+                &Constraint::RangeScan {..} => {
+                    get_iters.push(make_range_scan_get_iterator(constraint, ix));
+                    accepts.push(make_scan_accept(constraint, ix));
+                    get_rounds.push(make_scan_get_rounds(constraint));
+                },
                 &Constraint::LookupCommit {..} => {
                     get_iters.push(make_scan_get_iterator(constraint, ix));
                     accepts.push(make_scan_accept(constraint, ix));
@@ -207,26 +404,35 @@ impl Solver {
                     get_iters.push(make_lookup_remote_get_iterator(constraint, ix));
                 },
                 &Constraint::AntiScan {..}  => {
-                    get_rounds.push(make_anti_get_rounds(constraint));
+                    get_rounds.push(make_anti_get_rounds(constraint, prefix(ix)));
                 }
                 &Constraint::IntermediateScan {..} => {
-                    get_iters.push(make_intermediate_get_iterator(constraint, ix));
-                    accepts.push(make_intermediate_accept(constraint, ix));
+                    get_iters.push(make_intermediate_get_iterator(constraint, ix, prefix(ix)));
+                    accepts.push(make_intermediate_accept(constraint, ix, prefix(ix)));
                     get_rounds.push(make_intermediate_get_rounds(constraint));
                 }
                 &Constraint::Function {..} => {
-                    get_iters.push(make_function_get_iterator(constraint, ix));
-                    accepts.push(make_function_accept(constraint, ix));
+                    get_iters.push(make_function_get_iterator(constraint, ix, block));
+                    accepts.push(make_function_accept(constraint, ix, block));
                 }
                 &Constraint::MultiFunction {..} => {
                     get_iters.push(make_multi_get_iterator(constraint, ix));
                 }
+                &Constraint::IndexFunction {..} => {
+                    get_iters.push(make_index_function_get_iterator(constraint, ix));
+                }
+                &Constraint::CustomFunction {..} => {
+                    get_iters.push(make_custom_function_get_iterator(constraint, ix, block));
+                }
                 &Constraint::Aggregate {ref output_key, ref group, ref projection, ref params, add, remove, kind, ..} => {
                     aggregates.push((group.clone(), projection.clone(), params.clone(), output_key.clone(), add, remove, kind));
                     output_funcs.insert(OutputFuncs::Aggregate);
                 }
-                &Constraint::Filter {..} => {
+                &Constraint::Filter {ref op, left, right, ..} => {
                     accepts.push(make_filter_accept(constraint, ix));
+                    if let Some(filter) = batch_filter(interner, op, left, right) {
+                        batch_filters.push(filter);
+                    }
                 }
                 &Constraint::Insert { e,a,v,commit } => {
                     if commit {
@@ -286,10 +492,24 @@ impl Solver {
         // compare.
         let interned_remove = interner.string_id("remove");
 
-        Solver { block, id, moves, input_checks, get_iters, accepts, get_rounds, dynamic_commits, commits, binds, intermediates, intermediate_accepts, outputs, watch_registers, project_fields, aggregates, finished_mask, interned_remove }
+        Solver { block, id, moves, input_checks, get_iters, accepts, get_rounds, dynamic_commits, commits, binds, intermediates, intermediate_accepts, batch_filters, outputs, watch_registers, project_fields, aggregates, finished_mask, interned_remove }
+    }
+
+    pub fn register_count(&self) -> usize {
+        self.finished_mask.count_ones() as usize
     }
 
     pub fn run(&self, state:&mut RuntimeState, pool:&mut EstimateIterPool, frame:&mut Frame) {
+        self.run_in(&mut EvalState::Owned(state), pool, frame);
+    }
+
+    /// Runs against the program's state as it's shared with the other partitions running
+    /// on the pool, keeping what it writes in `outputs`.
+    pub fn run_shared(&self, state:&RuntimeState, outputs:&mut PartitionOutputs, pool:&mut EstimateIterPool, frame:&mut Frame) {
+        self.run_in(&mut EvalState::Shared(state, outputs), pool, frame);
+    }
+
+    fn run_in(&self, state:&mut EvalState, pool:&mut EstimateIterPool, frame:&mut Frame) {
         if !self.do_move(state, frame) { return; }
         if frame.row.solved_fields != self.finished_mask {
             self.solve_variables(state, pool, frame, 0);
@@ -301,6 +521,15 @@ impl Solver {
     }
 
     pub fn run_intermediate(&self, state:&mut RuntimeState, pool:&mut EstimateIterPool, frame:&mut Frame) {
+        self.run_intermediate_in(&mut EvalState::Owned(state), pool, frame);
+    }
+
+    /// See `run_shared`.
+    pub fn run_intermediate_shared(&self, state:&RuntimeState, outputs:&mut PartitionOutputs, pool:&mut EstimateIterPool, frame:&mut Frame) {
+        self.run_intermediate_in(&mut EvalState::Shared(state, outputs), pool, frame);
+    }
+
+    fn run_intermediate_in(&self, state:&mut EvalState, pool:&mut EstimateIterPool, frame:&mut Frame) {
         if !self.do_intermediate_move(frame) { return }
         for accept in self.accepts.iter() {
             let res = (*accept)(state, frame, usize::MAX);
@@ -316,6 +545,7 @@ impl Solver {
     }
 
     pub fn run_remote(&self, state:&mut RuntimeState, pool:&mut EstimateIterPool, frame:&mut Frame) {
+        let state = &mut EvalState::Owned(state);
         if !self.do_remote_move(frame) { return }
         for accept in self.accepts.iter() {
             let res = (*accept)(state, frame, usize::MAX);
@@ -330,7 +560,7 @@ impl Solver {
         }
     }
 
-    pub fn do_move(&self, state: &mut RuntimeState, frame:&mut Frame) -> bool {
+    pub fn do_move(&self, state: &mut EvalState, frame:&mut Frame) -> bool {
         if self.moves.len() > 0 {
             let change = frame.input.expect("running solver without an input!");
             for &(from, to) in self.moves.iter() {
@@ -398,9 +628,9 @@ impl Solver {
         true
     }
 
-    pub fn clear_rounds(&self, state:&mut RuntimeState, frame: &mut Frame) -> bool {
+    pub fn clear_rounds(&self, state:&mut EvalState, frame: &mut Frame) -> bool {
         {
-            let ref mut output_rounds = state.output_rounds;
+            let output_rounds = state.output_rounds();
             output_rounds.clear();
             if let Some(ref change) = frame.input {
                 output_rounds.output_rounds.push((change.round, change.count));
@@ -413,14 +643,14 @@ impl Solver {
         }
         for get in self.get_rounds.iter() {
             (*get)(state, frame);
-            if state.output_rounds.get_output_rounds().len() == 0 {
+            if state.output_rounds().get_output_rounds().len() == 0 {
                 return false;
             }
         }
         true
     }
 
-    pub fn solve_variables(&self, state:&mut RuntimeState, pool:&mut EstimateIterPool, frame:&mut Frame, ix:usize) {
+    pub fn solve_variables(&self, state:&mut EvalState, pool:&mut EstimateIterPool, frame:&mut Frame, ix:usize) {
         let active_constraint = {
             let iterator = pool.get(ix);
             for func in self.get_iters.iter() {
@@ -429,9 +659,12 @@ impl Solver {
                     return;
                 }
             }
+            self.filter_proposal(state, iterator);
             iterator.constraint
         };
         'main: while { pool.get(ix).iter.next(&mut frame.row, ix) } {
+            frame.count_step(active_constraint);
+            if frame.over_budget() { break; }
             for accept in self.accepts.iter() {
                 if !(*accept)(state, frame, active_constraint) {
                     continue 'main;
@@ -456,8 +689,45 @@ impl Solver {
         iterator.reset();
     }
 
+    /// Runs the batch filters over a big proposal for a single register so only values
+    /// that can pass them are tried. Anything that isn't a number is left to the accepts.
+    fn filter_proposal(&self, state:&EvalState, iterator:&mut EstimateIter) {
+        if self.batch_filters.is_empty() || iterator.pass_through { return; }
+        if iterator.estimate < BATCH_FILTER_MIN || iterator.estimate == usize::MAX { return; }
+        let reg = match iterator.iter {
+            OutputingIter::Single(reg, _) => reg,
+            _ => return,
+        };
+        if !self.batch_filters.iter().any(|&(filtered, _, _)| filtered == reg) { return; }
+        let proposed:Vec<Interned> = match iterator.iter {
+            OutputingIter::Single(_, ref mut iter) => iter.collect(),
+            _ => unreachable!(),
+        };
+        let mut column = vec![];
+        let mut numeric = vec![];
+        state.number_column(&proposed, &mut column, &mut numeric);
+        let mut mask = vec![!0; numeric.len()];
+        for &(filtered, comparison, constant) in self.batch_filters.iter() {
+            if filtered == reg {
+                batch::retain(&column, comparison, constant, &mut mask);
+            }
+        }
+        // walking the mask in order keeps the proposal's order
+        let mut values = Vec::with_capacity(proposed.len());
+        for (word, (&passed, &number)) in mask.iter().zip(numeric.iter()).enumerate() {
+            let mut kept = passed | !number;
+            while kept != 0 {
+                let ix = word * 64 + kept.trailing_zeros() as usize;
+                if ix >= proposed.len() { break; }
+                values.push(proposed[ix]);
+                kept &= kept - 1;
+            }
+        }
+        iterator.iter = OutputingIter::Single(reg, OutputingIter::make_ptr(Box::new(values.into_iter())));
+    }
+
     #[inline(always)]
-    pub fn do_output(&self, state:&mut RuntimeState, frame:&mut Frame) {
+    pub fn do_output(&self, state:&mut EvalState, frame:&mut Frame) {
         for output in self.outputs.iter() {
             output(self, state, frame);
         }
@@ -487,7 +757,7 @@ pub fn make_scan_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIteratorFun
         let resolved_a = frame.resolve(&a);
         let resolved_v = frame.resolve(&v);
 
-        if state.index.propose(iter, resolved_e, resolved_a, resolved_v) {
+        if state.runtime().index.propose(iter, resolved_e, resolved_a, resolved_v) {
             iter.constraint = ix;
             match iter.iter {
                 OutputingIter::Single(ref mut output, _) => {
@@ -508,6 +778,7 @@ pub fn make_scan_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIteratorFun
 pub fn make_scan_accept(scan:&Constraint, me:usize) -> Arc<AcceptFunc>  {
     let (e,a,v,register_mask) = match scan {
         &Constraint::Scan { e, a, v, register_mask} => (e,a,v,register_mask),
+        &Constraint::RangeScan { e, a, v, register_mask, ..} => (e,a,v,register_mask),
         &Constraint::LookupCommit { e, a, v, register_mask} => (e,a,v,register_mask),
         _ => unreachable!()
     };
@@ -520,21 +791,80 @@ pub fn make_scan_accept(scan:&Constraint, me:usize) -> Arc<AcceptFunc>  {
         let resolved_e = frame.resolve(&e);
         let resolved_a = frame.resolve(&a);
         let resolved_v = frame.resolve(&v);
-        state.index.check(resolved_e, resolved_a, resolved_v)
+        state.runtime().index.check(resolved_e, resolved_a, resolved_v)
     })
 }
 
 pub fn make_scan_get_rounds(scan:&Constraint) -> Arc<GetRoundsFunc> {
     let (e,a,v,_) = match scan {
         &Constraint::Scan { e, a, v, register_mask} => (e,a,v,register_mask),
+        &Constraint::RangeScan { e, a, v, register_mask, ..} => (e,a,v,register_mask),
         _ => unreachable!()
     };
     Arc::new(move |state, frame| {
             let resolved_e = frame.resolve(&e);
             let resolved_a = frame.resolve(&a);
             let resolved_v = frame.resolve(&v);
-            let iter = state.distinct_index.iter(resolved_e, resolved_a, resolved_v);
-            state.output_rounds.compute_output_rounds(iter);
+            let (output_rounds, distinct_index, _) = state.rounds_and_indexes();
+            output_rounds.compute_output_rounds(distinct_index.iter(resolved_e, resolved_a, resolved_v));
+    })
+}
+
+//-------------------------------------------------------------------------
+// RangeScan
+//-------------------------------------------------------------------------
+
+fn resolve_bound(state:&EvalState, frame:&Frame, bound:&Option<(Field, bool)>) -> Option<Bound<f32>> {
+    match bound {
+        &Some((ref field, inclusive)) => {
+            let value = state.get_value(frame.resolve(field));
+            match value {
+                &Internable::Number(_) => {
+                    let num = Internable::to_number(value);
+                    if inclusive { Some(Bound::Included(num)) } else { Some(Bound::Excluded(num)) }
+                }
+                _ => None,
+            }
+        }
+        &None => Some(Bound::Unbounded),
+    }
+}
+
+pub fn make_range_scan_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIteratorFunc> {
+    let (e,a,v,low,high,register_mask) = match scan {
+        &Constraint::RangeScan { e, a, v, low, high, register_mask} => (e,a,v,low,high,register_mask),
+        _ => unreachable!()
+    };
+    let plain = make_scan_get_iterator(&make_scan(e, a, v), ix);
+    Arc::new(move |iter, state, frame| {
+        if check_bits(frame.row.solved_fields, register_mask) {
+            return true;
+        }
+
+        let resolved_e = frame.resolve(&e);
+        let resolved_a = frame.resolve(&a);
+        let resolved_v = frame.resolve(&v);
+
+        // the range only helps when we'd otherwise be walking every value of `a`
+        if resolved_e == 0 && resolved_a != 0 && resolved_v == 0 {
+            if let (Some(low), Some(high)) = (resolve_bound(state, frame, &low), resolve_bound(state, frame, &high)) {
+                match state.runtime().index.propose_range(iter, resolved_a, low, high) {
+                    Some(true) => {
+                        iter.constraint = ix;
+                        match iter.iter {
+                            OutputingIter::Single(ref mut output, _) => {
+                                if let Field::Register(reg) = e { *output = reg; }
+                            }
+                            _ => {}
+                        }
+                        return true;
+                    }
+                    Some(false) => { return true; }
+                    None => {}
+                }
+            }
+        }
+        (*plain)(iter, state, frame)
     })
 }
 
@@ -551,8 +881,8 @@ pub fn make_commit_lookup_get_rounds(scan:&Constraint) -> Arc<GetRoundsFunc> {
             let resolved_e = frame.resolve(&e);
             let resolved_a = frame.resolve(&a);
             let resolved_v = frame.resolve(&v);
-            if !state.distinct_index.is_commit(resolved_e, resolved_a, resolved_v) {
-                state.output_rounds.clear();
+            if !state.runtime().distinct_index.is_commit(resolved_e, resolved_a, resolved_v) {
+                state.output_rounds().clear();
             }
     })
 }
@@ -581,7 +911,7 @@ pub fn make_lookup_remote_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIt
             return true;
         }
 
-        if iter.is_better(state.remote_index.len()) {
+        if iter.is_better(state.runtime().remote_index.len()) {
             let resolved_e = frame.resolve(&e);
             let resolved_a = frame.resolve(&a);
             let resolved_v = frame.resolve(&v);
@@ -591,7 +921,7 @@ pub fn make_lookup_remote_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIt
             let resolved_to = frame.resolve(&to);
             // @FIXME: why does this need to be collected into a vector first? If the iter is
             // passed directly, it's always empty.
-            let remote_iter = state.remote_index.index.iter().filter(|x| {
+            let remote_iter = state.runtime().remote_index.index.iter().filter(|x| {
                (resolved_e == 0 || x.e == resolved_e) &&
                (resolved_a == 0 || x.a == resolved_a) &&
                (resolved_v == 0 || x.v == resolved_v) &&
@@ -602,7 +932,7 @@ pub fn make_lookup_remote_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIt
             }).map(|x| {
                 x.extract(&fields)
             }).collect::<Vec<_>>();
-            iter.estimate = state.remote_index.len();
+            iter.estimate = state.runtime().remote_index.len();
             iter.iter = OutputingIter::Multi(outputs.clone(), OutputingIter::make_multi_ptr(Box::new(remote_iter.into_iter())));
             iter.constraint = ix;
         }
@@ -620,12 +950,14 @@ pub fn make_filter_accept(scan:&Constraint, me:usize) -> Arc<AcceptFunc>  {
         _ => unreachable!()
     };
     Arc::new(move |state, frame, cur_constraint| {
-        if cur_constraint == me || !has_any_bits(param_mask, frame.row.solving_for) {
+        // a filter between two constants, like the ones a prepared query's placeholders
+        // leave behind, is checked every time since no variable ever wakes it
+        if cur_constraint == me || (param_mask != 0 && !has_any_bits(param_mask, frame.row.solving_for)) {
             return true;
         }
         if check_bits(frame.row.solved_fields, param_mask) {
-            let resolved_left = state.interner.get_value(frame.resolve(&left));
-            let resolved_right = state.interner.get_value(frame.resolve(&right));
+            let resolved_left = state.get_value(frame.resolve(&left));
+            let resolved_right = state.get_value(frame.resolve(&right));
             func(resolved_left, resolved_right)
         } else {
             true
@@ -637,9 +969,20 @@ pub fn make_filter_accept(scan:&Constraint, me:usize) -> Arc<AcceptFunc>  {
 // Function
 //-------------------------------------------------------------------------
 
-pub fn make_function_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIteratorFunc> {
-    let (func, output, params, param_mask, output_mask) = match scan {
-        &Constraint::Function {ref func, ref output, ref params, param_mask, output_mask, ..} => (*func, output.clone(), params.clone(), param_mask, output_mask),
+// A function that couldn't make anything of its arguments is reported rather than
+// just not matching.
+fn check_function_error(block:Interned, constraint:usize, op:&str, params:&Vec<Field>, state:&mut EvalState, frame:&Frame) {
+    // a value that's going away doesn't need to be told it was wrong again
+    if frame.input.map_or(false, |input| input.count < 0) { return; }
+    let values = params.iter().map(|param| state.get_value(frame.resolve(param)).clone()).collect();
+    if let Some(error) = function_error(block, constraint, op, values) {
+        state.function_error(error);
+    }
+}
+
+pub fn make_function_get_iterator(scan:&Constraint, ix: usize, block:Interned) -> Arc<GetIteratorFunc> {
+    let (op, func, output, params, param_mask, output_mask) = match scan {
+        &Constraint::Function {ref op, ref func, ref output, ref params, param_mask, output_mask} => (op.to_string(), *func, output.clone(), params.clone(), param_mask, output_mask),
         _ => unreachable!()
     };
     Arc::new(move |iter, state, frame| {
@@ -648,14 +991,14 @@ pub fn make_function_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIterato
             let result = {
                 let mut resolved = vec![];
                 for param in params.iter() {
-                    resolved.push(state.interner.get_value(frame.resolve(param)));
+                    resolved.push(state.get_value(frame.resolve(param)));
                 }
                 func(resolved)
             };
             match result {
                 Some(v) => {
                     if iter.is_better(1) {
-                        let id = state.interner.internable_to_id(v);
+                        let id = state.intern(v);
                         let reg = if let Field::Register(reg) = output {
                             reg
                         } else {
@@ -667,7 +1010,10 @@ pub fn make_function_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIterato
                     }
                     true
                 }
-                _ => false,
+                _ => {
+                    check_function_error(block, ix, &op, &params, state, frame);
+                    false
+                }
             }
         } else {
             true
@@ -675,9 +1021,9 @@ pub fn make_function_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIterato
     })
 }
 
-pub fn make_function_accept(scan:&Constraint, me:usize) -> Arc<AcceptFunc>  {
-    let (func, output, params, param_mask, output_mask) = match scan {
-        &Constraint::Function {ref func, ref output, ref params, param_mask, output_mask, ..} => (*func, output.clone(), params.clone(), param_mask, output_mask),
+pub fn make_function_accept(scan:&Constraint, me:usize, block:Interned) -> Arc<AcceptFunc>  {
+    let (op, func, output, params, param_mask, output_mask) = match scan {
+        &Constraint::Function {ref op, ref func, ref output, ref params, param_mask, output_mask} => (op.to_string(), *func, output.clone(), params.clone(), param_mask, output_mask),
         _ => unreachable!()
     };
     Arc::new(move |state, frame, cur_constraint| {
@@ -697,16 +1043,19 @@ pub fn make_function_accept(scan:&Constraint, me:usize) -> Arc<AcceptFunc>  {
             let result = {
                 let mut resolved = vec![];
                 for param in params.iter() {
-                    resolved.push(state.interner.get_value(frame.resolve(param)));
+                    resolved.push(state.get_value(frame.resolve(param)));
                 }
                 func(resolved)
             };
             match result {
                 Some(v) => {
-                    let id = state.interner.internable_to_id(v);
+                    let id = state.intern(v);
                     id == frame.resolve(&output)
                 }
-                _ => false,
+                _ => {
+                    check_function_error(block, me, &op, &params, state, frame);
+                    false
+                }
             }
     })
 }
@@ -726,7 +1075,7 @@ pub fn make_multi_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIteratorFu
             let result = {
                 let mut resolved = vec![];
                 for param in params.iter() {
-                    resolved.push(state.interner.get_value(frame.resolve(param)));
+                    resolved.push(state.get_value(frame.resolve(param)));
                 }
                 func(resolved)
             };
@@ -742,7 +1091,7 @@ pub fn make_multi_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIteratorFu
                             }
                         }).collect();
                         let result_vec = result_values.drain(..).map(|mut row| {
-                            row.drain(..).map(|field| state.interner.internable_to_id(field)).collect()
+                            row.drain(..).map(|field| state.intern(field)).collect()
                         }).collect::<Vec<Vec<Interned>>>();
                         iter.constraint = ix;
                         iter.estimate = estimate;
@@ -758,6 +1107,92 @@ pub fn make_multi_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIteratorFu
     })
 }
 
+//-------------------------------------------------------------------------
+// IndexFunction
+//-------------------------------------------------------------------------
+
+pub fn make_index_function_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIteratorFunc> {
+    let (func, output_fields, params, param_mask, output_mask) = match scan {
+        &Constraint::IndexFunction {ref func, outputs:ref output_fields, ref params, param_mask, output_mask, ..} => (*func, output_fields.clone(), params.clone(), param_mask, output_mask),
+        _ => unreachable!()
+    };
+    Arc::new(move |iter, state, frame| {
+        let solved = frame.row.solved_fields;
+        if check_bits(solved, param_mask) && !check_bits(solved, output_mask) {
+            let resolved = params.iter().map(|param| frame.resolve(param)).collect();
+            let result = match *state {
+                EvalState::Owned(ref mut state) => func(&mut state.index, &mut state.interner, resolved),
+                // partitions calling index functions always run on the program's thread
+                EvalState::Shared(..) => unreachable!(),
+            };
+            match result {
+                Some(result_vec) => {
+                    let estimate = result_vec.len();
+                    if iter.is_better(estimate) {
+                        let outputs = output_fields.iter().map(|x| {
+                            if let &Field::Register(reg) = x {
+                                reg
+                            } else {
+                                panic!("Non-register index function output")
+                            }
+                        }).collect();
+                        iter.constraint = ix;
+                        iter.estimate = estimate;
+                        iter.iter = OutputingIter::Multi(outputs, OutputingIter::make_multi_ptr(Box::new(result_vec.into_iter())));
+                    }
+                    true
+                }
+                _ => false,
+            }
+        } else {
+            true
+        }
+    })
+}
+
+//-------------------------------------------------------------------------
+// CustomFunction
+//-------------------------------------------------------------------------
+
+pub fn make_custom_function_get_iterator(scan:&Constraint, ix: usize, block:Interned) -> Arc<GetIteratorFunc> {
+    let (op, func, output_fields, params, param_mask, output_mask) = match scan {
+        &Constraint::CustomFunction {ref op, ref func, outputs:ref output_fields, ref params, param_mask, output_mask} => (op.to_string(), func.clone(), output_fields.clone(), params.clone(), param_mask, output_mask),
+        _ => unreachable!()
+    };
+    Arc::new(move |iter, state, frame| {
+        let solved = frame.row.solved_fields;
+        if check_bits(solved, param_mask) && !check_bits(solved, output_mask) {
+            let resolved:Vec<Internable> = params.iter().map(|param| state.get_value(frame.resolve(param)).clone()).collect();
+            let rows = (**func)(&resolved);
+            if rows.is_empty() { return false; }
+            // a row of the wrong length is the embedder's bug, reported like a type error
+            // rather than bound
+            if let Some(row) = rows.iter().find(|row| row.len() != output_fields.len()) {
+                if !frame.input.map_or(false, |input| input.count < 0) {
+                    state.function_error(output_count_error(block, ix, &op, resolved, row.len(), output_fields.len()));
+                }
+                return false;
+            }
+            if iter.is_better(rows.len()) {
+                let outputs = output_fields.iter().map(|x| {
+                    if let &Field::Register(reg) = x {
+                        reg
+                    } else {
+                        panic!("Non-register custom function output")
+                    }
+                }).collect();
+                let result_rows:Vec<Vec<Interned>> = rows.into_iter().map(|row| row.into_iter().map(|field| state.intern(field)).collect()).collect();
+                iter.constraint = ix;
+                iter.estimate = result_rows.len();
+                iter.iter = OutputingIter::Multi(outputs, OutputingIter::make_multi_ptr(Box::new(result_rows.into_iter())));
+            }
+            true
+        } else {
+            true
+        }
+    })
+}
+
 pub fn make_multi_accept(_:&Constraint, _:usize) -> Arc<AcceptFunc>  {
     // let (e,a,v,register_mask) = match scan {
     //     &Constraint::Scan { e, a, v, register_mask} => (e,a,v,register_mask),
@@ -769,11 +1204,61 @@ pub fn make_multi_accept(_:&Constraint, _:usize) -> Arc<AcceptFunc>  {
     })
 }
 
+//-------------------------------------------------------------------------
+// Shared prefixes
+//-------------------------------------------------------------------------
+
+// Sub-block scans in the same block whose keys start with the same inputs, like a `not`
+// and an `if` that both look at the same person, share their prefix probes. Whichever
+// of them is checked first for a row asks the intermediate index whether any key starts
+// with those inputs and the rest reuse its answer, which is all it takes to turn them
+// all away when nothing in either sub-block matched. Each scan gets the longest prefix
+// it shares with another; scans that share nothing don't probe.
+fn shared_prefixes(active_scan:Option<&Constraint>, constraints:&Vec<Constraint>) -> HashMap<usize, Vec<Field>> {
+    let mut inputs = vec![];
+    for (ix, constraint) in constraints.iter().enumerate() {
+        if active_scan.map_or(false, |x| x == constraint) { continue; }
+        match constraint {
+            &Constraint::AntiScan { ref key, .. } |
+            &Constraint::IntermediateScan { ref key, .. } if key.len() > 1 => inputs.push((ix, &key[1..])),
+            _ => {}
+        }
+    }
+    let mut prefixes = HashMap::new();
+    for &(ix, fields) in inputs.iter() {
+        let shared = inputs.iter()
+            .filter(|&&(other, _)| other != ix)
+            .map(|&(_, other)| fields.iter().zip(other.iter()).take_while(|&(a, b)| a == b).count())
+            .max().unwrap_or(0);
+        if shared > 0 {
+            prefixes.insert(ix, fields[..shared].to_vec());
+        }
+    }
+    prefixes
+}
+
+// Whether any intermediate key starts with the resolved prefix. The answer is kept on
+// the frame until the index's keys change or the prefix resolves to something else.
+fn prefix_present(state:&EvalState, frame:&mut Frame, prefix:&[Field]) -> bool {
+    if prefix.is_empty() { return true; }
+    let generation = state.runtime().intermediates.generation();
+    if let Some(ref probe) = frame.prefix_probe {
+        if probe.generation == generation && probe.values.len() == prefix.len() &&
+           prefix.iter().zip(probe.values.iter()).all(|(field, value)| frame.resolve(field) == *value) {
+            return probe.present;
+        }
+    }
+    let values:Vec<Interned> = prefix.iter().map(|field| frame.resolve(field)).collect();
+    let present = state.runtime().intermediates.has_prefix(&values);
+    frame.prefix_probe = Some(PrefixProbe { generation, values, present });
+    present
+}
+
 //-------------------------------------------------------------------------
 // IntermediateScan
 //-------------------------------------------------------------------------
 
-pub fn make_intermediate_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIteratorFunc> {
+pub fn make_intermediate_get_iterator(scan:&Constraint, ix: usize, prefix:Vec<Field>) -> Arc<GetIteratorFunc> {
     let (key, value, register_mask, output_mask) = match scan {
         &Constraint::IntermediateScan { ref key, ref value, register_mask, output_mask, .. } => (key.clone(), value.clone(), register_mask, output_mask),
         _ => unreachable!()
@@ -786,6 +1271,12 @@ pub fn make_intermediate_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIte
                 return true;
             }
 
+        if !prefix_present(state, frame, &prefix) {
+            iter.iter = OutputingIter::Empty;
+            iter.estimate = 0;
+            iter.constraint = ix;
+            return true;
+        }
         let resolved = key.iter().map(|param| frame.resolve(param)).collect();
         let outputs = value.iter().map(|x| {
             if let &Field::Register(reg) = x {
@@ -794,14 +1285,14 @@ pub fn make_intermediate_get_iterator(scan:&Constraint, ix: usize) -> Arc<GetIte
                 panic!("Non-register intermediate scan output")
             }
         }).collect();
-        if state.intermediates.propose(&mut iter, resolved, outputs) {
+        if state.runtime().intermediates.propose(&mut iter, resolved, outputs) {
             iter.constraint = ix;
         }
         true
     })
 }
 
-pub fn make_intermediate_accept(scan:&Constraint, me:usize) -> Arc<AcceptFunc>  {
+pub fn make_intermediate_accept(scan:&Constraint, me:usize, prefix:Vec<Field>) -> Arc<AcceptFunc>  {
     let (key, value, register_mask, output_mask) = match scan {
         &Constraint::IntermediateScan { ref key, ref value, register_mask, output_mask, .. } => (key.clone(), value.clone(), register_mask, output_mask),
         _ => unreachable!()
@@ -814,10 +1305,11 @@ pub fn make_intermediate_accept(scan:&Constraint, me:usize) -> Arc<AcceptFunc>
                 return true;
             }
 
+        if !prefix_present(state, frame, &prefix) { return false; }
         let resolved = key.iter().map(|param| frame.resolve(param)).collect();
         let resolved_value = value.iter().map(|param| frame.resolve(param)).collect();
 
-        state.intermediates.check(&resolved, &resolved_value)
+        state.runtime().intermediates.check(&resolved, &resolved_value)
     })
 }
 
@@ -829,7 +1321,8 @@ pub fn make_intermediate_get_rounds(scan:&Constraint) -> Arc<GetRoundsFunc> {
     Arc::new(move |state, frame| {
         let resolved:Vec<Interned> = key.iter().map(|v| frame.resolve(v)).collect();
         let resolved_value:Vec<Interned> = value.iter().map(|v| frame.resolve(v)).collect();
-        state.output_rounds.compute_output_rounds(state.intermediates.distinct_iter(&resolved, &resolved_value));
+        let (output_rounds, _, intermediates) = state.rounds_and_indexes();
+        output_rounds.compute_output_rounds(intermediates.distinct_iter(&resolved, &resolved_value));
     })
 }
 
@@ -837,14 +1330,20 @@ pub fn make_intermediate_get_rounds(scan:&Constraint) -> Arc<GetRoundsFunc> {
 // AntiScan
 //-------------------------------------------------------------------------
 
-pub fn make_anti_get_rounds(scan:&Constraint) -> Arc<GetRoundsFunc> {
+pub fn make_anti_get_rounds(scan:&Constraint, prefix:Vec<Field>) -> Arc<GetRoundsFunc> {
     let key = match scan {
         &Constraint::AntiScan { ref key, .. } => key.clone(),
         _ => unreachable!()
     };
+    let nothing = vec![];
     Arc::new(move |state, frame| {
+        if !prefix_present(state, frame, &prefix) {
+            state.output_rounds().compute_anti_output_rounds(DistinctIter::new(&nothing));
+            return;
+        }
         let resolved:Vec<Interned> = key.iter().map(|v| frame.resolve(v)).collect();
-        state.output_rounds.compute_anti_output_rounds(state.intermediates.distinct_iter(&resolved, &vec![]));
+        let (output_rounds, _, intermediates) = state.rounds_and_indexes();
+        output_rounds.compute_anti_output_rounds(intermediates.distinct_iter(&resolved, &vec![]));
     })
 }
 
@@ -852,87 +1351,199 @@ pub fn make_anti_get_rounds(scan:&Constraint) -> Arc<GetRoundsFunc> {
 // Outputs
 //-------------------------------------------------------------------------
 
-pub fn do_bind(me: &Solver, state:&mut RuntimeState, frame: &mut Frame) {
-    for &(round, count) in state.output_rounds.get_output_rounds().iter() {
+#[inline]
+fn record_provenance(me: &Solver, provenance: &mut Option<Provenance>, frame: &Frame, output: &Change) {
+    if let &mut Some(ref mut provenance) = provenance {
+        let width = me.finished_mask.count_ones() as usize;
+        provenance.record(output.e, output.a, output.v, me.block, &frame.row.fields[..width], output.count);
+    }
+}
+
+// The row behind a partition's output, kept for provenance if the program records it.
+#[inline]
+fn provenance_row(me: &Solver, state: &RuntimeState, frame: &Frame) -> Option<Vec<Interned>> {
+    if state.provenance.is_none() { return None; }
+    let width = me.finished_mask.count_ones() as usize;
+    Some(frame.row.fields[..width].to_vec())
+}
+
+pub fn do_bind(me: &Solver, state:&mut EvalState, frame: &mut Frame) {
+    match *state {
+        EvalState::Owned(ref mut state) => {
+            if !state.block_distinct.is_empty() && state.block_distinct.contains_key(&me.block) {
+                return do_distinct_bind(me, state, frame);
+            }
+            for &(round, count) in state.output_rounds.get_output_rounds().iter() {
+                for &(e, a, v) in me.binds.iter() {
+                    let output = Change { e: frame.resolve(&e), a: frame.resolve(&a), v:frame.resolve(&v), n: 0, round: round + 1, transaction: 0, count, };
+                    frame.counters.inserts += 1;
+                    record_provenance(me, &mut state.provenance, frame, &output);
+                    state.distinct_index.distinct(&output, &mut state.rounds);
+                }
+            }
+        }
+        EvalState::Shared(state, ref mut outputs) => {
+            for &(round, count) in outputs.output_rounds.get_output_rounds().iter() {
+                for &(e, a, v) in me.binds.iter() {
+                    let output = Change { e: frame.resolve(&e), a: frame.resolve(&a), v:frame.resolve(&v), n: 0, round: round + 1, transaction: 0, count, };
+                    frame.counters.inserts += 1;
+                    outputs.writes.push(PartitionWrite::Bind(output, me.block, provenance_row(me, state, frame)));
+                }
+            }
+        }
+    }
+}
+
+// Binds for a block in distinct mode: each row's outputs are counted against the block's
+// own derivations first and only changes to whether the block derives them at all go on.
+fn do_distinct_bind(me: &Solver, state:&mut RuntimeState, frame: &mut Frame) {
+    let RuntimeState { ref output_rounds, ref mut block_distinct, ref mut distinct_index, ref mut rounds, ref mut provenance, .. } = *state;
+    let outputs = block_distinct.get_mut(&me.block).unwrap();
+    for &(round, count) in output_rounds.get_output_rounds().iter() {
         for &(e, a, v) in me.binds.iter() {
-            let output = Change { e: frame.resolve(&e), a: frame.resolve(&a), v:frame.resolve(&v), n: 0, round: round + 1, transaction: 0, count, };
-            frame.counters.inserts += 1;
-            state.distinct_index.distinct(&output, &mut state.rounds);
+            let derived = Change { e: frame.resolve(&e), a: frame.resolve(&a), v:frame.resolve(&v), n: 0, round: round + 1, transaction: 0, count, };
+            let mut changed = vec![];
+            outputs.derive(derived.e, derived.a, derived.v, derived.round, count, |round, delta| changed.push(derived.with_round_count(round, delta)));
+            for output in changed {
+                frame.counters.inserts += 1;
+                record_provenance(me, provenance, frame, &output);
+                distinct_index.distinct(&output, rounds);
+            }
         }
     }
 }
 
-pub fn do_commit(me: &Solver, state: &mut RuntimeState, frame: &mut Frame) {
-    let n = (me.block * 10000) as u32;
-    for &(_, count) in state.output_rounds.get_output_rounds().iter() {
-        for &(e, a, v, change_type) in me.commits.iter() {
-            let correct_count = if change_type == ChangeType::Remove { count * -1 } else { count };
-            let output = Change { e: frame.resolve(&e), a: frame.resolve(&a), v:frame.resolve(&v), n, round:0, transaction: 0, count:correct_count };
-            frame.counters.inserts += 1;
-            state.rounds.commit(output, change_type)
+// Commits carry the id of the block that made them in `n`.
+pub fn do_commit(me: &Solver, state: &mut EvalState, frame: &mut Frame) {
+    let n = me.block;
+    match *state {
+        EvalState::Owned(ref mut state) => {
+            for &(_, count) in state.output_rounds.get_output_rounds().iter() {
+                for &(e, a, v, change_type) in me.commits.iter() {
+                    let correct_count = if change_type == ChangeType::Remove { count * -1 } else { count };
+                    let output = Change { e: frame.resolve(&e), a: frame.resolve(&a), v:frame.resolve(&v), n, round:0, transaction: 0, count:correct_count };
+                    frame.counters.inserts += 1;
+                    if change_type == ChangeType::Insert { record_provenance(me, &mut state.provenance, frame, &output); }
+                    state.rounds.commit(output, change_type)
+                }
+            }
+        }
+        EvalState::Shared(state, ref mut outputs) => {
+            for &(_, count) in outputs.output_rounds.get_output_rounds().iter() {
+                for &(e, a, v, change_type) in me.commits.iter() {
+                    let correct_count = if change_type == ChangeType::Remove { count * -1 } else { count };
+                    let output = Change { e: frame.resolve(&e), a: frame.resolve(&a), v:frame.resolve(&v), n, round:0, transaction: 0, count:correct_count };
+                    frame.counters.inserts += 1;
+                    let row = if change_type == ChangeType::Insert { provenance_row(me, state, frame) } else { None };
+                    outputs.writes.push(PartitionWrite::Commit(output, change_type, me.block, row));
+                }
+            }
         }
     }
 }
 
-pub fn do_dynamic_commit(me: &Solver, state: &mut RuntimeState, frame: &mut Frame) {
-    let n = (me.block * 10000) as u32;
-    for &(_, count) in state.output_rounds.get_output_rounds().iter() {
-        for &(e, a, v, _type) in me.dynamic_commits.iter() {
-            let (correct_count, change_type) = if frame.resolve(&_type) == me.interned_remove { (count * -1, ChangeType::Remove) } else { (count, ChangeType::Insert) };
-            let output = Change { e: frame.resolve(&e), a: frame.resolve(&a), v:frame.resolve(&v), n, round:0, transaction: 0, count:correct_count };
-            frame.counters.inserts += 1;
-            state.rounds.commit(output, change_type)
+pub fn do_dynamic_commit(me: &Solver, state: &mut EvalState, frame: &mut Frame) {
+    let n = me.block;
+    match *state {
+        EvalState::Owned(ref mut state) => {
+            for &(_, count) in state.output_rounds.get_output_rounds().iter() {
+                for &(e, a, v, _type) in me.dynamic_commits.iter() {
+                    let (correct_count, change_type) = if frame.resolve(&_type) == me.interned_remove { (count * -1, ChangeType::Remove) } else { (count, ChangeType::Insert) };
+                    let output = Change { e: frame.resolve(&e), a: frame.resolve(&a), v:frame.resolve(&v), n, round:0, transaction: 0, count:correct_count };
+                    frame.counters.inserts += 1;
+                    state.rounds.commit(output, change_type)
+                }
+            }
+        }
+        EvalState::Shared(_, ref mut outputs) => {
+            for &(_, count) in outputs.output_rounds.get_output_rounds().iter() {
+                for &(e, a, v, _type) in me.dynamic_commits.iter() {
+                    let (correct_count, change_type) = if frame.resolve(&_type) == me.interned_remove { (count * -1, ChangeType::Remove) } else { (count, ChangeType::Insert) };
+                    let output = Change { e: frame.resolve(&e), a: frame.resolve(&a), v:frame.resolve(&v), n, round:0, transaction: 0, count:correct_count };
+                    frame.counters.inserts += 1;
+                    outputs.writes.push(PartitionWrite::Commit(output, change_type, me.block, None));
+                }
+            }
         }
     }
 }
 
-pub fn do_project(me: &Solver, _:&mut RuntimeState, frame: &mut Frame) {
+pub fn do_project(me: &Solver, _:&mut EvalState, frame: &mut Frame) {
     for from in me.project_fields.iter().cloned() {
         let value = frame.get_register(from);
         frame.results.push(value);
     }
+    frame.count_row();
 }
 
-pub fn do_intermediate_insert(me: &Solver, state: &mut RuntimeState, frame: &mut Frame) {
+pub fn do_intermediate_insert(me: &Solver, state: &mut EvalState, frame: &mut Frame) {
     for &(ref key, ref value, negate) in me.intermediates.iter() {
         let resolved:Vec<Interned> = key.iter().map(|v| frame.resolve(v)).collect();
         let resolved_value:Vec<Interned> = value.iter().map(|v| frame.resolve(v)).collect();
         let mut full_key = resolved.clone();
         full_key.extend(resolved_value.iter());
-        for &(round, count) in state.output_rounds.get_output_rounds().iter() {
-            frame.counters.inserts += 1;
-            state.intermediates.distinct(full_key.clone(), resolved.clone(), resolved_value.clone(), round, count, negate);
+        match *state {
+            EvalState::Owned(ref mut state) => {
+                for &(round, count) in state.output_rounds.get_output_rounds().iter() {
+                    frame.counters.inserts += 1;
+                    state.intermediates.distinct(full_key.clone(), resolved.clone(), resolved_value.clone(), round, count, negate);
+                }
+            }
+            EvalState::Shared(_, ref mut outputs) => {
+                for &(round, count) in outputs.output_rounds.get_output_rounds().iter() {
+                    frame.counters.inserts += 1;
+                    outputs.writes.push(PartitionWrite::Intermediate(full_key.clone(), resolved.clone(), resolved_value.clone(), round, count, negate));
+                }
+            }
         }
     }
 }
 
-pub fn do_aggregate(me: &Solver, state: &mut RuntimeState, frame: &mut Frame) {
+pub fn do_aggregate(me: &Solver, state: &mut EvalState, frame: &mut Frame) {
     for &(ref group, ref projection, ref params, ref output_key, add, remove, kind) in me.aggregates.iter() {
         let resolved_group:Vec<Interned> = group.iter().map(|v| frame.resolve(v)).collect();
         let resolved_projection = if kind == FunctionKind::Sort || kind == FunctionKind::NeedleSort || kind == FunctionKind::SortedSum {
-            projection.iter().map(|v| state.interner.get_value(frame.resolve(v)).clone()).collect()
+            projection.iter().map(|v| state.get_value(frame.resolve(v)).clone()).collect()
         } else {
             vec![]
         };
-        let resolved_params:Vec<Internable> = { params.iter().map(|v| state.interner.get_value(frame.resolve(v)).clone()).collect() };
+        let resolved_params:Vec<Internable> = { params.iter().map(|v| state.get_value(frame.resolve(v)).clone()).collect() };
         let resolved_output:Vec<Interned> = output_key.iter().map(|v| frame.resolve(v)).collect();
-        for &(round, count) in state.output_rounds.get_output_rounds().iter() {
-            let action = if count < 0 { remove } else { add };
-            frame.counters.inserts += 1;
-            state.intermediates.aggregate(&mut state.interner, resolved_group.clone(), resolved_projection.clone(), resolved_params.clone(), round, count, action, resolved_output.clone(), kind);
+        match *state {
+            EvalState::Owned(ref mut state) => {
+                for &(round, count) in state.output_rounds.get_output_rounds().iter() {
+                    let action = if count < 0 { remove } else { add };
+                    frame.counters.inserts += 1;
+                    state.intermediates.aggregate(&mut state.interner, resolved_group.clone(), resolved_projection.clone(), resolved_params.clone(), round, count, action, resolved_output.clone(), kind);
+                }
+            }
+            EvalState::Shared(_, ref mut outputs) => {
+                for &(round, count) in outputs.output_rounds.get_output_rounds().iter() {
+                    let action = if count < 0 { remove } else { add };
+                    frame.counters.inserts += 1;
+                    outputs.writes.push(PartitionWrite::Aggregate(resolved_group.clone(), resolved_projection.clone(), resolved_params.clone(), round, count, action, resolved_output.clone(), kind));
+                }
+            }
         }
     }
 }
 
-pub fn do_watch(me: &Solver, state: &mut RuntimeState, frame: &mut Frame) {
+pub fn do_watch(me: &Solver, state: &mut EvalState, frame: &mut Frame) {
     for &(ref name, ref registers) in me.watch_registers.iter() {
         let resolved:Vec<Interned> = registers.iter().map(|x| frame.resolve(x)).collect();
         let mut total = 0;
-        for &(_, count) in state.output_rounds.get_output_rounds().iter() {
+        for &(_, count) in state.output_rounds().get_output_rounds().iter() {
             total += count;
         }
         frame.counters.inserts += 1;
-        let index = state.watch_indexes.entry(name.to_string()).or_insert_with(|| WatchIndex::new());
-        index.insert(resolved, total);
+        match *state {
+            EvalState::Owned(ref mut state) => {
+                let index = state.watch_indexes.entry(name.to_string()).or_insert_with(|| WatchIndex::new());
+                index.insert(resolved, total);
+            }
+            EvalState::Shared(_, ref mut outputs) => {
+                outputs.writes.push(PartitionWrite::Watch(name.to_string(), resolved, total));
+            }
+        }
     }
 }
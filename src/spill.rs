@@ -0,0 +1,168 @@
+//-------------------------------------------------------------------------
+// Spilling
+//-------------------------------------------------------------------------
+
+extern crate bincode;
+
+use ops::{Change, Interned};
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Write, BufReader, BufWriter};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// A round that piles up more than `threshold` pending changes, e.g. the output of a
+// join far bigger than expected, gets sorted and written out as a run in `dir`. When
+// the round comes up its runs are merged back a batch at a time, summing the counts of
+// matching changes, so only about `threshold` changes of it are in memory at once.
+
+static NEXT_SPILL_RUN:AtomicUsize = AtomicUsize::new(0);
+
+struct SpillRun {
+    path: PathBuf,
+    reader: BufReader<File>,
+    head: Option<Change>,
+    remaining: usize,
+}
+
+impl SpillRun {
+    fn open(path:PathBuf, len:usize) -> Result<SpillRun, String> {
+        let file = File::open(&path).map_err(|why| format!("Unable to read back {}: {}", path.display(), why))?;
+        let mut run = SpillRun { path, reader: BufReader::new(file), head: None, remaining: len };
+        run.advance()?;
+        Ok(run)
+    }
+
+    // we know how many changes we wrote, so anything short of that is an error, not the end
+    fn advance(&mut self) -> Result<(), String> {
+        self.head = None;
+        if self.remaining == 0 { return Ok(()); }
+        self.remaining -= 1;
+        let change = bincode::deserialize_from(&mut self.reader, bincode::Infinite).map_err(|why| format!("Unable to read back {}: {}", self.path.display(), why))?;
+        self.head = Some(change);
+        Ok(())
+    }
+
+    fn key(&self) -> Option<(Interned, Interned, Interned)> {
+        self.head.map(|change| (change.e, change.a, change.v))
+    }
+}
+
+pub struct Spill {
+    pub threshold: usize,
+    dir: PathBuf,
+    runs: HashMap<usize, Vec<(PathBuf, usize)>>,
+    merging: Vec<SpillRun>,
+    /// The first I/O error since the last transaction stopped, after which nothing more
+    /// is spilled.
+    error: Option<String>,
+}
+
+impl Spill {
+    pub fn new(threshold:usize, dir:PathBuf) -> Spill {
+        Spill { threshold: cmp::max(threshold, 1), dir, runs: HashMap::new(), merging: vec![], error: None }
+    }
+
+    fn fail(&mut self, message:String) {
+        if self.error.is_none() { self.error = Some(message); }
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// Why spilling failed, if it did, which also lets rounds spill again.
+    pub fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+
+    /// Sorts `changes` and writes them out as another run of the round. Returns false if
+    /// the run couldn't be written, in which case the round just stays in memory.
+    pub fn write_run(&mut self, round:usize, mut changes:Vec<Change>) -> bool {
+        changes.sort_by_key(|change| (change.e, change.a, change.v));
+        let path = self.dir.join(format!("eve-spill-{}-{}.run", process::id(), NEXT_SPILL_RUN.fetch_add(1, Ordering::SeqCst)));
+        let written = File::create(&path).map_err(|why| why.to_string()).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            for change in changes.iter() {
+                bincode::serialize_into(&mut writer, change, bincode::Infinite).map_err(|why| why.to_string())?;
+            }
+            writer.flush().map_err(|why| why.to_string())
+        });
+        if let Err(why) = written {
+            fs::remove_file(&path).ok();
+            self.fail(format!("Unable to spill a round to {}: {}", path.display(), why));
+            return false;
+        }
+        self.runs.entry(round).or_insert_with(|| vec![]).push((path, changes.len()));
+        true
+    }
+
+    pub fn has_runs(&self, round:usize) -> bool {
+        self.runs.contains_key(&round)
+    }
+
+    pub fn is_merging(&self) -> bool {
+        self.merging.len() > 0
+    }
+
+    pub fn start_merge(&mut self, round:usize) {
+        for (path, len) in self.runs.remove(&round).unwrap_or_else(|| vec![]) {
+            match SpillRun::open(path.clone(), len) {
+                Ok(run) => self.merging.push(run),
+                Err(message) => {
+                    fs::remove_file(path).ok();
+                    self.fail(message);
+                }
+            }
+        }
+    }
+
+    /// Fills `batch` with up to `threshold` changes merged from the runs being read back.
+    pub fn merge_batch(&mut self, batch:&mut Vec<Change>) {
+        let mut errors = vec![];
+        while batch.len() < self.threshold {
+            let key = match self.merging.iter().filter_map(|run| run.key()).min() {
+                Some(key) => key,
+                None => break,
+            };
+            // a run never repeats a key, so each one holds at most one change for it
+            let mut merged:Option<Change> = None;
+            for run in self.merging.iter_mut() {
+                if run.key() != Some(key) { continue; }
+                let change = run.head.unwrap();
+                match merged {
+                    Some(ref mut merged) => merged.count += change.count,
+                    None => merged = Some(change),
+                }
+                if let Err(message) = run.advance() { errors.push(message); }
+            }
+            match merged {
+                Some(change) if change.count != 0 => batch.push(change),
+                _ => {}
+            }
+        }
+        for message in errors { self.fail(message); }
+        let (done, merging):(Vec<SpillRun>, Vec<SpillRun>) = self.merging.drain(..).partition(|run| run.head.is_none());
+        self.merging = merging;
+        for run in done {
+            fs::remove_file(run.path).ok();
+        }
+    }
+
+    pub fn discard(&mut self) {
+        for (_, runs) in self.runs.drain() {
+            for (path, _) in runs { fs::remove_file(path).ok(); }
+        }
+        for run in self.merging.drain(..) {
+            fs::remove_file(run.path).ok();
+        }
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        self.discard();
+    }
+}
@@ -0,0 +1,156 @@
+//-------------------------------------------------------------------------
+// Tutorial
+//-------------------------------------------------------------------------
+
+// `eve tutorial` walks through a series of lessons in the terminal. A lesson is a
+// markdown file like any other Eve program: its prose explains something, its ```eve
+// fences set up whatever data the lesson needs, and its ```check fences are blocks that
+// project at least one row once the lesson has been done. The engine ignores ```check
+// fences, so lessons still run as normal programs. The user types blocks in, they're
+// added to the lesson's program, and the lesson is passed when every check matches.
+
+use ops::{Program, CodeTransaction, View};
+use compiler::{compile_string, eve_files, CompileOptions};
+use std::fs::File;
+use std::io::{self, Read};
+
+const CHECK_FENCE:&'static str = "```check";
+
+pub struct Lesson {
+    pub title: String,
+    /// The lesson as it should be shown, without its checks.
+    pub text: String,
+    pub source: String,
+    pub checks: Vec<String>,
+}
+
+impl Lesson {
+    pub fn parse(source:&str) -> Lesson {
+        let mut text = String::new();
+        let mut checks = vec![];
+        let mut check:Option<String> = None;
+        for line in source.lines() {
+            let trimmed = line.trim();
+            match check.take() {
+                Some(code) => {
+                    if trimmed.starts_with("```") { checks.push(code); }
+                    else { check = Some(code + line + "\n"); }
+                }
+                None if trimmed == CHECK_FENCE => check = Some(String::new()),
+                None => { text.push_str(line); text.push('\n'); }
+            }
+        }
+        let title = source.lines().find(|line| line.starts_with("# "))
+                          .map_or("Untitled".to_string(), |line| line[2..].trim().to_string());
+        Lesson { title, text: text.trim().to_string(), source: source.to_string(), checks }
+    }
+}
+
+pub fn builtin_lessons() -> Vec<Lesson> {
+    vec![
+        Lesson::parse(include_str!("../tutorial/01-records.md")),
+        Lesson::parse(include_str!("../tutorial/02-search.md")),
+        Lesson::parse(include_str!("../tutorial/03-bind.md")),
+    ]
+}
+
+/// The lessons in `path`, in the order of their file names.
+pub fn lessons_in(path:&str) -> io::Result<Vec<Lesson>> {
+    let mut paths = eve_files(path);
+    paths.sort();
+    let mut lessons = vec![];
+    for path in paths {
+        let mut source = String::new();
+        File::open(&path)?.read_to_string(&mut source)?;
+        lessons.push(Lesson::parse(&source));
+    }
+    Ok(lessons)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Submission {
+    /// The code didn't compile, the errors have been printed.
+    Errors(usize),
+    /// The code was added but not every check matches yet.
+    Added,
+    Passed,
+}
+
+pub struct Tutorial {
+    lessons: Vec<Lesson>,
+    current: usize,
+    program: Program,
+    checks: Vec<View>,
+    submissions: usize,
+}
+
+impl Tutorial {
+    pub fn new(lessons:Vec<Lesson>) -> Tutorial {
+        let mut tutorial = Tutorial { lessons, current: 0, program: Program::new("tutorial"), checks: vec![], submissions: 0 };
+        tutorial.start_lesson();
+        tutorial
+    }
+
+    pub fn lesson(&self) -> Option<&Lesson> {
+        self.lessons.get(self.current)
+    }
+
+    /// Which lesson this is and how many there are, counting from 1.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.current + 1, self.lessons.len())
+    }
+
+    pub fn finished(&self) -> bool {
+        self.current >= self.lessons.len()
+    }
+
+    // Every lesson gets a program of its own, with the lesson's setup blocks and checks.
+    fn start_lesson(&mut self) {
+        self.program = Program::new("tutorial");
+        self.checks.clear();
+        self.submissions = 0;
+        let (source, count) = match self.lessons.get(self.current) {
+            Some(lesson) => {
+                let mut source = lesson.source.to_string();
+                for (ix, check) in lesson.checks.iter().enumerate() {
+                    source.push_str(&format!("\n```eve\neve:block name: \"tutorial/check|{}\"\n{}```\n", ix, check));
+                }
+                (source, lesson.checks.len())
+            }
+            None => return,
+        };
+        let (blocks, _) = compile_string(&mut self.program.state.interner, &source, "lesson", &CompileOptions::default());
+        let mut txn = CodeTransaction::new();
+        txn.exec(&mut self.program, blocks, vec![]);
+        for ix in 0..count {
+            match self.program.view(&format!("tutorial/check|{}", ix)) {
+                Ok(view) => self.checks.push(view),
+                Err(why) => panic!("Lesson `{}` has a broken check: {}", self.lessons[self.current].title, why),
+            }
+        }
+    }
+
+    /// Whether every check of the current lesson matches.
+    pub fn passed(&mut self) -> bool {
+        self.checks.iter_mut().all(|check| check.rows().len() > 0)
+    }
+
+    /// Adds the blocks in `code` to the current lesson's program.
+    pub fn submit(&mut self, code:&str) -> Submission {
+        self.submissions += 1;
+        let path = format!("tutorial|{}", self.submissions);
+        let (blocks, errors) = compile_string(&mut self.program.state.interner, code, &path, &CompileOptions::default());
+        if errors > 0 {
+            return Submission::Errors(errors);
+        }
+        let mut txn = CodeTransaction::new();
+        txn.exec(&mut self.program, blocks, vec![]);
+        if self.passed() { Submission::Passed } else { Submission::Added }
+    }
+
+    /// Moves on to the next lesson, whether or not this one was passed.
+    pub fn next_lesson(&mut self) {
+        self.current += 1;
+        self.start_lesson();
+    }
+}
@@ -0,0 +1,133 @@
+use super::super::ops::{Internable, RawChange};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+//-------------------------------------------------------------------------
+// Circuit breakers
+//-------------------------------------------------------------------------
+
+// A watcher that talks to something outside of Eve keeps a circuit per endpoint. Once an
+// endpoint has failed `threshold` requests in a row its circuit opens and requests to it
+// are refused without being tried. After the cooldown a single probe is let through
+// (half-open): if it works the circuit closes again, otherwise it stays open for another
+// cooldown. Every circuit is published as `[#service/circuit endpoint state]` so
+// programs can see which services are down and react to it.
+
+pub const DEFAULT_FAILURE_THRESHOLD:u32 = 5;
+pub const DEFAULT_COOLDOWN_MS:u64 = 30_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half-open",
+        }
+    }
+}
+
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    circuits: HashMap<String, Circuit>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold:u32, cooldown:Duration) -> CircuitBreaker {
+        CircuitBreaker { threshold: if threshold == 0 { 1 } else { threshold }, cooldown, circuits: HashMap::new() }
+    }
+
+    pub fn state(&self, endpoint:&str) -> CircuitState {
+        self.circuits.get(endpoint).map(|circuit| circuit.state).unwrap_or(CircuitState::Closed)
+    }
+
+    /// Whether a request to `endpoint` should go out at `now`. An open circuit whose
+    /// cooldown has passed moves to half-open and lets exactly one probe through; any
+    /// state change is pushed onto `changes` as #service/circuit facts.
+    pub fn allow(&mut self, endpoint:&str, now:Instant, changes:&mut Vec<RawChange>) -> bool {
+        let cooldown = self.cooldown;
+        if !self.circuits.contains_key(endpoint) {
+            self.circuits.insert(endpoint.to_string(), Circuit { state: CircuitState::Closed, failures: 0, opened_at: None, probing: false });
+            circuit_fact(changes, endpoint, CircuitState::Closed);
+        }
+        let circuit = self.circuits.get_mut(endpoint).unwrap();
+        match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen => {
+                if circuit.probing { return false; }
+                circuit.probing = true;
+                true
+            }
+            CircuitState::Open => {
+                match circuit.opened_at {
+                    Some(opened_at) if now.duration_since(opened_at) < cooldown => false,
+                    _ => {
+                        transition(circuit, endpoint, CircuitState::HalfOpen, changes);
+                        circuit.probing = true;
+                        true
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn record_success(&mut self, endpoint:&str, changes:&mut Vec<RawChange>) {
+        if let Some(circuit) = self.circuits.get_mut(endpoint) {
+            circuit.failures = 0;
+            circuit.probing = false;
+            circuit.opened_at = None;
+            transition(circuit, endpoint, CircuitState::Closed, changes);
+        }
+    }
+
+    pub fn record_failure(&mut self, endpoint:&str, now:Instant, changes:&mut Vec<RawChange>) {
+        let threshold = self.threshold;
+        if let Some(circuit) = self.circuits.get_mut(endpoint) {
+            circuit.failures = circuit.failures.saturating_add(1);
+            circuit.probing = false;
+            // a failed probe reopens the circuit straight away
+            if circuit.state == CircuitState::HalfOpen || circuit.failures >= threshold {
+                circuit.opened_at = Some(now);
+                transition(circuit, endpoint, CircuitState::Open, changes);
+            }
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker::new(DEFAULT_FAILURE_THRESHOLD, Duration::from_millis(DEFAULT_COOLDOWN_MS))
+    }
+}
+
+fn transition(circuit:&mut Circuit, endpoint:&str, state:CircuitState, changes:&mut Vec<RawChange>) {
+    if circuit.state == state { return; }
+    changes.push(RawChange {e: circuit_id(endpoint), a: Internable::String("state".to_string()), v: Internable::String(circuit.state.name().to_string()), n: Internable::String("service/circuit".to_string()), count: -1});
+    changes.push(RawChange {e: circuit_id(endpoint), a: Internable::String("state".to_string()), v: Internable::String(state.name().to_string()), n: Internable::String("service/circuit".to_string()), count: 1});
+    circuit.state = state;
+}
+
+fn circuit_id(endpoint:&str) -> Internable {
+    Internable::String(format!("service/circuit/{}", endpoint))
+}
+
+fn circuit_fact(changes:&mut Vec<RawChange>, endpoint:&str, state:CircuitState) {
+    let id = circuit_id(endpoint);
+    changes.push(RawChange {e: id.clone(), a: Internable::String("tag".to_string()), v: Internable::String("service/circuit".to_string()), n: Internable::String("service/circuit".to_string()), count: 1});
+    changes.push(RawChange {e: id.clone(), a: Internable::String("endpoint".to_string()), v: Internable::String(endpoint.to_string()), n: Internable::String("service/circuit".to_string()), count: 1});
+    changes.push(RawChange {e: id, a: Internable::String("state".to_string()), v: Internable::String(state.name().to_string()), n: Internable::String("service/circuit".to_string()), count: 1});
+}
@@ -192,7 +192,10 @@ impl Watcher for CompilerWatcher {
                     ("remote-output", &[id, block, ..]) => {
                         self.constraints.remove(&(block, id)).expect(format!("Unable to remove nonexistent constraint: '{:?}'", interner.get_value(id)).as_str());
                         self.constraint_to_params.remove(&(block, id)).unwrap();
-                        self.block_to_constraints.get_mut(&block).unwrap().remove_item(&id);
+                        let block_constraints = self.block_to_constraints.get_mut(&block).unwrap();
+                        if let Some(ix) = block_constraints.iter().position(|x| *x == id) {
+                            block_constraints.remove(ix);
+                        }
                         damaged_blocks.insert(block);
                         damaged_constraints.insert(id);
                     },
@@ -304,8 +307,8 @@ impl Watcher for CompilerWatcher {
                 }
 
                 comp.constraints.extend(constraints.iter().map(|&id| self.constraints.get(&(*block, id)).unwrap()).cloned());
-                comp.finalize();
-                added_blocks.extend(compilation_to_blocks(comp, interner, "compiler_watcher", "", false));
+                comp.finalize(interner);
+                added_blocks.extend(compilation_to_blocks(comp, interner, "compiler_watcher", ""));
             }
         }
 
@@ -5,24 +5,120 @@ use std::fs::File;
 use std::io::Error;
 use std::io::prelude::*;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
 use super::Watcher;
+use super::retry::RetryPolicy;
+use super::circuit::CircuitBreaker;
 
 pub struct FileWatcher {
     name: String,
     outgoing: Sender<RunLoopMessage>,
+    breaker: Option<Arc<Mutex<CircuitBreaker>>>,
 }
 
 impl FileWatcher {
     pub fn new(outgoing: Sender<RunLoopMessage>) -> FileWatcher {
-        FileWatcher { name: "file".to_string(), outgoing }
+        FileWatcher { name: "file".to_string(), outgoing, breaker: None }
     }
+
+    // A missing file usually turns up sooner or later, so reads and writes are only
+    // refused after repeated failures when a breaker is asked for.
+    pub fn with_breaker(outgoing: Sender<RunLoopMessage>, breaker: CircuitBreaker) -> FileWatcher {
+        FileWatcher { name: "file".to_string(), outgoing, breaker: Some(Arc::new(Mutex::new(breaker))) }
+    }
+}
+
+// Circuit changes are sent while the breaker is still locked, so however many threads are
+// running requests, the program gets each path's transitions in the order they happened.
+fn update_circuit<F>(breaker: &Mutex<CircuitBreaker>, outgoing: &Sender<RunLoopMessage>, update: F) -> bool
+    where F: FnOnce(&mut CircuitBreaker, &mut Vec<RawChange>) -> bool {
+    let mut breaker = match breaker.lock() {
+        Ok(breaker) => breaker,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let mut changes = vec![];
+    let result = update(&mut breaker, &mut changes);
+    if changes.len() > 0 {
+        outgoing.send(RunLoopMessage::Transaction(changes)).ok();
+    }
+    result
 }
 
-fn file_error(changes: &mut Vec<RawChange>, id: String, why: Error) {
+fn file_error(changes: &mut Vec<RawChange>, id: String, why: String, attempts: u32) {
     let err_id = Internable::String(format!("file/error/{}", id));
     changes.push(RawChange {e: err_id.clone(), a: Internable::String("tag".to_string()), v: Internable::String("file/error".to_string()), n: Internable::String("file/error".to_string()), count: 1});
-    changes.push(RawChange {e: err_id.clone(), a: Internable::String("message".to_string()), v: Internable::String(why.to_string()), n: Internable::String("file/error".to_string()), count: 1});
+    changes.push(RawChange {e: err_id.clone(), a: Internable::String("message".to_string()), v: Internable::String(why), n: Internable::String("file/error".to_string()), count: 1});
     changes.push(RawChange {e: err_id.clone(), a: Internable::String("file".to_string()), v: Internable::String(id.to_string()), n: Internable::String("file/error".to_string()), count: 1});
+    changes.push(RawChange {e: err_id.clone(), a: Internable::String("attempts".to_string()), v: Internable::from_number(attempts as f32), n: Internable::String("file/error".to_string()), count: 1});
+}
+
+fn read_file(path: &str) -> Result<String, Error> {
+    let mut file = File::open(Path::new(path))?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+fn write_file(path: &str, contents: &str) -> Result<(), Error> {
+    let mut file = File::create(Path::new(path))?;
+    file.write_all(contents.as_bytes())
+}
+
+struct FileRequest {
+    kind: String,
+    record_id: String,
+    path: String,
+    contents: String,
+}
+
+impl FileRequest {
+    fn perform(&self) -> Result<Vec<RawChange>, String> {
+        let id = Internable::String(format!("file/{}/change/{}", self.kind, self.record_id));
+        let mut changes = vec![];
+        match &self.kind[..] {
+            "read" => {
+                let contents = read_file(&self.path).map_err(|why| why.to_string())?;
+                changes.push(RawChange {e: id.clone(), a: Internable::String("tag".to_string()), v: Internable::String("file/read/change".to_string()), n: Internable::String("file/read".to_string()), count: 1});
+                changes.push(RawChange {e: id.clone(), a: Internable::String("file".to_string()), v: Internable::String(self.record_id.to_string()), n: Internable::String("file/read".to_string()), count: 1});
+                changes.push(RawChange {e: id.clone(), a: Internable::String("contents".to_string()), v: Internable::String(contents), n: Internable::String("file/read".to_string()), count: 1});
+            },
+            "write" => {
+                write_file(&self.path, &self.contents).map_err(|why| why.to_string())?;
+            },
+            _ => {},
+        }
+        Ok(changes)
+    }
+
+    // Each path gets its own circuit, so a missing or unwritable file stops being hit
+    // after enough failures without holding up requests for any other file.
+    fn run(&self, policy: &RetryPolicy, breaker: Option<&Mutex<CircuitBreaker>>, outgoing: &Sender<RunLoopMessage>) -> Vec<RawChange> {
+        let mut changes = vec![];
+        if let Some(breaker) = breaker {
+            let now = Instant::now();
+            if !update_circuit(breaker, outgoing, |breaker, circuit| breaker.allow(&self.path, now, circuit)) {
+                file_error(&mut changes, self.record_id.to_string(), format!("Circuit open for '{}'", self.path), 0);
+                return changes;
+            }
+        }
+        match policy.run(|_| self.perform()) {
+            Ok(mut result) => {
+                if let Some(breaker) = breaker {
+                    update_circuit(breaker, outgoing, |breaker, circuit| { breaker.record_success(&self.path, circuit); true });
+                }
+                changes.append(&mut result);
+            }
+            Err(failure) => {
+                if let Some(breaker) = breaker {
+                    update_circuit(breaker, outgoing, |breaker, circuit| { breaker.record_failure(&self.path, Instant::now(), circuit); true });
+                }
+                file_error(&mut changes, self.record_id.to_string(), failure.message, failure.attempts);
+            }
+        }
+        changes
+    }
 }
 
 impl Watcher for FileWatcher {
@@ -32,45 +128,35 @@ impl Watcher for FileWatcher {
     fn set_name(&mut self, name: &str) {
         self.name = name.to_string();
     }
+    // Rows are ("read", record, path) or ("write", record, path, contents), optionally
+    // followed by the attempts, backoff and delay of the record's retry policy.
     fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff) {
         for add in diff.adds {
             let kind = Internable::to_string(interner.get_value(add[0]));
-            let record_id = Internable::to_string(interner.get_value(add[1]));
-            let id = Internable::String(format!("file/{}/change/{}", kind, record_id));
-            let raw_path = Internable::to_string(interner.get_value(add[2]));
-            let path = Path::new(&raw_path[..]);
-            let mut changes = vec![];
-            match &kind[..] {
-                "read" => {
-                    match File::open(&path) {
-                        Err(why) => file_error(&mut changes, record_id, why),
-                        Ok(mut file) => {
-                            let mut contents = String::new();
-                            match file.read_to_string(&mut contents) {
-                                Err(why) => file_error(&mut changes, record_id, why),
-                                Ok(_) => {
-                                    changes.push(RawChange {e: id.clone(), a: Internable::String("tag".to_string()), v: Internable::String("file/read/change".to_string()), n: Internable::String("file/read".to_string()), count: 1});
-                                    changes.push(RawChange {e: id.clone(), a: Internable::String("file".to_string()), v: Internable::String(record_id.to_string()), n: Internable::String("file/read".to_string()), count: 1});
-                                    changes.push(RawChange {e: id.clone(), a: Internable::String("contents".to_string()), v: Internable::String(contents.clone()), n: Internable::String("file/read".to_string()), count: 1});
-                                },
-                            }
-                        },
-                    };
-                },
-                "write" => {
-                    let contents = Internable::to_string(interner.get_value(add[3]));
-                    match File::create(&path) {
-                        Err(why) => file_error(&mut changes, record_id, why),
-                        Ok(ref mut file) => {
-                            match file.write_all(contents.as_bytes()) {
-                                Err(why) => file_error(&mut changes, record_id, why),
-                                Ok(_) => (),
-                            };
-                        },
-                    };
-                },
-                _ => {},
+            let policy_start = if kind == "write" { 4 } else { 3 };
+            let request = FileRequest {
+                record_id: Internable::to_string(interner.get_value(add[1])),
+                path: Internable::to_string(interner.get_value(add[2])),
+                contents: if kind == "write" { Internable::to_string(interner.get_value(add[3])) } else { "".to_string() },
+                kind,
+            };
+            let policy = if add.len() > policy_start {
+                let values:Vec<&Internable> = add[policy_start..].iter().map(|value| interner.get_value(*value)).collect();
+                RetryPolicy::from_values(&values)
+            } else {
+                RetryPolicy::once()
+            };
+            if policy.attempts > 1 {
+                // retries sleep between attempts, which can't hold up the run loop
+                let outgoing = self.outgoing.clone();
+                let breaker = self.breaker.clone();
+                thread::spawn(move || {
+                    let changes = request.run(&policy, breaker.as_ref().map(|breaker| &**breaker), &outgoing);
+                    outgoing.send(RunLoopMessage::Transaction(changes)).ok();
+                });
+                continue;
             }
+            let changes = request.run(&policy, self.breaker.as_ref().map(|breaker| &**breaker), &self.outgoing);
             match self.outgoing.send(RunLoopMessage::Transaction(changes)) {
                 Err(_) => break,
                 _ => (),
@@ -0,0 +1,209 @@
+extern crate notify;
+
+use super::super::indexes::{WatchDiff};
+use super::super::ops::{Interned, Internable, Interner, RawChange, RunLoopMessage};
+use self::notify::{watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher as Notifier};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use super::Watcher;
+
+//-------------------------------------------------------------------------
+// File Watch Watcher
+//-------------------------------------------------------------------------
+
+// Tells programs when files change. A `[#file/watch path]` watches a file, or a directory
+// and everything under it, and every change adds a `[#file/changed file path kind]`, where
+// `file` is the watch record and `kind` is "created", "written", "removed" or "renamed".
+// Renames also have the old path as `from`.
+//
+// Made with `with_reload`, the watcher also hot-reloads any .eve or .md file under the
+// program's own sources when it changes, so only the edited blocks get swapped out.
+
+const SOURCE:&'static str = "file/watch";
+const DEBOUNCE_MS:u64 = 500;
+
+fn change(e:&Internable, a:&str, v:Internable) -> RawChange {
+    RawChange { e: e.clone(), a: Internable::String(a.to_string()), v, n: Internable::String(SOURCE.to_string()), count: 1 }
+}
+
+fn path_value(path:&Path) -> Internable {
+    Internable::String(path.to_string_lossy().replace("\\", "/"))
+}
+
+fn watch_error(changes:&mut Vec<RawChange>, record:&Internable, why:String) {
+    let err_id = Internable::Reference(format!("file/watch/error|{}|", Internable::to_string(record)));
+    changes.push(change(&err_id, "tag", Internable::String("file/error".to_string())));
+    changes.push(change(&err_id, "file", record.clone()));
+    changes.push(change(&err_id, "message", Internable::String(why)));
+}
+
+fn is_source(path:&Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("eve") | Some("md") => true,
+        _ => false,
+    }
+}
+
+#[derive(Default)]
+struct Watched {
+    // the records watching each path, keyed by where the path really is
+    records: HashMap<PathBuf, Vec<Internable>>,
+    // the program's own sources, when hot-reloading
+    sources: Vec<PathBuf>,
+}
+
+impl Watched {
+    fn changed(&self, kind:&str, path:&Path, from:Option<&Path>, count:&mut usize) -> Vec<RawChange> {
+        let mut changes = vec![];
+        for (root, records) in self.records.iter() {
+            if !path.starts_with(root) && !from.map_or(false, |from| from.starts_with(root)) { continue; }
+            for record in records.iter() {
+                *count += 1;
+                let id = Internable::Reference(format!("file/changed|{}|", count));
+                changes.push(change(&id, "tag", Internable::String("file/changed".to_string())));
+                changes.push(change(&id, "file", record.clone()));
+                changes.push(change(&id, "path", path_value(path)));
+                changes.push(change(&id, "kind", Internable::String(kind.to_string())));
+                if let Some(from) = from {
+                    changes.push(change(&id, "from", path_value(from)));
+                }
+            }
+        }
+        changes
+    }
+
+    fn dirty(&self, paths:&[&Path]) -> HashSet<PathBuf> {
+        paths.iter().filter(|path| is_source(path) && self.sources.iter().any(|root| path.starts_with(root)))
+                    .map(|path| path.to_path_buf()).collect()
+    }
+}
+
+fn notify_loop(incoming:Receiver<DebouncedEvent>, watched:Arc<Mutex<Watched>>, outgoing:Sender<RunLoopMessage>) {
+    let mut count = 0;
+    for event in incoming.iter() {
+        let (kind, path, from) = match event {
+            DebouncedEvent::Create(path) => ("created", path, None),
+            DebouncedEvent::Write(path) | DebouncedEvent::Chmod(path) => ("written", path, None),
+            DebouncedEvent::Remove(path) => ("removed", path, None),
+            DebouncedEvent::Rename(from, path) => ("renamed", path, Some(from)),
+            DebouncedEvent::Error(why, Some(path)) => {
+                let watched = watched.lock().unwrap();
+                let mut changes = vec![];
+                for (_, records) in watched.records.iter().filter(|&(root, _)| path.starts_with(root)) {
+                    for record in records.iter() {
+                        watch_error(&mut changes, record, why.to_string());
+                    }
+                }
+                if changes.len() > 0 && outgoing.send(RunLoopMessage::Transaction(changes)).is_err() { break; }
+                continue;
+            }
+            _ => continue,
+        };
+        let (changes, dirty) = {
+            let watched = watched.lock().unwrap();
+            let from = from.as_ref().map(|from| from.as_path());
+            let dirty = match from {
+                Some(from) => watched.dirty(&[&path, from]),
+                None => watched.dirty(&[&path]),
+            };
+            (watched.changed(kind, &path, from, &mut count), dirty)
+        };
+        if changes.len() > 0 && outgoing.send(RunLoopMessage::Transaction(changes)).is_err() { break; }
+        if dirty.len() > 0 && outgoing.send(RunLoopMessage::Reload(dirty)).is_err() { break; }
+    }
+}
+
+pub struct FileWatchWatcher {
+    name: String,
+    notifier: Result<RecommendedWatcher, String>,
+    watched: Arc<Mutex<Watched>>,
+    // where each (record, path) row is being watched
+    roots: HashMap<(Interned, Interned), PathBuf>,
+    outgoing: Sender<RunLoopMessage>,
+}
+
+impl FileWatchWatcher {
+    pub fn new(outgoing: Sender<RunLoopMessage>) -> FileWatchWatcher {
+        let (events, incoming) = mpsc::channel();
+        let notifier = watcher(events, Duration::from_millis(DEBOUNCE_MS)).map_err(|why| why.to_string());
+        let watched = Arc::new(Mutex::new(Watched::default()));
+        if notifier.is_ok() {
+            let watched = watched.clone();
+            let outgoing = outgoing.clone();
+            thread::Builder::new().name("file/watch".to_string()).spawn(move || notify_loop(incoming, watched, outgoing)).unwrap();
+        }
+        FileWatchWatcher { name: "file/watch".to_string(), notifier, watched, roots: HashMap::new(), outgoing }
+    }
+
+    /// A watcher that also hot-reloads the program's sources in `paths`, files or
+    /// directories, whenever they change.
+    pub fn with_reload(outgoing: Sender<RunLoopMessage>, paths:&[&str]) -> Result<FileWatchWatcher, String> {
+        let mut watcher = FileWatchWatcher::new(outgoing);
+        for path in paths {
+            let root = watcher.watch(path)?;
+            watcher.watched.lock().unwrap().sources.push(root);
+        }
+        Ok(watcher)
+    }
+
+    fn watch(&mut self, path:&str) -> Result<PathBuf, String> {
+        let root = Path::new(path).canonicalize().map_err(|why| format!("Unable to watch {}: {}", path, why))?;
+        let notifier = self.notifier.as_mut().map_err(|why| format!("Unable to watch files: {}", why))?;
+        notifier.watch(&root, RecursiveMode::Recursive).map_err(|why| format!("Unable to watch {}: {}", path, why))?;
+        Ok(root)
+    }
+
+    // Stops watching the root once nothing is interested in it anymore.
+    fn unwatch(&mut self, root:&Path) {
+        let watched = self.watched.lock().unwrap();
+        if watched.records.contains_key(root) || watched.sources.iter().any(|source| source == root) { return; }
+        if let Ok(ref mut notifier) = self.notifier {
+            notifier.unwatch(root).ok();
+        }
+    }
+}
+
+impl Watcher for FileWatchWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    // Rows are ("watch", record, path).
+    fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff) {
+        let mut changes = vec![];
+        for remove in diff.removes {
+            if let Some(root) = self.roots.remove(&(remove[1], remove[2])) {
+                let record = interner.get_value(remove[1]);
+                {
+                    let mut watched = self.watched.lock().unwrap();
+                    let empty = watched.records.get_mut(&root).map_or(false, |records| {
+                        records.retain(|existing| existing != record);
+                        records.is_empty()
+                    });
+                    if empty { watched.records.remove(&root); }
+                }
+                self.unwatch(&root);
+            }
+        }
+        for add in diff.adds {
+            let record = interner.get_value(add[1]).clone();
+            let path = Internable::to_string(interner.get_value(add[2]));
+            match self.watch(&path) {
+                Ok(root) => {
+                    self.watched.lock().unwrap().records.entry(root.clone()).or_insert_with(|| vec![]).push(record);
+                    self.roots.insert((add[1], add[2]), root);
+                }
+                Err(why) => watch_error(&mut changes, &record, why),
+            }
+        }
+        if changes.len() > 0 {
+            self.outgoing.send(RunLoopMessage::Transaction(changes)).ok();
+        }
+    }
+}
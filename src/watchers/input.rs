@@ -0,0 +1,185 @@
+use super::super::ops::{RawChange, RunLoopMessage};
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+//-------------------------------------------------------------------------
+// Watcher input
+//-------------------------------------------------------------------------
+
+// The way asynchronous sources like timers, sockets and HTTP push facts back into their
+// program. An Input is a cheap, cloneable handle that any thread can push changes into.
+// Pushes are batched, so a burst of small ones goes in as a single transaction, either
+// once `batch_size` changes are waiting or `max_delay` after the first of them arrived.
+// At most `max_in_flight` batches can be waiting on the run loop at a time; past that,
+// `push` blocks until the program catches up and `try_push` hands the changes back.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputConfig {
+    pub batch_size: usize,
+    pub max_delay: Duration,
+    pub max_in_flight: usize,
+}
+
+impl Default for InputConfig {
+    fn default() -> InputConfig {
+        InputConfig { batch_size: 1000, max_delay: Duration::from_millis(10), max_in_flight: 16 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InputError {
+    /// Too many batches are waiting on the program. Carries the changes that weren't taken.
+    Full(Vec<RawChange>),
+    /// The program has stopped.
+    Stopped,
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &InputError::Full(ref changes) => write!(f, "Input is full, {} changes were refused", changes.len()),
+            &InputError::Stopped => write!(f, "The program has stopped"),
+        }
+    }
+}
+
+struct Batches {
+    pending: Vec<RawChange>,
+    // when the first of the pending changes came in
+    since: Option<Instant>,
+    // one receiver per batch the run loop hasn't finished, oldest first
+    in_flight: VecDeque<Receiver<Vec<RawChange>>>,
+    stopped: bool,
+    // behind the lock with everything else, since a Sender can't be shared between threads
+    outgoing: Sender<RunLoopMessage>,
+}
+
+impl Batches {
+    // Forgets about batches the run loop is done with. A paused run loop drops batches
+    // without replying, which counts as done too.
+    fn reap(&mut self) {
+        while let Some(result) = self.in_flight.front().map(|reply| reply.try_recv()) {
+            match result {
+                Err(TryRecvError::Empty) => break,
+                _ => { self.in_flight.pop_front(); }
+            }
+        }
+    }
+}
+
+struct Shared {
+    config: InputConfig,
+    batches: Mutex<Batches>,
+}
+
+impl Shared {
+    fn flush(&self, batches:&mut Batches) -> Result<(), InputError> {
+        if batches.stopped { return Err(InputError::Stopped); }
+        if batches.pending.is_empty() { return Ok(()); }
+        let changes = mem::replace(&mut batches.pending, vec![]);
+        batches.since = None;
+        let (reply, done) = mpsc::channel();
+        if batches.outgoing.send(RunLoopMessage::ReplyTransaction(changes, reply)).is_err() {
+            batches.stopped = true;
+            return Err(InputError::Stopped);
+        }
+        batches.in_flight.push_back(done);
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Input {
+    shared: Arc<Shared>,
+}
+
+impl Input {
+    pub fn new(outgoing:Sender<RunLoopMessage>, config:InputConfig) -> Input {
+        let batches = Batches { pending: vec![], since: None, in_flight: VecDeque::new(), stopped: false, outgoing };
+        let shared = Arc::new(Shared { config, batches: Mutex::new(batches) });
+        let weak = Arc::downgrade(&shared);
+        thread::Builder::new().name("watcher input".to_string()).spawn(move || flush_loop(weak, config.max_delay)).unwrap();
+        Input { shared }
+    }
+
+    /// Queues the changes, waiting for the program to catch up first if it's too far behind.
+    pub fn push(&self, changes:Vec<RawChange>) -> Result<(), InputError> {
+        let mut batches = self.shared.batches.lock().unwrap();
+        batches.reap();
+        while batches.in_flight.len() >= self.shared.config.max_in_flight {
+            let oldest = batches.in_flight.pop_front().unwrap();
+            // the lock isn't held while waiting, so other threads can still flush
+            drop(batches);
+            oldest.recv().ok();
+            batches = self.shared.batches.lock().unwrap();
+            batches.reap();
+        }
+        self.queue(&mut batches, changes)
+    }
+
+    /// Queues the changes unless the program is too far behind to take them right now.
+    pub fn try_push(&self, changes:Vec<RawChange>) -> Result<(), InputError> {
+        let mut batches = self.shared.batches.lock().unwrap();
+        batches.reap();
+        if batches.in_flight.len() >= self.shared.config.max_in_flight {
+            return Err(InputError::Full(changes));
+        }
+        self.queue(&mut batches, changes)
+    }
+
+    /// Sends whatever is waiting without waiting for the batch to fill up.
+    pub fn flush(&self) -> Result<(), InputError> {
+        let mut batches = self.shared.batches.lock().unwrap();
+        self.shared.flush(&mut batches)
+    }
+
+    /// How many batches the program hasn't finished with yet.
+    pub fn in_flight(&self) -> usize {
+        let mut batches = self.shared.batches.lock().unwrap();
+        batches.reap();
+        batches.in_flight.len()
+    }
+
+    fn queue(&self, batches:&mut Batches, changes:Vec<RawChange>) -> Result<(), InputError> {
+        if batches.stopped { return Err(InputError::Stopped); }
+        if changes.is_empty() { return Ok(()); }
+        batches.pending.extend(changes);
+        if batches.since.is_none() { batches.since = Some(Instant::now()); }
+        if batches.pending.len() >= self.shared.config.batch_size {
+            self.shared.flush(batches)?;
+        }
+        Ok(())
+    }
+}
+
+// Sends batches that have waited long enough, until every handle is gone.
+fn flush_loop(shared:Weak<Shared>, max_delay:Duration) {
+    loop {
+        thread::sleep(max_delay);
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let mut batches = shared.batches.lock().unwrap();
+        if batches.since.map_or(false, |since| since.elapsed() >= max_delay) {
+            if shared.flush(&mut batches).is_err() { return; }
+        }
+    }
+}
+
+impl Drop for Shared {
+    // whatever is still waiting when the last handle goes away is sent, not lost
+    fn drop(&mut self) {
+        if let Ok(mut batches) = self.batches.lock() {
+            let changes = mem::replace(&mut batches.pending, vec![]);
+            if !batches.stopped && changes.len() > 0 {
+                batches.outgoing.send(RunLoopMessage::Transaction(changes)).ok();
+            }
+        }
+    }
+}
@@ -1,13 +1,54 @@
 use indexes::{WatchDiff};
-use ops::{Interner};
+use ops::{Interner, RunLoopMessage};
+use std::sync::mpsc::Sender;
 
+// Watchers are how programs talk to the outside world. A program hands each watcher the
+// rows its `watch` blocks add and remove, and the watcher sends whatever it learns back
+// in as transactions. Anything implementing this can be attached to a program, before it
+// runs with `Program::attach_watcher` or while it's running with `RunLoop::attach_watcher`.
 pub trait Watcher {
     fn get_name(& self) -> String;
     fn set_name(&mut self, &str);
+    // Called once the watcher is attached, before it sees any diffs, with where to report
+    // what goes wrong from then on.
+    fn setup(&mut self, _errors:WatcherErrors) {}
     fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff);
+    // Called when the watcher is detached or its program stops, to close whatever it has
+    // open. It won't see any more diffs.
+    fn teardown(&mut self) {}
+    // Watchers that have to see a transaction's diff before this one does, e.g. the
+    // one writing to a database before the one sending notifications about it.
+    fn dependencies(&self) -> Vec<String> { vec![] }
 }
 
+/// Where a watcher reports errors. Each one shows up in the program as
+/// `@system [#eve/error kind: "watcher" watcher message]`.
+#[derive(Clone)]
+pub struct WatcherErrors {
+    watcher: String,
+    outgoing: Sender<RunLoopMessage>,
+}
+
+impl WatcherErrors {
+    pub fn new(watcher:&str, outgoing:Sender<RunLoopMessage>) -> WatcherErrors {
+        WatcherErrors { watcher: watcher.to_string(), outgoing }
+    }
+
+    pub fn report(&self, message:&str) {
+        self.outgoing.send(RunLoopMessage::WatcherError(self.watcher.to_string(), message.to_string())).ok();
+    }
+}
+
+pub mod input;
+pub mod retry;
+pub mod circuit;
 pub mod file;
+pub mod filewatch;
+pub mod sqlite;
+#[cfg(feature = "db-postgres")]
+pub mod postgres;
+pub mod process;
+pub mod tcp;
 pub mod console;
 pub mod system;
 pub mod compiler;
@@ -15,3 +56,5 @@ pub mod textcompiler;
 pub mod editor;
 pub mod remote;
 pub mod websocket;
+pub mod sdk;
+pub mod plugin;
@@ -0,0 +1,177 @@
+//-------------------------------------------------------------------------
+// Watcher plugins
+//-------------------------------------------------------------------------
+
+// Watchers written against the SDK can be built into shared libraries and loaded when
+// a program starts, so a deployment can add integrations without rebuilding eve. The
+// plugins to load are listed in a manifest:
+//
+//     {"plugins": [{"path": "plugins/libslack.so", "name": "slack"}]}
+//
+// Relative paths are relative to the manifest. A plugin library exports the symbols
+// `eve_watcher_plugin!` generates. The ABI version is checked before anything else in
+// the library is touched, but watchers are still Rust trait objects, so a plugin has
+// to be built with the same compiler as the eve binary loading it.
+//
+// Sandboxed wasm modules are meant to be the second kind of plugin. There's no wasm
+// runtime in the engine yet, so for now they're rejected with a clear error.
+
+extern crate libc;
+extern crate serde_json;
+
+use ops::RunLoopMessage;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use super::Watcher;
+use super::sdk::{self, AbiMismatch, AbiVersion, Context, Diff, SdkWatcher, ABI_VERSION};
+
+pub const ABI_SYMBOL:&'static str = "eve_plugin_abi_version";
+pub const CREATE_SYMBOL:&'static str = "eve_plugin_create_watcher";
+
+pub type PluginWatcher = Box<SdkWatcher + Send>;
+
+impl SdkWatcher for PluginWatcher {
+    fn name(&self) -> String {
+        (**self).name()
+    }
+    fn on_diff(&mut self, context:&Context, diff:Diff) {
+        (**self).on_diff(context, diff)
+    }
+    fn dependencies(&self) -> Vec<String> {
+        (**self).dependencies()
+    }
+    fn teardown(&mut self) {
+        (**self).teardown()
+    }
+    fn abi_version(&self) -> AbiVersion {
+        (**self).abi_version()
+    }
+}
+
+/// Exports a watcher from a plugin library, e.g. `eve_watcher_plugin!(SlackWatcher::new());`
+#[macro_export]
+macro_rules! eve_watcher_plugin {
+    ($constructor:expr) => {
+        #[no_mangle]
+        pub extern "C" fn eve_plugin_abi_version() -> u64 {
+            let version = $crate::watchers::sdk::ABI_VERSION;
+            ((version.major as u64) << 32) | version.minor as u64
+        }
+
+        #[no_mangle]
+        pub extern "C" fn eve_plugin_create_watcher() -> *mut $crate::watchers::plugin::PluginWatcher {
+            let watcher:$crate::watchers::plugin::PluginWatcher = Box::new($constructor);
+            Box::into_raw(Box::new(watcher))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    Manifest(String, String),
+    Open(String, String),
+    MissingSymbol(String, &'static str),
+    Abi(AbiMismatch),
+    Unsupported(String),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &PluginError::Manifest(ref path, ref why) => write!(f, "Unable to read the plugin manifest {}: {}", path, why),
+            &PluginError::Open(ref path, ref why) => write!(f, "Unable to load the plugin {}: {}", path, why),
+            &PluginError::MissingSymbol(ref path, symbol) => write!(f, "{} isn't an eve plugin, it doesn't export `{}`", path, symbol),
+            &PluginError::Abi(ref mismatch) => write!(f, "{}", mismatch),
+            &PluginError::Unsupported(ref path) => write!(f, "Unable to load the plugin {}: wasm plugins aren't supported yet", path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginSpec {
+    pub path: String,
+    /// Attaches the watcher under this name instead of the one it gives itself.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PluginManifest {
+    #[serde(default)]
+    pub plugins: Vec<PluginSpec>,
+}
+
+impl PluginManifest {
+    pub fn from_file(path:&str) -> Result<PluginManifest, PluginError> {
+        let manifest_error = |why:String| PluginError::Manifest(path.to_string(), why);
+        let mut contents = String::new();
+        File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).map_err(|why:io::Error| manifest_error(why.to_string()))?;
+        let mut manifest:PluginManifest = serde_json::from_str(&contents).map_err(|why| manifest_error(why.to_string()))?;
+        let base = Path::new(path).parent().unwrap_or(Path::new(""));
+        for plugin in manifest.plugins.iter_mut() {
+            if Path::new(&plugin.path).is_relative() {
+                plugin.path = base.join(&plugin.path).to_string_lossy().to_string();
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// Loads the plugin and wraps its watcher so it can be passed to `Program::attach`.
+pub fn load_plugin(spec:&PluginSpec, outgoing:Sender<RunLoopMessage>) -> Result<Box<Watcher + Send>, PluginError> {
+    if spec.path.ends_with(".wasm") {
+        return Err(PluginError::Unsupported(spec.path.to_string()));
+    }
+    let watcher = load_library(&spec.path)?;
+    let mut watcher = sdk::adapt(watcher, outgoing).map_err(PluginError::Abi)?;
+    if let Some(ref name) = spec.name {
+        watcher.set_name(name);
+    }
+    Ok(watcher)
+}
+
+#[cfg(unix)]
+fn load_library(path:&str) -> Result<PluginWatcher, PluginError> {
+    use std::ffi::{CStr, CString};
+    use std::mem;
+    use self::libc::c_void;
+
+    fn last_error() -> String {
+        let error = unsafe { libc::dlerror() };
+        if error.is_null() { "unknown error".to_string() }
+        else { unsafe { CStr::from_ptr(error) }.to_string_lossy().to_string() }
+    }
+
+    fn symbol(handle:*mut c_void, path:&str, name:&'static str) -> Result<*mut c_void, PluginError> {
+        let c_name = CString::new(name).unwrap();
+        let found = unsafe { libc::dlsym(handle, c_name.as_ptr()) };
+        if found.is_null() { Err(PluginError::MissingSymbol(path.to_string(), name)) } else { Ok(found) }
+    }
+
+    let c_path = CString::new(path).map_err(|_| PluginError::Open(path.to_string(), "the path contains a nul byte".to_string()))?;
+    // the library is never closed, its code has to outlive the watcher it makes
+    let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+    if handle.is_null() {
+        return Err(PluginError::Open(path.to_string(), last_error()));
+    }
+    let abi_version:extern "C" fn() -> u64 = unsafe { mem::transmute(symbol(handle, path, ABI_SYMBOL)?) };
+    let packed = abi_version();
+    let built_for = AbiVersion { major: (packed >> 32) as u32, minor: packed as u32 };
+    if !ABI_VERSION.supports(built_for) {
+        return Err(PluginError::Abi(AbiMismatch { watcher: path.to_string(), host: ABI_VERSION, built_for }));
+    }
+    let create:extern "C" fn() -> *mut PluginWatcher = unsafe { mem::transmute(symbol(handle, path, CREATE_SYMBOL)?) };
+    let watcher = create();
+    if watcher.is_null() {
+        return Err(PluginError::Open(path.to_string(), "the plugin didn't create a watcher".to_string()));
+    }
+    Ok(unsafe { *Box::from_raw(watcher) })
+}
+
+#[cfg(not(unix))]
+fn load_library(path:&str) -> Result<PluginWatcher, PluginError> {
+    Err(PluginError::Open(path.to_string(), "shared library plugins are only supported on unix".to_string()))
+}
@@ -0,0 +1,214 @@
+use super::super::indexes::{WatchDiff};
+use super::super::ops::{Interned, Internable, Interner, RawChange, RunLoopMessage};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Sender};
+use std::thread;
+use std::time::Duration;
+use super::Watcher;
+
+//-------------------------------------------------------------------------
+// Process Watcher
+//-------------------------------------------------------------------------
+
+// Runs commands for programs. A `[#process/spawn cmd args stdin]` starts `cmd` with `args`,
+// split on whitespace unless quoted, and writes `stdin` to it if there is one. Every line
+// it prints comes back as a `[#process/output process stream line text]`, where `stream`
+// is "stdout" or "stderr" and `line` counts from 1 per stream. Once it's done there's a
+// `[#process/exit process code]`, with no `code` if it was killed by a signal. Removing the
+// spawn record kills the process if it's still running and takes back everything it sent.
+//
+// A command that can't be started is reported as a `[#process/error process message]`.
+
+const SOURCE:&'static str = "process/spawn";
+
+// A spawn's current run: what it has sent so far, and its process until it exits.
+struct Spawned {
+    generation: u64,
+    child: Option<Arc<Mutex<Child>>>,
+    sent: Vec<RawChange>,
+}
+
+type Runs = Arc<Mutex<HashMap<Interned, Spawned>>>;
+
+// Sends changes for a run, unless its spawn has been removed or respawned since. Returns
+// whether it's still worth sending more.
+fn send(runs:&Runs, id:Interned, generation:u64, changes:Vec<RawChange>, outgoing:&Sender<RunLoopMessage>) -> bool {
+    let mut runs = runs.lock().unwrap();
+    match runs.get_mut(&id) {
+        Some(spawned) if spawned.generation == generation => {
+            spawned.sent.extend(changes.iter().cloned());
+            outgoing.send(RunLoopMessage::Transaction(changes)).is_ok()
+        }
+        _ => false,
+    }
+}
+
+fn change(e:&Internable, a:&str, v:Internable) -> RawChange {
+    RawChange { e: e.clone(), a: Internable::String(a.to_string()), v, n: Internable::String(SOURCE.to_string()), count: 1 }
+}
+
+fn process_error(record:&Internable, id:Interned, why:String) -> Vec<RawChange> {
+    let err_id = Internable::Reference(format!("process/error|{}|", id));
+    vec![change(&err_id, "tag", Internable::String("process/error".to_string())),
+         change(&err_id, "process", record.clone()),
+         change(&err_id, "message", Internable::String(why))]
+}
+
+/// Splits `args` on whitespace, keeping anything in single or double quotes together.
+pub fn split_args(args:&str) -> Vec<String> {
+    let mut split = vec![];
+    let mut current = None;
+    let mut quote = None;
+    for c in args.chars() {
+        match (quote, c) {
+            (Some(q), _) if q == c => quote = None,
+            (Some(_), _) => current.get_or_insert_with(String::new).push(c),
+            (None, '\'') | (None, '"') => { quote = Some(c); current.get_or_insert_with(String::new); }
+            (None, _) if c.is_whitespace() => split.extend(current.take()),
+            (None, _) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    split.extend(current.take());
+    split
+}
+
+// Sends a fact per line of `stream` until it closes.
+fn stream_lines<R:Read>(stream:R, name:&'static str, record:Internable, id:Interned, generation:u64, runs:Runs, outgoing:Sender<RunLoopMessage>) {
+    for (ix, line) in BufReader::new(stream).lines().enumerate() {
+        let text = match line {
+            Ok(text) => text,
+            Err(_) => break,
+        };
+        let line_id = Internable::Reference(format!("process/output|{}|{}|{}|", id, name, ix + 1));
+        let changes = vec![change(&line_id, "tag", Internable::String("process/output".to_string())),
+                           change(&line_id, "process", record.clone()),
+                           change(&line_id, "stream", Internable::String(name.to_string())),
+                           change(&line_id, "line", Internable::from_number((ix + 1) as f32)),
+                           change(&line_id, "text", Internable::String(text))];
+        if !send(&runs, id, generation, changes, &outgoing) { break; }
+    }
+}
+
+pub struct ProcessWatcher {
+    name: String,
+    outgoing: Sender<RunLoopMessage>,
+    // each spawn record's run, an exited process is dropped from it
+    runs: Runs,
+    generation: u64,
+}
+
+impl ProcessWatcher {
+    pub fn new(outgoing: Sender<RunLoopMessage>) -> ProcessWatcher {
+        ProcessWatcher { name: "process/spawn".to_string(), outgoing, runs: Arc::new(Mutex::new(HashMap::new())), generation: 0 }
+    }
+
+    fn spawn(&mut self, id:Interned, record:Internable, cmd:&str, args:&str, stdin:Option<String>) {
+        self.generation += 1;
+        let generation = self.generation;
+        self.runs.lock().unwrap().insert(id, Spawned { generation, child: None, sent: vec![] });
+        let spawned = Command::new(cmd).args(split_args(args))
+                                       .stdin(if stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+                                       .stdout(Stdio::piped())
+                                       .stderr(Stdio::piped())
+                                       .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(why) => {
+                send(&self.runs, id, generation, process_error(&record, id, format!("Unable to run '{}': {}", cmd, why)), &self.outgoing);
+                return;
+            }
+        };
+        if let (Some(mut pipe), Some(input)) = (child.stdin.take(), stdin) {
+            // written from its own thread, so a process that doesn't read it can't block us
+            thread::spawn(move || { pipe.write_all(input.as_bytes()).ok(); });
+        }
+        let readers = vec![
+            child.stdout.take().map(|stdout| { let (record, runs, outgoing) = (record.clone(), self.runs.clone(), self.outgoing.clone()); thread::spawn(move || stream_lines(stdout, "stdout", record, id, generation, runs, outgoing)) }),
+            child.stderr.take().map(|stderr| { let (record, runs, outgoing) = (record.clone(), self.runs.clone(), self.outgoing.clone()); thread::spawn(move || stream_lines(stderr, "stderr", record, id, generation, runs, outgoing)) }),
+        ];
+        let child = Arc::new(Mutex::new(child));
+        if let Some(spawned) = self.runs.lock().unwrap().get_mut(&id) {
+            spawned.child = Some(child.clone());
+        }
+        let runs = self.runs.clone();
+        let outgoing = self.outgoing.clone();
+        thread::spawn(move || {
+            // the exit comes after all of the output
+            for reader in readers.into_iter().filter_map(|reader| reader) {
+                reader.join().ok();
+            }
+            // polled, so the lock is free for killing it in the meantime
+            let status = loop {
+                match child.lock().unwrap().try_wait() {
+                    Ok(None) => {}
+                    Ok(Some(status)) => break Ok(status),
+                    Err(why) => break Err(why),
+                }
+                thread::sleep(Duration::from_millis(10));
+            };
+            let exit_id = Internable::Reference(format!("process/exit|{}|", id));
+            let mut changes = vec![change(&exit_id, "tag", Internable::String("process/exit".to_string())),
+                                   change(&exit_id, "process", record.clone())];
+            match status {
+                Ok(status) => {
+                    if let Some(code) = status.code() {
+                        changes.push(change(&exit_id, "code", Internable::from_number(code as f32)));
+                    }
+                }
+                Err(why) => changes = process_error(&record, id, why.to_string()),
+            }
+            // there's nothing left to kill
+            if let Some(spawned) = runs.lock().unwrap().get_mut(&id) {
+                if spawned.generation == generation { spawned.child = None; }
+            }
+            send(&runs, id, generation, changes, &outgoing);
+        });
+    }
+
+    // Kills the spawn's process if it's still running and takes back what it sent.
+    fn retract(&mut self, id:Interned, changes:&mut Vec<RawChange>) {
+        if let Some(spawned) = self.runs.lock().unwrap().remove(&id) {
+            if let Some(child) = spawned.child {
+                // killing one that exited in the meantime just fails
+                child.lock().unwrap().kill().ok();
+            }
+            changes.extend(spawned.sent.into_iter().map(|mut change| { change.count = -1; change }));
+        }
+    }
+}
+
+impl Watcher for ProcessWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    fn teardown(&mut self) {
+        for (_, spawned) in self.runs.lock().unwrap().drain() {
+            if let Some(child) = spawned.child {
+                child.lock().unwrap().kill().ok();
+            }
+        }
+    }
+    // Rows are ("spawn", record, cmd, args) optionally followed by stdin.
+    fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff) {
+        let mut retracted = vec![];
+        for remove in diff.removes {
+            self.retract(remove[1], &mut retracted);
+        }
+        if retracted.len() > 0 {
+            self.outgoing.send(RunLoopMessage::Transaction(retracted)).ok();
+        }
+        for add in diff.adds {
+            let record = interner.get_value(add[1]).clone();
+            let cmd = Internable::to_string(interner.get_value(add[2]));
+            let args = Internable::to_string(interner.get_value(add[3]));
+            let stdin = add.get(4).map(|stdin| Internable::to_string(interner.get_value(*stdin)));
+            self.spawn(add[1], record, &cmd, &args, stdin);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use super::super::ops::{Internable};
+use rand::{thread_rng, Rng};
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+
+//-------------------------------------------------------------------------
+// Retry policies
+//-------------------------------------------------------------------------
+
+// Outbound requests can carry `retry: [attempts: 5 backoff: "exponential" delay: 200]`
+// and the watcher acting on them keeps trying until one attempt works or they've all
+// been used up, at which point it reports a single failure. Delays are in milliseconds.
+
+pub const DEFAULT_RETRY_DELAY:u64 = 100;
+pub const MAX_RETRY_DELAY:u64 = 30_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+    Constant,
+    Linear,
+    Exponential,
+}
+
+impl Backoff {
+    pub fn from_str(name:&str) -> Option<Backoff> {
+        match name {
+            "constant" => Some(Backoff::Constant),
+            "linear" => Some(Backoff::Linear),
+            "exponential" => Some(Backoff::Exponential),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryFailure {
+    pub attempts: u32,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub backoff: Backoff,
+    pub delay: u64,
+}
+
+impl RetryPolicy {
+    pub fn once() -> RetryPolicy {
+        RetryPolicy { attempts: 1, backoff: Backoff::Constant, delay: DEFAULT_RETRY_DELAY }
+    }
+
+    /// Builds a policy from the attempts, backoff and delay a watch row carries, any of
+    /// which can be missing or Null. Values that don't make sense fall back to the
+    /// defaults rather than failing the request outright.
+    pub fn from_values(values:&[&Internable]) -> RetryPolicy {
+        let mut policy = RetryPolicy::once();
+        match values.get(0) {
+            Some(&&Internable::Number(_)) if Internable::to_number(values[0]) >= 1.0 => policy.attempts = Internable::to_number(values[0]) as u32,
+            _ => {}
+        }
+        match values.get(1) {
+            Some(&&Internable::String(ref name)) => policy.backoff = Backoff::from_str(name).unwrap_or(Backoff::Constant),
+            _ => {}
+        }
+        match values.get(2) {
+            Some(&&Internable::Number(_)) if Internable::to_number(values[2]) >= 0.0 => policy.delay = Internable::to_number(values[2]) as u64,
+            _ => {}
+        }
+        policy
+    }
+
+    /// How long to wait before retry number `retry` (the first retry is 1) before any
+    /// jitter is applied, capped at MAX_RETRY_DELAY.
+    pub fn base_delay(&self, retry:u32) -> u64 {
+        let delay = match self.backoff {
+            Backoff::Constant => self.delay,
+            Backoff::Linear => self.delay.saturating_mul(retry as u64),
+            Backoff::Exponential => self.delay.saturating_mul(1u64 << cmp::min(retry.saturating_sub(1), 32)),
+        };
+        cmp::min(delay, MAX_RETRY_DELAY)
+    }
+
+    /// Waits somewhere between half and all of the base delay, so a batch of requests
+    /// that failed together don't all come back at the same moment.
+    pub fn jittered_delay(&self, retry:u32) -> u64 {
+        let delay = self.base_delay(retry);
+        if delay < 2 { return delay; }
+        delay / 2 + thread_rng().gen_range(0, delay / 2 + 1)
+    }
+
+    /// Calls `attempt` with the attempt number until it succeeds or the policy runs out,
+    /// sleeping between tries. This blocks, so watchers run it on their own thread.
+    pub fn run<T, F>(&self, mut attempt:F) -> Result<T, RetryFailure> where F: FnMut(u32) -> Result<T, String> {
+        let mut tries = 0;
+        loop {
+            tries += 1;
+            match attempt(tries) {
+                Ok(result) => return Ok(result),
+                Err(message) => {
+                    if tries >= self.attempts {
+                        return Err(RetryFailure { attempts: tries, message });
+                    }
+                }
+            }
+            thread::sleep(Duration::from_millis(self.jittered_delay(tries)));
+        }
+    }
+}
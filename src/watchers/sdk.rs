@@ -0,0 +1,202 @@
+//-------------------------------------------------------------------------
+// Watcher SDK
+//-------------------------------------------------------------------------
+
+// The interface third-party watchers are written against. Everything a watcher sees
+// here is decoded into types that belong to this module alone, so Interner, WatchDiff
+// and the run loop's messages are free to change without breaking anyone. The types
+// in this module follow semver through ABI_VERSION: additions bump the minor version,
+// anything else bumps the major one. Once it has settled this module is meant to
+// become its own crate.
+
+use indexes::WatchDiff;
+use numerics::Decimal;
+use ops::{Count, Interned, Interner, Internable, RawChange, RunLoopMessage};
+use std::fmt;
+use std::sync::mpsc::Sender;
+use super::{Watcher, WatcherErrors};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbiVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl AbiVersion {
+    /// Whether a host providing this version can run a watcher built against `built_for`.
+    pub fn supports(&self, built_for:AbiVersion) -> bool {
+        self.major == built_for.major && self.minor >= built_for.minor
+    }
+}
+
+impl fmt::Display for AbiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+pub const ABI_VERSION:AbiVersion = AbiVersion { major: 1, minor: 2 };
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f64),
+    /// An exact number, `mantissa / 10^scale`, e.g. `1.5d` is 15 with a scale of 1.
+    /// Since 1.2.
+    Decimal { mantissa: i64, scale: u32 },
+    /// The id of a record, which is never equal to a string that happens to spell it.
+    Record(String),
+    Null,
+}
+
+impl Value {
+    fn from_internable(internable:&Internable) -> Value {
+        match internable {
+            &Internable::String(ref string) => Value::String(string.to_string()),
+            &Internable::Reference(ref id) => Value::Record(id.to_string()),
+            &Internable::Scoped(..) => Value::String(Internable::to_string(internable)),
+            &Internable::Number(_) => Value::Number(Internable::to_number(internable) as f64),
+            &Internable::Decimal(ref decimal) => Value::Decimal { mantissa: decimal.mantissa(), scale: decimal.scale() },
+            &Internable::Null => Value::Null,
+        }
+    }
+
+    fn to_internable(&self) -> Internable {
+        match self {
+            &Value::String(ref string) => Internable::String(string.to_string()),
+            &Value::Record(ref id) => Internable::Reference(id.to_string()),
+            &Value::Number(number) => Internable::from_number(number as f32),
+            &Value::Decimal { mantissa, scale } => Internable::Decimal(Decimal::new(mantissa, scale)),
+            &Value::Null => Internable::Null,
+        }
+    }
+}
+
+/// One row per match of the watch block, in the order its `watch` section lists them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diff {
+    pub adds: Vec<Vec<Value>>,
+    pub removes: Vec<Vec<Value>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fact {
+    pub e: Value,
+    pub a: String,
+    pub v: Value,
+}
+
+impl Fact {
+    pub fn new(e:Value, a:&str, v:Value) -> Fact {
+        Fact { e, a: a.to_string(), v }
+    }
+}
+
+/// Returned by Context when the program the watcher belongs to has stopped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stopped;
+
+/// What a watcher can do to the program it's attached to.
+pub struct Context {
+    name: String,
+    outgoing: Sender<RunLoopMessage>,
+    errors: Option<WatcherErrors>,
+}
+
+impl Context {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Adds the facts to the program in a transaction of their own.
+    pub fn commit(&self, facts:Vec<Fact>) -> Result<(), Stopped> {
+        self.send(facts, 1)
+    }
+
+    /// Removes facts that were added with commit.
+    pub fn retract(&self, facts:Vec<Fact>) -> Result<(), Stopped> {
+        self.send(facts, -1)
+    }
+
+    /// Reports something that went wrong as an `#eve/error` in the program. Since 1.1.
+    pub fn report_error(&self, message:&str) {
+        if let Some(ref errors) = self.errors {
+            errors.report(message);
+        }
+    }
+
+    fn send(&self, facts:Vec<Fact>, count:Count) -> Result<(), Stopped> {
+        let node = Internable::String(self.name.to_string());
+        let changes = facts.into_iter().map(|fact| {
+            RawChange::new(fact.e.to_internable(), Internable::String(fact.a), fact.v.to_internable(), node.clone(), count)
+        }).collect();
+        self.outgoing.send(RunLoopMessage::Transaction(changes)).map_err(|_| Stopped)
+    }
+}
+
+pub trait SdkWatcher {
+    fn name(&self) -> String;
+    fn on_diff(&mut self, context:&Context, diff:Diff);
+    /// Watchers that have to see a transaction's diff before this one does.
+    fn dependencies(&self) -> Vec<String> { vec![] }
+    /// Called when the watcher is detached or its program stops. Since 1.1.
+    fn teardown(&mut self) {}
+    /// The version of this interface the watcher was built against. The default is
+    /// whatever version the watcher was compiled with, which is nearly always right.
+    fn abi_version(&self) -> AbiVersion { ABI_VERSION }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AbiMismatch {
+    pub watcher: String,
+    pub host: AbiVersion,
+    pub built_for: AbiVersion,
+}
+
+impl fmt::Display for AbiMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Watcher `{}` was built for watcher ABI {}, which this version of Eve ({}) can't run", self.watcher, self.built_for, self.host)
+    }
+}
+
+/// Runs an SdkWatcher as one of the engine's own.
+pub struct SdkAdapter<W:SdkWatcher> {
+    watcher: W,
+    context: Context,
+}
+
+impl<W:SdkWatcher> Watcher for SdkAdapter<W> {
+    fn get_name(& self) -> String {
+        self.context.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.context.name = name.to_string();
+    }
+    fn setup(&mut self, errors:WatcherErrors) {
+        self.context.errors = Some(errors);
+    }
+    fn teardown(&mut self) {
+        self.watcher.teardown();
+    }
+    fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff) {
+        let decode = |rows:Vec<Vec<Interned>>| -> Vec<Vec<Value>> {
+            rows.iter().map(|row| row.iter().map(|&v| Value::from_internable(interner.get_value(v))).collect()).collect()
+        };
+        let diff = Diff { removes: decode(diff.removes), adds: decode(diff.adds) };
+        self.watcher.on_diff(&self.context, diff);
+    }
+    fn dependencies(&self) -> Vec<String> {
+        self.watcher.dependencies()
+    }
+}
+
+/// Wraps `watcher` so it can be passed to `Program::attach`, as long as it was built
+/// against a version of this interface that this version of Eve supports.
+pub fn adapt<W:SdkWatcher + Send + 'static>(watcher:W, outgoing:Sender<RunLoopMessage>) -> Result<Box<Watcher + Send>, AbiMismatch> {
+    let built_for = watcher.abi_version();
+    if !ABI_VERSION.supports(built_for) {
+        return Err(AbiMismatch { watcher: watcher.name(), host: ABI_VERSION, built_for });
+    }
+    let context = Context { name: watcher.name(), outgoing, errors: None };
+    Ok(Box::new(SdkAdapter { watcher, context }))
+}
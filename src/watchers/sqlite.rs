@@ -0,0 +1,301 @@
+extern crate rusqlite;
+
+use super::super::indexes::{WatchDiff};
+use super::super::ops::{Interned, Internable, Interner, RawChange, RunLoopMessage};
+use self::rusqlite::Connection;
+use self::rusqlite::types::{ToSql, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{Sender};
+use super::Watcher;
+
+//-------------------------------------------------------------------------
+// SQLite Watcher
+//-------------------------------------------------------------------------
+
+// Lets programs use SQLite databases, opened by path the first time they're needed.
+// A `[#db/query database query params]` runs its query and gets a
+// `[#db/query/row query index ...]` per row back, with an attribute per non-null column,
+// and a `[#db/query/result query rows]` once it's done. `:name` in the query is bound
+// to the `name` attribute of `params`. The rows stay as long as the query record does,
+// and are replaced if it or its params change.
+//
+// A `[#db/persist database tagged]` keeps the facts of every record with the `tagged`
+// tag in a table named after it, as (entity, attribute, value) rows. The table is read back in
+// when the persist record shows up, so the records survive restarts. Removing the
+// persist record stops the syncing but leaves the table alone.
+//
+// Anything that goes wrong is reported as a `[#db/error database message]`, with the
+// `query` too if there was one.
+
+const SOURCE:&'static str = "db/sqlite";
+
+fn change(e:&Internable, a:&str, v:Internable) -> RawChange {
+    RawChange { e: e.clone(), a: Internable::String(a.to_string()), v, n: Internable::String(SOURCE.to_string()), count: 1 }
+}
+
+fn db_error(changes:&mut Vec<RawChange>, id:String, database:&str, query:Option<&Internable>, why:String) {
+    let err_id = Internable::Reference(format!("db/error|{}|", id));
+    changes.push(change(&err_id, "tag", Internable::String("db/error".to_string())));
+    changes.push(change(&err_id, "database", Internable::String(database.to_string())));
+    changes.push(change(&err_id, "message", Internable::String(why)));
+    if let Some(query) = query {
+        changes.push(change(&err_id, "query", query.clone()));
+    }
+}
+
+fn to_sql(value:&Internable) -> Value {
+    match value {
+        &Internable::String(ref string) | &Internable::Reference(ref string) => Value::Text(string.to_string()),
+        &Internable::Scoped(..) => Value::Text(Internable::to_string(value)),
+        &Internable::Number(_) => {
+            let number = Internable::to_number(value);
+            if number.fract() == 0.0 { Value::Integer(number as i64) } else { Value::Real(number as f64) }
+        }
+        &Internable::Decimal(ref decimal) => Value::Real(decimal.to_float()),
+        &Internable::Null => Value::Null,
+    }
+}
+
+// NULL has no value to become.
+fn from_sql(value:Value) -> Option<Internable> {
+    match value {
+        Value::Null => None,
+        Value::Integer(number) => Some(Internable::from_number(number as f32)),
+        Value::Real(number) => Some(Internable::from_number(number as f32)),
+        Value::Text(text) => Some(Internable::String(text)),
+        Value::Blob(bytes) => Some(Internable::String(String::from_utf8_lossy(&bytes).into_owned())),
+    }
+}
+
+// Persist tables are ours, so their values carry their kind: references are stored as
+// blobs and strings as text, and they come back as what they went in as.
+fn to_stored(value:&Internable) -> Value {
+    match value {
+        &Internable::Reference(ref id) => Value::Blob(id.as_bytes().to_vec()),
+        _ => to_sql(value),
+    }
+}
+
+fn from_stored(value:Value) -> Option<Internable> {
+    match value {
+        Value::Blob(bytes) => Some(Internable::Reference(String::from_utf8_lossy(&bytes).into_owned())),
+        value => from_sql(value),
+    }
+}
+
+// Every entity is a record, however it was stored.
+fn stored_entity(value:Value) -> Option<Internable> {
+    match value {
+        Value::Text(id) => Some(Internable::Reference(id)),
+        Value::Blob(bytes) => Some(Internable::Reference(String::from_utf8_lossy(&bytes).into_owned())),
+        _ => None,
+    }
+}
+
+/// The table facts with `tag` are persisted to. Anything but letters, digits and `_` is
+/// replaced, so `#ui/button` goes to `ui_button`.
+pub fn table_name(tag:&str) -> String {
+    tag.chars().map(|c| if c.is_alphanumeric() || c == '_' { c } else { '_' }).collect()
+}
+
+struct Query {
+    database: String,
+    sql: String,
+    // the record the query came from, as the program sees it
+    record: Internable,
+}
+
+pub struct SqliteWatcher {
+    name: String,
+    outgoing: Sender<RunLoopMessage>,
+    connections: HashMap<String, Connection>,
+    queries: HashMap<Interned, Query>,
+    params: HashMap<Interned, HashMap<String, Internable>>,
+    // what each query added, so it can be taken back out
+    results: HashMap<Interned, Vec<RawChange>>,
+    // the (database, tag) pairs being persisted
+    tables: HashSet<(String, String)>,
+}
+
+impl SqliteWatcher {
+    pub fn new(outgoing: Sender<RunLoopMessage>) -> SqliteWatcher {
+        SqliteWatcher { name: "db/sqlite".to_string(), outgoing, connections: HashMap::new(), queries: HashMap::new(), params: HashMap::new(), results: HashMap::new(), tables: HashSet::new() }
+    }
+
+    fn connection(&mut self, database:&str) -> Result<&Connection, String> {
+        if !self.connections.contains_key(database) {
+            let connection = Connection::open(database).map_err(|why| format!("Unable to open '{}': {}", database, why))?;
+            self.connections.insert(database.to_string(), connection);
+        }
+        Ok(&self.connections[database])
+    }
+
+    fn run_query(&mut self, id:Interned, changes:&mut Vec<RawChange>) {
+        let (database, sql, record) = match self.queries.get(&id) {
+            Some(query) => (query.database.to_string(), query.sql.to_string(), query.record.clone()),
+            None => return,
+        };
+        let params:Vec<(String, Value)> = self.params.get(&id).map_or(vec![], |params| {
+            params.iter().map(|(name, value)| (format!(":{}", name), to_sql(value))).collect()
+        });
+        let mut result = vec![];
+        let rows = self.connection(&database).and_then(|connection| {
+            let mut statement = connection.prepare(&sql).map_err(|why| why.to_string())?;
+            let columns:Vec<String> = statement.column_names().iter().map(|column| column.to_string()).collect();
+            let named:Vec<(&str, &ToSql)> = params.iter().map(|&(ref name, ref value)| (&name[..], value as &ToSql)).collect();
+            let mut rows = statement.query_named(&named).map_err(|why| why.to_string())?;
+            let mut count = 0;
+            while let Some(row) = rows.next() {
+                let row = row.map_err(|why| why.to_string())?;
+                count += 1;
+                let row_id = Internable::Reference(format!("db/query/row|{}|{}|", id, count));
+                result.push(change(&row_id, "tag", Internable::String("db/query/row".to_string())));
+                result.push(change(&row_id, "query", record.clone()));
+                result.push(change(&row_id, "index", Internable::from_number(count as f32)));
+                for (ix, column) in columns.iter().enumerate() {
+                    let value = row.get_checked::<i32, Value>(ix as i32).map_err(|why| why.to_string())?;
+                    if let Some(value) = from_sql(value) {
+                        result.push(change(&row_id, column, value));
+                    }
+                }
+            }
+            Ok(count)
+        });
+        match rows {
+            Ok(count) => {
+                let result_id = Internable::Reference(format!("db/query/result|{}|", id));
+                result.push(change(&result_id, "tag", Internable::String("db/query/result".to_string())));
+                result.push(change(&result_id, "query", record.clone()));
+                result.push(change(&result_id, "rows", Internable::from_number(count as f32)));
+            }
+            Err(why) => {
+                result.clear();
+                db_error(&mut result, format!("query|{}", id), &database, Some(&record), why);
+            }
+        }
+        changes.extend(result.iter().cloned());
+        self.results.insert(id, result);
+    }
+
+    fn retract_query(&mut self, id:Interned, changes:&mut Vec<RawChange>) {
+        if let Some(result) = self.results.remove(&id) {
+            changes.extend(result.into_iter().map(|mut change| { change.count = -1; change }));
+        }
+    }
+
+    // Makes sure the tag's table exists and reads back whatever is already in it.
+    fn open_table(&mut self, database:&str, tag:&str, changes:&mut Vec<RawChange>) {
+        let table = table_name(tag);
+        let restored = self.connection(database).and_then(|connection| {
+            connection.execute_batch(&format!("CREATE TABLE IF NOT EXISTS \"{}\" (entity TEXT NOT NULL, attribute TEXT NOT NULL, value, UNIQUE(entity, attribute, value));", table))
+                      .map_err(|why| why.to_string())?;
+            let mut statement = connection.prepare(&format!("SELECT entity, attribute, value FROM \"{}\"", table)).map_err(|why| why.to_string())?;
+            let mut rows = statement.query(&[]).map_err(|why| why.to_string())?;
+            let mut restored = vec![];
+            while let Some(row) = rows.next() {
+                let row = row.map_err(|why| why.to_string())?;
+                let entity:Value = row.get_checked(0).map_err(|why| why.to_string())?;
+                let attribute:String = row.get_checked(1).map_err(|why| why.to_string())?;
+                let value:Value = row.get_checked(2).map_err(|why| why.to_string())?;
+                if let (Some(entity), Some(value)) = (stored_entity(entity), from_stored(value)) {
+                    restored.push(change(&entity, &attribute, value));
+                }
+            }
+            Ok(restored)
+        });
+        match restored {
+            Ok(restored) => {
+                changes.extend(restored);
+                self.tables.insert((database.to_string(), tag.to_string()));
+            }
+            Err(why) => db_error(changes, format!("persist|{}|{}", database, tag), database, None, why),
+        }
+    }
+
+    fn persist(&mut self, database:&str, tag:&str, fact:(&Internable, &Internable, &Internable), count:i32, changes:&mut Vec<RawChange>) {
+        if !self.tables.contains(&(database.to_string(), tag.to_string())) { return; }
+        let (entity, attribute, value) = (to_sql(fact.0), to_sql(fact.1), to_stored(fact.2));
+        let sql = if count > 0 {
+            format!("INSERT OR IGNORE INTO \"{}\" (entity, attribute, value) VALUES (?1, ?2, ?3)", table_name(tag))
+        } else {
+            format!("DELETE FROM \"{}\" WHERE entity = ?1 AND attribute = ?2 AND value = ?3", table_name(tag))
+        };
+        let written = self.connection(database).and_then(|connection| {
+            connection.execute(&sql, &[&entity, &attribute, &value]).map_err(|why| why.to_string())
+        });
+        if let Err(why) = written {
+            db_error(changes, format!("persist|{}|{}", database, tag), database, None, why);
+        }
+    }
+}
+
+impl Watcher for SqliteWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    // Rows are ("query", record, database, query), ("param", record, name, value),
+    // ("table", database, tag) and ("persist", database, tag, entity, attribute, value).
+    fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff) {
+        let mut changes = vec![];
+        let kind = |row:&Vec<Interned>| Internable::to_string(interner.get_value(row[0]));
+        let text = |value:Interned| Internable::to_string(interner.get_value(value));
+
+        // tables go first, so removing a persist record doesn't delete what it persisted
+        for remove in diff.removes.iter().filter(|row| kind(*row) == "table") {
+            self.tables.remove(&(text(remove[1]), text(remove[2])));
+        }
+        for add in diff.adds.iter().filter(|row| kind(*row) == "table") {
+            self.open_table(&text(add[1]), &text(add[2]), &mut changes);
+        }
+        for (rows, count) in vec![(&diff.removes, -1), (&diff.adds, 1)] {
+            for row in rows.iter().filter(|row| kind(*row) == "persist") {
+                let fact = (interner.get_value(row[3]), interner.get_value(row[4]), interner.get_value(row[5]));
+                self.persist(&text(row[1]), &text(row[2]), fact, count, &mut changes);
+            }
+        }
+
+        // queries whose params change are run again
+        let mut rerun = HashSet::new();
+        for remove in diff.removes.iter() {
+            match &kind(remove)[..] {
+                "query" => {
+                    self.queries.remove(&remove[1]);
+                    self.retract_query(remove[1], &mut changes);
+                }
+                "param" => {
+                    if let Some(params) = self.params.get_mut(&remove[1]) { params.remove(&text(remove[2])); }
+                    rerun.insert(remove[1]);
+                }
+                _ => {}
+            }
+        }
+        for add in diff.adds.iter() {
+            match &kind(add)[..] {
+                "query" => {
+                    let query = Query { database: text(add[2]), sql: text(add[3]), record: interner.get_value(add[1]).clone() };
+                    self.queries.insert(add[1], query);
+                    rerun.insert(add[1]);
+                }
+                "param" => {
+                    self.params.entry(add[1]).or_insert_with(HashMap::new).insert(text(add[2]), interner.get_value(add[3]).clone());
+                    rerun.insert(add[1]);
+                }
+                _ => {}
+            }
+        }
+        let mut rerun:Vec<Interned> = rerun.into_iter().collect();
+        rerun.sort();
+        for id in rerun {
+            self.retract_query(id, &mut changes);
+            self.run_query(id, &mut changes);
+        }
+        self.params.retain(|_, params| !params.is_empty());
+
+        if changes.len() > 0 {
+            self.outgoing.send(RunLoopMessage::Transaction(changes)).ok();
+        }
+    }
+}
@@ -125,3 +125,69 @@ impl Watcher for PanicWatcher {
         }
     }
 }
+
+//-------------------------------------------------------------------------
+// Entity Merge Watcher
+//-------------------------------------------------------------------------
+
+pub struct EntityMergeWatcher {
+    name: String,
+    outgoing: Sender<RunLoopMessage>,
+}
+
+impl EntityMergeWatcher {
+    pub fn new(outgoing: Sender<RunLoopMessage>) -> EntityMergeWatcher {
+        EntityMergeWatcher { name: "entity/merge".to_string(), outgoing }
+    }
+}
+
+impl Watcher for EntityMergeWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff) {
+        for add in diff.adds {
+            let keep = interner.get_value(add[0]).clone();
+            let merge = interner.get_value(add[1]).clone();
+            match self.outgoing.send(RunLoopMessage::Merge(keep, merge)) {
+                Err(_) => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+//-------------------------------------------------------------------------
+// Inspector Watcher
+//-------------------------------------------------------------------------
+
+// Refreshes the program's `#eve/internal` records whenever a new row is watched, e.g.
+// `search [#system/timer/change tick] watch eve/inspector (tick)`. Watching anything
+// derived from the records themselves would refresh forever.
+pub struct InspectorWatcher {
+    name: String,
+    outgoing: Sender<RunLoopMessage>,
+}
+
+impl InspectorWatcher {
+    pub fn new(outgoing: Sender<RunLoopMessage>) -> InspectorWatcher {
+        InspectorWatcher { name: "eve/inspector".to_string(), outgoing }
+    }
+}
+
+impl Watcher for InspectorWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    fn on_diff(&mut self, _:&mut Interner, diff:WatchDiff) {
+        if diff.adds.len() > 0 {
+            self.outgoing.send(RunLoopMessage::Inspect).ok();
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use super::super::indexes::{WatchDiff};
+use super::super::ops::{Interned, Internable, Interner, RawChange, RunLoopMessage};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Sender};
+use std::thread;
+use std::time::Duration;
+use super::Watcher;
+
+//-------------------------------------------------------------------------
+// TCP Watchers
+//-------------------------------------------------------------------------
+
+// Lets programs speak line protocols over TCP. A `[#tcp/connect host port]` connects out and
+// a `[#tcp/listen host port]` accepts connections, adding a `[#tcp/listening listen port]`
+// once it's bound. Either way, every connection is a `[#tcp/connection peer state]`, with
+// the record it came from as `connect` or `listen`, and a `state` that goes from "open" to
+// "closed". Each line that comes in is a `[#tcp/line connection line text]`, with `line`
+// counting from 1, and a `[#tcp/send connection text]` writes `text` out as a line.
+// Removing the connect or listen record closes its connections.
+//
+// Anything that goes wrong is reported as a `[#tcp/error source message]`.
+
+const SOURCE:&'static str = "tcp";
+const ACCEPT_POLL_MS:u64 = 50;
+
+fn change(e:&Internable, a:&str, v:Internable, count:i32) -> RawChange {
+    RawChange { e: e.clone(), a: Internable::String(a.to_string()), v, n: Internable::String(SOURCE.to_string()), count }
+}
+
+fn tcp_error(source:&Internable, why:String) -> Vec<RawChange> {
+    let err_id = Internable::Reference(format!("tcp/error|{}|", Internable::to_string(source)));
+    vec![change(&err_id, "tag", Internable::String("tcp/error".to_string()), 1),
+         change(&err_id, "source", source.clone(), 1),
+         change(&err_id, "message", Internable::String(why), 1)]
+}
+
+// The connections a watcher has open, shared with the threads reading from them.
+#[derive(Clone)]
+struct Connections {
+    streams: Arc<Mutex<HashMap<String, TcpStream>>>,
+    // the connections opened for each connect or listen record still around
+    owners: Arc<Mutex<HashMap<Interned, Vec<String>>>>,
+    next: Arc<AtomicUsize>,
+    outgoing: Sender<RunLoopMessage>,
+}
+
+impl Connections {
+    fn new(outgoing:Sender<RunLoopMessage>) -> Connections {
+        Connections { streams: Arc::new(Mutex::new(HashMap::new())), owners: Arc::new(Mutex::new(HashMap::new())), next: Arc::new(AtomicUsize::new(0)), outgoing }
+    }
+
+    fn wanted(&self, owner:Interned) -> bool {
+        self.owners.lock().unwrap().contains_key(&owner)
+    }
+
+    // Takes on a stream for `owner`, announces it and reads lines off it on its own thread
+    // until it closes.
+    fn open(&self, stream:TcpStream, kind:&'static str, owner:Interned, record:&Internable) {
+        let reader = match stream.try_clone() {
+            Ok(reader) => reader,
+            Err(why) => { self.outgoing.send(RunLoopMessage::Transaction(tcp_error(record, why.to_string()))).ok(); return; }
+        };
+        let id = format!("tcp/connection|{}|", self.next.fetch_add(1, Ordering::SeqCst) + 1);
+        {
+            let mut owners = self.owners.lock().unwrap();
+            match owners.get_mut(&owner) {
+                Some(opened) => opened.push(id.to_string()),
+                // the record went away while we were connecting
+                None => { stream.shutdown(Shutdown::Both).ok(); return; }
+            }
+            let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+            self.streams.lock().unwrap().insert(id.to_string(), stream);
+            let connection = Internable::Reference(id.to_string());
+            self.outgoing.send(RunLoopMessage::Transaction(vec![
+                change(&connection, "tag", Internable::String("tcp/connection".to_string()), 1),
+                change(&connection, kind, record.clone(), 1),
+                change(&connection, "peer", Internable::String(peer), 1),
+                change(&connection, "state", Internable::String("open".to_string()), 1),
+            ])).ok();
+        }
+        let connections = self.clone();
+        thread::spawn(move || connections.read(id, reader));
+    }
+
+    fn read(&self, id:String, stream:TcpStream) {
+        let connection = Internable::Reference(id.to_string());
+        for (ix, line) in BufReader::new(stream).lines().enumerate() {
+            let text = match line {
+                Ok(text) => text,
+                Err(_) => break,
+            };
+            let line_id = Internable::Reference(format!("tcp/line|{}|{}|", id, ix + 1));
+            let changes = vec![change(&line_id, "tag", Internable::String("tcp/line".to_string()), 1),
+                               change(&line_id, "connection", connection.clone(), 1),
+                               change(&line_id, "line", Internable::from_number((ix + 1) as f32), 1),
+                               change(&line_id, "text", Internable::String(text), 1)];
+            if self.outgoing.send(RunLoopMessage::Transaction(changes)).is_err() { return; }
+        }
+        self.streams.lock().unwrap().remove(&id);
+        self.outgoing.send(RunLoopMessage::Transaction(vec![
+            change(&connection, "state", Internable::String("open".to_string()), -1),
+            change(&connection, "state", Internable::String("closed".to_string()), 1),
+        ])).ok();
+    }
+
+    fn send(&self, connection:&Internable, text:&str) {
+        let id = Internable::to_string(connection);
+        let written = match self.streams.lock().unwrap().get_mut(&id) {
+            Some(stream) => stream.write_all(format!("{}\n", text).as_bytes()).map_err(|why| why.to_string()),
+            None => Err(format!("Connection {} isn't open", id)),
+        };
+        if let Err(why) = written {
+            self.outgoing.send(RunLoopMessage::Transaction(tcp_error(connection, why))).ok();
+        }
+    }
+
+    // Closes everything opened for `owner`. The readers see the streams end and report
+    // them closed.
+    fn close(&self, owner:Interned) {
+        let opened = self.owners.lock().unwrap().remove(&owner).unwrap_or_default();
+        let streams = self.streams.lock().unwrap();
+        for id in opened {
+            if let Some(stream) = streams.get(&id) {
+                stream.shutdown(Shutdown::Both).ok();
+            }
+        }
+    }
+
+    fn close_all(&self) {
+        let owners:Vec<Interned> = self.owners.lock().unwrap().keys().cloned().collect();
+        for owner in owners {
+            self.close(owner);
+        }
+    }
+
+    // Rows are ("send", send, connection, text).
+    fn on_sends(&self, interner:&Interner, diff:&WatchDiff) {
+        for add in diff.adds.iter().filter(|row| Internable::to_string(interner.get_value(row[0])) == "send") {
+            self.send(interner.get_value(add[2]), &Internable::to_string(interner.get_value(add[3])));
+        }
+    }
+}
+
+fn address(interner:&Interner, host:Interned, port:Interned) -> String {
+    format!("{}:{}", Internable::to_string(interner.get_value(host)), Internable::to_number(interner.get_value(port)) as u16)
+}
+
+//-------------------------------------------------------------------------
+// Connect
+//-------------------------------------------------------------------------
+
+pub struct TcpConnectWatcher {
+    name: String,
+    connections: Connections,
+}
+
+impl TcpConnectWatcher {
+    pub fn new(outgoing: Sender<RunLoopMessage>) -> TcpConnectWatcher {
+        TcpConnectWatcher { name: "tcp/connect".to_string(), connections: Connections::new(outgoing) }
+    }
+}
+
+impl Watcher for TcpConnectWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    fn teardown(&mut self) {
+        self.connections.close_all();
+    }
+    // Rows are ("connect", record, host, port) and ("send", send, connection, text).
+    fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff) {
+        for remove in diff.removes.iter().filter(|row| Internable::to_string(interner.get_value(row[0])) == "connect") {
+            self.connections.close(remove[1]);
+        }
+        for add in diff.adds.iter().filter(|row| Internable::to_string(interner.get_value(row[0])) == "connect") {
+            let (owner, record, address) = (add[1], interner.get_value(add[1]).clone(), address(interner, add[2], add[3]));
+            self.connections.owners.lock().unwrap().insert(owner, vec![]);
+            let connections = self.connections.clone();
+            // connecting can take a while, so it doesn't hold up the run loop
+            thread::spawn(move || {
+                match TcpStream::connect(&address[..]) {
+                    Ok(stream) => connections.open(stream, "connect", owner, &record),
+                    Err(why) => {
+                        let why = format!("Unable to connect to {}: {}", address, why);
+                        connections.outgoing.send(RunLoopMessage::Transaction(tcp_error(&record, why))).ok();
+                    }
+                }
+            });
+        }
+        self.connections.on_sends(interner, &diff);
+    }
+}
+
+//-------------------------------------------------------------------------
+// Listen
+//-------------------------------------------------------------------------
+
+pub struct TcpListenWatcher {
+    name: String,
+    connections: Connections,
+}
+
+impl TcpListenWatcher {
+    pub fn new(outgoing: Sender<RunLoopMessage>) -> TcpListenWatcher {
+        TcpListenWatcher { name: "tcp/listen".to_string(), connections: Connections::new(outgoing) }
+    }
+
+    fn listen(&self, owner:Interned, record:Internable, address:&str) -> Result<(), String> {
+        let listener = TcpListener::bind(address).map_err(|why| format!("Unable to listen on {}: {}", address, why))?;
+        // polled, so the listener can notice its record is gone
+        listener.set_nonblocking(true).map_err(|why| why.to_string())?;
+        let port = listener.local_addr().map_err(|why| why.to_string())?.port();
+        let listening = Internable::Reference(format!("tcp/listening|{}|", Internable::to_string(&record)));
+        self.connections.outgoing.send(RunLoopMessage::Transaction(vec![
+            change(&listening, "tag", Internable::String("tcp/listening".to_string()), 1),
+            change(&listening, "listen", record.clone(), 1),
+            change(&listening, "port", Internable::from_number(port as f32), 1),
+        ])).ok();
+        let connections = self.connections.clone();
+        thread::spawn(move || {
+            while connections.wanted(owner) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        stream.set_nonblocking(false).ok();
+                        connections.open(stream, "listen", owner, &record);
+                    }
+                    Err(ref why) if why.kind() == ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(ACCEPT_POLL_MS)),
+                    Err(why) => {
+                        connections.outgoing.send(RunLoopMessage::Transaction(tcp_error(&record, why.to_string()))).ok();
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Watcher for TcpListenWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    fn teardown(&mut self) {
+        // listeners stop accepting once their records are gone
+        self.connections.close_all();
+    }
+    // Rows are ("listen", record, host, port) and ("send", send, connection, text).
+    fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff) {
+        for remove in diff.removes.iter().filter(|row| Internable::to_string(interner.get_value(row[0])) == "listen") {
+            self.connections.close(remove[1]);
+        }
+        for add in diff.adds.iter().filter(|row| Internable::to_string(interner.get_value(row[0])) == "listen") {
+            let (owner, record, address) = (add[1], interner.get_value(add[1]).clone(), address(interner, add[2], add[3]));
+            self.connections.owners.lock().unwrap().insert(owner, vec![]);
+            if let Err(why) = self.listen(owner, record.clone(), &address) {
+                self.connections.owners.lock().unwrap().remove(&owner);
+                self.connections.outgoing.send(RunLoopMessage::Transaction(tcp_error(&record, why))).ok();
+            }
+        }
+        self.connections.on_sends(interner, &diff);
+    }
+}
@@ -1,8 +1,42 @@
 #[macro_use]
 extern crate eve;
-
-use eve::ops::{Program, CodeTransaction};
-use eve::compiler::{parse_string};
+#[macro_use]
+extern crate serde_json;
+
+use eve::ops::{Program, ProgramRunner, CodeTransaction, Transaction, Fixpoint, TransactionStats, EvalLimits, RuntimeError, scoped_attribute, EstimateIterPool, RawChange, Internable, Interner, Constraint, QueryBudget, QueryDiff, IdGenerator, Value, RunLoopMessage, Field, growth_exponent};
+use eve::delivery::DeliveryLog;
+use eve::persister::{Persister, PersisterMessage};
+use eve::admin::{AdminCommand, AdminReply};
+use eve::indexes::{HashIndex, WatchDiff};
+use eve::watchers::{Watcher, WatcherErrors};
+use eve::watchers::plugin::{load_plugin, PluginError, PluginManifest, PluginWatcher};
+use eve::watchers::sdk::{self, SdkWatcher, Context, Diff, Fact, AbiVersion, ABI_VERSION};
+use eve::watchers::retry::{RetryPolicy, Backoff};
+use eve::watchers::circuit::{CircuitBreaker, CircuitState};
+use eve::watchers::sqlite::{SqliteWatcher, table_name};
+#[cfg(feature = "db-postgres")]
+use eve::watchers::postgres::{PostgresWatcher, bind_params, without_credentials};
+use eve::watchers::filewatch::FileWatchWatcher;
+use eve::watchers::file::FileWatcher;
+use eve::watchers::process::{ProcessWatcher, split_args};
+use eve::watchers::tcp::{TcpConnectWatcher, TcpListenWatcher};
+use eve::watchers::input::{InputConfig, InputError};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+use eve::check::{check_db, check_indexes};
+use eve::report::bundle_report;
+use eve::redact::{Redaction, RedactAction};
+use eve::scaffold::{find_template, new_project};
+use eve::lint::{lint_sources, Diagnostic, LintConfig, Severity};
+use eve::export::{export_json, export_eav, import_eav, parse_eav, ExportFilter};
+use eve::tutorial::{builtin_lessons, Lesson, Submission, Tutorial};
+use eve::compiler::{parse_string, parse_file_with, CompileOptions, CustomFunctions};
+use eve::bytecode::{read_compiled, write_compiled, CompiledError};
 
 //--------------------------------------------------------------------
 // Basic binds
@@ -52,6 +86,49 @@ test!(base_no_scans, {
     end
 });
 
+test!(base_modulo_and_power, {
+    search
+        x = 17 % 5
+        x = 2
+        y = 2 + 3 * 2 ^ 2
+        y = 14
+        z = 2 ^ 3 ^ 2
+        z = 512
+        w = 10 - 7 % 4
+        w = 7
+    bind
+        [#success]
+    end
+});
+
+test!(base_negative_literal, {
+    search
+        x = -5
+        x + 10 = 5
+        y = -2.5
+        y * 2 = -5
+    bind
+        [#success]
+    end
+});
+
+test!(base_unary_minus, {
+    commit
+        [#foo a: 3 b: 4]
+    end
+
+    search
+        [#foo a b]
+        -(a + b) = -7
+        z = -a
+        z = 0 - 3
+        w = - -b
+        w = 4
+    bind
+        [#success]
+    end
+});
+
 test!(base_no_scans_fail, {
     search
         x = 1 + 1
@@ -251,6 +328,677 @@ test!(base_join_nested_record, {
     end
 });
 
+//--------------------------------------------------------------------
+// Range scans
+//--------------------------------------------------------------------
+
+test!(base_range_scan, {
+    commit
+        [#person name: "ann" age: 20]
+        [#person name: "bo" age: 35]
+        [#person name: "cy" age: 50]
+        [#person name: "di" age: 51]
+    end
+
+    search
+        [#person name age]
+        age > 30
+        50 >= age
+        total = gather!/count![for: name]
+        total = 2
+    bind
+        [#success]
+    end
+});
+
+test!(base_range_scan_removal, {
+    commit
+        [#person name: "ann" age: 20]
+        [#person name: "bo" age: 35]
+    end
+
+    search
+        person = [#person name: "bo"]
+    commit
+        person.age := 10
+    end
+
+    search
+        not([#person age] age > 30)
+    bind
+        [#success]
+    end
+});
+
+#[test]
+fn base_range_scan_fused() {
+    let program = blocks!({
+        search
+            [#person age]
+            age > 30
+        bind
+            [#old age]
+        end
+    });
+    let fused = program.block_info.blocks.iter()
+        .flat_map(|block| block.constraints.iter())
+        .any(|constraint| match constraint { &Constraint::RangeScan {..} => true, _ => false });
+    assert!(fused, "Inequality wasn't fused into its scan");
+}
+
+test!(base_constant_folding, {
+    search
+        x = 2 + 3 * 4
+        y = "{{x}} apples"
+        x > 10
+        y = "14 apples"
+    bind
+        [#success]
+    end
+});
+
+#[test]
+fn base_constants_folded() {
+    let program = blocks!({
+        search
+            [#item value]
+            x = 2 + 3 * 4
+            y = random/number[seed: x]
+        bind
+            [#total value x y]
+        end
+    });
+    let ops:Vec<String> = program.block_info.blocks.iter()
+        .flat_map(|block| block.constraints.iter())
+        .filter_map(|constraint| match constraint { &Constraint::Function { ref op, .. } => Some(op.to_string()), _ => None })
+        .collect();
+    // the record's id depends on `y`, so its gen_id stays
+    assert_eq!(ops, vec!["random/number".to_string(), "gen_id".to_string()], "Constant arithmetic wasn't folded");
+}
+
+test!(base_access_scan_merging, {
+    commit
+        [#person address: [city: "Boston" zip: "02134"]]
+    end
+
+    search
+        p = [#person]
+        q = p
+        p.address.city = "Boston"
+        q.address.zip = "02134"
+    bind
+        [#success]
+    end
+});
+
+#[test]
+fn base_access_scans_merged() {
+    let program = blocks!({
+        search
+            p = [#person]
+            q = p
+            city = p.address.city
+            zip = q.address.zip
+        bind
+            [#label city zip]
+        end
+    });
+    let scans = program.block_info.blocks.iter()
+        .flat_map(|block| block.constraints.iter())
+        .filter(|constraint| match constraint { &&Constraint::Scan { .. } => true, _ => false })
+        .count();
+    // tag, address, city and zip, with address scanned once for both paths
+    assert_eq!(scans, 4, "Scans of the same path weren't merged");
+}
+
+// A block searching for `size` and `label` loaded into an empty program, then handed
+// `sizes` things with a size and `labels` with a label.
+fn replanned_program(sizes:usize, labels:usize) -> Program {
+    let mut program = Program::new("replan");
+    exec_code(&mut program, "search\n  [size]\n  [label: size]\nbind\n  [#match size]\nend\n", "replan.eve");
+    let mut builder = program.transaction();
+    for ix in 0..sizes {
+        builder = builder.insert(Internable::String(format!("thing|{}", ix)), "size", Internable::from_number(ix as f32));
+    }
+    for ix in 0..labels {
+        builder = builder.insert(Internable::String(format!("label|{}", ix)), "label", Internable::from_number(ix as f32));
+    }
+    builder.commit();
+    program
+}
+
+#[test]
+fn base_scans_replanned() {
+    let position = |program:&mut Program, attribute:&str| {
+        let attribute = s!(program, attribute);
+        program.block_info.blocks[0].constraints.iter().position(|constraint| match constraint {
+            &Constraint::Scan { a: Field::Value(a), .. } => a == attribute,
+            _ => false,
+        }).unwrap()
+    };
+    // once the index has grown, the far more selective scan goes first, whichever it is
+    let mut program = replanned_program(1000, 3);
+    assert!(position(&mut program, "label") < position(&mut program, "size"), "Scans weren't re-planned");
+    let mut flipped = replanned_program(3, 1000);
+    assert!(position(&mut flipped, "size") < position(&mut flipped, "label"), "Scans weren't re-planned");
+
+    // and the re-planned block still runs off the new facts
+    program.transaction().insert(Internable::String("label|3".to_string()), "label", Internable::from_number(3.0)).commit();
+    let tag = s!(program, "tag");
+    let matched = s!(program, "match");
+    assert_eq!(program.state.index.get(0, tag, matched).map_or(0, |iter| iter.count()), 4);
+}
+
+//--------------------------------------------------------------------
+// Queries
+//--------------------------------------------------------------------
+
+#[test]
+fn base_query_budget() {
+    let mut program = blocks!({
+        commit
+            [#item value: 1]
+            [#item value: 2]
+            [#item value: 3]
+            [#item value: 4]
+            [#item value: 5]
+        end
+    });
+    let source = "search\n  [#item value]\nproject (value)\nend";
+    let all = program.query(source, QueryBudget::unlimited()).unwrap();
+    assert!(all.complete);
+    assert_eq!(all.rows.len(), 5);
+
+    let partial = program.query(source, QueryBudget::unlimited().rows(2)).unwrap();
+    assert!(!partial.complete);
+    assert_eq!(partial.rows.len(), 2);
+    assert!(partial.rows.iter().all(|row| all.rows.contains(row)));
+
+    assert!(program.query("", QueryBudget::unlimited()).is_err());
+}
+
+#[test]
+fn base_query_sub_blocks() {
+    let mut program = blocks!({
+        commit
+            [#item value: 1]
+            [#item value: 2]
+            [#item value: 3]
+            [#banned value: 3]
+        end
+    });
+    let allowed = program.query("search\n  [#item value]\n  not([#banned value])\nproject (value)\nend", QueryBudget::unlimited()).unwrap();
+    let mut values:Vec<Internable> = allowed.rows.into_iter().map(|mut row| row.remove(0)).collect();
+    values.sort();
+    assert_eq!(values, vec![Internable::from_number(1.0), Internable::from_number(2.0)]);
+
+    let counted = program.query("search\n  [#item value]\n  total = gather/count[for: value]\nproject (total)\nend", QueryBudget::unlimited()).unwrap();
+    assert!(counted.rows.len() > 0);
+    assert!(counted.rows.iter().all(|row| row == &vec![Internable::from_number(3.0)]), "{:?}", counted.rows);
+    // the query's intermediates don't outlive it
+    assert_eq!(program.state.intermediates.keys().len(), 0);
+}
+
+#[test]
+fn base_solve_objective() {
+    let mut program = blocks!({
+        commit
+            [#shift person: "ann" cost: 30]
+            [#shift person: "bo" cost: 10]
+            [#shift person: "cy" cost: 20]
+            [#shift person: "di" cost: 10]
+        end
+    });
+    let cheapest = program.solve("search\n  [#shift person cost]\n  minimize cost\nproject (person cost)\nend", QueryBudget::unlimited()).unwrap();
+    assert!(cheapest.optimal);
+    assert_eq!(cheapest.row, Some(vec![Internable::String("bo".to_string()), Internable::from_number(10.0)]));
+    assert_eq!(cheapest.optimum, Some(Internable::from_number(10.0)));
+
+    let dearest = program.solve("search\n  [#shift person cost]\n  maximize cost\nproject (person cost)\nend", QueryBudget::unlimited()).unwrap();
+    assert!(dearest.optimal);
+    assert_eq!(dearest.optimum, Some(Internable::from_number(30.0)));
+
+    assert!(program.solve("search\n  [#shift person cost]\nproject (person cost)\nend", QueryBudget::unlimited()).is_err());
+    assert!(program.solve("search\n  [#shift person cost]\n  minimize cost\nproject (person)\nend", QueryBudget::unlimited()).is_err());
+}
+
+#[test]
+fn base_solve_objective_budget() {
+    let mut program = Program::new("test");
+    let mut txn = program.transaction();
+    for ix in 0..2000 {
+        let shift = Internable::String(format!("shift|{}", ix));
+        txn = txn.insert(shift.clone(), "tag", Internable::String("shift".to_string())).insert(shift, "cost", Internable::from_number(ix as f32));
+    }
+    txn.commit();
+    let source = "search\n  [#shift cost]\n  minimize cost\nproject (cost)\nend";
+    assert!(program.solve(source, QueryBudget::unlimited()).unwrap().optimal);
+    // running out of time can't prove anything, even when the best row was found
+    let rushed = program.solve(source, QueryBudget::unlimited().time(Duration::from_secs(0))).unwrap();
+    assert!(!rushed.optimal);
+}
+
+#[test]
+fn base_prepared_query() {
+    let mut program = blocks!({
+        commit
+            [#person name: "ann" age: 20]
+            [#person name: "bo" age: 35]
+            [#person name: "cy" age: 35]
+        end
+    });
+    let prepared = program.prepare("search\n  [#person name age: $age]\nproject (name)\nend").unwrap();
+    assert_eq!(prepared.params(), vec!["age"]);
+
+    let mut bindings = HashMap::new();
+    bindings.insert("age".to_string(), Internable::from_number(35.0));
+    let mut names:Vec<Internable> = prepared.exec(&mut program, &bindings, QueryBudget::unlimited()).unwrap()
+        .rows.into_iter().map(|mut row| row.remove(0)).collect();
+    names.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(names, vec![Internable::String("bo".to_string()), Internable::String("cy".to_string())]);
+
+    bindings.insert("age".to_string(), Internable::from_number(20.0));
+    let result = prepared.exec(&mut program, &bindings, QueryBudget::unlimited()).unwrap();
+    assert_eq!(result.rows, vec![vec![Internable::String("ann".to_string())]]);
+
+    assert!(prepared.exec(&mut program, &HashMap::new(), QueryBudget::unlimited()).is_err());
+}
+
+#[test]
+fn base_prepared_query_literal_dollar() {
+    let mut program = blocks!({
+        commit
+            [#note text: "$age"]
+            [#person name: "ann" age: 20]
+        end
+    });
+    // a string that happens to spell out a placeholder is still just a string
+    let prepared = program.prepare("search\n  [#note text: \"$age\"]\n  [#person name age: $age]\nproject (name)\nend").unwrap();
+    assert_eq!(prepared.params(), vec!["age"]);
+    let mut bindings = HashMap::new();
+    bindings.insert("age".to_string(), Internable::from_number(20.0));
+    for _ in 0..2 {
+        let result = prepared.exec(&mut program, &bindings, QueryBudget::unlimited()).unwrap();
+        assert_eq!(result.rows, vec![vec![Internable::String("ann".to_string())]]);
+    }
+
+    assert!(program.query("search\n  [#person name age: $age]\nproject (name)\nend", QueryBudget::unlimited()).is_err());
+}
+
+#[test]
+fn base_prepared_query_folding() {
+    let mut program = blocks!({
+        commit
+            [#person name: "ann" age: 20]
+            [#person name: "bo" age: 35]
+        end
+    });
+    // neither the filter nor the functions next to a placeholder can be folded ahead of time
+    let prepared = program.prepare("search\n  [#person name age]\n  age = $years + 10 + 5\n  $years != 5\n  lower = string/lowercase[text: $name]\n  lower = name\nproject (name)\nend").unwrap();
+    let mut bindings = HashMap::new();
+    bindings.insert("years".to_string(), Internable::from_number(20.0));
+    bindings.insert("name".to_string(), Internable::String("BO".to_string()));
+    let result = prepared.exec(&mut program, &bindings, QueryBudget::unlimited()).unwrap();
+    assert_eq!(result.rows, vec![vec![Internable::String("bo".to_string())]]);
+
+    bindings.insert("years".to_string(), Internable::from_number(5.0));
+    bindings.insert("name".to_string(), Internable::String("ANN".to_string()));
+    let result = prepared.exec(&mut program, &bindings, QueryBudget::unlimited()).unwrap();
+    assert_eq!(result.rows.len(), 0);
+}
+
+#[test]
+fn base_prepared_query_subscription() {
+    let mut program = blocks!({
+        commit
+            [#person name: "ann" age: 20]
+        end
+    });
+    let prepared = program.prepare("search\n  [#person name age: $age]\nproject (name)\nend").unwrap();
+    let mut bindings = HashMap::new();
+    bindings.insert("age".to_string(), Internable::from_number(20.0));
+    let subscription = prepared.subscribe(&mut program, &bindings).unwrap();
+    let ann = vec![Internable::String("ann".to_string())];
+    let bo = vec![Internable::String("bo".to_string())];
+    assert_eq!(subscription.rows, vec![ann.clone()]);
+    assert_eq!(subscription.changes().len(), 0);
+
+    let person = Internable::String("person|bo".to_string());
+    program.transaction()
+        .insert(person.clone(), "tag", Internable::String("person".to_string()))
+        .insert(person.clone(), "name", Internable::String("bo".to_string()))
+        .insert(person.clone(), "age", Internable::from_number(20.0))
+        .commit();
+    assert_eq!(subscription.changes(), vec![QueryDiff { adds: vec![bo.clone()], removes: vec![] }]);
+
+    // transactions that don't change the results don't send anything
+    program.transaction()
+        .insert(person.clone(), "height", Internable::from_number(180.0))
+        .commit();
+    assert_eq!(subscription.changes().len(), 0);
+
+    program.transaction()
+        .remove(person.clone(), "age", Internable::from_number(20.0))
+        .commit();
+    assert_eq!(subscription.changes(), vec![QueryDiff { adds: vec![], removes: vec![bo.clone()] }]);
+
+    let name = subscription.name().to_string();
+    program.unsubscribe(subscription);
+    assert!(program.block_info.block_names.get(&name).is_none());
+}
+
+#[test]
+fn base_dropped_subscription() {
+    let mut program = blocks!({
+        commit
+            [#person name: "ann"]
+        end
+    });
+    let watchers = program.stats().watchers;
+    let name = {
+        let prepared = program.prepare("search\n  [#person name]\nproject (name)\nend").unwrap();
+        let subscription = prepared.subscribe(&mut program, &HashMap::new()).unwrap();
+        assert_eq!(program.stats().watchers, watchers + 1);
+        subscription.name().to_string()
+    };
+    // dropping it is as good as unsubscribing once the next transaction comes along
+    program.transaction()
+        .insert(Internable::String("person|bo".to_string()), "tag", Internable::String("person".to_string()))
+        .commit();
+    assert!(program.block_info.block_names.get(&name).is_none());
+    assert_eq!(program.stats().watchers, watchers);
+}
+
+#[test]
+fn base_commit_deduplication() {
+    let mut program = Program::new("dedup");
+    exec_code(&mut program, "search\n  [#person]\ncommit\n  [#crowd]\nend\n", "crowd.eve");
+    let fixpoints = program.on_fixpoint();
+    let mut builder = program.transaction();
+    for name in ["ann", "bo", "cy"].iter() {
+        builder = builder.insert(Internable::String(format!("person|{}", name)), "tag", Internable::String("person".to_string()));
+    }
+    builder.commit();
+    // every person commits the same crowd, it only has to go in once
+    let seen:Vec<Fixpoint> = fixpoints.try_iter().collect();
+    assert_eq!(seen.len(), 1);
+    assert!(seen[0].stats.deduplicated >= 2, "Expected the repeated commits to be dropped, got {:?}", seen[0].stats);
+
+    // and once it's in, committing it again is dropped too
+    program.transaction()
+        .insert(Internable::String("person|dee".to_string()), "tag", Internable::String("person".to_string()))
+        .commit();
+    let seen:Vec<Fixpoint> = fixpoints.try_iter().collect();
+    assert!(seen[0].stats.deduplicated >= 1, "Expected the redundant commit to be dropped, got {:?}", seen[0].stats);
+    let tag = s!(program, "tag");
+    let crowd = s!(program, "crowd");
+    assert_eq!(program.state.index.get(0, tag, crowd).map_or(0, |iter| iter.count()), 1);
+}
+
+#[test]
+fn base_runaway_limits() {
+    let mut program = Program::new("runaway").with_limits(EvalLimits { max_rounds: 50, max_facts: 10_000 });
+    let fixpoints = program.on_fixpoint();
+    exec_code(&mut program, "search\n  s = [#switch state: \"on\"]\ncommit\n  s.state := \"off\"\nend\n\n\
+                             search\n  s = [#switch state: \"off\"]\ncommit\n  s.state := \"on\"\nend\n", "switch.eve");
+    fixpoints.try_iter().count();
+    assert!(program.last_error().is_none());
+
+    let switch = Internable::String("switch|1".to_string());
+    program.transaction()
+        .insert(switch.clone(), "tag", Internable::String("switch".to_string()))
+        .insert(switch.clone(), "state", Internable::String("on".to_string()))
+        .commit();
+    match program.last_error() {
+        Some(&RuntimeError::RoundLimit { limit, ref blocks }) => {
+            assert_eq!(limit, 50);
+            assert!(blocks.len() > 0 && blocks.iter().all(|block| block.starts_with("switch.eve|block|")), "Unexpected blocks {:?}", blocks);
+        }
+        other => panic!("Expected a round limit error, got {:?}", other),
+    }
+    // listeners still hear about it, with the error
+    let seen:Vec<Fixpoint> = fixpoints.try_iter().collect();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].error.as_ref(), program.last_error());
+    // the frame that was stopped is undone, so the switch is left in one state
+    let switch_id = program.state.interner.string_id("switch|1");
+    let state = s!(program, "state");
+    assert_eq!(program.state.index.get(switch_id, state, 0).map_or(0, |iter| iter.count()), 1);
+    assert_consistent(&program);
+
+    // the error rides along with the next transaction as a fact
+    let mut iter_pool = EstimateIterPool::new();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    let tag = program.state.interner.scoped_id("system", "tag");
+    let error = s!(program, "eve/error");
+    let kind_attribute = program.state.interner.scoped_id("system", "kind");
+    let kind = s!(program, "round-limit");
+    assert_eq!(find_entity(&program.state.index, tag, error), find_entity(&program.state.index, kind_attribute, kind));
+
+    // a transaction that reaches a fixpoint clears the error, and its record goes with
+    // the one after
+    program.transaction().insert(Internable::String("other|1".to_string()), "tag", Internable::String("other".to_string())).commit();
+    assert!(program.last_error().is_none());
+    assert!(fixpoints.try_iter().all(|fixpoint| fixpoint.error.is_none()));
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    assert_eq!(program.state.index.get(0, tag, error).map_or(0, |iter| iter.count()), 0);
+}
+
+#[test]
+fn base_runaway_frame() {
+    let mut program = Program::new("runaway frame").with_limits(EvalLimits { max_rounds: 200, max_facts: 100_000 });
+    // every counter binds the next one, so the first frame never runs out of rounds
+    exec_code(&mut program, "search\n  [#counter n]\n  m = n + 1\nbind\n  [#counter n: m]\nend\n", "counter.eve");
+    let fixpoints = program.on_fixpoint();
+    program.transaction()
+        .insert(Internable::String("counter|0".to_string()), "tag", Internable::String("counter".to_string()))
+        .insert(Internable::String("counter|0".to_string()), "n", Internable::from_number(0.0))
+        .commit();
+    match program.last_error() {
+        Some(&RuntimeError::RoundLimit { limit, ref blocks }) => {
+            assert_eq!(limit, 200);
+            assert_eq!(blocks, &vec!["counter.eve|block|1".to_string()]);
+        }
+        other => panic!("Expected a round limit error, got {:?}", other),
+    }
+    let seen:Vec<Fixpoint> = fixpoints.try_iter().collect();
+    assert_eq!(seen.len(), 1);
+    assert!(seen[0].stats.rounds <= 201, "The frame ran past its limit: {:?}", seen[0].stats);
+
+    // none of the counters it got to are left behind, nor is the input that set it off
+    let tag = s!(program, "tag");
+    let counter = s!(program, "counter");
+    assert_eq!(program.state.index.get(0, tag, counter).map_or(0, |iter| iter.count()), 0);
+    assert_consistent(&program);
+    let mut iter_pool = EstimateIterPool::new();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    assert_eq!(program.state.index.get(0, tag, counter).map_or(0, |iter| iter.count()), 0);
+    assert_consistent(&program);
+}
+
+#[test]
+fn base_function_type_errors() {
+    let mut program = Program::new("type errors");
+    exec_code(&mut program, "search\n  [#item price]\n  total = price * 2\nbind\n  [#priced total]\nend\n", "prices.eve");
+    program.transaction()
+        .insert(Internable::String("item|1".to_string()), "tag", Internable::String("item".to_string()))
+        .insert(Internable::String("item|1".to_string()), "price", Internable::from_number(3.0))
+        .insert(Internable::String("item|2".to_string()), "tag", Internable::String("item".to_string()))
+        .insert(Internable::String("item|2".to_string()), "price", Internable::String("cheap".to_string()))
+        .commit();
+
+    // the string price just doesn't bind a total
+    let tag = s!(program, "tag");
+    let priced = s!(program, "priced");
+    assert_eq!(program.state.index.get(0, tag, priced).map_or(0, |iter| iter.count()), 1);
+
+    let mut iter_pool = EstimateIterPool::new();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    let kind_attribute = program.state.interner.scoped_id("system", "kind");
+    let kind = s!(program, "type-error");
+    let error = find_entity(&program.state.index, kind_attribute, kind);
+    let function_attribute = program.state.interner.scoped_id("system", "function");
+    let function = s!(program, "*");
+    assert_eq!(find_entity(&program.state.index, function_attribute, function), error);
+    let value_attribute = program.state.interner.scoped_id("system", "value");
+    let cheap = s!(program, "cheap");
+    assert!(program.state.index.check(error, value_attribute, cheap));
+    let block_attribute = program.state.interner.scoped_id("system", "block");
+    let block = s!(program, "prices.eve|block|1");
+    assert!(program.state.index.check(error, block_attribute, block));
+
+    // the same constraint failing again updates its record rather than adding another
+    program.transaction()
+        .insert(Internable::String("item|3".to_string()), "tag", Internable::String("item".to_string()))
+        .insert(Internable::String("item|3".to_string()), "price", Internable::String("free".to_string()))
+        .commit();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    assert_eq!(program.state.index.get(0, kind_attribute, kind).map_or(0, |iter| iter.count()), 1);
+    let free = s!(program, "free");
+    assert!(program.state.index.check(error, value_attribute, free));
+    assert!(!program.state.index.check(error, value_attribute, cheap));
+
+    // and once the block runs cleanly the record goes away
+    program.transaction()
+        .remove(Internable::String("item|2".to_string()), "price", Internable::String("cheap".to_string()))
+        .insert(Internable::String("item|2".to_string()), "price", Internable::from_number(4.0))
+        .remove(Internable::String("item|3".to_string()), "price", Internable::String("free".to_string()))
+        .insert(Internable::String("item|3".to_string()), "price", Internable::from_number(5.0))
+        .commit();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    assert_eq!(program.state.index.get(0, kind_attribute, kind).map_or(0, |iter| iter.count()), 0);
+}
+
+#[test]
+fn base_schema_references() {
+    let mut program = Program::new("schema");
+    program.declare_reference("friend");
+    exec_code(&mut program, "search\n  [#person name]\nbind\n  [#greeting friend: name]\nend\n", "greet.eve");
+    let ann = Internable::Reference("person|ann|".to_string());
+    program.transaction()
+        .insert(ann.clone(), "tag", Internable::String("person".to_string()))
+        .insert(ann.clone(), "name", Internable::String("ann".to_string()))
+        .insert(ann.clone(), "friend", Internable::String("person|bo|".to_string()))
+        .insert(ann.clone(), "nickname", Internable::String("person|bo|".to_string()))
+        .commit();
+
+    // a string given for a reference attribute is the record it names, anywhere else it stays a string
+    let ann_id = program.state.interner.internable_to_id(ann.clone());
+    let bo = program.state.interner.internable_to_id(Internable::Reference("person|bo|".to_string()));
+    let bo_string = s!(program, "person|bo|");
+    let friend = s!(program, "friend");
+    let nickname = s!(program, "nickname");
+    assert!(program.state.index.check(ann_id, friend, bo));
+    assert!(!program.state.index.check(ann_id, friend, bo_string));
+    assert!(program.state.index.check(ann_id, nickname, bo_string));
+
+    // a block that binds a string there is reported
+    let mut iter_pool = EstimateIterPool::new();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    let kind_attribute = program.state.interner.scoped_id("system", "kind");
+    let kind = s!(program, "schema-error");
+    let error = find_entity(&program.state.index, kind_attribute, kind);
+    let attribute_attribute = program.state.interner.scoped_id("system", "attribute");
+    assert!(program.state.index.check(error, attribute_attribute, friend));
+    let value_attribute = program.state.interner.scoped_id("system", "value");
+    let name = s!(program, "ann");
+    assert!(program.state.index.check(error, value_attribute, name));
+}
+
+#[test]
+fn base_fixpoint_notifications() {
+    let mut program = Program::new("fixpoint");
+    exec_code(&mut program, "search\n  [#person name]\nbind\n  [#greeting name]\nend\n", "greet.eve");
+    // flush the @system facts about the block so they aren't counted below
+    let mut iter_pool = EstimateIterPool::new();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    let fixpoints = program.on_fixpoint();
+    let person = Internable::String("person|ann".to_string());
+    program.transaction()
+        .insert(person.clone(), "tag", Internable::String("person".to_string()))
+        .insert(person.clone(), "name", Internable::String("ann".to_string()))
+        .commit();
+    let seen:Vec<Fixpoint> = fixpoints.try_iter().collect();
+    assert_eq!(seen.len(), 1);
+    // the person's two facts plus the greeting's tag and name
+    assert_eq!(seen[0].stats.added, 4);
+    assert_eq!(seen[0].stats.removed, 0);
+    assert!(seen[0].stats.rounds >= 2, "The bind didn't run in a later round");
+    // and the block's refreshed @system counters
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    fixpoints.try_iter().count();
+
+    program.transaction()
+        .remove(person.clone(), "name", Internable::String("ann".to_string()))
+        .commit();
+    let seen:Vec<Fixpoint> = fixpoints.try_iter().collect();
+    assert_eq!(seen.len(), 1);
+    assert_eq!(seen[0].stats.removed, 3);
+    assert!(seen[0].transaction > 1);
+
+    drop(fixpoints);
+    program.transaction().insert(person.clone(), "age", Internable::from_number(30.0)).commit();
+}
+
+#[test]
+fn base_view() {
+    let mut program = Program::new("views");
+    exec_code(&mut program, "commit\n  [#person name: \"ann\" age: 20]\n  [#person name: \"cy\" age: 12]\nend\n\n\
+                             eve:block name: \"adults\"\nsearch\n  [#person name age]\n  age >= 18\nproject (name, age)\nend\n", "people.eve");
+    assert!(program.view("people.eve|block|1").is_err(), "Viewed a block without a project section");
+    assert!(program.view("children").is_err());
+
+    let mut view = program.view("adults").unwrap();
+    assert_eq!(view.typed::<(String, f64)>(), vec![("ann".to_string(), 20.0)]);
+
+    let person = Internable::String("person|bo".to_string());
+    program.transaction()
+        .insert(person.clone(), "tag", Internable::String("person".to_string()))
+        .insert(person.clone(), "name", Internable::String("bo".to_string()))
+        .insert(person.clone(), "age", Internable::from_number(30.0))
+        .commit();
+    let mut adults = view.typed::<(String, f64)>();
+    adults.sort_by(|a, b| a.0.cmp(&b.0));
+    assert_eq!(adults, vec![("ann".to_string(), 20.0), ("bo".to_string(), 30.0)]);
+    // rows that don't fit the type are left out
+    assert!(view.typed::<(f64, String)>().is_empty());
+
+    program.transaction()
+        .remove(person.clone(), "age", Internable::from_number(30.0))
+        .commit();
+    assert_eq!(view.rows(), vec![vec![Value::String("ann".to_string()), Value::Number(20.0)]]);
+
+    let name = view.name().to_string();
+    program.close_view(view);
+    assert!(program.block_info.block_names.get(&name).is_none());
+}
+
+#[test]
+fn base_tutorial() {
+    let lesson = Lesson::parse("# Hello\n\nSay hello.\n\n```check\nsearch\n  [#hello text]\nproject (text)\nend\n```\n");
+    assert_eq!(lesson.title, "Hello");
+    assert_eq!(lesson.text, "# Hello\n\nSay hello.");
+    assert_eq!(lesson.checks, vec!["search\n  [#hello text]\nproject (text)\nend\n".to_string()]);
+
+    let mut tutorial = Tutorial::new(builtin_lessons());
+    assert!(!tutorial.passed(), "The first lesson passed before anything was submitted");
+    assert_eq!(tutorial.submit("commit\n  [#greeting text: \"goodbye\"]\nend\n"), Submission::Added);
+    assert_eq!(tutorial.submit("commit\n  [#greeting text: \"hello world\"]\nend\n"), Submission::Passed);
+
+    // the search lesson's checks include one that uses not
+    tutorial.next_lesson();
+    assert_eq!(tutorial.progress().0, 2);
+    assert_eq!(tutorial.submit("search\n  [#person name]\nbind\n  [#adult name]\nend\n"), Submission::Added);
+    let mut tutorial = Tutorial::new(builtin_lessons());
+    tutorial.next_lesson();
+    assert_eq!(tutorial.submit("search\n  [#person name age]\n  age >= 18\nbind\n  [#adult name]\nend\n"), Submission::Passed);
+
+    tutorial.next_lesson();
+    assert_eq!(tutorial.submit("search\n  [#counter count]\nbind\n  [#counter-label text: \"Count: {{count}}\"]\nend\n"), Submission::Passed);
+    tutorial.next_lesson();
+    assert!(tutorial.finished());
+}
+
 //--------------------------------------------------------------------
 // Strings
 //--------------------------------------------------------------------
@@ -490,6 +1238,35 @@ test!(base_eve_auto_index, {
 // CommitLookup
 //--------------------------------------------------------------------
 
+test!(base_lookup_record, {
+    commit
+        [#foo a: 1 b: "two"]
+    end
+
+    search
+        foo = [#foo]
+        lookup![record: foo, attribute, value]
+        attribute != "tag"
+        total = gather!/count![for: attribute]
+    bind
+        [#attributes total]
+    end
+
+    search
+        foo = [#foo]
+        lookup![record: foo, attribute: "b", value]
+    bind
+        [#found value]
+    end
+
+    search
+        [#attributes total: 2]
+        [#found value: "two"]
+    bind
+        [#success]
+    end
+});
+
 test!(base_commit_lookup, {
     commit
         [#foo]
@@ -1040,6 +1817,58 @@ test!(base_union_record_multireturn, {
     end
 });
 
+test!(base_nested_search, {
+    commit
+        [#order number: 1 customer: [#customer name: "chris" address: [#address city: "Portland"]]]
+        [#order number: 2 customer: [#customer name: "sam" address: [#address city: "Seattle"]]]
+    end
+
+    search
+        [#order number customer: [#customer name address: [#address city: "Portland"]]]
+        number = 1
+        name = "chris"
+    bind
+        [#success]
+    end
+});
+
+test!(base_tag_union, {
+    commit
+        [#dog name: "rex"]
+        [#cat name: "tom"]
+        [#bird name: "tweety"]
+    end
+
+    search
+        [#dog|#cat name]
+    bind
+        [#pet name]
+    end
+
+    search
+        [#pet name: "rex"]
+        [#pet name: "tom"]
+        not([#pet name: "tweety"])
+    bind
+        [#success]
+    end
+});
+
+test!(base_nested_search_not, {
+    commit
+        [#order number: 1 customer: [#customer name: "chris"]]
+        [#order number: 2]
+    end
+
+    search
+        [#order number]
+        not([#order number customer: [#customer]])
+        number = 2
+    bind
+        [#success]
+    end
+});
+
 //--------------------------------------------------------------------
 // Update Operators
 //--------------------------------------------------------------------
@@ -1137,6 +1966,28 @@ test!(base_update_set_none, {
     end
 });
 
+test!(base_update_all_set, {
+    search
+        foo = [#foo]
+    commit
+        update all foo set bar := "fleeb", baz := none
+    end
+
+    commit
+        [#foo bar: "baz" baz: 1]
+        [#foo bar: "quux" baz: 2]
+    end
+
+    search
+        not([#foo bar: "baz"])
+        not([#foo bar: "quux"])
+        not([#foo baz])
+        [#foo bar: "fleeb"]
+    bind
+        [#success]
+    end
+});
+
 test!(base_update_merge, {
     search
         foo = [#foo]
@@ -2192,3 +3043,2604 @@ test!(base_aggregate_prev_rounds_removal, {
         [#success]
     end
 });
+
+test!(base_objective_minimize, {
+    search
+        [#shift name cost]
+        minimize cost
+    bind
+        [#cheapest name cost]
+    end
+
+    commit
+        [#shift name: "early" cost: 5]
+        [#shift name: "late" cost: 3]
+        [#shift name: "night" cost: 8]
+    end
+
+    search
+        [#cheapest name: "late" cost: 3]
+        not([#cheapest name: "early"])
+        not([#cheapest name: "night"])
+    bind
+        [#success]
+    end
+});
+
+test!(base_objective_minimize_total, {
+    search
+        a = [#worker name: first rate: r1]
+        b = [#worker name: second rate: r2]
+        first < second
+        total!-cost = r1 + r2
+        minimize total!-cost
+    bind
+        [#pair first second total!-cost]
+    end
+
+    commit
+        [#worker name: "ann" rate: 4]
+        [#worker name: "bob" rate: 1]
+        [#worker name: "cat" rate: 2]
+    end
+
+    search
+        [#pair first: "bob" second: "cat" total!-cost: 3]
+        not([#pair first: "ann"])
+    bind
+        [#success]
+    end
+});
+
+test!(base_objective_minimize_ties, {
+    search
+        [#shift name cost]
+        minimize cost
+    bind
+        [#cheapest name]
+    end
+
+    commit
+        [#shift name: "early" cost: 3]
+        [#shift name: "late" cost: 3]
+        [#shift name: "night" cost: 8]
+    end
+
+    search
+        [#cheapest name: "early"]
+        [#cheapest name: "late"]
+        not([#cheapest name: "night"])
+    bind
+        [#success]
+    end
+});
+
+test!(base_objective_minimize_remove, {
+    search
+        [#shift name cost]
+        minimize cost
+    bind
+        [#cheapest name]
+    end
+
+    commit
+        [#shift name: "early" cost: 5]
+        [#shift name: "late" cost: 3]
+        [#shift name: "night" cost: 8]
+    end
+
+    search
+        shift = [#shift name: "late"]
+    commit
+        shift := none
+    end
+
+    search
+        [#cheapest name: "early"]
+        not([#cheapest name: "late"])
+    bind
+        [#success]
+    end
+});
+
+test!(base_objective_maximize, {
+    search
+        [#shift name cost]
+        maximize cost * 2
+    bind
+        [#priciest name]
+    end
+
+    commit
+        [#shift name: "early" cost: 5]
+        [#shift name: "late" cost: 3]
+        [#shift name: "night" cost: 8]
+    end
+
+    search
+        [#priciest name: "night"]
+        not([#priciest name: "early"])
+    bind
+        [#success]
+    end
+});
+
+//--------------------------------------------------------------------
+// Multiplicity
+//--------------------------------------------------------------------
+
+#[test]
+fn base_retract_one_of_many_derivations() {
+    let mut program = blocks!({
+        search
+            [#source value]
+        bind
+            [#derived value]
+        end
+    });
+    let a = Internable::Reference("source|a|".to_string());
+    let b = Internable::Reference("source|b|".to_string());
+    let source = Internable::String("source".to_string());
+    let one = Internable::from_number(1.0);
+    program.transaction()
+        .insert(a.clone(), "tag", source.clone())
+        .insert(a.clone(), "value", one.clone())
+        .insert(b.clone(), "tag", source.clone())
+        .insert(b.clone(), "value", one.clone())
+        .commit();
+
+    let tag = s!(program, "tag");
+    let derived = s!(program, "derived");
+    let record = find_entity(&program.state.index, tag, derived);
+    assert_eq!(program.state.distinct_index.support(record, tag, derived), 2);
+
+    program.transaction()
+        .remove(a.clone(), "tag", source.clone())
+        .remove(a.clone(), "value", one.clone())
+        .commit();
+    assert_eq!(program.state.distinct_index.support(record, tag, derived), 1);
+    assert!(program.state.distinct_index.is_available(record, tag, derived), "Derived fact lost a surviving derivation");
+
+    program.transaction()
+        .remove(b.clone(), "tag", source.clone())
+        .remove(b.clone(), "value", one.clone())
+        .commit();
+    assert_eq!(program.state.distinct_index.support(record, tag, derived), 0);
+    assert!(!program.state.index.check(record, tag, derived), "Derived fact outlived its derivations");
+}
+
+#[test]
+fn base_duplicate_commits_are_set_like() {
+    let mut program = Program::new("test");
+    let a = Internable::Reference("source|a|".to_string());
+    let source = Internable::String("source".to_string());
+    program.transaction().insert(a.clone(), "tag", source.clone()).commit();
+    program.transaction().insert(a.clone(), "tag", source.clone()).commit();
+    program.transaction().remove(a.clone(), "tag", source.clone()).commit();
+    let tag = s!(program, "tag");
+    let source = s!(program, "source");
+    assert!(program.state.index.get(0, tag, source).map_or(true, |mut found| found.next().is_none()), "Committed fact counted twice");
+}
+
+//--------------------------------------------------------------------
+// Provenance
+//--------------------------------------------------------------------
+
+#[test]
+fn base_explain_fact() {
+    let mut program = blocks!({
+        search
+            [#order item]
+        bind
+            [#receipt item]
+        end
+
+        search
+            [#receipt item]
+        bind
+            [#printed item]
+        end
+    });
+    program.track_provenance(true);
+    let order = Internable::String("order|1".to_string());
+    program.transaction()
+        .insert(order.clone(), "tag", Internable::String("order".to_string()))
+        .insert(order.clone(), "item", Internable::String("tea".to_string()))
+        .commit();
+
+    let tag = s!(program, "tag");
+    let printed = s!(program, "printed");
+    let entity = find_entity(&program.state.index, tag, printed);
+    let explanation = program.explain_fact(entity, tag, printed);
+    assert_eq!(explanation.derivations.len(), 1);
+    let derivation = &explanation.derivations[0];
+    assert_eq!(derivation.block, "test|block|2");
+    assert!(derivation.inputs.iter().all(|input| input.derivations.len() == 1 && input.derivations[0].block == "test|block|1"));
+    let from_order = &derivation.inputs[0].derivations[0];
+    assert!(from_order.inputs.iter().all(|input| input.derivations.len() == 0));
+    assert!(from_order.inputs.iter().any(|input| input.e == s!(program, "order|1")));
+
+    program.track_provenance(false);
+    assert_eq!(program.explain_fact(entity, tag, printed).derivations.len(), 0);
+}
+
+#[test]
+fn base_provenance_forgets_removed_facts() {
+    let mut program = blocks!({
+        search
+            [#order item]
+        commit
+            [#shipment item]
+        end
+    });
+    program.track_provenance(true);
+    let order = Internable::String("order|1".to_string());
+    program.transaction()
+        .insert(order.clone(), "tag", Internable::String("order".to_string()))
+        .insert(order.clone(), "item", Internable::String("tea".to_string()))
+        .commit();
+    let tag = s!(program, "tag");
+    let shipment = s!(program, "shipment");
+    let entity = find_entity(&program.state.index, tag, shipment);
+    assert_eq!(program.explain_fact(entity, tag, shipment).derivations.len(), 1);
+    let tracked = program.state.provenance.as_ref().unwrap().len();
+
+    // the commit is never retracted by its block, but the fact can still go away
+    let shipment_id = program.state.interner.get_value(entity).clone();
+    program.transaction().remove(shipment_id.clone(), "tag", Internable::String("shipment".to_string())).commit();
+    assert_eq!(program.state.provenance.as_ref().unwrap().len(), tracked - 1);
+
+    // put back by hand, it has no derivation left over from the block
+    program.transaction().insert(shipment_id, "tag", Internable::String("shipment".to_string())).commit();
+    assert_eq!(program.explain_fact(entity, tag, shipment).derivations.len(), 0);
+}
+
+#[test]
+fn base_why_not() {
+    let mut program = blocks!({
+        commit
+            [#person name: "ann" age: 20]
+            [#person name: "bo" age: 40]
+        end
+
+        search
+            [#person name age]
+            age > 30
+        bind
+            [#senior name]
+        end
+    });
+    let pattern = |name:&str| vec![("tag".to_string(), Internable::String("senior".to_string())),
+                                   ("name".to_string(), Internable::String(name.to_string()))];
+
+    let ann = program.why_not(&pattern("ann"));
+    assert_eq!(ann.len(), 1);
+    assert_eq!(ann[0].block, "test|block|2");
+    assert!(ann[0].matched.len() >= 3);
+    assert!(ann[0].failed.as_ref().unwrap().starts_with("filter"), "Unexpected failure: {:?}", ann[0].failed);
+
+    let bo = program.why_not(&pattern("bo"));
+    assert_eq!(bo[0].failed, None);
+
+    let nobody = program.why_not(&vec![("tag".to_string(), Internable::String("unicorn".to_string()))]);
+    assert_eq!(nobody.len(), 0);
+}
+
+//--------------------------------------------------------------------
+// History
+//--------------------------------------------------------------------
+
+#[test]
+fn base_history_rewind_replay() {
+    let mut program = blocks!({
+        search
+            [#click button]
+        commit
+            [#clicked button]
+        end
+    });
+    program.record_history(true);
+    let mut iter_pool = EstimateIterPool::new();
+    let node = Internable::String("test".to_string());
+    for button in 1..3 {
+        let click = Internable::Reference(format!("click|{}|", button));
+        let mut txn = Transaction::new(&mut iter_pool);
+        txn.input_change(RawChange::new(click.clone(), Internable::String("tag".to_string()), Internable::String("click".to_string()), node.clone(), 1).to_change(&mut program.state.interner));
+        txn.input_change(RawChange::new(click, Internable::String("button".to_string()), Internable::from_number(button as f32), node.clone(), 1).to_change(&mut program.state.interner));
+        txn.exec(&mut program, &mut None);
+    }
+    let tag = s!(program, "tag");
+    let clicked = s!(program, "clicked");
+    let count = |program:&Program| program.state.index.get(0, tag, clicked).map_or(0, |iter| iter.count());
+    assert_eq!(program.history().unwrap().len(), 2);
+    assert_eq!(count(&program), 2);
+
+    let diff = program.history().unwrap().diff(1, 2);
+    assert!(diff.iter().any(|change| change.v == Internable::String("clicked".to_string()) && change.count == 1), "Second commit missing from diff: {:?}", diff);
+
+    program.rewind(1).unwrap();
+    assert_eq!(program.history().unwrap().position(), 1);
+    assert_eq!(count(&program), 1, "Rewind left the second click's commit behind");
+    assert!(program.rewind(2).is_err());
+
+    program.replay(2).unwrap();
+    assert_eq!(program.history().unwrap().position(), 2);
+    assert_eq!(count(&program), 2, "Replay didn't recommit the second click");
+}
+
+//--------------------------------------------------------------------
+// Recursion
+//--------------------------------------------------------------------
+
+test!(base_transitive_closure, {
+    commit
+        [#edge from: 1 to: 2]
+        [#edge from: 2 to: 3]
+        [#edge from: 3 to: 4]
+        [#edge from: 4 to: 5]
+        [#edge from: 5 to: 6]
+    end
+
+    search
+        [#edge from to]
+    bind
+        [#path from to]
+    end
+
+    search
+        [#path from to: mid]
+        [#edge from: mid to]
+    bind
+        [#path from to]
+    end
+
+    search
+        path = [#path]
+        total = gather!/count![for: path]
+        total = 15
+        [#path from: 1 to: 6]
+    bind
+        [#success]
+    end
+});
+
+// A chain of `length` edges with its transitive closure derived, and the stats of
+// the transaction that then adds the edge `from` -> `to`.
+fn closure_delta(length:usize, from:usize, to:usize) -> TransactionStats {
+    let mut program = Program::new("closure");
+    exec_code(&mut program, "search\n  [#edge from to]\nbind\n  [#path from to]\nend\n\n\
+                             search\n  [#path from to: mid]\n  [#edge from: mid to]\nbind\n  [#path from to]\nend\n", "closure.eve");
+    let edge = |program:&mut Program, from:usize, to:usize| {
+        let e = Internable::String(format!("edge|{}|{}", from, to));
+        program.transaction()
+            .insert(e.clone(), "tag", Internable::String("edge".to_string()))
+            .insert(e.clone(), "from", Internable::from_number(from as f32))
+            .insert(e, "to", Internable::from_number(to as f32))
+            .commit();
+    };
+    for ix in 0..length {
+        edge(&mut program, ix, ix + 1);
+    }
+    // flush the @system counters the chain left queued
+    let mut iter_pool = EstimateIterPool::new();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    let fixpoints = program.on_fixpoint();
+    edge(&mut program, from, to);
+    let seen:Vec<Fixpoint> = fixpoints.try_iter().collect();
+    assert_eq!(seen.len(), 1);
+    seen[0].stats
+}
+
+#[test]
+fn base_transitive_closure_delta() {
+    // an edge off to the side adds one path however big the closure already is
+    let small = closure_delta(10, 100, 101);
+    let large = closure_delta(30, 100, 101);
+    assert_eq!(small.added, 6);
+    assert_eq!((small.added, small.changes), (large.added, large.changes));
+
+    // extending the chain adds a path from every node on it, and the work follows
+    // those new paths rather than the 55 or 465 that were already there
+    let small = closure_delta(10, 10, 11);
+    let large = closure_delta(30, 30, 31);
+    assert_eq!(small.added, 3 + 3 * 11);
+    assert_eq!(large.added, 3 + 3 * 31);
+    assert!(large.changes * 11 <= small.changes * 31 * 2, "Work grew with the closure: {:?} vs {:?}", small, large);
+}
+
+//--------------------------------------------------------------------
+// Entity merge
+//--------------------------------------------------------------------
+
+fn find_entity(index:&HashIndex, a:u32, v:u32) -> u32 {
+    index.get(0, a, v).expect("No matching entity").next().unwrap()
+}
+
+// Every fact the distinct index counts is in the index and nothing else is.
+fn assert_consistent(program:&Program) {
+    for &(e, a, v) in program.state.distinct_index.eavs.keys() {
+        assert_eq!(program.state.distinct_index.is_available(e, a, v), program.state.index.check(e, a, v),
+                   "{:?}", (program.state.interner.get_value(e), program.state.interner.get_value(a), program.state.interner.get_value(v)));
+    }
+}
+
+#[test]
+fn base_entity_merge() {
+    let mut program = blocks!({
+        commit
+            [#person name: "Chris" email: "chris@example.com"]
+            [#person name: "Christopher" phone: "555-1234"]
+        end
+
+        search
+            merge = [#person name: "Christopher"]
+        commit
+            [#note about: merge]
+        end
+
+        search
+            person = [#person email: "chris@example.com" phone: "555-1234"]
+            [#note about: person]
+        bind
+            [#success]
+        end
+    });
+    let name = s!(program, "name");
+    let keep = find_entity(&program.state.index, name, s!(program, "Chris"));
+    let merge = find_entity(&program.state.index, name, s!(program, "Christopher"));
+    let keep_value = program.state.interner.get_value(keep).clone();
+    let merge_value = program.state.interner.get_value(merge).clone();
+    program.merge_entities(keep_value, merge_value);
+
+    let tag = s!(program, "tag");
+    let success = s!(program, "success");
+    let found = find_entity(&program.state.index, tag, success);
+    assert!(program.state.distinct_index.is_available(found, tag, success), "No success record");
+    assert!(program.state.index.entity_facts(merge).is_empty(), "Merged entity still has facts");
+}
+
+//--------------------------------------------------------------------
+// Scopes
+//--------------------------------------------------------------------
+
+test!(base_scope_separate, {
+    commit @session
+        [#user name: "chris"]
+    end
+
+    search @session
+        [#user name]
+    bind
+        [#greeting name]
+    end
+
+    search
+        [#greeting name: "chris"]
+        not([#user])
+    bind
+        [#success]
+    end
+});
+
+// attributes only known once the block runs are scoped too
+test!(base_scope_dynamic_attributes, {
+    commit @session
+        [#pref theme: "dark"]
+    end
+
+    commit
+        [#pref theme: "light"]
+        [#setting name: "color" value: "blue"]
+    end
+
+    search @session
+        p = [#pref]
+        lookup[record: p, attribute, value]
+    bind
+        [#seen attribute value]
+    end
+
+    search
+        s = [#setting name value]
+    commit @session
+        lookup[record: s, attribute: name, value]
+    end
+
+    search @session
+        lookup[record, attribute: "color", value: "blue"]
+    bind
+        [#colored record]
+    end
+
+    search
+        [#seen attribute: "theme" value: "dark"]
+        not([#seen value: "light"])
+        [#colored]
+    bind
+        [#success]
+    end
+});
+
+test!(base_system_blocks, {
+    search @system
+        [#system!/block name constraints]
+        constraints > 0
+    bind
+        [#success]
+    end
+});
+
+#[test]
+fn base_system_block_stats() {
+    let mut program = blocks!({
+        commit
+            [#order item: "tea"]
+        end
+
+        search
+            [#order item]
+        bind
+            [#receipt item]
+        end
+    });
+    // the counters from loading the blocks land with the next transaction
+    let mut iter_pool = EstimateIterPool::new();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+
+    let stats = program.admin_blocks().into_iter().find(|block| block.name == "test|block|2").unwrap().stats;
+    assert!(stats.transactions > 0 && stats.changes > 0, "No runtime stats for the block: {:?}", stats);
+    let block = program.state.interner.internable_to_id(Internable::Reference("system/block|test|block|2|".to_string()));
+    let transactions = program.state.interner.scoped_id("system", "transactions");
+    let count = program.state.interner.number_id(stats.transactions as f32);
+    assert!(program.state.index.check(block, transactions, count), "Counters missing from @system");
+
+    let enabled = program.state.interner.scoped_id("system", "enabled");
+    let yes = s!(program, "true");
+    let no = s!(program, "false");
+    assert!(program.state.index.check(block, enabled, yes));
+    program.disable_block("test|block|2");
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+    assert!(program.state.index.check(block, enabled, no), "Disabled block still reported as enabled");
+    assert!(!program.state.index.check(block, enabled, yes));
+}
+
+#[test]
+fn base_scope_event_expires() {
+    let mut program = blocks!({
+        commit @event
+            [#click button: 1]
+        end
+
+        search @event
+            [#click button]
+        commit
+            [#clicked button]
+        end
+    });
+    let tag = s!(program, "tag");
+    let clicked = s!(program, "clicked");
+    let found = find_entity(&program.state.index, tag, clicked);
+    assert!(program.state.distinct_index.is_available(found, tag, clicked), "No clicked record");
+
+    let event_tag = program.state.interner.scoped_id("event", "tag");
+    let click = s!(program, "click");
+    let remaining = program.state.index.get(0, event_tag, click).map_or(0, |iter| iter.count());
+    assert_eq!(remaining, 0, "Event facts outlived their transaction");
+}
+
+#[test]
+fn base_scope_readonly() {
+    let mut program = blocks!({
+        search
+            [#trigger]
+        commit @reference
+            [#country name: "Nowhere"]
+        end
+
+        search @system
+            [#system!/error kind: "readonly" scope: "reference" attribute: "name"]
+        bind
+            [#rejected]
+        end
+    });
+    program.set_scope_readonly("reference", true);
+    assert!(program.is_scope_readonly("reference"));
+
+    // loading the reference data from outside still works
+    let node = Internable::String("test".to_string());
+    let country = Internable::Reference("country|1|".to_string());
+    program.annotated_transaction(vec![
+        RawChange::new(country.clone(), scoped_attribute("reference", "tag"), Internable::String("country".to_string()), node.clone(), 1),
+        RawChange::new(country, scoped_attribute("reference", "name"), Internable::String("Somewhere".to_string()), node.clone(), 1),
+    ], vec![]);
+    program.annotated_transaction(vec![
+        RawChange::new(Internable::Reference("trigger|1|".to_string()), Internable::String("tag".to_string()), Internable::String("trigger".to_string()), node.clone(), 1),
+    ], vec![]);
+
+    let reference_name = program.state.interner.scoped_id("reference", "name");
+    let somewhere = s!(program, "Somewhere");
+    let nowhere = s!(program, "Nowhere");
+    assert!(program.state.index.get(0, reference_name, somewhere).map_or(0, |iter| iter.count()) > 0, "Reference data wasn't loaded");
+    assert!(program.state.index.get(0, reference_name, nowhere).map_or(0, |iter| iter.count()) == 0, "A block committed to a read-only scope");
+    let tag = s!(program, "tag");
+    let rejected = s!(program, "rejected");
+    let found = find_entity(&program.state.index, tag, rejected);
+    assert!(program.state.distinct_index.is_available(found, tag, rejected), "No error for the rejected commit");
+    let system_tag = program.state.interner.scoped_id("system", "tag");
+    let system_error = s!(program, "system/error");
+    let error = find_entity(&program.state.index, system_tag, system_error);
+    let block_attribute = program.state.interner.scoped_id("system", "block");
+    let blocks:Vec<String> = program.state.index.get(error, block_attribute, 0).map(|iter| iter.map(|v| Internable::to_string(program.state.interner.get_value(v))).collect()).unwrap_or(vec![]);
+    assert_eq!(blocks.len(), 1, "The error doesn't name the committing block");
+    assert!(program.block_info.block_names.contains_key(&blocks[0]));
+
+    program.set_scope_readonly("reference", false);
+    program.annotated_transaction(vec![
+        RawChange::new(Internable::Reference("trigger|2|".to_string()), Internable::String("tag".to_string()), Internable::String("trigger".to_string()), node, 1),
+    ], vec![]);
+    assert!(program.state.index.get(0, reference_name, nowhere).map_or(0, |iter| iter.count()) > 0, "Thawed scope still rejected the commit");
+    // and thawing it resolves the error
+    assert!(!program.state.distinct_index.is_available(found, tag, rejected), "The error outlived the read-only scope");
+}
+
+fn country(id:usize) -> Internable {
+    Internable::Reference(format!("country|{}|", id))
+}
+
+#[test]
+fn base_scope_replace() {
+    let mut program = blocks!({
+        search @reference
+            [#country name]
+        bind
+            [#known-country name]
+        end
+    });
+    let loaded = program.replace_scope("reference")
+        .insert(country(1), "tag", Internable::String("country".to_string()))
+        .insert(country(1), "name", Internable::String("Atlantis".to_string()))
+        .insert(country(2), "tag", Internable::String("country".to_string()))
+        .insert(country(2), "name", Internable::String("Lemuria".to_string()))
+        .commit();
+    assert_eq!(loaded.len(), 4);
+
+    // only the country that went away and the one that showed up are touched
+    let swapped = program.replace_scope("reference")
+        .insert(country(1), "tag", Internable::String("country".to_string()))
+        .insert(country(1), "name", Internable::String("Atlantis".to_string()))
+        .insert(country(3), "tag", Internable::String("country".to_string()))
+        .insert(country(3), "name", Internable::String("Mu".to_string()))
+        .commit();
+    assert_eq!(swapped.len(), 4);
+    assert_eq!(swapped.iter().filter(|change| change.count < 0 && change.e == country(2)).count(), 2);
+    assert_eq!(swapped.iter().filter(|change| change.count > 0 && change.e == country(3)).count(), 2);
+
+    let name = s!(program, "name");
+    for &(country_name, expected) in [("Atlantis", 1), ("Lemuria", 0), ("Mu", 1)].iter() {
+        let value = s!(program, country_name);
+        let found = program.state.index.get(0, name, value).map_or(0, |iter| iter.count());
+        assert_eq!(found, expected, "Wrong number of #known-country records for {}", country_name);
+    }
+
+    let unchanged = program.replace_scope("reference")
+        .insert(country(1), "tag", Internable::String("country".to_string()))
+        .insert(country(1), "name", Internable::String("Atlantis".to_string()))
+        .insert(country(3), "tag", Internable::String("country".to_string()))
+        .insert(country(3), "name", Internable::String("Mu".to_string()))
+        .commit();
+    assert!(unchanged.is_empty(), "Replacing a scope with the same facts changed it: {:?}", unchanged);
+}
+
+#[test]
+fn base_scope_replace_persisted() {
+    let path = std::env::temp_dir().join("eve-base-scope-replace.db");
+    let path = path.to_str().unwrap();
+    fs::remove_file(path).ok();
+    let persister = Persister::new(path);
+    let mut channel = Some(persister.get_channel());
+    let mut program = Program::new("replace");
+    program.replace_scope("reference")
+        .insert(country(1), "name", Internable::String("Atlantis".to_string()))
+        .commit_with(&mut channel);
+    program.replace_scope("reference")
+        .insert(country(2), "name", Internable::String("Mu".to_string()))
+        .commit_with(&mut channel);
+    persister.close();
+    persister.wait();
+
+    // what the db replays is the scope as it was last replaced
+    let mut persister = Persister::new(path);
+    persister.load(path);
+    let saved = persister.get_commits();
+    persister.close();
+    fs::remove_file(path).ok();
+    let count = |name:&str| saved.iter()
+        .filter(|change| change.a == scoped_attribute("reference", "name") && change.v == Internable::String(name.to_string()))
+        .map(|change| change.count).sum::<i32>();
+    assert_eq!(count("Atlantis"), 0);
+    assert_eq!(count("Mu"), 1);
+}
+
+#[test]
+fn base_inspect_internals() {
+    let mut program = blocks!({
+        search @system
+            [#eve!/internal kind: "block" registers]
+            registers > 0
+        bind
+            [#block-seen]
+        end
+
+        search @system
+            [#eve!/internal kind: "transaction" changes]
+            changes > 0
+        bind
+            [#transaction-seen]
+        end
+    });
+    let node = Internable::String("test".to_string());
+    program.annotated_transaction(vec![
+        RawChange::new(Internable::Reference("person|1|".to_string()), Internable::String("tag".to_string()), Internable::String("person".to_string()), node, 1),
+    ], vec![]);
+    assert!(program.last_transaction.changes > 0, "Transaction stats weren't recorded");
+
+    program.inspect();
+    let mut iter_pool = EstimateIterPool::new();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+
+    let tag = s!(program, "tag");
+    for name in ["block-seen", "transaction-seen"].iter() {
+        let seen = s!(program, name);
+        let found = find_entity(&program.state.index, tag, seen);
+        assert!(program.state.distinct_index.is_available(found, tag, seen), "No {} record", name);
+    }
+}
+
+#[test]
+fn base_growth_exponent() {
+    let linear:Vec<(u64, u64)> = (1..10).map(|n| (n * 10, n * 300)).collect();
+    let quadratic:Vec<(u64, u64)> = (1..10).map(|n| (n * 10, n * n * 300)).collect();
+    assert!((growth_exponent(linear.into_iter()).unwrap() - 1.0).abs() < 0.01);
+    assert!((growth_exponent(quadratic.into_iter()).unwrap() - 2.0).abs() < 0.01);
+    // too few samples, or inputs that are all the same size, say nothing about growth
+    assert_eq!(growth_exponent((1..4).map(|n| (n * 10, n * n))), None);
+    assert_eq!(growth_exponent((1..10).map(|n| (10, n * n))), None);
+}
+
+#[test]
+fn base_perf_warning() {
+    let mut program = blocks!({
+        search
+            a = [#a]
+            b = [#b]
+        bind
+            [#pair]
+        end
+
+        search @system
+            [#system!/perf-warning block growth join]
+            growth > 1.5
+        bind
+            [#slow]
+        end
+    });
+    let node = Internable::String("test".to_string());
+    let mut next = 0;
+    for size in [2, 3, 5, 8, 12, 18, 27, 40, 60].iter() {
+        let mut changes = vec![];
+        for _ in 0..*size {
+            for tag in ["a", "b"].iter() {
+                next += 1;
+                changes.push(RawChange::new(Internable::Reference(format!("{}|{}|", tag, next)), Internable::String("tag".to_string()), Internable::String(tag.to_string()), node.clone(), 1));
+            }
+        }
+        program.annotated_transaction(changes, vec![]);
+    }
+    let mut iter_pool = EstimateIterPool::new();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+
+    let tag = s!(program, "tag");
+    let slow = s!(program, "slow");
+    let found = find_entity(&program.state.index, tag, slow);
+    assert!(program.state.distinct_index.is_available(found, tag, slow), "No perf warning for the cross product");
+}
+
+//--------------------------------------------------------------------
+// Transaction annotations
+//--------------------------------------------------------------------
+
+#[test]
+fn base_annotated_transaction() {
+    let mut program = blocks!({
+        search
+            [#eve!/transaction source: "import" user: "chris" entity: [#person name: "Chris"]]
+        bind
+            [#success]
+        end
+    });
+    let person = Internable::Reference("person|1|".to_string());
+    let node = Internable::String("test".to_string());
+    let changes = vec![
+        RawChange::new(person.clone(), Internable::String("tag".to_string()), Internable::String("person".to_string()), node.clone(), 1),
+        RawChange::new(person.clone(), Internable::String("name".to_string()), Internable::String("Chris".to_string()), node.clone(), 1),
+    ];
+    program.annotated_transaction(changes, vec![
+        ("source".to_string(), Internable::String("import".to_string())),
+        ("user".to_string(), Internable::String("chris".to_string())),
+    ]);
+
+    let tag = s!(program, "tag");
+    let success = s!(program, "success");
+    let found = find_entity(&program.state.index, tag, success);
+    assert!(program.state.distinct_index.is_available(found, tag, success), "No success record");
+}
+
+#[test]
+fn base_transaction_builder() {
+    let mut program = blocks!({
+        search
+            [#order item]
+        bind
+            [#receipt item]
+        end
+    });
+    let order = Internable::Reference("order|1|".to_string());
+    let outputs = program.transaction()
+        .insert(order.clone(), "tag", Internable::String("order".to_string()))
+        .insert(order.clone(), "item", Internable::String("tea".to_string()))
+        .commit();
+    let receipt = Internable::String("receipt".to_string());
+    assert!(outputs.iter().any(|change| change.v == receipt && change.count > 0), "No derived receipt");
+}
+
+#[test]
+fn base_admin_disable_block() {
+    let mut program = blocks!({
+        commit
+            [#order item: "tea"]
+        end
+
+        search
+            [#order item]
+        bind
+            [#receipt item]
+        end
+    });
+    let tag = s!(program, "tag");
+    let receipt = s!(program, "receipt");
+    let has_receipt = |program:&Program| program.state.index.get(0, tag, receipt).map_or(false, |mut found| found.next().is_some());
+    assert!(has_receipt(&program), "No receipt before disabling");
+
+    assert!(program.disable_block("test|block|2"));
+    assert!(!program.disable_block("test|block|2"), "Disabled a block twice");
+    assert!(!has_receipt(&program), "Receipt survived disabling its block");
+    assert_eq!(program.stats().disabled, 1);
+    assert!(program.admin_blocks().iter().any(|block| block.name == "test|block|2" && !block.enabled));
+
+    assert!(program.enable_block("test|block|2"));
+    assert!(has_receipt(&program), "No receipt after re-enabling");
+    assert_eq!(program.stats().disabled, 0);
+}
+
+#[test]
+fn base_distinct_block() {
+    let mut program = Program::new("distinct");
+    exec_code(&mut program, "commit\n  [#person name: \"ann\"]\nend\n\neve:block distinct\n\nsearch\n  [#person name]\n  [#pet owner: name]\nbind\n  [#owner name]\nend\n", "pets.eve");
+    let pets:Vec<Internable> = (0..3).map(|ix| Internable::Reference(format!("pet|{}|", ix))).collect();
+    for pet in pets.iter() {
+        program.transaction()
+            .insert(pet.clone(), "tag", Internable::String("pet".to_string()))
+            .insert(pet.clone(), "owner", Internable::String("ann".to_string()))
+            .commit();
+    }
+    let tag = s!(program, "tag");
+    let owner = s!(program, "owner");
+    let ann = find_entity(&program.state.index, tag, owner);
+    assert_eq!(program.state.distinct_index.support(ann, tag, owner), 1, "Repeated derivations weren't collapsed");
+    let block_id = program.block_info.blocks.iter().find(|block| block.metadata.distinct).unwrap().block_id;
+    assert_eq!(program.state.block_distinct[&block_id].derivations(ann, tag, owner), 3);
+
+    for pet in pets[..2].iter() {
+        program.transaction()
+            .remove(pet.clone(), "owner", Internable::String("ann".to_string()))
+            .commit();
+    }
+    assert!(program.state.index.check(ann, tag, owner), "Owner retracted while a pet is left");
+    program.transaction()
+        .remove(pets[2].clone(), "owner", Internable::String("ann".to_string()))
+        .commit();
+    assert!(!program.state.index.check(ann, tag, owner), "Owner survived its last pet");
+    assert_eq!(program.state.block_distinct[&block_id].len(), 0);
+}
+
+#[test]
+fn base_intermediate_compaction() {
+    let mut program = blocks!({
+        search
+            [#order item]
+            not([#shipped item])
+        bind
+            [#pending item]
+        end
+    });
+    let orders:Vec<Internable> = (0..20).map(|ix| Internable::Reference(format!("order|{}|", ix))).collect();
+    let shipments:Vec<Internable> = (0..20).map(|ix| Internable::Reference(format!("shipped|{}|", ix))).collect();
+    for (ix, order) in orders.iter().enumerate() {
+        program.transaction()
+            .insert(order.clone(), "tag", Internable::String("order".to_string()))
+            .insert(order.clone(), "item", Internable::from_number(ix as f32))
+            .commit();
+    }
+    for (ix, shipped) in shipments.iter().enumerate() {
+        program.transaction()
+            .insert(shipped.clone(), "tag", Internable::String("shipped".to_string()))
+            .insert(shipped.clone(), "item", Internable::from_number(ix as f32))
+            .commit();
+    }
+    assert!(program.state.intermediates.stats().entries > 0, "Shipments left no intermediates");
+    for (ix, shipped) in shipments.iter().enumerate() {
+        program.transaction()
+            .remove(shipped.clone(), "tag", Internable::String("shipped".to_string()))
+            .remove(shipped.clone(), "item", Internable::from_number(ix as f32))
+            .commit();
+    }
+    program.compact();
+    let stats = program.state.intermediates.stats();
+    assert_eq!(stats.entries, 0, "Retracted keys survived compaction");
+    assert!(stats.load_factor() <= 1.0);
+
+    let tag = s!(program, "tag");
+    let pending = s!(program, "pending");
+    let count_pending = |program:&Program| program.state.index.get(0, tag, pending).map_or(0, |found| found.count());
+    assert_eq!(count_pending(&program), 20);
+    program.transaction()
+        .insert(shipments[0].clone(), "tag", Internable::String("shipped".to_string()))
+        .insert(shipments[0].clone(), "item", Internable::from_number(0.0))
+        .commit();
+    assert_eq!(count_pending(&program), 19, "Compaction broke the not");
+
+    let names:Vec<String> = program.stats().indexes.into_iter().map(|(name, _)| name).collect();
+    assert_eq!(&names[..3], &["facts".to_string(), "distinct".to_string(), "intermediates".to_string()]);
+}
+
+#[test]
+fn base_intermediate_shared_prefix() {
+    let mut program = blocks!({
+        commit
+            [#person name: "ann"]
+            [#person name: "bob"]
+            [#banned name: "bob"]
+            [#vip name: "ann"]
+        end
+
+        search
+            [#person name]
+            not([#banned name])
+            status = if [#vip name] then "vip" else "regular"
+        bind
+            [#greeting name status]
+        end
+    });
+    let status = s!(program, "status");
+    let vip = s!(program, "vip");
+    let regular = s!(program, "regular");
+    let carl = s!(program, "carl");
+    let greetings = |program:&Program, value| program.state.index.get(0, status, value).map_or(0, |found| found.count());
+    assert_eq!(greetings(&program, vip), 1);
+    assert_eq!(greetings(&program, regular), 0, "The not let bob through");
+    assert!(!program.state.intermediates.has_prefix(&[carl]));
+
+    let generation = program.state.intermediates.generation();
+    program.transaction()
+        .insert(Internable::Reference("person|carl|".to_string()), "tag", Internable::String("person".to_string()))
+        .insert(Internable::Reference("person|carl|".to_string()), "name", Internable::String("carl".to_string()))
+        .commit();
+    assert!(program.state.intermediates.generation() > generation);
+    assert!(program.state.intermediates.has_prefix(&[carl]));
+    assert_eq!(greetings(&program, regular), 1, "Stale prefix probe after the index changed");
+}
+
+#[test]
+fn base_block_metadata() {
+    let mut program = Program::new("metadata");
+    exec_code(&mut program, "commit\n  [#order item: \"tea\"]\nend\n\neve:block name: \"receipts\" disabled\n\nsearch\n  [#order item]\nbind\n  [#receipt item]\nend\n", "orders.eve");
+    let tag = s!(program, "tag");
+    let receipt = s!(program, "receipt");
+    let has_receipt = |program:&Program| program.state.index.get(0, tag, receipt).map_or(false, |mut found| found.next().is_some());
+    assert!(!has_receipt(&program), "A disabled block ran");
+    assert!(program.admin_blocks().iter().any(|block| block.name == "receipts" && !block.enabled));
+
+    assert!(program.enable_block("receipts"));
+    assert!(has_receipt(&program), "No receipt after enabling");
+    assert!(program.disable_block("receipts"));
+    assert!(!has_receipt(&program), "Receipt survived disabling its block");
+}
+
+//--------------------------------------------------------------------
+// Mounting
+//--------------------------------------------------------------------
+
+#[test]
+fn base_mount_program() {
+    let billing = blocks!({
+        search
+            [#order item]
+        bind
+            [#invoice item]
+            [#ledger item]
+        end
+    });
+    let mut program = blocks!({
+        commit
+            [#order item: "tea"]
+        end
+
+        search
+            [#invoice item: "tea"]
+            not([#ledger])
+        bind
+            [#success]
+        end
+    });
+    program.mount(billing, "billing", vec!["order"], vec!["invoice"]);
+
+    let tag = s!(program, "tag");
+    let success = s!(program, "success");
+    let found = find_entity(&program.state.index, tag, success);
+    assert!(program.state.distinct_index.is_available(found, tag, success), "No success record");
+    let ledger = s!(program, "billing/ledger");
+    assert_eq!(program.state.index.get(0, tag, ledger).map_or(0, |iter| iter.count()), 1);
+}
+
+#[test]
+fn base_mount_prefixes_intermediates() {
+    let billing = blocks!({
+        search
+            [#order item]
+            not([#void item])
+        bind
+            [#invoice item]
+        end
+    });
+    let mut program = blocks!({
+        search
+            [#invoice item]
+            not([#hold item])
+        bind
+            [#success item]
+        end
+    });
+    program.mount(billing, "billing", vec!["order"], vec!["invoice"]);
+
+    let mut keys = vec![];
+    for block in program.block_info.blocks.iter().filter(|block| block.name.starts_with("billing/")) {
+        let mut constraints = block.constraints.clone();
+        for constraint in constraints.iter_mut() {
+            for field in constraint.fields_mut() {
+                if let Field::Value(id) = *field {
+                    if let &Internable::String(ref key) = program.state.interner.get_value(id) {
+                        if key.contains("|sub_block|") { keys.push(key.to_string()); }
+                    }
+                }
+            }
+        }
+    }
+    assert!(keys.len() > 0, "No intermediate keys in the mounted blocks");
+    for key in keys {
+        assert!(key.starts_with("billing/"), "Unprefixed intermediate key {}", key);
+    }
+}
+
+//--------------------------------------------------------------------
+// Block partitions
+//--------------------------------------------------------------------
+
+#[test]
+fn base_block_partitions() {
+    let program = blocks!({
+        search
+            [#a]
+        bind
+            [#b]
+        end
+
+        search
+            [#b]
+        bind
+            [#c]
+        end
+
+        search
+            [#x]
+        bind
+            [#y]
+        end
+    }).with_threads(4);
+    let mut sizes:Vec<usize> = program.block_info.partitions().iter().map(|partition| partition.len()).collect();
+    sizes.sort();
+    assert_eq!(sizes, vec![1, 2]);
+    assert_eq!(program.threads(), 4);
+}
+
+// Blocks in separate partitions that all read the same people, so each change to a
+// person feeds several of them at once.
+fn partitioned_people(threads:usize) -> Vec<(Internable, Internable, Internable)> {
+    let mut program = blocks!({
+        search
+            [#person name age]
+            age > 30
+        bind
+            [#older who: name]
+        end
+
+        search
+            [#person name]
+            total = gather!/count![for: name]
+        bind
+            [#census total]
+        end
+
+        search
+            [#person name]
+            not([#banned name])
+        bind
+            [#allowed who: name]
+        end
+
+        search
+            [#person name]
+            shout = string!/replace![text: name replace: "a" with: "A"]
+        bind
+            [#shout shout]
+        end
+    }).with_threads(threads);
+    let mut builder = program.transaction();
+    for ix in 0..50 {
+        let person = Internable::Reference(format!("person|{}|", ix));
+        builder = builder.insert(person.clone(), "tag", Internable::String("person".to_string()))
+            .insert(person.clone(), "name", Internable::String(format!("ann {}", ix)))
+            .insert(person, "age", Internable::from_number(ix as f32));
+    }
+    builder.commit();
+    let mut facts:Vec<(Internable, Internable, Internable)> = program.all_facts().map(|(e, a, v)| (e.clone(), a.clone(), v.clone())).collect();
+    facts.sort();
+    facts
+}
+
+#[test]
+fn base_parallel_partitions() {
+    let serial = partitioned_people(1);
+    assert!(serial.iter().any(|&(_, _, ref v)| v == &Internable::String("Ann 7".to_string())));
+    assert!(serial.iter().any(|&(_, _, ref v)| v == &Internable::from_number(50.0)));
+    assert_eq!(partitioned_people(4), serial);
+}
+
+//--------------------------------------------------------------------
+// Spilling
+//--------------------------------------------------------------------
+
+fn cross_join(spill:Option<(usize, &std::path::Path)>) -> Program {
+    let mut program = blocks!({
+        search
+            [#a x]
+            [#b y]
+        bind
+            [#pair x y]
+        end
+    });
+    if let Some((threshold, dir)) = spill {
+        program = program.with_spill(threshold, dir);
+    }
+    let node = Internable::String("test".to_string());
+    let mut changes = vec![];
+    for ix in 0..30 {
+        let a = Internable::Reference(format!("a|{}|", ix));
+        let b = Internable::Reference(format!("b|{}|", ix));
+        changes.push(RawChange::new(a.clone(), Internable::String("tag".to_string()), Internable::String("a".to_string()), node.clone(), 1));
+        changes.push(RawChange::new(a, Internable::String("x".to_string()), Internable::from_number(ix as f32), node.clone(), 1));
+        changes.push(RawChange::new(b.clone(), Internable::String("tag".to_string()), Internable::String("b".to_string()), node.clone(), 1));
+        changes.push(RawChange::new(b, Internable::String("y".to_string()), Internable::from_number(ix as f32), node.clone(), 1));
+    }
+    program.annotated_transaction(changes, vec![]);
+    program
+}
+
+fn cross_join_pairs(spill:Option<usize>) -> (usize, usize) {
+    let dir = std::env::temp_dir();
+    let mut program = cross_join(spill.map(|threshold| (threshold, dir.as_path())));
+    let tag = s!(program, "tag");
+    let pair = s!(program, "pair");
+    let pairs = program.state.index.get(0, tag, pair).map_or(0, |iter| iter.count());
+    (pairs, program.state.rounds.spilled)
+}
+
+#[test]
+fn base_spill_large_join() {
+    let (in_memory, not_spilled) = cross_join_pairs(None);
+    assert_eq!((in_memory, not_spilled), (900, 0));
+    let (spilled_pairs, spilled) = cross_join_pairs(Some(50));
+    assert_eq!(spilled_pairs, 900);
+    assert!(spilled > 0, "Nothing was spilled");
+}
+
+#[test]
+fn base_spill_io_error() {
+    let dir = std::env::temp_dir().join("eve-spill-missing").join("nested");
+    let program = cross_join(Some((50, &dir)));
+    match program.last_error() {
+        Some(&RuntimeError::Spill { .. }) => {}
+        other => panic!("Expected a spill error, got {:?}", other),
+    }
+    assert_eq!(program.state.rounds.spilled, 0);
+}
+
+//--------------------------------------------------------------------
+// Watcher delivery
+//--------------------------------------------------------------------
+
+struct RecordingWatcher {
+    name: String,
+    seen: Arc<Mutex<Vec<(String, bool)>>>,
+}
+
+impl Watcher for RecordingWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    fn on_diff(&mut self, interner:&mut Interner, diff:WatchDiff) {
+        let mut seen = self.seen.lock().unwrap();
+        for add in diff.adds {
+            seen.push((Internable::to_string(interner.get_value(add[0])), true));
+        }
+        for remove in diff.removes {
+            seen.push((Internable::to_string(interner.get_value(remove[0])), false));
+        }
+    }
+}
+
+fn run_with_checkpoints(path:&str, code:&str) -> Vec<(String, bool)> {
+    let mut program = Program::new("test");
+    program.delivery = DeliveryLog::load(path);
+    let seen = Arc::new(Mutex::new(vec![]));
+    program.attach(Box::new(RecordingWatcher { name: "test/record".to_string(), seen: seen.clone() }));
+    let blocks = parse_string(&mut program.state.interner, code, "test");
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+    let mut result = seen.lock().unwrap().clone();
+    result.sort();
+    result
+}
+
+#[test]
+fn base_watcher_delivery_resumes() {
+    let path = std::env::temp_dir().join("eve-base-watcher-delivery.watchers");
+    let path = path.to_str().unwrap();
+    fs::remove_file(path).ok();
+    let watch = "search\n  [#item name]\nwatch test/record\n  (name)\nend\n";
+    let first = format!("commit\n  [#item name: \"a\"]\n  [#item name: \"b\"]\nend\n\n{}", watch);
+    let second = format!("commit\n  [#item name: \"a\"]\n  [#item name: \"c\"]\nend\n\n{}", watch);
+
+    assert_eq!(run_with_checkpoints(path, &first), vec![("a".to_string(), true), ("b".to_string(), true)]);
+    // restarting with the same state delivers nothing new
+    assert_eq!(run_with_checkpoints(path, &first), vec![]);
+    // restarting with changed state only delivers the difference
+    assert_eq!(run_with_checkpoints(path, &second), vec![("b".to_string(), false), ("c".to_string(), true)]);
+    // the high-water mark survives the restarts
+    let log = DeliveryLog::load(path);
+    assert!(log.checkpoint("test/record").map_or(0, |checkpoint| checkpoint.position) > 0);
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn base_watcher_delivery_errors() {
+    let path = std::env::temp_dir().join("eve-base-missing-dir").join("deliveries.watchers");
+    let mut program = Program::new("test");
+    program.delivery = DeliveryLog::load(path.to_str().unwrap());
+    let seen = Arc::new(Mutex::new(vec![]));
+    program.attach(Box::new(RecordingWatcher { name: "test/record".to_string(), seen: seen.clone() }));
+    let blocks = parse_string(&mut program.state.interner, "commit\n  [#item name: \"a\"]\nend\n\nsearch\n  [#item name]\nwatch test/record\n  (name)\nend\n", "test");
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+    // the watcher still gets its diff, and the log failing to record it is reported
+    assert_eq!(seen.lock().unwrap().clone(), vec![("a".to_string(), true)]);
+    match program.last_error() {
+        Some(&RuntimeError::Delivery { .. }) => {}
+        other => panic!("Expected a delivery error, got {:?}", other),
+    }
+    // nor is it sent again while the log stays behind
+    program.transaction().insert(Internable::String("other|1".to_string()), "tag", Internable::String("other".to_string())).commit();
+    assert_eq!(seen.lock().unwrap().len(), 1);
+}
+
+// Notes how big the delivery log is whenever it's handed a diff.
+struct LogSizeWatcher {
+    name: String,
+    path: String,
+    sizes: Arc<Mutex<Vec<u64>>>,
+}
+
+impl Watcher for LogSizeWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    fn on_diff(&mut self, _:&mut Interner, _:WatchDiff) {
+        self.sizes.lock().unwrap().push(fs::metadata(&self.path).map(|meta| meta.len()).unwrap_or(0));
+    }
+}
+
+#[test]
+fn base_watcher_delivery_recorded_after() {
+    let path = std::env::temp_dir().join("eve-base-watcher-recorded.watchers");
+    let path = path.to_str().unwrap();
+    fs::remove_file(path).ok();
+    let mut program = Program::new("test");
+    program.delivery = DeliveryLog::load(path);
+    let sizes = Arc::new(Mutex::new(vec![]));
+    program.attach(Box::new(LogSizeWatcher { name: "test/size".to_string(), path: path.to_string(), sizes: sizes.clone() }));
+    let blocks = parse_string(&mut program.state.interner, "search
+  [#item name]
+watch test/size
+  (name)
+end
+", "test");
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+    program.transaction().insert(Internable::String("item|1".to_string()), "name", Internable::String("a".to_string()))
+        .insert(Internable::String("item|1".to_string()), "tag", Internable::String("item".to_string())).commit();
+    program.transaction().insert(Internable::String("item|2".to_string()), "name", Internable::String("b".to_string()))
+        .insert(Internable::String("item|2".to_string()), "tag", Internable::String("item".to_string())).commit();
+    // a diff is only in the log once the watcher has had it
+    let sizes = sizes.lock().unwrap().clone();
+    assert_eq!(sizes.len(), 2);
+    assert_eq!(sizes[0], 0);
+    let after = fs::metadata(path).unwrap().len();
+    assert!(sizes[1] > 0 && sizes[1] < after, "Unexpected log sizes {:?} then {}", sizes, after);
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn base_save_snapshot() {
+    let mut program = blocks!({
+        commit
+            [#order item: "tea"]
+            [#order item: "cake"]
+        end
+    });
+    let path = std::env::temp_dir().join("eve-base-save.db");
+    let path = path.to_str().unwrap();
+    fs::remove_file(path).ok();
+    let saving = program.save(path).unwrap();
+    // the program keeps taking transactions while the snapshot is written
+    program.transaction()
+        .insert(Internable::Reference("order|3|".to_string()), "tag", Internable::String("order".to_string()))
+        .commit();
+    saving.join().unwrap().unwrap();
+
+    let mut persister = Persister::new(path);
+    persister.load(path);
+    let saved = persister.get_commits();
+    persister.close();
+    fs::remove_file(path).ok();
+    let tag = Internable::String("tag".to_string());
+    let order = Internable::String("order".to_string());
+    assert_eq!(saved.iter().filter(|change| change.a == tag && change.v == order).count(), 2);
+}
+
+#[test]
+fn base_save_rejects_persisted_db() {
+    let path = std::env::temp_dir().join("eve-base-save-persisted.db");
+    let path = path.to_str().unwrap();
+    fs::remove_file(path).ok();
+    let mut persister = Persister::new(path);
+    let mut runner = ProgramRunner::new("test");
+    runner.persist(&mut persister);
+    assert!(runner.program.save(path).is_err(), "Saved over the db the persister is writing");
+    let other = format!("{}.copy", path);
+    runner.program.save(&other).unwrap().join().unwrap().unwrap();
+    persister.close();
+    persister.wait();
+    fs::remove_file(path).ok();
+    fs::remove_file(&other).ok();
+}
+
+#[test]
+fn base_persister_snapshot_errors() {
+    let path = std::env::temp_dir().join("eve-base-persister-snapshot.db");
+    let path = path.to_str().unwrap();
+    let snapshot_path = format!("{}.snapshot", path);
+    fs::remove_file(path).ok();
+    // a directory where the snapshot goes makes writing it fail
+    fs::create_dir_all(&snapshot_path).unwrap();
+    let persister = Persister::new(path);
+    let channel = persister.get_channel();
+    let (reply, result) = mpsc::channel();
+    channel.send(PersisterMessage::Snapshot(vec![], reply)).unwrap();
+    assert!(result.recv().unwrap().is_err(), "Snapshot claimed to succeed");
+
+    // the persister is still around for the next snapshot
+    fs::remove_dir(&snapshot_path).unwrap();
+    let (reply, result) = mpsc::channel();
+    channel.send(PersisterMessage::Snapshot(vec![], reply)).unwrap();
+    assert_eq!(result.recv().unwrap(), Ok(()));
+    persister.close();
+    persister.wait();
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn base_admin_snapshot_off_thread() {
+    let mut program = blocks!({
+        commit
+            [#order item: "tea"]
+        end
+    });
+    let path = std::env::temp_dir().join("eve-base-admin-snapshot.db");
+    let path = path.to_str().unwrap();
+    fs::remove_file(path).ok();
+    let persister = Persister::new(path);
+    let channel = Some(persister.get_channel());
+    let (reply, result) = mpsc::channel();
+    program.admin(AdminCommand::Snapshot, &channel, reply);
+    // the persister answers, so the program can take transactions before it has
+    program.transaction()
+        .insert(Internable::Reference("order|2|".to_string()), "tag", Internable::String("order".to_string()))
+        .commit();
+    match result.recv().unwrap() {
+        AdminReply::Done => {}
+        other => panic!("Snapshot failed: {:?}", other),
+    }
+    persister.close();
+    persister.wait();
+
+    // what was committed after the snapshot was asked for isn't in it
+    let mut loaded = Persister::new(path);
+    loaded.load(path);
+    let saved = loaded.get_commits();
+    loaded.close();
+    loaded.wait();
+    fs::remove_file(path).ok();
+    let order = Internable::String("order".to_string());
+    assert_eq!(saved.iter().filter(|change| change.v == order).count(), 1);
+}
+
+#[test]
+fn base_all_facts_and_entity() {
+    let mut program = Program::new("facts");
+    exec_code(&mut program, "search\n  [#person name]\nbind\n  [#greeting name]\nend\n", "people.eve");
+    let ann = Internable::Reference("person|ann|".to_string());
+    program.transaction()
+        .insert(ann.clone(), "tag", Internable::String("person".to_string()))
+        .insert(ann.clone(), "tag", Internable::String("admin".to_string()))
+        .insert(ann.clone(), "name", Internable::String("ann".to_string()))
+        .commit();
+
+    let facts:Vec<(Internable, Internable, Internable)> = program.all_facts().map(|(e, a, v)| (e.clone(), a.clone(), v.clone())).collect();
+    let name = Internable::String("name".to_string());
+    let greeting = Internable::String("greeting".to_string());
+    assert!(facts.contains(&(ann.clone(), name.clone(), Internable::String("ann".to_string()))));
+    // bound facts are in there too
+    assert!(facts.iter().any(|&(_, _, ref v)| v == &greeting));
+
+    let record = program.entity(&ann).expect("No record for ann");
+    assert_eq!(record["tag"], vec![Internable::String("admin".to_string()), Internable::String("person".to_string())]);
+    assert_eq!(record["name"], vec![Internable::String("ann".to_string())]);
+    assert!(program.entity(&Internable::Reference("person|nobody|".to_string())).is_none());
+}
+
+#[test]
+fn base_export_state() {
+    let mut program = Program::new("export");
+    exec_code(&mut program, "search\n  [#person name]\nbind\n  [#greeting name]\nend\n", "greet.eve");
+    let ann = Internable::Reference("person|ann|".to_string());
+    let bo = Internable::Reference("person|bo|".to_string());
+    program.transaction()
+        .insert(ann.clone(), "tag", Internable::String("person".to_string()))
+        .insert(ann.clone(), "name", Internable::String("ann".to_string()))
+        .insert(ann.clone(), "friend", bo.clone())
+        .insert(bo.clone(), "tag", Internable::String("person".to_string()))
+        .insert(bo.clone(), "name", Internable::String("bo".to_string()))
+        .insert(bo.clone(), "friend", ann.clone())
+        .insert(Internable::Reference("pet|rex|".to_string()), "tag", Internable::String("pet".to_string()))
+        .commit();
+
+    let people = ExportFilter { tag: Some("person".to_string()), scope: None };
+    let documents:serde_json::Value = serde_json::from_str(&export_json(&program, &people, 1)).unwrap();
+    let documents = documents.as_array().unwrap();
+    assert_eq!(documents.len(), 2);
+    let first = &documents[0];
+    assert_eq!(first["id"], json!("person|ann|"));
+    // one level of references is resolved, the friend's friend is left as an id
+    assert_eq!(first["friend"]["name"], json!("bo"));
+    assert_eq!(first["friend"]["friend"], json!("person|ann|"));
+
+    // EAV text goes back in as the same facts, leaving out what was only bound
+    let greetings = ExportFilter { tag: Some("greeting".to_string()), scope: None };
+    assert_eq!(export_json(&program, &greetings, 0).matches("\"greeting\"").count(), 2);
+    assert_eq!(export_eav(&program, &greetings), "");
+    let text = export_eav(&program, &ExportFilter::default());
+    assert_eq!(text.lines().count(), 7);
+    let mut copy = Program::new("import");
+    assert_eq!(import_eav(&mut copy, &text), Ok(7));
+    assert_eq!(copy.entity(&ann), program.entity(&ann));
+    assert_eq!(copy.entity(&bo), program.entity(&bo));
+    assert!(parse_eav("\"person|ann|\"\t\"name\"").is_err());
+}
+
+#[test]
+fn base_derivation_checkpoints() {
+    let code = "search\n  [#order item]\nbind\n  [#ordered item]\nend\n";
+    let mut program = Program::new("checkpoints");
+    exec_code(&mut program, code, "orders.eve");
+    let order = Internable::Reference("order|1|".to_string());
+    program.transaction()
+        .insert(order.clone(), "tag", Internable::String("order".to_string()))
+        .insert(order.clone(), "item", Internable::String("tea".to_string()))
+        .commit();
+    let commits = program.committed_facts();
+    let path = std::env::temp_dir().join("eve-base-checkpoint.db.derived");
+    let path = path.to_str().unwrap();
+    program.write_checkpoint(path, &commits).unwrap();
+
+    let mut resumed = Program::new("checkpoints");
+    let point = resumed.restore_checkpoint(path, &commits).expect("The checkpoint wasn't restored");
+    assert_eq!(point.commits, commits.len());
+    let blocks = parse_string(&mut resumed.state.interner, code, "orders.eve");
+    assert!(point.matches(&blocks));
+    let mut txn = CodeTransaction::new();
+    txn.resume(&mut resumed, blocks);
+    let tag = s!(resumed, "tag");
+    let ordered = s!(resumed, "ordered");
+    assert_eq!(resumed.state.index.get(0, tag, ordered).map_or(0, |iter| iter.count()), 1);
+
+    // the block wasn't run again over the restored state, so one removal retracts it
+    resumed.transaction()
+        .remove(order.clone(), "item", Internable::String("tea".to_string()))
+        .commit();
+    assert_eq!(resumed.state.index.get(0, tag, ordered).map_or(0, |iter| iter.count()), 0);
+
+    // changed blocks and a different db both mean deriving everything again
+    let mut changed = Program::new("checkpoints");
+    let point = changed.restore_checkpoint(path, &commits).unwrap();
+    let blocks = parse_string(&mut changed.state.interner, "search\n  [#order item]\nbind\n  [#listed item]\nend\n", "orders.eve");
+    assert!(!point.matches(&blocks));
+    let mut other = Program::new("checkpoints");
+    assert!(other.restore_checkpoint(path, &commits[1..]).is_none());
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn base_check_db_repairs_torn_tail() {
+    let program = blocks!({
+        commit
+            [#order item: "tea"]
+        end
+    });
+    let path = std::env::temp_dir().join("eve-base-check.db");
+    let path = path.to_str().unwrap();
+    fs::remove_file(path).ok();
+    program.save(path).unwrap().join().unwrap().unwrap();
+    assert!(check_db(path, false).is_ok());
+
+    // a write cut off halfway and a snapshot that never got renamed into place
+    OpenOptions::new().append(true).open(path).unwrap().write_all(&[1, 0, 0]).unwrap();
+    let snapshot = format!("{}.snapshot", path);
+    fs::File::create(&snapshot).unwrap();
+    let report = check_db(path, false);
+    assert_eq!(report.problems.len(), 2);
+    assert_eq!(report.repaired.len(), 0);
+
+    let report = check_db(path, true);
+    assert!(report.is_ok());
+    assert_eq!(report.repaired.len(), 2);
+    assert!(!std::path::Path::new(&snapshot).exists());
+    let report = check_db(path, false);
+    assert!(report.is_ok());
+    assert_eq!(report.changes, 2);
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn base_check_indexes() {
+    let mut program = blocks!({
+        commit
+            [#person name: "ann"]
+            [#banned name: "ann"]
+        end
+
+        search
+            [#person name]
+            not([#banned name])
+        bind
+            [#welcome name]
+        end
+    });
+    assert_eq!(check_indexes(&program), Vec::<String>::new());
+    assert!(!program.state.distinct_index.is_available(0, 0, 0));
+
+    // an index that's lost count of its facts
+    program.state.index.size += 1;
+    assert_eq!(check_indexes(&program).len(), 1);
+    program.state.index.size -= 1;
+
+    // intermediates outliving the block that made them
+    let names:Vec<String> = program.block_info.block_names.keys().cloned().collect();
+    for name in names {
+        program.unregister_block(name);
+    }
+    let problems = check_indexes(&program);
+    assert!(problems.iter().any(|problem| problem.contains("isn't loaded")), "{:?}", problems);
+}
+
+#[test]
+fn base_bundle_report() {
+    let program = blocks!({
+        commit
+            [#person name: "ann" email: "ann@example.com"]
+        end
+    });
+    let dir = std::env::temp_dir();
+    let db = dir.join("eve-base-report.db");
+    let db = db.to_str().unwrap();
+    let source = dir.join("eve-base-report.eve");
+    let source = source.to_str().unwrap().to_string();
+    fs::remove_file(db).ok();
+    program.save(db).unwrap().join().unwrap().unwrap();
+    fs::File::create(&source).unwrap().write_all(b"search\n  [#person name]\nbind\n  [#greeting name]\nend\n").unwrap();
+
+    let redaction = Redaction::none().attribute("email", RedactAction::Hash);
+    let report = bundle_report(&[source.clone()], Some(db), &redaction, 1).unwrap();
+    fs::remove_file(db).ok();
+    fs::remove_file(&source).ok();
+
+    assert_eq!(report.sources.len(), 1);
+    assert_eq!((report.sources[0].blocks, report.sources[0].errors), (1, 0));
+    assert_eq!(report.stats.log_changes, 3);
+    assert_eq!(report.log.len(), 1);
+    assert!(report.stats.problems.is_empty());
+    let email = Internable::String("email".to_string());
+    let emails:Vec<&RawChange> = report.snapshot.iter().filter(|change| change.a == email).collect();
+    assert_eq!(emails.len(), 1);
+    assert!(emails[0].v != Internable::String("ann@example.com".to_string()));
+    let name = Internable::String("name".to_string());
+    assert!(report.snapshot.iter().any(|change| change.a == name && change.v == Internable::String("ann".to_string())));
+}
+
+#[test]
+fn base_redaction() {
+    let program = blocks!({
+        commit
+            [#person name: "ann" email: "ann@example.com" ssn: "123-45-6789" age: 30]
+            [#person name: "bob" email: "ann@example.com"]
+        end
+    });
+    let redaction = Redaction::none()
+        .attribute("email", RedactAction::Hash)
+        .attribute("ssn", RedactAction::Drop)
+        .attribute("age", RedactAction::Mask)
+        .strings(RedactAction::Mask);
+    let facts = redaction.redact_all(program.committed_facts());
+    let values = |attribute:&str| -> Vec<Internable> {
+        let attribute = Internable::String(attribute.to_string());
+        facts.iter().filter(|change| change.a == attribute).map(|change| change.v.clone()).collect()
+    };
+    assert!(values("ssn").is_empty());
+    assert_eq!(values("age"), vec![Internable::from_number(0.0)]);
+    let emails = values("email");
+    assert_eq!(emails.len(), 2);
+    assert_eq!(emails[0], emails[1]);
+    assert!(emails[0] != Internable::String("ann@example.com".to_string()));
+    let mut names = values("name");
+    names.sort();
+    assert_eq!(names, vec![Internable::String("***".to_string()), Internable::String("***".to_string())]);
+    assert_eq!(values("tag"), vec![Internable::String("person".to_string()), Internable::String("person".to_string())]);
+
+    // hashes are keyed by the salt, so they only line up when it's the same
+    let email = Internable::String("email".to_string());
+    let salted = |salt:&str| {
+        let redaction = Redaction::none().attribute("email", RedactAction::Hash).salt(salt);
+        redaction.redact_all(program.committed_facts()).into_iter().find(|change| change.a == email).unwrap().v
+    };
+    assert_eq!(salted("secret"), salted("secret"));
+    assert!(salted("secret") != salted("other"));
+}
+
+struct OrderedWatcher {
+    name: String,
+    after: Vec<String>,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl Watcher for OrderedWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    fn on_diff(&mut self, _:&mut Interner, _:WatchDiff) {
+        self.log.lock().unwrap().push(self.name.clone());
+    }
+    fn dependencies(&self) -> Vec<String> {
+        self.after.clone()
+    }
+}
+
+#[test]
+fn base_watcher_dependency_order() {
+    let mut program = Program::new("test");
+    let log = Arc::new(Mutex::new(vec![]));
+    program.attach(Box::new(OrderedWatcher { name: "test/email".to_string(), after: vec!["test/db".to_string()], log: log.clone() }));
+    program.attach(Box::new(OrderedWatcher { name: "test/db".to_string(), after: vec![], log: log.clone() }));
+    let code = "commit\n  [#order item: \"tea\"]\nend\n\n\
+                search\n  [#order item]\nwatch test/email\n  (item)\nend\n\n\
+                search\n  [#order item]\nwatch test/db\n  (item)\nend\n";
+    let blocks = parse_string(&mut program.state.interner, code, "test");
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+    assert_eq!(*log.lock().unwrap(), vec!["test/db".to_string(), "test/email".to_string()]);
+}
+
+struct LifecycleWatcher {
+    name: String,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl Watcher for LifecycleWatcher {
+    fn get_name(& self) -> String {
+        self.name.clone()
+    }
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+    fn setup(&mut self, errors:WatcherErrors) {
+        self.log.lock().unwrap().push(format!("setup {}", self.name));
+        errors.report("no credentials");
+    }
+    fn on_diff(&mut self, _:&mut Interner, diff:WatchDiff) {
+        self.log.lock().unwrap().push(format!("diff {}", diff.adds.len()));
+    }
+    fn teardown(&mut self) {
+        self.log.lock().unwrap().push("teardown".to_string());
+    }
+}
+
+#[test]
+fn base_watcher_lifecycle() {
+    let mut program = Program::new("test");
+    let log = Arc::new(Mutex::new(vec![]));
+    program.attach_watcher("test/orders", Box::new(LifecycleWatcher { name: "unnamed".to_string(), log: log.clone() }));
+    match program.incoming.try_recv() {
+        Ok(RunLoopMessage::WatcherError(name, message)) => {
+            assert_eq!((&name[..], &message[..]), ("test/orders", "no credentials"));
+            program.watcher_error(&name, &message);
+        }
+        _ => panic!("The watcher's error wasn't reported"),
+    }
+    exec_code(&mut program, "commit\n  [#order item: \"tea\"]\nend\n\n\
+                             search\n  [#order item]\nwatch test/orders\n  (item)\nend\n\n\
+                             search @system\n  [#eve/error kind: \"watcher\" watcher message]\ncommit\n  [#seen watcher message]\nend\n", "test");
+    let tag = s!(program, "tag");
+    let seen = s!(program, "seen");
+    assert!(find_entity(&program.state.index, tag, seen) != 0, "The error should be visible to blocks");
+
+    assert!(program.detach_watcher("test/orders").is_some());
+    assert!(program.detach_watcher("test/orders").is_none());
+    assert!(!program.watcher_order().contains(&"test/orders".to_string()));
+    exec_code(&mut program, "commit\n  [#order item: \"coffee\"]\nend\n", "test");
+    assert_eq!(*log.lock().unwrap(), vec!["setup test/orders".to_string(), "diff 1".to_string(), "teardown".to_string()]);
+}
+
+#[test]
+fn base_watcher_input() {
+    let program = Program::new("test");
+    let config = InputConfig { batch_size: 3, max_delay: Duration::from_secs(60), max_in_flight: 1 };
+    let input = program.input(config);
+    let order = |item:&str| vec![RawChange::new(Internable::Reference("order|".to_string()), Internable::String("item".to_string()), Internable::String(item.to_string()), Internable::String("test".to_string()), 1)];
+
+    // pushes from any thread are batched into one transaction
+    let pusher = input.clone();
+    thread::spawn(move || { pusher.push(order("tea")).unwrap(); pusher.push(order("coffee")).unwrap(); }).join().unwrap();
+    assert!(program.incoming.try_recv().is_err(), "The batch isn't full yet");
+    input.push(order("water")).unwrap();
+    let reply = match program.incoming.try_recv() {
+        Ok(RunLoopMessage::ReplyTransaction(changes, reply)) => { assert_eq!(changes.len(), 3); reply }
+        _ => panic!("The full batch wasn't sent"),
+    };
+
+    // until the program has finished that batch, there's no room for another
+    match input.try_push(order("juice")) {
+        Err(InputError::Full(refused)) => assert_eq!(refused, order("juice")),
+        _ => panic!("The input should be full"),
+    }
+    reply.send(vec![]).unwrap();
+    input.try_push(order("juice")).unwrap();
+    input.flush().unwrap();
+    assert_eq!(input.in_flight(), 1);
+
+    // and push waits for room
+    let pusher = input.clone();
+    let waiting = thread::spawn(move || { pusher.push(order("milk")).unwrap(); pusher.flush().unwrap(); });
+    match program.incoming.recv_timeout(Duration::from_secs(5)) {
+        Ok(RunLoopMessage::ReplyTransaction(changes, _)) => assert_eq!(changes, order("juice")),
+        _ => panic!("The flushed batch wasn't sent"),
+    }
+    waiting.join().unwrap();
+    match program.incoming.recv_timeout(Duration::from_secs(5)) {
+        Ok(RunLoopMessage::ReplyTransaction(changes, _)) => assert_eq!(changes, order("milk")),
+        _ => panic!("The waiting push never went through"),
+    }
+}
+
+struct ReceiptWatcher {
+    abi: AbiVersion,
+    seen: Arc<Mutex<Vec<Diff>>>,
+}
+
+impl SdkWatcher for ReceiptWatcher {
+    fn name(&self) -> String {
+        "test/receipts".to_string()
+    }
+    fn on_diff(&mut self, context:&Context, diff:Diff) {
+        for add in diff.adds.iter() {
+            context.commit(vec![Fact::new(sdk::Value::Record("receipt|".to_string()), "item", add[0].clone())]).unwrap();
+        }
+        self.seen.lock().unwrap().push(diff);
+    }
+    fn abi_version(&self) -> AbiVersion {
+        self.abi
+    }
+}
+
+#[test]
+fn base_sdk_watcher() {
+    let mut program = Program::new("test");
+    let (outgoing, incoming) = mpsc::channel();
+    let seen = Arc::new(Mutex::new(vec![]));
+    let future = AbiVersion { major: ABI_VERSION.major + 1, minor: 0 };
+    assert!(sdk::adapt(ReceiptWatcher { abi: future, seen: seen.clone() }, outgoing.clone()).is_err());
+    program.attach(sdk::adapt(ReceiptWatcher { abi: ABI_VERSION, seen: seen.clone() }, outgoing).unwrap());
+    exec_code(&mut program, "commit\n  [#order item: \"tea\" count: 2 price: 1.50d]\nend\n\n\
+                             search\n  [#order item count price]\nwatch test/receipts\n  (item, count, price)\nend\n", "test");
+
+    let diffs = seen.lock().unwrap();
+    assert_eq!(diffs.len(), 1);
+    let price = sdk::Value::Decimal { mantissa: 15, scale: 1 };
+    assert_eq!(diffs[0].adds, vec![vec![sdk::Value::String("tea".to_string()), sdk::Value::Number(2.0), price]]);
+    match incoming.try_recv() {
+        Ok(RunLoopMessage::Transaction(changes)) => {
+            assert_eq!(changes.len(), 1);
+            assert_eq!(changes[0].e, Internable::Reference("receipt|".to_string()));
+            assert_eq!(changes[0].v, Internable::String("tea".to_string()));
+        }
+        _ => panic!("The watcher didn't commit anything"),
+    }
+}
+
+#[test]
+fn base_plugin_manifest() {
+    let dir = std::env::temp_dir().join("eve-base-plugins");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("plugins.json");
+    fs::File::create(&path).unwrap().write_all(b"{\"plugins\": [{\"path\": \"libmissing.so\", \"name\": \"missing\"}, {\"path\": \"/abs/sandboxed.wasm\"}]}").unwrap();
+    let manifest = PluginManifest::from_file(path.to_str().unwrap()).unwrap();
+    fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(manifest.plugins.len(), 2);
+    assert_eq!(manifest.plugins[0].path, dir.join("libmissing.so").to_str().unwrap());
+    assert_eq!(manifest.plugins[0].name, Some("missing".to_string()));
+    assert_eq!(manifest.plugins[1].path, "/abs/sandboxed.wasm");
+
+    let (outgoing, _incoming) = mpsc::channel();
+    match load_plugin(&manifest.plugins[0], outgoing.clone()) {
+        Err(PluginError::Open(path, _)) => assert_eq!(path, manifest.plugins[0].path),
+        _ => panic!("Loaded a library that doesn't exist"),
+    }
+    match load_plugin(&manifest.plugins[1], outgoing.clone()) {
+        Err(PluginError::Unsupported(_)) => {}
+        _ => panic!("Loaded a wasm plugin"),
+    }
+
+    // a loaded plugin is boxed, which has to pass teardown through to the watcher
+    let torn_down = Arc::new(Mutex::new(false));
+    let plugin:PluginWatcher = Box::new(TeardownWatcher(torn_down.clone()));
+    let mut watcher = sdk::adapt(plugin, outgoing).unwrap();
+    watcher.teardown();
+    assert!(*torn_down.lock().unwrap());
+}
+
+struct TeardownWatcher(Arc<Mutex<bool>>);
+
+impl SdkWatcher for TeardownWatcher {
+    fn name(&self) -> String {
+        "test/teardown".to_string()
+    }
+    fn on_diff(&mut self, _:&Context, _:Diff) {}
+    fn teardown(&mut self) {
+        *self.0.lock().unwrap() = true;
+    }
+}
+
+#[test]
+fn base_lint() {
+    let app = "commit\n  [#person name: \"ann\" age: 20]\nend\n\n\
+               search\n  [#person name age]\n  status = if age >= 18 then \"adult\"\nbind\n  [#greeting name status]\n  [#html/div text: name]\nend\n\n\
+               search\n  [#person name age]\n  kind = if age >= 18 then \"adult\" else \"child\"\nbind\n  [#label name kind]\nend\n";
+    let views = "search\n  [#label name]\nbind\n  [#html/div text: name]\nend\n";
+    let sources = vec![("app.eve".to_string(), app.to_string()), ("views.eve".to_string(), views.to_string())];
+    let diagnostics = lint_sources(&sources, &LintConfig::default());
+    let found:Vec<(&str, &str, usize)> = diagnostics.iter().map(|d| (&d.rule[..], &d.path[..], d.line)).collect();
+    assert_eq!(found, vec![("commit-without-search", "app.eve", 1), ("unread-tag", "app.eve", 9), ("non-exhaustive-if", "app.eve", 9)]);
+    assert!(diagnostics[2].message.contains("`status`"));
+    assert_eq!(diagnostics[1].severity, Severity::Warning);
+
+    // a project can change severities, turn rules off and say which tags are read elsewhere
+    let mut config = LintConfig::default();
+    config.rules.insert("commit-without-search".to_string(), Severity::Off);
+    config.rules.insert("non-exhaustive-if".to_string(), Severity::Error);
+    config.ignore_tags.push("greeting".to_string());
+    let diagnostics = lint_sources(&sources, &config);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!((&diagnostics[0].rule[..], diagnostics[0].severity), ("non-exhaustive-if", Severity::Error));
+}
+
+#[test]
+fn base_lint_unused_variables() {
+    let app = "search\n  [#person name: n age]\n  [#team member: p]\n  _unused = age\nbind\n  [#adult age]\nend\n\n\
+               search\n  p = [#person name]\n  not(p.age > 18)\nbind\n  [#child name]\nend\n";
+    let sources = vec![("app.eve".to_string(), app.to_string())];
+    let diagnostics:Vec<Diagnostic> = lint_sources(&sources, &LintConfig::default()).into_iter().filter(|d| d.rule == "unused-variable").collect();
+    let found:Vec<(usize, bool)> = diagnostics.iter().map(|d| (d.line, d.message.contains("`n`"))).collect();
+    assert_eq!(found, vec![(2, true), (3, false)]);
+    assert!(diagnostics[1].message.contains("`p`"));
+}
+
+#[test]
+fn base_imports() {
+    let dir = std::env::temp_dir().join("eve-base-imports");
+    fs::create_dir_all(&dir).unwrap();
+    let write = |name:&str, content:&str| fs::File::create(dir.join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+    write("main.eve", "import \"util.eve\"\nimport \"shared.eve\"\n\nsearch\n  [#util value]\nbind\n  [#main value]\nend\n");
+    write("util.eve", "import \"shared.eve\"\n\nsearch\n  [#shared value]\nbind\n  [#util value]\nend\n");
+    // imports main.eve back, which is reported and otherwise ignored
+    write("shared.eve", "import \"main.eve\"\n\ncommit\n  [#shared value: 3]\nend\n");
+
+    let mut program = Program::new("imports");
+    let main = dir.join("main.eve");
+    let blocks = parse_file_with(&mut program.state.interner, main.to_str().unwrap(), false, &CompileOptions::default());
+    fs::remove_dir_all(&dir).ok();
+
+    let mut paths:Vec<String> = blocks.iter().map(|block| block.path.replace("\\", "/")).collect();
+    assert_eq!(blocks.len(), 3, "Every file should be loaded exactly once");
+    paths.dedup();
+    assert_eq!(paths.len(), 3);
+    assert!(paths[0].ends_with("shared.eve") && paths[1].ends_with("util.eve") && paths[2].ends_with("main.eve"), "Imports load before their importers: {:?}", paths);
+
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+    let tag = s!(program, "tag");
+    let main_tag = s!(program, "main");
+    let value = s!(program, "value");
+    let three = program.state.interner.number_id(3.0);
+    let record = find_entity(&program.state.index, tag, main_tag);
+    assert!(program.state.index.check(record, value, three));
+}
+
+#[test]
+fn base_todo_app_template() {
+    let dir = std::env::temp_dir().join("eve-base-todo-app");
+    fs::remove_dir_all(&dir).ok();
+    let template = find_template("todo-app").unwrap();
+    assert_eq!(new_project(template, &dir).unwrap().len(), template.files.len());
+    assert!(new_project(template, &dir).is_err(), "Wrote over an existing project");
+
+    let mut program = Program::new("todo-app");
+    let blocks = parse_file_with(&mut program.state.interner, dir.to_str().unwrap(), false, &CompileOptions::default());
+    fs::remove_dir_all(&dir).ok();
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+
+    let tag = s!(program, "tag");
+    let result = s!(program, "test/result");
+    let status = s!(program, "status");
+    let passed = s!(program, "passed");
+    let results:Vec<u32> = program.state.index.get(0, tag, result).map_or(vec![], |found| found.collect());
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|&test| program.state.index.check(test, status, passed)), "A template test failed");
+}
+
+//--------------------------------------------------------------------
+// Retry policies
+//--------------------------------------------------------------------
+
+#[test]
+fn base_retry_policy() {
+    let attempts = Internable::from_number(4.0);
+    let backoff = Internable::String("exponential".to_string());
+    let delay = Internable::from_number(0.0);
+    let policy = RetryPolicy::from_values(&[&attempts, &backoff, &delay]);
+    assert_eq!(policy.attempts, 4);
+    assert_eq!(policy.backoff, Backoff::Exponential);
+
+    let slow = RetryPolicy { delay: 100, ..policy };
+    assert_eq!(slow.base_delay(1), 100);
+    assert_eq!(slow.base_delay(3), 400);
+    assert_eq!(slow.base_delay(40), 30_000);
+    let jittered = slow.jittered_delay(3);
+    assert!(jittered >= 200 && jittered <= 400, "Jitter outside of half to all the delay: {}", jittered);
+
+    let mut calls = 0;
+    let result:Result<(), _> = policy.run(|_| { calls += 1; Err("nope".to_string()) });
+    let failure = result.unwrap_err();
+    assert_eq!((calls, failure.attempts, failure.message), (4, 4, "nope".to_string()));
+    assert_eq!(policy.run(|attempt| if attempt < 3 { Err("not yet".to_string()) } else { Ok(attempt) }), Ok(3));
+
+    assert_eq!(RetryPolicy::from_values(&[]), RetryPolicy::once());
+}
+
+//--------------------------------------------------------------------
+// Circuit breakers
+//--------------------------------------------------------------------
+
+fn circuit_states(changes:&Vec<RawChange>) -> Vec<(String, i32)> {
+    changes.iter()
+        .filter(|change| change.a == Internable::String("state".to_string()))
+        .map(|change| (Internable::to_string(&change.v), change.count))
+        .collect()
+}
+
+#[test]
+fn base_circuit_breaker() {
+    let mut breaker = CircuitBreaker::new(2, Duration::from_millis(1000));
+    let start = Instant::now();
+    let mut changes = vec![];
+    assert!(breaker.allow("db", start, &mut changes));
+    assert_eq!(circuit_states(&changes), vec![("closed".to_string(), 1)]);
+
+    changes.clear();
+    breaker.record_failure("db", start, &mut changes);
+    assert_eq!(breaker.state("db"), CircuitState::Closed);
+    assert!(breaker.allow("db", start, &mut changes));
+    breaker.record_failure("db", start, &mut changes);
+    assert_eq!(breaker.state("db"), CircuitState::Open);
+    assert_eq!(circuit_states(&changes), vec![("closed".to_string(), -1), ("open".to_string(), 1)]);
+
+    // open circuits refuse requests until the cooldown is up, then let a single probe through
+    changes.clear();
+    assert!(!breaker.allow("db", start + Duration::from_millis(500), &mut changes));
+    assert!(breaker.allow("db", start + Duration::from_millis(1000), &mut changes));
+    assert_eq!(breaker.state("db"), CircuitState::HalfOpen);
+    assert!(!breaker.allow("db", start + Duration::from_millis(1000), &mut changes));
+    assert_eq!(circuit_states(&changes), vec![("open".to_string(), -1), ("half-open".to_string(), 1)]);
+
+    // a failed probe reopens it, a successful one closes it
+    breaker.record_failure("db", start + Duration::from_millis(1000), &mut changes);
+    assert_eq!(breaker.state("db"), CircuitState::Open);
+    assert!(breaker.allow("db", start + Duration::from_millis(2000), &mut changes));
+    changes.clear();
+    breaker.record_success("db", &mut changes);
+    assert_eq!(breaker.state("db"), CircuitState::Closed);
+    assert_eq!(circuit_states(&changes), vec![("half-open".to_string(), -1), ("closed".to_string(), 1)]);
+
+    // circuits are per endpoint
+    assert!(breaker.allow("cache", start, &mut changes));
+    assert_eq!(breaker.state("cache"), CircuitState::Closed);
+}
+
+//--------------------------------------------------------------------
+// File watching
+//--------------------------------------------------------------------
+
+#[test]
+fn base_file_watch() {
+    let dir = std::env::temp_dir().join("eve-base-file-watch");
+    fs::create_dir_all(&dir).unwrap();
+    let mut program = Program::new("file watch");
+    let (outgoing, incoming) = mpsc::channel();
+    program.attach(Box::new(FileWatchWatcher::new(outgoing)));
+    exec_code(&mut program, &format!("commit\n  [#file/watch path: \"{}\"]\nend\n\n\
+                                      search\n  file = [#file/watch path]\nwatch file/watch\n  (\"watch\", file, path)\nend\n", dir.to_str().unwrap().replace("\\", "/")), "test");
+    fs::File::create(dir.join("notes.txt")).unwrap().write_all(b"tea").unwrap();
+    let changes = match incoming.recv_timeout(Duration::from_secs(5)) {
+        Ok(RunLoopMessage::Transaction(changes)) => changes,
+        _ => panic!("No change was reported"),
+    };
+    let value = |attribute:&str| changes.iter().find(|change| change.a == Internable::String(attribute.to_string())).map(|change| change.v.clone());
+    assert_eq!(value("tag"), Some(Internable::String("file/changed".to_string())));
+    assert!(Internable::to_string(&value("path").unwrap()).ends_with("notes.txt"));
+
+    // sources being hot-reloaded come back as reloads
+    let (outgoing, incoming) = mpsc::channel();
+    assert!(FileWatchWatcher::with_reload(outgoing.clone(), &["/does/not/exist.eve"]).is_err());
+    let _watcher = FileWatchWatcher::with_reload(outgoing, &[dir.to_str().unwrap()]).unwrap();
+    fs::File::create(dir.join("main.eve")).unwrap().write_all(b"commit\n  [#tea]\nend\n").unwrap();
+    let reloaded = match incoming.recv_timeout(Duration::from_secs(5)) {
+        Ok(RunLoopMessage::Reload(paths)) => paths,
+        _ => panic!("The source wasn't reloaded"),
+    };
+    fs::remove_dir_all(&dir).ok();
+    assert!(reloaded.iter().all(|path| path.ends_with("main.eve")));
+}
+
+#[test]
+fn base_file_watcher_breaker() {
+    let dir = std::env::temp_dir().join("eve-base-file-breaker");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("late.txt");
+    fs::remove_file(&path).ok();
+    let mut interner = Interner::new();
+    let read = |interner:&mut Interner, watcher:&mut FileWatcher| {
+        let row = vec![interner.string_id("read"), interner.string_id("late"), interner.string_id(path.to_str().unwrap())];
+        watcher.on_diff(interner, WatchDiff { adds: vec![row], removes: vec![] });
+    };
+    let drain = |incoming:&mpsc::Receiver<RunLoopMessage>| -> Vec<Vec<RawChange>> {
+        incoming.try_iter().filter_map(|message| match message { RunLoopMessage::Transaction(changes) => Some(changes), _ => None }).collect()
+    };
+    let contents = Internable::String("contents".to_string());
+
+    // without a breaker a file that turns up late is read as soon as it's there
+    let (outgoing, incoming) = mpsc::channel();
+    let mut watcher = FileWatcher::new(outgoing);
+    for _ in 0..6 { read(&mut interner, &mut watcher); }
+    fs::File::create(&path).unwrap().write_all(b"tea").unwrap();
+    read(&mut interner, &mut watcher);
+    let transactions = drain(&incoming);
+    assert_eq!(transactions.len(), 7);
+    assert!(transactions[6].iter().any(|change| change.a == contents));
+    assert!(transactions.iter().flat_map(|txn| txn.iter()).all(|change| change.n != Internable::String("service/circuit".to_string())));
+
+    // with one, it's refused until the cooldown passes and the circuit is reported in order
+    fs::remove_file(&path).unwrap();
+    let (outgoing, incoming) = mpsc::channel();
+    let mut watcher = FileWatcher::with_breaker(outgoing, CircuitBreaker::new(1, Duration::from_millis(60_000)));
+    read(&mut interner, &mut watcher);
+    fs::File::create(&path).unwrap().write_all(b"tea").unwrap();
+    read(&mut interner, &mut watcher);
+    let states:Vec<(Internable, i32)> = drain(&incoming).into_iter().flat_map(|txn| txn.into_iter())
+        .filter(|change| change.a == Internable::String("state".to_string()))
+        .map(|change| (change.v, change.count)).collect();
+    fs::remove_dir_all(&dir).ok();
+    assert_eq!(states, vec![(Internable::String("closed".to_string()), 1),
+                            (Internable::String("closed".to_string()), -1),
+                            (Internable::String("open".to_string()), 1)]);
+}
+
+//--------------------------------------------------------------------
+// Processes
+//--------------------------------------------------------------------
+
+#[test]
+fn base_process_spawn() {
+    assert_eq!(split_args("commit -m \"buy tea\" ''"), vec!["commit", "-m", "buy tea", ""]);
+
+    let mut program = Program::new("process");
+    let (outgoing, incoming) = mpsc::channel();
+    program.attach(Box::new(ProcessWatcher::new(outgoing)));
+    let mut library = String::new();
+    fs::File::open("libraries/process/process.eve").unwrap().read_to_string(&mut library).unwrap();
+    exec_code(&mut program, &library, "libraries/process/process.eve");
+    exec_code(&mut program, "commit\n  [#process/spawn cmd: \"cat\" stdin: \"tea\\ncoffee\"]\n  [#process/spawn cmd: \"no-such-command-for-eve\"]\nend\n", "test");
+
+    let mut changes = vec![];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !changes.iter().any(|change:&RawChange| change.v == Internable::String("process/exit".to_string())) && Instant::now() < deadline {
+        if let Ok(RunLoopMessage::Transaction(txn)) = incoming.recv_timeout(Duration::from_millis(100)) {
+            changes.extend(txn);
+        }
+    }
+    let values = |attribute:&str| -> Vec<Internable> {
+        let attribute = Internable::String(attribute.to_string());
+        changes.iter().filter(|change| change.a == attribute).map(|change| change.v.clone()).collect()
+    };
+    assert_eq!(values("text"), vec![Internable::String("tea".to_string()), Internable::String("coffee".to_string())]);
+    assert_eq!(values("code"), vec![Internable::from_number(0.0)]);
+    assert_eq!(values("message").len(), 1, "The missing command should be reported");
+
+    // removing the spawn takes back its output and exit
+    exec_code(&mut program, "search\n  spawn = [#process/spawn cmd: \"cat\"]\ncommit\n  spawn := none\nend\n", "remove");
+    let retracted = next_changes(&incoming);
+    assert!(retracted.iter().all(|change| change.count < 0));
+    let texts = retracted.iter().filter(|change| change.a == Internable::String("text".to_string())).count();
+    assert_eq!(texts, 2);
+    assert!(retracted.iter().any(|change| change.v == Internable::String("process/exit".to_string())));
+}
+
+//--------------------------------------------------------------------
+// TCP
+//--------------------------------------------------------------------
+
+fn next_changes(incoming:&mpsc::Receiver<RunLoopMessage>) -> Vec<RawChange> {
+    match incoming.recv_timeout(Duration::from_secs(5)) {
+        Ok(RunLoopMessage::Transaction(changes)) => changes,
+        _ => panic!("Nothing came back from the watcher"),
+    }
+}
+
+fn change_value(changes:&Vec<RawChange>, attribute:&str) -> Option<Internable> {
+    changes.iter().find(|change| change.a == Internable::String(attribute.to_string()) && change.count > 0).map(|change| change.v.clone())
+}
+
+#[test]
+fn base_tcp_watchers() {
+    let mut program = Program::new("tcp");
+    let (outgoing, incoming) = mpsc::channel();
+    program.attach(Box::new(TcpListenWatcher::new(outgoing.clone())));
+    program.attach(Box::new(TcpConnectWatcher::new(outgoing)));
+    let mut library = String::new();
+    fs::File::open("libraries/tcp/tcp.eve").unwrap().read_to_string(&mut library).unwrap();
+    exec_code(&mut program, &library, "libraries/tcp/tcp.eve");
+    exec_code(&mut program, "commit\n  [#tcp/listen port: 0]\nend\n", "test");
+    let port = Internable::to_number(&change_value(&next_changes(&incoming), "port").unwrap()) as u16;
+
+    // lines from a client come in on a connection of the listener
+    let mut client = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let opened = next_changes(&incoming);
+    assert_eq!(change_value(&opened, "state"), Some(Internable::String("open".to_string())));
+    client.write_all(b"hello\nworld\n").unwrap();
+    assert_eq!(change_value(&next_changes(&incoming), "text"), Some(Internable::String("hello".to_string())));
+    assert_eq!(change_value(&next_changes(&incoming), "text"), Some(Internable::String("world".to_string())));
+    drop(client);
+    assert_eq!(change_value(&next_changes(&incoming), "state"), Some(Internable::String("closed".to_string())));
+
+    // and connecting somewhere nothing is listening is reported
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let closed_port = closed.local_addr().unwrap().port();
+    drop(closed);
+    exec_code(&mut program, &format!("commit\n  [#tcp/connect host: \"127.0.0.1\" port: {}]\nend\n", closed_port), "test");
+    assert!(change_value(&next_changes(&incoming), "message").is_some());
+}
+
+//--------------------------------------------------------------------
+// SQLite
+//--------------------------------------------------------------------
+
+fn sqlite_program(database:&str) -> (Program, mpsc::Receiver<RunLoopMessage>) {
+    let mut program = Program::new("sqlite");
+    let (outgoing, incoming) = mpsc::channel();
+    program.attach(Box::new(SqliteWatcher::new(outgoing)));
+    let mut library = String::new();
+    fs::File::open("libraries/db/sqlite.eve").unwrap().read_to_string(&mut library).unwrap();
+    exec_code(&mut program, &library, "libraries/db/sqlite.eve");
+    exec_code(&mut program, &format!("commit\n  [#db/persist database: \"{}\" tagged: \"ui/note\"]\nend\n", database), "test");
+    (program, incoming)
+}
+
+fn sqlite_changes(incoming:&mpsc::Receiver<RunLoopMessage>) -> Vec<RawChange> {
+    let mut changes = vec![];
+    while let Ok(RunLoopMessage::Transaction(txn)) = incoming.try_recv() {
+        changes.extend(txn);
+    }
+    changes
+}
+
+#[test]
+fn base_sqlite_watcher() {
+    assert_eq!(table_name("ui/note"), "ui_note");
+    let dir = std::env::temp_dir().join("eve-base-sqlite");
+    fs::create_dir_all(&dir).unwrap();
+    let database = dir.join("notes.db");
+    fs::remove_file(&database).ok();
+    let database = database.to_str().unwrap().replace("\\", "/");
+
+    // persisted records land in the tag's table
+    {
+        let (mut program, incoming) = sqlite_program(&database);
+        exec_code(&mut program, "commit\n  [#ui/note text: \"buy tea\"]\nend\n", "test");
+        assert!(sqlite_changes(&incoming).iter().all(|change| change.a != Internable::String("message".to_string())));
+    }
+
+    // and are read back in when they're persisted again
+    let (mut program, incoming) = sqlite_program(&database);
+    let restored = sqlite_changes(&incoming);
+    assert!(restored.iter().any(|change| change.a == Internable::String("text".to_string()) && change.v == Internable::String("buy tea".to_string())));
+
+    exec_code(&mut program, "commit\n  [#db/query database: \"DB\" query: \"SELECT value FROM ui_note WHERE attribute = :attribute\" params: [attribute: \"text\"]]\n  [#db/query database: \"DB\" query: \"SELECT nope FROM nowhere\"]\nend\n".replace("DB", &database).as_str(), "test");
+    let changes = sqlite_changes(&incoming);
+    fs::remove_dir_all(&dir).ok();
+    let values = |attribute:&str| -> Vec<Internable> {
+        let attribute = Internable::String(attribute.to_string());
+        changes.iter().filter(|change| change.a == attribute && change.count > 0).map(|change| change.v.clone()).collect()
+    };
+    assert_eq!(values("value"), vec![Internable::String("buy tea".to_string())]);
+    assert_eq!(values("rows"), vec![Internable::from_number(1.0)]);
+    assert_eq!(values("message").len(), 1, "The bad query should be reported");
+}
+
+#[cfg(feature = "db-postgres")]
+#[test]
+fn base_postgres_watcher() {
+    let (sql, names) = bind_params("SELECT name::text, ':skip' FROM people WHERE age > :age AND (name = :name OR nick = :name)");
+    assert_eq!(sql, "SELECT name::text, ':skip' FROM people WHERE age > $1 AND (name = $2 OR nick = $2)");
+    assert_eq!(names, vec!["age".to_string(), "name".to_string()]);
+
+    // passwords never make it into facts
+    assert_eq!(without_credentials("postgres://app:s3cr@t@db.local:5432/todo?sslmode=disable&password=s3cr"), "postgres://db.local:5432/todo?sslmode=disable");
+    assert_eq!(without_credentials("postgresql://db.local/todo"), "postgresql://db.local/todo");
+    assert_eq!(without_credentials("host=db.local user=app password=s3cr dbname=todo"), "host=db.local dbname=todo");
+
+    // a database that can't be reached is reported on the query
+    let mut program = Program::new("postgres");
+    let (outgoing, incoming) = mpsc::channel();
+    program.attach(Box::new(PostgresWatcher::new(outgoing)));
+    let mut library = String::new();
+    fs::File::open("libraries/db/postgres.eve").unwrap().read_to_string(&mut library).unwrap();
+    exec_code(&mut program, &library, "libraries/db/postgres.eve");
+    exec_code(&mut program, "commit\n  [#db/postgres/query database: \"not a url\" query: \"SELECT 1\"]\nend\n", "test");
+    match incoming.recv_timeout(Duration::from_secs(5)) {
+        Ok(RunLoopMessage::Transaction(changes)) => {
+            let message = changes.iter().find(|change| change.a == Internable::String("message".to_string())).expect("No error reported");
+            assert!(Internable::to_string(&message.v).contains("not a url"));
+        }
+        _ => panic!("The query never finished"),
+    }
+}
+
+//--------------------------------------------------------------------
+// References
+//--------------------------------------------------------------------
+
+test!(base_reference_type, {
+    commit
+        [#person name: "chris"]
+    end
+
+    search
+        person = [#person]
+        "record" = eve!/type!-of![value: person]
+        id = "{{person}}"
+        "string" = eve!/type!-of![value: id]
+        id != person
+    bind
+        [#success]
+    end
+});
+
+//--------------------------------------------------------------------
+// Tag aliases
+//--------------------------------------------------------------------
+
+fn exec_code(program:&mut Program, code:&str, path:&str) {
+    let blocks = parse_string(&mut program.state.interner, code, path);
+    let mut txn = CodeTransaction::new();
+    txn.exec(program, blocks, vec![]);
+}
+
+#[test]
+fn base_compiled_blocks() {
+    let code = "commit\n  [#person name: \"ann\" age: 30]\n  [#person name: \"bo\" age: 12]\n  [#person name: \"cy\" age: 40]\n  [#banned name: \"cy\"]\nend\n\n\
+                search\n  [#person name age]\n  age > 18\n  not([#banned name])\n  next = age + 1\nbind\n  [#adult name next]\nend\n\n\
+                search\n  [#person age]\n  total = gather/count[for: age]\nbind\n  [#adult-count total]\nend\n";
+    let mut parsed = Program::new("parsed");
+    let blocks = parse_string(&mut parsed.state.interner, code, "people.eve");
+    let mut bytes = vec![];
+    write_compiled(&parsed.state.interner, &blocks, &mut bytes).unwrap();
+    let names:Vec<String> = blocks.iter().map(|block| block.name.to_string()).collect();
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut parsed, blocks, vec![]);
+
+    // loaded into a program whose interner has never seen any of it
+    let mut loaded = Program::new("loaded");
+    let blocks = read_compiled(&mut loaded.state.interner, &CustomFunctions::new(), &mut &bytes[..]).unwrap();
+    assert_eq!(blocks.iter().map(|block| block.name.to_string()).collect::<Vec<String>>(), names);
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut loaded, blocks, vec![]);
+    for tag in vec!["adult", "adult-count"] {
+        let filter = ExportFilter { tag: Some(tag.to_string()), scope: None };
+        let expected = export_json(&parsed, &filter, 0);
+        assert!(expected != "[]");
+        assert_eq!(export_json(&loaded, &filter, 0), expected);
+    }
+
+    assert_eq!(read_compiled(&mut loaded.state.interner, &CustomFunctions::new(), &mut &b"search\n"[..]).err(), Some(CompiledError::NotCompiled));
+    let mut future = bytes.clone();
+    future[4] = 99;
+    assert_eq!(read_compiled(&mut loaded.state.interner, &CustomFunctions::new(), &mut &future[..]).err(), Some(CompiledError::Version(99)));
+}
+
+#[test]
+fn base_arrangements() {
+    let mut program = Program::new("arrangements");
+    exec_code(&mut program, "commit\n  [#person name: \"ann\"]\n  [#person name: \"bo\" archived: \"yes\"]\nend\n", "data.eve");
+    exec_code(&mut program, "search\n  p = [#person name]\n  not(p.archived)\nbind\n  [#active name]\nend\n", "a.eve");
+    exec_code(&mut program, "search\n  p = [#person name]\n  not(p.archived)\nbind\n  [#listed name]\nend\n", "b.eve");
+
+    let first = "a.eve|block|1|sub_block|0";
+    let second = "b.eve|block|1|sub_block|0";
+    assert_eq!(program.arrangement_count(), 1);
+    assert_eq!(program.arrangement(first), program.arrangement(second));
+    assert_eq!(program.arrangement(second).unwrap().maintainer, first);
+
+    let tag = s!(program, "tag");
+    let active = s!(program, "active");
+    let listed = s!(program, "listed");
+    let count = |program:&Program, tag_value| program.state.index.get(0, tag, tag_value).map_or(0, |iter| iter.count());
+    assert_eq!(count(&program, active), 1);
+    assert_eq!(count(&program, listed), 1);
+
+    // removing the maintainer hands the arrangement over and the other block keeps working
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, vec![], vec!["a.eve|block|1".to_string(), first.to_string()]);
+    assert_eq!(program.arrangement(second).unwrap().maintainer, second);
+    assert_eq!(count(&program, active), 0);
+    assert_eq!(count(&program, listed), 1);
+
+    let cy = Internable::String("person|cy".to_string());
+    program.transaction()
+        .insert(cy.clone(), "tag", Internable::String("person".to_string()))
+        .insert(cy.clone(), "name", Internable::String("cy".to_string()))
+        .commit();
+    assert_eq!(count(&program, listed), 2);
+    program.transaction()
+        .insert(cy.clone(), "archived", Internable::String("true".to_string()))
+        .commit();
+    assert_eq!(count(&program, listed), 1);
+}
+
+#[test]
+fn base_tag_alias() {
+    let mut program = Program::new("aliases");
+    exec_code(&mut program, "commit\n  [#staff name: \"ann\"]\nend\n", "data.eve");
+    program.alias_tag("employee", "staff");
+    exec_code(&mut program, "search\n  [#staff name]\nbind\n  [#found name]\nend\n", "old.eve");
+    exec_code(&mut program, "search\n  [#employee name]\nbind\n  [#seen name]\nend\n", "new.eve");
+
+    let tag = s!(program, "tag");
+    for name in ["found", "seen"].iter() {
+        let seen = s!(program, name);
+        let found = find_entity(&program.state.index, tag, seen);
+        assert!(program.state.distinct_index.is_available(found, tag, seen), "No {} record", name);
+    }
+    // the original data is untouched
+    let staff = s!(program, "staff");
+    assert_eq!(program.state.index.get(0, tag, staff).map_or(0, |iter| iter.count()), 1);
+}
+
+#[test]
+fn base_tag_deprecated() {
+    let mut program = Program::new("aliases");
+    exec_code(&mut program, "commit\n  [#staff name: \"ann\"]\nend\n", "data.eve");
+    program.deprecate_tag("staff", "employee");
+    exec_code(&mut program, "search\n  [#staff name]\nbind\n  [#found name]\nend\n", "old.eve");
+    let mut iter_pool = EstimateIterPool::new();
+    Transaction::new(&mut iter_pool).exec(&mut program, &mut None);
+
+    let tag = s!(program, "tag");
+    let staff = s!(program, "staff");
+    let employee = s!(program, "employee");
+    let remaining = program.state.index.get(0, tag, staff).map_or(0, |iter| iter.count());
+    assert_eq!(remaining, 0, "Records kept the deprecated tag");
+    let ann = find_entity(&program.state.index, tag, employee);
+    assert!(program.state.distinct_index.is_available(ann, tag, employee));
+    let found = s!(program, "found");
+    assert_eq!(program.state.index.get(0, tag, found).map_or(0, |iter| iter.count()), 1, "Block using the deprecated tag didn't match");
+
+    let system_tag = program.state.interner.scoped_id("system", "tag");
+    let deprecated = s!(program, "system/deprecated-tag");
+    assert_eq!(program.state.index.get(0, system_tag, deprecated).map_or(0, |iter| iter.count()), 1, "Deprecated tag use wasn't reported");
+}
+
+//--------------------------------------------------------------------
+// Record ids
+//--------------------------------------------------------------------
+
+fn person_id(ids:IdGenerator) -> Internable {
+    program_person_id(Program::new("ids").with_id_generator(ids))
+}
+
+fn program_person_id(mut program:Program) -> Internable {
+    let blocks = parse_string(&mut program.state.interner, "commit\n  [#person name: \"ann\"]\nend\n", "test");
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+    let tag = s!(program, "tag");
+    let person = s!(program, "person");
+    let e = program.state.index.get(0, tag, person).expect("No #person record").next().unwrap();
+    program.state.interner.get_value(e).clone()
+}
+
+#[test]
+fn base_id_generators() {
+    let hashed = person_id(IdGenerator::ContentHash);
+    assert_eq!(hashed, person_id(IdGenerator::ContentHash));
+
+    let uuid = match person_id(IdGenerator::Uuid) {
+        Internable::Reference(id) => id,
+        other => panic!("Expected a reference, got {:?}", other),
+    };
+    assert_eq!(uuid.len(), 37);
+    assert_eq!(&uuid[14..15], "4");
+    assert!(uuid.ends_with("|"));
+    assert!(person_id(IdGenerator::Uuid) != Internable::Reference(uuid));
+
+    let custom = person_id(IdGenerator::custom(|params| format!("person/{}", params.len())));
+    assert!(match custom { Internable::Reference(ref id) => id.starts_with("person/") && id.ends_with("|"), _ => false });
+}
+
+#[test]
+fn base_uuid_ids_only_for_commits() {
+    let mut program = Program::new("ids").with_id_generator(IdGenerator::Uuid);
+    exec_code(&mut program, "search\n  [#person name]\nbind\n  [#greeting name]\nend\n", "test");
+    let person = Internable::Reference("person|ann|".to_string());
+    let node = Internable::String("test".to_string());
+    let facts = |count| vec![
+        RawChange::new(person.clone(), Internable::String("tag".to_string()), Internable::String("person".to_string()), node.clone(), count),
+        RawChange::new(person.clone(), Internable::String("name".to_string()), Internable::String("ann".to_string()), node.clone(), count),
+    ];
+    let tag = s!(program, "tag");
+    let greeting = s!(program, "greeting");
+    program.annotated_transaction(facts(1), vec![]);
+    let first = find_entity(&program.state.index, tag, greeting);
+    program.annotated_transaction(facts(-1), vec![]);
+    assert_eq!(program.state.index.get(0, tag, greeting).map_or(0, |iter| iter.count()), 0, "Bound record wasn't retracted");
+    program.annotated_transaction(facts(1), vec![]);
+    assert_eq!(find_entity(&program.state.index, tag, greeting), first, "Bound record got a new id");
+}
+
+#[test]
+fn base_deterministic_ids() {
+    let seeded = |seed| program_person_id(Program::new("ids").deterministic(seed).with_id_generator(IdGenerator::Uuid));
+    let first = seeded(7);
+    assert!(match first { Internable::Reference(ref id) => id.len() == 37 && id.ends_with("|"), _ => false });
+    assert_eq!(first, seeded(7));
+    assert!(first != seeded(8));
+
+    let hashed = program_person_id(Program::new("ids").deterministic(7));
+    assert_eq!(hashed, person_id(IdGenerator::ContentHash));
+}
+
+// Several records committed over a couple of frames, so the ids only line up from run
+// to run if blocks see their changes in the same order every time.
+fn deterministic_records(seed:u64) -> Vec<(Internable, Internable)> {
+    let mut program = Program::new("ids").deterministic(seed).with_id_generator(IdGenerator::Uuid);
+    exec_code(&mut program, "commit\n  [#person name: \"ann\"]\n  [#person name: \"bo\"]\n  [#person name: \"cy\"]\n  [#person name: \"di\"]\nend\n\n\
+                             search\n  [#person name]\ncommit\n  [#badge name]\nend\n", "test");
+    let (tag, name) = (s!(program, "tag"), s!(program, "name"));
+    let kinds = vec![s!(program, "person"), s!(program, "badge")];
+    let mut records = vec![];
+    for kind in kinds {
+        for e in program.state.index.get(0, tag, kind).expect("No records") {
+            let value = program.state.index.get(e, name, 0).expect("No name").next().unwrap();
+            records.push((program.state.interner.get_value(value).clone(), program.state.interner.get_value(e).clone()));
+        }
+    }
+    records.sort();
+    records
+}
+
+#[test]
+fn base_deterministic_records() {
+    let first = deterministic_records(7);
+    assert_eq!(first.len(), 8);
+    for _ in 0..3 {
+        assert_eq!(deterministic_records(7), first);
+    }
+}
+
+test!(base_random_uuid, {
+    search
+        id = random!/uuid![]
+        36 = string!/length![text: id]
+    bind
+        [#success]
+    end
+});
+
+#[test]
+fn base_interner_values() {
+    let mut interner = Interner::new();
+    let name = interner.string_id("chris");
+    let age = interner.number_id(30.5);
+    let id = interner.internable_to_id(Internable::Reference("person|1|".to_string()));
+    assert_eq!(interner.value(name), Value::String("chris".to_string()));
+    assert_eq!(interner.value(age), Value::Number(30.5));
+    assert_eq!(interner.value(id), Value::String("person|1|".to_string()));
+    assert_eq!(interner.value(0), Value::None);
+    assert_eq!(interner.value(10_000), Value::None);
+    assert_eq!(interner.value_to_string(age), Some("30.5".to_string()));
+    assert_eq!(interner.value_to_number(age), Some(30.5));
+    assert_eq!(interner.value_to_number(name), None);
+}
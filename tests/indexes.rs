@@ -1,7 +1,9 @@
 extern crate eve;
 use eve::indexes::*;
-use eve::ops::{EstimateIter, OutputRounds, RoundHolder, Change};
-use std::collections::HashMap;
+use eve::ops::{EstimateIter, OutputRounds, RoundHolder, Change, ChangeType, Internable, Interner, Field, make_scan, make_filter};
+use eve::compiler::order_scans;
+use std::collections::{HashMap, BTreeSet, Bound};
+use std::sync::Arc;
 
 #[test]
 fn index_insert_check() {
@@ -54,6 +56,86 @@ fn index_level_remove() {
     assert!(!index.check(2,1));
 }
 
+#[test]
+fn index_leaf_grows_and_shrinks() {
+    let mut index = HashIndexLevel::new();
+    for e in 1..21 {
+        assert!(index.insert(e, 7));
+        assert!(!index.insert(e, 7));
+    }
+    for e in (1..21).filter(|e| e % 3 == 0) {
+        index.remove(e, 7);
+    }
+    let mut entities:Vec<u32> = index.get(0, 7).unwrap().collect();
+    entities.sort();
+    assert_eq!(entities, (1..21).filter(|e| e % 3 != 0).collect::<Vec<u32>>());
+    for e in 1..21 {
+        assert_eq!(index.check(e, 7), e % 3 != 0);
+    }
+    for e in (1..21).filter(|e| e % 3 != 0) {
+        index.remove(e, 7);
+    }
+    assert!(!index.check(0, 7));
+}
+
+// Enough facts, and enough removals, to split blocks and fold emptied ones back together,
+// checked against a plain set of the same facts.
+#[test]
+fn index_level_matches_set() {
+    let mut index = HashIndexLevel::new();
+    let mut facts = BTreeSet::new();
+    let mut seed:u32 = 7;
+    for round in 0..20_000 {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let e = (seed >> 8) % 500 + 1;
+        let v = (seed >> 20) % 50 + 1;
+        if round % 3 == 2 {
+            assert_eq!(index.remove(e, v), facts.remove(&(e, v)));
+        } else {
+            assert_eq!(index.insert(e, v), facts.insert((e, v)));
+        }
+    }
+    for e in 1..501 {
+        let expected:Vec<u32> = facts.iter().filter(|&&(fe, _)| fe == e).map(|&(_, v)| v).collect();
+        match index.get(e, 0) {
+            Some(values) => {
+                assert_eq!(values.len(), expected.len());
+                assert_eq!(values.collect::<Vec<u32>>(), expected);
+            }
+            None => assert!(expected.is_empty()),
+        }
+    }
+    for v in 1..51 {
+        let expected:Vec<u32> = facts.iter().filter(|&&(_, fv)| fv == v).map(|&(e, _)| e).collect();
+        assert_eq!(index.get(0, v).map_or(vec![], |entities| entities.collect::<Vec<u32>>()), expected);
+        assert_eq!(index.check(0, v), !expected.is_empty());
+    }
+    let mut pairs = index.pairs();
+    pairs.sort();
+    assert_eq!(pairs, facts.iter().cloned().collect::<Vec<(u32, u32)>>());
+    // there are fewer values than entities, so those are what a free scan proposes
+    let values:BTreeSet<u32> = facts.iter().map(|&(_, v)| v).collect();
+    let keys = index.get(0, 0).unwrap();
+    assert_eq!(keys.len(), values.len());
+    assert_eq!(keys.collect::<Vec<u32>>(), values.into_iter().collect::<Vec<u32>>());
+}
+
+#[test]
+fn index_ordered_range() {
+    let mut level = OrderedLevel::new();
+    level.insert(10, &Internable::from_number(-5.0));
+    level.insert(11, &Internable::from_number(0.0));
+    level.insert(12, &Internable::from_number(30.0));
+    level.insert(13, &Internable::from_number(31.5));
+    level.insert(14, &Internable::String("thirty".to_string()));
+    assert_eq!(level.range(Bound::Excluded(0.0), Bound::Unbounded).unwrap(), vec![12, 13]);
+    assert_eq!(level.range(Bound::Included(30.0), Bound::Included(30.0)).unwrap(), vec![12]);
+    assert_eq!(level.range(Bound::Unbounded, Bound::Excluded(-0.0)).unwrap(), vec![10]);
+    assert_eq!(level.range(Bound::Included(40.0), Bound::Included(1.0)).unwrap(), Vec::<u32>::new());
+    level.remove(12, &Internable::from_number(30.0));
+    assert_eq!(level.range(Bound::Included(1.0), Bound::Unbounded).unwrap(), vec![13]);
+}
+
 #[test]
 fn index_remove() {
     let mut index = HashIndex::new();
@@ -106,6 +188,45 @@ fn index_propose() {
 }
 
 
+#[test]
+fn index_propose_bound_value_uses_reverse_index() {
+    let mut index = HashIndex::new();
+    for e in 1..1001 {
+        index.insert(e, 1, e + 5000);
+    }
+    index.insert(7, 1, 9000);
+    let mut proposal = EstimateIter::new();
+    index.propose(&mut proposal, 0, 1, 9000);
+    assert_eq!(proposal.estimate, 1);
+    let mut unbound = EstimateIter::new();
+    index.propose(&mut unbound, 0, 1, 0);
+    assert_eq!(unbound.estimate, 1000);
+}
+
+#[test]
+fn index_order_scans_by_selectivity() {
+    let mut index = HashIndex::new();
+    // attribute 1 is on every entity, attribute 2 on only a handful
+    for e in 1..1001 {
+        index.insert(e, 1, e % 2 + 5000);
+    }
+    for e in 1..4 {
+        index.insert(e, 2, 6000);
+    }
+    assert_eq!(index.attribute_stats(1), AttributeStats { facts: 1000, entities: 1000, values: 2 });
+
+    let mut constraints = vec![
+        make_scan(Field::Register(0), Field::Value(1), Field::Value(5000)),
+        make_filter("<", Field::Register(1), Field::Value(7)),
+        make_scan(Field::Register(0), Field::Value(2), Field::Register(1)),
+    ];
+    assert!(order_scans(&mut constraints, &index));
+    assert_eq!(constraints[0], make_scan(Field::Register(0), Field::Value(2), Field::Register(1)));
+    assert_eq!(constraints[2], make_scan(Field::Register(0), Field::Value(1), Field::Value(5000)));
+    assert!(!order_scans(&mut constraints, &index));
+}
+
+
 //---------------------------------------------------------------
 // Distinct index
 //---------------------------------------------------------------
@@ -175,6 +296,27 @@ fn test_distinct(counts: Vec<(u32, i32)>, expected: Vec<(u32, i32)>) {
 
 }
 
+// Commits the same fact with each count in turn and says whether anything made it through.
+fn commit_counts(counts: Vec<i32>) -> (bool, usize) {
+    let mut index = HashIndex::new();
+    let mut distinct_index = DistinctIndex::new();
+    let mut holder = RoundHolder::new();
+    for count in counts {
+        let change = Change { e: 1, a: 2, v: 3, n: 4, round: 0, transaction: 0, count };
+        holder.commit(change, ChangeType::Insert);
+    }
+    let changed = holder.prepare_commits(&mut index, &mut distinct_index, &mut vec![], |_| false);
+    (changed, holder.duplicates)
+}
+
+#[test]
+fn round_holder_commit_counts() {
+    // the net count doesn't depend on the order the commits came in
+    assert_eq!(commit_counts(vec![1, 1, -1]), (true, 1));
+    assert_eq!(commit_counts(vec![1, -1, 1]), (true, 0));
+    assert_eq!(commit_counts(vec![1, -1]), (false, 0));
+}
+
 #[test]
 fn distinct_basic() {
     test_distinct(vec![
@@ -670,3 +812,90 @@ fn distinct_anti_simple_retraction_same_end2() {
         );
 }
 
+
+#[test]
+fn index_key_filter() {
+    let mut filter = KeyFilter::new(0);
+    for ix in 0..5000 {
+        filter.insert(&[ix, ix + 1]);
+    }
+    for ix in 0..5000 {
+        assert!(filter.may_contain(&[ix, ix + 1]));
+    }
+    assert!(filter.is_saturated());
+    assert!(filter.may_contain_hash(key_hash(&[7, 8])));
+
+    let mut index = IntermediateIndex::new();
+    index.distinct(vec![1, 2, 3], vec![1, 2], vec![3], 1, 1, false);
+    assert!(index.may_contain(&vec![1, 2]));
+    assert!(!index.check(&vec![1, 5], &vec![3]));
+    assert_eq!(index.distinct_iter(&vec![7, 8], &vec![]).count(), 0);
+}
+
+#[test]
+fn index_graph_follows_changes() {
+    let mut interner = Interner::new();
+    let link = interner.string_id("link");
+    let a = interner.string_id("a");
+    let b = interner.string_id("b");
+    let c = interner.string_id("c");
+    let mut index = HashIndex::new();
+    index.insert_value(b, link, a, &interner);
+    index.insert_value(c, link, b, &interner);
+    let mut components = index.graph(link).components();
+    components.sort();
+    assert_eq!(components, vec![(a, a), (b, a), (c, a)]);
+    assert_eq!(index.graph(link).shortest_path(c, a), Some(vec![c, b, a]));
+
+    // once the graph exists, changes to the edges are applied to it rather than rebuilt
+    index.insert_value(c, link, a, &interner);
+    assert_eq!(index.graph(link).shortest_path(c, a), Some(vec![c, a]));
+    index.remove_value(b, link, a, &interner);
+    index.remove_value(c, link, a, &interner);
+    let mut components = index.graph(link).components();
+    components.sort();
+    assert_eq!(components, vec![(b, b), (c, b)]);
+    assert_eq!(index.graph(link).shortest_path(c, a), None);
+}
+
+#[test]
+fn index_text_follows_changes() {
+    let mut interner = Interner::new();
+    let body = interner.string_id("body");
+    let fox = interner.string_id("The quick brown fox");
+    let foxes = interner.string_id("A fox and another fox");
+    let terms = vec!["fox".to_string()];
+    let mut index = HashIndex::new();
+    index.insert_value(10, body, fox, &interner);
+    assert_eq!(index.text(body, &interner).score(&terms).len(), 1);
+
+    index.insert_value(11, body, foxes, &interner);
+    let mut scores = index.text(body, &interner).score(&terms);
+    scores.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+    assert_eq!(scores.iter().map(|&(e, _)| e).collect::<Vec<_>>(), vec![10, 11]);
+
+    index.remove_value(10, body, fox, &interner);
+    let scores = index.text(body, &interner).score(&terms);
+    assert_eq!(scores.iter().map(|&(e, _)| e).collect::<Vec<_>>(), vec![11]);
+}
+
+#[test]
+fn index_shared_map_copy_on_write() {
+    let mut map:SharedMap<u32, u32> = SharedMap::new();
+    for ix in 0..1000 {
+        map.insert(ix, ix);
+    }
+    let copy = map.share();
+    map.insert(5, 50);
+    map.remove(&6);
+    map.insert(2000, 1);
+    assert_eq!(copy.get(&5), Some(&5));
+    assert!(copy.contains_key(&6));
+    assert_eq!(copy.len(), 1000);
+    assert_eq!(map.get(&5), Some(&50));
+    assert!(!map.contains_key(&6));
+    assert_eq!(map.len(), 1000);
+    // only the shards that were written to were copied
+    let shared = map.shards().iter().zip(copy.shards()).filter(|&(a, b)| Arc::ptr_eq(a, b)).count();
+    assert!(shared >= map.shards().len() - 3, "Copied {} shards", map.shards().len() - shared);
+}
@@ -1,8 +1,9 @@
 extern crate eve;
-use eve::ops::{Program};
+use eve::ops::{Program, Tracer, DebugMode, BlockMetadata};
 use eve::compiler::*;
 use eve::parser::*;
 use eve::combinators::*;
+use eve::formatter::*;
 
 //--------------------------------------------------------------------
 // Helper macros
@@ -21,7 +22,7 @@ macro_rules! parse_blocks (($info:tt) => ({
         .replace(" ! / ", "/")
         .replace(": =", ":=")
         .replace(" . ", ".");
-    let blocks = parse_string(&mut program.state.interner, &stringy, "test", false);
+    let blocks = parse_string(&mut program.state.interner, &stringy, "test");
     blocks
 }));
 
@@ -61,3 +62,379 @@ pub fn parser_combinator() {
     let result = search_section_statement(&mut state);
     println!("{:?}", result);
 }
+
+#[test]
+pub fn parser_trace_capture() {
+    Tracer::enable(DebugMode::Compile);
+    assert!(Tracer::is_enabled(DebugMode::Compile) && !Tracer::is_enabled(DebugMode::Unify));
+    Tracer::capture();
+    parse_blocks!({
+        search
+            [#foo woah]
+        bind
+            [#bar baz: woah]
+        end
+    });
+    Tracer::disable(DebugMode::Compile);
+    assert!(!Tracer::is_enabled(DebugMode::Compile));
+    let entries = Tracer::take();
+    assert!(entries.iter().any(|entry| entry.mode == DebugMode::Compile && entry.message.contains("test|block|1")));
+}
+
+//--------------------------------------------------------------------
+// Function validation
+//--------------------------------------------------------------------
+
+#[test]
+pub fn compile_error_unknown_function() {
+    let blocks = parse_blocks!({
+        search
+            x = string!/lenght![text: "hello"]
+        bind
+            [#success]
+        end
+    });
+    assert_eq!(blocks.len(), 0);
+    assert!(suggest_functions("string/lenght").contains(&"string/length".to_string()));
+}
+
+#[test]
+pub fn compile_error_unknown_function_param() {
+    let blocks = parse_blocks!({
+        search
+            x = string!/length![txt: "hello"]
+        bind
+            [#success]
+        end
+    });
+    assert_eq!(blocks.len(), 0);
+}
+
+#[test]
+pub fn compile_error_too_many_function_outputs() {
+    let blocks = parse_blocks!({
+        search
+            (x, y) = string!/length![text: "hello"]
+        bind
+            [#success]
+        end
+    });
+    assert_eq!(blocks.len(), 0);
+}
+
+//--------------------------------------------------------------------
+// Markdown
+//--------------------------------------------------------------------
+
+#[test]
+pub fn markdown_only_eve_fences_run() {
+    let doc = "# Doc\n\
+               \n\
+               search for the fenced block below.\n\
+               \n\
+               \x20   search\n\
+               \x20     [#example]\n\
+               \x20   bind\n\
+               \x20     [#not-run]\n\
+               \x20   end\n\
+               \n\
+               ```js\n\
+               search = 1\n\
+               ```\n\
+               \n\
+               ```eve\n\
+               search\n\
+               \x20 [#foo]\n\
+               bind\n\
+               \x20 [#success]\n\
+               end\n\
+               ```\n";
+    let mut state = ParseState::new(doc);
+    match embedded_blocks(&mut state, "test.md") {
+        ParseResult::Ok(Node::Doc { blocks, .. }) => {
+            assert_eq!(blocks.len(), 1);
+            match blocks[0] {
+                Node::Pos(ref span, _) => assert_eq!(span.start.line, 15),
+                _ => panic!("Expected a positioned block"),
+            }
+        }
+        _ => panic!("Failed to parse markdown"),
+    }
+}
+
+#[test]
+pub fn markdown_without_fences_scans_everything() {
+    let mut program = Program::new("parser test");
+    let blocks = parse_string(&mut program.state.interner, "prose\nsearch\n  [#foo]\nbind\n  [#bar]\nend\n", "test");
+    assert_eq!(blocks.len(), 1);
+}
+
+//--------------------------------------------------------------------
+// Checking
+//--------------------------------------------------------------------
+
+#[test]
+pub fn check_reports_errors() {
+    let mut program = Program::new("check test");
+    let good = "search\n  [#foo woah]\nbind\n  [#bar baz: woah]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, good, "good.eve"), (1, 0));
+    let bad = "search\n  x = string/lenght[text: \"hello\"]\nbind\n  [#bar baz: x]\nend\n";
+    let (blocks, errors) = check_string(&mut program.state.interner, bad, "bad.eve");
+    assert_eq!(blocks, 0);
+    assert!(errors > 0);
+}
+
+#[test]
+pub fn check_reports_never_matching_blocks() {
+    let mut program = Program::new("check test");
+    let range = "search\n  [#person age]\n  age > 5\n  age < 3\nbind\n  [#child age]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, range, "range.eve"), (0, 1));
+    let touching = "search\n  [#person age]\n  age >= 5\n  5 >= age\nbind\n  [#five age]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, touching, "touching.eve"), (1, 0));
+    let constants = "search\n  [#person age]\n  x = 3\n  x = 4\nbind\n  [#odd age]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, constants, "constants.eve"), (0, 1));
+    let filter = "search\n  [#person age]\n  x = 3\n  x > 10\nbind\n  [#odd age]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, filter, "filter.eve"), (0, 1));
+    // a `not` that can never match just always holds
+    let negated = "search\n  [#person age]\n  not(age > 5 age < 3)\nbind\n  [#fine age]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, negated, "negated.eve"), (2, 0));
+    let literal = "search\n  [#person age]\n  3 > 5\nbind\n  [#odd age]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, literal, "literal.eve"), (0, 1));
+    let tags = "search\n  p = [#person age]\n  not(p = [#person])\nbind\n  [#odd age]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, tags, "tags.eve"), (0, 1));
+    let other_tag = "search\n  p = [#person age]\n  not(p = [#admin])\nbind\n  [#odd age]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, other_tag, "other_tag.eve"), (2, 0));
+    // an unknown function is reported once, not compared against the constant
+    let unknown = "search\n  5 = string/lenght[text: \"hello\"]\nbind\n  [#odd]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, unknown, "unknown.eve"), (0, 1));
+}
+
+#[test]
+pub fn check_reports_unbound_variables() {
+    let mut program = Program::new("check test");
+    let bound = "search\n  [#person name]\nbind\n  [#greeting name]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, bound, "bound.eve"), (1, 0));
+    let bind = "search\n  [#person name]\nbind\n  [#greeting name: nickname]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, bind, "bind.eve"), (0, 1));
+    let commit = "search\n  [#person name]\ncommit\n  friend.name := name\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, commit, "commit.eve"), (0, 1));
+}
+
+#[test]
+pub fn check_if_branch_arity() {
+    let mut program = Program::new("check test");
+    let matched = "search\n  [#person age]\n  (a, b) = if age > 18 then (1, 2) else (3, 4)\nbind\n  [#pair a b]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, matched, "matched.eve").1, 0);
+    let short = "search\n  [#person age]\n  (a, b) = if age > 18 then (1, 2) else 3\nbind\n  [#pair a b]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, short, "short.eve"), (0, 1));
+    let both = "search\n  [#person age]\n  a = if age > 18 then (1, 2) else (3, 4)\nbind\n  [#pair a]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, both, "both.eve"), (0, 2));
+}
+
+#[test]
+pub fn check_negation_cycles() {
+    let mut program = Program::new("check test");
+    let own = "search\n  [#person name]\n  not([#greeting name])\nbind\n  [#greeting name]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, own, "own.eve"), (0, 1));
+    let through = "search\n  [#person name]\n  not([#vip name])\nbind\n  [#regular name]\nend\n\nsearch\n  [#regular name]\nbind\n  [#vip name]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, through, "through.eve"), (1, 1));
+    // binding something else on a record found by the `not` is fine
+    let fine = "search\n  [#person name]\n  not([#banned name])\nbind\n  [#greeting name]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, fine, "fine.eve"), (2, 0));
+    // records the block makes arrive whole, so they can't flip a branch on what it's handed
+    let created = "search\n  [#foo value]\n  type = if lookup[entity: value] then \"record\" else \"value\"\nbind\n  [#value type]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, created, "created.eve"), (3, 0));
+
+    // files are checked on their own, so cycles across them need all the blocks at once
+    let mut blocks = parse_string(&mut program.state.interner, "search\n  [#person name]\n  not([#vip name])\nbind\n  [#regular name]\nend\n", "one.eve");
+    blocks.extend(parse_string(&mut program.state.interner, "search\n  [#regular name]\nbind\n  [#vip name]\nend\n", "two.eve"));
+    let cycles = negation_cycles(&program.state.interner, &blocks);
+    assert_eq!(cycles, vec![vec!["one.eve|block|1".to_string(), "two.eve|block|1".to_string(), "one.eve|block|1".to_string()]]);
+}
+
+#[test]
+pub fn check_strict_attributes() {
+    let mut program = Program::new("check test");
+    let typo = "search\n  [#person name]\nbind\n  [#greeting nmae: name]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, typo, "typo.eve"), (1, 0));
+    let strict = format!("eve:strict\n{}", typo);
+    assert_eq!(check_string(&mut program.state.interner, &strict, "strict.eve"), (0, 1));
+    let declared = format!("eve:strict\neve:attributes nmae\n{}", typo);
+    assert_eq!(check_string(&mut program.state.interner, &declared, "declared.eve"), (1, 0));
+
+    let options = CompileOptions { strict: true, ..CompileOptions::default() };
+    let fine = "search\n  [#person name]\nbind\n  [#greeting name]\nend\n";
+    assert_eq!(parse_string_with(&mut program.state.interner, fine, "fine.eve", &options).len(), 1);
+    let update = "search\n  p = [#person]\ncommit\n  p.nmae := \"x\"\nend\n";
+    assert_eq!(parse_string_with(&mut program.state.interner, update, "update.eve", &options).len(), 0);
+
+    // an annotation quoted in a code sample doesn't count
+    let quoted = format!("```text\neve:strict\n```\n\n```eve\n{}```\n", typo);
+    assert_eq!(check_string(&mut program.state.interner, &quoted, "quoted.md"), (1, 0));
+    let prose = format!("eve:strict\n\n```eve\n{}```\n", typo);
+    assert_eq!(check_string(&mut program.state.interner, &prose, "prose.md"), (0, 1));
+}
+
+#[test]
+pub fn check_strict_project_attributes() {
+    let dir = std::env::temp_dir().join("eve-strict-project");
+    std::fs::create_dir_all(&dir).unwrap();
+    let writes = dir.join("writes.eve");
+    let reads = dir.join("reads.eve");
+    std::fs::write(&writes, "eve:strict\nsearch\n  [#person name]\nbind\n  [#greeting nmae: name]\nend\n").unwrap();
+    std::fs::remove_file(&reads).ok();
+    let mut program = Program::new("strict test");
+    assert_eq!(parse_file_with(&mut program.state.interner, &dir.to_string_lossy(), false, &CompileOptions::default()).len(), 0);
+    // another file searching for it makes it a known attribute
+    std::fs::write(&reads, "search\n  [#greeting nmae]\nbind\n  [#greeted nmae]\nend\n").unwrap();
+    assert_eq!(parse_file_with(&mut program.state.interner, &dir.to_string_lossy(), false, &CompileOptions::default()).len(), 2);
+
+    // so does declaring it in the program's schema
+    let mut program = Program::new("strict test").with_strict(true);
+    program.declare_reference("owner");
+    let options = program.compile_options();
+    let pets = "search\n  [#person name]\nbind\n  [#pet owner: name]\nend\n";
+    assert_eq!(parse_string_with(&mut program.state.interner, pets, "pets.eve", &options).len(), 1);
+}
+
+//--------------------------------------------------------------------
+// Formatting
+//--------------------------------------------------------------------
+
+#[test]
+pub fn format_block_sections() {
+    let source = "# Doc   \nsearch\n      [#person name]\n   [#ui/div | children:\n[#ui/text text: \"[\" ]]  \n bind\n [#greeting name]\n    end\nend of prose\n\n\n";
+    // a search can't have a `|`, so the block doesn't parse and is only laid out line by line
+    let expected = "# Doc\nsearch\n  [#person name]\n  [#ui/div | children:\n    [#ui/text text: \"[\" ]]\nbind\n  [#greeting name]\nend\nend of prose\n";
+    assert_eq!(format_source(source), expected);
+    assert_eq!(format_source(expected), expected);
+}
+
+#[test]
+pub fn format_only_eve_fences() {
+    let source = "```js\nsearch\n      x\n```\n```eve\nsearch\n      [#foo]\nbind\n[#bar]\nend\n```\n";
+    let expected = "```js\nsearch\n      x\n```\n```eve\nsearch\n  [#foo]\nbind\n  [#bar]\nend\n```\n";
+    assert_eq!(format_source(source), expected);
+}
+
+#[test]
+pub fn format_from_ast() {
+    let source = "search  [#person   name age]\n  age >   10 ,  x = (age + 1) * 2\n  s = if age > 20 then \"old\" else \"young\"\n  not( [#banned name])\ncommit @browser [#div text: \"{{name}}: {{x}}\"]\nend\n";
+    let expected = "search\n  [#person name age]\n  age > 10\n  x = (age + 1) * 2\n  s = if age > 20 then \"old\"\n    else \"young\"\n  not([#banned name])\ncommit @browser\n  [#div text: \"{{name}}: {{x}}\"]\nend\n";
+    assert_eq!(format_source(source), expected);
+    assert_eq!(format_source(expected), expected);
+}
+
+#[test]
+pub fn format_objective() {
+    let source = "search\n  [#shift cost]\n  minimize   cost *  2\nbind\n  [#cheapest cost]\nend\n";
+    let expected = "search\n  [#shift cost]\n  minimize cost * 2\nbind\n  [#cheapest cost]\nend\n";
+    assert_eq!(format_source(source), expected);
+    assert_eq!(format_source(expected), expected);
+}
+
+#[test]
+pub fn format_wraps_long_records() {
+    let source = "bind\n  [#ui/div class: \"container\" style: [width: 100 height: 200] children: [#ui/text text: \"hello there\"] [#ui/text text: \"and hello again\"]]\nend\n";
+    let expected = "bind\n  [#ui/div class: \"container\" style: [width: 100 height: 200] children:\n    [#ui/text text: \"hello there\"]\n    [#ui/text text: \"and hello again\"]]\nend\n";
+    assert_eq!(format_source(source), expected);
+    assert_eq!(format_source(expected), expected);
+}
+
+#[test]
+pub fn format_sorts_attributes() {
+    let options = FormatOptions { sort_attributes: true, ..FormatOptions::default() };
+    let source = "search\n  [name #person age friend]\nbind\n  [zed: name #greeting alpha: age | friend #buddy]\nend\n";
+    let expected = "search\n  [#person age friend name]\nbind\n  [#greeting alpha: age zed: name | #buddy friend]\nend\n";
+    assert_eq!(format_source_with(source, &options), expected);
+    assert_eq!(format_source(source), source);
+}
+
+#[test]
+pub fn format_tag_unions() {
+    let source = "search\n  [#dog|#cat   name]\nbind\n  [#pet name]\nend\n";
+    let expected = "search\n  [#dog|#cat name]\nbind\n  [#pet name]\nend\n";
+    assert_eq!(format_source(source), expected);
+}
+
+#[test]
+pub fn format_keeps_comments() {
+    // blocks with comments can't be printed from the AST, so they're only re-indented
+    let source = "search\n[#person name] // everyone\nbind\n[#greeting name]\nend\n";
+    let expected = "search\n  [#person name] // everyone\nbind\n  [#greeting name]\nend\n";
+    assert_eq!(format_source(source), expected);
+}
+
+#[test]
+pub fn parse_block_annotations() {
+    let named = BlockMetadata { name: Some("my block".to_string()), disabled: true, distinct: false };
+    assert_eq!(block_annotation("eve:block name: \"my block\" disabled"), Some(named));
+    let bare = BlockMetadata { name: Some("greeter".to_string()), disabled: false, distinct: false };
+    assert_eq!(block_annotation("  eve:block name: greeter"), Some(bare));
+    let distinct = BlockMetadata { name: None, disabled: false, distinct: true };
+    assert_eq!(block_annotation("eve:block distinct"), Some(distinct));
+    assert_eq!(block_annotation("eve:strict"), None);
+    assert_eq!(block_annotation("search"), None);
+}
+
+#[test]
+pub fn parse_file_imports() {
+    let source = "# Notes\nimport \"util.eve\"\n  import \"lib/dates.eve\"\nimport util.eve\n\nsearch\n  [#foo]\nbind\n  [#bar]\nend\n";
+    let imports = file_imports(source);
+    let paths:Vec<&str> = imports.iter().map(|&(ref path, _)| &path[..]).collect();
+    assert_eq!(paths, vec!["util.eve", "lib/dates.eve"]);
+    assert_eq!((imports[1].1.start.line, imports[1].1.start.ch), (2, 2));
+}
+
+//--------------------------------------------------------------------
+// Syntax versions
+//--------------------------------------------------------------------
+
+#[test]
+pub fn parse_syntax_headers() {
+    match syntax_header("\nsyntax 0.4\nsearch\n  [#foo]\nbind\n  [#bar]\nend\n") {
+        Some((Ok(syntax), span)) => {
+            assert_eq!(syntax, LATEST_SYNTAX);
+            assert_eq!(span.start.line, 1);
+        }
+        other => panic!("Expected a header, got {:?}", other),
+    }
+    match syntax_header("  syntax 1.0") {
+        Some((Err(version), span)) => {
+            assert_eq!(version, "1.0");
+            assert_eq!(span.start.ch, 2);
+        }
+        other => panic!("Expected an unsupported header, got {:?}", other),
+    }
+    assert!(syntax_header("# Notes\nsyntax 0.4\n").is_none());
+    assert!(syntax_header("syntax matters\n").is_none());
+    assert!(DEFAULT_SYNTAX < LATEST_SYNTAX);
+    assert!(LATEST_SYNTAX.allows(Feature::BlockComments));
+    assert!(!DEFAULT_SYNTAX.allows(Feature::BlockComments));
+}
+
+#[test]
+pub fn syntax_gates_block_comments() {
+    let mut program = Program::new("syntax test");
+    let code = "search\n  [#foo] /* a note */\n  /* the\nend of the\n  note */\nbind\n  [#bar]\nend\n";
+    let (_, errors) = check_string(&mut program.state.interner, code, "default.eve");
+    assert!(errors > 0);
+    let old = format!("syntax 0.3\n{}", code);
+    let (_, errors) = check_string(&mut program.state.interner, &old, "old.eve");
+    assert!(errors > 0);
+    let latest = format!("syntax 0.4\n{}", code);
+    assert_eq!(check_string(&mut program.state.interner, &latest, "latest.eve"), (1, 0));
+    // line comments work in every version
+    let line = "search\n  [#foo] // a note\nbind\n  [#bar]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, line, "line.eve"), (1, 0));
+}
+
+#[test]
+pub fn syntax_unsupported_versions() {
+    let mut program = Program::new("syntax test");
+    let code = "syntax 9.1\nsearch\n  [#foo]\nbind\n  [#bar]\nend\n";
+    assert_eq!(check_string(&mut program.state.interner, code, "future.eve"), (0, 1));
+    assert!(missing_syntax_warning("future.eve", code).is_none());
+    let warning = missing_syntax_warning("plain.eve", "search\n  [#foo]\nbind\n  [#bar]\nend\n").unwrap();
+    assert!(warning.contains("plain.eve") && warning.contains("0.3"));
+}
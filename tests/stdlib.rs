@@ -1,8 +1,8 @@
 #[macro_use]
 extern crate eve;
 
-use eve::ops::{Program, CodeTransaction};
-use eve::compiler::{parse_string};
+use eve::ops::{Program, CodeTransaction, Internable};
+use eve::compiler::{parse_string, parse_string_with, compile_string};
 
 //--------------------------------------------------------------------
 // math
@@ -25,6 +25,152 @@ test!(stdlib_math_range, {
     end
 });
 
+test!(stdlib_range, {
+    search
+        value = range![from:0 to:10 increment:5]
+    bind
+        [#thing value]
+    end
+
+    search
+        step = -1
+        value = range![from:3 to:1 increment:step]
+    bind
+        [#down value]
+    end
+
+    search
+        [#thing value:0]
+        [#thing value:5]
+        [#thing value:10]
+        not([#thing value:15])
+        [#down value:1]
+        [#down value:2]
+        [#down value:3]
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_range_unbounded, {
+    search
+        step = -1
+        value = range![from:1 to:4 increment:step]
+    bind
+        [#backwards value]
+    end
+
+    search
+        value = range![from:1 to:4 increment:0]
+    bind
+        [#stuck value]
+    end
+
+    search
+        value = range![from:0 to:100000000]
+    bind
+        [#huge value]
+    end
+
+    search
+        not([#backwards])
+        not([#stuck])
+        not([#huge])
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_math_decimal_exact, {
+    search
+        a = eve!/parse!-value![value: "0.1d"]
+        b = eve!/parse!-value![value: "0.2d"]
+        c = eve!/parse!-value![value: "0.30d"]
+        c = a + b
+        c > 0.29
+        c < 0.31
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_math_decimal_sum, {
+    commit
+        [#price item: "a" amount: "19.99d"]
+        [#price item: "b" amount: "0.01d"]
+        [#price item: "c" amount: "5.10d"]
+    end
+
+    search
+        [#price item amount: text]
+        amount = eve!/parse!-value![value: text]
+        total = gather!/sum![for: item value: amount]
+        "25.1" = "{{total}}"
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_sample_fraction, {
+    search
+        user = math!/range![from:1 to:200]
+        sample![fraction: 0.5 per: user seed: "experiment"]
+        count = gather!/count![for: user]
+        count > 60
+        count < 140
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_sample_all_or_none, {
+    search
+        user = math!/range![from:1 to:20]
+        sample![fraction: 1 per: user seed: "everyone"]
+        count = gather!/count![for: user]
+        count = 20
+        not(sample![fraction: 0 per: user seed: "nobody"])
+    bind
+        [#success]
+    end
+});
+
+//--------------------------------------------------------------------
+// date
+//--------------------------------------------------------------------
+
+test!(stdlib_date_parse_format, {
+    search
+        stamp = date!/parse![text: "2017-08-21 10:30" format: "%Y-%m-%d %H:%M"]
+        text = date!/format![timestamp: stamp format: "%d/%m/%Y %H:%M"]
+    bind
+        [#result text]
+    end
+
+    search
+        [#result text: "21/08/2017 10:30"]
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_date_add_diff, {
+    search
+        stamp = date!/parse![text: "2017-08-21" format: "%Y-%m-%d"]
+        later = date!/add![timestamp: stamp amount: 3 unit: "days"]
+        text = date!/format![timestamp: later format: "%Y-%m-%d"]
+        hours = date!/diff![from: stamp to: later unit: "hours"]
+    bind
+        [#result text hours]
+    end
+
+    search
+        [#result text: "2017-08-24" hours: 72]
+    bind
+        [#success]
+    end
+});
+
 //--------------------------------------------------------------------
 // string
 //--------------------------------------------------------------------
@@ -240,3 +386,312 @@ test!(stdlib_string_join, {
         [#success]
     end
 });
+
+test!(stdlib_string_levenshtein, {
+    search
+        3 = string!/levenshtein![a: "kitten" b: "sitting"]
+        0 = string!/levenshtein![a: "same" b: "same"]
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_string_soundex, {
+    search
+        "R163" = string!/soundex![text: "Robert"]
+        "R163" = string!/soundex![text: "Rupert"]
+        "A261" = string!/soundex![text: "Ashcraft"]
+        "T522" = string!/soundex![text: "Tymczak"]
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_string_pad, {
+    search
+        "007" = string!/pad!-start![text: 7 length: 3 pad: "0"]
+        "ab   " = string!/pad!-end![text: "ab" length: 5]
+        "-=-=ab" = string!/pad!-start![text: "ab" length: 6 pad: "-="]
+        "abcdef" = string!/pad!-start![text: "abcdef" length: 3 pad: "0"]
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_string_title_case, {
+    search
+        "Hello World" = string!/title!-case![text: "hELLO world"]
+        "" = string!/title!-case![text: ""]
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_string_slugify, {
+    search
+        "hello-world" = string!/slugify![text: "  Hello, World! "]
+        "q3-2017-report" = string!/slugify![text: "Q3 -- 2017 Report"]
+    bind
+        [#success]
+    end
+});
+
+//--------------------------------------------------------------------
+// url
+//--------------------------------------------------------------------
+
+test!(stdlib_url_parse, {
+    search
+        (scheme, host, port, path, query, fragment) = url!/parse![text: "HTTPS://user@Example.com/a/b?x=1&y=2#top"]
+        scheme = "https"
+        host = "example.com"
+        port = 443
+        path = "/a/b"
+        query = "x=1&y=2"
+        fragment = "top"
+        (_, "localhost", 8080, "/", "", "") = url!/parse![text: "ws://localhost:8080"]
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_url_query_param, {
+    search
+        ("q", "hello world", 1) = url!/query!-param![text: "q=hello+world"]
+        ("tag", "a&b", 2) = url!/query!-param![text: "http://example.com/search?q=hello+world&tag=a%26b&tag=c"]
+        ("tag", "c", 3) = url!/query!-param![text: "http://example.com/search?q=hello+world&tag=a%26b&tag=c"]
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_url_encode, {
+    search
+        "a%20b%26c" = url!/encode![value: "a b&c"]
+        "a b&c" = url!/decode![text: "a%20b%26c"]
+        "name=Jane%20Doe" = url!/encode!-query![key: "name", value: "Jane Doe"]
+        "page=2" = url!/encode!-query![key: "page", value: 2]
+    bind
+        [#success]
+    end
+});
+
+//--------------------------------------------------------------------
+// html
+//--------------------------------------------------------------------
+
+test!(stdlib_html_select, {
+    commit
+        [#page html: "<a name='top'>Top</a><ul><li class='item'><a href='/one'>One</a></li><li class='item'>Two</li><li>Three</li></ul>"]
+    end
+
+    search
+        [#page html]
+        (1, "li", "One", _) = html!/select![document: html, selector: "li.item"]
+        (2, "li", "Two", "Two") = html!/select![document: html, selector: "li.item"]
+        (1, "a", "One", "/one") = html!/select![document: html, selector: "a", attribute: "href"]
+    bind
+        [#success]
+    end
+});
+
+//--------------------------------------------------------------------
+// graph
+//--------------------------------------------------------------------
+
+test!(stdlib_graph_shortest_path, {
+    commit
+        a = [#node name: "a"]
+        b = [#node name: "b" link: a]
+        c = [#node name: "c" link: b]
+        d = [#node name: "d" link: c, link: a]
+    end
+
+    search
+        start = [#node name: "d"]
+        goal = [#node name: "b"]
+        [#node link]
+        (node, step) = graph!/shortest!-path![from: start, to: goal, edge: "link"]
+    bind
+        [#path node step]
+    end
+
+    search
+        c = [#node name: "c"]
+        b = [#node name: "b"]
+        [#path node: c, step: 2]
+        [#path node: b, step: 3]
+        [#path node]
+        count = gather!/count![for: node]
+        count = 3
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_graph_components, {
+    commit
+        a = [#node name: "a"]
+        b = [#node name: "b" link: a]
+        c = [#node name: "c"]
+        d = [#node name: "d" link: c]
+    end
+
+    search
+        [#node link]
+        (node, component) = graph!/components![edge: "link"]
+    bind
+        node.component += component
+    end
+
+    search
+        [#node name: "a" component]
+        [#node name: "b" component]
+        [#node name: "d" component: other]
+        component != other
+    bind
+        [#success]
+    end
+});
+
+//--------------------------------------------------------------------
+// search
+//--------------------------------------------------------------------
+
+test!(stdlib_search_text, {
+    commit
+        [#doc name: "a" body: "The quick brown fox"]
+        [#doc name: "b" body: "A fox and another fox"]
+        [#doc name: "c" body: "Nothing to see here"]
+    end
+
+    search
+        [#doc body]
+        (record, score) = search!/text![query: "fox", attribute: "body"]
+    bind
+        record.score += score
+    end
+
+    search
+        [#doc name: "a" score: ascore]
+        [#doc name: "b" score: bscore]
+        not([#doc name: "c" score])
+        bscore > ascore
+    bind
+        [#success]
+    end
+});
+
+test!(stdlib_search_text_fulltext, {
+    commit @fulltext
+        [attribute: "body"]
+    end
+
+    commit
+        [#doc name: "a" body: "Fox news"]
+        [#doc name: "b" body: "Old news"]
+    end
+
+    search
+        [#doc body]
+        (record, score) = search!/text![query: "fox", attribute: "body"]
+    bind
+        record.match += "yes"
+    end
+
+    search
+        [#doc name: "a" match: "yes"]
+        not([#doc name: "b" match])
+    bind
+        [#success]
+    end
+});
+
+//--------------------------------------------------------------------
+// custom functions
+//--------------------------------------------------------------------
+
+fn has_success(program:&mut Program) -> bool {
+    let a = program.state.interner.string_id("tag");
+    let v = program.state.interner.string_id("success");
+    match program.state.index.get(0, a, v) {
+        Some(iter) => iter.collect::<Vec<_>>().into_iter().any(|e| program.state.distinct_index.is_available(e, a, v)),
+        None => false,
+    }
+}
+
+#[test]
+fn stdlib_custom_function() {
+    let mut program = Program::new("register");
+    program.register_function("test/divmod", vec!["value", "by"], vec!["quotient", "remainder"], Box::new(|params: &[Internable]| {
+        match params {
+            &[Internable::Number(_), Internable::Number(_)] => {
+                let value = Internable::to_number(&params[0]) as i64;
+                let by = Internable::to_number(&params[1]) as i64;
+                if by == 0 { return None; }
+                Some(vec![Internable::from_number((value / by) as f32), Internable::from_number((value % by) as f32)])
+            }
+            _ => None
+        }
+    }));
+    let code = "search\n  (3, 2) = test/divmod[value: 17, by: 5]\n  not(test/divmod[value: 17, by: 0])\nbind\n  [#success]\nend\n";
+    let options = program.compile_options();
+    let blocks = parse_string_with(&mut program.state.interner, code, "divmod.eve", &options);
+    assert_eq!(blocks.len(), 2);
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+    assert!(has_success(&mut program));
+
+    // functions belong to the program they were registered on
+    let mut other = Program::new("other");
+    let options = other.compile_options();
+    let (blocks, errors) = compile_string(&mut other.state.interner, code, "divmod.eve", &options);
+    assert_eq!(blocks.len(), 0);
+    assert!(errors > 0);
+}
+
+#[test]
+fn stdlib_custom_multi_function() {
+    let mut program = Program::new("register");
+    program.register_multi_function("test/between", vec!["from", "to"], vec!["value"], Box::new(|params: &[Internable]| {
+        match params {
+            &[Internable::Number(_), Internable::Number(_)] => {
+                let from = Internable::to_number(&params[0]) as i64;
+                let to = Internable::to_number(&params[1]) as i64;
+                (from..to + 1).map(|value| vec![Internable::from_number(value as f32)]).collect()
+            }
+            _ => vec![]
+        }
+    }));
+    let code = "search\n  value = test/between[from: 1, to: 3]\nbind\n  [#thing value]\nend\n
+search\n  [#thing value: 1]\n  [#thing value: 2]\n  [#thing value: 3]\n  not([#thing value: 4])\nbind\n  [#success]\nend\n";
+    let options = program.compile_options();
+    let blocks = parse_string_with(&mut program.state.interner, code, "between.eve", &options);
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+    assert!(has_success(&mut program));
+}
+
+#[test]
+fn stdlib_custom_function_output_count() {
+    let mut program = Program::new("register");
+    program.register_function("test/pair", vec!["value"], vec!["first", "second"], Box::new(|params: &[Internable]| {
+        Some(vec![params[0].clone()])
+    }));
+    let code = "search\n  (a, b) = test/pair[value: 1]\nbind\n  [#success]\nend\n";
+    let options = program.compile_options();
+    let blocks = parse_string_with(&mut program.state.interner, code, "pair.eve", &options);
+    let mut txn = CodeTransaction::new();
+    txn.exec(&mut program, blocks, vec![]);
+    assert!(!has_success(&mut program));
+
+    // the engine keeps going and says what went wrong
+    program.transaction().commit();
+    let kind_attribute = program.state.interner.scoped_id("system", "kind");
+    let kind = program.state.interner.string_id("type-error");
+    let function_attribute = program.state.interner.scoped_id("system", "function");
+    let function = program.state.interner.string_id("test/pair");
+    let errors:Vec<_> = program.state.index.get(0, kind_attribute, kind).map_or(vec![], |iter| iter.collect());
+    assert_eq!(errors.len(), 1);
+    assert!(program.state.index.check(errors[0], function_attribute, function));
+}
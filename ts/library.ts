@@ -7,6 +7,10 @@ export type RawEAV = [RawValue, RawValue, RawValue];
 export interface RawMap<V> {[key:string]: V, [key:number]: V};
 export type RawRecord = RawMap<RawValue|RawValue[]>;
 
+// Record ids travel tagged as {ref: id} so they're never mistaken for strings, and
+// scoped attributes as {scope, attribute}.
+export type WireValue = RawValue|{ref:string}|{scope:string, attribute:string};
+
 // Diffs.
 export type Diff<T> = {adds?: T, removes?: T};
 export type DiffHandler = (diff:Diff<RawTuple[]>) => void
@@ -131,8 +135,29 @@ export function asValue(value:RawValue|undefined) {
   return value;
 }
 
+// Ids we've seen or made, so they go back to the server as records.
+let references:{[id:string]: boolean} = {};
+
 export function createId() {
-  return "|" + uuid();
+  let id = "|" + uuid();
+  references[id] = true;
+  return id;
+}
+
+export function fromWire(value:WireValue):RawValue {
+  if(typeof value == "object") {
+    let scoped = value as {scope?:string, attribute?:string};
+    if(scoped.scope !== undefined) return `@${scoped.scope}|${scoped.attribute}`;
+    let id = (value as {ref:string}).ref;
+    references[id] = true;
+    return id;
+  }
+  return value;
+}
+
+export function toWire(value:RawValue):WireValue {
+  if(typeof value == "string" && references[value]) return {ref: value};
+  return value;
 }
 
 export function tupleToRecord<T extends RawRecord>(attributes: string[], tuple:RawTuple, record:RawRecord = {}): T {
@@ -1,7 +1,8 @@
 import {Program, Library, Diff, RawEAV, RawTuple, libraries} from ".";
+import {WireValue, fromWire, toWire} from "./library";
 import {Connection, Message} from "./connection";
 
-export interface DiffMessage extends Message { type: "diff"; adds?:RawTuple[]; removes?:RawTuple[]; }
+export interface DiffMessage extends Message { type: "diff"; adds?:WireValue[][]; removes?:WireValue[][]; }
 export interface LoadBundleMessage extends Message { type: "load-bundle"; bundle: string }
 export interface ErrorMessage extends Message { type:"error"; error:string }
 
@@ -25,12 +26,13 @@ class RemoteProgram implements Program {
   constructor(public name = "Remote Client", public send:(type: string, diff: any) => void) {}
 
   inputEAVs(eavs:RawEAV[]) {
-    let diff:Diff<RawEAV[]> = {adds: eavs, removes: []};
+    let diff:Diff<WireValue[][]> = {adds: eavs.map((eav) => eav.map(toWire)), removes: []};
     this.send("Transaction", diff);
     return this;
   }
 
-  handleDiff(diff:Diff<RawTuple[]>) {
+  handleDiff(wire:Diff<WireValue[][]>) {
+    let diff:Diff<RawTuple[]> = {adds: (wire.adds || EMPTY).map((row) => row.map(fromWire)), removes: (wire.removes || EMPTY).map((row) => row.map(fromWire))};
     let types:{[type:string]: Diff<RawTuple[]>} = {};
     for(let add of diff.adds || EMPTY) {
       let type = add[0];